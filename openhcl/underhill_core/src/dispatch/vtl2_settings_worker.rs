@@ -1449,6 +1449,7 @@ async fn make_nvme_controller_config(
             namespaces,
             max_io_queues: 64,
             msix_count: 64,
+            namespace_pool: Vec::new(),
         }
         .into_resource(),
     })
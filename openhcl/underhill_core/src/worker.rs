@@ -3125,6 +3125,7 @@ async fn new_underhill_vm(
         use virt::Hv1;
         use vmcore::vpci_msi::VpciInterruptMapper;
 
+        let guest_ram: Vec<_> = mem_layout.ram().iter().map(|r| r.range).collect();
         for crate::dispatch::vtl2_settings_worker::UhVpciDeviceConfig {
             instance_id,
             resource,
@@ -3138,6 +3139,7 @@ async fn new_underhill_vm(
                 &driver_source,
                 &resolver,
                 device_memory,
+                &guest_ram,
                 vmbus.control(),
                 instance_id,
                 resource,
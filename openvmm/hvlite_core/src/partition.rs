@@ -102,6 +102,16 @@ fn into_doorbell_registration(
     /// Returns whether partition reset is supported.
     fn supports_reset(&self) -> bool;
 
+    /// Returns whether tracking guest writes to memory is supported.
+    fn supports_dirty_tracking(&self) -> bool;
+
+    /// Enables or disables tracking guest writes to memory.
+    fn set_dirty_tracking(&self, enable: bool) -> anyhow::Result<()>;
+
+    /// Sets the bits in `bitmap` for the pages in `range` that the guest has
+    /// written since they were last retrieved, and marks them clean.
+    fn take_dirty_bitmap(&self, range: MemoryRange, bitmap: &mut [u64]) -> anyhow::Result<()>;
+
     /// Returns the reference time source.
     fn reference_time_source(&self) -> Option<ReferenceTimeSource>;
 
@@ -227,6 +237,24 @@ fn supports_reset(&self) -> bool {
         self.supports_reset().is_some()
     }
 
+    fn supports_dirty_tracking(&self) -> bool {
+        self.supports_dirty_tracking().is_some()
+    }
+
+    fn set_dirty_tracking(&self, enable: bool) -> anyhow::Result<()> {
+        self.supports_dirty_tracking()
+            .context("dirty page tracking not supported")?
+            .set_dirty_tracking(enable)?;
+        Ok(())
+    }
+
+    fn take_dirty_bitmap(&self, range: MemoryRange, bitmap: &mut [u64]) -> anyhow::Result<()> {
+        self.supports_dirty_tracking()
+            .context("dirty page tracking not supported")?
+            .take_dirty_bitmap(range, bitmap)?;
+        Ok(())
+    }
+
     fn reference_time_source(&self) -> Option<ReferenceTimeSource> {
         self.reference_time_source()
     }
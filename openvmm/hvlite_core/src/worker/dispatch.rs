@@ -5,6 +5,9 @@
 use crate::partition::BindHvliteVp;
 use crate::partition::HvlitePartition;
 use crate::vmgs_non_volatile_store::HvLiteVmgsNonVolatileStore;
+use crate::worker::migrate::MigrationReceiver;
+use crate::worker::migrate::MigrationSender;
+use crate::worker::migrate::migration_ranges;
 use crate::worker::rom::RomBuilder;
 use acpi::dsdt;
 use anyhow::Context;
@@ -13,6 +16,7 @@
 use debug_ptr::DebugPtr;
 use disk_backend::Disk;
 use disk_backend::resolve::ResolveDiskParameters;
+use disk_backend_resources::LayeredDiskRequest;
 use firmware_uefi::LogLevel;
 use firmware_uefi::UefiCommandSet;
 use floppy_resources::FloppyDiskConfig;
@@ -20,6 +24,7 @@
 use futures::StreamExt;
 use futures::executor::block_on;
 use futures::future::try_join_all;
+use futures::io::AllowStdIo;
 use futures_concurrency::prelude::*;
 use guestmem::GuestMemory;
 use guid::Guid;
@@ -53,6 +58,7 @@
 use hvlite_defs::worker::VM_WORKER;
 use hvlite_defs::worker::VmWorkerParameters;
 use hvlite_pcat_locator::RomFileLocation;
+use hyperv_ic_resources::vss::VssRpc;
 use ide_resources::GuestMedia;
 use ide_resources::IdeDeviceConfig;
 use igvm::IgvmFile;
@@ -68,6 +74,8 @@
 use mesh::error::RemoteError;
 use mesh::payload::Protobuf;
 use mesh::payload::message::ProtobufMessage;
+use mesh::rpc::FailableRpc;
+use mesh::rpc::RpcSend;
 use mesh_worker::Worker;
 use mesh_worker::WorkerId;
 use mesh_worker::WorkerRpc;
@@ -75,6 +83,7 @@
 use pal_async::DefaultDriver;
 use pal_async::DefaultPool;
 use pal_async::local::block_with_io;
+use pal_async::socket::PolledSocket;
 use pal_async::task::Spawn;
 use pal_async::task::Task;
 use pci_core::PciInterruptPin;
@@ -90,6 +99,8 @@
 use state_unit::SpawnedUnit;
 use state_unit::StateUnits;
 use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
 use std::io::Read;
 use std::io::Write;
 use std::sync::Arc;
@@ -203,6 +214,8 @@ fn from_config(config: Config) -> Self {
             vmbus_devices: config.vmbus_devices,
             chipset_devices: config.chipset_devices,
             generation_id_recv: config.generation_id_recv,
+            vss_ic: config.vss_ic,
+            layered_disks: config.layered_disks,
             rtc_delta_milliseconds: config.rtc_delta_milliseconds,
             automatic_guest_reset: config.automatic_guest_reset,
             efi_diagnostics_log_level: match config.efi_diagnostics_log_level {
@@ -252,6 +265,8 @@ pub struct Manifest {
     vmbus_devices: Vec<(DeviceVtl, Resource<VmbusDeviceHandleKind>)>,
     chipset_devices: Vec<ChipsetDeviceHandle>,
     generation_id_recv: Option<mesh::Receiver<[u8; 16]>>,
+    vss_ic: Option<mesh::Sender<VssRpc>>,
+    layered_disks: Vec<mesh::Sender<LayeredDiskRequest>>,
     rtc_delta_milliseconds: i64,
     automatic_guest_reset: bool,
     efi_diagnostics_log_level: LogLevel,
@@ -264,6 +279,15 @@ pub struct SavedState {
     pub units: Vec<SavedStateUnit>,
 }
 
+/// Requests from a migration task to the worker loop.
+enum MigrateRequest {
+    /// Pauses the VM and saves its state, returning whether the VM was
+    /// running.
+    PauseAndSave(FailableRpc<(), (bool, SavedState)>),
+    /// Reports that the migration has finished, resuming the VM if needed.
+    Complete { resume: bool },
+}
+
 async fn open_simple_disk(
     resolver: &ResourceResolver,
     disk_type: Resource<DiskHandleKind>,
@@ -333,7 +357,50 @@ fn new(parameters: Self::Parameters) -> anyhow::Result<Self> {
             .transpose()
             .context("failed to decode saved state")?;
 
-        let vm = block_with_io(|_| vm.load(saved_state, parameters.notify))?;
+        let sources = [
+            saved_state.is_some(),
+            parameters.incoming.is_some(),
+            parameters.restore_from.is_some(),
+        ];
+        if sources.iter().filter(|&&x| x).count() > 1 {
+            anyhow::bail!("only one of saved state, incoming migration, or snapshot may be used");
+        }
+
+        let vm = if let Some(incoming) = parameters.incoming {
+            block_with_io(async |driver| {
+                let ranges = migration_ranges(&vm.mem_layout)?;
+                let incoming = PolledSocket::new(&driver, incoming)
+                    .context("failed to create polled socket")?;
+                let mut receiver = MigrationReceiver::new(incoming, ranges)
+                    .await
+                    .context("failed to start incoming migration")?;
+                let saved_state = receiver
+                    .receive(&vm.gm)
+                    .await
+                    .context("failed to receive migrated VM")?;
+                let r = vm.load(Some(saved_state), parameters.notify).await;
+                receiver
+                    .complete(r.is_ok())
+                    .await
+                    .context("failed to complete incoming migration")?;
+                r
+            })?
+        } else if let Some(file) = parameters.restore_from {
+            block_with_io(async |_| {
+                let ranges = migration_ranges(&vm.mem_layout)?;
+                let mut receiver =
+                    MigrationReceiver::new(AllowStdIo::new(BufReader::new(file)), ranges)
+                        .await
+                        .context("failed to open snapshot")?;
+                let saved_state = receiver
+                    .receive(&vm.gm)
+                    .await
+                    .context("failed to read snapshot")?;
+                vm.load(Some(saved_state), parameters.notify).await
+            })?
+        } else {
+            block_with_io(|_| vm.load(saved_state, parameters.notify))?
+        };
 
         LOADED_VM.store(&vm);
 
@@ -583,6 +650,8 @@ struct LoadedVmInner {
     #[cfg_attr(not(guest_arch = "x86_64"), expect(dead_code))]
     pci_legacy_interrupts: Vec<((u8, Option<u8>), u32)>,
     firmware_event_send: Option<mesh::Sender<get_resources::ged::FirmwareEvent>>,
+    vss_ic: Option<mesh::Sender<VssRpc>>,
+    layered_disks: Vec<mesh::Sender<LayeredDiskRequest>>,
 
     load_mode: LoadMode,
     igvm_file: Option<IgvmFile>,
@@ -1048,6 +1117,10 @@ async fn load(
             }
         }
 
+        // Expose the guest RAM backing, for device back-ends that access guest
+        // memory directly.
+        resolver.add_resolver(memory_manager.shared_guest_ram());
+
         if cfg
             .vmgs
             .as_ref()
@@ -1841,6 +1914,8 @@ async fn load(
             }
         }
 
+        let guest_ram: Vec<_> = mem_layout.ram().iter().map(|r| r.range).collect();
+
         for dev_cfg in cfg.pcie_devices {
             vmm_core::device_builder::build_pcie_device(
                 &mut chipset_builder,
@@ -1848,6 +1923,7 @@ async fn load(
                 &driver_source,
                 &resolver,
                 &gm,
+                &guest_ram,
                 dev_cfg.resource,
                 partition.clone().into_doorbell_registration(Vtl::Vtl0),
                 Some(&mapper),
@@ -2109,6 +2185,7 @@ async fn add_virtio_vpci(
                         &driver_source,
                         &resolver,
                         &gm,
+                        &guest_ram,
                         vmbus.control(),
                         dev_cfg.instance_id,
                         dev_cfg.resource,
@@ -2244,6 +2321,7 @@ async fn add_virtio_vpci(
                     VirtioResolveInput {
                         driver_source: &driver_source,
                         guest_memory: &gm,
+                        guest_ram: &guest_ram,
                     },
                 )
                 .await?;
@@ -2483,6 +2561,8 @@ async fn add_virtio_vpci(
                 vmbus_devices,
                 chipset_cfg: cfg.chipset,
                 firmware_event_send: cfg.firmware_event_send,
+                vss_ic: cfg.vss_ic,
+                layered_disks: cfg.layered_disks,
                 load_mode: cfg.load_mode,
                 virtio_mmio_count,
                 virtio_mmio_irq,
@@ -2768,6 +2848,7 @@ enum Event {
             WorkerRpc(Result<WorkerRpc<RestartState>, mesh::RecvError>),
             VmRpc(Result<VmRpc, mesh::RecvError>),
             Halt(Result<HaltReason, mesh::RecvError>),
+            Migrate(Result<MigrateRequest, mesh::RecvError>),
         }
 
         // Start a task to handle state unit inspections by filtering the worker
@@ -2790,12 +2871,18 @@ enum Event {
         });
         let mut worker_rpc = worker_rpc_recv;
 
+        // Migrations run on a separate task, which asks the loop to pause and
+        // save the VM once pre-copy is done.
+        let (migrate_send, mut migrate_recv) = mesh::channel();
+        let mut migrating = false;
+
         loop {
             let event: Event = {
                 let a = rpc_recv.recv().map(Event::VmRpc);
                 let b = worker_rpc.recv().map(Event::WorkerRpc);
                 let c = self.inner.halt_recv.recv().map(Event::Halt);
-                (a, b, c).race().await
+                let d = migrate_recv.recv().map(Event::Migrate);
+                (a, b, c, d).race().await
             };
 
             match event {
@@ -2834,6 +2921,7 @@ enum Event {
                     WorkerRpc::Inspect(deferred) => deferred.respond(|resp| {
                         resp.field("memory", &self.inner.memory_manager)
                             .field("memory_layout", &self.inner.mem_layout)
+                            .field("dirty_tracking", self.inner.gm.inspect_dirty_tracking())
                             .field("resolver", &self.inner.resolver)
                             .field("vmgs", &self.inner.vmgs_client_inspect_handle);
                     }),
@@ -2951,6 +3039,44 @@ enum Event {
                     VmRpc::WriteMemory(rpc) => rpc.handle_failable_sync(|(gpa, bytes)| {
                         self.inner.gm.write_at(gpa, bytes.as_slice())
                     }),
+                    VmRpc::SaveSnapshot(rpc) => {
+                        rpc.handle_failable(async |file| self.save_snapshot(file).await)
+                            .await
+                    }
+                    VmRpc::Migrate(rpc) => {
+                        if migrating {
+                            rpc.fail(anyhow::anyhow!("a migration is already in progress"));
+                        } else {
+                            migrating = self.start_migration(driver, rpc, migrate_send.clone());
+                        }
+                    }
+                    VmRpc::FreezeGuest(rpc) => self.send_vss_request(driver, VssRpc::Freeze, rpc),
+                    VmRpc::ThawGuest(rpc) => self.send_vss_request(driver, VssRpc::Thaw, rpc),
+                    VmRpc::MergeDiskLayer(rpc) => self.merge_disk_layer(driver, rpc),
+                },
+                Event::Migrate(Err(_)) => unreachable!("the sender is owned by the loop"),
+                Event::Migrate(Ok(request)) => match request {
+                    MigrateRequest::PauseAndSave(rpc) => {
+                        rpc.handle_failable(async |()| {
+                            let paused = self.pause().await;
+                            match self.save().await {
+                                Ok(saved_state) => Ok((paused, saved_state)),
+                                Err(err) => {
+                                    if paused {
+                                        self.resume().await;
+                                    }
+                                    Err(err)
+                                }
+                            }
+                        })
+                        .await
+                    }
+                    MigrateRequest::Complete { resume } => {
+                        if resume {
+                            self.resume().await;
+                        }
+                        migrating = false;
+                    }
                 },
                 Event::Halt(Err(_)) => break,
                 Event::Halt(Ok(reason)) => {
@@ -3039,6 +3165,43 @@ async fn complete_reload_igvm(&mut self, complete: bool) -> anyhow::Result<()> {
         Ok(())
     }
 
+    /// Forwards a freeze or thaw request to the guest's VSS IC. The response
+    /// is awaited on a separate task, since the guest may take a while to
+    /// flush its filesystems.
+    fn send_vss_request(
+        &self,
+        driver: &impl Spawn,
+        f: fn(FailableRpc<(), ()>) -> VssRpc,
+        rpc: FailableRpc<(), ()>,
+    ) {
+        let Some(vss_ic) = &self.inner.vss_ic else {
+            rpc.fail(anyhow::anyhow!("no vss ic configured"));
+            return;
+        };
+        let call = vss_ic.call_failable(f, ());
+        driver
+            .spawn("vmrpc-vss", async move {
+                rpc.complete(call.await.map_err(RemoteError::new))
+            })
+            .detach();
+    }
+
+    /// Forwards a merge request to a layered disk. The merge copies the whole
+    /// top layer, so the response is awaited on a separate task.
+    fn merge_disk_layer(&self, driver: &impl Spawn, rpc: FailableRpc<usize, ()>) {
+        let (index, rpc) = rpc.split();
+        let Some(disk) = self.inner.layered_disks.get(index) else {
+            rpc.fail(anyhow::anyhow!("no mergeable disk at index {index}"));
+            return;
+        };
+        let call = disk.call_failable(LayeredDiskRequest::MergeTopLayer, ());
+        driver
+            .spawn("vmrpc-merge-disk", async move {
+                rpc.complete(call.await.map_err(RemoteError::new))
+            })
+            .detach();
+    }
+
     /// Get the associated hvsock relay for a given vtl, if any.
     fn hvsock_relay(&self, vtl: DeviceVtl) -> Option<&HvsockRelay> {
         match vtl {
@@ -3063,6 +3226,94 @@ async fn restore(&mut self, state: SavedState) -> anyhow::Result<()> {
         Ok(())
     }
 
+    /// Starts live migrating the VM to the destination connected to
+    /// `socket`. Returns whether the migration was started.
+    ///
+    /// Memory is sent on a separate task, which uses `requests` to have the
+    /// VM paused and saved once pre-copy is done. On success, the VM is left
+    /// paused and should be torn down. On failure, the VM is resumed if it
+    /// was running.
+    fn start_migration(
+        &self,
+        driver: &impl Spawn,
+        rpc: FailableRpc<socket2::Socket, ()>,
+        requests: mesh::Sender<MigrateRequest>,
+    ) -> bool {
+        let (socket, rpc) = rpc.split();
+        let ranges = match migration_ranges(&self.inner.mem_layout) {
+            Ok(ranges) => ranges,
+            Err(err) => {
+                rpc.fail(err);
+                return false;
+            }
+        };
+        let socket = match PolledSocket::new(&self.inner.driver_source.simple(), socket)
+            .context("failed to create polled socket")
+        {
+            Ok(socket) => socket,
+            Err(err) => {
+                rpc.fail(err);
+                return false;
+            }
+        };
+        let gm = self.inner.gm.clone();
+        let partition = self.inner.partition.clone();
+        driver
+            .spawn("vmrpc-migrate", async move {
+                let mut paused = false;
+                let r = async {
+                    let mut sender = MigrationSender::new(socket, gm, ranges)
+                        .await
+                        .context("failed to start migration")?;
+                    if partition.supports_dirty_tracking() {
+                        sender.precopy(partition).await?;
+                    } else {
+                        tracing::info!(
+                            "dirty page tracking not supported, pausing VM for migration"
+                        );
+                    }
+                    let saved_state;
+                    (paused, saved_state) = requests
+                        .call_failable(MigrateRequest::PauseAndSave, ())
+                        .await
+                        .context("failed to pause and save the VM")?;
+                    sender.send_remaining_memory().await?;
+                    sender.send_saved_state(saved_state).await?;
+                    sender.wait_for_destination().await
+                }
+                .await;
+                requests.send(MigrateRequest::Complete {
+                    resume: r.is_err() && paused,
+                });
+                rpc.complete(r.map_err(RemoteError::new))
+            })
+            .detach();
+        true
+    }
+
+    /// Writes the VM's memory and saved state to `file`, pausing the VM while
+    /// doing so.
+    async fn save_snapshot(&mut self, file: File) -> anyhow::Result<()> {
+        let ranges = migration_ranges(&self.inner.mem_layout)?;
+        let paused = self.pause().await;
+        let r = async {
+            let mut sender = MigrationSender::new(
+                AllowStdIo::new(BufWriter::new(file)),
+                self.inner.gm.clone(),
+                ranges,
+            )
+            .await?;
+            sender.send_remaining_memory().await?;
+            let saved_state = self.save().await?;
+            sender.send_saved_state(saved_state).await
+        }
+        .await;
+        if paused {
+            self.resume().await;
+        }
+        r
+    }
+
     /// Do a save, reset, restore.
     async fn save_reset_restore(&mut self) -> anyhow::Result<()> {
         let state = self.save().await?;
@@ -3120,6 +3371,8 @@ async fn serialize(
             generation_id_recv: None,  // TODO
             rtc_delta_milliseconds: 0, // TODO
             automatic_guest_reset: self.inner.automatic_guest_reset,
+            vss_ic: self.inner.vss_ic,
+            layered_disks: self.inner.layered_disks,
             efi_diagnostics_log_level: Default::default(),
         };
         RestartState {
@@ -0,0 +1,539 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Live migration of a VM's memory and state over a byte stream.
+//!
+//! Memory is transferred with iterative pre-copy: the source sends all of RAM
+//! while the VM keeps running, then repeatedly resends the pages that were
+//! written during the previous round. Once few enough pages remain dirty, the
+//! VM is paused, the remaining dirty pages are sent, and the VM's
+//! [`SavedState`] follows as a `mesh_protobuf` message.
+//!
+//! The stream starts with a [`StreamHeader`] and the RAM ranges being
+//! migrated, followed by a sequence of records, each starting with a
+//! [`RecordHeader`]. The saved state record is always last. Once the
+//! destination has restored the VM, it replies with a single status byte so
+//! that the source can resume the VM if the destination failed.
+//!
+//! Snapshot files use the same format, without pre-copy or the status byte.
+
+use super::dispatch::SavedState;
+use crate::partition::HvlitePartition;
+use anyhow::Context;
+use futures::AsyncRead;
+use futures::AsyncReadExt;
+use futures::AsyncWrite;
+use futures::AsyncWriteExt;
+use guestmem::GuestMemory;
+use memory_range::MemoryRange;
+use std::sync::Arc;
+use vm_topology::memory::MemoryLayout;
+use zerocopy::FromBytes;
+use zerocopy::FromZeros;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+const PAGE_SIZE: u64 = guestmem::PAGE_SIZE as u64;
+
+const MAGIC: [u8; 8] = *b"OVMMMIGR";
+const VERSION: u32 = 1;
+
+/// Followed by `page_count` pages of data.
+const RECORD_PAGES: u32 = 1;
+/// Pages that are all zeroes, with no data.
+const RECORD_ZERO_PAGES: u32 = 2;
+/// Followed by `value` bytes of encoded [`SavedState`].
+const RECORD_SAVED_STATE: u32 = 3;
+
+const STATUS_SUCCESS: u8 = 0;
+const STATUS_FAILURE: u8 = 1;
+
+/// The maximum number of pre-copy rounds before the VM is paused, even if the
+/// guest is dirtying memory faster than it can be sent.
+const MAX_PRECOPY_ROUNDS: u32 = 30;
+
+/// Pre-copy stops once fewer than this many pages are dirty, since those can
+/// be sent quickly enough with the VM paused.
+const STOP_COPY_THRESHOLD_PAGES: u64 = 4096;
+
+/// The maximum number of pages described by a single record.
+const MAX_RECORD_PAGES: u64 = 256;
+
+/// The maximum size of the saved state accepted by the destination.
+const MAX_SAVED_STATE_SIZE: u64 = 256 * 1024 * 1024;
+
+#[repr(C)]
+#[derive(IntoBytes, FromBytes, Immutable, KnownLayout)]
+struct StreamHeader {
+    magic: [u8; 8],
+    version: u32,
+    range_count: u32,
+}
+
+#[repr(C)]
+#[derive(IntoBytes, FromBytes, Immutable, KnownLayout)]
+struct RangeDescriptor {
+    start: u64,
+    end: u64,
+}
+
+#[repr(C)]
+#[derive(IntoBytes, FromBytes, Immutable, KnownLayout)]
+struct RecordHeader {
+    kind: u32,
+    page_count: u32,
+    /// The guest physical address of the pages, or the length of the saved
+    /// state.
+    value: u64,
+}
+
+/// Returns the guest memory ranges to migrate.
+pub(crate) fn migration_ranges(layout: &MemoryLayout) -> anyhow::Result<Vec<MemoryRange>> {
+    if layout.vtl2_range().is_some() {
+        anyhow::bail!("migrating VMs with VTL2 memory is not supported");
+    }
+    Ok(layout.ram().iter().map(|r| r.range).collect())
+}
+
+/// The source side of a migration, writing to `S`.
+pub(crate) struct MigrationSender<S> {
+    stream: S,
+    gm: GuestMemory,
+    ranges: Vec<MemoryRange>,
+    /// The pages to send in the next round, one bitmap per range.
+    bitmaps: Vec<Vec<u64>>,
+    /// The partition, while dirty page tracking is enabled for pre-copy.
+    dirty_tracking: Option<Arc<dyn HvlitePartition>>,
+    buf: Vec<u8>,
+    pages_sent: u64,
+}
+
+impl<S: AsyncWrite + Unpin> MigrationSender<S> {
+    /// Starts a migration of the memory in `ranges` to `stream`.
+    pub async fn new(
+        mut stream: S,
+        gm: GuestMemory,
+        ranges: Vec<MemoryRange>,
+    ) -> anyhow::Result<Self> {
+        let header = StreamHeader {
+            magic: MAGIC,
+            version: VERSION,
+            range_count: ranges.len() as u32,
+        };
+        stream.write_all(header.as_bytes()).await?;
+        for range in &ranges {
+            let desc = RangeDescriptor {
+                start: range.start(),
+                end: range.end(),
+            };
+            stream.write_all(desc.as_bytes()).await?;
+        }
+
+        let bitmaps = ranges
+            .iter()
+            .map(|range| vec![0; range.page_count_4k().div_ceil(64) as usize])
+            .collect();
+
+        Ok(Self {
+            stream,
+            gm,
+            ranges,
+            bitmaps,
+            dirty_tracking: None,
+            buf: Vec::new(),
+            pages_sent: 0,
+        })
+    }
+
+    /// Sends memory while the VM is running, until the amount of memory
+    /// dirtied in each round is small enough to send with the VM paused.
+    ///
+    /// The caller must check that `partition` supports dirty page tracking.
+    pub async fn precopy(&mut self, partition: Arc<dyn HvlitePartition>) -> anyhow::Result<()> {
+        partition
+            .set_dirty_tracking(true)
+            .context("failed to enable dirty page tracking")?;
+        self.gm.enable_dirty_tracking(&self.ranges);
+        self.dirty_tracking = Some(partition);
+
+        self.mark_all();
+        let mut sent = self.send_pages(true).await?;
+        for round in 1..=MAX_PRECOPY_ROUNDS {
+            let dirty = self.collect_dirty()?;
+            tracing::debug!(round, sent, dirty, "pre-copy round complete");
+            if dirty < STOP_COPY_THRESHOLD_PAGES {
+                break;
+            }
+            sent = self.send_pages(false).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the memory that has not yet been sent. The VM must be paused.
+    pub async fn send_remaining_memory(&mut self) -> anyhow::Result<()> {
+        if self.dirty_tracking.is_some() {
+            self.collect_dirty()?;
+            self.send_pages(false).await?;
+        } else {
+            self.mark_all();
+            self.send_pages(true).await?;
+        }
+        tracing::info!(
+            pages_sent = self.pages_sent,
+            "migration memory transfer complete"
+        );
+        Ok(())
+    }
+
+    /// Sends the saved state, which completes the stream.
+    pub async fn send_saved_state(&mut self, state: SavedState) -> anyhow::Result<()> {
+        let data = mesh::payload::encode(state);
+        let header = RecordHeader {
+            kind: RECORD_SAVED_STATE,
+            page_count: 0,
+            value: data.len() as u64,
+        };
+        self.stream.write_all(header.as_bytes()).await?;
+        self.stream.write_all(&data).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    fn mark_all(&mut self) {
+        for (range, bitmap) in self.ranges.iter().zip(&mut self.bitmaps) {
+            bitmap.fill(!0);
+            let extra = bitmap.len() as u64 * 64 - range.page_count_4k();
+            if extra != 0 {
+                *bitmap.last_mut().unwrap() >>= extra;
+            }
+        }
+    }
+
+    /// Adds the pages dirtied since the last call to the pages to send.
+    /// Returns the number of pages to send.
+    fn collect_dirty(&mut self) -> anyhow::Result<u64> {
+        let partition = self
+            .dirty_tracking
+            .as_ref()
+            .expect("dirty tracking should be enabled");
+        for (&range, bitmap) in self.ranges.iter().zip(&mut self.bitmaps) {
+            partition.take_dirty_bitmap(range, bitmap)?;
+            self.gm.take_dirty_bitmap(range, bitmap);
+        }
+        Ok(self
+            .bitmaps
+            .iter()
+            .flatten()
+            .map(|w| w.count_ones() as u64)
+            .sum())
+    }
+
+    /// Sends the marked pages and clears the bitmaps. If `skip_zero`, pages
+    /// that are all zeroes are not sent at all, since the destination's memory
+    /// starts zeroed.
+    ///
+    /// Returns the number of pages sent.
+    async fn send_pages(&mut self, skip_zero: bool) -> anyhow::Result<u64> {
+        let mut sent = 0;
+        for i in 0..self.ranges.len() {
+            let range = self.ranges[i];
+            let page_count = range.page_count_4k();
+            let mut page = 0;
+            while page < page_count {
+                if !is_set(&self.bitmaps[i], page) {
+                    page += 1;
+                    continue;
+                }
+                let start = page;
+                while page < page_count
+                    && page - start < MAX_RECORD_PAGES
+                    && is_set(&self.bitmaps[i], page)
+                {
+                    page += 1;
+                }
+                self.send_run(range.start() + start * PAGE_SIZE, page - start, skip_zero)
+                    .await?;
+                sent += page - start;
+            }
+            self.bitmaps[i].fill(0);
+        }
+        self.pages_sent += sent;
+        Ok(sent)
+    }
+
+    /// Sends `count` contiguous pages starting at `gpa`, using zero page
+    /// records for runs of pages that are all zeroes.
+    async fn send_run(&mut self, gpa: u64, count: u64, skip_zero: bool) -> anyhow::Result<()> {
+        self.buf.resize((count * PAGE_SIZE) as usize, 0);
+        self.gm
+            .read_at(gpa, &mut self.buf)
+            .context("failed to read guest memory")?;
+
+        let is_zero = |page: u64| {
+            let offset = (page * PAGE_SIZE) as usize;
+            self.buf[offset..offset + PAGE_SIZE as usize]
+                .iter()
+                .all(|&b| b == 0)
+        };
+        let mut page = 0;
+        while page < count {
+            let start = page;
+            let zero = is_zero(page);
+            while page < count && is_zero(page) == zero {
+                page += 1;
+            }
+            if zero && skip_zero {
+                continue;
+            }
+            let header = RecordHeader {
+                kind: if zero {
+                    RECORD_ZERO_PAGES
+                } else {
+                    RECORD_PAGES
+                },
+                page_count: (page - start) as u32,
+                value: gpa + start * PAGE_SIZE,
+            };
+            self.stream.write_all(header.as_bytes()).await?;
+            if !zero {
+                self.stream
+                    .write_all(&self.buf[(start * PAGE_SIZE) as usize..(page * PAGE_SIZE) as usize])
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> MigrationSender<S> {
+    /// Waits for the destination to report that it has restored the VM.
+    pub async fn wait_for_destination(&mut self) -> anyhow::Result<()> {
+        let mut status = [0];
+        self.stream
+            .read_exact(&mut status)
+            .await
+            .context("no response from migration destination")?;
+        if status[0] != STATUS_SUCCESS {
+            anyhow::bail!("migration destination failed to restore the VM");
+        }
+        Ok(())
+    }
+}
+
+impl<S> Drop for MigrationSender<S> {
+    fn drop(&mut self) {
+        if let Some(partition) = &self.dirty_tracking {
+            self.gm.disable_dirty_tracking();
+            if let Err(err) = partition.set_dirty_tracking(false) {
+                tracing::error!(
+                    error = err.as_ref() as &dyn std::error::Error,
+                    "failed to disable dirty page tracking"
+                );
+            }
+        }
+    }
+}
+
+fn is_set(bitmap: &[u64], n: u64) -> bool {
+    bitmap[(n / 64) as usize] & (1 << (n % 64)) != 0
+}
+
+/// The destination side of a migration, reading from `S`.
+pub(crate) struct MigrationReceiver<S> {
+    stream: S,
+    ranges: Vec<MemoryRange>,
+    buf: Vec<u8>,
+}
+
+impl<S: AsyncRead + Unpin> MigrationReceiver<S> {
+    /// Accepts a migration from `stream`, checking that the source's memory
+    /// layout matches `ranges`.
+    pub async fn new(mut stream: S, ranges: Vec<MemoryRange>) -> anyhow::Result<Self> {
+        let mut header = StreamHeader::new_zeroed();
+        stream
+            .read_exact(header.as_mut_bytes())
+            .await
+            .context("failed to read migration stream header")?;
+        if header.magic != MAGIC {
+            anyhow::bail!("not a migration stream");
+        }
+        if header.version != VERSION {
+            anyhow::bail!("unsupported migration stream version {}", header.version);
+        }
+        if header.range_count as usize != ranges.len() {
+            anyhow::bail!(
+                "memory layout mismatch: source has {} ranges, expected {}",
+                header.range_count,
+                ranges.len()
+            );
+        }
+        for range in &ranges {
+            let mut desc = RangeDescriptor::new_zeroed();
+            stream.read_exact(desc.as_mut_bytes()).await?;
+            if desc.start != range.start() || desc.end != range.end() {
+                anyhow::bail!(
+                    "memory layout mismatch: source has range {:#x}-{:#x}, expected {range}",
+                    desc.start,
+                    desc.end
+                );
+            }
+        }
+
+        Ok(Self {
+            stream,
+            ranges,
+            buf: Vec::new(),
+        })
+    }
+
+    /// Receives guest memory into `gm` until the saved state arrives, and
+    /// returns the saved state.
+    pub async fn receive(&mut self, gm: &GuestMemory) -> anyhow::Result<SavedState> {
+        let mut pages_received = 0;
+        loop {
+            let mut header = RecordHeader::new_zeroed();
+            self.stream
+                .read_exact(header.as_mut_bytes())
+                .await
+                .context("failed to read migration record")?;
+
+            match header.kind {
+                RECORD_PAGES | RECORD_ZERO_PAGES => {
+                    let gpa = header.value;
+                    let count = header.page_count as u64;
+                    let len = count * PAGE_SIZE;
+                    if count == 0
+                        || count > MAX_RECORD_PAGES
+                        || gpa % PAGE_SIZE != 0
+                        || !gpa.checked_add(len).is_some_and(|end| {
+                            self.ranges
+                                .iter()
+                                .any(|r| r.start() <= gpa && end <= r.end())
+                        })
+                    {
+                        anyhow::bail!("invalid page record at {gpa:#x}, {count} pages");
+                    }
+                    if header.kind == RECORD_PAGES {
+                        self.buf.resize(len as usize, 0);
+                        self.stream
+                            .read_exact(&mut self.buf)
+                            .await
+                            .context("failed to read page data")?;
+                        gm.write_at(gpa, &self.buf)?;
+                    } else {
+                        gm.fill_at(gpa, 0, len as usize)?;
+                    }
+                    pages_received += count;
+                }
+                RECORD_SAVED_STATE => {
+                    let len = header.value;
+                    if len > MAX_SAVED_STATE_SIZE {
+                        anyhow::bail!("saved state too large: {len} bytes");
+                    }
+                    let mut data = vec![0; len as usize];
+                    self.stream
+                        .read_exact(&mut data)
+                        .await
+                        .context("failed to read saved state")?;
+                    let state = mesh::payload::decode(&data)
+                        .context("failed to decode migrated saved state")?;
+                    tracing::info!(pages_received, "migration memory transfer complete");
+                    return Ok(state);
+                }
+                kind => anyhow::bail!("unknown migration record type {kind}"),
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> MigrationReceiver<S> {
+    /// Reports to the source whether the VM was restored.
+    pub async fn complete(mut self, success: bool) -> anyhow::Result<()> {
+        let status = if success {
+            STATUS_SUCCESS
+        } else {
+            STATUS_FAILURE
+        };
+        self.stream.write_all(&[status]).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pal_async::async_test;
+
+    const RANGES: [MemoryRange; 2] = [
+        MemoryRange::new(0..0x10000),
+        MemoryRange::new(0x100000..0x110000),
+    ];
+
+    async fn send(gm: &GuestMemory) -> Vec<u8> {
+        let mut sender = MigrationSender::new(Vec::new(), gm.clone(), RANGES.to_vec())
+            .await
+            .unwrap();
+        sender.send_remaining_memory().await.unwrap();
+        sender
+            .send_saved_state(SavedState { units: Vec::new() })
+            .await
+            .unwrap();
+        std::mem::take(&mut sender.stream)
+    }
+
+    #[async_test]
+    async fn test_roundtrip() {
+        let src = GuestMemory::allocate(0x110000);
+        // A partial page, a run of pages, and a page at the end of the second
+        // range.
+        src.write_at(0x1008, &[1, 2, 3]).unwrap();
+        src.fill_at(0x3000, 0xaa, 0x3000).unwrap();
+        src.write_at(0x10fff8, &[4; 8]).unwrap();
+        // Memory outside the ranges is not sent.
+        src.write_at(0x20000, &[5]).unwrap();
+        let stream = send(&src).await;
+
+        let dst = GuestMemory::allocate(0x110000);
+        let mut receiver = MigrationReceiver::new(stream.as_slice(), RANGES.to_vec())
+            .await
+            .unwrap();
+        let state = receiver.receive(&dst).await.unwrap();
+        assert!(state.units.is_empty());
+
+        for range in RANGES {
+            let mut expected = vec![0; range.len() as usize];
+            let mut actual = vec![0; range.len() as usize];
+            src.read_at(range.start(), &mut expected).unwrap();
+            dst.read_at(range.start(), &mut actual).unwrap();
+            assert!(expected == actual, "mismatch in {range}");
+        }
+        assert_eq!(dst.read_plain::<u8>(0x20000).unwrap(), 0);
+    }
+
+    #[async_test]
+    async fn test_layout_mismatch() {
+        let stream = send(&GuestMemory::allocate(0x110000)).await;
+        assert!(
+            MigrationReceiver::new(stream.as_slice(), RANGES[..1].to_vec())
+                .await
+                .is_err()
+        );
+        assert!(
+            MigrationReceiver::new(
+                stream.as_slice(),
+                vec![RANGES[0], MemoryRange::new(0x100000..0x120000)]
+            )
+            .await
+            .is_err()
+        );
+        let mut bad_magic = stream.clone();
+        bad_magic[0] ^= 1;
+        assert!(
+            MigrationReceiver::new(bad_magic.as_slice(), RANGES.to_vec())
+                .await
+                .is_err()
+        );
+    }
+}
@@ -2,5 +2,6 @@
 // Licensed under the MIT License.
 
 pub mod dispatch;
+mod migrate;
 mod rom;
 pub mod vm_loaders;
@@ -55,6 +55,13 @@ pub struct Config {
     pub vmbus_devices: Vec<(DeviceVtl, Resource<VmbusDeviceHandleKind>)>,
     pub chipset_devices: Vec<ChipsetDeviceHandle>,
     pub generation_id_recv: Option<mesh::Receiver<[u8; 16]>>,
+    /// The channel to the guest's VSS IC, used to freeze and thaw the guest's
+    /// filesystems via [`VmRpc::FreezeGuest`](crate::rpc::VmRpc::FreezeGuest).
+    pub vss_ic: Option<mesh::Sender<hyperv_ic_resources::vss::VssRpc>>,
+    /// The request channels of the layered disks whose top layer can be
+    /// merged at runtime, via
+    /// [`VmRpc::MergeDiskLayer`](crate::rpc::VmRpc::MergeDiskLayer).
+    pub layered_disks: Vec<mesh::Sender<disk_backend_resources::LayeredDiskRequest>>,
     // This is used for testing. TODO: resourcify, and also store this in VMGS.
     pub rtc_delta_milliseconds: i64,
     /// allow the guest to reset without notifying the client
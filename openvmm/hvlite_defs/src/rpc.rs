@@ -31,6 +31,21 @@ pub enum VmRpc {
     CompleteReloadIgvm(FailableRpc<bool, ()>),
     ReadMemory(FailableRpc<(u64, usize), Vec<u8>>),
     WriteMemory(FailableRpc<(u64, Vec<u8>), ()>),
+    /// Live migrates the VM to the destination connected to the socket. On
+    /// success, the VM is left paused and should be torn down.
+    Migrate(FailableRpc<socket2::Socket, ()>),
+    /// Writes the VM's memory and saved state to the file, pausing the VM
+    /// while doing so.
+    SaveSnapshot(FailableRpc<File, ()>),
+    /// Asks the guest to freeze its applications and filesystems via the VSS
+    /// IC, so that the VM's disks can be snapshotted consistently.
+    FreezeGuest(FailableRpc<(), ()>),
+    /// Thaws a guest previously frozen with [`VmRpc::FreezeGuest`].
+    ThawGuest(FailableRpc<(), ()>),
+    /// Merges the top layer of a layered disk into the layer below it. The
+    /// input is an index into
+    /// [`Config::layered_disks`](crate::config::Config::layered_disks).
+    MergeDiskLayer(FailableRpc<usize, ()>),
 }
 
 #[derive(Debug, MeshPayload, thiserror::Error)]
@@ -63,6 +78,11 @@ fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
             VmRpc::CompleteReloadIgvm(_) => "CompleteReloadIgvm",
             VmRpc::ReadMemory(_) => "ReadMemory",
             VmRpc::WriteMemory(_) => "WriteMemory",
+            VmRpc::Migrate(_) => "Migrate",
+            VmRpc::SaveSnapshot(_) => "SaveSnapshot",
+            VmRpc::FreezeGuest(_) => "FreezeGuest",
+            VmRpc::ThawGuest(_) => "ThawGuest",
+            VmRpc::MergeDiskLayer(_) => "MergeDiskLayer",
         };
         f.pad(s)
     }
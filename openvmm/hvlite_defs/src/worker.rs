@@ -9,6 +9,7 @@
 use mesh::MeshPayload;
 use mesh::payload::message::ProtobufMessage;
 use mesh_worker::WorkerId;
+use std::fs::File;
 use vmm_core_defs::HaltReason;
 
 pub const VM_WORKER: WorkerId<VmWorkerParameters> = WorkerId::new("VmWorker");
@@ -22,6 +23,12 @@ pub struct VmWorkerParameters {
     pub cfg: Config,
     /// The saved state.
     pub saved_state: Option<ProtobufMessage>,
+    /// A connection from a live migration source. If set, the VM's memory and
+    /// saved state are received over this connection instead of `saved_state`.
+    pub incoming: Option<socket2::Socket>,
+    /// A snapshot file to restore the VM from, positioned at the start of the
+    /// VM's memory and saved state.
+    pub restore_from: Option<File>,
     /// The VM RPC channel.
     pub rpc: mesh::Receiver<VmRpc>,
     /// The notification channel.
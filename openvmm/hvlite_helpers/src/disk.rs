@@ -10,8 +10,15 @@
 /// Opens the resources needed for using a disk from a file at `path`.
 ///
 /// If the file ends with .vhd and is a fixed VHD1, it will be opened using
-/// the user-mode VHD parser. Otherwise, if the file ends with .vhd or
-/// .vhdx, the file will be opened using the kernel-mode VHD parser.
+/// the user-mode VHD parser. Other VHDs are opened using the kernel-mode VHD
+/// parser on Windows. Elsewhere, they are opened as a layered disk using the
+/// user-mode VHD1 parser, with a layer for each disk in the differencing
+/// chain. If the file ends with .vhdx, the file will be opened using the
+/// kernel-mode VHD parser on Windows. Elsewhere, it is opened as a layered
+/// disk using the user-mode VHDX parser, with a layer for each disk in the
+/// differencing chain. If the file ends with .qcow2, it will be opened as a
+/// layered disk using the user-mode qcow2 parser, with a layer for each image
+/// in the backing file chain.
 pub fn open_disk_type(path: &Path, read_only: bool) -> anyhow::Result<Resource<DiskHandleKind>> {
     Ok(match path.extension().and_then(|s| s.to_str()) {
         Some("vhd") => {
@@ -32,7 +39,18 @@ pub fn open_disk_type(path: &Path, read_only: bool) -> anyhow::Result<Resource<D
                         ))
                     }
                     #[cfg(not(windows))]
-                    anyhow::bail!("non-fixed VHD not supported on Linux");
+                    {
+                        Resource::new(disk_image_layer::layered_disk_handle(
+                            disk_vhd1::Vhd1Layer::open_chain(path, read_only)?,
+                            read_only,
+                            |file, read_only| {
+                                Resource::new(disk_backend_resources::layer::Vhd1DiskLayerHandle {
+                                    file,
+                                    read_only,
+                                })
+                            },
+                        ))
+                    }
                 }
                 Err(err) => return Err(err.into()),
             }
@@ -45,8 +63,20 @@ pub fn open_disk_type(path: &Path, read_only: bool) -> anyhow::Result<Resource<D
                 ))
             }
             #[cfg(not(windows))]
-            anyhow::bail!("VHDX not supported on Linux");
+            {
+                Resource::new(disk_image_layer::layered_disk_handle(
+                    disk_vhdx::VhdxLayer::open_chain(path, read_only)?,
+                    read_only,
+                    |file, read_only| {
+                        Resource::new(disk_backend_resources::layer::VhdxDiskLayerHandle {
+                            file,
+                            read_only,
+                        })
+                    },
+                ))
+            }
         }
+        Some("qcow2") => Resource::new(disk_qcow2::Qcow2Layer::open_chain(path, read_only)?),
         Some("iso") if !read_only => {
             anyhow::bail!("iso file cannot be opened as read/write")
         }
@@ -87,7 +117,37 @@ pub fn create_disk_type(path: &Path, size: u64) -> anyhow::Result<Resource<DiskH
             Resource::new(disk_backend_resources::FixedVhd1DiskHandle(file))
         }
         Some("vhdx") => {
-            anyhow::bail!("creating vhdx not supported")
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .read(true)
+                .write(true)
+                .open(path)?;
+
+            disk_vhdx::VhdxLayer::make_dynamic(&file, size)?;
+            Resource::new(disk_backend_resources::LayeredDiskHandle::single_layer(
+                disk_backend_resources::layer::VhdxDiskLayerHandle {
+                    file,
+                    read_only: false,
+                },
+            ))
+        }
+        Some("qcow2") => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .read(true)
+                .write(true)
+                .open(path)?;
+
+            disk_qcow2::Qcow2Layer::create(&file, size)?;
+            Resource::new(disk_backend_resources::LayeredDiskHandle::single_layer(
+                disk_backend_resources::layer::Qcow2DiskLayerHandle {
+                    file,
+                    read_only: false,
+                    backing: None,
+                },
+            ))
         }
         Some("iso") => {
             anyhow::bail!("creating iso not supported")
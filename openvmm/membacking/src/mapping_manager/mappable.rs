@@ -31,6 +31,12 @@ fn from(value: Arc<OwnedType>) -> Self {
     }
 }
 
+impl From<Mappable> for Arc<OwnedType> {
+    fn from(value: Mappable) -> Self {
+        value.0
+    }
+}
+
 impl From<Mappable> for OwnedType {
     fn from(value: Mappable) -> Self {
         // Currently there is no way to avoid the unwrap here. Mesh improvements
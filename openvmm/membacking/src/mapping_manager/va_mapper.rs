@@ -30,6 +30,7 @@
 use crate::RemoteProcess;
 use futures::executor::block_on;
 use guestmem::GuestMemoryAccess;
+use guestmem::GuestMemoryBackingError;
 use guestmem::PageFaultAction;
 use guestmem::PageFaultError;
 use memory_range::MemoryRange;
@@ -303,4 +304,13 @@ fn page_fault(
         }
         PageFaultAction::Retry
     }
+
+    fn decommit(&self, address: u64, len: u64) -> Result<(), GuestMemoryBackingError> {
+        // The guest RAM mappings are backed by shared memory, so this releases
+        // the pages from the underlying memory object, not just this view.
+        self.inner
+            .mapping
+            .decommit(address as usize, len as usize)
+            .map_err(|err| GuestMemoryBackingError::other(address, err))
+    }
 }
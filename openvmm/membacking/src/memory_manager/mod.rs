@@ -27,6 +27,8 @@
 use std::thread::JoinHandle;
 use thiserror::Error;
 use vm_topology::memory::MemoryLayout;
+use vmcore::shared_ram::SharedGuestRam;
+use vmcore::shared_ram::SharedRamRange;
 
 /// The HvLite memory manager.
 #[derive(Debug, Inspect)]
@@ -359,6 +361,33 @@ pub fn shared_memory_backing(&self) -> SharedMemoryBacking {
         SharedMemoryBacking { guest_ram }
     }
 
+    /// Returns the memory object backing guest RAM and the location of each
+    /// RAM range within it, so that guest RAM can be shared with device
+    /// back-ends in other processes.
+    ///
+    /// Adjacent RAM ranges are merged.
+    pub fn shared_guest_ram(&self) -> SharedGuestRam {
+        let mut ranges = Vec::<SharedRamRange>::new();
+        let mut offset = 0;
+        for region in self.ram_regions.iter() {
+            let range = region.range;
+            match ranges.last_mut() {
+                Some(last) if last.gpa + last.len == range.start() => last.len += range.len(),
+                _ => ranges.push(SharedRamRange {
+                    gpa: range.start(),
+                    len: range.len(),
+                    offset,
+                }),
+            }
+            // RAM ranges are allocated consecutively from the backing memory.
+            offset += range.len();
+        }
+        SharedGuestRam {
+            memory: self.guest_ram.clone().into(),
+            ranges,
+        }
+    }
+
     /// Attaches the guest memory to a partition, mapping it to the guest
     /// physical address space.
     ///
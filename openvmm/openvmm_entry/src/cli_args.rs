@@ -28,6 +28,7 @@
 use hvlite_defs::config::Vtl2BaseAddressType;
 use hvlite_defs::config::X2ApicConfig;
 use std::ffi::OsString;
+use std::net::Ipv4Addr;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::str::FromStr;
@@ -145,6 +146,7 @@ pub struct Options {
     `vtl2`                         assign this disk to VTL2
     `uh`                           relay this disk to VTL0 through SCSI-to-OpenHCL (show to VTL0 as SCSI)
     `uh-nvme`                      relay this disk to VTL0 through NVMe-to-OpenHCL (show to VTL0 as SCSI)
+    `merge`                        open the lower layers of a diff disk for write, so that the top layer can be merged into them with `merge-disk`
 "#)]
     #[clap(long, value_name = "FILE")]
     pub disk: Vec<DiskCli>,
@@ -168,6 +170,7 @@ pub struct Options {
     `vtl2`                         assign this disk to VTL2
     `uh`                           relay this disk to VTL0 through SCSI-to-OpenHCL (show to VTL0 as NVMe)
     `uh-nvme`                      relay this disk to VTL0 through NVMe-to-OpenHCL (show to VTL0 as NVMe)
+    `merge`                        open the lower layers of a diff disk for write, so that the top layer can be merged into them with `merge-disk`
 
 options:
     `pcie_port=<name>`             present the disk using pcie under the specified port, incompatible with `vtl2`, `uh`, and `uh-nvme`
@@ -183,10 +186,23 @@ pub struct Options {
     #[clap(long)]
     pub nic: bool,
 
-    /// expose a virtual NIC with the given backend (consomme | dio | tap | none)
+    /// expose a virtual NIC with the given backend (consomme | dio | tap |
+    /// switch | vhost-user | none)
     ///
     /// Prefix with `uh:` to add this NIC via Mana emulation through OpenHCL,
     /// or `vtl2:` to assign this NIC to VTL2.
+    ///
+    /// `consomme` takes comma-separated options: an optional network CIDR,
+    /// and `hostfwd=[tcp|udp]:[hostaddr]:hostport-:guestport` rules to forward
+    /// host ports to the guest, e.g.
+    /// `consomme:10.0.0.0/24,hostfwd=tcp:127.0.0.1:2222-:22`.
+    ///
+    /// `switch:<path>` connects to a user-mode switch listening on the Unix
+    /// socket at `path` (see `--net-switch`).
+    ///
+    /// `vhost-user:<path>` connects to a vhost-user back-end (such as DPDK or
+    /// Open vSwitch) listening on the Unix socket at `path`. Guest RAM is
+    /// shared with the back-end.
     #[clap(long)]
     pub net: Vec<NicConfigCli>,
 
@@ -340,6 +356,64 @@ pub struct Options {
     #[clap(long, value_name = "PATH")]
     pub virtio_pmem: Option<String>,
 
+    /// add a virtio balloon device, allowing guest memory to be reclaimed
+    /// with the `balloon` console command
+    #[clap(long)]
+    pub virtio_balloon: bool,
+
+    /// add a virtio entropy device, providing the guest with random data from
+    /// the host
+    #[clap(long)]
+    pub virtio_rng: bool,
+
+    /// the maximum rate, in bytes per second, at which the virtio entropy
+    /// device provides random data
+    #[clap(long, value_name = "BYTES", requires("virtio_rng"), value_parser = clap::value_parser!(u64).range(1..))]
+    pub virtio_rng_rate: Option<u64>,
+
+    /// add the virtio entropy device under either the PCI or MMIO bus, or whatever the hypervisor supports (pci | mmio | auto)
+    #[clap(
+        long,
+        value_name = "BUS",
+        default_value = "auto",
+        requires("virtio_rng")
+    )]
+    pub virtio_rng_bus: VirtioBusCli,
+
+    /// add a virtio vsock device, using the hybrid vsock listener at PATH
+    /// (guest connections to port N are relayed to PATH_N)
+    #[clap(long, value_name = "PATH")]
+    pub virtio_vsock_path: Option<String>,
+
+    /// the guest context ID for the virtio vsock device
+    #[clap(
+        long,
+        value_name = "CID",
+        default_value = "3",
+        requires("virtio_vsock_path")
+    )]
+    pub virtio_vsock_cid: u64,
+
+    /// attach a disk via a virtio-blk device
+    #[clap(long_help = r#"
+e.g: --virtio-disk memdiff:file:/path/to/disk.img
+
+syntax: <path> | kind:<arg>[,flag]
+
+valid disk kinds:
+    `mem:<len>`                    memory backed disk
+        <len>: length of ramdisk, e.g.: `1G`
+    `memdiff:<disk>`               memory backed diff disk
+        <disk>: lower disk, e.g.: `file:base.img`
+    `file:<path>`                  file-backed disk
+        <path>: path to file
+
+flags:
+    `ro`                           open disk as read-only
+"#)]
+    #[clap(long)]
+    pub virtio_disk: Vec<DiskCli>,
+
     /// expose a virtio network with the given backend (dio | vmnic | tap |
     /// none)
     ///
@@ -360,6 +434,11 @@ pub struct Options {
     #[clap(long, value_name = "SOCKETPATH", conflicts_with("ttrpc"))]
     pub grpc: Option<PathBuf>,
 
+    /// run as a user-mode Ethernet switch on the specified Unix socket,
+    /// which VMs can connect to with `--net switch:<path>`
+    #[clap(long, value_name = "SOCKETPATH", conflicts_with_all(&["ttrpc", "grpc"]))]
+    pub net_switch: Option<PathBuf>,
+
     /// do not launch child processes
     #[clap(long)]
     pub single_process: bool,
@@ -537,6 +616,17 @@ pub struct Options {
     #[clap(long)]
     pub write_saved_state_proto: Option<PathBuf>,
 
+    /// wait for an incoming live migration on the specified address
+    /// (\<path\> | tcp:\<ip\>:\<port\>) instead of booting the VM
+    #[clap(long, value_name = "ADDR", conflicts_with("restore_from"))]
+    pub incoming: Option<MigrationAddress>,
+
+    /// restore the VM from a snapshot file written by the `save` command
+    /// instead of booting the VM. The VM must be configured with the same
+    /// memory and disks as when the snapshot was taken.
+    #[clap(long, value_name = "PATH")]
+    pub restore_from: Option<PathBuf>,
+
     /// specify the IMC hive file for booting Windows
     #[clap(long)]
     pub imc: Option<PathBuf>,
@@ -703,7 +793,7 @@ pub enum SecureBootTemplateCli {
     UefiCa,
 }
 
-fn parse_memory(s: &str) -> anyhow::Result<u64> {
+pub fn parse_memory(s: &str) -> anyhow::Result<u64> {
     if s == "VMGS_DEFAULT" {
         Ok(vmgs_format::VMGS_DEFAULT_CAPACITY)
     } else {
@@ -786,6 +876,17 @@ pub enum DiskCliKind {
         delay_ms: u64,
         disk: Box<DiskCliKind>,
     },
+    // cache:<size>[;file=<path>]:<kind>
+    WriteBackCache {
+        size: u64,
+        file: Option<PathBuf>,
+        disk: Box<DiskCliKind>,
+    },
+    // throttle:<limit>=<value>[;<limit>=<value>...]:<kind>
+    Throttle {
+        limits: disk_backend_resources::ThrottleLimits,
+        disk: Box<DiskCliKind>,
+    },
 }
 
 #[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
@@ -800,6 +901,23 @@ pub enum BlobKind {
     Vhd1,
 }
 
+fn parse_throttle_limits(arg: &str) -> anyhow::Result<disk_backend_resources::ThrottleLimits> {
+    let mut limits = disk_backend_resources::ThrottleLimits::default();
+    for limit in arg.split(';') {
+        let (name, value) = limit
+            .split_once('=')
+            .with_context(|| format!("expected limit=value, got '{limit}'"))?;
+        match name {
+            "riops" => limits.read_iops = Some(value.parse()?),
+            "wiops" => limits.write_iops = Some(value.parse()?),
+            "rbps" => limits.read_bytes_per_sec = Some(parse_memory(value)?),
+            "wbps" => limits.write_bytes_per_sec = Some(parse_memory(value)?),
+            _ => anyhow::bail!("unknown limit '{name}', expected riops, wiops, rbps, or wbps"),
+        }
+    }
+    Ok(limits)
+}
+
 fn parse_path_and_len(arg: &str) -> anyhow::Result<(PathBuf, Option<u64>)> {
     Ok(match arg.split_once(';') {
         Some((path, len)) => {
@@ -871,6 +989,34 @@ fn from_str(s: &str) -> anyhow::Result<Self> {
                     }
                 }
                 "prwrap" => DiskCliKind::PersistentReservationsWrapper(Box::new(arg.parse()?)),
+                "cache" => {
+                    let (size_and_opts, kind) = arg
+                        .split_once(':')
+                        .context("expected size[;file=<path>]:kind")?;
+                    let (size, file) = match size_and_opts.split_once(';') {
+                        Some((size, file)) => {
+                            let Some(file) = file.strip_prefix("file=") else {
+                                anyhow::bail!("invalid syntax after ';', expected 'file=<path>'")
+                            };
+                            (size, Some(file.into()))
+                        }
+                        None => (size_and_opts, None),
+                    };
+                    DiskCliKind::WriteBackCache {
+                        size: parse_memory(size)?,
+                        file,
+                        disk: Box::new(kind.parse()?),
+                    }
+                }
+                "throttle" => {
+                    let (limits, kind) = arg
+                        .split_once(':')
+                        .context("expected limit=value[;limit=value...]:kind")?;
+                    DiskCliKind::Throttle {
+                        limits: parse_throttle_limits(limits)?,
+                        disk: Box::new(kind.parse()?),
+                    }
+                }
                 "file" => {
                     let (path, create_with_len) = parse_path_and_len(arg)?;
                     DiskCliKind::File {
@@ -958,7 +1104,7 @@ fn from_str(s: &str) -> anyhow::Result<Self> {
 }
 
 // <kind>[,ro]
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct DiskCli {
     pub vtl: DeviceVtl,
     pub kind: DiskCliKind,
@@ -966,9 +1112,10 @@ pub struct DiskCli {
     pub is_dvd: bool,
     pub underhill: Option<UnderhillDiskSource>,
     pub pcie_port: Option<String>,
+    pub merge: bool,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub enum UnderhillDiskSource {
     Scsi,
     Nvme,
@@ -986,6 +1133,7 @@ fn from_str(s: &str) -> anyhow::Result<Self> {
         let mut underhill = None;
         let mut vtl = DeviceVtl::Vtl0;
         let mut pcie_port = None;
+        let mut merge = false;
         for opt in opts {
             let mut s = opt.split('=');
             let opt = s.next().unwrap();
@@ -1000,6 +1148,7 @@ fn from_str(s: &str) -> anyhow::Result<Self> {
                 }
                 "uh" => underhill = Some(UnderhillDiskSource::Scsi),
                 "uh-nvme" => underhill = Some(UnderhillDiskSource::Nvme),
+                "merge" => merge = true,
                 "pcie_port" => {
                     let port = s.next();
                     if port.is_none_or(|p| p.is_empty()) {
@@ -1019,6 +1168,10 @@ fn from_str(s: &str) -> anyhow::Result<Self> {
             anyhow::bail!("`pcie_port` is incompatible with `uh`, `uh-nvme`, `vtl2`, and `dvd`");
         }
 
+        if merge && read_only {
+            anyhow::bail!("`merge` is incompatible with `ro` and `dvd`");
+        }
+
         Ok(DiskCli {
             vtl,
             kind,
@@ -1026,12 +1179,13 @@ fn from_str(s: &str) -> anyhow::Result<Self> {
             is_dvd,
             underhill,
             pcie_port,
+            merge,
         })
     }
 }
 
 // <kind>[,ro,s]
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct IdeDiskCli {
     pub kind: DiskCliKind,
     pub read_only: bool,
@@ -1133,6 +1287,30 @@ fn from_str(s: &str) -> Result<Self, Self::Err> {
     }
 }
 
+/// The address of a live migration connection (\<path\> | tcp:\<ip\>:\<port\>)
+#[derive(Clone, Debug, PartialEq)]
+pub enum MigrationAddress {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl FromStr for MigrationAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(tcp) = s.strip_prefix("tcp:") {
+            let addr = tcp
+                .parse()
+                .map_err(|err| format!("invalid tcp address: {err}"))?;
+            Ok(MigrationAddress::Tcp(addr))
+        } else if s.is_empty() {
+            Err("invalid migration address: no path supplied".to_string())
+        } else {
+            Ok(MigrationAddress::Unix(s.into()))
+        }
+    }
+}
+
 /// (console | stderr | listen=\<path\> | file=\<path\> (overwrites) | listen=tcp:\<ip\>:\<port\> | term[=\<program\>][,name=<windowtitle>] | none)
 #[derive(Clone, Debug, PartialEq)]
 pub enum SerialConfigCli {
@@ -1230,19 +1408,64 @@ fn parse_keyvalues(s: &str) -> Result<Vec<(String, Option<String>)>, String> {
 #[derive(Clone, Debug, PartialEq)]
 pub enum EndpointConfigCli {
     None,
-    Consomme { cidr: Option<String> },
-    Dio { id: Option<String> },
-    Tap { name: String },
+    Consomme {
+        cidr: Option<String>,
+        hostfwd: Vec<HostFwdCli>,
+    },
+    Dio {
+        id: Option<String>,
+    },
+    Tap {
+        name: String,
+    },
+    UserSwitch {
+        path: String,
+    },
+    VhostUser {
+        path: String,
+    },
+}
+
+impl EndpointConfigCli {
+    fn parse_consomme(options: &str) -> Result<Self, String> {
+        let mut cidr = None;
+        let mut hostfwd = Vec::new();
+        for item in options.split(',') {
+            if let Some(rule) = item.strip_prefix("hostfwd=") {
+                hostfwd.push(rule.parse()?);
+            } else if cidr.is_none() && !item.contains('=') {
+                cidr = Some(item.to_owned());
+            } else {
+                return Err(format!("invalid consomme option: {item}"));
+            }
+        }
+        Ok(EndpointConfigCli::Consomme { cidr, hostfwd })
+    }
 }
 
 impl FromStr for EndpointConfigCli {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Consomme options contain colons, so they are parsed separately.
+        if let Some(options) = s.strip_prefix("consomme:") {
+            return Self::parse_consomme(options);
+        }
+        if let Some(path) = s.strip_prefix("switch:") {
+            return Ok(EndpointConfigCli::UserSwitch {
+                path: path.to_owned(),
+            });
+        }
+        if let Some(path) = s.strip_prefix("vhost-user:") {
+            return Ok(EndpointConfigCli::VhostUser {
+                path: path.to_owned(),
+            });
+        }
         let ret = match s.split(':').collect::<Vec<_>>().as_slice() {
             ["none"] => EndpointConfigCli::None,
-            ["consomme", s @ ..] => EndpointConfigCli::Consomme {
-                cidr: s.first().map(|&s| s.to_owned()),
+            ["consomme"] => EndpointConfigCli::Consomme {
+                cidr: None,
+                hostfwd: Vec::new(),
             },
             ["dio", s @ ..] => EndpointConfigCli::Dio {
                 id: s.first().map(|s| (*s).to_owned()),
@@ -1257,6 +1480,111 @@ fn from_str(s: &str) -> Result<Self, Self::Err> {
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HostFwdProtocol {
+    Tcp,
+    Udp,
+}
+
+impl FromStr for HostFwdProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(HostFwdProtocol::Tcp),
+            "udp" => Ok(HostFwdProtocol::Udp),
+            _ => Err(format!("invalid protocol: {s}")),
+        }
+    }
+}
+
+/// A host port, in the form `[tcp|udp]:[hostaddr]:hostport`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HostPortCli {
+    pub protocol: HostFwdProtocol,
+    pub address: Option<Ipv4Addr>,
+    pub port: u16,
+}
+
+impl FromStr for HostPortCli {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (protocol, rest) = s
+            .split_once(':')
+            .ok_or("expected `[tcp|udp]:[hostaddr]:hostport`")?;
+        let protocol = protocol.parse()?;
+        let (address, port) = rest.rsplit_once(':').unwrap_or(("", rest));
+        let address = if address.is_empty() {
+            None
+        } else {
+            Some(
+                address
+                    .parse()
+                    .map_err(|_| format!("invalid host address: {address}"))?,
+            )
+        };
+        let port = port
+            .parse()
+            .map_err(|_| format!("invalid host port: {port}"))?;
+        Ok(HostPortCli {
+            protocol,
+            address,
+            port,
+        })
+    }
+}
+
+/// A forwarded host port, in the form `[tcp|udp]:hostport`.
+///
+/// Forwarded ports are identified by protocol and port alone, since only one
+/// host address can be bound to each port.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HostFwdPortCli {
+    pub protocol: HostFwdProtocol,
+    pub port: u16,
+}
+
+impl FromStr for HostFwdPortCli {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (protocol, port) = s.split_once(':').ok_or("expected `[tcp|udp]:hostport`")?;
+        let protocol = protocol.parse()?;
+        let port = port
+            .parse()
+            .map_err(|_| format!("invalid host port: {port}"))?;
+        Ok(HostFwdPortCli { protocol, port })
+    }
+}
+
+/// A host port forwarding rule, in the form
+/// `[tcp|udp]:[hostaddr]:hostport-:guestport`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HostFwdCli {
+    pub host: HostPortCli,
+    pub guest_port: u16,
+}
+
+impl FromStr for HostFwdCli {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, guest) = s
+            .split_once('-')
+            .ok_or("expected `[tcp|udp]:[hostaddr]:hostport-:guestport`")?;
+        let host = host.parse()?;
+        let (guest_address, guest_port) = guest.rsplit_once(':').unwrap_or(("", guest));
+        if !guest_address.is_empty() {
+            return Err("guest address is not supported".into());
+        }
+        let guest_port = guest_port
+            .parse()
+            .map_err(|_| format!("invalid guest port: {guest_port}"))?;
+        Ok(HostFwdCli { host, guest_port })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct NicConfigCli {
     pub vtl: DeviceVtl,
@@ -1777,6 +2105,18 @@ fn test_parse_pcie_disk() {
         assert!(DiskCli::from_str("file:disk.vhd,pcie_port=p0,uh-nvme").is_err());
     }
 
+    #[test]
+    fn test_disk_merge() {
+        assert!(
+            DiskCli::from_str("memdiff:file:path.vhdx,merge")
+                .unwrap()
+                .merge
+        );
+        assert!(!DiskCli::from_str("memdiff:file:path.vhdx").unwrap().merge);
+        assert!(DiskCli::from_str("memdiff:file:path.vhdx,merge,ro").is_err());
+        assert!(DiskCli::from_str("memdiff:file:path.vhdx,dvd,merge").is_err());
+    }
+
     #[test]
     fn test_parse_memory_diff_disk() {
         let s = "memdiff:file:base.img";
@@ -1796,6 +2136,52 @@ fn test_parse_memory_diff_disk() {
         }
     }
 
+    #[test]
+    fn test_parse_cache_disk() {
+        let disk = DiskCliKind::from_str("cache:64M;file=cache.bin:mem:1G").unwrap();
+        match disk {
+            DiskCliKind::WriteBackCache { size, file, disk } => {
+                assert_eq!(size, 64 * 1024 * 1024);
+                assert_eq!(file, Some(PathBuf::from("cache.bin")));
+                assert!(matches!(*disk, DiskCliKind::Memory(len) if len == 1024 * 1024 * 1024));
+            }
+            _ => panic!("Expected WriteBackCache variant"),
+        }
+
+        let disk = DiskCliKind::from_str("cache:64M:file:disk.img").unwrap();
+        assert!(matches!(
+            disk,
+            DiskCliKind::WriteBackCache { file: None, .. }
+        ));
+
+        assert!(DiskCliKind::from_str("cache:64M;path=cache.bin:mem:1G").is_err());
+        assert!(DiskCliKind::from_str("cache:64M").is_err());
+    }
+
+    #[test]
+    fn test_parse_throttle_disk() {
+        let disk = DiskCliKind::from_str("throttle:riops=100;wbps=10M:file:disk.img").unwrap();
+        match disk {
+            DiskCliKind::Throttle { limits, disk } => {
+                assert_eq!(
+                    limits,
+                    disk_backend_resources::ThrottleLimits {
+                        read_iops: Some(100),
+                        write_iops: None,
+                        read_bytes_per_sec: None,
+                        write_bytes_per_sec: Some(10 * 1024 * 1024),
+                    }
+                );
+                assert!(matches!(*disk, DiskCliKind::File { .. }));
+            }
+            _ => panic!("Expected Throttle variant"),
+        }
+
+        assert!(DiskCliKind::from_str("throttle:iops=100:mem:1G").is_err());
+        assert!(DiskCliKind::from_str("throttle:riops:mem:1G").is_err());
+        assert!(DiskCliKind::from_str("throttle:riops=100").is_err());
+    }
+
     #[test]
     fn test_parse_sqlite_disk() {
         let s = "sql:db.sqlite;create=2G";
@@ -2036,6 +2422,20 @@ fn test_serial_config_from_str() {
         assert!(SerialConfigCli::from_str("listen").is_err());
     }
 
+    #[test]
+    fn test_migration_address_from_str() {
+        assert_eq!(
+            MigrationAddress::from_str("/tmp/migrate.sock").unwrap(),
+            MigrationAddress::Unix("/tmp/migrate.sock".into())
+        );
+        assert_eq!(
+            MigrationAddress::from_str("tcp:127.0.0.1:4444").unwrap(),
+            MigrationAddress::Tcp("127.0.0.1:4444".parse().unwrap())
+        );
+        assert!(MigrationAddress::from_str("tcp:localhost").is_err());
+        assert!(MigrationAddress::from_str("").is_err());
+    }
+
     #[test]
     fn test_endpoint_config_from_str() {
         // Test none
@@ -2046,18 +2446,65 @@ fn test_endpoint_config_from_str() {
 
         // Test consomme without cidr
         match EndpointConfigCli::from_str("consomme").unwrap() {
-            EndpointConfigCli::Consomme { cidr: None } => (),
+            EndpointConfigCli::Consomme {
+                cidr: None,
+                hostfwd,
+            } if hostfwd.is_empty() => (),
             _ => panic!("Expected Consomme variant without cidr"),
         }
 
         // Test consomme with cidr
         match EndpointConfigCli::from_str("consomme:192.168.0.0/24").unwrap() {
-            EndpointConfigCli::Consomme { cidr: Some(cidr) } => {
+            EndpointConfigCli::Consomme {
+                cidr: Some(cidr), ..
+            } => {
                 assert_eq!(cidr, "192.168.0.0/24");
             }
             _ => panic!("Expected Consomme variant with cidr"),
         }
 
+        // Test consomme with port forwarding
+        assert_eq!(
+            EndpointConfigCli::from_str(
+                "consomme:192.168.0.0/24,hostfwd=tcp:127.0.0.1:2222-:22,hostfwd=udp::5353-:53"
+            )
+            .unwrap(),
+            EndpointConfigCli::Consomme {
+                cidr: Some("192.168.0.0/24".into()),
+                hostfwd: vec![
+                    HostFwdCli {
+                        host: HostPortCli {
+                            protocol: HostFwdProtocol::Tcp,
+                            address: Some(Ipv4Addr::LOCALHOST),
+                            port: 2222,
+                        },
+                        guest_port: 22,
+                    },
+                    HostFwdCli {
+                        host: HostPortCli {
+                            protocol: HostFwdProtocol::Udp,
+                            address: None,
+                            port: 5353,
+                        },
+                        guest_port: 53,
+                    },
+                ],
+            }
+        );
+        assert!(EndpointConfigCli::from_str("consomme:hostfwd=tcp:2222").is_err());
+        assert!(EndpointConfigCli::from_str("consomme:hostfwd=icmp::1-:1").is_err());
+        assert!(EndpointConfigCli::from_str("consomme:hostfwd=tcp::2222-10.0.0.2:22").is_err());
+
+        // Test removing a forwarded port
+        assert_eq!(
+            HostFwdPortCli::from_str("udp:5353").unwrap(),
+            HostFwdPortCli {
+                protocol: HostFwdProtocol::Udp,
+                port: 5353,
+            }
+        );
+        assert!(HostFwdPortCli::from_str("tcp:127.0.0.1:2222").is_err());
+
         // Test dio without id
         match EndpointConfigCli::from_str("dio").unwrap() {
             EndpointConfigCli::Dio { id: None } => (),
@@ -2080,6 +2527,22 @@ fn test_endpoint_config_from_str() {
             _ => panic!("Expected Tap variant"),
         }
 
+        // Test user-mode switch
+        assert_eq!(
+            EndpointConfigCli::from_str("switch:/tmp/switch.sock").unwrap(),
+            EndpointConfigCli::UserSwitch {
+                path: "/tmp/switch.sock".into()
+            }
+        );
+
+        // Test vhost-user
+        assert_eq!(
+            EndpointConfigCli::from_str("vhost-user:/tmp/vhost-user.sock").unwrap(),
+            EndpointConfigCli::VhostUser {
+                path: "/tmp/vhost-user.sock".into()
+            }
+        );
+
         // Test error case
         assert!(EndpointConfigCli::from_str("invalid").is_err());
     }
@@ -11,7 +11,9 @@
 mod crash_dump;
 mod kvp;
 mod meshworker;
+mod migrate;
 mod serial_io;
+mod snapshot;
 mod storage_builder;
 mod tracing_init;
 mod ttrpc;
@@ -31,6 +33,9 @@
 use cli_args::DiskCliKind;
 use cli_args::EfiDiagnosticsLogLevelCli;
 use cli_args::EndpointConfigCli;
+use cli_args::HostFwdCli;
+use cli_args::HostFwdPortCli;
+use cli_args::HostFwdProtocol;
 use cli_args::NicConfigCli;
 use cli_args::ProvisionVmgs;
 use cli_args::SerialConfigCli;
@@ -198,9 +203,12 @@ struct VmResources {
     console_in: Option<Box<dyn AsyncWrite + Send + Unpin>>,
     framebuffer_access: Option<FramebufferAccess>,
     shutdown_ic: Option<mesh::Sender<hyperv_ic_resources::shutdown::ShutdownRpc>>,
+    balloon: Option<mesh::Sender<virtio_resources::balloon::BalloonRpc>>,
     kvp_ic: Option<mesh::Sender<hyperv_ic_resources::kvp::KvpConnectRpc>>,
+    heartbeat_ic: Option<mesh::Sender<hyperv_ic_resources::heartbeat::HeartbeatRpc>>,
     scsi_rpc: Option<mesh::Sender<ScsiControllerRequest>>,
     ged_rpc: Option<mesh::Sender<get_resources::ged::GuestEmulationRequest>>,
+    consomme_rpc: Vec<mesh::Sender<net_backend_resources::consomme::ConsommeRpc>>,
     #[cfg(windows)]
     switch_ports: Vec<vmswitch::kernel::SwitchPort>,
 }
@@ -526,6 +534,7 @@ fn vm_config_from_command_line(
         is_dvd,
         underhill,
         ref pcie_port,
+        merge,
     } in &opt.disk
     {
         if pcie_port.is_some() {
@@ -539,6 +548,7 @@ fn vm_config_from_command_line(
             kind,
             is_dvd,
             read_only,
+            merge,
         )?;
     }
 
@@ -557,6 +567,7 @@ fn vm_config_from_command_line(
             kind,
             is_dvd,
             read_only,
+            false,
         )?;
     }
 
@@ -567,6 +578,7 @@ fn vm_config_from_command_line(
         is_dvd,
         underhill,
         ref pcie_port,
+        merge,
     } in &opt.nvme
     {
         storage.add(
@@ -576,6 +588,7 @@ fn vm_config_from_command_line(
             kind,
             is_dvd,
             read_only,
+            merge,
         )?;
     }
 
@@ -627,7 +640,10 @@ fn vm_config_from_command_line(
         let nic_config = parse_endpoint(
             &NicConfigCli {
                 vtl: DeviceVtl::Vtl0,
-                endpoint: EndpointConfigCli::Consomme { cidr: None },
+                endpoint: EndpointConfigCli::Consomme {
+                    cidr: None,
+                    hostfwd: Vec::new(),
+                },
                 max_queues: None,
                 underhill: false,
             },
@@ -1266,11 +1282,16 @@ fn vm_config_from_command_line(
         None
     };
 
+    let mut vss_ic = None;
     if with_hv {
         let (shutdown_send, shutdown_recv) = mesh::channel();
         resources.shutdown_ic = Some(shutdown_send);
         let (kvp_send, kvp_recv) = mesh::channel();
         resources.kvp_ic = Some(kvp_send);
+        let (heartbeat_send, heartbeat_recv) = mesh::channel();
+        resources.heartbeat_ic = Some(heartbeat_send);
+        let (vss_send, vss_recv) = mesh::channel();
+        vss_ic = Some(vss_send);
         vmbus_devices.extend(
             [
                 hyperv_ic_resources::shutdown::ShutdownIcHandle {
@@ -1278,6 +1299,11 @@ fn vm_config_from_command_line(
                 }
                 .into_resource(),
                 hyperv_ic_resources::kvp::KvpIcHandle { recv: kvp_recv }.into_resource(),
+                hyperv_ic_resources::heartbeat::HeartbeatIcHandle {
+                    recv: heartbeat_recv,
+                }
+                .into_resource(),
+                hyperv_ic_resources::vss::VssIcHandle { recv: vss_recv }.into_resource(),
                 hyperv_ic_resources::timesync::TimesyncIcHandle.into_resource(),
             ]
             .map(|r| (DeviceVtl::Vtl0, r)),
@@ -1381,6 +1407,65 @@ fn vm_config_from_command_line(
         );
     }
 
+    if opt.virtio_balloon {
+        let (balloon_send, balloon_recv) = mesh::channel();
+        resources.balloon = Some(balloon_send);
+        add_virtio_device(
+            VirtioBusCli::Auto,
+            virtio_resources::balloon::VirtioBalloonHandle {
+                deflate_on_oom: true,
+                recv: balloon_recv,
+            }
+            .into_resource(),
+        );
+    }
+
+    if opt.virtio_rng {
+        add_virtio_device(
+            opt.virtio_rng_bus,
+            virtio_resources::rng::VirtioRngHandle {
+                bytes_per_second: opt.virtio_rng_rate,
+            }
+            .into_resource(),
+        );
+    }
+
+    if let Some(path) = &opt.virtio_vsock_path {
+        let listener = vsock_listener(Some(path))?;
+        add_virtio_device(
+            VirtioBusCli::Auto,
+            virtio_resources::vsock::VirtioVsockHandle {
+                guest_cid: opt.virtio_vsock_cid,
+                base_path: path.clone(),
+                listener,
+            }
+            .into_resource(),
+        );
+    }
+
+    for &cli_args::DiskCli {
+        vtl,
+        ref kind,
+        read_only,
+        is_dvd,
+        underhill,
+        ref pcie_port,
+    } in &opt.virtio_disk
+    {
+        if vtl != DeviceVtl::Vtl0 || is_dvd || underhill.is_some() || pcie_port.is_some() {
+            anyhow::bail!("`--virtio-disk` only supports the `ro` flag");
+        }
+        add_virtio_device(
+            VirtioBusCli::Auto,
+            virtio_resources::blk::VirtioBlkHandle {
+                disk: disk_open(kind, read_only)?,
+                read_only,
+                max_queues: None,
+            }
+            .into_resource(),
+        );
+    }
+
     let mut cfg = Config {
         chipset,
         load_mode,
@@ -1455,6 +1540,8 @@ fn vm_config_from_command_line(
         firmware_event_send: None,
         debugger_rpc: None,
         generation_id_recv: None,
+        vss_ic,
+        layered_disks: Vec::new(),
         rtc_delta_milliseconds: 0,
         automatic_guest_reset: !opt.halt_on_reset,
         efi_diagnostics_log_level: {
@@ -1517,15 +1604,37 @@ fn new_switch_port(
     Ok((id, port))
 }
 
+fn port_protocol(protocol: HostFwdProtocol) -> net_backend_resources::consomme::PortProtocol {
+    match protocol {
+        HostFwdProtocol::Tcp => net_backend_resources::consomme::PortProtocol::Tcp,
+        HostFwdProtocol::Udp => net_backend_resources::consomme::PortProtocol::Udp,
+    }
+}
+
+fn port_forward(hostfwd: &HostFwdCli) -> net_backend_resources::consomme::PortForward {
+    net_backend_resources::consomme::PortForward {
+        protocol: port_protocol(hostfwd.host.protocol),
+        host_address: hostfwd.host.address,
+        host_port: hostfwd.host.port,
+        guest_port: hostfwd.guest_port,
+    }
+}
+
 fn parse_endpoint(
     cli_cfg: &NicConfigCli,
     index: &mut usize,
     resources: &mut VmResources,
 ) -> anyhow::Result<NicConfig> {
-    let _ = resources;
     let endpoint = match &cli_cfg.endpoint {
-        EndpointConfigCli::Consomme { cidr } => {
-            net_backend_resources::consomme::ConsommeHandle { cidr: cidr.clone() }.into_resource()
+        EndpointConfigCli::Consomme { cidr, hostfwd } => {
+            let (send, recv) = mesh::channel();
+            resources.consomme_rpc.push(send);
+            net_backend_resources::consomme::ConsommeHandle {
+                cidr: cidr.clone(),
+                port_forwards: hostfwd.iter().map(port_forward).collect(),
+                control: Some(recv),
+            }
+            .into_resource()
         }
         EndpointConfigCli::None => net_backend_resources::null::NullHandle.into_resource(),
         EndpointConfigCli::Dio { id } => {
@@ -1551,6 +1660,14 @@ fn parse_endpoint(
         EndpointConfigCli::Tap { name } => {
             net_backend_resources::tap::TapHandle { name: name.clone() }.into_resource()
         }
+        EndpointConfigCli::UserSwitch { path } => {
+            net_backend_resources::user_switch::UserSwitchHandle { path: path.clone() }
+                .into_resource()
+        }
+        EndpointConfigCli::VhostUser { path } => {
+            net_backend_resources::vhost_user::VhostUserHandle { path: path.clone() }
+                .into_resource()
+        }
     };
 
     // Pick a random MAC address.
@@ -1604,8 +1721,33 @@ enum LayerOrDisk {
 }
 
 fn disk_open(disk_cli: &DiskCliKind, read_only: bool) -> anyhow::Result<Resource<DiskHandleKind>> {
+    disk_open_layers(disk_cli, read_only, None)
+}
+
+/// Opens a layered disk whose top layer can be merged into the layers below
+/// it at runtime, via the returned request channel.
+fn disk_open_mergeable(
+    disk_cli: &DiskCliKind,
+    read_only: bool,
+) -> anyhow::Result<(
+    Resource<DiskHandleKind>,
+    mesh::Sender<disk_backend_resources::LayeredDiskRequest>,
+)> {
+    let (send, recv) = mesh::channel();
+    let disk = disk_open_layers(disk_cli, read_only, Some(recv))?;
+    Ok((disk, send))
+}
+
+fn disk_open_layers(
+    disk_cli: &DiskCliKind,
+    read_only: bool,
+    requests: Option<mesh::Receiver<disk_backend_resources::LayeredDiskRequest>>,
+) -> anyhow::Result<Resource<DiskHandleKind>> {
     let mut layers = Vec::new();
-    disk_open_inner(disk_cli, read_only, &mut layers)?;
+    disk_open_inner(disk_cli, read_only, requests.is_some(), &mut layers)?;
+    if requests.is_some() && layers.len() < 2 {
+        anyhow::bail!("`merge` requires a diff disk, such as `memdiff` or `sqldiff`");
+    }
     if layers.len() == 1 && matches!(layers[0], LayerOrDisk::Disk(_)) {
         let LayerOrDisk::Disk(disk) = layers.pop().unwrap() else {
             unreachable!()
@@ -1624,13 +1766,18 @@ fn disk_open(disk_cli: &DiskCliKind, read_only: bool) -> anyhow::Result<Resource
                     },
                 })
                 .collect(),
+            requests,
         }))
     }
 }
 
+/// Opens the layers of a disk, top layer first. If `mergeable` is set and the
+/// disk is a diff disk, the layer below the top layer is opened for write
+/// (unless `read_only` is set), since the top layer can be merged into it.
 fn disk_open_inner(
     disk_cli: &DiskCliKind,
     read_only: bool,
+    mergeable: bool,
     layers: &mut Vec<LayerOrDisk>,
 ) -> anyhow::Result<()> {
     fn layer<T: IntoResource<DiskLayerHandleKind>>(layer: T) -> LayerOrDisk {
@@ -1664,7 +1811,7 @@ fn disk<T: IntoResource<DiskHandleKind>>(disk: T) -> LayerOrDisk {
         }
         DiskCliKind::MemoryDiff(inner) => {
             layers.push(layer(RamDiskLayerHandle { len: None }));
-            disk_open_inner(inner, true, layers)?;
+            disk_open_inner(inner, read_only || !mergeable, false, layers)?;
         }
         DiskCliKind::PersistentReservationsWrapper(inner) => layers.push(disk(
             disk_backend_resources::DiskWithReservationsHandle(disk_open(inner, read_only)?),
@@ -1676,6 +1823,37 @@ fn disk<T: IntoResource<DiskHandleKind>>(disk: T) -> LayerOrDisk {
             delay: CellUpdater::new(Duration::from_millis(*delay_ms)).cell(),
             disk: disk_open(inner, read_only)?,
         })),
+        DiskCliKind::WriteBackCache {
+            size,
+            file,
+            disk: inner,
+        } => layers.push(disk(disk_backend_resources::CacheDiskHandle {
+            disk: disk_open(inner, read_only)?,
+            storage: match file {
+                Some(path) => disk_backend_resources::CacheStorage::File(
+                    fs_err::OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .open(path)
+                        .context("failed to open cache file")?
+                        .into(),
+                ),
+                None => disk_backend_resources::CacheStorage::Ram,
+            },
+            cache_size: *size,
+            block_size: 64 * 1024,
+            write_back: true,
+            flush_interval: Some(Duration::from_secs(5)),
+        })),
+        DiskCliKind::Throttle {
+            limits,
+            disk: inner,
+        } => layers.push(disk(disk_backend_resources::ThrottleDiskHandle {
+            disk: disk_open(inner, read_only)?,
+            limits: limits.clone(),
+        })),
         DiskCliKind::Crypt {
             disk: inner,
             cipher,
@@ -1743,7 +1921,7 @@ fn disk<T: IntoResource<DiskHandleKind>>(disk: T) -> LayerOrDisk {
                     },
                 ),
             }));
-            disk_open_inner(disk, true, layers)?;
+            disk_open_inner(disk, read_only || !mergeable, false, layers)?;
         }
         DiskCliKind::AutoCacheSqlite {
             cache_path,
@@ -1759,7 +1937,7 @@ fn disk<T: IntoResource<DiskHandleKind>>(disk: T) -> LayerOrDisk {
                 }
                 .into_resource(),
             }));
-            disk_open_inner(disk, read_only, layers)?;
+            disk_open_inner(disk, read_only, mergeable, layers)?;
         }
     }
     Ok(())
@@ -1789,6 +1967,10 @@ fn do_main() -> anyhow::Result<()> {
         return console_relay::relay_console(&path, console_title.as_str());
     }
 
+    if let Some(path) = &opt.net_switch {
+        return run_net_switch(path);
+    }
+
     if let Some(path) = opt.ttrpc.as_ref().or(opt.grpc.as_ref()) {
         block_on(async {
             let _ = std::fs::remove_file(path);
@@ -1827,6 +2009,26 @@ fn do_main() -> anyhow::Result<()> {
     }
 }
 
+/// Runs a user-mode Ethernet switch on the Unix socket at `path` until the
+/// process is terminated.
+#[cfg(target_os = "linux")]
+fn run_net_switch(path: &Path) -> anyhow::Result<()> {
+    DefaultPool::run_with(async |driver| {
+        cleanup_socket(path);
+        let switch = net_user_switch::switch::Switch::new(&driver, path)
+            .context("failed to create switch")?;
+
+        tracing::info!(path = %path.display(), "switch listening");
+
+        switch.run(&driver).await.context("switch failed")
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn run_net_switch(_path: &Path) -> anyhow::Result<()> {
+    bail!("the user-mode switch is only supported on Linux")
+}
+
 fn maybe_with_radix_u64(s: &str) -> Result<u64, String> {
     let (radix, prefix_len) = if s.starts_with("0x") || s.starts_with("0X") {
         (16, 2)
@@ -1975,6 +2177,25 @@ enum InteractiveCommand {
         force: bool,
     },
 
+    /// Show the guest health reported by the heartbeat IC.
+    Heartbeat,
+
+    /// Freeze the guest's applications and filesystems via the VSS IC, so
+    /// that the VM's disks can be snapshotted consistently.
+    Freeze,
+
+    /// Thaw the guest after `freeze`.
+    Thaw,
+
+    /// Merge the top layer of a disk opened with the `merge` flag into the
+    /// layer below it, while the VM keeps running.
+    MergeDisk {
+        /// The index of the disk, counting the `--disk` and then the `--nvme`
+        /// disks with the `merge` flag, in command line order.
+        #[clap(default_value = "0")]
+        index: usize,
+    },
+
     /// Clears the current halt condition, resuming the VPs if the VM is
     /// running.
     #[clap(visible_alias = "ch")]
@@ -2023,11 +2244,65 @@ enum InteractiveCommand {
         file: Option<PathBuf>,
     },
 
+    /// Live migrate the VM to another OpenVMM process.
+    ///
+    /// The destination must have been started with `--incoming`. Once the
+    /// migration completes, this process exits.
+    Migrate {
+        /// The destination address (\<path\> | tcp:\<ip\>:\<port\>).
+        address: cli_args::MigrationAddress,
+    },
+
+    /// Save a snapshot of the VM to a file.
+    ///
+    /// The VM is paused while its memory and state are written. The snapshot
+    /// can be restored by starting a new OpenVMM process with the same
+    /// configuration and `--restore-from`.
+    Save {
+        /// The snapshot file to write.
+        path: PathBuf,
+    },
+
     /// Inject an artificial panic into OpenVMM
     Panic,
 
     /// Use KVP to interact with the guest.
     Kvp(kvp::KvpCommand),
+
+    /// Add or remove host port forwarding to a consomme NIC.
+    Hostfwd {
+        /// The index of the consomme NIC. Consomme NICs are counted in the
+        /// order `--net`, `--nic`, `--mana`, then `--virtio-net`, and in
+        /// command line order within each option.
+        #[clap(long, default_value = "0")]
+        nic: usize,
+        #[clap(subcommand)]
+        command: HostfwdCommand,
+    },
+
+    /// Show or set the virtio balloon target.
+    ///
+    /// The target is the amount of memory the guest is asked to give up.
+    Balloon {
+        /// The new target, e.g. `1G`. If omitted, shows the current balloon
+        /// state.
+        #[clap(value_parser = cli_args::parse_memory)]
+        target: Option<u64>,
+    },
+}
+
+#[derive(clap::Subcommand)]
+enum HostfwdCommand {
+    /// Forward a host port to the guest.
+    Add {
+        /// The rule, in the form `[tcp|udp]:[hostaddr]:hostport-:guestport`.
+        rule: HostFwdCli,
+    },
+    /// Stop forwarding a host port to the guest.
+    Rm {
+        /// The host port, in the form `[tcp|udp]:hostport`.
+        port: HostFwdPortCli,
+    },
 }
 
 struct CommandParser {
@@ -2134,10 +2409,23 @@ async fn run_control(driver: &DefaultDriver, mesh: &VmmMesh, opt: Options) -> an
     let mut vm_worker = {
         let vm_host = mesh.make_host("vm", opt.log_file.clone()).await?;
 
+        let incoming = if let Some(address) = &opt.incoming {
+            Some(migrate::accept(driver, address).await?)
+        } else {
+            None
+        };
+        let restore_from = opt
+            .restore_from
+            .as_deref()
+            .map(|path| snapshot::open(path, &opt))
+            .transpose()?;
+
         let params = VmWorkerParameters {
             hypervisor: opt.hypervisor,
             cfg: vm_config,
             saved_state: None,
+            incoming,
+            restore_from,
             rpc: rpc_recv,
             notify: notify_send,
         };
@@ -2316,6 +2604,8 @@ enum StateChange {
         Reset(Result<(), RemoteError>),
         PulseSaveRestore(Result<(), PulseSaveRestoreError>),
         ServiceVtl2(anyhow::Result<Duration>),
+        Migrate(Result<(), RemoteError>),
+        SaveSnapshot(Result<(), RemoteError>),
     }
 
     enum Event {
@@ -2507,6 +2797,27 @@ enum Event {
                                 "vtl2 servicing failed"
                             ),
                         },
+                        StateChange::Migrate(r) => match r {
+                            Ok(()) => {
+                                tracing::info!("migration complete");
+                                // Work around the detached SCSI task holding up
+                                // worker stop, as for quit.
+                                resources.scsi_rpc = None;
+                                vm_worker.stop();
+                                quit = true;
+                            }
+                            Err(err) => tracing::error!(
+                                error = &err as &dyn std::error::Error,
+                                "migration failed"
+                            ),
+                        },
+                        StateChange::SaveSnapshot(r) => match r {
+                            Ok(()) => tracing::info!("snapshot saved"),
+                            Err(err) => tracing::error!(
+                                error = &err as &dyn std::error::Error,
+                                "failed to save snapshot"
+                            ),
+                        },
                     },
                     Err(err) => {
                         tracing::error!(
@@ -2663,6 +2974,35 @@ fn state_change<U: 'static + Send>(
                     println!("no shutdown ic configured");
                 }
             }
+            InteractiveCommand::Heartbeat => {
+                let Some(heartbeat) = &resources.heartbeat_ic else {
+                    eprintln!("error: no heartbeat ic configured");
+                    continue;
+                };
+                match heartbeat
+                    .call(hyperv_ic_resources::heartbeat::HeartbeatRpc::GetStatus, ())
+                    .await
+                {
+                    Ok(status) => println!("{status:?}"),
+                    Err(err) => eprintln!("error: {err}"),
+                }
+            }
+            InteractiveCommand::Freeze => {
+                match vm_rpc.call_failable(VmRpc::FreezeGuest, ()).await {
+                    Ok(()) => println!("guest frozen"),
+                    Err(err) => eprintln!("error: {err:#}"),
+                }
+            }
+            InteractiveCommand::Thaw => match vm_rpc.call_failable(VmRpc::ThawGuest, ()).await {
+                Ok(()) => println!("guest thawed"),
+                Err(err) => eprintln!("error: {err:#}"),
+            },
+            InteractiveCommand::MergeDisk { index } => {
+                match vm_rpc.call_failable(VmRpc::MergeDiskLayer, index).await {
+                    Ok(()) => println!("disk layer merged"),
+                    Err(err) => eprintln!("error: {err:#}"),
+                }
+            }
             InteractiveCommand::Nmi => {
                 let _ = vm_rpc.call(VmRpc::Nmi, 0).await;
             }
@@ -2885,6 +3225,40 @@ fn state_change<U: 'static + Send>(
                     state_change_task = Some(driver.spawn("state-change", r));
                 }
             }
+            InteractiveCommand::Save { path } => {
+                if state_change_task.is_some() {
+                    tracing::error!("state change already in progress");
+                    continue;
+                }
+                let file = match snapshot::create(&path, &opt) {
+                    Ok(file) => file,
+                    Err(err) => {
+                        eprintln!("error: {err:#}");
+                        continue;
+                    }
+                };
+                let rpc = vm_rpc.call(VmRpc::SaveSnapshot, file);
+                state_change_task = Some(driver.spawn("state-change", async move {
+                    Ok(StateChange::SaveSnapshot(rpc.await?))
+                }));
+            }
+            InteractiveCommand::Migrate { address } => {
+                if state_change_task.is_some() {
+                    tracing::error!("state change already in progress");
+                    continue;
+                }
+                let socket = match migrate::connect(driver, &address).await {
+                    Ok(socket) => socket,
+                    Err(err) => {
+                        eprintln!("error: {err:#}");
+                        continue;
+                    }
+                };
+                let rpc = vm_rpc.call(VmRpc::Migrate, socket);
+                state_change_task = Some(driver.spawn("state-change", async move {
+                    Ok(StateChange::Migrate(rpc.await?))
+                }));
+            }
             InteractiveCommand::Quit => {
                 tracing::info!("quitting");
                 // Work around the detached SCSI task holding up worker stop.
@@ -3005,6 +3379,55 @@ fn state_change<U: 'static + Send>(
                     eprintln!("error: {err:#}");
                 }
             }
+            InteractiveCommand::Balloon { target } => {
+                let Some(balloon) = &resources.balloon else {
+                    eprintln!("error: no balloon device configured");
+                    continue;
+                };
+                if let Some(target) = target {
+                    if let Err(err) = balloon
+                        .call(virtio_resources::balloon::BalloonRpc::SetTarget, target)
+                        .await
+                    {
+                        eprintln!("error: {err}");
+                    }
+                } else {
+                    match balloon
+                        .call(virtio_resources::balloon::BalloonRpc::Query, ())
+                        .await
+                    {
+                        Ok(status) => println!("{status:#?}"),
+                        Err(err) => eprintln!("error: {err}"),
+                    }
+                }
+            }
+            InteractiveCommand::Hostfwd { nic, command } => {
+                let Some(consomme) = resources.consomme_rpc.get(nic) else {
+                    eprintln!("error: no consomme nic {nic}");
+                    continue;
+                };
+                let result = match command {
+                    HostfwdCommand::Add { rule } => {
+                        consomme
+                            .call_failable(
+                                net_backend_resources::consomme::ConsommeRpc::AddPortForward,
+                                port_forward(&rule),
+                            )
+                            .await
+                    }
+                    HostfwdCommand::Rm { port } => {
+                        consomme
+                            .call_failable(
+                                net_backend_resources::consomme::ConsommeRpc::RemovePortForward,
+                                (port_protocol(port.protocol), port.port),
+                            )
+                            .await
+                    }
+                };
+                if let Err(err) = result {
+                    eprintln!("error: {err:#}");
+                }
+            }
             InteractiveCommand::Input { .. } | InteractiveCommand::InputMode => unreachable!(),
         }
     }
@@ -0,0 +1,60 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Connection setup for live migration between OpenVMM processes.
+
+use crate::cleanup_socket;
+use crate::cli_args::MigrationAddress;
+use anyhow::Context;
+use pal_async::DefaultDriver;
+use pal_async::socket::PolledSocket;
+
+/// Connects to a destination that is waiting for an incoming migration.
+pub async fn connect(
+    driver: &DefaultDriver,
+    address: &MigrationAddress,
+) -> anyhow::Result<socket2::Socket> {
+    let socket = match address {
+        MigrationAddress::Unix(path) => PolledSocket::connect_unix(driver, path)
+            .await
+            .with_context(|| format!("failed to connect to {}", path.display()))?
+            .convert::<socket2::Socket>()
+            .into_inner(),
+        MigrationAddress::Tcp(addr) => {
+            let addr = socket2::SockAddr::from(*addr);
+            let socket = socket2::Socket::new(addr.domain(), socket2::Type::STREAM, None)?;
+            let mut socket = PolledSocket::new(driver, socket)?;
+            socket
+                .connect(&addr)
+                .await
+                .with_context(|| format!("failed to connect to {address:?}"))?;
+            socket.into_inner()
+        }
+    };
+    Ok(socket)
+}
+
+/// Waits for a migration source to connect to `address`.
+pub async fn accept(
+    driver: &DefaultDriver,
+    address: &MigrationAddress,
+) -> anyhow::Result<socket2::Socket> {
+    let listener: socket2::Socket = match address {
+        MigrationAddress::Unix(path) => {
+            cleanup_socket(path);
+            unix_socket::UnixListener::bind(path)
+                .with_context(|| format!("failed to bind to {}", path.display()))?
+                .into()
+        }
+        MigrationAddress::Tcp(addr) => std::net::TcpListener::bind(addr)
+            .with_context(|| format!("failed to bind to {addr}"))?
+            .into(),
+    };
+    let mut listener = PolledSocket::new(driver, listener)?;
+    tracing::info!(?address, "waiting for incoming migration");
+    let (socket, _) = listener
+        .accept()
+        .await
+        .context("failed to accept incoming migration")?;
+    Ok(socket)
+}
@@ -0,0 +1,388 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! VM snapshot files.
+//!
+//! A snapshot file starts with a header and metadata describing the VM's disk
+//! configuration, followed by the VM's memory and saved state in the live
+//! migration stream format, which the VM worker writes and reads directly.
+
+use crate::cli_args::DiskCli;
+use crate::cli_args::DiskCliKind;
+use crate::cli_args::Options;
+use crate::cli_args::UnderhillDiskSource;
+use anyhow::Context;
+use hvlite_defs::config::DeviceVtl;
+use mesh::payload::Protobuf;
+use mesh::payload::Timestamp;
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+
+const MAGIC: [u8; 8] = *b"OVMMSNAP";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 20;
+
+/// The largest metadata accepted when opening a snapshot.
+const MAX_METADATA_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Protobuf)]
+struct SnapshotMetadata {
+    #[mesh(1)]
+    disks: Vec<DiskMetadata>,
+}
+
+#[derive(Protobuf, Debug, PartialEq)]
+struct DiskMetadata {
+    #[mesh(1)]
+    config: DiskConfig,
+    /// The image files backing the disk.
+    #[mesh(2)]
+    files: Vec<FileMetadata>,
+}
+
+/// The parts of a disk's configuration that affect the VM's saved state.
+#[derive(Protobuf, Debug, PartialEq)]
+struct DiskConfig {
+    /// The command line option that added the disk, such as `--disk`.
+    #[mesh(1)]
+    option: String,
+    #[mesh(2)]
+    vtl2: bool,
+    #[mesh(3)]
+    read_only: bool,
+    #[mesh(4)]
+    is_dvd: bool,
+    /// `scsi` or `nvme` for disks relayed through VTL2.
+    #[mesh(5)]
+    underhill: Option<String>,
+    #[mesh(6)]
+    pcie_port: Option<String>,
+    #[mesh(7)]
+    ide_channel: Option<u32>,
+    #[mesh(8)]
+    ide_device: Option<u32>,
+}
+
+#[derive(Protobuf, Debug, PartialEq)]
+struct FileMetadata {
+    #[mesh(1)]
+    path: String,
+    #[mesh(2)]
+    len: u64,
+    #[mesh(3)]
+    modified: Timestamp,
+}
+
+/// Creates a snapshot file at `path` for the VM configured by `opt`.
+///
+/// Returns the file positioned for the VM worker to write the VM's memory and
+/// saved state.
+pub fn create(path: &Path, opt: &Options) -> anyhow::Result<File> {
+    let metadata = mesh::payload::encode(SnapshotMetadata {
+        disks: disk_metadata(opt)?,
+    });
+
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    header.extend_from_slice(&MAGIC);
+    header.extend_from_slice(&VERSION.to_le_bytes());
+    header.extend_from_slice(&(metadata.len() as u64).to_le_bytes());
+
+    let mut file =
+        File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    file.write_all(&header)?;
+    file.write_all(&metadata)?;
+    Ok(file)
+}
+
+/// Opens the snapshot file at `path`, checking that its disk configuration
+/// matches the VM configured by `opt`.
+///
+/// Returns the file positioned at the VM's memory and saved state.
+pub fn open(path: &Path, opt: &Options) -> anyhow::Result<File> {
+    let mut file =
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+
+    let mut header = [0; HEADER_SIZE];
+    file.read_exact(&mut header)
+        .context("failed to read snapshot header")?;
+    if header[..8] != MAGIC {
+        anyhow::bail!("{} is not a snapshot file", path.display());
+    }
+    let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    if version != VERSION {
+        anyhow::bail!("unsupported snapshot version {version}");
+    }
+    let len = u64::from_le_bytes(header[12..20].try_into().unwrap());
+    if len > MAX_METADATA_SIZE {
+        anyhow::bail!("snapshot metadata too large: {len} bytes");
+    }
+    let mut data = vec![0; len as usize];
+    file.read_exact(&mut data)
+        .context("failed to read snapshot metadata")?;
+    let metadata: SnapshotMetadata =
+        mesh::payload::decode(&data).context("failed to decode snapshot metadata")?;
+
+    check_disks(&metadata.disks, &disk_metadata(opt)?)?;
+    Ok(file)
+}
+
+fn check_disks(saved: &[DiskMetadata], current: &[DiskMetadata]) -> anyhow::Result<()> {
+    if saved.len() != current.len() {
+        anyhow::bail!(
+            "snapshot was taken with {} disks, but {} are configured",
+            saved.len(),
+            current.len()
+        );
+    }
+    for (i, (saved, current)) in saved.iter().zip(current).enumerate() {
+        if saved.config != current.config {
+            anyhow::bail!(
+                "configuration of disk {i} does not match snapshot: expected {:?}, found {:?}",
+                saved.config,
+                current.config
+            );
+        }
+        if saved.files.len() != current.files.len() {
+            anyhow::bail!(
+                "disk {i} is backed by {} image files, but the snapshot was taken with {}",
+                current.files.len(),
+                saved.files.len()
+            );
+        }
+        for (saved, current) in saved.files.iter().zip(&current.files) {
+            if saved.path != current.path {
+                anyhow::bail!(
+                    "disk {i} is backed by {}, but the snapshot was taken with {}",
+                    current.path,
+                    saved.path
+                );
+            }
+            if saved.len != current.len || saved.modified != current.modified {
+                anyhow::bail!(
+                    "disk image {} has changed since the snapshot was taken",
+                    saved.path
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn disk_metadata(opt: &Options) -> anyhow::Result<Vec<DiskMetadata>> {
+    let disk_config = |option: &str, d: &DiskCli| DiskConfig {
+        option: option.into(),
+        vtl2: d.vtl == DeviceVtl::Vtl2,
+        read_only: d.read_only,
+        is_dvd: d.is_dvd,
+        underhill: d.underhill.map(|source| {
+            match source {
+                UnderhillDiskSource::Scsi => "scsi",
+                UnderhillDiskSource::Nvme => "nvme",
+            }
+            .into()
+        }),
+        pcie_port: d.pcie_port.clone(),
+        ide_channel: None,
+        ide_device: None,
+    };
+    let disks = opt
+        .disk
+        .iter()
+        .map(|d| (disk_config("--disk", d), &d.kind))
+        .chain(opt.nvme.iter().map(|d| (disk_config("--nvme", d), &d.kind)))
+        .chain(
+            opt.virtio_disk
+                .iter()
+                .map(|d| (disk_config("--virtio-disk", d), &d.kind)),
+        )
+        .chain(opt.ide.iter().map(|d| {
+            let config = DiskConfig {
+                option: "--ide".into(),
+                vtl2: false,
+                read_only: d.read_only,
+                is_dvd: d.is_dvd,
+                underhill: None,
+                pcie_port: None,
+                ide_channel: d.channel.map(Into::into),
+                ide_device: d.device.map(Into::into),
+            };
+            (config, &d.kind)
+        }))
+        .chain(opt.floppy.iter().map(|d| {
+            let config = DiskConfig {
+                option: "--floppy".into(),
+                vtl2: false,
+                read_only: d.read_only,
+                is_dvd: false,
+                underhill: None,
+                pcie_port: None,
+                ide_channel: None,
+                ide_device: None,
+            };
+            (config, &d.kind)
+        }));
+
+    disks
+        .map(|(config, kind)| {
+            let mut paths = Vec::new();
+            image_files(kind, !config.read_only, &mut paths)?;
+            let files = paths
+                .into_iter()
+                .map(|path| {
+                    let metadata = std::fs::metadata(path)
+                        .with_context(|| format!("failed to query {}", path.display()))?;
+                    Ok(FileMetadata {
+                        path: path.display().to_string(),
+                        len: metadata.len(),
+                        modified: metadata
+                            .modified()
+                            .with_context(|| format!("failed to query {}", path.display()))?
+                            .into(),
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?;
+            Ok(DiskMetadata { config, files })
+        })
+        .collect()
+}
+
+/// Collects the paths of the image files backing a disk, which must not
+/// change between taking and restoring a snapshot.
+///
+/// Fails if an image file would be written by the VM, since the VM keeps
+/// running after the snapshot is taken. Such disks must be read-only or be
+/// behind a diff layer, such as `memdiff:`.
+fn image_files<'a>(
+    kind: &'a DiskCliKind,
+    writable: bool,
+    paths: &mut Vec<&'a Path>,
+) -> anyhow::Result<()> {
+    match kind {
+        DiskCliKind::File { path, .. } | DiskCliKind::Sqlite { path, .. } => {
+            if writable {
+                anyhow::bail!(
+                    "disk image {} is writable; snapshots require read-only disks or a diff layer such as memdiff",
+                    path.display()
+                );
+            }
+            paths.push(path);
+        }
+        DiskCliKind::Memory(_) | DiskCliKind::Blob { .. } => {}
+        DiskCliKind::MemoryDiff(disk)
+        | DiskCliKind::SqliteDiff { disk, .. }
+        | DiskCliKind::AutoCacheSqlite { disk, .. } => image_files(disk, false, paths)?,
+        DiskCliKind::PersistentReservationsWrapper(disk)
+        | DiskCliKind::Crypt { disk, .. }
+        | DiskCliKind::DelayDiskWrapper { disk, .. }
+        | DiskCliKind::WriteBackCache { disk, .. }
+        | DiskCliKind::Throttle { disk, .. } => image_files(disk, writable, paths)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    fn options(args: &[&str]) -> Options {
+        Options::try_parse_from(std::iter::once("openvmm").chain(args.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn test_create_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("disk.img");
+        std::fs::write(&image, [0; 4096]).unwrap();
+        let disk = format!("file:{}", image.display());
+        let opt = options(&[
+            "--disk",
+            &format!("{disk},ro"),
+            "--ide",
+            &format!("memdiff:{disk}"),
+        ]);
+
+        let path = dir.path().join("vm.snap");
+        let mut file = create(&path, &opt).unwrap();
+        file.write_all(b"vm state").unwrap();
+        drop(file);
+
+        let mut file = open(&path, &opt).unwrap();
+        let mut rest = Vec::new();
+        file.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"vm state");
+
+        // The disk configuration must match.
+        assert!(open(&path, &options(&["--disk", &format!("{disk},ro")])).is_err());
+        assert!(
+            open(
+                &path,
+                &options(&[
+                    "--disk",
+                    &format!("{disk},dvd"),
+                    "--ide",
+                    &format!("memdiff:{disk}")
+                ])
+            )
+            .is_err()
+        );
+
+        // The image must not have changed.
+        let image_file = File::options().write(true).open(&image).unwrap();
+        image_file
+            .set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        drop(image_file);
+        assert!(open(&path, &opt).is_err());
+
+        // The file must be a snapshot.
+        std::fs::write(&path, [0; HEADER_SIZE]).unwrap();
+        assert!(open(&path, &opt).is_err());
+    }
+
+    #[test]
+    fn test_writable_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("disk.img");
+        std::fs::write(&image, [0; 4096]).unwrap();
+        let opt = options(&["--disk", &format!("file:{}", image.display())]);
+        assert!(create(&dir.path().join("vm.snap"), &opt).is_err());
+    }
+
+    #[test]
+    fn test_check_disks() {
+        let disk = |path: &str, len| DiskMetadata {
+            config: DiskConfig {
+                option: "--disk".into(),
+                vtl2: false,
+                read_only: true,
+                is_dvd: false,
+                underhill: None,
+                pcie_port: None,
+                ide_channel: None,
+                ide_device: None,
+            },
+            files: vec![FileMetadata {
+                path: path.into(),
+                len,
+                modified: Timestamp {
+                    seconds: 1,
+                    nanos: 0,
+                },
+            }],
+        };
+        let saved = [disk("a", 1), disk("b", 2)];
+        check_disks(&saved, &[disk("a", 1), disk("b", 2)]).unwrap();
+        check_disks(&saved, &[disk("a", 1)]).unwrap_err();
+        check_disks(&saved, &[disk("a", 1), disk("c", 2)]).unwrap_err();
+        check_disks(&saved, &[disk("a", 1), disk("b", 3)]).unwrap_err();
+
+        let mut extra_file = disk("b", 2);
+        extra_file.files.push(disk("c", 3).files.remove(0));
+        check_disks(&saved, &[disk("a", 1), extra_file]).unwrap_err();
+    }
+}
@@ -7,7 +7,9 @@
 use crate::cli_args::DiskCliKind;
 use crate::cli_args::UnderhillDiskSource;
 use crate::disk_open;
+use crate::disk_open_mergeable;
 use anyhow::Context;
+use disk_backend_resources::LayeredDiskRequest;
 use guid::Guid;
 use hvlite_defs::config::Config;
 use hvlite_defs::config::DeviceVtl;
@@ -40,6 +42,7 @@ pub(super) struct StorageBuilder {
     underhill_scsi_luns: Vec<Lun>,
     underhill_nvme_luns: Vec<Lun>,
     openhcl_vtl: Option<DeviceVtl>,
+    layered_disks: Vec<mesh::Sender<LayeredDiskRequest>>,
 }
 
 #[derive(Clone)]
@@ -102,6 +105,7 @@ pub fn new(openhcl_vtl: Option<DeviceVtl>) -> Self {
             underhill_scsi_luns: Vec::new(),
             underhill_nvme_luns: Vec::new(),
             openhcl_vtl,
+            layered_disks: Vec::new(),
         }
     }
 
@@ -117,14 +121,15 @@ pub fn add(
         kind: &DiskCliKind,
         is_dvd: bool,
         read_only: bool,
+        merge: bool,
     ) -> anyhow::Result<()> {
         if let Some(source) = underhill {
             if vtl != DeviceVtl::Vtl0 {
                 anyhow::bail!("underhill can only offer devices to vtl0");
             }
-            self.add_underhill(source.into(), target, kind, is_dvd, read_only)?;
+            self.add_underhill(source.into(), target, kind, is_dvd, read_only, merge)?;
         } else {
-            self.add_inner(vtl, target, kind, is_dvd, read_only)?;
+            self.add_inner(vtl, target, kind, is_dvd, read_only, merge)?;
         }
         Ok(())
     }
@@ -138,8 +143,15 @@ fn add_inner(
         kind: &DiskCliKind,
         is_dvd: bool,
         read_only: bool,
+        merge: bool,
     ) -> anyhow::Result<Option<u32>> {
-        let disk = disk_open(kind, read_only || is_dvd)?;
+        let disk = if merge {
+            let (disk, requests) = disk_open_mergeable(kind, read_only || is_dvd)?;
+            self.layered_disks.push(requests);
+            disk
+        } else {
+            disk_open(kind, read_only || is_dvd)?
+        };
         let location = match target {
             DiskLocation::Ide(channel, device) => {
                 let guest_media = if is_dvd {
@@ -250,10 +262,11 @@ fn add_underhill(
         kind: &DiskCliKind,
         is_dvd: bool,
         read_only: bool,
+        merge: bool,
     ) -> anyhow::Result<()> {
         let vtl = self.openhcl_vtl.context("openhcl not configured")?;
         let sub_device_path = self
-            .add_inner(vtl, source.clone(), kind, is_dvd, read_only)?
+            .add_inner(vtl, source.clone(), kind, is_dvd, read_only, merge)?
             .context("source device not supported by underhill")?;
 
         let (device_type, device_path) = match source {
@@ -328,6 +341,7 @@ pub fn build_config(
         scsi_sub_channels: u16,
     ) -> anyhow::Result<()> {
         config.ide_disks.append(&mut self.vtl0_ide_disks);
+        config.layered_disks.append(&mut self.layered_disks);
 
         // Add an empty VTL0 SCSI controller even if there are no configured disks.
         if !self.vtl0_scsi_devices.is_empty() || config.vmbus.is_some() {
@@ -379,6 +393,7 @@ pub fn build_config(
                     namespaces: std::mem::take(&mut self.vtl0_nvme_namespaces),
                     max_io_queues: 64,
                     msix_count: 64,
+                    namespace_pool: Vec::new(),
                 }
                 .into_resource(),
             });
@@ -411,6 +426,7 @@ pub fn build_config(
                     namespaces: std::mem::take(&mut self.vtl2_nvme_namespaces),
                     max_io_queues: 64,
                     msix_count: 64,
+                    namespace_pool: Vec::new(),
                 }
                 .into_resource(),
             });
@@ -434,6 +450,7 @@ pub fn build_config(
                     namespaces,
                     max_io_queues: 64,
                     msix_count: 64,
+                    namespace_pool: Vec::new(),
                 }
                 .into_resource(),
             });
@@ -510,6 +510,8 @@ async fn create_vm(&mut self, request: vmservice::CreateVmRequest) -> anyhow::Re
             debugger_rpc: None,
             chipset_devices: chipset.chipset_devices,
             generation_id_recv: None,
+            vss_ic: None,
+            layered_disks: Vec::new(),
             rtc_delta_milliseconds: 0,
             automatic_guest_reset: true,
             efi_diagnostics_log_level: Default::default(),
@@ -588,6 +590,8 @@ async fn create_vm(&mut self, request: vmservice::CreateVmRequest) -> anyhow::Re
                     hypervisor: None,
                     cfg: config,
                     saved_state: None,
+                    incoming: None,
+                    restore_from: None,
                     rpc: recv,
                     notify: notify_send,
                 },
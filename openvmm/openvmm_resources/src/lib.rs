@@ -33,10 +33,15 @@
 
     // Network backends
     net_backend::null::NullResolver,
+    net_impairment::resolver::ImpairmentResolver,
     #[cfg(feature = "net_consomme")]
     net_consomme::resolver::ConsommeResolver,
     #[cfg(all(feature = "net_tap", target_os = "linux"))]
     net_tap::resolver::TapResolver,
+    #[cfg(all(feature = "net_user_switch", target_os = "linux"))]
+    net_user_switch::resolver::UserSwitchResolver,
+    #[cfg(all(feature = "net_vhost_user", target_os = "linux"))]
+    net_vhost_user::resolver::VhostUserResolver,
     #[cfg(windows)]
     net_dio::resolver::DioResolver,
 
@@ -46,7 +51,10 @@
     disk_crypt::resolver::DiskCryptResolver,
     disk_file::FileDiskResolver,
     disk_prwrap::DiskWithReservationsResolver,
+    disk_cache::resolver::CacheDiskResolver,
     disk_delay::resolver::DelayDiskResolver,
+    disk_fault::resolver::FaultDiskResolver,
+    disk_throttle::resolver::ThrottleDiskResolver,
     disk_vhd1::Vhd1Resolver,
     #[cfg(windows)]
     disk_vhdmp::VhdmpDiskResolver,
@@ -57,6 +65,9 @@
     disklayer_ram::resolver::RamDiskLayerResolver,
     #[cfg(feature = "disklayer_sqlite")]
     disklayer_sqlite::resolver::SqliteDiskLayerResolver,
+    disk_qcow2::resolver::Qcow2LayerResolver,
+    disk_vhd1::Vhd1LayerResolver,
+    disk_vhdx::resolver::VhdxLayerResolver,
 
     // PCI devices
     gdma::resolver::GdmaDeviceResolver,
@@ -72,16 +83,22 @@
     virtiofs::resolver::VirtioFsResolver,
     #[cfg(any(windows, target_os = "linux"))]
     virtio_p9::resolver::VirtioPlan9Resolver,
+    virtio_balloon::resolver::VirtioBalloonResolver,
+    virtio_blk::resolver::VirtioBlkResolver,
     virtio_net::resolver::VirtioNetResolver,
     virtio_pmem::resolver::VirtioPmemResolver,
+    virtio_rng::resolver::VirtioRngResolver,
+    virtio_vsock::resolver::VirtioVsockResolver,
 
     // Vmbus devices
     guest_crash_device::resolver::GuestCrashDeviceResolver,
     guest_emulation_device::resolver::GuestEmulationDeviceResolver,
     guest_emulation_log::resolver::GuestEmulationLogResolver,
+    hyperv_ic::resolver::HeartbeatIcResolver,
     hyperv_ic::resolver::KvpIcResolver,
     hyperv_ic::resolver::ShutdownIcResolver,
     hyperv_ic::resolver::TimesyncIcResolver,
+    hyperv_ic::resolver::VssIcResolver,
     netvsp::resolver::NetvspResolver,
     storvsp::resolver::StorvspResolver,
     uidevices::resolver::VmbusUiResolver,
@@ -306,6 +306,23 @@ pub fn new(
             hyperv_ic_resources::kvp::KvpIcHandle { recv: kvp_ic_recv }.into_resource(),
         ));
 
+        // Add the Hyper-V heartbeat IC
+        let (heartbeat_ic_send, heartbeat_ic_recv) = mesh::channel();
+        vmbus_devices.push((
+            DeviceVtl::Vtl0,
+            hyperv_ic_resources::heartbeat::HeartbeatIcHandle {
+                recv: heartbeat_ic_recv,
+            }
+            .into_resource(),
+        ));
+
+        // Add the Hyper-V VSS IC
+        let (vss_ic_send, vss_ic_recv) = mesh::channel();
+        vmbus_devices.push((
+            DeviceVtl::Vtl0,
+            hyperv_ic_resources::vss::VssIcHandle { recv: vss_ic_recv }.into_resource(),
+        ));
+
         // Add the Hyper-V timesync IC
         vmbus_devices.push((
             DeviceVtl::Vtl0,
@@ -485,6 +502,8 @@ pub fn new(
             vpci_resources: vec![],
             debugger_rpc: None,
             generation_id_recv: None,
+            vss_ic: None,
+            layered_disks: Vec::new(),
             rtc_delta_milliseconds: 0,
             efi_diagnostics_log_level: Default::default(), // TODO: Add config for tests
         };
@@ -520,6 +539,8 @@ pub fn new(
                 firmware_event_recv,
                 shutdown_ic_send,
                 kvp_ic_send,
+                heartbeat_ic_send,
+                vss_ic_send,
                 ged_send,
                 pipette_listener,
                 vtl2_pipette_listener,
@@ -863,6 +884,7 @@ enum Media {
                         subsystem_id: PARAVISOR_BOOT_NVME_INSTANCE,
                         max_io_queues: 64,
                         msix_count: 64,
+                        namespace_pool: Vec::new(),
                         namespaces: vec![NamespaceDefinition {
                             nsid: BOOT_NVME_NSID,
                             disk,
@@ -929,6 +951,7 @@ enum Media {
                             subsystem_id: BOOT_NVME_INSTANCE,
                             max_io_queues: 64,
                             msix_count: 64,
+                            namespace_pool: Vec::new(),
                             namespaces: vec![NamespaceDefinition {
                                 nsid: BOOT_NVME_NSID,
                                 disk,
@@ -182,6 +182,8 @@ struct PetriVmResourcesOpenVmm {
     firmware_event_recv: Receiver<FirmwareEvent>,
     shutdown_ic_send: Sender<ShutdownRpc>,
     kvp_ic_send: Sender<hyperv_ic_resources::kvp::KvpConnectRpc>,
+    heartbeat_ic_send: Sender<hyperv_ic_resources::heartbeat::HeartbeatRpc>,
+    vss_ic_send: Sender<hyperv_ic_resources::vss::VssRpc>,
     ged_send: Option<Sender<get_resources::ged::GuestEmulationRequest>>,
     pipette_listener: PolledSocket<UnixListener>,
     vtl2_pipette_listener: Option<PolledSocket<UnixListener>>,
@@ -216,6 +218,7 @@ fn memdiff_disk(path: &Path) -> anyhow::Result<Resource<DiskHandleKind>> {
             RamDiskLayerHandle { len: None }.into_resource().into(),
             DiskLayerHandle(disk).into_resource().into(),
         ],
+        requests: None,
     }
     .into_resource())
 }
@@ -124,8 +124,12 @@ pub fn with_igvm_attest_test_config(mut self, config: IgvmAttestTestConfig) -> S
     ///
     /// Uses a mana emulator and the paravisor if a paravisor is present.
     pub fn with_nic(mut self) -> Self {
-        let endpoint =
-            net_backend_resources::consomme::ConsommeHandle { cidr: None }.into_resource();
+        let endpoint = net_backend_resources::consomme::ConsommeHandle {
+            cidr: None,
+            port_forwards: Vec::new(),
+            control: None,
+        }
+        .into_resource();
         if self.resources.vtl2_settings.is_some() {
             self.config.vpci_devices.push(VpciDeviceConfig {
                 vtl: DeviceVtl::Vtl2,
@@ -20,7 +20,10 @@
 use futures_concurrency::future::Race;
 use get_resources::ged::FirmwareEvent;
 use hvlite_defs::rpc::PulseSaveRestoreError;
+use hyperv_ic_resources::heartbeat::HeartbeatRpc;
+use hyperv_ic_resources::heartbeat::HeartbeatStatus;
 use hyperv_ic_resources::shutdown::ShutdownRpc;
+use hyperv_ic_resources::vss::VssRpc;
 use mesh::CancelContext;
 use mesh::Receiver;
 use mesh::RecvError;
@@ -226,6 +229,24 @@ pub async fn send_enlightened_shutdown(&mut self, kind: ShutdownKind) -> anyhow:
         /// to send requests to it.
         pub async fn wait_for_kvp(&mut self) -> anyhow::Result<mesh::Sender<hyperv_ic_resources::kvp::KvpRpc>>
     );
+    petri_vm_fn!(
+        /// Waits for the guest to respond to the Hyper-V heartbeat IC with a
+        /// healthy status.
+        pub async fn wait_for_heartbeat_ok(&mut self) -> anyhow::Result<()>
+    );
+    petri_vm_fn!(
+        /// Gets the guest health reported by the Hyper-V heartbeat IC.
+        pub async fn heartbeat_status(&mut self) -> anyhow::Result<HeartbeatStatus>
+    );
+    petri_vm_fn!(
+        /// Asks the guest to freeze its applications and filesystems via the
+        /// Hyper-V VSS IC.
+        pub async fn freeze_guest(&mut self) -> anyhow::Result<()>
+    );
+    petri_vm_fn!(
+        /// Thaws a guest previously frozen with `freeze_guest`.
+        pub async fn thaw_guest(&mut self) -> anyhow::Result<()>
+    );
     petri_vm_fn!(
         /// Stages the new OpenHCL file and saves the existing state.
         pub async fn save_openhcl(
@@ -390,6 +411,42 @@ async fn wait_for_kvp(
         Ok(send)
     }
 
+    async fn wait_for_heartbeat_ok(&mut self) -> anyhow::Result<()> {
+        tracing::info!("Waiting for guest heartbeat");
+        self.resources
+            .heartbeat_ic_send
+            .call(HeartbeatRpc::WaitOk, ())
+            .await
+            .context("failed to wait for guest heartbeat")?;
+
+        tracing::info!("Guest heartbeat OK");
+        Ok(())
+    }
+
+    async fn heartbeat_status(&mut self) -> anyhow::Result<HeartbeatStatus> {
+        self.resources
+            .heartbeat_ic_send
+            .call(HeartbeatRpc::GetStatus, ())
+            .await
+            .context("failed to get guest heartbeat status")
+    }
+
+    async fn freeze_guest(&mut self) -> anyhow::Result<()> {
+        self.resources
+            .vss_ic_send
+            .call_failable(VssRpc::Freeze, ())
+            .await
+            .context("failed to freeze guest")
+    }
+
+    async fn thaw_guest(&mut self) -> anyhow::Result<()> {
+        self.resources
+            .vss_ic_send
+            .call_failable(VssRpc::Thaw, ())
+            .await
+            .context("failed to thaw guest")
+    }
+
     async fn save_openhcl(
         &self,
         new_openhcl: &ResolvedArtifact,
@@ -31,6 +31,8 @@ pub(crate) async fn launch(
             hypervisor: None,
             cfg,
             saved_state: None,
+            incoming: None,
+            restore_from: None,
             rpc: rpc_recv,
             notify: notify_send,
         };
@@ -20,6 +20,15 @@ pub enum OsResource {
     Socket(std::os::windows::io::OwnedSocket),
 }
 
+#[cfg(unix)]
+impl std::os::fd::AsFd for OsResource {
+    fn as_fd(&self) -> std::os::fd::BorrowedFd<'_> {
+        match self {
+            OsResource::Fd(fd) => fd.as_fd(),
+        }
+    }
+}
+
 /// A resource that can be sent via a port.
 #[derive(Debug)]
 pub enum Resource {
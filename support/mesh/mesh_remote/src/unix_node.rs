@@ -12,8 +12,7 @@
 //! of Unix sockets.
 
 #![cfg(unix)]
-// UNSAFETY: Calls to libc send/recvmsg fns and the work to prepare their inputs
-// and handle their outputs (mem::zeroed, transmutes, from_raw_fds).
+// UNSAFETY: Extending the lifetime of an IoSlice in advance_slices.
 #![expect(unsafe_code)]
 
 #[cfg(target_os = "linux")]
@@ -59,7 +58,6 @@
 use std::future::poll_fn;
 use std::io;
 use std::io::IoSlice;
-use std::io::IoSliceMut;
 use std::os::unix::prelude::*;
 use std::pin::pin;
 use std::sync::Arc;
@@ -1016,12 +1014,6 @@ struct UnixSocket {
     socket: Mutex<PolledSocket<Socket>>,
 }
 
-#[repr(C)]
-struct CmsgScmRights {
-    hdr: libc::cmsghdr,
-    fds: [RawFd; 64],
-}
-
 // TODO: replace this copy+paste of IoSlice::advance_slices with std's
 // implementation once stabilized.
 fn advance_slices(bufs: &mut &mut [IoSlice<'_>], n: usize) {
@@ -1163,127 +1155,18 @@ async fn close_write(&self) -> io::Result<()> {
 
 /// Sends a packet, including the specified file descriptors. May fail with
 /// ErrorKind::WouldBlock.
-// x86_64-unknown-linux-musl targets have a different type defn for
-// `libc::cmsghdr`, hence why these lints are being suppressed.
-#[allow(clippy::needless_update, clippy::useless_conversion)]
 fn try_send(socket: &Socket, msg: &[IoSlice<'_>], fds: &[OsResource]) -> io::Result<usize> {
-    let mut cmsg = CmsgScmRights {
-        hdr: libc::cmsghdr {
-            cmsg_level: libc::SOL_SOCKET,
-            cmsg_type: libc::SCM_RIGHTS,
-            cmsg_len: (size_of::<libc::cmsghdr>() + size_of_val(fds))
-                .try_into()
-                .unwrap(),
-
-            ..{
-                // SAFETY: type has no invariants
-                unsafe { std::mem::zeroed() }
-            }
-        },
-        fds: [0; 64],
-    };
-    for (fdi, fdo) in fds.iter().zip(cmsg.fds.iter_mut()) {
-        *fdo = match fdi {
-            OsResource::Fd(fd) => fd.as_raw_fd(),
-        }
-    }
-
-    // SAFETY: type has no invariants
-    let mut hdr: libc::msghdr = unsafe { std::mem::zeroed() };
-    hdr.msg_iov = msg.as_ptr() as *mut libc::iovec;
-    hdr.msg_iovlen = msg.len().try_into().unwrap();
-    hdr.msg_control = if fds.is_empty() {
-        std::ptr::null_mut()
-    } else {
-        std::ptr::from_mut(&mut cmsg).cast::<libc::c_void>()
-    };
-    hdr.msg_controllen = if fds.is_empty() { 0 } else { cmsg.hdr.cmsg_len };
-    // SAFETY: calling with appropriately initialized buffers.
-    let n = unsafe { libc::sendmsg(socket.as_raw_fd(), &hdr, 0) };
-    if n < 0 {
-        return Err(io::Error::last_os_error());
-    }
-    Ok(n as usize)
+    pal::unix::fds::send_with_fds(socket, msg, fds)
 }
 
 /// Receives the next packet. Returns the number of bytes read and any file
 /// descriptors that were associated with the packet. May fail with
 /// ErrorKind::WouldBlock.
 fn try_recv(socket: &Socket, buf: &mut [u8], fds: &mut Vec<OsResource>) -> io::Result<usize> {
-    assert!(!buf.is_empty());
-    let mut iov = IoSliceMut::new(buf);
-    // SAFETY: type has no invariants
-    let mut cmsg: CmsgScmRights = unsafe { std::mem::zeroed() };
-    // SAFETY: type has no invariants
-    let mut hdr: libc::msghdr = unsafe { std::mem::zeroed() };
-    hdr.msg_iov = std::ptr::from_mut(&mut iov).cast::<libc::iovec>();
-    hdr.msg_iovlen = 1;
-    hdr.msg_control = std::ptr::from_mut(&mut cmsg).cast::<libc::c_void>();
-    hdr.msg_controllen = size_of_val(&cmsg) as _;
-
-    // On Linux, automatically set O_CLOEXEC on incoming fds.
-    #[cfg(target_os = "linux")]
-    let flags = libc::MSG_CMSG_CLOEXEC;
-    #[cfg(not(target_os = "linux"))]
-    let flags = 0;
-
-    // SAFETY: calling with properly initialized buffers.
-    let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut hdr, flags) };
-    if n < 0 {
-        return Err(io::Error::last_os_error());
-    }
-    if n == 0 {
-        assert_eq!(hdr.msg_controllen, 0);
-        return Ok(0);
-    }
-
-    let fd_count = if hdr.msg_controllen > 0 {
-        if cmsg.hdr.cmsg_level != libc::SOL_SOCKET || cmsg.hdr.cmsg_type != libc::SCM_RIGHTS {
-            // BUGBUG: need to loop: possible to leak fds
-            return Err(ErrorKind::InvalidData.into());
-        }
-        #[allow(clippy::unnecessary_cast)] // cmsg_len is u32 on musl and usize on gnu.
-        {
-            (cmsg.hdr.cmsg_len as usize - size_of_val(&cmsg.hdr)) / size_of::<RawFd>()
-        }
-    } else {
-        0
-    };
-
-    let start = fds.len();
-    fds.extend(cmsg.fds[..fd_count].iter().map(|x| {
-        // SAFETY: according to the contract with the kernel, this
-        // fd is now owned by the process.
-        OsResource::Fd(unsafe { OwnedFd::from_raw_fd(*x) })
-    }));
-
-    // Set O_CLOEXEC on all received fds on platforms that don't support
-    // MSG_CMSG_CLOEXEC (set above).
-    if !cfg!(target_os = "linux") {
-        for OsResource::Fd(fd) in &fds[start..] {
-            set_cloexec(fd);
-        }
-    }
-
-    // Check for truncation only after taking ownership of the fds.
-    if hdr.msg_flags & (libc::MSG_TRUNC | libc::MSG_CTRUNC) != 0 {
-        return Err(io::Error::from_raw_os_error(libc::EMSGSIZE));
-    }
-    Ok(n as usize)
-}
-
-fn set_cloexec(fd: impl AsFd) {
-    // SAFETY: using fcntl as documented.
-    unsafe {
-        let flags = libc::fcntl(fd.as_fd().as_raw_fd(), libc::F_GETFD);
-        assert!(flags >= 0);
-        let r = libc::fcntl(
-            fd.as_fd().as_raw_fd(),
-            libc::F_SETFD,
-            flags | libc::FD_CLOEXEC,
-        );
-        assert!(r >= 0);
-    }
+    let mut received = Vec::new();
+    let r = pal::unix::fds::recv_with_fds(socket, buf, &mut received);
+    fds.extend(received.into_iter().map(OsResource::Fd));
+    r
 }
 
 #[cfg(test)]
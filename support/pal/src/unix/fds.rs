@@ -0,0 +1,181 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Sending and receiving file descriptors over Unix sockets, using the
+//! `SCM_RIGHTS` functionality.
+
+use std::io;
+use std::io::IoSlice;
+use std::io::IoSliceMut;
+use std::os::fd::AsFd;
+use std::os::fd::AsRawFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+use std::os::fd::RawFd;
+
+/// The maximum number of file descriptors that can be sent or received with a
+/// single message.
+pub const MAX_FDS: usize = 64;
+
+#[repr(C)]
+struct CmsgScmRights {
+    hdr: libc::cmsghdr,
+    fds: [RawFd; MAX_FDS],
+}
+
+/// Sends `msg` on `socket`, attaching the file descriptors `fds`. Returns the
+/// number of bytes sent.
+///
+/// May fail with `WouldBlock` if the socket is non-blocking.
+///
+/// # Panics
+///
+/// Panics if more than [`MAX_FDS`] file descriptors are passed.
+// x86_64-unknown-linux-musl targets have a different type defn for
+// `libc::cmsghdr`, hence why these lints are being suppressed.
+#[allow(clippy::needless_update, clippy::useless_conversion)]
+pub fn send_with_fds(
+    socket: impl AsFd,
+    msg: &[IoSlice<'_>],
+    fds: &[impl AsFd],
+) -> io::Result<usize> {
+    assert!(fds.len() <= MAX_FDS);
+    let mut cmsg = CmsgScmRights {
+        hdr: libc::cmsghdr {
+            cmsg_level: libc::SOL_SOCKET,
+            cmsg_type: libc::SCM_RIGHTS,
+            cmsg_len: (size_of::<libc::cmsghdr>() + size_of_val(fds))
+                .try_into()
+                .unwrap(),
+
+            ..{
+                // SAFETY: type has no invariants
+                unsafe { std::mem::zeroed() }
+            }
+        },
+        fds: [0; MAX_FDS],
+    };
+    for (fdi, fdo) in fds.iter().zip(cmsg.fds.iter_mut()) {
+        *fdo = fdi.as_fd().as_raw_fd();
+    }
+
+    // SAFETY: type has no invariants
+    let mut hdr: libc::msghdr = unsafe { std::mem::zeroed() };
+    hdr.msg_iov = msg.as_ptr() as *mut libc::iovec;
+    hdr.msg_iovlen = msg.len().try_into().unwrap();
+    hdr.msg_control = if fds.is_empty() {
+        std::ptr::null_mut()
+    } else {
+        std::ptr::from_mut(&mut cmsg).cast::<libc::c_void>()
+    };
+    hdr.msg_controllen = if fds.is_empty() { 0 } else { cmsg.hdr.cmsg_len };
+    // SAFETY: calling with appropriately initialized buffers.
+    let n = unsafe { libc::sendmsg(socket.as_fd().as_raw_fd(), &hdr, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+/// Receives the next message from `socket` into `buf`, appending any attached
+/// file descriptors to `fds`. Returns the number of bytes read, or 0 if the
+/// socket has been closed.
+///
+/// The received file descriptors have `FD_CLOEXEC` set.
+///
+/// Fails with `EMSGSIZE` if the message or its file descriptors were
+/// truncated, or with `InvalidData` if the message carried control data other
+/// than file descriptors. Any file descriptors that were received are still
+/// appended to `fds`.
+///
+/// May fail with `WouldBlock` if the socket is non-blocking.
+pub fn recv_with_fds(
+    socket: impl AsFd,
+    buf: &mut [u8],
+    fds: &mut Vec<OwnedFd>,
+) -> io::Result<usize> {
+    assert!(!buf.is_empty());
+    let mut iov = IoSliceMut::new(buf);
+    // SAFETY: type has no invariants
+    let mut cmsg: CmsgScmRights = unsafe { std::mem::zeroed() };
+    // SAFETY: type has no invariants
+    let mut hdr: libc::msghdr = unsafe { std::mem::zeroed() };
+    hdr.msg_iov = std::ptr::from_mut(&mut iov).cast::<libc::iovec>();
+    hdr.msg_iovlen = 1;
+    hdr.msg_control = std::ptr::from_mut(&mut cmsg).cast::<libc::c_void>();
+    hdr.msg_controllen = size_of_val(&cmsg) as _;
+
+    // On Linux, automatically set O_CLOEXEC on incoming fds.
+    #[cfg(target_os = "linux")]
+    let flags = libc::MSG_CMSG_CLOEXEC;
+    #[cfg(not(target_os = "linux"))]
+    let flags = 0;
+
+    // SAFETY: calling with properly initialized buffers.
+    let n = unsafe { libc::recvmsg(socket.as_fd().as_raw_fd(), &mut hdr, flags) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if n == 0 {
+        assert_eq!(hdr.msg_controllen, 0);
+        return Ok(0);
+    }
+
+    // Take ownership of the fds in every control message, so that none are
+    // leaked if there is an unexpected message before or between them.
+    let start = fds.len();
+    let mut unexpected = false;
+    // SAFETY: `hdr` describes the control buffer filled in by the kernel.
+    let mut cmsg_ptr = unsafe { libc::CMSG_FIRSTHDR(&hdr) };
+    while !cmsg_ptr.is_null() {
+        // SAFETY: the kernel wrote a complete header at this position.
+        let cmsg_hdr = unsafe { cmsg_ptr.read_unaligned() };
+        if cmsg_hdr.cmsg_level == libc::SOL_SOCKET && cmsg_hdr.cmsg_type == libc::SCM_RIGHTS {
+            let data_offset = libc::CMSG_LEN(0) as usize;
+            #[allow(clippy::unnecessary_cast)] // cmsg_len is u32 on musl and usize on gnu.
+            let fd_count = (cmsg_hdr.cmsg_len as usize - data_offset) / size_of::<RawFd>();
+            // SAFETY: the message's data follows its header.
+            let data = unsafe { libc::CMSG_DATA(cmsg_ptr) }.cast::<RawFd>();
+            fds.extend((0..fd_count).map(|i| {
+                // SAFETY: according to the contract with the kernel, this
+                // fd is within the message and is now owned by the process.
+                unsafe { OwnedFd::from_raw_fd(data.add(i).read_unaligned()) }
+            }));
+        } else {
+            unexpected = true;
+        }
+        // SAFETY: `cmsg_ptr` is a header within the control buffer.
+        cmsg_ptr = unsafe { libc::CMSG_NXTHDR(&hdr, cmsg_ptr) };
+    }
+
+    // Set O_CLOEXEC on all received fds on platforms that don't support
+    // MSG_CMSG_CLOEXEC (set above).
+    if !cfg!(target_os = "linux") {
+        for fd in &fds[start..] {
+            set_cloexec(fd);
+        }
+    }
+
+    // Check for truncation only after taking ownership of the fds.
+    if hdr.msg_flags & (libc::MSG_TRUNC | libc::MSG_CTRUNC) != 0 {
+        return Err(io::Error::from_raw_os_error(libc::EMSGSIZE));
+    }
+    if unexpected {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+    Ok(n as usize)
+}
+
+fn set_cloexec(fd: impl AsFd) {
+    // SAFETY: using fcntl as documented.
+    unsafe {
+        let flags = libc::fcntl(fd.as_fd().as_raw_fd(), libc::F_GETFD);
+        assert!(flags >= 0);
+        let r = libc::fcntl(
+            fd.as_fd().as_raw_fd(),
+            libc::F_SETFD,
+            flags | libc::FD_CLOEXEC,
+        );
+        assert!(r >= 0);
+    }
+}
@@ -220,6 +220,30 @@ pub fn set_writable(&self, offset: usize, len: usize, allow_writes: bool) -> Res
         self.mprotect(offset, len, prot)
     }
 
+    /// Releases the host memory backing the given range of the mapping.
+    ///
+    /// The range stays mapped with its current protection, but its contents
+    /// are undefined afterwards.
+    pub fn decommit(&self, offset: usize, len: usize) -> Result<(), Error> {
+        self.validate_offset_len(offset, len)?;
+        // SAFETY: The address range is within the mapping, and callers of
+        // this method have agreed to the contents being discarded.
+        unsafe {
+            let address = self.address.add(offset);
+            // Prefer MADV_REMOVE, which frees the backing pages of shared
+            // mappings. Fall back to MADV_DONTNEED, which only releases
+            // private pages, for mappings that do not support it.
+            #[cfg(target_os = "linux")]
+            if libc::madvise(address, len, libc::MADV_REMOVE) == 0 {
+                return Ok(());
+            }
+            if libc::madvise(address, len, libc::MADV_DONTNEED) < 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
     /// Calls `mprotect` on the mapping at the given offset and length, changing
     /// the protection flags to `prot`.
     fn mprotect(&self, offset: usize, len: usize, prot: i32) -> Result<(), Error> {
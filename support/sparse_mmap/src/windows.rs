@@ -9,6 +9,7 @@
 use Memory::MEM_COMMIT;
 use Memory::MEM_RELEASE;
 use Memory::MEM_RESERVE;
+use Memory::MEM_RESET;
 use Memory::MEMORY_MAPPED_VIEW_ADDRESS;
 use Memory::MapViewOfFile3;
 use Memory::PAGE_EXECUTE;
@@ -374,6 +375,28 @@ pub fn map_zero(&self, offset: usize, len: usize) -> Result<(), Error> {
         self.virtual_alloc(offset, len, PAGE_READONLY)
     }
 
+    /// Releases the host memory backing the given range of the mapping.
+    ///
+    /// The range stays mapped with its current protection, but its contents
+    /// are undefined afterwards.
+    pub fn decommit(&self, offset: usize, len: usize) -> Result<(), Error> {
+        self.validate_offset_len(offset, len)?;
+        // SAFETY: The address range is within the mapping, and callers of
+        // this method have agreed to the contents being discarded.
+        unsafe {
+            virtual_alloc(
+                self.process.as_ref(),
+                self.address.wrapping_add(offset),
+                len,
+                MEM_RESET,
+                PAGE_NOACCESS,
+                null_mut(),
+                0,
+            )?;
+        }
+        Ok(())
+    }
+
     fn validate_offset_len(&self, offset: usize, len: usize) -> io::Result<usize> {
         let end = offset.checked_add(len).ok_or(io::ErrorKind::InvalidInput)?;
         if !offset.is_multiple_of(PAGE_SIZE) || !end.is_multiple_of(PAGE_SIZE) || end > self.len {
@@ -12,6 +12,7 @@
 use hyperv_ic_protocol::Version;
 use inspect::Inspect;
 use inspect::InspectMut;
+use pal_async::timer::Instant;
 use std::io::IoSlice;
 use vmbus_async::async_dgram::AsyncRecvExt;
 use vmbus_async::async_dgram::AsyncSendExt;
@@ -164,3 +165,15 @@ pub async fn read_response(&mut self) -> anyhow::Result<(Status, &[u8])> {
         Ok((header.status, rest))
     }
 }
+
+/// Inspects `instant` as the corresponding wall clock time.
+pub(crate) fn inspect_instant(&instant: &Instant) -> inspect::AsDisplay<jiff::Timestamp> {
+    let now = Instant::now();
+    let time = jiff::Timestamp::now();
+    let sd = if now <= instant {
+        jiff::SignedDuration::try_from(instant - now).unwrap()
+    } else {
+        -jiff::SignedDuration::try_from(now - instant).unwrap()
+    };
+    inspect::AsDisplay(time + sd)
+}
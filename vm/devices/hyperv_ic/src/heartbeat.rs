@@ -0,0 +1,328 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! The heartbeat IC.
+//!
+//! The host periodically sends a heartbeat request to the guest, and the guest
+//! responds with its application state. The guest is considered healthy as
+//! long as it keeps responding and does not report a critical error.
+
+use crate::common::IcPipe;
+use crate::common::NegotiateState;
+use crate::common::Versions;
+use crate::common::inspect_instant;
+use anyhow::Context as _;
+use async_trait::async_trait;
+use futures::FutureExt;
+use futures::StreamExt;
+use futures::stream::once;
+use futures_concurrency::stream::Merge;
+use guestmem::GuestMemory;
+use hyperv_ic_protocol::Status;
+use hyperv_ic_protocol::heartbeat as proto;
+use hyperv_ic_resources::heartbeat::HeartbeatRpc;
+use hyperv_ic_resources::heartbeat::HeartbeatStatus;
+use inspect::Inspect;
+use inspect::InspectMut;
+use mesh::rpc::Rpc;
+use pal_async::driver::Driver;
+use pal_async::timer::Instant;
+use pal_async::timer::PolledTimer;
+use std::future::pending;
+use std::pin::pin;
+use std::time::Duration;
+use task_control::Cancelled;
+use task_control::StopTask;
+use vmbus_channel::RawAsyncChannel;
+use vmbus_channel::bus::ChannelType;
+use vmbus_channel::bus::OfferParams;
+use vmbus_channel::channel::ChannelOpenError;
+use vmbus_channel::gpadl_ring::GpadlRingMem;
+use vmbus_channel::simple::SaveRestoreSimpleVmbusDevice;
+use vmbus_channel::simple::SimpleVmbusDevice;
+use vmcore::save_restore::NoSavedState;
+use zerocopy::FromBytes;
+use zerocopy::IntoBytes;
+
+const HEARTBEAT_VERSIONS: &[hyperv_ic_protocol::Version] =
+    &[proto::HEARTBEAT_VERSION_1, proto::HEARTBEAT_VERSION_3];
+
+/// Send a heartbeat every second.
+const HEARTBEAT_PERIOD: Duration = Duration::from_secs(1);
+
+/// Consider communication with the guest lost if it has not responded to a
+/// heartbeat for this long.
+const LOST_COMMUNICATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A heartbeat IC device.
+#[derive(InspectMut)]
+pub struct HeartbeatIc {
+    #[inspect(skip)]
+    recv: mesh::Receiver<HeartbeatRpc>,
+    #[inspect(skip)]
+    timer: PolledTimer,
+    #[inspect(flatten)]
+    health: GuestHealth,
+    #[inspect(skip)]
+    wait_ok: Vec<Rpc<(), ()>>,
+}
+
+/// The guest health, as of the last heartbeat response.
+#[derive(Inspect)]
+struct GuestHealth {
+    #[inspect(debug)]
+    application_state: proto::ApplicationState,
+    sequence_number: u64,
+    #[inspect(with = "|x| x.as_ref().map(inspect_instant)")]
+    last_heartbeat: Option<Instant>,
+    #[inspect(debug)]
+    status: HeartbeatStatus,
+}
+
+impl GuestHealth {
+    fn new() -> Self {
+        Self {
+            application_state: proto::ApplicationState::UNKNOWN,
+            sequence_number: 0,
+            last_heartbeat: None,
+            status: HeartbeatStatus::NoContact,
+        }
+    }
+
+    /// Updates and returns the current status.
+    fn update_status(&mut self) -> HeartbeatStatus {
+        self.status = match self.last_heartbeat {
+            None => HeartbeatStatus::NoContact,
+            Some(last) if Instant::now().saturating_sub(last) > LOST_COMMUNICATION_TIMEOUT => {
+                HeartbeatStatus::LostCommunication
+            }
+            Some(_) => match self.application_state {
+                proto::ApplicationState::CRITICAL => HeartbeatStatus::Critical,
+                proto::ApplicationState::STOPPED => HeartbeatStatus::Stopped,
+                // Linux guests do not report an application state, so treat
+                // unknown as healthy.
+                _ => HeartbeatStatus::Ok,
+            },
+        };
+        self.status
+    }
+}
+
+#[doc(hidden)]
+#[derive(InspectMut)]
+pub struct HeartbeatChannel {
+    #[inspect(mut)]
+    pipe: IcPipe,
+    state: ChannelState,
+}
+
+#[derive(Inspect)]
+#[inspect(external_tag)]
+enum ChannelState {
+    Negotiate(#[inspect(rename = "state")] NegotiateState),
+    Ready {
+        versions: Versions,
+        state: ReadyState,
+    },
+    Failed,
+}
+
+#[derive(Inspect)]
+#[inspect(external_tag)]
+enum ReadyState {
+    SleepUntilNextHeartbeat {
+        #[inspect(with = "inspect_instant")]
+        next_heartbeat: Instant,
+    },
+    SendHeartbeat,
+    WaitForResponse,
+}
+
+impl HeartbeatIc {
+    /// Returns a new heartbeat IC, using `recv` to receive guest health
+    /// requests.
+    pub fn new(driver: &(impl Driver + ?Sized), recv: mesh::Receiver<HeartbeatRpc>) -> Self {
+        Self {
+            recv,
+            timer: PolledTimer::new(driver),
+            health: GuestHealth::new(),
+            wait_ok: Vec::new(),
+        }
+    }
+}
+
+impl HeartbeatChannel {
+    fn new(channel: RawAsyncChannel<GpadlRingMem>) -> Result<Self, ChannelOpenError> {
+        let pipe = IcPipe::new(channel)?;
+        Ok(Self {
+            pipe,
+            state: ChannelState::Negotiate(NegotiateState::default()),
+        })
+    }
+
+    async fn process(&mut self, ic: &mut HeartbeatIc) -> ! {
+        enum Event {
+            StateMachine(anyhow::Result<()>),
+            Request(HeartbeatRpc),
+        }
+
+        loop {
+            let event = pin!(
+                (
+                    once(
+                        self.process_state_machine(&mut ic.timer, &mut ic.health)
+                            .map(Event::StateMachine)
+                    ),
+                    (&mut ic.recv).map(Event::Request),
+                )
+                    .merge()
+            )
+            .next()
+            .await
+            .unwrap();
+            match event {
+                Event::StateMachine(r) => {
+                    if let Err(err) = r {
+                        tracing::error!(
+                            error = err.as_ref() as &dyn std::error::Error,
+                            "heartbeat ic error"
+                        );
+                        self.state = ChannelState::Failed;
+                    }
+                }
+                Event::Request(req) => match req {
+                    HeartbeatRpc::GetStatus(rpc) => rpc.complete(ic.health.update_status()),
+                    HeartbeatRpc::WaitOk(rpc) => ic.wait_ok.push(rpc),
+                },
+            }
+            if !ic.wait_ok.is_empty() && ic.health.update_status() == HeartbeatStatus::Ok {
+                for rpc in ic.wait_ok.drain(..) {
+                    rpc.complete(());
+                }
+            }
+        }
+    }
+
+    async fn process_state_machine(
+        &mut self,
+        timer: &mut PolledTimer,
+        health: &mut GuestHealth,
+    ) -> anyhow::Result<()> {
+        match self.state {
+            ChannelState::Negotiate(ref mut state) => {
+                if let Some(versions) = self.pipe.negotiate(state, HEARTBEAT_VERSIONS).await? {
+                    tracelimit::info_ratelimited!(
+                        framework = %versions.framework_version,
+                        version = %versions.message_version,
+                        "heartbeat versions negotiated"
+                    );
+                    self.state = ChannelState::Ready {
+                        versions,
+                        state: ReadyState::SendHeartbeat,
+                    };
+                }
+            }
+            ChannelState::Ready {
+                ref versions,
+                ref mut state,
+            } => match *state {
+                ReadyState::SleepUntilNextHeartbeat { next_heartbeat } => {
+                    timer.sleep_until(next_heartbeat).await;
+                    *state = ReadyState::SendHeartbeat;
+                }
+                ReadyState::SendHeartbeat => {
+                    let message = proto::HeartbeatMessage {
+                        sequence_number: health.sequence_number,
+                        application_state: proto::ApplicationState::UNKNOWN,
+                        reserved: [0; 4],
+                    };
+                    self.pipe
+                        .write_message(
+                            versions,
+                            hyperv_ic_protocol::MessageType::HEARTBEAT,
+                            hyperv_ic_protocol::HeaderFlags::new()
+                                .with_request(true)
+                                .with_transaction(true),
+                            message.as_bytes(),
+                        )
+                        .await?;
+
+                    *state = ReadyState::WaitForResponse;
+                }
+                ReadyState::WaitForResponse => {
+                    let (status, message) = self.pipe.read_response().await?;
+                    if status != Status::SUCCESS {
+                        anyhow::bail!("heartbeat failed with status {:#x}", status.0);
+                    }
+                    let (message, _) = proto::HeartbeatMessage::read_from_prefix(message)
+                        .ok()
+                        .context("missing heartbeat message")?;
+
+                    if message.application_state != health.application_state {
+                        tracing::info!(
+                            application_state = ?message.application_state,
+                            "guest application state changed"
+                        );
+                    }
+                    health.sequence_number = message.sequence_number;
+                    health.application_state = message.application_state;
+                    health.last_heartbeat = Some(Instant::now());
+
+                    *state = ReadyState::SleepUntilNextHeartbeat {
+                        next_heartbeat: Instant::now() + HEARTBEAT_PERIOD,
+                    };
+                }
+            },
+            ChannelState::Failed => pending().await,
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SimpleVmbusDevice for HeartbeatIc {
+    type SavedState = NoSavedState;
+    type Runner = HeartbeatChannel;
+
+    fn offer(&self) -> OfferParams {
+        OfferParams {
+            interface_name: "heartbeat_ic".to_owned(),
+            instance_id: proto::INSTANCE_ID,
+            interface_id: proto::INTERFACE_ID,
+            channel_type: ChannelType::Pipe { message_mode: true },
+            ..Default::default()
+        }
+    }
+
+    fn inspect(&mut self, req: inspect::Request<'_>, runner: Option<&mut Self::Runner>) {
+        self.health.update_status();
+        req.respond().merge(self).merge(runner);
+    }
+
+    fn open(
+        &mut self,
+        channel: RawAsyncChannel<GpadlRingMem>,
+        _guest_memory: GuestMemory,
+    ) -> Result<Self::Runner, ChannelOpenError> {
+        // The guest's health from a previous connection no longer applies.
+        self.health = GuestHealth::new();
+        HeartbeatChannel::new(channel)
+    }
+
+    async fn run(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        runner: &mut Self::Runner,
+    ) -> Result<(), Cancelled> {
+        stop.until_stopped(async { runner.process(self).await })
+            .await
+    }
+
+    fn supports_save_restore(
+        &mut self,
+    ) -> Option<
+        &mut dyn SaveRestoreSimpleVmbusDevice<SavedState = Self::SavedState, Runner = Self::Runner>,
+    > {
+        None
+    }
+}
@@ -12,11 +12,14 @@
 //! * timesync IC for synchronizing time
 //! * heartbeat IC for reporting guest health
 //! * KVP IC for exchanging arbitrary key/value data between the host and guest
+//! * VSS IC for freezing the guest's filesystems during backups
 
 #![forbid(unsafe_code)]
 
 mod common;
+pub mod heartbeat;
 pub mod kvp;
 pub mod resolver;
 pub mod shutdown;
 pub mod timesync;
+pub mod vss;
@@ -12,6 +12,7 @@
 use crate::common::IcPipe;
 use crate::common::NegotiateState;
 use crate::common::Versions;
+use crate::common::inspect_instant;
 use async_trait::async_trait;
 use guestmem::GuestMemory;
 use hyperv_ic_protocol::timesync as proto;
@@ -81,17 +82,6 @@ enum ReadyState {
     WaitForResponse,
 }
 
-fn inspect_instant(&instant: &Instant) -> inspect::AsDisplay<jiff::Timestamp> {
-    let now = Instant::now();
-    let time = jiff::Timestamp::now();
-    let sd = if now <= instant {
-        jiff::SignedDuration::try_from(instant - now).unwrap()
-    } else {
-        -jiff::SignedDuration::try_from(now - instant).unwrap()
-    };
-    inspect::AsDisplay(time + sd)
-}
-
 impl TimesyncIc {
     /// Create a new timesync IC.
     pub fn new(driver: &(impl Driver + ?Sized), ref_time: ReferenceTimeSource) -> Self {
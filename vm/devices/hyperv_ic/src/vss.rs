@@ -0,0 +1,342 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! The VSS (Volume Shadow Service) IC.
+//!
+//! This only implements the application freeze and thaw operations, which are
+//! used to make the guest's disks consistent while the host snapshots them.
+//! The guest-side shadow copy operations are not supported.
+
+use crate::common::IcPipe;
+use crate::common::NegotiateState;
+use crate::common::Versions;
+use async_trait::async_trait;
+use futures::FutureExt;
+use futures::StreamExt;
+use futures::stream::once;
+use futures_concurrency::stream::Merge;
+use guestmem::GuestMemory;
+use hyperv_ic_protocol::Status;
+use hyperv_ic_protocol::vss as proto;
+use hyperv_ic_resources::vss::VssRpc;
+use inspect::Inspect;
+use inspect::InspectMut;
+use mesh::rpc::FailableRpc;
+use pal_async::driver::Driver;
+use pal_async::timer::Instant;
+use pal_async::timer::PolledTimer;
+use std::future::pending;
+use std::pin::pin;
+use std::time::Duration;
+use task_control::Cancelled;
+use task_control::StopTask;
+use thiserror::Error;
+use vmbus_channel::RawAsyncChannel;
+use vmbus_channel::bus::ChannelType;
+use vmbus_channel::bus::OfferParams;
+use vmbus_channel::channel::ChannelOpenError;
+use vmbus_channel::gpadl_ring::GpadlRingMem;
+use vmbus_channel::simple::SaveRestoreSimpleVmbusDevice;
+use vmbus_channel::simple::SimpleVmbusDevice;
+use vmcore::save_restore::NoSavedState;
+use zerocopy::FromZeros;
+use zerocopy::IntoBytes;
+
+/// Freeze and thaw require version 5.0 or later.
+const VSS_VERSIONS: &[hyperv_ic_protocol::Version] = &[
+    proto::VSS_VERSION_WINBLUE,
+    proto::VSS_VERSION_THRESHOLD,
+    proto::VSS_VERSION_THRESHOLD_UR1,
+];
+
+/// Fail a request if the guest has not responded within this long. The guest
+/// may still complete the operation later.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A VSS IC device.
+#[derive(InspectMut)]
+pub struct VssIc {
+    #[inspect(skip)]
+    recv: mesh::Receiver<VssRpc>,
+    #[inspect(skip)]
+    timer: PolledTimer,
+    /// Whether the guest has been successfully frozen and not yet thawed.
+    frozen: bool,
+}
+
+#[doc(hidden)]
+#[derive(InspectMut)]
+pub struct VssChannel {
+    #[inspect(mut)]
+    pipe: IcPipe,
+    state: ChannelState,
+    #[inspect(with = "Option::is_some")]
+    pending_request: Option<(FailableRpc<(), ()>, Instant)>,
+}
+
+#[derive(Inspect)]
+#[inspect(external_tag)]
+enum ChannelState {
+    Negotiate(#[inspect(rename = "state")] NegotiateState),
+    Ready {
+        versions: Versions,
+        state: ReadyState,
+    },
+    Failed,
+}
+
+#[derive(Inspect)]
+#[inspect(external_tag)]
+enum ReadyState {
+    Ready,
+    SendRequest(#[inspect(debug)] proto::Operation),
+    WaitResponse(#[inspect(debug)] proto::Operation),
+}
+
+#[derive(Debug, Error)]
+enum RequestError {
+    #[error("the vss ic is not ready")]
+    NotReady,
+    #[error("a vss request is already in progress")]
+    AlreadyInProgress,
+    #[error("the vss channel failed")]
+    ChannelFailed,
+    #[error("the guest is not frozen")]
+    NotFrozen,
+    #[error("the guest did not respond to the vss request")]
+    TimedOut,
+    #[error("vss operation {0:?} failed with status {1:#x}")]
+    Failed(proto::Operation, u32),
+}
+
+impl VssIc {
+    /// Returns a new VSS IC, using `recv` to receive freeze and thaw requests.
+    pub fn new(driver: &(impl Driver + ?Sized), recv: mesh::Receiver<VssRpc>) -> Self {
+        Self {
+            recv,
+            timer: PolledTimer::new(driver),
+            frozen: false,
+        }
+    }
+}
+
+impl VssChannel {
+    fn new(channel: RawAsyncChannel<GpadlRingMem>) -> Result<Self, ChannelOpenError> {
+        let pipe = IcPipe::new(channel)?;
+        Ok(Self {
+            pipe,
+            state: ChannelState::Negotiate(NegotiateState::default()),
+            pending_request: None,
+        })
+    }
+
+    async fn process(&mut self, ic: &mut VssIc) -> ! {
+        enum Event {
+            StateMachine(anyhow::Result<()>),
+            Request(VssRpc),
+            Timeout,
+        }
+
+        loop {
+            let deadline = self.pending_request.as_ref().map(|(_, deadline)| *deadline);
+            let timer = &mut ic.timer;
+            let timeout = async move {
+                match deadline {
+                    Some(deadline) => timer.sleep_until(deadline).await,
+                    None => pending().await,
+                }
+            };
+            let event = pin!(
+                (
+                    once(
+                        self.process_state_machine(&mut ic.frozen)
+                            .map(Event::StateMachine)
+                    ),
+                    (&mut ic.recv).map(Event::Request),
+                    once(timeout.map(|()| Event::Timeout)),
+                )
+                    .merge()
+            )
+            .next()
+            .await
+            .unwrap();
+            match event {
+                Event::StateMachine(r) => {
+                    if let Err(err) = r {
+                        tracing::error!(
+                            error = err.as_ref() as &dyn std::error::Error,
+                            "vss ic error"
+                        );
+                        self.state = ChannelState::Failed;
+                        if let Some((rpc, _)) = self.pending_request.take() {
+                            rpc.fail(RequestError::ChannelFailed);
+                        }
+                    }
+                }
+                Event::Timeout => {
+                    // Keep waiting for the response so that it is not
+                    // mistaken for the response to a later request.
+                    tracing::warn!("guest did not respond to vss request");
+                    if let Some((rpc, _)) = self.pending_request.take() {
+                        rpc.fail(RequestError::TimedOut);
+                    }
+                }
+                Event::Request(req) => {
+                    let (operation, rpc) = match req {
+                        VssRpc::Freeze(rpc) => (proto::Operation::FREEZE_APPLICATIONS, rpc),
+                        VssRpc::Thaw(rpc) => {
+                            if !ic.frozen {
+                                rpc.fail(RequestError::NotFrozen);
+                                continue;
+                            }
+                            (proto::Operation::THAW_APPLICATIONS, rpc)
+                        }
+                    };
+                    match &mut self.state {
+                        ChannelState::Negotiate(_) => rpc.fail(RequestError::NotReady),
+                        ChannelState::Failed => rpc.fail(RequestError::ChannelFailed),
+                        ChannelState::Ready { state, .. } => match state {
+                            ReadyState::Ready => {
+                                self.pending_request =
+                                    Some((rpc, Instant::now() + REQUEST_TIMEOUT));
+                                *state = ReadyState::SendRequest(operation);
+                            }
+                            ReadyState::SendRequest(_) | ReadyState::WaitResponse(_) => {
+                                rpc.fail(RequestError::AlreadyInProgress)
+                            }
+                        },
+                    }
+                }
+            }
+        }
+    }
+
+    async fn process_state_machine(&mut self, frozen: &mut bool) -> anyhow::Result<()> {
+        match self.state {
+            ChannelState::Negotiate(ref mut state) => {
+                if let Some(versions) = self.pipe.negotiate(state, VSS_VERSIONS).await? {
+                    tracelimit::info_ratelimited!(
+                        framework = %versions.framework_version,
+                        version = %versions.message_version,
+                        "vss versions negotiated"
+                    );
+                    self.state = ChannelState::Ready {
+                        versions,
+                        state: ReadyState::Ready,
+                    };
+                }
+            }
+            ChannelState::Ready {
+                ref versions,
+                ref mut state,
+            } => match *state {
+                ReadyState::Ready => pending().await,
+                ReadyState::SendRequest(operation) => {
+                    let header = proto::VssHeader {
+                        operation,
+                        reserved: [0; 7],
+                    };
+                    let message = if operation == proto::Operation::FREEZE_APPLICATIONS {
+                        // Leave the LUN list empty to freeze all volumes.
+                        let message = Box::new(proto::Message2 {
+                            header,
+                            backup_type: 0,
+                            flags: 0,
+                            lun_count: 0,
+                            luns: FromZeros::new_zeroed(),
+                        });
+                        message.as_bytes().to_vec()
+                    } else {
+                        proto::VssMessage {
+                            header,
+                            reserved: [0; 7],
+                            data: [0; 24],
+                        }
+                        .as_bytes()
+                        .to_vec()
+                    };
+
+                    self.pipe
+                        .write_message(
+                            versions,
+                            hyperv_ic_protocol::MessageType::VSS,
+                            hyperv_ic_protocol::HeaderFlags::new()
+                                .with_transaction(true)
+                                .with_request(true),
+                            &message,
+                        )
+                        .await?;
+
+                    *state = ReadyState::WaitResponse(operation);
+                }
+                ReadyState::WaitResponse(operation) => {
+                    let (status, _) = self.pipe.read_response().await?;
+                    let result = if status == Status::SUCCESS {
+                        *frozen = operation == proto::Operation::FREEZE_APPLICATIONS;
+                        tracing::info!(frozen = *frozen, "guest vss operation complete");
+                        Ok(())
+                    } else {
+                        Err(RequestError::Failed(operation, status.0))
+                    };
+                    if let Some((rpc, _)) = self.pending_request.take() {
+                        rpc.handle_failable_sync(|()| result);
+                    }
+                    *state = ReadyState::Ready;
+                }
+            },
+            ChannelState::Failed => pending().await,
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SimpleVmbusDevice for VssIc {
+    type SavedState = NoSavedState;
+    type Runner = VssChannel;
+
+    fn offer(&self) -> OfferParams {
+        OfferParams {
+            interface_name: "vss_ic".to_owned(),
+            instance_id: proto::INSTANCE_ID,
+            interface_id: proto::INTERFACE_ID,
+            channel_type: ChannelType::Pipe { message_mode: true },
+            ..Default::default()
+        }
+    }
+
+    fn inspect(&mut self, req: inspect::Request<'_>, runner: Option<&mut Self::Runner>) {
+        req.respond().merge(self).merge(runner);
+    }
+
+    fn open(
+        &mut self,
+        channel: RawAsyncChannel<GpadlRingMem>,
+        _guest_memory: GuestMemory,
+    ) -> Result<Self::Runner, ChannelOpenError> {
+        // The guest's VSS service thaws the guest if it loses its connection,
+        // so a freeze from a previous connection no longer applies.
+        if self.frozen {
+            tracing::warn!("vss channel reopened while the guest was frozen");
+            self.frozen = false;
+        }
+        VssChannel::new(channel)
+    }
+
+    async fn run(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        runner: &mut Self::Runner,
+    ) -> Result<(), Cancelled> {
+        stop.until_stopped(async { runner.process(self).await })
+            .await
+    }
+
+    fn supports_save_restore(
+        &mut self,
+    ) -> Option<
+        &mut dyn SaveRestoreSimpleVmbusDevice<SavedState = Self::SavedState, Runner = Self::Runner>,
+    > {
+        None
+    }
+}
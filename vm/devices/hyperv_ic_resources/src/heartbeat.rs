@@ -0,0 +1,45 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resource definitions for the heartbeat IC.
+
+use mesh::MeshPayload;
+use mesh::rpc::Rpc;
+use vm_resource::ResourceId;
+use vm_resource::kind::VmbusDeviceHandleKind;
+
+/// A handle to a heartbeat IC.
+#[derive(MeshPayload)]
+pub struct HeartbeatIcHandle {
+    /// The channel by which to receive guest health requests.
+    pub recv: mesh::Receiver<HeartbeatRpc>,
+}
+
+impl ResourceId<VmbusDeviceHandleKind> for HeartbeatIcHandle {
+    const ID: &'static str = "heartbeat_ic";
+}
+
+/// An RPC request to the heartbeat IC.
+#[derive(MeshPayload)]
+pub enum HeartbeatRpc {
+    /// Gets the guest's current health.
+    GetStatus(Rpc<(), HeartbeatStatus>),
+    /// Waits for the guest to respond to a heartbeat with a healthy status.
+    WaitOk(Rpc<(), ()>),
+}
+
+/// The guest health, as reported by the heartbeat IC.
+#[derive(MeshPayload, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HeartbeatStatus {
+    /// The guest has not yet responded to a heartbeat.
+    NoContact,
+    /// The guest is responding to heartbeats and is healthy.
+    Ok,
+    /// The guest is responding to heartbeats but has reported a critical
+    /// error.
+    Critical,
+    /// The guest has reported that it is no longer running.
+    Stopped,
+    /// The guest previously responded to heartbeats but has stopped doing so.
+    LostCommunication,
+}
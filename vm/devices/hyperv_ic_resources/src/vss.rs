@@ -0,0 +1,34 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resource definitions for the VSS IC.
+
+use mesh::MeshPayload;
+use mesh::rpc::FailableRpc;
+use vm_resource::ResourceId;
+use vm_resource::kind::VmbusDeviceHandleKind;
+
+/// A handle to a VSS IC.
+#[derive(MeshPayload)]
+pub struct VssIcHandle {
+    /// The channel by which to receive freeze and thaw requests.
+    pub recv: mesh::Receiver<VssRpc>,
+}
+
+impl ResourceId<VmbusDeviceHandleKind> for VssIcHandle {
+    const ID: &'static str = "vss_ic";
+}
+
+/// An RPC request to the VSS IC.
+#[derive(MeshPayload)]
+pub enum VssRpc {
+    /// Asks the guest to flush and freeze its applications and filesystems,
+    /// so that the VM's disks are in a consistent state.
+    ///
+    /// Fails if the guest's VSS service is not running or the guest cannot
+    /// freeze. On success, the guest must be thawed with [`VssRpc::Thaw`].
+    Freeze(FailableRpc<(), ()>),
+    /// Asks the guest to thaw applications and filesystems previously frozen
+    /// with [`VssRpc::Freeze`].
+    Thaw(FailableRpc<(), ()>),
+}
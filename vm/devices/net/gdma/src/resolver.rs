@@ -51,6 +51,7 @@ async fn resolve(
                     vport.endpoint,
                     ResolveEndpointParams {
                         mac_address: vport.mac_address,
+                        driver_source: input.driver_source,
                     },
                 )
                 .await
@@ -188,20 +188,29 @@ fn queue_stats(&self) -> Option<&dyn BackendQueueStats> {
 }
 
 /// A trait for providing access to guest memory buffers.
+///
+/// Each receive buffer has a data area, described by
+/// [`guest_addresses`](Self::guest_addresses), which excludes any space the
+/// buffer reserves for its own headers. An endpoint can fill in a packet either
+/// by calling [`write_data`](Self::write_data), or by writing directly to the
+/// guest addresses (e.g., via DMA). Either way, it then calls
+/// [`write_header`](Self::write_header) with the packet's offset and length
+/// within the data area.
 pub trait BufferAccess: 'static + Send {
     /// The associated guest memory accessor.
     fn guest_memory(&self) -> &GuestMemory;
 
-    /// Writes data to the specified buffer.
+    /// Writes data to the start of the specified buffer's data area.
     fn write_data(&mut self, id: RxId, data: &[u8]);
 
-    /// The guest addresses of the specified buffer.
+    /// The guest addresses of the specified buffer's data area.
     fn guest_addresses(&mut self, id: RxId) -> &[RxBufferSegment];
 
-    /// The capacity of the specified buffer in bytes.
+    /// The capacity of the specified buffer's data area in bytes.
     fn capacity(&self, id: RxId) -> u32;
 
-    /// Sets the packet metadata for the receive.
+    /// Sets the packet metadata for the receive. `metadata.offset` and
+    /// `metadata.len` locate the packet within the data area.
     fn write_header(&mut self, id: RxId, metadata: &RxMetadata);
 
     /// Writes the packet header and data in a single call.
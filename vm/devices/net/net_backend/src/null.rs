@@ -40,7 +40,7 @@ impl ResolveResource<NetEndpointHandleKind, NullHandle> for NullResolver {
     fn resolve(
         &self,
         _resource: NullHandle,
-        _input: ResolveEndpointParams,
+        _input: ResolveEndpointParams<'_>,
     ) -> Result<Self::Output, Self::Error> {
         Ok(NullEndpoint::new().into())
     }
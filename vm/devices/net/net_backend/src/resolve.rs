@@ -7,13 +7,15 @@
 use net_backend_resources::mac_address::MacAddress;
 use vm_resource::CanResolveTo;
 use vm_resource::kind::NetEndpointHandleKind;
+use vmcore::vm_task::VmTaskDriverSource;
 
-pub struct ResolveEndpointParams {
+pub struct ResolveEndpointParams<'a> {
     pub mac_address: MacAddress,
+    pub driver_source: &'a VmTaskDriverSource,
 }
 
 impl CanResolveTo<ResolvedEndpoint> for NetEndpointHandleKind {
-    type Input<'a> = ResolveEndpointParams;
+    type Input<'a> = ResolveEndpointParams<'a>;
 }
 
 pub struct ResolvedEndpoint(pub Box<dyn Endpoint>);
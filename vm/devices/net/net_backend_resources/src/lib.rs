@@ -28,6 +28,8 @@ impl ResourceId<NetEndpointHandleKind> for NullHandle {
 /// Consomme backend.
 pub mod consomme {
     use mesh::MeshPayload;
+    use mesh::rpc::FailableRpc;
+    use std::net::Ipv4Addr;
     use vm_resource::ResourceId;
     use vm_resource::kind::NetEndpointHandleKind;
 
@@ -36,11 +38,48 @@ pub mod consomme {
     pub struct ConsommeHandle {
         /// The CIDR of the network to use.
         pub cidr: Option<String>,
+        /// Host ports to forward to the guest.
+        pub port_forwards: Vec<PortForward>,
+        /// The channel by which to receive port forwarding requests at
+        /// runtime.
+        pub control: Option<mesh::Receiver<ConsommeRpc>>,
     }
 
     impl ResourceId<NetEndpointHandleKind> for ConsommeHandle {
         const ID: &'static str = "consomme";
     }
+
+    /// The protocol of a forwarded port.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq, MeshPayload)]
+    pub enum PortProtocol {
+        /// TCP.
+        Tcp,
+        /// UDP.
+        Udp,
+    }
+
+    /// A host port forwarded to a port on the guest.
+    #[derive(Debug, Clone, PartialEq, Eq, MeshPayload)]
+    pub struct PortForward {
+        /// The protocol to forward.
+        pub protocol: PortProtocol,
+        /// The host address to bind, or `None` for all addresses.
+        pub host_address: Option<Ipv4Addr>,
+        /// The host port to bind.
+        pub host_port: u16,
+        /// The guest port to forward to.
+        pub guest_port: u16,
+    }
+
+    /// An RPC request to a Consomme endpoint.
+    #[derive(MeshPayload)]
+    pub enum ConsommeRpc {
+        /// Starts forwarding a host port to the guest.
+        AddPortForward(FailableRpc<PortForward, ()>),
+        /// Stops forwarding the host port with the given protocol and port
+        /// number.
+        RemovePortForward(FailableRpc<(PortProtocol, u16), ()>),
+    }
 }
 
 /// Windows vmswitch DirectIO backend.
@@ -90,3 +129,93 @@ impl ResourceId<NetEndpointHandleKind> for TapHandle {
         const ID: &'static str = "tap";
     }
 }
+
+/// User-mode switch backend.
+pub mod user_switch {
+    use mesh::MeshPayload;
+    use vm_resource::ResourceId;
+    use vm_resource::kind::NetEndpointHandleKind;
+
+    /// A handle to a port on a user-mode Ethernet switch.
+    #[derive(MeshPayload)]
+    pub struct UserSwitchHandle {
+        /// The path to the switch's Unix socket.
+        pub path: String,
+    }
+
+    impl ResourceId<NetEndpointHandleKind> for UserSwitchHandle {
+        const ID: &'static str = "user_switch";
+    }
+}
+
+/// vhost-user backend.
+pub mod vhost_user {
+    use mesh::MeshPayload;
+    use vm_resource::ResourceId;
+    use vm_resource::kind::NetEndpointHandleKind;
+
+    /// A handle to a vhost-user back-end.
+    #[derive(MeshPayload)]
+    pub struct VhostUserHandle {
+        /// The path to the back-end's Unix socket.
+        pub path: String,
+    }
+
+    impl ResourceId<NetEndpointHandleKind> for VhostUserHandle {
+        const ID: &'static str = "vhost_user";
+    }
+}
+
+/// Impairment wrapper backend.
+pub mod impairment {
+    use mesh::MeshPayload;
+    use std::time::Duration;
+    use vm_resource::Resource;
+    use vm_resource::ResourceId;
+    use vm_resource::kind::NetEndpointHandleKind;
+
+    /// Handle to an endpoint that impairs the traffic of another endpoint, for
+    /// testing how devices and guests handle a poor network.
+    #[derive(MeshPayload)]
+    pub struct ImpairmentHandle {
+        /// The underlying endpoint.
+        pub endpoint: Resource<NetEndpointHandleKind>,
+        /// The initial impairments for transmitted packets. These can be
+        /// changed at runtime via `inspect`.
+        pub tx: ImpairmentConfig,
+        /// The initial impairments for received packets. These can be
+        /// changed at runtime via `inspect`.
+        pub rx: ImpairmentConfig,
+        /// The seed for random impairments, so that runs are reproducible.
+        pub seed: u64,
+    }
+
+    impl ResourceId<NetEndpointHandleKind> for ImpairmentHandle {
+        const ID: &'static str = "impairment";
+    }
+
+    /// The impairments for one direction of traffic. The default is no
+    /// impairment.
+    #[derive(MeshPayload, Clone, Debug, Default, PartialEq)]
+    pub struct ImpairmentConfig {
+        /// The fixed delay added to each packet.
+        pub latency: Duration,
+        /// The maximum random delay added to each packet, on top of
+        /// `latency`. Jitter can reorder packets.
+        pub jitter: Duration,
+        /// The probability, between 0 and 1, that a packet is dropped.
+        pub loss: f64,
+        /// The probability, between 0 and 1, that a packet is delivered
+        /// twice.
+        pub duplicate: f64,
+        /// The probability, between 0 and 1, that a packet skips the delay,
+        /// overtaking earlier packets. This has no effect without `latency` or
+        /// `jitter`.
+        pub reorder: f64,
+        /// The probability, between 0 and 1, that a bit in a packet is
+        /// flipped.
+        pub corrupt: f64,
+        /// The maximum bandwidth in bits per second, or `None` for unlimited.
+        pub rate: Option<u64>,
+    }
+}
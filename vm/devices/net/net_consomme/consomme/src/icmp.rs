@@ -10,7 +10,7 @@
 use super::DropReason;
 use super::SocketAddress;
 use crate::ChecksumState;
-use crate::Ipv4Addresses;
+use crate::IpAddresses;
 
 use inspect::Inspect;
 use inspect_counters::Counter;
@@ -23,7 +23,13 @@
 use smoltcp::wire::EthernetProtocol;
 use smoltcp::wire::EthernetRepr;
 use smoltcp::wire::IPV4_HEADER_LEN;
+use smoltcp::wire::IPV6_HEADER_LEN;
+use smoltcp::wire::Icmpv6Packet;
+use smoltcp::wire::Icmpv6Repr;
+use smoltcp::wire::IpAddress;
+use smoltcp::wire::IpProtocol;
 use smoltcp::wire::Ipv4Packet;
+use smoltcp::wire::Ipv6Address;
 use socket2::Domain;
 use socket2::Protocol;
 use socket2::SockAddr;
@@ -35,11 +41,13 @@
 use std::mem::MaybeUninit;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
 use std::net::SocketAddr;
 use std::task::Context;
 use std::task::Poll;
 
 const ICMPV4_HEADER_LEN: usize = 8;
+const ICMPV6_HEADER_LEN: usize = 8;
 
 pub(crate) struct Icmp {
     connections: HashMap<SocketAddress, IcmpConnection>,
@@ -57,7 +65,7 @@ impl Inspect for Icmp {
     fn inspect(&self, req: inspect::Request<'_>) {
         let mut resp = req.respond();
         for (addr, conn) in &self.connections {
-            resp.field(&format!("{}:{}", addr.ip, addr.port), conn);
+            resp.field(&SocketAddr::from(*addr).to_string(), conn);
         }
     }
 }
@@ -88,28 +96,68 @@ fn poll_conn(
         client: &mut impl Client,
     ) {
         let mut eth = EthernetFrame::new_unchecked(&mut state.buffer);
+        // ICMPv4 sockets receive the IP header, but ICMPv6 sockets do not, so
+        // leave room to add one.
+        let header_len = match dst_addr.ip {
+            IpAddr::V4(_) => 0,
+            IpAddr::V6(_) => IPV6_HEADER_LEN,
+        };
         loop {
             match self
                 .socket
                 .poll_io(cx, InterestSlot::Read, PollEvents::IN, |socket| {
-                    Self::recv_from(socket.get(), &mut eth.payload_mut()[..])
+                    Self::recv_from(socket.get(), &mut eth.payload_mut()[header_len..])
                 }) {
-                Poll::Ready(Ok((n, _))) => {
-                    if n < IPV4_HEADER_LEN + ICMPV4_HEADER_LEN {
-                        tracing::warn!("dropping malformed ICMP incoming packet");
-                        continue;
-                    }
-
-                    // What is received is a raw IPV4 packet. Add the Ethernet frame and
-                    // set the destination address in the IP header.
-                    eth.set_ethertype(EthernetProtocol::Ipv4);
+                Poll::Ready(Ok((n, src_addr))) => {
                     eth.set_src_addr(state.params.gateway_mac);
                     eth.set_dst_addr(self.guest_mac);
-                    let mut ipv4 = Ipv4Packet::new_unchecked(eth.payload_mut());
-                    ipv4.set_dst_addr(dst_addr.ip);
-                    ipv4.fill_checksum();
-                    let len = ETHERNET_HEADER_LEN + n;
-                    client.recv(&eth.as_ref()[..len], &ChecksumState::IPV4_ONLY);
+                    let (len, checksum) = match dst_addr.ip {
+                        IpAddr::V4(dst_ip) => {
+                            if n < IPV4_HEADER_LEN + ICMPV4_HEADER_LEN {
+                                tracing::warn!("dropping malformed ICMP incoming packet");
+                                continue;
+                            }
+
+                            // What is received is a raw IPV4 packet. Add the Ethernet frame and
+                            // set the destination address in the IP header.
+                            eth.set_ethertype(EthernetProtocol::Ipv4);
+                            let mut ipv4 = Ipv4Packet::new_unchecked(eth.payload_mut());
+                            ipv4.set_dst_addr(dst_ip.into());
+                            ipv4.fill_checksum();
+                            (n, ChecksumState::IPV4_ONLY)
+                        }
+                        IpAddr::V6(_) => {
+                            let Some(src_addr) = src_addr.as_socket_ipv6() else {
+                                continue;
+                            };
+                            if n < ICMPV6_HEADER_LEN {
+                                tracing::warn!("dropping malformed ICMPv6 incoming packet");
+                                continue;
+                            }
+
+                            // What is received is just the ICMPv6 message. Add
+                            // the IPv6 header, then recompute the checksum,
+                            // which covers the addresses.
+                            let src_ip = IpAddr::V6(*src_addr.ip());
+                            crate::emit_ip_header(
+                                &mut eth,
+                                src_ip,
+                                dst_addr.ip,
+                                IpProtocol::Icmpv6,
+                                n,
+                            );
+                            let mut icmp = Icmpv6Packet::new_unchecked(
+                                &mut eth.payload_mut()[IPV6_HEADER_LEN..IPV6_HEADER_LEN + n],
+                            );
+                            icmp.fill_checksum(
+                                &IpAddress::from(src_ip),
+                                &IpAddress::from(dst_addr.ip),
+                            );
+                            (IPV6_HEADER_LEN + n, ChecksumState::NONE)
+                        }
+                    };
+                    let len = ETHERNET_HEADER_LEN + len;
+                    client.recv(&eth.as_ref()[..len], &checksum);
                     self.stats.rx_packets.increment();
                 }
                 Poll::Ready(Err(err)) => {
@@ -135,11 +183,13 @@ fn recv_from(socket: &Socket, buffer: &mut [u8]) -> std::io::Result<(usize, Sock
         Ok((read_count, addr))
     }
 
-    fn send_to(&mut self, dest: Ipv4Addr, buffer: &[u8], hop_limit: u8) -> std::io::Result<()> {
+    fn send_to(&mut self, dest: IpAddr, buffer: &[u8], hop_limit: u8) -> std::io::Result<()> {
         let socket = self.socket.get();
-        let dest = SocketAddr::new(IpAddr::V4(dest), 0);
-        socket.set_ttl_v4(hop_limit as u32)?;
-        socket.send_to(buffer, &dest.into())?;
+        match dest {
+            IpAddr::V4(_) => socket.set_ttl_v4(hop_limit as u32)?,
+            IpAddr::V6(_) => socket.set_unicast_hops_v6(hop_limit as u32)?,
+        }
+        socket.send_to(buffer, &SocketAddr::new(dest, 0).into())?;
         Ok(())
     }
 }
@@ -154,12 +204,11 @@ pub(crate) fn poll_icmp(&mut self, cx: &mut Context<'_>) {
     pub(crate) fn handle_icmp(
         &mut self,
         frame: &EthernetRepr,
-        addresses: &Ipv4Addresses,
+        addresses: &IpAddresses,
         payload: &[u8],
         _checksum: &ChecksumState,
         hop_limit: u8,
     ) -> Result<(), DropReason> {
-        let icmp_packet = smoltcp::wire::Icmpv4Packet::new_unchecked(payload);
         let guest_addr = SocketAddress {
             ip: addresses.src_addr,
             port: 0,
@@ -178,15 +227,26 @@ pub(crate) fn handle_icmp(
                 } else {
                     Type::DGRAM
                 };
-                let mut socket =
-                    match Socket::new(Domain::IPV4, socket_type, Some(Protocol::ICMPV4)) {
-                        Err(e) => {
-                            tracing::error!("socket creation failed, {}", e);
-                            return Err(DropReason::Io(e));
-                        }
-                        Ok(s) => s,
-                    };
-                Self::bind(&mut socket, Ipv4Addr::UNSPECIFIED).map_err(DropReason::Io)?;
+                let (domain, protocol, unspecified) = match addresses.src_addr {
+                    IpAddr::V4(_) => (
+                        Domain::IPV4,
+                        Protocol::ICMPV4,
+                        IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    ),
+                    IpAddr::V6(_) => (
+                        Domain::IPV6,
+                        Protocol::ICMPV6,
+                        IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                    ),
+                };
+                let mut socket = match Socket::new(domain, socket_type, Some(protocol)) {
+                    Err(e) => {
+                        tracing::error!("socket creation failed, {}", e);
+                        return Err(DropReason::Io(e));
+                    }
+                    Ok(s) => s,
+                };
+                Self::bind(&mut socket, unspecified).map_err(DropReason::Io)?;
                 let socket =
                     PolledSocket::new(self.client.driver(), socket).map_err(DropReason::Io)?;
                 let conn = IcmpConnection {
@@ -198,9 +258,7 @@ pub(crate) fn handle_icmp(
             }
         };
 
-        let send_buffer = icmp_packet.into_inner();
-        let ip4_addr = Ipv4Addr::from(addresses.dst_addr);
-        match conn.send_to(ip4_addr, send_buffer, hop_limit) {
+        match conn.send_to(addresses.dst_addr, payload, hop_limit) {
             Ok(_) => {
                 conn.stats.tx_packets.increment();
                 Ok(())
@@ -216,8 +274,43 @@ pub(crate) fn handle_icmp(
         }
     }
 
-    fn bind<A: Into<Ipv4Addr>>(socket: &mut Socket, addr: A) -> std::io::Result<()> {
-        let addr = SocketAddr::new(IpAddr::V4(addr.into()), 0);
+    pub(crate) fn handle_icmpv6(
+        &mut self,
+        frame: &EthernetRepr,
+        addresses: &IpAddresses,
+        payload: &[u8],
+        checksum: &ChecksumState,
+        hop_limit: u8,
+    ) -> Result<(), DropReason> {
+        let icmp_packet = Icmpv6Packet::new_checked(payload)?;
+        let icmp = Icmpv6Repr::parse(
+            &addresses.src_addr.into(),
+            &addresses.dst_addr.into(),
+            &icmp_packet,
+            &checksum.caps(),
+        )?;
+        let IpAddr::V6(src_addr) = addresses.src_addr else {
+            unreachable!()
+        };
+        let IpAddr::V6(dst_addr) = addresses.dst_addr else {
+            unreachable!()
+        };
+        match icmp {
+            Icmpv6Repr::Ndisc(ndisc) => {
+                self.handle_ndp(frame, Ipv6Address::from(src_addr), ndisc, hop_limit)
+            }
+            Icmpv6Repr::EchoRequest { .. } => {
+                if !crate::is_forwardable_v6(dst_addr.into()) {
+                    return Err(DropReason::UnsupportedDestination(addresses.dst_addr));
+                }
+                self.handle_icmp(frame, addresses, payload, checksum, hop_limit)
+            }
+            _ => Err(DropReason::UnsupportedIcmpv6(icmp_packet.msg_type())),
+        }
+    }
+
+    fn bind(socket: &mut Socket, addr: IpAddr) -> std::io::Result<()> {
+        let addr = SocketAddr::new(addr, 0);
         socket.bind(&addr.into())?;
         Ok(())
     }
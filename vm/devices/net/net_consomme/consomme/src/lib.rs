@@ -12,7 +12,9 @@
 //! essentially causing this stack to act as a NAT implementation, providing
 //! guest OS networking by leveraging the host's network stack.
 //!
-//! This implementation includes a small DHCP server for address assignment.
+//! This implementation includes a small DHCP server for IPv4 address
+//! assignment, and answers IPv6 router solicitations with router advertisements
+//! so that the guest can configure an IPv6 address via SLAAC.
 
 mod arp;
 mod dhcp;
@@ -20,6 +22,7 @@
 #[cfg_attr(windows, path = "dns_windows.rs")]
 mod dns;
 mod icmp;
+mod ndp;
 mod tcp;
 mod udp;
 mod windows;
@@ -35,10 +38,20 @@
 use smoltcp::wire::EthernetProtocol;
 use smoltcp::wire::EthernetRepr;
 use smoltcp::wire::IPV4_HEADER_LEN;
+use smoltcp::wire::IPV6_HEADER_LEN;
+use smoltcp::wire::Icmpv6Message;
 use smoltcp::wire::IpProtocol;
 use smoltcp::wire::Ipv4Address;
 use smoltcp::wire::Ipv4Packet;
-use std::net::SocketAddrV4;
+use smoltcp::wire::Ipv4Repr;
+use smoltcp::wire::Ipv6Address;
+use smoltcp::wire::Ipv6Cidr;
+use smoltcp::wire::Ipv6Packet;
+use smoltcp::wire::Ipv6Repr;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
 use std::task::Context;
 use thiserror::Error;
 
@@ -50,6 +63,8 @@ pub struct Consomme {
     #[inspect(mut)]
     udp: udp::Udp,
     icmp: icmp::Icmp,
+    #[inspect(skip)]
+    ndp: ndp::Ndp,
 }
 
 #[derive(Inspect)]
@@ -80,6 +95,12 @@ pub struct ConsommeParams {
     /// Current list of DNS resolvers.
     #[inspect(with = "|x| inspect::iter_by_index(x).map_value(inspect::AsDisplay)")]
     pub nameservers: Vec<Ipv4Address>,
+    /// Current IPv6 prefix advertised to the client for SLAAC.
+    #[inspect(display)]
+    pub prefix_v6: Ipv6Cidr,
+    /// Current IPv6 link-local gateway address.
+    #[inspect(display)]
+    pub gateway_ip_v6: Ipv6Address,
 }
 
 /// An error indicating that the CIDR is invalid.
@@ -91,6 +112,8 @@ impl ConsommeParams {
     /// Create default dynamic network state. The default state is
     ///     IP address: 10.0.0.2 / 24
     ///     gateway: 10.0.0.1 with MAC address 52-55-10-0-0-1
+    ///     IPv6 prefix: fd00::/64
+    ///     IPv6 gateway: fe80::1
     ///     no DNS resolvers
     pub fn new() -> Result<Self, Error> {
         let nameservers = dns::nameservers()?;
@@ -101,6 +124,8 @@ pub fn new() -> Result<Self, Error> {
             client_mac: EthernetAddress([0x0, 0x0, 0x0, 0x0, 0x1, 0x0]),
             net_mask: Ipv4Address::new(255, 255, 255, 0),
             nameservers,
+            prefix_v6: Ipv6Cidr::new(Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 0), 64),
+            gateway_ip_v6: Ipv6Address::new(0xfe80, 0, 0, 0, 0, 0, 0, 1),
         })
     }
 
@@ -118,6 +143,22 @@ pub fn set_cidr(&mut self, cidr: &str) -> Result<(), InvalidCidr> {
         self.net_mask = cidr.netmask();
         Ok(())
     }
+
+    /// Sets the IPv6 prefix advertised to the client.
+    ///
+    /// The prefix length must be 64, since that is what SLAAC requires. Any
+    /// host bits in the address are ignored, so setting, for example,
+    /// fd00:1::1/64 will advertise the prefix fd00:1::/64.
+    pub fn set_cidr_v6(&mut self, cidr: &str) -> Result<(), InvalidCidr> {
+        let cidr: Ipv6Cidr = cidr.parse().map_err(|()| InvalidCidr)?;
+        if cidr.prefix_len() != 64 {
+            return Err(InvalidCidr);
+        }
+        let mut prefix = cidr.address();
+        prefix.0[8..].fill(0);
+        self.prefix_v6 = Ipv6Cidr::new(prefix, 64);
+        Ok(())
+    }
 }
 
 /// An accessor for consomme.
@@ -201,6 +242,18 @@ impl ChecksumState {
         udp: true,
         tso: None,
     };
+    const TCP6: Self = Self {
+        ipv4: false,
+        tcp: true,
+        udp: false,
+        tso: None,
+    };
+    const UDP6: Self = Self {
+        ipv4: false,
+        tcp: false,
+        udp: true,
+        tso: None,
+    };
 
     fn caps(&self) -> ChecksumCapabilities {
         let mut caps = ChecksumCapabilities::default();
@@ -223,19 +276,19 @@ fn caps(&self) -> ChecksumCapabilities {
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 struct SocketAddress {
-    ip: Ipv4Address,
+    ip: IpAddr,
     port: u16,
 }
 
-impl From<SocketAddress> for SocketAddrV4 {
+impl From<SocketAddress> for SocketAddr {
     fn from(addr: SocketAddress) -> Self {
-        Self::new(addr.ip.into(), addr.port)
+        Self::new(addr.ip, addr.port)
     }
 }
 
 impl From<SocketAddress> for socket2::SockAddr {
     fn from(addr: SocketAddress) -> Self {
-        socket2::SockAddr::from(SocketAddrV4::from(addr))
+        socket2::SockAddr::from(SocketAddr::from(addr))
     }
 }
 
@@ -263,6 +316,15 @@ pub enum DropReason {
     /// The ARP type is unsupported.
     #[error("unsupported arp type")]
     UnsupportedArp,
+    /// The ICMPv6 message type is unsupported.
+    #[error("unsupported icmpv6 message type {0:?}")]
+    UnsupportedIcmpv6(Icmpv6Message),
+    /// The neighbor discovery message is unsupported.
+    #[error("unsupported ndp message")]
+    UnsupportedNdp,
+    /// The destination address cannot be reached through the host.
+    #[error("unsupported destination address {0}")]
+    UnsupportedDestination(IpAddr),
     /// The IPv4 checksum was invalid.
     #[error("ipv4 checksum failure")]
     Ipv4Checksum,
@@ -278,6 +340,9 @@ pub enum DropReason {
     /// Specified port is not bound.
     #[error("port is not bound")]
     PortNotBound,
+    /// Specified port is already bound.
+    #[error("port is already bound")]
+    PortAlreadyBound,
 }
 
 /// An error to create a consomme instance.
@@ -289,9 +354,65 @@ pub enum Error {
 }
 
 #[derive(Debug)]
-struct Ipv4Addresses {
-    src_addr: Ipv4Address,
-    dst_addr: Ipv4Address,
+struct IpAddresses {
+    src_addr: IpAddr,
+    dst_addr: IpAddr,
+}
+
+/// Returns the length of the IP header used for packets to or from `addr`.
+fn ip_header_len(addr: IpAddr) -> usize {
+    match addr {
+        IpAddr::V4(_) => IPV4_HEADER_LEN,
+        IpAddr::V6(_) => IPV6_HEADER_LEN,
+    }
+}
+
+/// Emits the IP header for a packet from `src_addr` to `dst_addr` into `eth`,
+/// setting the frame's ethertype to match. Returns the length of the IP
+/// header.
+fn emit_ip_header<T: AsRef<[u8]> + AsMut<[u8]>>(
+    eth: &mut EthernetFrame<T>,
+    src_addr: IpAddr,
+    dst_addr: IpAddr,
+    protocol: IpProtocol,
+    payload_len: usize,
+) -> usize {
+    match (src_addr, dst_addr) {
+        (IpAddr::V4(src_addr), IpAddr::V4(dst_addr)) => {
+            eth.set_ethertype(EthernetProtocol::Ipv4);
+            let ipv4 = Ipv4Repr {
+                src_addr: src_addr.into(),
+                dst_addr: dst_addr.into(),
+                protocol,
+                payload_len,
+                hop_limit: 64,
+            };
+            ipv4.emit(
+                &mut Ipv4Packet::new_unchecked(eth.payload_mut()),
+                &ChecksumCapabilities::default(),
+            );
+            ipv4.buffer_len()
+        }
+        (IpAddr::V6(src_addr), IpAddr::V6(dst_addr)) => {
+            eth.set_ethertype(EthernetProtocol::Ipv6);
+            let ipv6 = Ipv6Repr {
+                src_addr: src_addr.into(),
+                dst_addr: dst_addr.into(),
+                next_header: protocol,
+                payload_len,
+                hop_limit: 64,
+            };
+            ipv6.emit(&mut Ipv6Packet::new_unchecked(eth.payload_mut()));
+            ipv6.buffer_len()
+        }
+        _ => unreachable!("mismatched address families"),
+    }
+}
+
+/// Returns whether packets to `addr` can be forwarded to the host network.
+/// Multicast and link-local traffic is confined to the guest's link.
+fn is_forwardable_v6(addr: Ipv6Address) -> bool {
+    !(addr.is_multicast() || addr.is_link_local() || addr.is_unspecified())
 }
 
 impl Consomme {
@@ -305,6 +426,7 @@ pub fn new(params: ConsommeParams) -> Self {
             tcp: tcp::Tcp::new(),
             udp: udp::Udp::new(),
             icmp: icmp::Icmp::new(),
+            ndp: ndp::Ndp::new(),
         }
     }
 
@@ -341,6 +463,7 @@ pub fn poll(&mut self, cx: &mut Context<'_>) {
         self.poll_udp(cx);
         self.poll_tcp(cx);
         self.poll_icmp(cx);
+        self.poll_ndp(cx);
     }
 
     /// Update all sockets to use the new client's IO driver. This must be
@@ -349,6 +472,7 @@ pub fn poll(&mut self, cx: &mut Context<'_>) {
     pub fn refresh_driver(&mut self) {
         self.refresh_tcp_driver();
         self.refresh_udp_driver();
+        self.refresh_ndp_driver();
     }
 
     /// Sends an Ethernet frame to the network.
@@ -385,6 +509,7 @@ pub fn send(&mut self, data: &[u8], checksum: &ChecksumState) -> Result<(), Drop
         let frame = EthernetRepr::parse(&frame_packet)?;
         match frame.ethertype {
             EthernetProtocol::Ipv4 => self.handle_ipv4(&frame, frame_packet.payload(), checksum)?,
+            EthernetProtocol::Ipv6 => self.handle_ipv6(&frame, frame_packet.payload(), checksum)?,
             EthernetProtocol::Arp => self.handle_arp(&frame, frame_packet.payload())?,
             _ => return Err(DropReason::UnsupportedEthertype(frame.ethertype)),
         }
@@ -423,9 +548,9 @@ fn handle_ipv4(
             return Err(DropReason::Ipv4Checksum);
         }
 
-        let addresses = Ipv4Addresses {
-            src_addr: ipv4.src_addr(),
-            dst_addr: ipv4.dst_addr(),
+        let addresses = IpAddresses {
+            src_addr: Ipv4Addr::from(ipv4.src_addr()).into(),
+            dst_addr: Ipv4Addr::from(ipv4.dst_addr()).into(),
         };
 
         let inner = &payload[ipv4.header_len().into()..total_len];
@@ -440,4 +565,47 @@ fn handle_ipv4(
         };
         Ok(())
     }
+
+    fn handle_ipv6(
+        &mut self,
+        frame: &EthernetRepr,
+        payload: &[u8],
+        checksum: &ChecksumState,
+    ) -> Result<(), DropReason> {
+        let ipv6 = Ipv6Packet::new_unchecked(payload);
+        if payload.len() < IPV6_HEADER_LEN || ipv6.version() != 6 {
+            return Err(DropReason::Packet(smoltcp::Error::Malformed));
+        }
+
+        let total_len = if checksum.tso.is_some() {
+            payload.len()
+        } else {
+            IPV6_HEADER_LEN + usize::from(ipv6.payload_len())
+        };
+        if payload.len() < total_len {
+            return Err(DropReason::Packet(smoltcp::Error::Malformed));
+        }
+
+        let addresses = IpAddresses {
+            src_addr: Ipv6Addr::from(ipv6.src_addr()).into(),
+            dst_addr: Ipv6Addr::from(ipv6.dst_addr()).into(),
+        };
+
+        let inner = &payload[IPV6_HEADER_LEN..total_len];
+
+        // Extension headers are not supported, so the next header is always
+        // the upper-layer protocol.
+        match ipv6.next_header() {
+            IpProtocol::Tcp | IpProtocol::Udp if !is_forwardable_v6(ipv6.dst_addr()) => {
+                return Err(DropReason::UnsupportedDestination(addresses.dst_addr));
+            }
+            IpProtocol::Tcp => self.handle_tcp(&addresses, inner, checksum)?,
+            IpProtocol::Udp => self.handle_udp(frame, &addresses, inner, checksum)?,
+            IpProtocol::Icmpv6 => {
+                self.handle_icmpv6(frame, &addresses, inner, checksum, ipv6.hop_limit())?
+            }
+            p => return Err(DropReason::UnsupportedIpProtocol(p)),
+        };
+        Ok(())
+    }
 }
@@ -0,0 +1,525 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use super::Access;
+use super::Client;
+use super::DropReason;
+use crate::ChecksumState;
+use crate::MIN_MTU;
+use pal_async::timer::Instant;
+use pal_async::timer::PolledTimer;
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::time::Duration;
+use smoltcp::wire::ETHERNET_HEADER_LEN;
+use smoltcp::wire::EthernetAddress;
+use smoltcp::wire::EthernetFrame;
+use smoltcp::wire::EthernetProtocol;
+use smoltcp::wire::EthernetRepr;
+use smoltcp::wire::Icmpv6Packet;
+use smoltcp::wire::Icmpv6Repr;
+use smoltcp::wire::IpProtocol;
+use smoltcp::wire::Ipv6Address;
+use smoltcp::wire::Ipv6Packet;
+use smoltcp::wire::Ipv6Repr;
+use smoltcp::wire::NdiscNeighborFlags;
+use smoltcp::wire::NdiscPrefixInfoFlags;
+use smoltcp::wire::NdiscPrefixInformation;
+use smoltcp::wire::NdiscRepr;
+use smoltcp::wire::NdiscRouterFlags;
+use smoltcp::wire::RawHardwareAddress;
+use std::task::Context;
+use std::task::Poll;
+
+/// The hop limit that all neighbor discovery messages must be sent with, so
+/// that receivers can tell that they originated on the local link.
+const NDP_HOP_LIMIT: u8 = 255;
+
+/// The Ethernet address for the IPv6 all-nodes multicast group.
+const ALL_NODES_MAC: EthernetAddress = EthernetAddress([0x33, 0x33, 0, 0, 0, 1]);
+
+/// The interval between unsolicited router advertisements (MaxRtrAdvInterval
+/// in RFC 4861). This is a third of the advertised router lifetime, so that
+/// the guest's default route is refreshed well before it expires.
+const ROUTER_ADVERT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(600);
+
+pub(crate) struct Ndp {
+    timer: Option<PolledTimer>,
+    next_advert: Instant,
+}
+
+impl Ndp {
+    pub fn new() -> Self {
+        Self {
+            timer: None,
+            next_advert: Instant::now().saturating_add(ROUTER_ADVERT_INTERVAL),
+        }
+    }
+}
+
+impl<T: Client> Access<'_, T> {
+    /// Sends unsolicited router advertisements when they are due.
+    pub(crate) fn poll_ndp(&mut self, cx: &mut Context<'_>) {
+        loop {
+            let timer = self
+                .inner
+                .ndp
+                .timer
+                .get_or_insert_with(|| PolledTimer::new(self.client.driver()));
+            match timer.poll_until(cx, self.inner.ndp.next_advert) {
+                Poll::Ready(now) => self.advertise_router(now),
+                Poll::Pending => break,
+            }
+        }
+    }
+
+    /// Recreates the advertisement timer on the client's current driver.
+    pub(crate) fn refresh_ndp_driver(&mut self) {
+        self.inner.ndp.timer = None;
+    }
+
+    /// Sends an unsolicited router advertisement to all nodes if one is due
+    /// at `now`, and schedules the next one.
+    fn advertise_router(&mut self, now: Instant) {
+        if now < self.inner.ndp.next_advert {
+            return;
+        }
+        self.inner.ndp.next_advert = now.saturating_add(ROUTER_ADVERT_INTERVAL);
+        let advert = self.router_advert();
+        self.send_ndp(ALL_NODES_MAC, Ipv6Address::LINK_LOCAL_ALL_NODES, advert);
+    }
+
+    fn router_advert(&self) -> NdiscRepr<'static> {
+        let params = &self.inner.state.params;
+        NdiscRepr::RouterAdvert {
+            hop_limit: 64,
+            flags: NdiscRouterFlags::empty(),
+            router_lifetime: Duration::from_secs(1800),
+            reachable_time: Duration::from_millis(0),
+            retrans_time: Duration::from_millis(0),
+            lladdr: Some(RawHardwareAddress::from_bytes(
+                params.gateway_mac.as_bytes(),
+            )),
+            mtu: Some((MIN_MTU - ETHERNET_HEADER_LEN) as u32),
+            // Advertise the prefix for autoconfiguration only, without the
+            // on-link flag, so that the guest sends all traffic through the
+            // gateway.
+            prefix_info: Some(NdiscPrefixInformation {
+                prefix_len: params.prefix_v6.prefix_len(),
+                flags: NdiscPrefixInfoFlags::ADDRCONF,
+                valid_lifetime: Duration::from_secs(86400),
+                preferred_lifetime: Duration::from_secs(14400),
+                prefix: params.prefix_v6.address(),
+            }),
+        }
+    }
+
+    pub(crate) fn handle_ndp(
+        &mut self,
+        frame: &EthernetRepr,
+        src_addr: Ipv6Address,
+        ndisc: NdiscRepr<'_>,
+        hop_limit: u8,
+    ) -> Result<(), DropReason> {
+        if hop_limit != NDP_HOP_LIMIT {
+            return Err(DropReason::Packet(smoltcp::Error::Malformed));
+        }
+
+        let params = &self.inner.state.params;
+        let gateway_lladdr = Some(RawHardwareAddress::from_bytes(
+            params.gateway_mac.as_bytes(),
+        ));
+        let response = match ndisc {
+            NdiscRepr::RouterSolicit { .. } => self.router_advert(),
+            NdiscRepr::NeighborSolicit { target_addr, .. }
+                if target_addr == params.gateway_ip_v6 =>
+            {
+                // Solicitations from the unspecified address come from
+                // duplicate address detection and must be answered to all
+                // nodes.
+                let flags = if src_addr.is_unspecified() {
+                    NdiscNeighborFlags::ROUTER | NdiscNeighborFlags::OVERRIDE
+                } else {
+                    NdiscNeighborFlags::ROUTER
+                        | NdiscNeighborFlags::SOLICITED
+                        | NdiscNeighborFlags::OVERRIDE
+                };
+                NdiscRepr::NeighborAdvert {
+                    flags,
+                    target_addr,
+                    lladdr: gateway_lladdr,
+                }
+            }
+            _ => return Err(DropReason::UnsupportedNdp),
+        };
+
+        let (dst_mac, dst_addr) = if src_addr.is_unspecified() {
+            (ALL_NODES_MAC, Ipv6Address::LINK_LOCAL_ALL_NODES)
+        } else {
+            (frame.src_addr, src_addr)
+        };
+        self.send_ndp(dst_mac, dst_addr, response);
+        Ok(())
+    }
+
+    fn send_ndp(&mut self, dst_mac: EthernetAddress, dst_addr: Ipv6Address, ndisc: NdiscRepr<'_>) {
+        let src_addr = self.inner.state.params.gateway_ip_v6;
+        let icmp_repr = Icmpv6Repr::Ndisc(ndisc);
+        let ipv6_repr = Ipv6Repr {
+            src_addr,
+            dst_addr,
+            next_header: IpProtocol::Icmpv6,
+            payload_len: icmp_repr.buffer_len(),
+            hop_limit: NDP_HOP_LIMIT,
+        };
+        let eth_repr = EthernetRepr {
+            src_addr: self.inner.state.params.gateway_mac,
+            dst_addr: dst_mac,
+            ethertype: EthernetProtocol::Ipv6,
+        };
+
+        let mut buffer = [0; MIN_MTU];
+        let mut eth_frame = EthernetFrame::new_unchecked(&mut buffer);
+        eth_repr.emit(&mut eth_frame);
+        let mut ipv6_packet = Ipv6Packet::new_unchecked(eth_frame.payload_mut());
+        ipv6_repr.emit(&mut ipv6_packet);
+        let mut icmp_packet = Icmpv6Packet::new_unchecked(ipv6_packet.payload_mut());
+        icmp_repr.emit(
+            &src_addr.into(),
+            &dst_addr.into(),
+            &mut icmp_packet,
+            &ChecksumCapabilities::default(),
+        );
+        let len = eth_repr.buffer_len() + ipv6_repr.buffer_len() + icmp_repr.buffer_len();
+        self.client.recv(&buffer[..len], &ChecksumState::NONE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ALL_NODES_MAC;
+    use super::NDP_HOP_LIMIT;
+    use super::ROUTER_ADVERT_INTERVAL;
+    use crate::ChecksumState;
+    use crate::Client;
+    use crate::Consomme;
+    use crate::ConsommeParams;
+    use crate::DropReason;
+    use pal_async::DefaultDriver;
+    use pal_async::async_test;
+    use pal_async::driver::Driver;
+    use pal_async::timer::Instant;
+    use smoltcp::phy::ChecksumCapabilities;
+    use smoltcp::wire::EthernetAddress;
+    use smoltcp::wire::EthernetFrame;
+    use smoltcp::wire::EthernetProtocol;
+    use smoltcp::wire::EthernetRepr;
+    use smoltcp::wire::Icmpv6Packet;
+    use smoltcp::wire::Icmpv6Repr;
+    use smoltcp::wire::IpProtocol;
+    use smoltcp::wire::Ipv4Address;
+    use smoltcp::wire::Ipv6Address;
+    use smoltcp::wire::Ipv6Cidr;
+    use smoltcp::wire::Ipv6Packet;
+    use smoltcp::wire::Ipv6Repr;
+    use smoltcp::wire::NdiscNeighborFlags;
+    use smoltcp::wire::NdiscPrefixInfoFlags;
+    use smoltcp::wire::NdiscRepr;
+
+    const GUEST_MAC: EthernetAddress = EthernetAddress([0, 0, 0, 0, 1, 0]);
+    const GATEWAY_MAC: EthernetAddress = EthernetAddress([0x52, 0x55, 10, 0, 0, 1]);
+    const GUEST_IP: Ipv6Address =
+        Ipv6Address([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+    const GATEWAY_IP: Ipv6Address =
+        Ipv6Address([0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+    struct TestClient {
+        driver: DefaultDriver,
+        received: Vec<Vec<u8>>,
+    }
+
+    impl Client for TestClient {
+        fn driver(&self) -> &dyn Driver {
+            &self.driver
+        }
+
+        fn recv(&mut self, data: &[u8], _checksum: &ChecksumState) {
+            self.received.push(data.to_vec());
+        }
+
+        fn rx_mtu(&mut self) -> usize {
+            1514
+        }
+    }
+
+    fn consomme() -> Consomme {
+        Consomme::new(ConsommeParams {
+            net_mask: Ipv4Address::new(255, 255, 255, 0),
+            gateway_ip: Ipv4Address::new(10, 0, 0, 1),
+            gateway_mac: GATEWAY_MAC,
+            client_ip: Ipv4Address::new(10, 0, 0, 2),
+            client_mac: GUEST_MAC,
+            nameservers: Vec::new(),
+            prefix_v6: Ipv6Cidr::new(Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 0), 64),
+            gateway_ip_v6: GATEWAY_IP,
+        })
+    }
+
+    /// Builds an IPv6 frame from the guest.
+    fn ipv6_frame(
+        src_addr: Ipv6Address,
+        dst_addr: Ipv6Address,
+        hop_limit: u8,
+        next_header: IpProtocol,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let eth_repr = EthernetRepr {
+            src_addr: GUEST_MAC,
+            dst_addr: GATEWAY_MAC,
+            ethertype: EthernetProtocol::Ipv6,
+        };
+        let ipv6_repr = Ipv6Repr {
+            src_addr,
+            dst_addr,
+            next_header,
+            payload_len: payload.len(),
+            hop_limit,
+        };
+        let mut buffer = vec![0; eth_repr.buffer_len() + ipv6_repr.buffer_len() + payload.len()];
+        let mut eth_frame = EthernetFrame::new_unchecked(&mut buffer);
+        eth_repr.emit(&mut eth_frame);
+        let mut ipv6_packet = Ipv6Packet::new_unchecked(eth_frame.payload_mut());
+        ipv6_repr.emit(&mut ipv6_packet);
+        ipv6_packet.payload_mut().copy_from_slice(payload);
+        buffer
+    }
+
+    /// Builds an ICMPv6 frame from the guest.
+    fn icmpv6_frame(
+        src_addr: Ipv6Address,
+        dst_addr: Ipv6Address,
+        hop_limit: u8,
+        icmp_repr: Icmpv6Repr<'_>,
+    ) -> Vec<u8> {
+        let mut payload = vec![0; icmp_repr.buffer_len()];
+        icmp_repr.emit(
+            &src_addr.into(),
+            &dst_addr.into(),
+            &mut Icmpv6Packet::new_unchecked(&mut payload),
+            &ChecksumCapabilities::default(),
+        );
+        ipv6_frame(src_addr, dst_addr, hop_limit, IpProtocol::Icmpv6, &payload)
+    }
+
+    /// Parses a neighbor discovery frame sent to the guest, passing the
+    /// message to `f`.
+    fn parse_ndp(frame: &[u8], f: impl FnOnce(NdiscRepr<'_>)) -> (EthernetRepr, Ipv6Repr) {
+        let eth_frame = EthernetFrame::new_checked(frame).unwrap();
+        let eth_repr = EthernetRepr::parse(&eth_frame).unwrap();
+        let ipv6_packet = Ipv6Packet::new_checked(eth_frame.payload()).unwrap();
+        let ipv6_repr = Ipv6Repr::parse(&ipv6_packet).unwrap();
+        let icmp_packet = Icmpv6Packet::new_checked(ipv6_packet.payload()).unwrap();
+        let icmp_repr = Icmpv6Repr::parse(
+            &ipv6_repr.src_addr.into(),
+            &ipv6_repr.dst_addr.into(),
+            &icmp_packet,
+            &ChecksumCapabilities::default(),
+        )
+        .unwrap();
+        let Icmpv6Repr::Ndisc(ndisc) = icmp_repr else {
+            panic!("not an ndp message");
+        };
+        f(ndisc);
+        (eth_repr, ipv6_repr)
+    }
+
+    #[async_test]
+    async fn router_solicitation(driver: DefaultDriver) {
+        let mut consomme = consomme();
+        let mut client = TestClient {
+            driver,
+            received: Vec::new(),
+        };
+        let frame = icmpv6_frame(
+            GUEST_IP,
+            Ipv6Address::LINK_LOCAL_ALL_ROUTERS,
+            NDP_HOP_LIMIT,
+            Icmpv6Repr::Ndisc(NdiscRepr::RouterSolicit { lladdr: None }),
+        );
+        consomme
+            .access(&mut client)
+            .send(&frame, &ChecksumState::NONE)
+            .unwrap();
+
+        assert_eq!(client.received.len(), 1);
+        let (eth_repr, ipv6_repr) = parse_ndp(&client.received[0], |ndisc| {
+            let NdiscRepr::RouterAdvert {
+                lladdr,
+                prefix_info,
+                ..
+            } = ndisc
+            else {
+                panic!("not a router advertisement");
+            };
+            assert_eq!(lladdr.unwrap().as_bytes(), GATEWAY_MAC.as_bytes());
+            let prefix_info = prefix_info.unwrap();
+            assert_eq!(
+                prefix_info.prefix,
+                Ipv6Address::new(0xfd00, 0, 0, 0, 0, 0, 0, 0)
+            );
+            assert_eq!(prefix_info.prefix_len, 64);
+            assert_eq!(prefix_info.flags, NdiscPrefixInfoFlags::ADDRCONF);
+        });
+        assert_eq!(eth_repr.src_addr, GATEWAY_MAC);
+        assert_eq!(eth_repr.dst_addr, GUEST_MAC);
+        assert_eq!(ipv6_repr.src_addr, GATEWAY_IP);
+        assert_eq!(ipv6_repr.dst_addr, GUEST_IP);
+        assert_eq!(ipv6_repr.hop_limit, NDP_HOP_LIMIT);
+    }
+
+    #[async_test]
+    async fn unsolicited_router_advertisement(driver: DefaultDriver) {
+        let mut consomme = consomme();
+        let mut client = TestClient {
+            driver,
+            received: Vec::new(),
+        };
+        let start = Instant::now();
+        consomme.access(&mut client).advertise_router(start);
+        assert!(client.received.is_empty());
+
+        let now = start.saturating_add(ROUTER_ADVERT_INTERVAL);
+        consomme.access(&mut client).advertise_router(now);
+        assert_eq!(client.received.len(), 1);
+        let (eth_repr, ipv6_repr) = parse_ndp(&client.received[0], |ndisc| {
+            let NdiscRepr::RouterAdvert {
+                router_lifetime, ..
+            } = ndisc
+            else {
+                panic!("not a router advertisement");
+            };
+            assert!(router_lifetime.secs() > ROUTER_ADVERT_INTERVAL.as_secs());
+        });
+        assert_eq!(eth_repr.dst_addr, ALL_NODES_MAC);
+        assert_eq!(ipv6_repr.dst_addr, Ipv6Address::LINK_LOCAL_ALL_NODES);
+
+        // The next advertisement is not due until another interval passes.
+        consomme.access(&mut client).advertise_router(now);
+        assert_eq!(client.received.len(), 1);
+    }
+
+    #[async_test]
+    async fn neighbor_solicitation(driver: DefaultDriver) {
+        let mut consomme = consomme();
+        let mut client = TestClient {
+            driver,
+            received: Vec::new(),
+        };
+
+        // A solicitation from the guest's address is answered directly, and
+        // one from the unspecified address (duplicate address detection) is
+        // answered to all nodes.
+        for (src_addr, dst_mac, dst_addr, solicited) in [
+            (GUEST_IP, GUEST_MAC, GUEST_IP, true),
+            (
+                Ipv6Address::UNSPECIFIED,
+                ALL_NODES_MAC,
+                Ipv6Address::LINK_LOCAL_ALL_NODES,
+                false,
+            ),
+        ] {
+            client.received.clear();
+            let frame = icmpv6_frame(
+                src_addr,
+                GATEWAY_IP.solicited_node(),
+                NDP_HOP_LIMIT,
+                Icmpv6Repr::Ndisc(NdiscRepr::NeighborSolicit {
+                    target_addr: GATEWAY_IP,
+                    lladdr: None,
+                }),
+            );
+            consomme
+                .access(&mut client)
+                .send(&frame, &ChecksumState::NONE)
+                .unwrap();
+
+            assert_eq!(client.received.len(), 1);
+            let (eth_repr, ipv6_repr) = parse_ndp(&client.received[0], |ndisc| {
+                let NdiscRepr::NeighborAdvert {
+                    flags,
+                    target_addr,
+                    lladdr,
+                } = ndisc
+                else {
+                    panic!("not a neighbor advertisement");
+                };
+                assert_eq!(target_addr, GATEWAY_IP);
+                assert_eq!(lladdr.unwrap().as_bytes(), GATEWAY_MAC.as_bytes());
+                assert!(flags.contains(NdiscNeighborFlags::ROUTER | NdiscNeighborFlags::OVERRIDE));
+                assert_eq!(flags.contains(NdiscNeighborFlags::SOLICITED), solicited);
+            });
+            assert_eq!(eth_repr.dst_addr, dst_mac);
+            assert_eq!(ipv6_repr.src_addr, GATEWAY_IP);
+            assert_eq!(ipv6_repr.dst_addr, dst_addr);
+        }
+
+        // Solicitations for other addresses, or that did not originate on the
+        // local link, are dropped.
+        let other_ip = Ipv6Address::new(0xfe80, 0, 0, 0, 0, 0, 0, 3);
+        for (target_addr, hop_limit) in [(other_ip, NDP_HOP_LIMIT), (GATEWAY_IP, 64)] {
+            let frame = icmpv6_frame(
+                GUEST_IP,
+                target_addr.solicited_node(),
+                hop_limit,
+                Icmpv6Repr::Ndisc(NdiscRepr::NeighborSolicit {
+                    target_addr,
+                    lladdr: None,
+                }),
+            );
+            consomme
+                .access(&mut client)
+                .send(&frame, &ChecksumState::NONE)
+                .unwrap_err();
+        }
+    }
+
+    #[async_test]
+    async fn unforwardable_destination(driver: DefaultDriver) {
+        let mut consomme = consomme();
+        let mut client = TestClient {
+            driver,
+            received: Vec::new(),
+        };
+        for dst_addr in [
+            GATEWAY_IP,
+            Ipv6Address::LINK_LOCAL_ALL_NODES,
+            Ipv6Address::UNSPECIFIED,
+        ] {
+            for next_header in [IpProtocol::Udp, IpProtocol::Tcp] {
+                let frame = ipv6_frame(GUEST_IP, dst_addr, 64, next_header, &[0; 20]);
+                assert!(matches!(
+                    consomme
+                        .access(&mut client)
+                        .send(&frame, &ChecksumState::NONE),
+                    Err(DropReason::UnsupportedDestination(_))
+                ));
+            }
+            let frame = icmpv6_frame(
+                GUEST_IP,
+                dst_addr,
+                64,
+                Icmpv6Repr::EchoRequest {
+                    ident: 1,
+                    seq_no: 1,
+                    data: &[],
+                },
+            );
+            assert!(matches!(
+                consomme
+                    .access(&mut client)
+                    .send(&frame, &ChecksumState::NONE),
+                Err(DropReason::UnsupportedDestination(_))
+            ));
+        }
+        assert!(client.received.is_empty());
+    }
+}
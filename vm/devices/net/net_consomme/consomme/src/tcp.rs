@@ -10,7 +10,7 @@
 use super::SocketAddress;
 use crate::ChecksumState;
 use crate::ConsommeState;
-use crate::Ipv4Addresses;
+use crate::IpAddresses;
 use futures::AsyncRead;
 use futures::AsyncWrite;
 use inspect::Inspect;
@@ -20,11 +20,8 @@
 use smoltcp::phy::ChecksumCapabilities;
 use smoltcp::wire::ETHERNET_HEADER_LEN;
 use smoltcp::wire::EthernetFrame;
-use smoltcp::wire::EthernetProtocol;
-use smoltcp::wire::IPV4_HEADER_LEN;
+use smoltcp::wire::IpAddress;
 use smoltcp::wire::IpProtocol;
-use smoltcp::wire::Ipv4Packet;
-use smoltcp::wire::Ipv4Repr;
 use smoltcp::wire::TcpControl;
 use smoltcp::wire::TcpPacket;
 use smoltcp::wire::TcpRepr;
@@ -41,9 +38,10 @@
 use std::io::ErrorKind;
 use std::io::IoSlice;
 use std::io::IoSliceMut;
+use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::Shutdown;
-use std::net::SocketAddrV4;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::task::Context;
 use std::task::Poll;
@@ -76,14 +74,15 @@ fn inspect(&self, req: inspect::Request<'_>) {
         for (addr, conn) in &self.connections {
             resp.field(
                 &format!(
-                    "{}:{}-{}:{}",
-                    addr.src.ip, addr.src.port, addr.dst.ip, addr.dst.port
+                    "{}-{}",
+                    SocketAddr::from(addr.src),
+                    SocketAddr::from(addr.dst)
                 ),
                 conn,
             );
         }
-        for port in self.listeners.keys() {
-            resp.field("listening port", port);
+        for (port, listener) in &self.listeners {
+            resp.field(&format!("listener:{}", port), listener);
         }
     }
 }
@@ -148,6 +147,7 @@ fn inspect_seq(seq: &TcpSeqNumber) -> inspect::AsHex<u32> {
 struct TcpListener {
     #[inspect(skip)]
     socket: PolledSocket<Socket>,
+    guest_port: u16,
 }
 
 #[derive(Debug, PartialEq, Eq, Inspect)]
@@ -222,8 +222,8 @@ pub(crate) fn poll_tcp(&mut self, cx: &mut Context<'_>) {
                         }
 
                         let ft = FourTuple { dst: other_addr, src: SocketAddress {
-                            ip: self.inner.state.params.client_ip,
-                            port: *port,
+                            ip: Ipv4Addr::from(self.inner.state.params.client_ip).into(),
+                            port: listener.guest_port,
                         } };
 
                         match self.inner.tcp.connections.entry(ft) {
@@ -295,7 +295,7 @@ pub(crate) fn refresh_tcp_driver(&mut self) {
 
     pub(crate) fn handle_tcp(
         &mut self,
-        addresses: &Ipv4Addresses,
+        addresses: &IpAddresses,
         payload: &[u8],
         checksum: &ChecksumState,
     ) -> Result<(), DropReason> {
@@ -351,16 +351,15 @@ pub(crate) fn handle_tcp(
     }
 
     /// Binds to the specified host IP and port for listening for incoming
-    /// connections.
+    /// connections, which are forwarded to `guest_port` on the guest.
     pub fn bind_tcp_port(
         &mut self,
         ip_addr: Option<Ipv4Addr>,
         port: u16,
+        guest_port: u16,
     ) -> Result<(), DropReason> {
         match self.inner.tcp.listeners.entry(port) {
-            hash_map::Entry::Occupied(_) => {
-                tracing::warn!(port, "Duplicate TCP bind for port");
-            }
+            hash_map::Entry::Occupied(_) => return Err(DropReason::PortAlreadyBound),
             hash_map::Entry::Vacant(e) => {
                 let ft = FourTuple {
                     dst: SocketAddress {
@@ -378,7 +377,7 @@ pub fn bind_tcp_port(
                     state: &mut self.inner.state,
                 };
 
-                let listener = TcpListener::new(&mut sender)?;
+                let listener = TcpListener::new(&mut sender, guest_port)?;
                 e.insert(listener);
             }
         }
@@ -407,23 +406,25 @@ impl<T: Client> Sender<'_, T> {
     fn send_packet(&mut self, tcp: &TcpRepr<'_>, payload: Option<ring::View<'_>>) {
         let buffer = &mut self.state.buffer;
         let mut eth_packet = EthernetFrame::new_unchecked(&mut buffer[..]);
-        eth_packet.set_ethertype(EthernetProtocol::Ipv4);
         eth_packet.set_dst_addr(self.state.params.client_mac);
         eth_packet.set_src_addr(self.state.params.gateway_mac);
-        let mut ipv4_packet = Ipv4Packet::new_unchecked(eth_packet.payload_mut());
-        let ipv4 = Ipv4Repr {
-            src_addr: self.ft.dst.ip,
-            dst_addr: self.ft.src.ip,
-            protocol: IpProtocol::Tcp,
-            payload_len: tcp.header_len() + payload.as_ref().map_or(0, |p| p.len()),
-            hop_limit: 64,
-        };
-        ipv4.emit(&mut ipv4_packet, &ChecksumCapabilities::default());
-        let mut tcp_packet = TcpPacket::new_unchecked(ipv4_packet.payload_mut());
+        let payload_len = tcp.header_len() + payload.as_ref().map_or(0, |p| p.len());
+        let ip_header_len = crate::emit_ip_header(
+            &mut eth_packet,
+            self.ft.dst.ip,
+            self.ft.src.ip,
+            IpProtocol::Tcp,
+            payload_len,
+        );
+        let mut tcp_packet = TcpPacket::new_unchecked(
+            &mut eth_packet.payload_mut()[ip_header_len..ip_header_len + payload_len],
+        );
+        let src_addr = IpAddress::from(self.ft.dst.ip);
+        let dst_addr = IpAddress::from(self.ft.src.ip);
         tcp.emit(
             &mut tcp_packet,
-            &self.ft.dst.ip.into(),
-            &self.ft.src.ip.into(),
+            &src_addr,
+            &dst_addr,
             &ChecksumCapabilities::default(),
         );
         if let Some(payload) = payload {
@@ -431,9 +432,13 @@ fn send_packet(&mut self, tcp: &TcpRepr<'_>, payload: Option<ring::View<'_>>) {
                 *b = *c;
             }
         }
-        tcp_packet.fill_checksum(&self.ft.dst.ip.into(), &self.ft.src.ip.into());
-        let n = ETHERNET_HEADER_LEN + ipv4_packet.total_len() as usize;
-        self.client.recv(&buffer[..n], &ChecksumState::TCP4);
+        tcp_packet.fill_checksum(&src_addr, &dst_addr);
+        let n = ETHERNET_HEADER_LEN + ip_header_len + payload_len;
+        let checksum = match self.ft.dst.ip {
+            IpAddr::V4(_) => ChecksumState::TCP4,
+            IpAddr::V6(_) => ChecksumState::TCP6,
+        };
+        self.client.recv(&buffer[..n], &checksum);
     }
 
     fn rst(&mut self, seq: TcpSeqNumber, ack: Option<TcpSeqNumber>) {
@@ -505,8 +510,12 @@ fn new(sender: &mut Sender<'_, impl Client>, tcp: &TcpRepr<'_>) -> Result<Self,
         let mut this = Self::default();
         this.initialize_from_first_client_packet(tcp)?;
 
-        let socket =
-            Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)).map_err(DropReason::Io)?;
+        let socket = Socket::new(
+            Domain::for_address(sender.ft.dst.into()),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )
+        .map_err(DropReason::Io)?;
 
         // On Windows the default behavior for non-existent loopback sockets is
         // to wait and try again. This is different than the Linux behavior of
@@ -519,10 +528,7 @@ fn new(sender: &mut Sender<'_, impl Client>, tcp: &TcpRepr<'_>) -> Result<Self,
         }
 
         let socket = PolledSocket::new(sender.client.driver(), socket).map_err(DropReason::Io)?;
-        match socket
-            .get()
-            .connect(&SockAddr::from(SocketAddrV4::from(sender.ft.dst)))
-        {
+        match socket.get().connect(&SockAddr::from(sender.ft.dst)) {
             Ok(_) => unreachable!(),
             Err(err) if is_connect_incomplete_error(&err) => (),
             Err(err) => {
@@ -535,7 +541,7 @@ fn new(sender: &mut Sender<'_, impl Client>, tcp: &TcpRepr<'_>) -> Result<Self,
             }
         }
         if let Ok(addr) = socket.get().local_addr() {
-            if let Some(addr) = addr.as_socket_ipv4() {
+            if let Some(addr) = addr.as_socket() {
                 if addr.ip().is_loopback() {
                     this.loopback_port = LoopbackPortInfo::ProxyForGuestPort {
                         sending_port: addr.port(),
@@ -843,7 +849,8 @@ fn send_data(&mut self, sender: &mut Sender<'_, impl Client>) {
             // 3. The configured maximum segment size.
             // 4. The client MTU.
             let tx_segment_end = {
-                let header_len = ETHERNET_HEADER_LEN + IPV4_HEADER_LEN + tcp.header_len();
+                let header_len =
+                    ETHERNET_HEADER_LEN + crate::ip_header_len(sender.ft.dst.ip) + tcp.header_len();
                 let mtu = rx_mtu.min(sender.state.buffer.len());
                 seq_min([
                     tx_payload_end,
@@ -1142,7 +1149,7 @@ fn handle_packet(
 }
 
 impl TcpListener {
-    pub fn new(sender: &mut Sender<'_, impl Client>) -> Result<Self, DropReason> {
+    pub fn new(sender: &mut Sender<'_, impl Client>, guest_port: u16) -> Result<Self, DropReason> {
         let socket =
             Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)).map_err(DropReason::Io)?;
 
@@ -1162,7 +1169,7 @@ pub fn new(sender: &mut Sender<'_, impl Client>) -> Result<Self, DropReason> {
             );
             return Err(DropReason::Io(err));
         }
-        Ok(Self { socket })
+        Ok(Self { socket, guest_port })
     }
 
     fn poll_listener(
@@ -1176,7 +1183,7 @@ fn poll_listener(
                         Some(src_address) => Ok(Some((
                             socket,
                             SocketAddress {
-                                ip: (*src_address.ip()).into(),
+                                ip: IpAddr::V4(*src_address.ip()),
                                 port: addr.port(),
                             },
                         ))),
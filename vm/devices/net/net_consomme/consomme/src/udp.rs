@@ -8,23 +8,19 @@
 use super::dhcp::DHCP_SERVER;
 use crate::ChecksumState;
 use crate::ConsommeState;
-use crate::Ipv4Addresses;
+use crate::IpAddresses;
 use inspect::Inspect;
 use inspect::InspectMut;
 use inspect_counters::Counter;
 use pal_async::interest::InterestSlot;
 use pal_async::interest::PollEvents;
 use pal_async::socket::PolledSocket;
-use smoltcp::phy::ChecksumCapabilities;
 use smoltcp::wire::ETHERNET_HEADER_LEN;
 use smoltcp::wire::EthernetAddress;
 use smoltcp::wire::EthernetFrame;
-use smoltcp::wire::EthernetProtocol;
 use smoltcp::wire::EthernetRepr;
-use smoltcp::wire::IPV4_HEADER_LEN;
+use smoltcp::wire::IpAddress;
 use smoltcp::wire::IpProtocol;
-use smoltcp::wire::Ipv4Packet;
-use smoltcp::wire::Ipv4Repr;
 use smoltcp::wire::UDP_HEADER_LEN;
 use smoltcp::wire::UdpPacket;
 use smoltcp::wire::UdpRepr;
@@ -33,6 +29,8 @@
 use std::io::ErrorKind;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
 use std::net::UdpSocket;
 use std::task::Context;
 use std::task::Poll;
@@ -53,7 +51,7 @@ impl InspectMut for Udp {
     fn inspect_mut(&mut self, req: inspect::Request<'_>) {
         let mut resp = req.respond();
         for (addr, conn) in &mut self.connections {
-            resp.field_mut(&format!("{}:{}", addr.ip, addr.port), conn);
+            resp.field_mut(&SocketAddr::from(*addr).to_string(), conn);
         }
     }
 }
@@ -64,6 +62,8 @@ struct UdpConnection {
     socket: Option<PolledSocket<UdpSocket>>,
     #[inspect(display)]
     guest_mac: EthernetAddress,
+    /// The host port this connection was bound to by `bind_udp_port`, if any.
+    host_port: Option<u16>,
     stats: Stats,
     #[inspect(mut)]
     recycle: bool,
@@ -90,6 +90,7 @@ fn poll_conn(
         }
 
         let mut eth = EthernetFrame::new_unchecked(&mut state.buffer);
+        let ip_header_len = crate::ip_header_len(dst_addr.ip);
         loop {
             // Receive UDP packets while there are receive buffers available. This
             // means we won't drop UDP packets at this level--instead, we only drop
@@ -106,34 +107,33 @@ fn poll_conn(
                 |socket| {
                     socket
                         .get()
-                        .recv_from(&mut eth.payload_mut()[IPV4_HEADER_LEN + UDP_HEADER_LEN..])
+                        .recv_from(&mut eth.payload_mut()[ip_header_len + UDP_HEADER_LEN..])
                 },
             ) {
                 Poll::Ready(Ok((n, src_addr))) => {
-                    let src_ip = if let IpAddr::V4(ip) = src_addr.ip() {
-                        ip
-                    } else {
-                        unreachable!()
-                    };
-                    eth.set_ethertype(EthernetProtocol::Ipv4);
                     eth.set_src_addr(state.params.gateway_mac);
                     eth.set_dst_addr(self.guest_mac);
-                    let mut ipv4 = Ipv4Packet::new_unchecked(eth.payload_mut());
-                    Ipv4Repr {
-                        src_addr: src_ip.into(),
-                        dst_addr: dst_addr.ip,
-                        protocol: IpProtocol::Udp,
-                        payload_len: UDP_HEADER_LEN + n,
-                        hop_limit: 64,
-                    }
-                    .emit(&mut ipv4, &ChecksumCapabilities::default());
-                    let mut udp = UdpPacket::new_unchecked(ipv4.payload_mut());
+                    crate::emit_ip_header(
+                        &mut eth,
+                        src_addr.ip(),
+                        dst_addr.ip,
+                        IpProtocol::Udp,
+                        UDP_HEADER_LEN + n,
+                    );
+                    let mut udp = UdpPacket::new_unchecked(&mut eth.payload_mut()[ip_header_len..]);
                     udp.set_src_port(src_addr.port());
                     udp.set_dst_port(dst_addr.port);
                     udp.set_len((UDP_HEADER_LEN + n) as u16);
-                    udp.fill_checksum(&src_ip.into(), &dst_addr.ip.into());
-                    let len = ETHERNET_HEADER_LEN + ipv4.total_len() as usize;
-                    client.recv(&eth.as_ref()[..len], &ChecksumState::UDP4);
+                    udp.fill_checksum(
+                        &IpAddress::from(src_addr.ip()),
+                        &IpAddress::from(dst_addr.ip),
+                    );
+                    let len = ETHERNET_HEADER_LEN + ip_header_len + UDP_HEADER_LEN + n;
+                    let checksum = match dst_addr.ip {
+                        IpAddr::V4(_) => ChecksumState::UDP4,
+                        IpAddr::V6(_) => ChecksumState::UDP6,
+                    };
+                    client.recv(&eth.as_ref()[..len], &checksum);
                     self.stats.rx_packets.increment();
                 }
                 Poll::Ready(Err(err)) => {
@@ -175,7 +175,7 @@ pub(crate) fn refresh_udp_driver(&mut self) {
     pub(crate) fn handle_udp(
         &mut self,
         frame: &EthernetRepr,
-        addresses: &Ipv4Addresses,
+        addresses: &IpAddresses,
         payload: &[u8],
         checksum: &ChecksumState,
     ) -> Result<(), DropReason> {
@@ -187,9 +187,13 @@ pub(crate) fn handle_udp(
             &checksum.caps(),
         )?;
 
-        if addresses.dst_addr == self.inner.state.params.gateway_ip
-            || addresses.dst_addr.is_broadcast()
-        {
+        let is_gateway = match addresses.dst_addr {
+            IpAddr::V4(ip) => {
+                ip == Ipv4Addr::from(self.inner.state.params.gateway_ip) || ip.is_broadcast()
+            }
+            IpAddr::V6(_) => false,
+        };
+        if is_gateway {
             if self.handle_gateway_udp(&udp_packet)? {
                 return Ok(());
             }
@@ -201,10 +205,13 @@ pub(crate) fn handle_udp(
         };
 
         let conn = self.get_or_insert(guest_addr, None, Some(frame.src_addr))?;
-        match conn.socket.as_mut().unwrap().get().send_to(
-            udp_packet.payload(),
-            (Ipv4Addr::from(addresses.dst_addr), udp.dst_port),
-        ) {
+        match conn
+            .socket
+            .as_mut()
+            .unwrap()
+            .get()
+            .send_to(udp_packet.payload(), (addresses.dst_addr, udp.dst_port))
+        {
             Ok(_) => {
                 conn.stats.tx_packets.increment();
                 Ok(())
@@ -223,20 +230,24 @@ pub(crate) fn handle_udp(
     fn get_or_insert(
         &mut self,
         guest_addr: SocketAddress,
-        host_addr: Option<Ipv4Addr>,
+        host_addr: Option<SocketAddr>,
         guest_mac: Option<EthernetAddress>,
     ) -> Result<&mut UdpConnection, DropReason> {
         let entry = self.inner.udp.connections.entry(guest_addr);
         match entry {
             hash_map::Entry::Occupied(conn) => Ok(conn.into_mut()),
             hash_map::Entry::Vacant(e) => {
-                let socket = UdpSocket::bind((host_addr.unwrap_or(Ipv4Addr::UNSPECIFIED), 0))
-                    .map_err(DropReason::Io)?;
+                let host_addr = host_addr.unwrap_or_else(|| match guest_addr.ip {
+                    IpAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                    IpAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+                });
+                let socket = UdpSocket::bind(host_addr).map_err(DropReason::Io)?;
                 let socket =
                     PolledSocket::new(self.client.driver(), socket).map_err(DropReason::Io)?;
                 let conn = UdpConnection {
                     socket: Some(socket),
                     guest_mac: guest_mac.unwrap_or(self.inner.state.params.client_mac),
+                    host_port: None,
                     stats: Default::default(),
                     recycle: false,
                 };
@@ -257,29 +268,57 @@ fn handle_gateway_udp(&mut self, udp: &UdpPacket<&[u8]>) -> Result<bool, DropRea
     }
 
     /// Binds to the specified host IP and port for forwarding inbound UDP
-    /// packets to the guest.
+    /// packets to `guest_port` on the guest.
+    ///
+    /// Packets that the guest sends from `guest_port` are sent from the bound
+    /// host port, so that replies reach the original sender.
     pub fn bind_udp_port(
         &mut self,
         ip_addr: Option<Ipv4Addr>,
         port: u16,
+        guest_port: u16,
     ) -> Result<(), DropReason> {
+        if self
+            .inner
+            .udp
+            .connections
+            .values()
+            .any(|conn| conn.host_port == Some(port))
+        {
+            return Err(DropReason::PortAlreadyBound);
+        }
         let guest_addr = SocketAddress {
-            ip: ip_addr.unwrap_or(Ipv4Addr::UNSPECIFIED).into(),
-            port,
+            ip: Ipv4Addr::from(self.inner.state.params.client_ip).into(),
+            port: guest_port,
         };
-        let _ = self.get_or_insert(guest_addr, ip_addr, None)?;
+        match self.inner.udp.connections.entry(guest_addr) {
+            // Another host port is already forwarded to the guest port.
+            hash_map::Entry::Occupied(e) if e.get().host_port.is_some() => {
+                return Err(DropReason::PortAlreadyBound);
+            }
+            // Replace the connection the guest opened from the guest port,
+            // since its traffic must now go through the bound socket.
+            hash_map::Entry::Occupied(e) => {
+                e.remove();
+            }
+            hash_map::Entry::Vacant(_) => {}
+        }
+        let host_addr = SocketAddr::new(ip_addr.unwrap_or(Ipv4Addr::UNSPECIFIED).into(), port);
+        let conn = self.get_or_insert(guest_addr, Some(host_addr), None)?;
+        conn.host_port = Some(port);
         Ok(())
     }
 
     /// Unbinds from the specified host port.
     pub fn unbind_udp_port(&mut self, port: u16) -> Result<(), DropReason> {
-        let guest_addr = SocketAddress {
-            ip: Ipv4Addr::UNSPECIFIED.into(),
-            port,
-        };
-        match self.inner.udp.connections.remove(&guest_addr) {
-            Some(_) => Ok(()),
-            None => Err(DropReason::PortNotBound),
-        }
+        let guest_addr = self
+            .inner
+            .udp
+            .connections
+            .iter()
+            .find_map(|(addr, conn)| (conn.host_port == Some(port)).then_some(*addr))
+            .ok_or(DropReason::PortNotBound)?;
+        self.inner.udp.connections.remove(&guest_addr);
+        Ok(())
     }
 }
@@ -28,7 +28,12 @@
 use net_backend::TxOffloadSupport;
 use net_backend::TxSegment;
 use net_backend::TxSegmentType;
+use net_backend_resources::consomme::ConsommeRpc;
+use net_backend_resources::consomme::PortForward;
+use net_backend_resources::consomme::PortProtocol;
 use pal_async::driver::Driver;
+use pal_async::task::Spawn;
+use pal_async::task::Task;
 use parking_lot::Mutex;
 use std::collections::VecDeque;
 use std::net::Ipv4Addr;
@@ -36,14 +41,28 @@
 use std::task::Context;
 use std::task::Poll;
 use thiserror::Error;
+use vmcore::vm_task::VmTaskDriver;
+use vmcore::vm_task::VmTaskDriverSource;
 
 pub struct ConsommeEndpoint {
     endpoint_state: Arc<Mutex<Option<EndpointState>>>,
+    _control_task: Option<Task<()>>,
 }
 
 struct EndpointState {
     consomme: Consomme,
     recv: Option<mesh::Receiver<ConsommeMessage>>,
+    /// Port forwarding requests deferred to the running queue.
+    control: Option<mesh::Receiver<ConsommeRpc>>,
+}
+
+/// Error returned when a host port cannot be forwarded to the guest.
+#[derive(Debug, Error)]
+#[error("failed to forward host port {}", .forward.host_port)]
+pub struct PortForwardError {
+    forward: PortForward,
+    #[source]
+    source: consomme::DropReason,
 }
 
 impl ConsommeEndpoint {
@@ -52,10 +71,54 @@ pub fn new(state: ConsommeParams) -> Self {
             endpoint_state: Arc::new(Mutex::new(Some(EndpointState {
                 consomme: Consomme::new(state),
                 recv: None,
+                control: None,
             }))),
+            _control_task: None,
         }
     }
 
+    /// Creates an endpoint that forwards `port_forwards` from the host to the
+    /// guest, and that takes requests to change the forwarded ports at runtime
+    /// from `control`.
+    ///
+    /// The ports are bound before returning, so that a port that is in use is
+    /// reported to the caller.
+    pub fn new_with_port_forwards(
+        driver_source: &VmTaskDriverSource,
+        state: ConsommeParams,
+        port_forwards: &[PortForward],
+        control: Option<mesh::Receiver<ConsommeRpc>>,
+    ) -> Result<Self, PortForwardError> {
+        let driver = driver_source.simple();
+        let mut consomme = Consomme::new(state);
+        for forward in port_forwards {
+            add_port_forward(
+                &mut consomme.access(&mut IdleClient { driver: &driver }),
+                forward,
+            )
+            .map_err(|source| PortForwardError {
+                forward: forward.clone(),
+                source,
+            })?;
+        }
+        let (queue_send, queue_recv) = mesh::channel();
+        let endpoint_state = Arc::new(Mutex::new(Some(EndpointState {
+            consomme,
+            recv: None,
+            control: Some(queue_recv),
+        })));
+        let control_task = control.map(|control| {
+            driver.spawn(
+                "consomme-control",
+                run_control(driver.clone(), endpoint_state.clone(), control, queue_send),
+            )
+        });
+        Ok(Self {
+            endpoint_state,
+            _control_task: control_task,
+        })
+    }
+
     pub fn new_dynamic(state: ConsommeParams) -> (Self, ConsommeControl) {
         let consomme = Consomme::new(state);
         let (send, recv) = mesh::channel();
@@ -64,7 +127,9 @@ pub fn new_dynamic(state: ConsommeParams) -> (Self, ConsommeControl) {
                 endpoint_state: Arc::new(Mutex::new(Some(EndpointState {
                     consomme,
                     recv: Some(recv),
+                    control: None,
                 }))),
+                _control_task: None,
             },
             ConsommeControl { send },
         )
@@ -109,6 +174,7 @@ struct MessageBindPort {
     protocol: IpProtocol,
     address: Option<Ipv4Addr>,
     port: u16,
+    guest_port: u16,
 }
 
 enum ConsommeMessage {
@@ -132,6 +198,7 @@ pub async fn bind_port(
                     protocol,
                     address: ip_addr,
                     port,
+                    guest_port: port,
                 },
             )
             .await
@@ -152,6 +219,7 @@ pub async fn unbind_port(
                     protocol,
                     address: None,
                     port,
+                    guest_port: port,
                 },
             )
             .await
@@ -243,7 +311,22 @@ fn inspect_mut(&mut self, req: inspect::Request<'_>) {
 
 impl Drop for ConsommeQueue {
     fn drop(&mut self) {
-        *self.slot.lock() = self.endpoint_state.take();
+        let slot = self.slot.clone();
+        let mut slot = slot.lock();
+        // Handle the port forwarding requests deferred to this queue. Once the
+        // state is back in the slot, the control task handles new requests
+        // directly.
+        while let Some(Ok(rpc)) = self
+            .endpoint_state
+            .as_mut()
+            .unwrap()
+            .control
+            .as_mut()
+            .map(|control| control.try_recv())
+        {
+            self.with_consomme(|c| process_rpc(c, rpc));
+        }
+        *slot = self.endpoint_state.take();
     }
 }
 
@@ -275,7 +358,6 @@ fn poll_message(&mut self, cx: &mut Context<'_>) {
                         "Consomme dynamic update channel failure"
                     );
                     state.recv = None;
-                    return;
                 }
                 Poll::Ready(Ok(message)) => process_message(
                     &mut state.consomme.access(&mut Client {
@@ -285,7 +367,29 @@ fn poll_message(&mut self, cx: &mut Context<'_>) {
                     }),
                     message,
                 ),
-                Poll::Pending => return,
+                Poll::Pending => break,
+            }
+        }
+        while let Some(control) = &mut state.control {
+            match control.poll_recv(cx) {
+                Poll::Ready(Err(err)) => {
+                    if !matches!(err, mesh::RecvError::Closed) {
+                        tracing::warn!(
+                            err = &err as &dyn std::error::Error,
+                            "Consomme control channel failure"
+                        );
+                    }
+                    state.control = None;
+                }
+                Poll::Ready(Ok(rpc)) => process_rpc(
+                    &mut state.consomme.access(&mut Client {
+                        state: &mut self.state,
+                        stats: &mut self.stats,
+                        driver: &self.driver,
+                    }),
+                    rpc,
+                ),
+                Poll::Pending => break,
             }
         }
     }
@@ -298,8 +402,16 @@ fn process_message(
     match message {
         ConsommeMessage::BindPort(rpc) => {
             rpc.handle_sync(|bind_message| match bind_message.protocol {
-                IpProtocol::Tcp => consomme.bind_tcp_port(bind_message.address, bind_message.port),
-                IpProtocol::Udp => consomme.bind_udp_port(bind_message.address, bind_message.port),
+                IpProtocol::Tcp => consomme.bind_tcp_port(
+                    bind_message.address,
+                    bind_message.port,
+                    bind_message.guest_port,
+                ),
+                IpProtocol::Udp => consomme.bind_udp_port(
+                    bind_message.address,
+                    bind_message.port,
+                    bind_message.guest_port,
+                ),
             });
         }
         ConsommeMessage::UnbindPort(rpc) => {
@@ -314,6 +426,55 @@ fn process_message(
     }
 }
 
+/// Handles port forwarding requests from `recv`, directly while no queue is
+/// running or by deferring them to the running queue through `queue_send`.
+async fn run_control(
+    driver: VmTaskDriver,
+    slot: Arc<Mutex<Option<EndpointState>>>,
+    mut recv: mesh::Receiver<ConsommeRpc>,
+    queue_send: mesh::Sender<ConsommeRpc>,
+) {
+    while let Ok(rpc) = recv.recv().await {
+        let mut slot = slot.lock();
+        if let Some(state) = &mut *slot {
+            process_rpc(
+                &mut state.consomme.access(&mut IdleClient { driver: &driver }),
+                rpc,
+            );
+        } else {
+            queue_send.send(rpc);
+        }
+    }
+}
+
+fn process_rpc(consomme: &mut consomme::Access<'_, impl consomme::Client>, rpc: ConsommeRpc) {
+    match rpc {
+        ConsommeRpc::AddPortForward(rpc) => {
+            rpc.handle_failable_sync(|forward| add_port_forward(consomme, &forward))
+        }
+        ConsommeRpc::RemovePortForward(rpc) => {
+            rpc.handle_failable_sync(|(protocol, port)| match protocol {
+                PortProtocol::Tcp => consomme.unbind_tcp_port(port),
+                PortProtocol::Udp => consomme.unbind_udp_port(port),
+            })
+        }
+    }
+}
+
+fn add_port_forward(
+    consomme: &mut consomme::Access<'_, impl consomme::Client>,
+    forward: &PortForward,
+) -> Result<(), consomme::DropReason> {
+    match forward.protocol {
+        PortProtocol::Tcp => {
+            consomme.bind_tcp_port(forward.host_address, forward.host_port, forward.guest_port)
+        }
+        PortProtocol::Udp => {
+            consomme.bind_udp_port(forward.host_address, forward.host_port, forward.guest_port)
+        }
+    }
+}
+
 impl net_backend::Queue for ConsommeQueue {
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
         while let Some(head) = self.state.tx_avail.front() {
@@ -352,7 +513,12 @@ fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
                     consomme::DropReason::UnsupportedEthertype(_)
                     | consomme::DropReason::UnsupportedIpProtocol(_)
                     | consomme::DropReason::UnsupportedDhcp(_)
-                    | consomme::DropReason::UnsupportedArp => self.stats.tx_unknown.increment(),
+                    | consomme::DropReason::UnsupportedArp
+                    | consomme::DropReason::UnsupportedIcmpv6(_)
+                    | consomme::DropReason::UnsupportedNdp
+                    | consomme::DropReason::UnsupportedDestination(_) => {
+                        self.stats.tx_unknown.increment()
+                    }
                     consomme::DropReason::Packet(_)
                     | consomme::DropReason::Ipv4Checksum
                     | consomme::DropReason::Io(_)
@@ -486,3 +652,21 @@ fn rx_mtu(&mut self) -> usize {
         }
     }
 }
+
+/// A client for handling requests while no queue is running, which drops any
+/// packets sent to the guest.
+struct IdleClient<'a> {
+    driver: &'a dyn Driver,
+}
+
+impl consomme::Client for IdleClient<'_> {
+    fn driver(&self) -> &dyn Driver {
+        self.driver
+    }
+
+    fn recv(&mut self, _data: &[u8], _checksum: &ChecksumState) {}
+
+    fn rx_mtu(&mut self) -> usize {
+        0
+    }
+}
@@ -2,6 +2,7 @@
 // Licensed under the MIT License.
 
 use crate::ConsommeEndpoint;
+use crate::PortForwardError;
 use consomme::ConsommeParams;
 use net_backend::resolve::ResolveEndpointParams;
 use net_backend::resolve::ResolvedEndpoint;
@@ -24,6 +25,8 @@ pub enum ResolveConsommeError {
     Consomme(consomme::Error),
     #[error(transparent)]
     InvalidCidr(consomme::InvalidCidr),
+    #[error(transparent)]
+    PortForward(PortForwardError),
 }
 
 impl ResolveResource<NetEndpointHandleKind, ConsommeHandle> for ConsommeResolver {
@@ -33,7 +36,7 @@ impl ResolveResource<NetEndpointHandleKind, ConsommeHandle> for ConsommeResolver
     fn resolve(
         &self,
         resource: ConsommeHandle,
-        input: ResolveEndpointParams,
+        input: ResolveEndpointParams<'_>,
     ) -> Result<Self::Output, Self::Error> {
         let mut state = ConsommeParams::new().map_err(ResolveConsommeError::Consomme)?;
         state.client_mac.0 = input.mac_address.to_bytes();
@@ -42,7 +45,13 @@ fn resolve(
                 .set_cidr(cidr)
                 .map_err(ResolveConsommeError::InvalidCidr)?;
         }
-        let endpoint = ConsommeEndpoint::new(state);
+        let endpoint = ConsommeEndpoint::new_with_port_forwards(
+            input.driver_source,
+            state,
+            &resource.port_forwards,
+            resource.control,
+        )
+        .map_err(ResolveConsommeError::PortForward)?;
         Ok(endpoint.into())
     }
 }
@@ -0,0 +1,1263 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! An endpoint wrapper that impairs network traffic, for testing how devices
+//! and guests handle a poor network.
+//!
+//! Each direction of traffic can be delayed by a fixed latency plus random
+//! jitter, limited to a maximum bandwidth, and subjected to random loss,
+//! duplication, reordering and corruption. Random impairments draw from a
+//! seeded generator, so that a run can be reproduced.
+//!
+//! The impairments can be adjusted at runtime via `inspect`, by updating
+//! `latency_us`, `jitter_us`, `rate_bps` (zero means unlimited), `loss`,
+//! `duplicate`, `reorder` and `corrupt` under `tx` or `rx`.
+//!
+//! Transmitted packets are read by the inner endpoint directly from guest
+//! memory, so a transmitted packet is corrupted by copying it to one of the
+//! guest's receive buffers held back from the inner endpoint, leaving the
+//! guest's transmit buffers intact.
+
+#![forbid(unsafe_code)]
+
+pub mod resolver;
+
+use async_trait::async_trait;
+use guestmem::GuestMemory;
+use guestmem::GuestMemoryError;
+use inspect::Inspect;
+use inspect::InspectMut;
+use net_backend::BackendQueueStats;
+use net_backend::BufferAccess;
+use net_backend::Endpoint;
+use net_backend::EndpointAction;
+use net_backend::MultiQueueSupport;
+use net_backend::Queue;
+use net_backend::QueueConfig;
+use net_backend::RssConfig;
+use net_backend::RxBufferSegment;
+use net_backend::RxId;
+use net_backend::RxMetadata;
+use net_backend::TxError;
+use net_backend::TxId;
+use net_backend::TxMetadata;
+use net_backend::TxOffloadSupport;
+use net_backend::TxSegment;
+use net_backend::TxSegmentType;
+use net_backend::linearize;
+use net_backend::next_packet;
+use net_backend_resources::impairment::ImpairmentConfig;
+use pal_async::timer::Instant;
+use pal_async::timer::PolledTimer;
+use parking_lot::Mutex;
+use seeded_rng::SeededRng;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use thiserror::Error;
+
+/// The maximum number of receive buffers per queue that are held back from
+/// the inner endpoint, for duplicating received packets and corrupting
+/// transmitted ones.
+const RX_SPARES: usize = 16;
+
+type UpdateError = Box<dyn std::error::Error + Send + Sync>;
+
+/// An endpoint that impairs the traffic of another endpoint.
+pub struct ImpairmentEndpoint {
+    inner: Box<dyn Endpoint>,
+    shared: Arc<Shared>,
+}
+
+/// An error returned when an impairment configuration is invalid.
+#[derive(Debug, Error)]
+pub enum InvalidConfig {
+    /// A probability is out of range.
+    #[error("{0}: probability {1} is not between 0 and 1")]
+    Probability(&'static str, f64),
+}
+
+impl ImpairmentEndpoint {
+    /// Creates a new endpoint that applies the `tx` and `rx` impairments to
+    /// the traffic of `inner`.
+    ///
+    /// `seed` seeds the generators used for random impairments.
+    pub fn new(
+        inner: Box<dyn Endpoint>,
+        tx: ImpairmentConfig,
+        rx: ImpairmentConfig,
+        seed: u64,
+    ) -> Result<Self, InvalidConfig> {
+        Ok(Self {
+            inner,
+            shared: Arc::new(Shared {
+                tx: Impairment::new(tx, seed)?,
+                rx: Impairment::new(rx, seed.wrapping_add(1))?,
+            }),
+        })
+    }
+}
+
+impl InspectMut for ImpairmentEndpoint {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        req.respond()
+            .field_mut("inner", self.inner.as_mut())
+            .field("tx", &self.shared.tx)
+            .field("rx", &self.shared.rx);
+    }
+}
+
+#[async_trait]
+impl Endpoint for ImpairmentEndpoint {
+    fn endpoint_type(&self) -> &'static str {
+        "impairment"
+    }
+
+    async fn get_queues(
+        &mut self,
+        config: Vec<QueueConfig<'_>>,
+        rss: Option<&RssConfig<'_>>,
+        queues: &mut Vec<Box<dyn Queue>>,
+    ) -> anyhow::Result<()> {
+        let spares = self.shared.spares();
+        let mut inner_config = Vec::with_capacity(config.len());
+        let mut queue_state = Vec::with_capacity(config.len());
+        for config in config {
+            // Don't starve the inner queue of receive buffers.
+            let (rx_spare, initial_rx) = config
+                .initial_rx
+                .split_at(spares.min(config.initial_rx.len() / 2));
+            let metadata = Arc::new(Mutex::new(HashMap::new()));
+            queue_state.push((
+                PolledTimer::new(config.driver.as_ref()),
+                metadata.clone(),
+                rx_spare.to_vec(),
+            ));
+            inner_config.push(QueueConfig {
+                pool: Box::new(RecordingBuffers {
+                    inner: config.pool,
+                    metadata,
+                }),
+                initial_rx,
+                driver: config.driver,
+            });
+        }
+
+        let mut inner_queues = Vec::new();
+        self.inner
+            .get_queues(inner_config, rss, &mut inner_queues)
+            .await?;
+        queues.extend(inner_queues.into_iter().zip(queue_state).map(
+            |(inner, (timer, metadata, rx_spare))| {
+                Box::new(ImpairmentQueue {
+                    inner,
+                    shared: self.shared.clone(),
+                    metadata,
+                    timer,
+                    tx_delayed: VecDeque::new(),
+                    tx_done: VecDeque::new(),
+                    tx_duplicates: HashMap::new(),
+                    tx_bounced: HashMap::new(),
+                    rx_delayed: VecDeque::new(),
+                    rx_ready: VecDeque::new(),
+                    rx_spare,
+                }) as _
+            },
+        ));
+        Ok(())
+    }
+
+    async fn stop(&mut self) {
+        self.inner.stop().await
+    }
+
+    fn is_ordered(&self) -> bool {
+        // Jitter, reordering and duplicates all complete transmits out of
+        // order.
+        false
+    }
+
+    fn tx_offload_support(&self) -> TxOffloadSupport {
+        self.inner.tx_offload_support()
+    }
+
+    fn multiqueue_support(&self) -> MultiQueueSupport {
+        self.inner.multiqueue_support()
+    }
+
+    fn tx_fast_completions(&self) -> bool {
+        // Delayed transmits are not completed until they are sent.
+        false
+    }
+
+    async fn set_data_path_to_guest_vf(&self, use_vf: bool) -> anyhow::Result<()> {
+        self.inner.set_data_path_to_guest_vf(use_vf).await
+    }
+
+    async fn get_data_path_to_guest_vf(&self) -> anyhow::Result<bool> {
+        self.inner.get_data_path_to_guest_vf().await
+    }
+
+    async fn wait_for_endpoint_action(&mut self) -> EndpointAction {
+        self.inner.wait_for_endpoint_action().await
+    }
+
+    fn link_speed(&self) -> u64 {
+        self.inner.link_speed()
+    }
+}
+
+struct Shared {
+    tx: Impairment,
+    rx: Impairment,
+}
+
+impl Shared {
+    /// The number of receive buffers to hold back for duplicating received
+    /// packets and corrupting transmitted ones.
+    fn spares(&self) -> usize {
+        if self.rx.state.lock().config.duplicate > 0.0 || self.tx.state.lock().config.corrupt > 0.0
+        {
+            RX_SPARES
+        } else {
+            0
+        }
+    }
+}
+
+/// The impairments for one direction of traffic.
+struct Impairment {
+    state: Mutex<Direction>,
+}
+
+struct Direction {
+    config: ImpairmentConfig,
+    rng: SeededRng,
+    /// When the simulated link finishes sending the previous packet, for
+    /// enforcing the bandwidth limit.
+    link_idle: Instant,
+    dropped: u64,
+    duplicated: u64,
+    reordered: u64,
+    corrupted: u64,
+}
+
+/// The fate of a packet that was not dropped.
+struct Verdict {
+    /// When to deliver the packet.
+    release: Instant,
+    duplicate: bool,
+    corrupt: bool,
+}
+
+fn validate(config: &ImpairmentConfig) -> Result<(), InvalidConfig> {
+    for (name, p) in [
+        ("loss", config.loss),
+        ("duplicate", config.duplicate),
+        ("reorder", config.reorder),
+        ("corrupt", config.corrupt),
+    ] {
+        if !(0.0..=1.0).contains(&p) {
+            return Err(InvalidConfig::Probability(name, p));
+        }
+    }
+    Ok(())
+}
+
+impl Impairment {
+    fn new(config: ImpairmentConfig, seed: u64) -> Result<Self, InvalidConfig> {
+        validate(&config)?;
+        Ok(Self {
+            state: Mutex::new(Direction {
+                config,
+                rng: SeededRng::new(seed),
+                link_idle: Instant::now(),
+                dropped: 0,
+                duplicated: 0,
+                reordered: 0,
+                corrupted: 0,
+            }),
+        })
+    }
+
+    /// Handles an inspect request for a configuration field. A new value is
+    /// parsed and applied with `set` to a copy of the configuration, which is
+    /// kept only if it is valid. Returns the current value via `get`.
+    fn update<P: FromStr, V>(
+        &self,
+        new_value: Option<&str>,
+        set: impl FnOnce(&mut ImpairmentConfig, P),
+        get: impl FnOnce(&ImpairmentConfig) -> V,
+    ) -> Result<V, UpdateError>
+    where
+        P::Err: Into<UpdateError>,
+    {
+        let mut state = self.state.lock();
+        if let Some(new_value) = new_value {
+            let mut config = state.config.clone();
+            set(&mut config, new_value.parse().map_err(Into::into)?);
+            validate(&config)?;
+            state.config = config;
+        }
+        Ok(get(&state.config))
+    }
+}
+
+impl Inspect for Impairment {
+    fn inspect(&self, req: inspect::Request<'_>) {
+        let mut resp = req.respond();
+        resp.field_mut_with("latency_us", |v| {
+            self.update(
+                v,
+                |c, us: u64| c.latency = Duration::from_micros(us),
+                |c| c.latency.as_micros() as u64,
+            )
+        })
+        .field_mut_with("jitter_us", |v| {
+            self.update(
+                v,
+                |c, us: u64| c.jitter = Duration::from_micros(us),
+                |c| c.jitter.as_micros() as u64,
+            )
+        })
+        .field_mut_with("rate_bps", |v| {
+            self.update(
+                v,
+                |c, bps: u64| c.rate = (bps != 0).then_some(bps),
+                |c| c.rate.unwrap_or(0),
+            )
+        })
+        .field_mut_with("loss", |v| {
+            self.update(v, |c, p: f64| c.loss = p, |c| c.loss)
+        })
+        .field_mut_with("duplicate", |v| {
+            self.update(v, |c, p: f64| c.duplicate = p, |c| c.duplicate)
+        })
+        .field_mut_with("reorder", |v| {
+            self.update(v, |c, p: f64| c.reorder = p, |c| c.reorder)
+        })
+        .field_mut_with("corrupt", |v| {
+            self.update(v, |c, p: f64| c.corrupt = p, |c| c.corrupt)
+        });
+        let state = self.state.lock();
+        resp.counter("dropped", state.dropped)
+            .counter("duplicated", state.duplicated)
+            .counter("reordered", state.reordered)
+            .counter("corrupted", state.corrupted);
+    }
+}
+
+impl Direction {
+    fn is_active(&self) -> bool {
+        self.config != ImpairmentConfig::default()
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && self.rng.next_f64() < p
+    }
+
+    /// Decides the fate of a packet of `len` bytes that arrived at `now`.
+    /// Returns `None` if the packet should be dropped.
+    fn judge(&mut self, len: usize, now: Instant) -> Option<Verdict> {
+        if self.chance(self.config.loss) {
+            self.dropped += 1;
+            return None;
+        }
+        let mut release = now;
+        if let Some(rate) = self.config.rate.filter(|&rate| rate != 0) {
+            release =
+                self.link_idle.max(now) + Duration::from_secs_f64(len as f64 * 8.0 / rate as f64);
+            self.link_idle = release;
+        }
+        if self.chance(self.config.reorder) {
+            self.reordered += 1;
+        } else {
+            let jitter = self.config.jitter.mul_f64(self.rng.next_f64());
+            release = release + self.config.latency + jitter;
+        }
+        Some(Verdict {
+            release,
+            duplicate: self.chance(self.config.duplicate),
+            corrupt: self.chance(self.config.corrupt),
+        })
+    }
+}
+
+/// Wraps a queue's buffer pool to record the metadata of received packets,
+/// which is needed to impair them.
+struct RecordingBuffers {
+    inner: Box<dyn BufferAccess>,
+    metadata: Arc<Mutex<HashMap<u32, RxMetadata>>>,
+}
+
+impl BufferAccess for RecordingBuffers {
+    fn guest_memory(&self) -> &GuestMemory {
+        self.inner.guest_memory()
+    }
+
+    fn write_data(&mut self, id: RxId, data: &[u8]) {
+        self.inner.write_data(id, data)
+    }
+
+    fn guest_addresses(&mut self, id: RxId) -> &[RxBufferSegment] {
+        self.inner.guest_addresses(id)
+    }
+
+    fn capacity(&self, id: RxId) -> u32 {
+        self.inner.capacity(id)
+    }
+
+    fn write_header(&mut self, id: RxId, metadata: &RxMetadata) {
+        self.metadata.lock().insert(id.0, *metadata);
+        self.inner.write_header(id, metadata)
+    }
+
+    fn write_packet(&mut self, id: RxId, metadata: &RxMetadata, data: &[u8]) {
+        self.metadata.lock().insert(id.0, *metadata);
+        self.inner.write_packet(id, metadata, data)
+    }
+}
+
+/// Returns the guest address and length of each part of the packet in a
+/// receive buffer's data area, as described by `segments`.
+fn packet_ranges(segments: &[RxBufferSegment], metadata: &RxMetadata) -> Vec<(u64, usize)> {
+    let mut skip = metadata.offset;
+    let mut remaining = metadata.len;
+    let mut ranges = Vec::new();
+    for segment in segments {
+        let len = segment.len as usize;
+        if skip >= len {
+            skip -= len;
+            continue;
+        }
+        let n = (len - skip).min(remaining);
+        if n == 0 {
+            break;
+        }
+        ranges.push((segment.gpa + skip as u64, n));
+        skip = 0;
+        remaining -= n;
+    }
+    ranges
+}
+
+/// Copies the packet received in buffer `src` to buffer `dst`. Returns false
+/// if it does not fit.
+fn copy_packet(
+    pool: &mut dyn BufferAccess,
+    src: RxId,
+    dst: RxId,
+    metadata: &RxMetadata,
+) -> Result<bool, GuestMemoryError> {
+    if metadata.len > pool.capacity(dst) as usize {
+        return Ok(false);
+    }
+    let mem = pool.guest_memory().clone();
+    let mut data = vec![0; metadata.len];
+    let mut offset = 0;
+    for (gpa, len) in packet_ranges(pool.guest_addresses(src), metadata) {
+        mem.read_at(gpa, &mut data[offset..offset + len])?;
+        offset += len;
+    }
+    pool.write_packet(
+        dst,
+        &RxMetadata {
+            offset: 0,
+            ..*metadata
+        },
+        &data[..offset],
+    );
+    Ok(true)
+}
+
+/// Flips a random bit of the packet received in buffer `id`.
+fn corrupt_packet(
+    pool: &mut dyn BufferAccess,
+    id: RxId,
+    metadata: &RxMetadata,
+    rng: &mut SeededRng,
+) -> Result<(), GuestMemoryError> {
+    let mem = pool.guest_memory().clone();
+    let ranges = packet_ranges(pool.guest_addresses(id), metadata);
+    let total = ranges.iter().map(|&(_, len)| len).sum::<usize>();
+    if total == 0 {
+        return Ok(());
+    }
+    let bit = rng.next_u64() % (total as u64 * 8);
+    let mut offset = (bit / 8) as usize;
+    for (gpa, len) in ranges {
+        if offset < len {
+            let gpa = gpa + offset as u64;
+            let byte = mem.read_plain::<u8>(gpa)?;
+            mem.write_plain(gpa, &(byte ^ (1 << (bit % 8))))?;
+            break;
+        }
+        offset -= len;
+    }
+    Ok(())
+}
+
+/// Copies the transmit `packet` to receive buffer `id` and flips a random bit
+/// of the copy. Returns the segments of the copy, or `None` if it does not
+/// fit.
+fn bounce_packet(
+    pool: &mut dyn BufferAccess,
+    id: RxId,
+    packet: &[TxSegment],
+    rng: &mut SeededRng,
+) -> Result<Option<Vec<TxSegment>>, GuestMemoryError> {
+    let (metadata, _, _) = next_packet(packet);
+    let len = metadata.len as usize;
+    if len == 0 || len > pool.capacity(id) as usize {
+        return Ok(None);
+    }
+    let mut data = linearize(pool, &mut &*packet)?;
+    let bit = rng.next_u64() % (len as u64 * 8);
+    data[(bit / 8) as usize] ^= 1 << (bit % 8);
+    pool.write_data(id, &data);
+    let ranges = packet_ranges(
+        pool.guest_addresses(id),
+        &RxMetadata {
+            len,
+            ..Default::default()
+        },
+    );
+    let Ok(segment_count) = u8::try_from(ranges.len()) else {
+        return Ok(None);
+    };
+    let mut head = Some(TxMetadata {
+        segment_count,
+        ..metadata.clone()
+    });
+    Ok(Some(
+        ranges
+            .into_iter()
+            .map(|(gpa, len)| TxSegment {
+                ty: head.take().map_or(TxSegmentType::Tail, TxSegmentType::Head),
+                gpa,
+                len: len as u32,
+            })
+            .collect(),
+    ))
+}
+
+struct Delayed<T> {
+    release: Instant,
+    item: T,
+}
+
+/// Inserts `item` into `queue`, which is ordered by release time, after any
+/// items with the same release time.
+fn insert_delayed<T>(queue: &mut VecDeque<Delayed<T>>, release: Instant, item: T) {
+    let i = queue.partition_point(|d| d.release <= release);
+    queue.insert(i, Delayed { release, item });
+}
+
+struct TxPacket {
+    id: TxId,
+    segments: Vec<TxSegment>,
+}
+
+struct ImpairmentQueue {
+    inner: Box<dyn Queue>,
+    shared: Arc<Shared>,
+    metadata: Arc<Mutex<HashMap<u32, RxMetadata>>>,
+    timer: PolledTimer,
+    /// Transmits waiting to be passed to the inner queue.
+    tx_delayed: VecDeque<Delayed<TxPacket>>,
+    /// Transmits that were completed without going through the inner queue's
+    /// `tx_poll`.
+    tx_done: VecDeque<TxId>,
+    /// The number of completions of each duplicated transmit to suppress, so
+    /// that the guest sees one completion after all copies have been sent.
+    tx_duplicates: HashMap<u32, u32>,
+    /// The receive buffers holding corrupted copies of transmits, until the
+    /// transmits complete.
+    tx_bounced: HashMap<u32, RxId>,
+    /// Received packets waiting to be delivered.
+    rx_delayed: VecDeque<Delayed<RxId>>,
+    rx_ready: VecDeque<RxId>,
+    /// Receive buffers held back from the inner queue for duplicates.
+    rx_spare: Vec<RxId>,
+}
+
+impl InspectMut for ImpairmentQueue {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        req.respond()
+            .field_mut("inner", self.inner.as_mut())
+            .field("tx_delayed", self.tx_delayed.len())
+            .field("tx_bounced", self.tx_bounced.len())
+            .field("rx_delayed", self.rx_delayed.len())
+            .field("rx_spare", self.rx_spare.len());
+    }
+}
+
+impl ImpairmentQueue {
+    /// Records the completion of one copy of a transmit, returning whether
+    /// the transmit is now complete.
+    fn tx_complete(&mut self, id: TxId) -> bool {
+        if let Some(count) = self.tx_duplicates.get_mut(&id.0) {
+            *count -= 1;
+            if *count == 0 {
+                self.tx_duplicates.remove(&id.0);
+            }
+            return false;
+        }
+        if let Some(spare) = self.tx_bounced.remove(&id.0) {
+            if self.rx_spare.len() < self.shared.spares() {
+                self.rx_spare.push(spare);
+            } else {
+                self.inner.rx_avail(&[spare]);
+            }
+        }
+        true
+    }
+
+    /// Passes transmits that are due to the inner queue. Returns the release
+    /// time of the next delayed transmit, unless the inner queue is full.
+    fn release_tx(&mut self, now: Instant) -> Option<Instant> {
+        while let Some(front) = self.tx_delayed.front() {
+            if front.release > now {
+                return Some(front.release);
+            }
+            let (sync, n) = match self.inner.tx_avail(&front.item.segments) {
+                Ok(r) => r,
+                Err(err) => {
+                    tracing::warn!(
+                        error = err.as_ref() as &dyn std::error::Error,
+                        "failed to send delayed packet"
+                    );
+                    (true, front.item.segments.len())
+                }
+            };
+            if n == 0 {
+                // The inner queue is full. Try again when it is next ready.
+                return None;
+            }
+            let id = self.tx_delayed.pop_front().unwrap().item.id;
+            if sync && self.tx_complete(id) {
+                self.tx_done.push_back(id);
+            }
+        }
+        None
+    }
+
+    /// Moves received packets that are due to the ready list. Returns the
+    /// release time of the next delayed packet.
+    fn release_rx(&mut self, now: Instant) -> Option<Instant> {
+        while let Some(front) = self.rx_delayed.front() {
+            if front.release > now {
+                return Some(front.release);
+            }
+            let id = self.rx_delayed.pop_front().unwrap().item;
+            self.rx_ready.push_back(id);
+        }
+        None
+    }
+
+    /// Takes received packets from the inner queue and applies the receive
+    /// impairments.
+    fn pull_rx(&mut self, now: Instant) -> anyhow::Result<()> {
+        let mut ids = [RxId(0); 64];
+        loop {
+            let n = self.inner.rx_poll(&mut ids)?;
+            if n == 0 {
+                break;
+            }
+            let mut rx = self.shared.rx.state.lock();
+            for &id in &ids[..n] {
+                let metadata = self.metadata.lock().get(&id.0).copied().unwrap_or_default();
+                let Some(verdict) = rx.judge(metadata.len, now) else {
+                    self.inner.rx_avail(&[id]);
+                    continue;
+                };
+                let mut duplicate = None;
+                if let Some(pool) = self.inner.buffer_access() {
+                    // Copy the packet before corrupting it, so that only one
+                    // copy is corrupted.
+                    if verdict.duplicate {
+                        if let Some(spare) = self.rx_spare.pop() {
+                            if copy_packet(pool, id, spare, &metadata)? {
+                                rx.duplicated += 1;
+                                duplicate = Some(spare);
+                            } else {
+                                self.rx_spare.push(spare);
+                            }
+                        }
+                    }
+                    if verdict.corrupt {
+                        corrupt_packet(pool, id, &metadata, &mut rx.rng)?;
+                        rx.corrupted += 1;
+                    }
+                }
+                insert_delayed(&mut self.rx_delayed, verdict.release, id);
+                if let Some(spare) = duplicate {
+                    insert_delayed(&mut self.rx_delayed, verdict.release, spare);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Queue for ImpairmentQueue {
+    async fn update_target_vp(&mut self, target_vp: u32) {
+        self.inner.update_target_vp(target_vp).await
+    }
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let inner_ready = self.inner.poll_ready(cx).is_ready();
+        loop {
+            let now = Instant::now();
+            let next_tx = self.release_tx(now);
+            let next_rx = self.release_rx(now);
+            let Some(deadline) = next_tx.into_iter().chain(next_rx).min() else {
+                break;
+            };
+            if self.timer.poll_until(cx, deadline).is_pending() {
+                break;
+            }
+        }
+        if inner_ready || !self.rx_ready.is_empty() || !self.tx_done.is_empty() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn rx_avail(&mut self, done: &[RxId]) {
+        let spares = self.shared.spares();
+        if self.rx_spare.len() > spares {
+            let released = self.rx_spare.split_off(spares);
+            self.inner.rx_avail(&released);
+        }
+        let keep = (spares - self.rx_spare.len()).min(done.len());
+        self.rx_spare.extend_from_slice(&done[..keep]);
+        if keep < done.len() {
+            self.inner.rx_avail(&done[keep..]);
+        }
+    }
+
+    fn rx_poll(&mut self, packets: &mut [RxId]) -> anyhow::Result<usize> {
+        let now = Instant::now();
+        self.pull_rx(now)?;
+        self.release_rx(now);
+        let n = packets.len().min(self.rx_ready.len());
+        for (d, s) in packets.iter_mut().zip(self.rx_ready.drain(..n)) {
+            *d = s;
+        }
+        Ok(n)
+    }
+
+    fn tx_avail(&mut self, segments: &[TxSegment]) -> anyhow::Result<(bool, usize)> {
+        let mut tx = self.shared.tx.state.lock();
+        if !tx.is_active() && self.tx_delayed.is_empty() {
+            drop(tx);
+            return self.inner.tx_avail(segments);
+        }
+        let now = Instant::now();
+        let mut rest = segments;
+        while !rest.is_empty() {
+            let (metadata, packet, next) = next_packet(rest);
+            rest = next;
+            let Some(verdict) = tx.judge(metadata.len as usize, now) else {
+                self.tx_done.push_back(metadata.id);
+                continue;
+            };
+            let mut copies = vec![packet.to_vec()];
+            if verdict.duplicate {
+                tx.duplicated += 1;
+                *self.tx_duplicates.entry(metadata.id.0).or_default() += 1;
+                copies.push(packet.to_vec());
+            }
+            // Only corrupt one copy, in a bounce buffer.
+            if verdict.corrupt {
+                if let Some(pool) = self.inner.buffer_access() {
+                    if let Some(spare) = self.rx_spare.pop() {
+                        if let Some(segments) = bounce_packet(pool, spare, packet, &mut tx.rng)? {
+                            tx.corrupted += 1;
+                            self.tx_bounced.insert(metadata.id.0, spare);
+                            copies[0] = segments;
+                        } else {
+                            self.rx_spare.push(spare);
+                        }
+                    }
+                }
+            }
+            for segments in copies {
+                insert_delayed(
+                    &mut self.tx_delayed,
+                    verdict.release,
+                    TxPacket {
+                        id: metadata.id,
+                        segments,
+                    },
+                );
+            }
+        }
+        Ok((false, segments.len()))
+    }
+
+    fn tx_poll(&mut self, done: &mut [TxId]) -> Result<usize, TxError> {
+        let mut n = 0;
+        while n < done.len() {
+            let Some(id) = self.tx_done.pop_front() else {
+                break;
+            };
+            done[n] = id;
+            n += 1;
+        }
+        let polled = self.inner.tx_poll(&mut done[n..])?;
+        for i in n..n + polled {
+            let id = done[i];
+            if self.tx_complete(id) {
+                done[n] = id;
+                n += 1;
+            }
+        }
+        Ok(n)
+    }
+
+    fn buffer_access(&mut self) -> Option<&mut dyn BufferAccess> {
+        self.inner.buffer_access()
+    }
+
+    fn queue_stats(&self) -> Option<&dyn BackendQueueStats> {
+        self.inner.queue_stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Direction;
+    use super::Impairment;
+    use super::ImpairmentEndpoint;
+    use super::InvalidConfig;
+    use super::packet_ranges;
+    use futures::StreamExt;
+    use futures::future::poll_fn;
+    use guestmem::GuestMemory;
+    use net_backend::BufferAccess;
+    use net_backend::Endpoint;
+    use net_backend::Queue;
+    use net_backend::QueueConfig;
+    use net_backend::RxId;
+    use net_backend::RxMetadata;
+    use net_backend::TxId;
+    use net_backend::TxMetadata;
+    use net_backend::TxSegment;
+    use net_backend::TxSegmentType;
+    use net_backend::loopback::LoopbackEndpoint;
+    use net_backend::tests::Bufs;
+    use net_backend_resources::impairment::ImpairmentConfig;
+    use netvsp::BufferPool;
+    use netvsp::GuestBuffers;
+    use netvsp::sub_allocation_size_for_mtu;
+    use pal_async::DefaultDriver;
+    use pal_async::async_test;
+    use pal_async::timer::Instant;
+    use pal_async::wait::PolledWait;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use virtio::QueueResources;
+    use virtio::VirtioQueue;
+    use virtio::test_helpers::TestBuffer;
+    use virtio::test_helpers::TestQueue;
+    use virtio_net::VirtioWorkPool;
+    use vmbus_channel::gpadl::GpadlId;
+    use vmbus_channel::gpadl::GpadlMap;
+    use vmbus_ring::gparange::MultiPagedRangeBuf;
+
+    const PACKET_LEN: usize = 1000;
+
+    fn direction(config: ImpairmentConfig) -> Direction {
+        Impairment::new(config, 0).unwrap().state.into_inner()
+    }
+
+    #[test]
+    fn test_judge() {
+        let now = Instant::now();
+
+        let mut d = direction(ImpairmentConfig {
+            rate: Some(8000),
+            ..Default::default()
+        });
+        let first = d.judge(1000, now).unwrap().release;
+        let second = d.judge(1000, now).unwrap().release;
+        assert_eq!(first - now, Duration::from_secs(1));
+        assert_eq!(second - now, Duration::from_secs(2));
+
+        let latency = Duration::from_millis(10);
+        let mut d = direction(ImpairmentConfig {
+            latency,
+            ..Default::default()
+        });
+        assert_eq!(d.judge(1000, now).unwrap().release - now, latency);
+
+        let mut d = direction(ImpairmentConfig {
+            latency,
+            reorder: 1.0,
+            ..Default::default()
+        });
+        assert_eq!(d.judge(1000, now).unwrap().release, now);
+        assert_eq!(d.reordered, 1);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(matches!(
+            Impairment::new(
+                ImpairmentConfig {
+                    loss: 1.5,
+                    ..Default::default()
+                },
+                0
+            ),
+            Err(InvalidConfig::Probability("loss", _))
+        ));
+    }
+
+    /// Returns a queue for `endpoint`, with a loopback endpoint inside, and
+    /// the guest memory backing its buffers.
+    async fn start(
+        driver: &DefaultDriver,
+        endpoint: &mut ImpairmentEndpoint,
+    ) -> (Box<dyn Queue>, GuestMemory) {
+        let mem = GuestMemory::allocate(128 * 2048);
+        let mut queues = Vec::new();
+        endpoint
+            .get_queues(
+                vec![QueueConfig {
+                    pool: Box::new(Bufs::new(mem.clone())),
+                    initial_rx: &(1..128).map(RxId).collect::<Vec<_>>(),
+                    driver: Box::new(driver.clone()),
+                }],
+                None,
+                &mut queues,
+            )
+            .await
+            .unwrap();
+        // Buffer 0 is used for transmits.
+        let data = (0..PACKET_LEN).map(|v| v as u8).collect::<Vec<_>>();
+        mem.write_at(0, &data).unwrap();
+        (queues.pop().unwrap(), mem)
+    }
+
+    fn send(queue: &mut dyn Queue, id: u32) -> (bool, usize) {
+        queue
+            .tx_avail(&[TxSegment {
+                ty: TxSegmentType::Head(TxMetadata {
+                    id: TxId(id),
+                    segment_count: 1,
+                    len: PACKET_LEN as u32,
+                    ..Default::default()
+                }),
+                gpa: 0,
+                len: PACKET_LEN as u32,
+            }])
+            .unwrap()
+    }
+
+    /// Waits for received packets, returning their data.
+    async fn receive(queue: &mut dyn Queue, mem: &GuestMemory) -> Vec<Vec<u8>> {
+        let mut ids = [RxId(0); 8];
+        let n = loop {
+            poll_fn(|cx| queue.poll_ready(cx)).await;
+            let n = queue.rx_poll(&mut ids).unwrap();
+            if n > 0 {
+                break n;
+            }
+        };
+        ids[..n]
+            .iter()
+            .map(|id| {
+                let mut data = vec![0; PACKET_LEN];
+                mem.read_at(id.0 as u64 * 2048, &mut data).unwrap();
+                data
+            })
+            .collect()
+    }
+
+    #[async_test]
+    async fn test_rx_duplicate_corrupt(driver: DefaultDriver) {
+        let mut endpoint = ImpairmentEndpoint::new(
+            Box::new(LoopbackEndpoint::new()),
+            ImpairmentConfig::default(),
+            ImpairmentConfig {
+                duplicate: 1.0,
+                corrupt: 1.0,
+                ..Default::default()
+            },
+            0,
+        )
+        .unwrap();
+        let (mut queue, mem) = start(&driver, &mut endpoint).await;
+        let mut sent = vec![0; PACKET_LEN];
+        mem.read_at(0, &mut sent).unwrap();
+
+        assert_eq!(send(queue.as_mut(), 1), (true, 1));
+        let received = receive(queue.as_mut(), &mem).await;
+        assert_eq!(received.len(), 2);
+
+        // The original is corrupted by a single bit, and the duplicate is
+        // intact.
+        let flipped = |data: &[u8]| {
+            data.iter()
+                .zip(&sent)
+                .map(|(a, b)| (a ^ b).count_ones())
+                .sum::<u32>()
+        };
+        assert_eq!(flipped(&received[0]), 1);
+        assert_eq!(flipped(&received[1]), 0);
+    }
+
+    #[async_test]
+    async fn test_tx_corrupt(driver: DefaultDriver) {
+        let mut endpoint = ImpairmentEndpoint::new(
+            Box::new(LoopbackEndpoint::new()),
+            ImpairmentConfig {
+                corrupt: 1.0,
+                ..Default::default()
+            },
+            ImpairmentConfig::default(),
+            0,
+        )
+        .unwrap();
+        let (mut queue, mem) = start(&driver, &mut endpoint).await;
+        let mut sent = vec![0; PACKET_LEN];
+        mem.read_at(0, &mut sent).unwrap();
+
+        assert_eq!(send(queue.as_mut(), 1), (false, 1));
+        let received = receive(queue.as_mut(), &mem).await;
+        assert_eq!(received.len(), 1);
+        let mut done = [TxId(0); 4];
+        assert_eq!(queue.tx_poll(&mut done).unwrap(), 1);
+        assert_eq!(done[0].0, 1);
+
+        // The received packet is corrupted by a single bit, and the guest's
+        // transmit buffer is intact.
+        let flipped = received[0]
+            .iter()
+            .zip(&sent)
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum::<u32>();
+        assert_eq!(flipped, 1);
+        let mut after = vec![0; PACKET_LEN];
+        mem.read_at(0, &mut after).unwrap();
+        assert_eq!(after, sent);
+    }
+
+    #[async_test]
+    async fn test_tx_runtime_update(driver: DefaultDriver) {
+        let mut endpoint = ImpairmentEndpoint::new(
+            Box::new(LoopbackEndpoint::new()),
+            ImpairmentConfig {
+                loss: 1.0,
+                ..Default::default()
+            },
+            ImpairmentConfig::default(),
+            0,
+        )
+        .unwrap();
+        let (mut queue, mem) = start(&driver, &mut endpoint).await;
+
+        // Lost packets are completed without being sent.
+        assert_eq!(send(queue.as_mut(), 1), (false, 1));
+        poll_fn(|cx| queue.poll_ready(cx)).await;
+        let mut done = [TxId(0); 4];
+        assert_eq!(queue.tx_poll(&mut done).unwrap(), 1);
+        assert_eq!(done[0].0, 1);
+        assert_eq!(queue.rx_poll(&mut [RxId(0); 4]).unwrap(), 0);
+
+        // Replace the loss with latency.
+        inspect::update("tx/loss", "0", &mut endpoint)
+            .await
+            .unwrap();
+        inspect::update("tx/latency_us", "20000", &mut endpoint)
+            .await
+            .unwrap();
+
+        let start = Instant::now();
+        assert_eq!(send(queue.as_mut(), 2), (false, 1));
+        let received = receive(queue.as_mut(), &mem).await;
+        assert!(Instant::now() - start >= Duration::from_millis(20));
+        assert_eq!(received.len(), 1);
+        assert_eq!(queue.tx_poll(&mut done).unwrap(), 1);
+        assert_eq!(done[0].0, 2);
+    }
+
+    /// Sends a packet through an endpoint that corrupts transmits and
+    /// duplicates receives, with `pool` holding the receive buffers. `reader`
+    /// is another view of the same buffers, used to read the received
+    /// packets.
+    async fn check_pool(
+        driver: &DefaultDriver,
+        mem: &GuestMemory,
+        pool: Box<dyn BufferAccess>,
+        mut reader: Box<dyn BufferAccess>,
+        initial_rx: &[RxId],
+    ) {
+        let mut endpoint = ImpairmentEndpoint::new(
+            Box::new(LoopbackEndpoint::new()),
+            ImpairmentConfig {
+                corrupt: 1.0,
+                ..Default::default()
+            },
+            ImpairmentConfig {
+                duplicate: 1.0,
+                ..Default::default()
+            },
+            0,
+        )
+        .unwrap();
+        let mut queues = Vec::new();
+        endpoint
+            .get_queues(
+                vec![QueueConfig {
+                    pool,
+                    initial_rx,
+                    driver: Box::new(driver.clone()),
+                }],
+                None,
+                &mut queues,
+            )
+            .await
+            .unwrap();
+        let queue = queues[0].as_mut();
+        let sent = (0..PACKET_LEN).map(|v| v as u8).collect::<Vec<_>>();
+        mem.write_at(0, &sent).unwrap();
+
+        assert_eq!(send(queue, 1), (false, 1));
+        let mut ids = [RxId(0); 8];
+        let mut n = 0;
+        while n < 2 {
+            poll_fn(|cx| queue.poll_ready(cx)).await;
+            n += queue.rx_poll(&mut ids[n..]).unwrap();
+        }
+        assert_eq!(n, 2);
+        let mut done = [TxId(0); 4];
+        assert_eq!(queue.tx_poll(&mut done).unwrap(), 1);
+        assert_eq!(done[0].0, 1);
+
+        let received = ids[..n]
+            .iter()
+            .map(|&id| {
+                let ranges = packet_ranges(
+                    reader.guest_addresses(id),
+                    &RxMetadata {
+                        len: PACKET_LEN,
+                        ..Default::default()
+                    },
+                );
+                let mut data = vec![0; PACKET_LEN];
+                let mut offset = 0;
+                for (gpa, len) in ranges {
+                    mem.read_at(gpa, &mut data[offset..offset + len]).unwrap();
+                    offset += len;
+                }
+                assert_eq!(offset, PACKET_LEN);
+                data
+            })
+            .collect::<Vec<_>>();
+
+        // Both copies carry the same single-bit corruption, and the guest's
+        // transmit buffer is intact.
+        assert_eq!(received[0], received[1]);
+        let flipped = received[0]
+            .iter()
+            .zip(&sent)
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum::<u32>();
+        assert_eq!(flipped, 1);
+        let mut after = vec![0; PACKET_LEN];
+        mem.read_at(0, &mut after).unwrap();
+        assert_eq!(after, sent);
+    }
+
+    /// Uses netvsp's pool, which places a header ahead of each buffer's data
+    /// area.
+    #[async_test]
+    async fn test_netvsp_pool(driver: DefaultDriver) {
+        const PAGE_SIZE: u64 = 4096;
+        const MTU: u32 = 1500;
+        const BUFFER_COUNT: u32 = 32;
+
+        // The receive buffer follows the transmitted packet in page 0.
+        let sub_allocation_size = sub_allocation_size_for_mtu(MTU);
+        let page_count = (BUFFER_COUNT * sub_allocation_size).div_ceil(PAGE_SIZE as u32) as u64;
+        let mem = GuestMemory::allocate(((1 + page_count) * PAGE_SIZE) as usize);
+        let gpadl_map = GpadlMap::new();
+        gpadl_map.add(
+            GpadlId(1),
+            MultiPagedRangeBuf::from_range_buffer(
+                1,
+                std::iter::once(page_count * PAGE_SIZE)
+                    .chain(1..=page_count)
+                    .collect(),
+            )
+            .unwrap(),
+        );
+        let gpadl = gpadl_map.view().map(GpadlId(1)).unwrap();
+        let buffers =
+            Arc::new(GuestBuffers::new(mem.clone(), gpadl, sub_allocation_size, MTU).unwrap());
+        check_pool(
+            &driver,
+            &mem,
+            Box::new(BufferPool::new(buffers.clone())),
+            Box::new(BufferPool::new(buffers)),
+            &(0..BUFFER_COUNT).map(RxId).collect::<Vec<_>>(),
+        )
+        .await;
+    }
+
+    /// Uses virtio-net's pool, which places the virtio-net header at the start
+    /// of each buffer.
+    #[async_test]
+    async fn test_virtio_net_pool(driver: DefaultDriver) {
+        const QUEUE_SIZE: u16 = 64;
+        const BUFFER_COUNT: u64 = 32;
+
+        let mem = GuestMemory::allocate(0x40000);
+        let mut test_queue = TestQueue::new(&driver, &mem, 0x30000, QUEUE_SIZE);
+        for i in 0..BUFFER_COUNT {
+            // Split the header across two descriptors.
+            let addr = 0x1000 * (i + 1);
+            test_queue.add(&[
+                TestBuffer::writeable(addr, 8),
+                TestBuffer::writeable(addr + 0x100, 0x700),
+            ]);
+        }
+        let QueueResources {
+            params,
+            notify,
+            event,
+        } = test_queue.resources();
+        let mut virtio_queue = VirtioQueue::new(
+            0,
+            params,
+            mem.clone(),
+            notify,
+            PolledWait::new(&driver, event).unwrap(),
+        )
+        .unwrap();
+        let pool = VirtioWorkPool::new(mem.clone(), QUEUE_SIZE);
+        let mut initial_rx = Vec::new();
+        for _ in 0..BUFFER_COUNT {
+            let work = virtio_queue.next().await.unwrap().unwrap();
+            initial_rx.push(pool.queue_work(work));
+        }
+        check_pool(
+            &driver,
+            &mem,
+            Box::new(pool.clone()),
+            Box::new(pool),
+            &initial_rx,
+        )
+        .await;
+    }
+}
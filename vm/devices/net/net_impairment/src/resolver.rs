@@ -0,0 +1,38 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resource resolver for the impairment endpoint.
+
+use crate::ImpairmentEndpoint;
+use async_trait::async_trait;
+use net_backend::resolve::ResolveEndpointParams;
+use net_backend::resolve::ResolvedEndpoint;
+use net_backend_resources::impairment::ImpairmentHandle;
+use vm_resource::AsyncResolveResource;
+use vm_resource::ResourceResolver;
+use vm_resource::declare_static_async_resolver;
+use vm_resource::kind::NetEndpointHandleKind;
+
+/// A resolver for [`ImpairmentEndpoint`].
+pub struct ImpairmentResolver;
+declare_static_async_resolver!(
+    ImpairmentResolver,
+    (NetEndpointHandleKind, ImpairmentHandle)
+);
+
+#[async_trait]
+impl AsyncResolveResource<NetEndpointHandleKind, ImpairmentHandle> for ImpairmentResolver {
+    type Output = ResolvedEndpoint;
+    type Error = anyhow::Error;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        rsrc: ImpairmentHandle,
+        input: ResolveEndpointParams<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let inner = resolver.resolve(rsrc.endpoint, input).await?;
+        let endpoint = ImpairmentEndpoint::new(inner.0, rsrc.tx, rsrc.rx, rsrc.seed)?;
+        Ok(endpoint.into())
+    }
+}
@@ -23,7 +23,7 @@ impl ResolveResource<NetEndpointHandleKind, TapHandle> for TapResolver {
     fn resolve(
         &self,
         resource: TapHandle,
-        _input: ResolveEndpointParams,
+        _input: ResolveEndpointParams<'_>,
     ) -> Result<Self::Output, Self::Error> {
         let endpoint = TapEndpoint::new(&resource.name)?;
         Ok(endpoint.into())
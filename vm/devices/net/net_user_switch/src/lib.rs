@@ -0,0 +1,406 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! An endpoint that connects to a user-mode Ethernet switch over a Unix
+//! socket.
+//!
+//! This allows several unprivileged VMs on the same host to share a virtual
+//! network segment, without requiring TAP devices or bridges. See
+//! [`switch::Switch`] for the switch side.
+
+#![cfg(target_os = "linux")]
+#![expect(missing_docs)]
+
+pub mod resolver;
+pub mod switch;
+
+use async_trait::async_trait;
+use futures::io::AsyncRead;
+use inspect::InspectMut;
+use net_backend::BufferAccess;
+use net_backend::Endpoint;
+use net_backend::Queue;
+use net_backend::QueueConfig;
+use net_backend::RssConfig;
+use net_backend::RxId;
+use net_backend::RxMetadata;
+use net_backend::TxError;
+use net_backend::TxId;
+use net_backend::TxSegment;
+use net_backend::linearize;
+use pal_async::driver::Driver;
+use pal_async::socket::PolledSocket;
+use parking_lot::Mutex;
+use socket2::Socket;
+use std::collections::VecDeque;
+use std::io;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use thiserror::Error;
+
+/// The maximum size of a frame exchanged with the switch.
+const MAX_FRAME_SIZE: usize = 65535;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to connect to switch at {path}")]
+    Connect {
+        path: PathBuf,
+        #[source]
+        err: io::Error,
+    },
+}
+
+/// Creates a Unix socket for exchanging frames with the switch.
+///
+/// A sequenced packet socket is used so that each message carries exactly one
+/// Ethernet frame.
+fn new_socket() -> io::Result<Socket> {
+    Socket::new(socket2::Domain::UNIX, socket2::Type::SEQPACKET, None)
+}
+
+/// An endpoint connected to a port on a user-mode switch.
+pub struct UserSwitchEndpoint {
+    socket: Arc<Mutex<Option<Socket>>>,
+}
+
+impl UserSwitchEndpoint {
+    /// Connects to the switch listening on the Unix socket at `path`.
+    pub fn connect(path: &Path) -> Result<Self, Error> {
+        let socket = (|| {
+            let socket = new_socket()?;
+            socket.connect(&socket2::SockAddr::unix(path)?)?;
+            Ok(socket)
+        })()
+        .map_err(|err| Error::Connect {
+            path: path.to_owned(),
+            err,
+        })?;
+        Ok(Self {
+            socket: Arc::new(Mutex::new(Some(socket))),
+        })
+    }
+}
+
+impl InspectMut for UserSwitchEndpoint {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        req.respond();
+    }
+}
+
+#[async_trait]
+impl Endpoint for UserSwitchEndpoint {
+    fn endpoint_type(&self) -> &'static str {
+        "user_switch"
+    }
+
+    async fn get_queues(
+        &mut self,
+        mut config: Vec<QueueConfig<'_>>,
+        _rss: Option<&RssConfig<'_>>,
+        queues: &mut Vec<Box<dyn Queue>>,
+    ) -> anyhow::Result<()> {
+        assert_eq!(config.len(), 1);
+        let config = config.drain(..).next().unwrap();
+
+        queues.push(Box::new(UserSwitchQueue::new(
+            config.driver.as_ref(),
+            self.socket.clone(),
+            config.pool,
+            config.initial_rx,
+        )?));
+        Ok(())
+    }
+
+    async fn stop(&mut self) {
+        assert!(self.socket.lock().is_some(), "queue has not been dropped");
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+}
+
+struct UserSwitchQueue {
+    slot: Arc<Mutex<Option<Socket>>>,
+    socket: Option<PolledSocket<Socket>>,
+    disconnected: bool,
+    pool: Box<dyn BufferAccess>,
+    rx_free: VecDeque<RxId>,
+    rx_ready: VecDeque<RxId>,
+    buffer: Box<[u8]>,
+}
+
+impl InspectMut for UserSwitchQueue {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        req.respond();
+    }
+}
+
+impl Drop for UserSwitchQueue {
+    fn drop(&mut self) {
+        if let Some(socket) = self.socket.take() {
+            *self.slot.lock() = Some(socket.into_inner());
+        }
+    }
+}
+
+impl UserSwitchQueue {
+    fn new(
+        driver: &dyn Driver,
+        slot: Arc<Mutex<Option<Socket>>>,
+        pool: Box<dyn BufferAccess>,
+        initial_rx: &[RxId],
+    ) -> anyhow::Result<Self> {
+        let socket = slot.lock().take().expect("queue is already in use");
+        let socket = PolledSocket::new(driver, socket)?;
+        Ok(Self {
+            slot,
+            socket: Some(socket),
+            disconnected: false,
+            pool,
+            rx_free: initial_rx.iter().copied().collect(),
+            rx_ready: VecDeque::new(),
+            buffer: vec![0; MAX_FRAME_SIZE].into(),
+        })
+    }
+}
+
+impl Queue for UserSwitchQueue {
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.rx_ready.is_empty() {
+            return Poll::Ready(());
+        }
+
+        if self.disconnected {
+            return Poll::Pending;
+        }
+
+        let Some(socket) = self.socket.as_mut() else {
+            return Poll::Pending;
+        };
+
+        while let Some(&rx) = self.rx_free.front() {
+            match Pin::new(&mut *socket).poll_read(cx, &mut self.buffer) {
+                Poll::Ready(Ok(0)) => {
+                    tracing::warn!("user-mode switch disconnected");
+                    self.disconnected = true;
+                    break;
+                }
+                Poll::Ready(Ok(read_len)) => {
+                    self.pool.write_packet(
+                        rx,
+                        &RxMetadata {
+                            offset: 0,
+                            len: read_len,
+                            ..Default::default()
+                        },
+                        &self.buffer[..read_len],
+                    );
+
+                    self.rx_ready.push_back(rx);
+                    self.rx_free.pop_front();
+                }
+                Poll::Ready(Err(err)) => {
+                    tracing::warn!(
+                        error = &err as &dyn std::error::Error,
+                        "user-mode switch rx error"
+                    );
+                    break;
+                }
+                Poll::Pending => break,
+            }
+        }
+
+        if !self.rx_ready.is_empty() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn rx_avail(&mut self, done: &[RxId]) {
+        self.rx_free.extend(done);
+    }
+
+    fn rx_poll(&mut self, packets: &mut [RxId]) -> anyhow::Result<usize> {
+        let n = std::cmp::min(self.rx_ready.len(), packets.len());
+        for (done, id) in packets[..n].iter_mut().zip(self.rx_ready.drain(..n)) {
+            *done = id;
+        }
+        Ok(n)
+    }
+
+    fn tx_avail(&mut self, mut segments: &[TxSegment]) -> anyhow::Result<(bool, usize)> {
+        let n = segments.len();
+        // Frames are sent synchronously. The switch does not guarantee
+        // delivery, so frames that cannot be sent immediately are dropped.
+        if let Some(socket) = self.socket.as_ref() {
+            while !segments.is_empty() {
+                let packet = linearize(self.pool.as_ref(), &mut segments)?;
+                if self.disconnected {
+                    continue;
+                }
+                match socket.get().send_with_flags(&packet, libc::MSG_NOSIGNAL) {
+                    Ok(_) => {}
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                        // dropped packet: the switch is not keeping up
+                    }
+                    Err(err) => {
+                        tracing::warn!(
+                            error = &err as &dyn std::error::Error,
+                            "write to user-mode switch failed"
+                        );
+                    }
+                }
+            }
+        }
+        Ok((true, n))
+    }
+
+    fn tx_poll(&mut self, _done: &mut [TxId]) -> Result<usize, TxError> {
+        // Packets are sent synchronously, so there is nothing to complete here.
+        Ok(0)
+    }
+
+    fn buffer_access(&mut self) -> Option<&mut dyn BufferAccess> {
+        Some(self.pool.as_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UserSwitchEndpoint;
+    use crate::switch::Switch;
+    use futures::future::poll_fn;
+    use guestmem::GuestMemory;
+    use net_backend::Endpoint;
+    use net_backend::Queue;
+    use net_backend::QueueConfig;
+    use net_backend::RxId;
+    use net_backend::TxId;
+    use net_backend::TxMetadata;
+    use net_backend::TxSegment;
+    use net_backend::TxSegmentType;
+    use net_backend::tests::Bufs;
+    use pal_async::DefaultDriver;
+    use pal_async::async_test;
+    use pal_async::task::Spawn;
+
+    const MAC_A: [u8; 6] = [0x00, 0x15, 0x5d, 0, 0, 1];
+    const MAC_B: [u8; 6] = [0x00, 0x15, 0x5d, 0, 0, 2];
+
+    struct Port {
+        endpoint: UserSwitchEndpoint,
+        mem: GuestMemory,
+        queues: Vec<Box<dyn Queue>>,
+    }
+
+    impl Port {
+        async fn new(driver: &DefaultDriver, mut endpoint: UserSwitchEndpoint) -> Self {
+            let mem = GuestMemory::allocate(128 * 2048);
+            let mut queues = Vec::new();
+            endpoint
+                .get_queues(
+                    vec![QueueConfig {
+                        pool: Box::new(Bufs::new(mem.clone())),
+                        initial_rx: &(1..128).map(RxId).collect::<Vec<_>>(),
+                        driver: Box::new(driver.clone()),
+                    }],
+                    None,
+                    &mut queues,
+                )
+                .await
+                .unwrap();
+            Self {
+                endpoint,
+                mem,
+                queues,
+            }
+        }
+
+        fn send(&mut self, frame: &[u8]) {
+            self.mem.write_at(0, frame).unwrap();
+            let (sync, sent) = self.queues[0]
+                .tx_avail(&[TxSegment {
+                    ty: TxSegmentType::Head(TxMetadata {
+                        id: TxId(1),
+                        segment_count: 1,
+                        len: frame.len() as u32,
+                        ..Default::default()
+                    }),
+                    gpa: 0,
+                    len: frame.len() as u32,
+                }])
+                .unwrap();
+            assert!(sync);
+            assert_eq!(sent, 1);
+        }
+
+        async fn recv(&mut self, len: usize) -> Vec<u8> {
+            let mut rx_packets = [RxId(0); 1];
+            loop {
+                poll_fn(|cx| self.queues[0].poll_ready(cx)).await;
+                if self.queues[0].rx_poll(&mut rx_packets).unwrap() > 0 {
+                    break;
+                }
+            }
+            let mut frame = vec![0; len];
+            self.mem
+                .read_at(rx_packets[0].0 as u64 * 2048, &mut frame)
+                .unwrap();
+            self.queues[0].rx_avail(&rx_packets);
+            frame
+        }
+
+        async fn stop(mut self) {
+            drop(self.queues);
+            self.endpoint.stop().await;
+        }
+    }
+
+    fn frame(dst: [u8; 6], src: [u8; 6], payload: u8) -> Vec<u8> {
+        let mut frame = [dst, src].concat();
+        frame.extend_from_slice(&[0x88, 0xb5]);
+        frame.extend((0..100).map(|i| payload.wrapping_add(i)));
+        frame
+    }
+
+    #[async_test]
+    async fn test_switch(driver: DefaultDriver) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("switch.sock");
+        let switch = Switch::new(&driver, &path).unwrap();
+        let _switch_task = driver.spawn("switch", {
+            let driver = driver.clone();
+            async move { switch.run(&driver).await }
+        });
+
+        let mut a = Port::new(&driver, UserSwitchEndpoint::connect(&path).unwrap()).await;
+        let mut b = Port::new(&driver, UserSwitchEndpoint::connect(&path).unwrap()).await;
+
+        // The switch accepts ports in connection order, so `a` is connected by
+        // the time the switch reads from `b`. The broadcast is flooded to `a`,
+        // and the switch learns where `b` is.
+        let broadcast = frame([0xff; 6], MAC_B, 1);
+        b.send(&broadcast);
+        assert_eq!(a.recv(broadcast.len()).await, broadcast);
+
+        // Unicast frames to a learned address are forwarded to its port.
+        let to_b = frame(MAC_B, MAC_A, 2);
+        a.send(&to_b);
+        assert_eq!(b.recv(to_b.len()).await, to_b);
+
+        let to_a = frame(MAC_A, MAC_B, 3);
+        b.send(&to_a);
+        assert_eq!(a.recv(to_a.len()).await, to_a);
+
+        a.stop().await;
+        b.stop().await;
+    }
+}
@@ -0,0 +1,31 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use crate::UserSwitchEndpoint;
+use net_backend::resolve::ResolveEndpointParams;
+use net_backend::resolve::ResolvedEndpoint;
+use net_backend_resources::user_switch::UserSwitchHandle;
+use vm_resource::ResolveResource;
+use vm_resource::declare_static_resolver;
+use vm_resource::kind::NetEndpointHandleKind;
+
+pub struct UserSwitchResolver;
+
+declare_static_resolver! {
+    UserSwitchResolver,
+    (NetEndpointHandleKind, UserSwitchHandle),
+}
+
+impl ResolveResource<NetEndpointHandleKind, UserSwitchHandle> for UserSwitchResolver {
+    type Output = ResolvedEndpoint;
+    type Error = super::Error;
+
+    fn resolve(
+        &self,
+        resource: UserSwitchHandle,
+        _input: ResolveEndpointParams<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let endpoint = UserSwitchEndpoint::connect(resource.path.as_ref())?;
+        Ok(endpoint.into())
+    }
+}
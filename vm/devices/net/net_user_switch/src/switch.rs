@@ -0,0 +1,215 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A user-mode learning Ethernet switch.
+//!
+//! The switch listens on a Unix sequenced packet socket. Each connection is a
+//! switch port, and each message on a connection is a single Ethernet frame.
+//! Frames are forwarded to the port on which the destination MAC address was
+//! last seen, or flooded to all other ports for broadcast, multicast, and
+//! unknown destinations.
+
+use crate::MAX_FRAME_SIZE;
+use futures::AsyncReadExt;
+use pal_async::driver::Driver;
+use pal_async::socket::PolledSocket;
+use pal_async::task::Spawn;
+use parking_lot::Mutex;
+use socket2::Socket;
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The maximum number of MAC addresses the switch will learn. Frames to
+/// addresses beyond this are flooded.
+const MAX_TABLE_ENTRIES: usize = 4096;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+
+type MacAddress = [u8; 6];
+
+fn is_multicast(mac: &MacAddress) -> bool {
+    mac[0] & 1 != 0
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct PortId(u64);
+
+/// A user-mode learning Ethernet switch.
+pub struct Switch {
+    listener: PolledSocket<Socket>,
+}
+
+impl Switch {
+    /// Creates a new switch listening on the Unix socket at `path`.
+    pub fn new(driver: &(impl ?Sized + Driver), path: &Path) -> io::Result<Self> {
+        let listener = crate::new_socket()?;
+        listener.bind(&socket2::SockAddr::unix(path)?)?;
+        listener.listen(16)?;
+        Ok(Self {
+            listener: PolledSocket::new(driver, listener)?,
+        })
+    }
+
+    /// Runs the switch, accepting new port connections until an error occurs.
+    ///
+    /// Each port is serviced by a separate task spawned on `driver`.
+    pub async fn run(mut self, driver: &(impl Driver + Spawn)) -> io::Result<()> {
+        let state = Arc::new(Mutex::new(SwitchState::default()));
+        let mut next_port = 0;
+        loop {
+            let (socket, _) = self.listener.accept().await?;
+            let port = PortId(next_port);
+            next_port += 1;
+            // Keep a second handle to the socket for sending frames from
+            // other ports' tasks.
+            let sender = socket.try_clone()?;
+            let socket = PolledSocket::new(driver, socket)?;
+            state.lock().ports.insert(port, sender);
+            tracing::info!(port = port.0, "switch port connected");
+            driver
+                .spawn(
+                    format!("switch-port-{}", port.0),
+                    run_port(state.clone(), port, socket),
+                )
+                .detach();
+        }
+    }
+}
+
+async fn run_port(state: Arc<Mutex<SwitchState>>, port: PortId, mut socket: PolledSocket<Socket>) {
+    let mut buffer = vec![0; MAX_FRAME_SIZE];
+    loop {
+        match socket.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(len) => state.lock().forward(port, &buffer[..len]),
+            Err(err) => {
+                tracing::warn!(
+                    port = port.0,
+                    error = &err as &dyn std::error::Error,
+                    "switch port read failed"
+                );
+                break;
+            }
+        }
+    }
+    state.lock().remove_port(port);
+    tracing::info!(port = port.0, "switch port disconnected");
+}
+
+#[derive(Default)]
+struct SwitchState {
+    ports: HashMap<PortId, Socket>,
+    table: ForwardingTable,
+}
+
+impl SwitchState {
+    fn forward(&mut self, src_port: PortId, frame: &[u8]) {
+        if frame.len() < ETHERNET_HEADER_LEN {
+            return;
+        }
+        let dst_mac: MacAddress = frame[0..6].try_into().unwrap();
+        let src_mac: MacAddress = frame[6..12].try_into().unwrap();
+        self.table.learn(src_mac, src_port);
+        match self.table.lookup(&dst_mac) {
+            Some(port) => {
+                if port != src_port {
+                    self.send(port, frame);
+                }
+            }
+            None => {
+                for &port in self.ports.keys() {
+                    if port != src_port {
+                        self.send(port, frame);
+                    }
+                }
+            }
+        }
+    }
+
+    fn send(&self, port: PortId, frame: &[u8]) {
+        let Some(socket) = self.ports.get(&port) else {
+            return;
+        };
+        match socket.send_with_flags(frame, libc::MSG_NOSIGNAL) {
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                // dropped packet: the port is not keeping up
+            }
+            Err(err) => {
+                tracing::debug!(
+                    port = port.0,
+                    error = &err as &dyn std::error::Error,
+                    "switch port send failed"
+                );
+            }
+        }
+    }
+
+    fn remove_port(&mut self, port: PortId) {
+        self.ports.remove(&port);
+        self.table.remove_port(port);
+    }
+}
+
+/// A table mapping learned MAC addresses to switch ports.
+#[derive(Default)]
+struct ForwardingTable {
+    entries: HashMap<MacAddress, PortId>,
+}
+
+impl ForwardingTable {
+    fn learn(&mut self, mac: MacAddress, port: PortId) {
+        if is_multicast(&mac) {
+            return;
+        }
+        if self.entries.len() >= MAX_TABLE_ENTRIES && !self.entries.contains_key(&mac) {
+            return;
+        }
+        self.entries.insert(mac, port);
+    }
+
+    fn lookup(&self, mac: &MacAddress) -> Option<PortId> {
+        if is_multicast(mac) {
+            return None;
+        }
+        self.entries.get(mac).copied()
+    }
+
+    fn remove_port(&mut self, port: PortId) {
+        self.entries.retain(|_, p| *p != port);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ForwardingTable;
+    use super::PortId;
+
+    #[test]
+    fn test_forwarding_table() {
+        let mut table = ForwardingTable::default();
+        let a = [0x00, 0x15, 0x5d, 0, 0, 1];
+        let b = [0x00, 0x15, 0x5d, 0, 0, 2];
+        let broadcast = [0xff; 6];
+
+        table.learn(a, PortId(0));
+        table.learn(b, PortId(1));
+        assert_eq!(table.lookup(&a), Some(PortId(0)));
+        assert_eq!(table.lookup(&b), Some(PortId(1)));
+
+        // Multicast and broadcast addresses are never learned.
+        table.learn(broadcast, PortId(0));
+        assert_eq!(table.lookup(&broadcast), None);
+
+        // A station that moves is relearned on its new port.
+        table.learn(a, PortId(2));
+        assert_eq!(table.lookup(&a), Some(PortId(2)));
+
+        table.remove_port(PortId(2));
+        assert_eq!(table.lookup(&a), None);
+        assert_eq!(table.lookup(&b), Some(PortId(1)));
+    }
+}
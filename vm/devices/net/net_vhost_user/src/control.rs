@@ -0,0 +1,167 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! The vhost-user control channel.
+//!
+//! File descriptors are passed alongside messages using the `SCM_RIGHTS`
+//! functionality of Unix sockets.
+
+use crate::protocol::FLAG_REPLY;
+use crate::protocol::Header;
+use crate::protocol::Request;
+use crate::protocol::VERSION;
+use pal_async::driver::Driver;
+use pal_async::interest::InterestSlot;
+use pal_async::interest::PollEvents;
+use pal_async::socket::PolledSocket;
+use std::future::poll_fn;
+use std::io;
+use std::io::IoSlice;
+use std::os::fd::BorrowedFd;
+use std::os::fd::OwnedFd;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use zerocopy::FromBytes;
+use zerocopy::FromZeros;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+/// The largest payload accepted from the peer. The largest message in use,
+/// `SET_MEM_TABLE`, is well below this.
+const MAX_PAYLOAD_SIZE: u32 = 4096;
+
+/// Sends a message with the given payload and file descriptors.
+pub(crate) async fn send_message(
+    socket: &mut PolledSocket<UnixStream>,
+    header: &Header,
+    payload: &[u8],
+    mut fds: &[BorrowedFd<'_>],
+) -> io::Result<()> {
+    assert_eq!(header.size as usize, payload.len());
+    let mut message = header.as_bytes().to_vec();
+    message.extend_from_slice(payload);
+    let mut written = 0;
+    while written < message.len() {
+        let n = poll_fn(|cx| {
+            socket.poll_io(cx, InterestSlot::Write, PollEvents::OUT, |socket| {
+                pal::unix::fds::send_with_fds(
+                    socket.get(),
+                    &[IoSlice::new(&message[written..])],
+                    fds,
+                )
+            })
+        })
+        .await?;
+        written += n;
+        // The file descriptors are sent with the first part of the message.
+        fds = &[];
+    }
+    Ok(())
+}
+
+/// Fills `buf` from the socket, appending any received file descriptors to
+/// `fds`.
+async fn recv_exact(
+    socket: &mut PolledSocket<UnixStream>,
+    buf: &mut [u8],
+    fds: &mut Vec<OwnedFd>,
+) -> io::Result<()> {
+    let mut read = 0;
+    while read < buf.len() {
+        let n = poll_fn(|cx| {
+            socket.poll_io(cx, InterestSlot::Read, PollEvents::IN, |socket| {
+                pal::unix::fds::recv_with_fds(socket.get(), &mut buf[read..], fds)
+            })
+        })
+        .await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        read += n;
+    }
+    Ok(())
+}
+
+/// Receives the next message, returning its header. The payload is written to
+/// `payload`, and any attached file descriptors are appended to `fds`.
+pub(crate) async fn recv_message(
+    socket: &mut PolledSocket<UnixStream>,
+    payload: &mut Vec<u8>,
+    fds: &mut Vec<OwnedFd>,
+) -> io::Result<Header> {
+    let mut header = Header::new_zeroed();
+    recv_exact(socket, header.as_mut_bytes(), fds).await?;
+    if header.size > MAX_PAYLOAD_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("payload size {} is too large", header.size),
+        ));
+    }
+    payload.resize(header.size as usize, 0);
+    recv_exact(socket, payload, fds).await?;
+    Ok(header)
+}
+
+/// A front-end connection to a vhost-user back-end.
+pub(crate) struct Connection {
+    socket: PolledSocket<UnixStream>,
+}
+
+impl Connection {
+    /// Connects to the back-end listening at `path`.
+    pub async fn connect(driver: &(impl ?Sized + Driver), path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            socket: PolledSocket::connect_unix(driver, path).await?,
+        })
+    }
+
+    /// Sends a request that does not have a reply.
+    pub async fn send(
+        &mut self,
+        request: Request,
+        payload: &[u8],
+        fds: &[BorrowedFd<'_>],
+    ) -> io::Result<()> {
+        let header = Header {
+            request,
+            flags: VERSION,
+            size: payload.len() as u32,
+        };
+        send_message(&mut self.socket, &header, payload, fds).await
+    }
+
+    /// Sends a request and waits for its reply.
+    pub async fn call<T: IntoBytes + FromBytes + Immutable + KnownLayout>(
+        &mut self,
+        request: Request,
+        payload: &[u8],
+    ) -> io::Result<T> {
+        self.send(request, payload, &[]).await?;
+        let mut reply = Vec::new();
+        let mut fds = Vec::new();
+        let header = recv_message(&mut self.socket, &mut reply, &mut fds).await?;
+        if header.request != request || header.flags & FLAG_REPLY == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected reply {:?}", header.request),
+            ));
+        }
+        T::read_from_bytes(&reply).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid reply size {}", reply.len()),
+            )
+        })
+    }
+
+    /// Sends a request with a `u64` payload.
+    pub async fn set_u64(&mut self, request: Request, value: u64) -> io::Result<()> {
+        self.send(request, value.as_bytes(), &[]).await
+    }
+
+    /// Sends a request and returns its `u64` reply.
+    pub async fn get_u64(&mut self, request: Request) -> io::Result<u64> {
+        self.call(request, &[]).await
+    }
+}
@@ -0,0 +1,697 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A vhost-user network endpoint.
+//!
+//! This endpoint acts as a vhost-user front-end, connecting to a back-end
+//! dataplane (such as DPDK or Open vSwitch) over a Unix socket and driving a
+//! virtio-net device with one receive and one transmit queue.
+//!
+//! Guest RAM is shared with the back-end with `SET_MEM_TABLE`, using the
+//! memory object that backs it (see [`vmcore::shared_ram`]), and the guest's
+//! transmit and receive buffers are posted to the back-end directly. Each
+//! region's `userspace_addr` is its guest physical address, so the back-end
+//! translates ring and buffer addresses the same way.
+//!
+//! The virtqueues and the virtio-net headers live in a small dedicated shared
+//! memory region, which is placed above guest RAM in the back-end's view of
+//! memory.
+
+#![cfg(target_os = "linux")]
+#![expect(missing_docs)]
+
+mod control;
+pub mod protocol;
+pub mod resolver;
+mod ring;
+#[cfg(test)]
+mod tests;
+
+use anyhow::Context as _;
+use async_trait::async_trait;
+use control::Connection;
+use guestmem::GuestMemory;
+use inspect::InspectMut;
+use mesh::rpc::FailableRpc;
+use mesh::rpc::Rpc;
+use mesh::rpc::RpcSend;
+use net_backend::BufferAccess;
+use net_backend::Endpoint;
+use net_backend::Queue;
+use net_backend::QueueConfig;
+use net_backend::RssConfig;
+use net_backend::RxId;
+use net_backend::RxMetadata;
+use net_backend::TxError;
+use net_backend::TxId;
+use net_backend::TxSegment;
+use net_backend::TxSegmentType;
+use pal_async::driver::Driver;
+use pal_async::task::Spawn;
+use pal_async::task::Task;
+use pal_async::wait::PolledWait;
+use pal_event::Event;
+use protocol::MemoryHeader;
+use protocol::MemoryRegion;
+use protocol::Request;
+use protocol::VirtioNetHeader;
+use protocol::VringAddr;
+use protocol::VringState;
+use ring::DriverRing;
+use ring::QUEUE_REGION_SIZE;
+use ring::QUEUE_SIZE;
+use ring::QueueLayout;
+use sparse_mmap::SparseMapping;
+use std::collections::VecDeque;
+use std::io;
+use std::os::fd::AsFd;
+use std::os::fd::OwnedFd;
+use std::path::Path;
+use std::path::PathBuf;
+use std::task::Context;
+use std::task::Poll;
+use thiserror::Error;
+use vmcore::shared_ram::SharedGuestRam;
+use vmcore::vm_task::VmTaskDriverSource;
+use zerocopy::IntoBytes;
+
+/// The size of the virtio-net header preceding each frame.
+const HEADER_LEN: u32 = size_of::<VirtioNetHeader>() as u32;
+
+/// The most data buffers in a descriptor chain. The header takes one
+/// descriptor, and a chain can use every descriptor in the ring.
+const MAX_SEGMENTS: usize = QUEUE_SIZE as usize - 1;
+
+/// The alignment of the queue region's address in the back-end's view of
+/// memory.
+const REGION_ALIGNMENT: u64 = 0x200000;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to connect to vhost-user back-end at {path}")]
+    Connect {
+        path: PathBuf,
+        #[source]
+        err: io::Error,
+    },
+    #[error("vhost-user control request {0:?} failed")]
+    Request(Request, #[source] io::Error),
+    #[error("vhost-user back-end does not support virtio 1.0")]
+    MissingVersion1,
+    #[error("failed to allocate shared memory region")]
+    SharedMemory(#[source] io::Error),
+    #[error("guest RAM has too many ranges ({0}) to share with the back-end")]
+    TooManyRanges(usize),
+}
+
+fn request_error(request: Request) -> impl FnOnce(io::Error) -> Error {
+    move |err| Error::Request(request, err)
+}
+
+/// A request to the control task, which owns the connection to the back-end.
+enum ControlRequest {
+    /// Initializes and starts the receive and transmit rings.
+    StartRings(FailableRpc<RingEvents, (DriverRing, DriverRing)>),
+    /// Stops the rings. Sent when the queue is dropped.
+    StopRings,
+    /// Completes once all previous requests have been processed.
+    Flush(Rpc<(), ()>),
+}
+
+/// The notification events for the receive and transmit rings.
+struct RingEvents {
+    rx_kick: Event,
+    rx_call: Event,
+    tx_kick: Event,
+    tx_call: Event,
+}
+
+/// The control task state.
+struct ControlWorker {
+    connection: Connection,
+    /// The shared memory region holding the virtqueues and headers.
+    mem: GuestMemory,
+    /// The address of the region in the back-end's view of memory.
+    region_addr: u64,
+    /// Whether `VHOST_USER_F_PROTOCOL_FEATURES` was negotiated, in which
+    /// case rings must be explicitly enabled.
+    protocol_features: bool,
+    rings_started: bool,
+}
+
+impl ControlWorker {
+    async fn run(mut self, mut recv: mesh::Receiver<ControlRequest>) {
+        while let Ok(req) = recv.recv().await {
+            match req {
+                ControlRequest::StartRings(rpc) => {
+                    rpc.handle_failable(async |events| self.start_rings(events).await)
+                        .await
+                }
+                ControlRequest::StopRings => self.stop_rings().await,
+                ControlRequest::Flush(rpc) => rpc.complete(()),
+            }
+        }
+    }
+
+    async fn send(&mut self, request: Request, payload: &[u8]) -> Result<(), Error> {
+        self.connection
+            .send(request, payload, &[])
+            .await
+            .map_err(request_error(request))
+    }
+
+    async fn send_fd(&mut self, request: Request, index: u32, fd: &impl AsFd) -> Result<(), Error> {
+        self.connection
+            .send(request, (index as u64).as_bytes(), &[fd.as_fd()])
+            .await
+            .map_err(request_error(request))
+    }
+
+    async fn start_rings(
+        &mut self,
+        events: RingEvents,
+    ) -> anyhow::Result<(DriverRing, DriverRing)> {
+        if self.rings_started {
+            anyhow::bail!("queue is already in use");
+        }
+        self.rings_started = true;
+        let r = async {
+            let rx = self
+                .start_ring(protocol::RX_QUEUE, &events.rx_kick, &events.rx_call)
+                .await?;
+            let tx = self
+                .start_ring(protocol::TX_QUEUE, &events.tx_kick, &events.tx_call)
+                .await?;
+            anyhow::Ok((rx, tx))
+        }
+        .await;
+        if r.is_err() {
+            self.stop_rings().await;
+        }
+        r
+    }
+
+    /// Initializes and starts ring `index`.
+    async fn start_ring(
+        &mut self,
+        index: u32,
+        kick: &Event,
+        call: &Event,
+    ) -> anyhow::Result<DriverRing> {
+        let offset = index as u64 * QUEUE_REGION_SIZE;
+        let layout = QueueLayout {
+            offset,
+            addr: self.region_addr + offset,
+        };
+        let ring = DriverRing::new(self.mem.clone(), layout)?;
+        self.send(
+            Request::SET_VRING_NUM,
+            VringState {
+                index,
+                num: QUEUE_SIZE.into(),
+            }
+            .as_bytes(),
+        )
+        .await?;
+        self.send(
+            Request::SET_VRING_ADDR,
+            VringAddr {
+                index,
+                flags: 0,
+                desc_user_addr: layout.desc(),
+                used_user_addr: layout.used(),
+                avail_user_addr: layout.avail(),
+                log_guest_addr: 0,
+            }
+            .as_bytes(),
+        )
+        .await?;
+        self.send(
+            Request::SET_VRING_BASE,
+            VringState { index, num: 0 }.as_bytes(),
+        )
+        .await?;
+        self.send_fd(Request::SET_VRING_CALL, index, call).await?;
+        self.send_fd(Request::SET_VRING_KICK, index, kick).await?;
+        if self.protocol_features {
+            self.send(
+                Request::SET_VRING_ENABLE,
+                VringState { index, num: 1 }.as_bytes(),
+            )
+            .await?;
+        }
+        Ok(ring)
+    }
+
+    /// Stops the rings, if they are started. The back-end stops processing
+    /// each ring before it replies.
+    async fn stop_rings(&mut self) {
+        if !std::mem::take(&mut self.rings_started) {
+            return;
+        }
+        for index in [protocol::RX_QUEUE, protocol::TX_QUEUE] {
+            if let Err(err) = self
+                .connection
+                .call::<VringState>(
+                    Request::GET_VRING_BASE,
+                    VringState { index, num: 0 }.as_bytes(),
+                )
+                .await
+            {
+                tracing::warn!(
+                    index,
+                    error = &err as &dyn std::error::Error,
+                    "failed to stop vhost-user ring"
+                );
+            }
+        }
+    }
+}
+
+/// An endpoint backed by a vhost-user back-end.
+pub struct VhostUserEndpoint {
+    control: mesh::Sender<ControlRequest>,
+    _control_task: Task<()>,
+    features: u64,
+}
+
+impl VhostUserEndpoint {
+    /// Connects to the vhost-user back-end listening on the Unix socket at
+    /// `path`, negotiates features, and shares `guest_ram` with it.
+    ///
+    /// Subsequent control requests are issued from a task spawned on a
+    /// driver from `driver_source`.
+    pub async fn connect(
+        driver_source: &VmTaskDriverSource,
+        path: &Path,
+        guest_ram: &SharedGuestRam,
+    ) -> Result<Self, Error> {
+        let driver = driver_source.simple();
+        let mut connection =
+            Connection::connect(&driver, path)
+                .await
+                .map_err(|err| Error::Connect {
+                    path: path.to_owned(),
+                    err,
+                })?;
+
+        connection
+            .send(Request::SET_OWNER, &[], &[])
+            .await
+            .map_err(request_error(Request::SET_OWNER))?;
+        let backend_features = connection
+            .get_u64(Request::GET_FEATURES)
+            .await
+            .map_err(request_error(Request::GET_FEATURES))?;
+        if backend_features & protocol::VIRTIO_F_VERSION_1 == 0 {
+            return Err(Error::MissingVersion1);
+        }
+
+        let mut features = protocol::VIRTIO_F_VERSION_1;
+        let protocol_features = backend_features & protocol::VHOST_USER_F_PROTOCOL_FEATURES != 0;
+        if protocol_features {
+            features |= protocol::VHOST_USER_F_PROTOCOL_FEATURES;
+            // None of the optional protocol features are used.
+            connection
+                .get_u64(Request::GET_PROTOCOL_FEATURES)
+                .await
+                .map_err(request_error(Request::GET_PROTOCOL_FEATURES))?;
+            connection
+                .set_u64(Request::SET_PROTOCOL_FEATURES, 0)
+                .await
+                .map_err(request_error(Request::SET_PROTOCOL_FEATURES))?;
+        }
+        connection
+            .set_u64(Request::SET_FEATURES, features)
+            .await
+            .map_err(request_error(Request::SET_FEATURES))?;
+
+        // Share guest RAM, followed by a region for the receive and transmit
+        // queues.
+        if guest_ram.ranges.len() >= protocol::MAX_MEMORY_REGIONS {
+            return Err(Error::TooManyRanges(guest_ram.ranges.len()));
+        }
+        let size = 2 * QUEUE_REGION_SIZE;
+        let (fd, mapping) = (|| {
+            let fd: OwnedFd = sparse_mmap::alloc_shared_memory(size as usize)?;
+            let mapping = SparseMapping::new(size as usize)?;
+            mapping.map_file(0, size as usize, &fd, 0, true)?;
+            io::Result::Ok((fd, mapping))
+        })()
+        .map_err(Error::SharedMemory)?;
+        let region_addr = guest_ram
+            .ranges
+            .iter()
+            .map(|range| range.gpa + range.len)
+            .max()
+            .unwrap_or(0)
+            .next_multiple_of(REGION_ALIGNMENT);
+        let mut regions = guest_ram
+            .ranges
+            .iter()
+            .map(|range| MemoryRegion {
+                guest_phys_addr: range.gpa,
+                memory_size: range.len,
+                userspace_addr: range.gpa,
+                mmap_offset: range.offset,
+            })
+            .collect::<Vec<_>>();
+        regions.push(MemoryRegion {
+            guest_phys_addr: region_addr,
+            memory_size: size,
+            userspace_addr: region_addr,
+            mmap_offset: 0,
+        });
+        let mut fds = vec![guest_ram.memory.as_fd(); guest_ram.ranges.len()];
+        fds.push(fd.as_fd());
+        let mut payload = MemoryHeader {
+            num_regions: regions.len() as u32,
+            padding: 0,
+        }
+        .as_bytes()
+        .to_vec();
+        payload.extend_from_slice(regions.as_bytes());
+        connection
+            .send(Request::SET_MEM_TABLE, &payload, &fds)
+            .await
+            .map_err(request_error(Request::SET_MEM_TABLE))?;
+
+        let worker = ControlWorker {
+            connection,
+            mem: GuestMemory::new("vhost-user", mapping),
+            region_addr,
+            protocol_features,
+            rings_started: false,
+        };
+        let (send, recv) = mesh::channel();
+        let task = driver.spawn("vhost-user-control", worker.run(recv));
+        Ok(Self {
+            control: send,
+            _control_task: task,
+            features,
+        })
+    }
+}
+
+impl InspectMut for VhostUserEndpoint {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        req.respond().hex("features", self.features);
+    }
+}
+
+#[async_trait]
+impl Endpoint for VhostUserEndpoint {
+    fn endpoint_type(&self) -> &'static str {
+        "vhost-user"
+    }
+
+    async fn get_queues(
+        &mut self,
+        mut config: Vec<QueueConfig<'_>>,
+        _rss: Option<&RssConfig<'_>>,
+        queues: &mut Vec<Box<dyn Queue>>,
+    ) -> anyhow::Result<()> {
+        assert_eq!(config.len(), 1);
+        let config = config.drain(..).next().unwrap();
+
+        queues.push(Box::new(
+            VhostUserQueue::new(
+                config.driver.as_ref(),
+                self.control.clone(),
+                config.pool,
+                config.initial_rx,
+            )
+            .await?,
+        ));
+        Ok(())
+    }
+
+    async fn stop(&mut self) {
+        // Wait for the rings of any dropped queue to be stopped.
+        if let Err(err) = self.control.call(ControlRequest::Flush, ()).await {
+            tracing::warn!(
+                error = &err as &dyn std::error::Error,
+                "vhost-user control task failed"
+            );
+        }
+    }
+}
+
+struct VhostUserQueue {
+    control: mesh::Sender<ControlRequest>,
+    rx: DriverRing,
+    tx: DriverRing,
+    rx_kick: Event,
+    tx_kick: Event,
+    rx_call: PolledWait<Event>,
+    tx_call: PolledWait<Event>,
+    pool: Box<dyn BufferAccess>,
+    /// Receive buffers not yet posted to the back-end.
+    rx_free: VecDeque<RxId>,
+    /// The receive buffer of each posted chain, by head descriptor.
+    rx_posted: Vec<Option<RxId>>,
+    rx_ready: VecDeque<RxId>,
+    /// The transmit of each posted chain, by head descriptor.
+    tx_posted: Vec<Option<TxId>>,
+    tx_done: VecDeque<TxId>,
+    /// A ring error, reported as fatal from the next poll.
+    error: Option<anyhow::Error>,
+    rx_dropped: u64,
+    tx_dropped: u64,
+}
+
+impl InspectMut for VhostUserQueue {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        req.respond()
+            .field("rx_free", self.rx_free.len())
+            .field("rx_posted", self.rx_posted.iter().flatten().count())
+            .field("tx_posted", self.tx_posted.iter().flatten().count())
+            .counter("rx_dropped", self.rx_dropped)
+            .counter("tx_dropped", self.tx_dropped);
+    }
+}
+
+impl Drop for VhostUserQueue {
+    fn drop(&mut self) {
+        self.control.send(ControlRequest::StopRings);
+    }
+}
+
+impl VhostUserQueue {
+    async fn new(
+        driver: &dyn Driver,
+        control: mesh::Sender<ControlRequest>,
+        pool: Box<dyn BufferAccess>,
+        initial_rx: &[RxId],
+    ) -> anyhow::Result<Self> {
+        let rx_call = PolledWait::new(driver, Event::new())?;
+        let tx_call = PolledWait::new(driver, Event::new())?;
+        let rx_kick = Event::new();
+        let tx_kick = Event::new();
+
+        let (rx, tx) = control
+            .call_failable(
+                ControlRequest::StartRings,
+                RingEvents {
+                    rx_kick: rx_kick.clone(),
+                    rx_call: rx_call.get().clone(),
+                    tx_kick: tx_kick.clone(),
+                    tx_call: tx_call.get().clone(),
+                },
+            )
+            .await?;
+
+        // From here on, dropping the queue stops the rings.
+        let mut this = Self {
+            control,
+            rx,
+            tx,
+            rx_kick,
+            tx_kick,
+            rx_call,
+            tx_call,
+            pool,
+            rx_free: initial_rx.iter().copied().collect(),
+            rx_posted: vec![None; QUEUE_SIZE.into()],
+            rx_ready: VecDeque::new(),
+            tx_posted: vec![None; QUEUE_SIZE.into()],
+            tx_done: VecDeque::new(),
+            error: None,
+            rx_dropped: 0,
+            tx_dropped: 0,
+        };
+
+        this.post_rx()?;
+        Ok(this)
+    }
+
+    /// Posts free receive buffers to the back-end.
+    fn post_rx(&mut self) -> anyhow::Result<()> {
+        let mut posted = false;
+        while let Some(&rx_id) = self.rx_free.front() {
+            // The back-end writes the frame directly to the buffer's data
+            // area, after its own header in the queue region. A buffer with
+            // more segments than fit in a chain is truncated, which the
+            // capacity check in `process_rx` still covers.
+            let segments = self.pool.guest_addresses(rx_id);
+            let Some(head) = self.rx.push(
+                VirtioNetHeader::default().as_bytes(),
+                segments
+                    .iter()
+                    .take(MAX_SEGMENTS)
+                    .map(|segment| (segment.gpa, segment.len)),
+                true,
+            )?
+            else {
+                break;
+            };
+            self.rx_free.pop_front();
+            self.rx_posted[head as usize] = Some(rx_id);
+            posted = true;
+        }
+        if posted && self.rx.needs_kick()? {
+            self.rx_kick.signal();
+        }
+        Ok(())
+    }
+
+    /// Completes transmits that the back-end has finished with.
+    fn process_tx_completions(&mut self) -> anyhow::Result<()> {
+        while let Some((head, _)) = self.tx.pop_used()? {
+            let id = self.tx_posted[head as usize]
+                .take()
+                .context("completed transmit was not posted")?;
+            self.tx_done.push_back(id);
+        }
+        Ok(())
+    }
+
+    /// Completes receives that the back-end has finished with.
+    fn process_rx(&mut self) -> anyhow::Result<()> {
+        while let Some((head, len)) = self.rx.pop_used()? {
+            let rx_id = self.rx_posted[head as usize]
+                .take()
+                .context("completed receive was not posted")?;
+            let frame_len = len.wrapping_sub(HEADER_LEN);
+            if len <= HEADER_LEN || frame_len > self.pool.capacity(rx_id) {
+                // Reuse the buffer.
+                self.rx_dropped += 1;
+                self.rx_free.push_back(rx_id);
+                continue;
+            }
+            self.pool.write_header(
+                rx_id,
+                &RxMetadata {
+                    offset: 0,
+                    len: frame_len as usize,
+                    ..Default::default()
+                },
+            );
+            self.rx_ready.push_back(rx_id);
+        }
+        Ok(())
+    }
+}
+
+impl Queue for VhostUserQueue {
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.rx_ready.is_empty() || !self.tx_done.is_empty() || self.error.is_some() {
+            return Poll::Ready(());
+        }
+
+        // Consume any notifications before checking the rings, so that a
+        // notification arriving after the check wakes this task.
+        while let Poll::Ready(r) = self.rx_call.poll_wait(cx) {
+            r.expect("eventfd wait cannot fail");
+        }
+        while let Poll::Ready(r) = self.tx_call.poll_wait(cx) {
+            r.expect("eventfd wait cannot fail");
+        }
+
+        if let Err(err) = self
+            .process_tx_completions()
+            .and_then(|()| self.process_rx())
+            .and_then(|()| self.post_rx())
+        {
+            self.error = Some(err);
+        }
+
+        if !self.rx_ready.is_empty() || !self.tx_done.is_empty() || self.error.is_some() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn rx_avail(&mut self, done: &[RxId]) {
+        self.rx_free.extend(done);
+        if let Err(err) = self.post_rx() {
+            self.error.get_or_insert(err);
+        }
+    }
+
+    fn rx_poll(&mut self, packets: &mut [RxId]) -> anyhow::Result<usize> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        let n = std::cmp::min(self.rx_ready.len(), packets.len());
+        for (done, id) in packets[..n].iter_mut().zip(self.rx_ready.drain(..n)) {
+            *done = id;
+        }
+        Ok(n)
+    }
+
+    fn tx_avail(&mut self, segments: &[TxSegment]) -> anyhow::Result<(bool, usize)> {
+        self.process_tx_completions()?;
+        let mut i = 0;
+        let mut posted = false;
+        while i < segments.len() {
+            let TxSegmentType::Head(meta) = &segments[i].ty else {
+                unreachable!()
+            };
+            let packet = &segments[i..i + meta.segment_count as usize];
+            if packet.len() > MAX_SEGMENTS {
+                // The packet can never fit in the ring, so drop it rather
+                // than stall the queue. This cannot happen with the current
+                // segment count limit, but guards against a smaller ring.
+                self.tx_dropped += 1;
+                self.tx_done.push_back(meta.id);
+                i += packet.len();
+                continue;
+            }
+            let Some(head) = self.tx.push(
+                VirtioNetHeader::default().as_bytes(),
+                packet.iter().map(|segment| (segment.gpa, segment.len)),
+                false,
+            )?
+            else {
+                // The ring is full. The remaining packets are posted after
+                // the back-end completes some transmits.
+                break;
+            };
+            self.tx_posted[head as usize] = Some(meta.id);
+            posted = true;
+            i += packet.len();
+        }
+        if posted && self.tx.needs_kick()? {
+            self.tx_kick.signal();
+        }
+        Ok((false, i))
+    }
+
+    fn tx_poll(&mut self, done: &mut [TxId]) -> Result<usize, TxError> {
+        if let Some(err) = self.error.take() {
+            return Err(TxError::Fatal(err));
+        }
+        self.process_tx_completions().map_err(TxError::Fatal)?;
+        let n = std::cmp::min(self.tx_done.len(), done.len());
+        for (done, id) in done[..n].iter_mut().zip(self.tx_done.drain(..n)) {
+            *done = id;
+        }
+        Ok(n)
+    }
+
+    fn buffer_access(&mut self) -> Option<&mut dyn BufferAccess> {
+        Some(self.pool.as_mut())
+    }
+}
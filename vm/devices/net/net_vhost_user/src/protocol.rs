@@ -0,0 +1,125 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! vhost-user protocol definitions.
+//!
+//! See the vhost-user specification in the QEMU documentation
+//! (`docs/interop/vhost-user.rst`). Messages use the host's native byte order.
+
+use open_enum::open_enum;
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+open_enum! {
+    /// Front-end request codes.
+    #[derive(IntoBytes, FromBytes, Immutable, KnownLayout)]
+    pub enum Request: u32 {
+        GET_FEATURES = 1,
+        SET_FEATURES = 2,
+        SET_OWNER = 3,
+        RESET_OWNER = 4,
+        SET_MEM_TABLE = 5,
+        SET_VRING_NUM = 8,
+        SET_VRING_ADDR = 9,
+        SET_VRING_BASE = 10,
+        GET_VRING_BASE = 11,
+        SET_VRING_KICK = 12,
+        SET_VRING_CALL = 13,
+        SET_VRING_ERR = 14,
+        GET_PROTOCOL_FEATURES = 15,
+        SET_PROTOCOL_FEATURES = 16,
+        GET_QUEUE_NUM = 17,
+        SET_VRING_ENABLE = 18,
+    }
+}
+
+/// The protocol version, stored in the low bits of [`Header::flags`].
+pub const VERSION: u32 = 0x1;
+/// Set in [`Header::flags`] for replies.
+pub const FLAG_REPLY: u32 = 0x4;
+
+/// The message header.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct Header {
+    pub request: Request,
+    pub flags: u32,
+    /// The size of the payload following the header.
+    pub size: u32,
+}
+
+/// Feature bit indicating that the protocol feature requests are supported.
+pub const VHOST_USER_F_PROTOCOL_FEATURES: u64 = 1 << 30;
+/// The virtio 1.0 feature bit.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Set in the payload of `SET_VRING_KICK`/`SET_VRING_CALL` when no file
+/// descriptor is attached.
+pub const VRING_NOFD_MASK: u64 = 0x100;
+/// The mask of the ring index in the payload of `SET_VRING_KICK`/`SET_VRING_CALL`.
+pub const VRING_IDX_MASK: u64 = 0xff;
+
+/// The maximum number of memory regions in a `SET_MEM_TABLE` message.
+pub const MAX_MEMORY_REGIONS: usize = 8;
+
+/// Payload for requests that operate on a ring index and a value.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct VringState {
+    pub index: u32,
+    pub num: u32,
+}
+
+/// Payload for `SET_VRING_ADDR`. Addresses are in the front-end's virtual
+/// address space.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct VringAddr {
+    pub index: u32,
+    pub flags: u32,
+    pub desc_user_addr: u64,
+    pub used_user_addr: u64,
+    pub avail_user_addr: u64,
+    pub log_guest_addr: u64,
+}
+
+/// A memory region in a `SET_MEM_TABLE` message. Each region is accompanied
+/// by a file descriptor for the backing memory.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct MemoryRegion {
+    pub guest_phys_addr: u64,
+    pub memory_size: u64,
+    pub userspace_addr: u64,
+    pub mmap_offset: u64,
+}
+
+/// The header of the `SET_MEM_TABLE` payload, followed by `num_regions`
+/// [`MemoryRegion`]s.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct MemoryHeader {
+    pub num_regions: u32,
+    pub padding: u32,
+}
+
+/// The virtio-net header prepended to each frame, as used when
+/// `VIRTIO_F_VERSION_1` is negotiated.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, IntoBytes, FromBytes, Immutable, KnownLayout)]
+pub struct VirtioNetHeader {
+    pub flags: u8,
+    pub gso_type: u8,
+    pub hdr_len: u16,
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+    pub num_buffers: u16,
+}
+
+/// The index of the receive queue.
+pub const RX_QUEUE: u32 = 0;
+/// The index of the transmit queue.
+pub const TX_QUEUE: u32 = 1;
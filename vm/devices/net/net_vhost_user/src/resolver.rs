@@ -0,0 +1,45 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use crate::VhostUserEndpoint;
+use anyhow::Context as _;
+use async_trait::async_trait;
+use net_backend::resolve::ResolveEndpointParams;
+use net_backend::resolve::ResolvedEndpoint;
+use net_backend_resources::vhost_user::VhostUserHandle;
+use vm_resource::AsyncResolveResource;
+use vm_resource::IntoResource;
+use vm_resource::PlatformResource;
+use vm_resource::ResourceResolver;
+use vm_resource::declare_static_async_resolver;
+use vm_resource::kind::NetEndpointHandleKind;
+use vmcore::shared_ram::SharedGuestRamKind;
+
+pub struct VhostUserResolver;
+
+declare_static_async_resolver! {
+    VhostUserResolver,
+    (NetEndpointHandleKind, VhostUserHandle),
+}
+
+#[async_trait]
+impl AsyncResolveResource<NetEndpointHandleKind, VhostUserHandle> for VhostUserResolver {
+    type Output = ResolvedEndpoint;
+    type Error = anyhow::Error;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        resource: VhostUserHandle,
+        input: ResolveEndpointParams<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let guest_ram = resolver
+            .resolve::<SharedGuestRamKind, _>(PlatformResource.into_resource(), ())
+            .await
+            .context("failed to resolve guest RAM")?;
+        let endpoint =
+            VhostUserEndpoint::connect(input.driver_source, resource.path.as_ref(), &guest_ram)
+                .await?;
+        Ok(endpoint.into())
+    }
+}
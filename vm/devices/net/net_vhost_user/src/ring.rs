@@ -0,0 +1,221 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! The driver side of a split virtqueue placed in the memory region shared
+//! with the back-end.
+//!
+//! Each queue has a fixed layout within the region: the descriptor table, the
+//! available ring, the used ring, and a table of virtio-net header slots each
+//! start on a page boundary. Each buffer is posted as a descriptor chain
+//! starting with the header slot of the chain's head descriptor, followed by
+//! the buffer's guest memory.
+
+use guestmem::GuestMemory;
+use guestmem::GuestMemoryError;
+use std::sync::atomic;
+use thiserror::Error;
+use virtio::spec::queue::AVAIL_OFFSET_IDX;
+use virtio::spec::queue::AVAIL_OFFSET_RING;
+use virtio::spec::queue::Descriptor;
+use virtio::spec::queue::DescriptorFlags;
+use virtio::spec::queue::USED_OFFSET_FLAGS;
+use virtio::spec::queue::USED_OFFSET_IDX;
+use virtio::spec::queue::USED_OFFSET_RING;
+use virtio::spec::queue::UsedElement;
+use virtio::spec::queue::UsedFlags;
+use virtio::spec::u16_le;
+
+/// The number of descriptors in each queue.
+pub const QUEUE_SIZE: u16 = 256;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// The offset of the available ring from the start of the queue.
+const AVAIL_OFFSET: u64 = PAGE_SIZE;
+/// The offset of the used ring from the start of the queue.
+const USED_OFFSET: u64 = 2 * PAGE_SIZE;
+/// The offset of the header slots from the start of the queue.
+const HEADERS_OFFSET: u64 = 3 * PAGE_SIZE;
+/// The size of each header slot.
+const HEADER_SLOT_SIZE: u64 = 16;
+
+/// The size of the region used by each queue.
+pub const QUEUE_REGION_SIZE: u64 = 4 * PAGE_SIZE;
+
+const _: () = {
+    assert!(QUEUE_SIZE as usize * size_of::<Descriptor>() <= AVAIL_OFFSET as usize);
+    assert!(AVAIL_OFFSET_RING as usize + QUEUE_SIZE as usize * 2 + 2 <= PAGE_SIZE as usize);
+    assert!(
+        USED_OFFSET_RING as usize + QUEUE_SIZE as usize * size_of::<UsedElement>() + 2
+            <= PAGE_SIZE as usize
+    );
+    assert!(QUEUE_SIZE as u64 * HEADER_SLOT_SIZE <= PAGE_SIZE);
+};
+
+#[derive(Debug, Error)]
+pub enum RingError {
+    #[error("shared memory access error")]
+    Memory(#[from] GuestMemoryError),
+    #[error("device returned invalid descriptor index {0}")]
+    InvalidDescriptor(u32),
+}
+
+/// The location of a queue's structures.
+#[derive(Debug, Copy, Clone)]
+pub struct QueueLayout {
+    /// The offset of the queue within the shared region.
+    pub offset: u64,
+    /// The address of the queue in the back-end's view of memory.
+    pub addr: u64,
+}
+
+impl QueueLayout {
+    pub fn desc(&self) -> u64 {
+        self.addr
+    }
+
+    pub fn avail(&self) -> u64 {
+        self.addr + AVAIL_OFFSET
+    }
+
+    pub fn used(&self) -> u64 {
+        self.addr + USED_OFFSET
+    }
+}
+
+/// The driver side of a split virtqueue.
+pub struct DriverRing {
+    mem: GuestMemory,
+    layout: QueueLayout,
+    /// Descriptors not currently owned by the device.
+    free: Vec<u16>,
+    /// The descriptors of each chain, indexed by head descriptor.
+    chains: Vec<Vec<u16>>,
+    /// The next available index to publish.
+    avail_idx: u16,
+    /// The next used index to consume.
+    used_idx: u16,
+}
+
+impl DriverRing {
+    /// Initializes a new ring at `layout`, resetting any previous state.
+    pub fn new(mem: GuestMemory, layout: QueueLayout) -> Result<Self, GuestMemoryError> {
+        mem.fill_at(layout.offset, 0, QUEUE_REGION_SIZE as usize)?;
+        Ok(Self {
+            mem,
+            layout,
+            free: (0..QUEUE_SIZE).rev().collect(),
+            chains: vec![Vec::new(); QUEUE_SIZE.into()],
+            avail_idx: 0,
+            used_idx: 0,
+        })
+    }
+
+    fn header_offset(&self, head: u16) -> u64 {
+        HEADERS_OFFSET + head as u64 * HEADER_SLOT_SIZE
+    }
+
+    /// Makes a chain available to the device, consisting of a header slot
+    /// initialized with `header` followed by the guest memory `buffers`.
+    ///
+    /// If `device_writable`, the chain is set up for the device to write
+    /// (i.e., for receives).
+    ///
+    /// Returns the chain's head descriptor, or `None` if there are not enough
+    /// free descriptors.
+    pub fn push(
+        &mut self,
+        header: &[u8],
+        buffers: impl ExactSizeIterator<Item = (u64, u32)>,
+        device_writable: bool,
+    ) -> Result<Option<u16>, GuestMemoryError> {
+        assert!(header.len() as u64 <= HEADER_SLOT_SIZE);
+        let count = 1 + buffers.len();
+        if count > self.free.len() {
+            return Ok(None);
+        }
+        let chain = self.free.split_off(self.free.len() - count);
+        let head = chain[0];
+        self.mem
+            .write_at(self.layout.offset + self.header_offset(head), header)?;
+        let descs = std::iter::once((
+            self.layout.addr + self.header_offset(head),
+            header.len() as u32,
+        ))
+        .chain(buffers);
+        for (i, (address, length)) in descs.enumerate() {
+            let next = chain.get(i + 1).copied();
+            self.mem.write_plain(
+                self.layout.offset + chain[i] as u64 * size_of::<Descriptor>() as u64,
+                &Descriptor {
+                    address: address.into(),
+                    length: length.into(),
+                    flags_raw: u16::from(
+                        DescriptorFlags::new()
+                            .with_next(next.is_some())
+                            .with_write(device_writable),
+                    )
+                    .into(),
+                    next: next.unwrap_or(0).into(),
+                },
+            )?;
+        }
+        self.chains[head as usize] = chain;
+
+        let slot = (self.avail_idx % QUEUE_SIZE) as u64;
+        self.mem.write_plain::<u16_le>(
+            self.layout.offset + AVAIL_OFFSET + AVAIL_OFFSET_RING + slot * 2,
+            &head.into(),
+        )?;
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        // Ensure the ring entry is visible before the index update.
+        atomic::fence(atomic::Ordering::Release);
+        self.mem.write_plain::<u16_le>(
+            self.layout.offset + AVAIL_OFFSET + AVAIL_OFFSET_IDX,
+            &self.avail_idx.into(),
+        )?;
+        Ok(Some(head))
+    }
+
+    /// Returns whether the device needs to be notified of new available
+    /// descriptors.
+    pub fn needs_kick(&self) -> Result<bool, GuestMemoryError> {
+        // Ensure the index update is visible before checking the flags.
+        atomic::fence(atomic::Ordering::SeqCst);
+        let flags = self
+            .mem
+            .read_plain::<u16_le>(self.layout.offset + USED_OFFSET + USED_OFFSET_FLAGS)?;
+        Ok(!UsedFlags::from(flags.get()).no_notify())
+    }
+
+    /// Takes the next chain the device has finished with, returning its head
+    /// descriptor and the number of bytes the device wrote, including the
+    /// header.
+    pub fn pop_used(&mut self) -> Result<Option<(u16, u32)>, RingError> {
+        let used_idx = self
+            .mem
+            .read_plain::<u16_le>(self.layout.offset + USED_OFFSET + USED_OFFSET_IDX)?
+            .get();
+        if used_idx == self.used_idx {
+            return Ok(None);
+        }
+        // Ensure the used element is read after the index.
+        atomic::fence(atomic::Ordering::Acquire);
+        let slot = (self.used_idx % QUEUE_SIZE) as u64;
+        let elem = self.mem.read_plain::<UsedElement>(
+            self.layout.offset
+                + USED_OFFSET
+                + USED_OFFSET_RING
+                + slot * size_of::<UsedElement>() as u64,
+        )?;
+        self.used_idx = self.used_idx.wrapping_add(1);
+        let head = elem.id.get();
+        let chain = self
+            .chains
+            .get_mut(head as usize)
+            .filter(|chain| !chain.is_empty())
+            .ok_or(RingError::InvalidDescriptor(head))?;
+        self.free.append(chain);
+        Ok(Some((head as u16, elem.len.get())))
+    }
+}
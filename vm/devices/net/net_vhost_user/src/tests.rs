@@ -0,0 +1,493 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Tests against a local test double of a vhost-user back-end, which loops
+//! transmitted frames back as receives.
+
+use crate::HEADER_LEN;
+use crate::VhostUserEndpoint;
+use crate::control::recv_message;
+use crate::control::send_message;
+use crate::protocol;
+use crate::protocol::FLAG_REPLY;
+use crate::protocol::Header;
+use crate::protocol::MemoryHeader;
+use crate::protocol::MemoryRegion;
+use crate::protocol::Request;
+use crate::protocol::VERSION;
+use crate::protocol::VringAddr;
+use crate::protocol::VringState;
+use futures::StreamExt;
+use futures::future::poll_fn;
+use guestmem::GuestMemory;
+use net_backend::Endpoint;
+use net_backend::Queue;
+use net_backend::QueueConfig;
+use net_backend::RxId;
+use net_backend::TxId;
+use net_backend::TxMetadata;
+use net_backend::TxSegment;
+use net_backend::TxSegmentType;
+use net_backend::tests::Bufs;
+use pal_async::DefaultDriver;
+use pal_async::async_test;
+use pal_async::socket::PolledSocket;
+use pal_async::task::Spawn;
+use pal_async::task::Task;
+use pal_async::wait::PolledWait;
+use pal_event::Event;
+use sparse_mmap::SparseMapping;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic;
+use std::sync::atomic::AtomicBool;
+use std::thread::JoinHandle;
+use virtio::QueueResources;
+use virtio::VirtioQueue;
+use virtio::spec::queue::Descriptor;
+use virtio::spec::queue::UsedElement;
+use virtio::spec::u16_le;
+use virtio::test_helpers::TestBuffer;
+use virtio::test_helpers::TestQueue;
+use virtio_net::VirtioWorkPool;
+use vmcore::shared_ram::SharedGuestRam;
+use vmcore::shared_ram::SharedRamRange;
+use vmcore::vm_task::SingleDriverBackend;
+use vmcore::vm_task::VmTaskDriverSource;
+use zerocopy::FromBytes;
+use zerocopy::IntoBytes;
+
+#[derive(Copy, Clone, Default)]
+struct BackendRing {
+    num: u16,
+    desc: u64,
+    avail: u64,
+    used: u64,
+    /// The next available index to consume.
+    avail_idx: u16,
+    /// The next used index to publish.
+    used_idx: u16,
+}
+
+impl BackendRing {
+    /// Takes the next available descriptor chain.
+    fn pop(&mut self, mem: &GuestMemory) -> Option<(u16, Vec<Descriptor>)> {
+        let avail_idx = mem.read_plain::<u16_le>(self.avail + 2).unwrap().get();
+        if avail_idx == self.avail_idx {
+            return None;
+        }
+        atomic::fence(atomic::Ordering::Acquire);
+        let slot = (self.avail_idx % self.num) as u64;
+        let id = mem
+            .read_plain::<u16_le>(self.avail + 4 + slot * 2)
+            .unwrap()
+            .get();
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        let mut chain = Vec::new();
+        let mut index = id;
+        loop {
+            let desc = mem
+                .read_plain::<Descriptor>(self.desc + index as u64 * size_of::<Descriptor>() as u64)
+                .unwrap();
+            chain.push(desc);
+            if !desc.flags().next() {
+                break;
+            }
+            index = desc.next.get();
+        }
+        Some((id, chain))
+    }
+
+    /// Returns a descriptor to the driver.
+    fn push_used(&mut self, mem: &GuestMemory, id: u16, len: u32) {
+        let slot = (self.used_idx % self.num) as u64;
+        mem.write_plain(
+            self.used + 4 + slot * size_of::<UsedElement>() as u64,
+            &UsedElement {
+                id: (id as u32).into(),
+                len: len.into(),
+            },
+        )
+        .unwrap();
+        self.used_idx = self.used_idx.wrapping_add(1);
+        atomic::fence(atomic::Ordering::Release);
+        mem.write_plain::<u16_le>(self.used + 2, &self.used_idx.into())
+            .unwrap();
+    }
+}
+
+/// A vhost-user back-end test double.
+#[derive(Default)]
+struct TestBackend {
+    /// The front-end's memory, mapped at the guest physical addresses of the
+    /// regions.
+    mem: Option<GuestMemory>,
+    regions: Vec<MemoryRegion>,
+    rings: [BackendRing; 2],
+    calls: [Option<Event>; 2],
+    rx_kick: Option<Event>,
+    loopback: Option<(Arc<AtomicBool>, Event, JoinHandle<()>)>,
+}
+
+impl TestBackend {
+    async fn run(mut self, mut socket: PolledSocket<UnixStream>) {
+        let mut payload = Vec::new();
+        let mut fds = Vec::new();
+        while let Ok(header) = recv_message(&mut socket, &mut payload, &mut fds).await {
+            let reply = match header.request {
+                Request::GET_FEATURES => Some(
+                    (protocol::VIRTIO_F_VERSION_1 | protocol::VHOST_USER_F_PROTOCOL_FEATURES)
+                        .as_bytes()
+                        .to_vec(),
+                ),
+                Request::GET_PROTOCOL_FEATURES => Some(0u64.as_bytes().to_vec()),
+                Request::SET_OWNER
+                | Request::SET_FEATURES
+                | Request::SET_PROTOCOL_FEATURES
+                | Request::SET_VRING_ENABLE => None,
+                Request::SET_MEM_TABLE => {
+                    let (mem_header, rest) = MemoryHeader::read_from_prefix(&payload).unwrap();
+                    let regions = rest
+                        .chunks_exact(size_of::<MemoryRegion>())
+                        .take(mem_header.num_regions as usize)
+                        .map(|region| MemoryRegion::read_from_bytes(region).unwrap())
+                        .collect::<Vec<_>>();
+                    assert_eq!(regions.len(), fds.len());
+                    let end = regions
+                        .iter()
+                        .map(|region| region.guest_phys_addr + region.memory_size)
+                        .max()
+                        .unwrap();
+                    let mapping = SparseMapping::new(end as usize).unwrap();
+                    for (region, fd) in regions.iter().zip(&fds) {
+                        mapping
+                            .map_file(
+                                region.guest_phys_addr as usize,
+                                region.memory_size as usize,
+                                fd,
+                                region.mmap_offset,
+                                true,
+                            )
+                            .unwrap();
+                    }
+                    self.mem = Some(GuestMemory::new("backend", mapping));
+                    self.regions = regions;
+                    None
+                }
+                Request::SET_VRING_NUM => {
+                    let state = VringState::read_from_bytes(&payload).unwrap();
+                    self.rings[state.index as usize].num = state.num as u16;
+                    None
+                }
+                Request::SET_VRING_ADDR => {
+                    let addr = VringAddr::read_from_bytes(&payload).unwrap();
+                    let regions = &self.regions;
+                    let gpa = |va: u64| {
+                        let region = regions
+                            .iter()
+                            .find(|region| {
+                                (region.userspace_addr..region.userspace_addr + region.memory_size)
+                                    .contains(&va)
+                            })
+                            .expect("ring address is not in a region");
+                        va - region.userspace_addr + region.guest_phys_addr
+                    };
+                    let ring = &mut self.rings[addr.index as usize];
+                    ring.desc = gpa(addr.desc_user_addr);
+                    ring.avail = gpa(addr.avail_user_addr);
+                    ring.used = gpa(addr.used_user_addr);
+                    None
+                }
+                Request::SET_VRING_BASE => {
+                    let state = VringState::read_from_bytes(&payload).unwrap();
+                    let ring = &mut self.rings[state.index as usize];
+                    ring.avail_idx = state.num as u16;
+                    ring.used_idx = state.num as u16;
+                    None
+                }
+                Request::SET_VRING_CALL => {
+                    let index = u64::read_from_bytes(&payload).unwrap();
+                    self.calls[index as usize] = Some(fds.pop().unwrap().into());
+                    None
+                }
+                Request::SET_VRING_KICK => {
+                    let index = u64::read_from_bytes(&payload).unwrap() as u32;
+                    let kick = Event::from(fds.pop().unwrap());
+                    if index == protocol::RX_QUEUE {
+                        self.rx_kick = Some(kick);
+                    } else {
+                        self.start_loopback(kick);
+                    }
+                    None
+                }
+                Request::GET_VRING_BASE => {
+                    let state = VringState::read_from_bytes(&payload).unwrap();
+                    if state.index == protocol::TX_QUEUE {
+                        self.stop_loopback();
+                    }
+                    Some(
+                        VringState {
+                            index: state.index,
+                            num: self.rings[state.index as usize].avail_idx.into(),
+                        }
+                        .as_bytes()
+                        .to_vec(),
+                    )
+                }
+                request => panic!("unexpected request {request:?}"),
+            };
+            if let Some(data) = reply {
+                send_message(
+                    &mut socket,
+                    &Header {
+                        request: header.request,
+                        flags: VERSION | FLAG_REPLY,
+                        size: data.len() as u32,
+                    },
+                    &data,
+                    &[],
+                )
+                .await
+                .unwrap();
+            }
+            fds.clear();
+        }
+        self.stop_loopback();
+    }
+
+    /// Starts a thread that copies each transmitted frame into the next
+    /// receive buffer.
+    fn start_loopback(&mut self, tx_kick: Event) {
+        let mem = self.mem.clone().unwrap();
+        let [mut rx, mut tx] = self.rings;
+        let rx_call = self.calls[0].clone().unwrap();
+        let tx_call = self.calls[1].clone().unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::spawn({
+            let stop = stop.clone();
+            let tx_kick = tx_kick.clone();
+            move || {
+                loop {
+                    tx_kick.wait();
+                    if stop.load(atomic::Ordering::SeqCst) {
+                        break;
+                    }
+                    while let Some((tx_id, tx_chain)) = tx.pop(&mem) {
+                        let (rx_id, rx_chain) = rx.pop(&mem).expect("no receive buffers");
+                        // Gather the header and frame, and scatter them to
+                        // the receive buffer.
+                        let mut buf = Vec::new();
+                        for desc in &tx_chain {
+                            assert!(!desc.flags().write());
+                            let start = buf.len();
+                            buf.resize(start + desc.length.get() as usize, 0);
+                            mem.read_at(desc.address.get(), &mut buf[start..]).unwrap();
+                        }
+                        let mut remaining = buf.as_slice();
+                        for desc in &rx_chain {
+                            assert!(desc.flags().write());
+                            let (data, rest) =
+                                remaining.split_at(remaining.len().min(desc.length.get() as usize));
+                            mem.write_at(desc.address.get(), data).unwrap();
+                            remaining = rest;
+                        }
+                        assert!(remaining.is_empty(), "receive buffer too small");
+                        tx.push_used(&mem, tx_id, 0);
+                        rx.push_used(&mem, rx_id, buf.len() as u32);
+                    }
+                    tx_call.signal();
+                    rx_call.signal();
+                }
+            }
+        });
+        self.loopback = Some((stop, tx_kick, thread));
+    }
+
+    fn stop_loopback(&mut self) {
+        if let Some((stop, tx_kick, thread)) = self.loopback.take() {
+            stop.store(true, atomic::Ordering::SeqCst);
+            tx_kick.signal();
+            thread.join().unwrap();
+        }
+    }
+}
+
+/// Starts a test back-end and connects an endpoint to it, returning the
+/// back-end task, the endpoint and the guest memory shared with the back-end.
+async fn connect(
+    driver: &DefaultDriver,
+    path: &Path,
+) -> (Task<()>, VhostUserEndpoint, GuestMemory) {
+    let mut listener = PolledSocket::new(driver, UnixListener::bind(path).unwrap()).unwrap();
+    let backend = driver.spawn("backend", {
+        let driver = driver.clone();
+        async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let socket = PolledSocket::new(&driver, socket).unwrap();
+            TestBackend::default().run(socket).await;
+        }
+    });
+
+    // Back guest memory with a shared memory object, as membacking does.
+    let size = 128 * 2048;
+    let fd = sparse_mmap::alloc_shared_memory(size).unwrap();
+    let mapping = SparseMapping::new(size).unwrap();
+    mapping.map_file(0, size, &fd, 0, true).unwrap();
+    let mem = GuestMemory::new("test", mapping);
+    let guest_ram = SharedGuestRam {
+        memory: Arc::new(fd),
+        ranges: vec![SharedRamRange {
+            gpa: 0,
+            len: size as u64,
+            offset: 0,
+        }],
+    };
+
+    let driver_source = VmTaskDriverSource::new(SingleDriverBackend::new(driver.clone()));
+    let endpoint = VhostUserEndpoint::connect(&driver_source, path, &guest_ram)
+        .await
+        .unwrap();
+    (backend, endpoint, mem)
+}
+
+/// Transmits `data` from guest address 0 and waits for it to be looped back,
+/// returning the receive buffer it landed in.
+async fn loop_packet(queue: &mut dyn Queue, mem: &GuestMemory, data: &[u8]) -> RxId {
+    mem.write_at(0, data).unwrap();
+    let (sync, sent) = queue
+        .tx_avail(&[TxSegment {
+            ty: TxSegmentType::Head(TxMetadata {
+                id: TxId(1),
+                segment_count: 1,
+                len: data.len() as u32,
+                ..Default::default()
+            }),
+            gpa: 0,
+            len: data.len() as u32,
+        }])
+        .unwrap();
+    assert!(!sync);
+    assert_eq!(sent, 1);
+
+    let mut rx_packets = [RxId(0); 2];
+    let mut tx_done = [TxId(0); 2];
+    let mut rx_count = 0;
+    let mut tx_count = 0;
+    while rx_count == 0 || tx_count == 0 {
+        poll_fn(|cx| queue.poll_ready(cx)).await;
+        rx_count += queue.rx_poll(&mut rx_packets[rx_count..]).unwrap();
+        tx_count += queue.tx_poll(&mut tx_done[tx_count..]).unwrap();
+    }
+    assert_eq!(rx_count, 1);
+    assert_eq!(tx_count, 1);
+    assert_eq!(tx_done[0].0, 1);
+    rx_packets[0]
+}
+
+#[async_test]
+async fn test_loopback(driver: DefaultDriver) {
+    let dir = tempfile::tempdir().unwrap();
+    let (backend, mut endpoint, mem) = connect(&driver, &dir.path().join("vhost-user.sock")).await;
+    let mut queues = Vec::new();
+    endpoint
+        .get_queues(
+            vec![QueueConfig {
+                pool: Box::new(Bufs::new(mem.clone())),
+                initial_rx: &(1..128).map(RxId).collect::<Vec<_>>(),
+                driver: Box::new(driver.clone()),
+            }],
+            None,
+            &mut queues,
+        )
+        .await
+        .unwrap();
+
+    let data = (0..1000).map(|v| v as u8).collect::<Vec<u8>>();
+    let rx_id = loop_packet(queues[0].as_mut(), &mem, &data).await;
+    let mut received = vec![0; data.len()];
+    mem.read_at(rx_id.0 as u64 * 2048, &mut received).unwrap();
+    assert_eq!(received, data);
+
+    drop(queues);
+    endpoint.stop().await;
+    drop(endpoint);
+    backend.await;
+}
+
+/// Receives into virtio-net's buffer pool, which reserves the start of each
+/// buffer for its own header.
+#[async_test]
+async fn test_loopback_virtio_net_pool(driver: DefaultDriver) {
+    const QUEUE_SIZE: u16 = 16;
+    const QUEUE_BASE: u64 = 0x3f000;
+    const BUFFER_BASE: u64 = 0x10000;
+
+    let dir = tempfile::tempdir().unwrap();
+    let (backend, mut endpoint, mem) = connect(&driver, &dir.path().join("vhost-user.sock")).await;
+
+    // Split the header area across two descriptors to check that the pool
+    // skips it correctly.
+    let mut test_queue = TestQueue::new(&driver, &mem, QUEUE_BASE, QUEUE_SIZE);
+    let buffer = |i: u64| BUFFER_BASE + i * 0x1000;
+    let heads = (0..4)
+        .map(|i| {
+            test_queue.add(&[
+                TestBuffer::writeable(buffer(i), 8),
+                TestBuffer::writeable(buffer(i) + 0x100, 2000),
+            ])
+        })
+        .collect::<Vec<_>>();
+    let QueueResources {
+        params,
+        notify,
+        event,
+    } = test_queue.resources();
+    let mut virtio_queue = VirtioQueue::new(
+        0,
+        params,
+        mem.clone(),
+        notify,
+        PolledWait::new(&driver, event).unwrap(),
+    )
+    .unwrap();
+    let pool = VirtioWorkPool::new(mem.clone(), QUEUE_SIZE);
+    let mut initial_rx = Vec::new();
+    for _ in &heads {
+        let work = virtio_queue.next().await.unwrap().unwrap();
+        initial_rx.push(pool.queue_work(work));
+    }
+
+    let mut queues = Vec::new();
+    endpoint
+        .get_queues(
+            vec![QueueConfig {
+                pool: Box::new(pool.clone()),
+                initial_rx: &initial_rx,
+                driver: Box::new(driver.clone()),
+            }],
+            None,
+            &mut queues,
+        )
+        .await
+        .unwrap();
+
+    let data = (0..1000).map(|v| v as u8).collect::<Vec<u8>>();
+    let rx_id = loop_packet(queues[0].as_mut(), &mem, &data).await;
+    pool.complete_packet(rx_id);
+    let (head, len) = test_queue.used().await;
+    assert_eq!(RxId(head.into()), rx_id);
+    assert_eq!(len, HEADER_LEN + data.len() as u32);
+
+    let i = heads.iter().position(|&h| h == head).unwrap() as u64;
+    let mut received = vec![0; data.len()];
+    mem.read_at(buffer(i) + 0x100 + (HEADER_LEN as u64 - 8), &mut received)
+        .unwrap();
+    assert_eq!(received, data);
+
+    drop(queues);
+    endpoint.stop().await;
+    drop(endpoint);
+    backend.await;
+}
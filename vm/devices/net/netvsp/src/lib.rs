@@ -14,7 +14,6 @@
 mod saved_state;
 mod test;
 
-use crate::buffers::GuestBuffers;
 use crate::protocol::Message1RevokeReceiveBuffer;
 use crate::protocol::Message1RevokeSendBuffer;
 use crate::protocol::VMS_SWITCH_RSS_MAX_SEND_INDIRECTION_TABLE_ENTRIES;
@@ -23,7 +22,8 @@
 use crate::rndisprot::NDIS_RSS_PARAM_FLAG_DISABLE_RSS;
 use async_trait::async_trait;
 pub use buffers::BufferPool;
-use buffers::sub_allocation_size_for_mtu;
+pub use buffers::GuestBuffers;
+pub use buffers::sub_allocation_size_for_mtu;
 use futures::FutureExt;
 use futures::StreamExt;
 use futures::channel::mpsc;
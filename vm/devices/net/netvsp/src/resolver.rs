@@ -36,6 +36,7 @@ async fn resolve(
                 resource.endpoint,
                 ResolveEndpointParams {
                     mac_address: resource.mac_address,
+                    driver_source: input.driver_source,
                 },
             )
             .await?;
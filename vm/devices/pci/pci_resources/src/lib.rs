@@ -11,6 +11,7 @@
 use guestmem::DoorbellRegistration;
 use guestmem::GuestMemory;
 use guestmem::MemoryMapper;
+use memory_range::MemoryRange;
 use pci_core::msi::RegisterMsi;
 use std::sync::Arc;
 use vm_resource::CanResolveTo;
@@ -40,6 +41,8 @@ pub struct ResolvePciDeviceHandleParams<'a> {
     pub driver_source: &'a VmTaskDriverSource,
     /// The VM's guest memory.
     pub guest_memory: &'a GuestMemory,
+    /// The VM's guest RAM ranges.
+    pub guest_ram: &'a [MemoryRange],
     /// An object with which to register doorbell regions.
     pub doorbell_registration: Option<Arc<dyn DoorbellRegistration>>,
     /// An object with which to register shared memory regions.
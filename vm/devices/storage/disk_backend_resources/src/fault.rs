@@ -0,0 +1,159 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Fault injection rules for [`FaultDiskHandle`](super::FaultDiskHandle).
+
+use mesh::MeshPayload;
+use mesh::rpc::FailableRpc;
+use mesh::rpc::Rpc;
+use std::ops::Range;
+
+/// A rule describing which I/Os to fail, and how.
+///
+/// Rules are evaluated in order, and the first rule that matches an I/O
+/// determines its fate. An I/O that matches no rule is passed through to the
+/// underlying disk.
+///
+/// # Example
+/// Fail the third read that touches sectors 100 through 199 with an
+/// unrecovered read error:
+/// ```
+/// use disk_backend_resources::fault::FaultAction;
+/// use disk_backend_resources::fault::FaultOp;
+/// use disk_backend_resources::fault::FaultRule;
+/// use disk_backend_resources::fault::MediumError;
+///
+/// let rule = FaultRule::new(FaultAction::MediumError(MediumError::UnrecoveredRead))
+///     .with_ops([FaultOp::Read])
+///     .with_sectors(100..200)
+///     .with_nth(3);
+/// ```
+#[derive(MeshPayload, Clone, Debug, PartialEq)]
+pub struct FaultRule {
+    /// The operations this rule applies to. If empty, the rule applies to all
+    /// operations.
+    pub ops: Vec<FaultOp>,
+    /// The sectors this rule applies to. An I/O matches if it touches any
+    /// sector in the range. If `None`, the rule applies to all sectors,
+    /// including flushes, which have no sector range.
+    pub sectors: Option<Range<u64>>,
+    /// The number of matching I/Os to let through before the rule fires.
+    pub skip: u64,
+    /// The maximum number of times the rule fires. If `None`, there is no
+    /// limit.
+    pub count: Option<u64>,
+    /// The probability, between 0 and 1, that the rule fires for a matching
+    /// I/O. If `None`, the rule always fires.
+    pub probability: Option<f64>,
+    /// What to do when the rule fires.
+    pub action: FaultAction,
+}
+
+impl FaultRule {
+    /// Returns a rule that applies `action` to every I/O.
+    pub fn new(action: FaultAction) -> Self {
+        Self {
+            ops: Vec::new(),
+            sectors: None,
+            skip: 0,
+            count: None,
+            probability: None,
+            action,
+        }
+    }
+
+    /// Limits the rule to the given operations.
+    pub fn with_ops(mut self, ops: impl IntoIterator<Item = FaultOp>) -> Self {
+        self.ops = ops.into_iter().collect();
+        self
+    }
+
+    /// Limits the rule to I/Os that touch `sectors`.
+    pub fn with_sectors(mut self, sectors: Range<u64>) -> Self {
+        self.sectors = Some(sectors);
+        self
+    }
+
+    /// Fires the rule only for the `n`th matching I/O, counting from 1.
+    pub fn with_nth(mut self, n: u64) -> Self {
+        self.skip = n.saturating_sub(1);
+        self.count = Some(1);
+        self
+    }
+
+    /// Lets `skip` matching I/Os through before the rule fires.
+    pub fn with_skip(mut self, skip: u64) -> Self {
+        self.skip = skip;
+        self
+    }
+
+    /// Limits the number of times the rule fires.
+    pub fn with_count(mut self, count: u64) -> Self {
+        self.count = Some(count);
+        self
+    }
+
+    /// Fires the rule for a matching I/O with the given probability.
+    pub fn with_probability(mut self, probability: f64) -> Self {
+        self.probability = Some(probability);
+        self
+    }
+}
+
+/// A disk operation, for matching with a [`FaultRule`].
+#[derive(MeshPayload, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FaultOp {
+    /// A read.
+    Read,
+    /// A write.
+    Write,
+    /// A cache flush.
+    Flush,
+    /// An unmap.
+    Unmap,
+}
+
+/// The fault to inject when a [`FaultRule`] fires.
+#[derive(MeshPayload, Clone, Debug, PartialEq, Eq)]
+pub enum FaultAction {
+    /// Fail the I/O with a medium error.
+    MediumError(MediumError),
+    /// Fail the I/O with a generic I/O error.
+    IoError,
+    /// Fail the I/O with a reservation conflict.
+    ReservationConflict,
+    /// Write only the first `sectors` sectors of a write, then fail it with an
+    /// I/O error. Other operations just fail with an I/O error.
+    TornWrite {
+        /// The number of sectors to write before failing.
+        sectors: u64,
+    },
+    /// Do not complete the I/O until hung I/Os are released, at which point
+    /// it is passed through to the underlying disk.
+    Hang,
+}
+
+/// The kind of medium error to inject.
+#[derive(MeshPayload, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MediumError {
+    /// An unrecovered read error.
+    UnrecoveredRead,
+    /// A write fault.
+    WriteFault,
+    /// An end-to-end guard check failure.
+    GuardCheck,
+    /// An end-to-end application tag check failure.
+    ApplicationTagCheck,
+    /// An end-to-end reference tag check failure.
+    ReferenceTagCheck,
+}
+
+/// A request to change the behavior of a fault disk at runtime.
+#[derive(MeshPayload)]
+pub enum FaultDiskRequest {
+    /// Replaces the fault rules, resetting their counters.
+    SetRules(FailableRpc<Vec<FaultRule>, ()>),
+    /// Releases any I/Os hung by a [`FaultAction::Hang`] rule, returning how
+    /// many there were.
+    ReleaseHangs(Rpc<(), usize>),
+}
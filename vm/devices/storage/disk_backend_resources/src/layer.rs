@@ -32,6 +32,64 @@ impl ResourceId<DiskLayerHandleKind> for DiskLayerHandle {
     const ID: &'static str = "disk";
 }
 
+/// Handle for a disk layer backed by a VHD1 file, which may be fixed, dynamic,
+/// or differencing.
+///
+/// A differencing VHD's parents must be provided as separate, lower layers.
+#[derive(MeshPayload)]
+pub struct Vhd1DiskLayerHandle {
+    /// The VHD file.
+    pub file: std::fs::File,
+    /// Whether the layer is read-only, regardless of whether the disk is. This
+    /// must be set if `file` was not opened for write, such as for a parent
+    /// disk.
+    pub read_only: bool,
+}
+
+impl ResourceId<DiskLayerHandleKind> for Vhd1DiskLayerHandle {
+    const ID: &'static str = "vhd1";
+}
+
+/// Handle for a disk layer backed by a VHDX file, which may be fixed, dynamic,
+/// or differencing.
+///
+/// A differencing VHDX's parents must be provided as separate, lower layers.
+#[derive(MeshPayload)]
+pub struct VhdxDiskLayerHandle {
+    /// The VHDX file.
+    pub file: std::fs::File,
+    /// Whether the layer is read-only, regardless of whether the disk is. This
+    /// must be set if `file` was not opened for write, such as for a parent
+    /// disk.
+    pub read_only: bool,
+}
+
+impl ResourceId<DiskLayerHandleKind> for VhdxDiskLayerHandle {
+    const ID: &'static str = "vhdx";
+}
+
+/// Handle for a disk layer backed by a qcow2 image.
+///
+/// The images in the backing file chain must be provided as separate, lower
+/// layers, with a raw backing file provided as a [`DiskLayerHandle`].
+#[derive(MeshPayload)]
+pub struct Qcow2DiskLayerHandle {
+    /// The qcow2 file.
+    pub file: std::fs::File,
+    /// Whether the layer is read-only, regardless of whether the disk is. This
+    /// must be set if `file` was not opened for write, such as for a backing
+    /// file.
+    pub read_only: bool,
+    /// A read-only view of the layers below this one, which the rest of a
+    /// newly allocated cluster is copied from on a partial write. If `None`,
+    /// the rest of the cluster is zeroed.
+    pub backing: Option<Resource<DiskHandleKind>>,
+}
+
+impl ResourceId<DiskLayerHandleKind> for Qcow2DiskLayerHandle {
+    const ID: &'static str = "qcow2";
+}
+
 /// Parameters used when performing first-time init of `dbhd` files.
 #[derive(MeshPayload)]
 pub struct SqliteDiskLayerFormatParams {
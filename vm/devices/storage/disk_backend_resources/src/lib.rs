@@ -5,10 +5,12 @@
 
 #![forbid(unsafe_code)]
 
+pub mod fault;
 pub mod layer;
 
 use mesh::Cell;
 use mesh::MeshPayload;
+use mesh::rpc::FailableRpc;
 use std::time::Duration;
 use vm_resource::IntoResource;
 use vm_resource::Resource;
@@ -48,6 +50,85 @@ impl ResourceId<DiskHandleKind> for DelayDiskHandle {
     const ID: &'static str = "delay";
 }
 
+/// Disk handle for a disk with I/O rate limits.
+#[derive(MeshPayload)]
+pub struct ThrottleDiskHandle {
+    /// The underlying disk resource.
+    pub disk: Resource<DiskHandleKind>,
+    /// The initial limits. These can be changed at runtime via `inspect`.
+    pub limits: ThrottleLimits,
+}
+
+impl ResourceId<DiskHandleKind> for ThrottleDiskHandle {
+    const ID: &'static str = "throttle";
+}
+
+/// I/O rate limits for a [`ThrottleDiskHandle`]. `None` means unlimited.
+#[derive(MeshPayload, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ThrottleLimits {
+    /// The maximum number of reads per second.
+    pub read_iops: Option<u64>,
+    /// The maximum number of writes per second.
+    pub write_iops: Option<u64>,
+    /// The maximum number of bytes read per second.
+    pub read_bytes_per_sec: Option<u64>,
+    /// The maximum number of bytes written per second.
+    pub write_bytes_per_sec: Option<u64>,
+}
+
+/// Disk handle for a disk that injects faults into I/O, for testing error
+/// paths.
+#[derive(MeshPayload)]
+pub struct FaultDiskHandle {
+    /// The underlying disk resource.
+    pub disk: Resource<DiskHandleKind>,
+    /// The initial fault rules.
+    pub rules: Vec<fault::FaultRule>,
+    /// The seed for rules that fire with a given probability, so that runs are
+    /// reproducible.
+    pub seed: u64,
+    /// A channel for changing the fault rules at runtime.
+    pub requests: Option<mesh::Receiver<fault::FaultDiskRequest>>,
+}
+
+impl ResourceId<DiskHandleKind> for FaultDiskHandle {
+    const ID: &'static str = "fault";
+}
+
+/// Disk handle for a disk with a bounded block cache in front of it.
+#[derive(MeshPayload)]
+pub struct CacheDiskHandle {
+    /// The underlying disk resource.
+    pub disk: Resource<DiskHandleKind>,
+    /// Where to store the cached blocks.
+    pub storage: CacheStorage,
+    /// The maximum size of the cache, in bytes.
+    pub cache_size: u64,
+    /// The size of each cached block, in bytes. Must be a power of two and a
+    /// multiple of the disk's sector size.
+    pub block_size: u32,
+    /// If true, writes are absorbed by the cache and written to the
+    /// underlying disk later. Otherwise, writes go straight through to the
+    /// underlying disk.
+    pub write_back: bool,
+    /// How often to write dirty blocks back to the underlying disk, if at
+    /// all. Dirty blocks are always written back on eviction and flush.
+    pub flush_interval: Option<Duration>,
+}
+
+impl ResourceId<DiskHandleKind> for CacheDiskHandle {
+    const ID: &'static str = "cache";
+}
+
+/// The backing store for a [`CacheDiskHandle`].
+#[derive(MeshPayload)]
+pub enum CacheStorage {
+    /// Store cached blocks in memory.
+    Ram,
+    /// Store cached blocks in a file. Any existing contents are discarded.
+    File(std::fs::File),
+}
+
 /// Disk handle for a fixed VHD1 disk.
 #[derive(MeshPayload)]
 pub struct FixedVhd1DiskHandle(pub std::fs::File);
@@ -120,6 +201,11 @@ pub enum BlobDiskFormat {
 pub struct LayeredDiskHandle {
     /// The layers that make up the disk. The first layer is the top-most layer.
     pub layers: Vec<DiskLayerDescription>,
+    /// Runtime request channel.
+    ///
+    /// If set, the layers below the top layer are opened for write (unless
+    /// the disk is read-only) so that the top layer can be merged into them.
+    pub requests: Option<mesh::Receiver<LayeredDiskRequest>>,
 }
 
 impl LayeredDiskHandle {
@@ -127,10 +213,20 @@ impl LayeredDiskHandle {
     pub fn single_layer(layer: impl IntoResource<DiskLayerHandleKind>) -> Self {
         Self {
             layers: vec![layer.into_resource().into()],
+            requests: None,
         }
     }
 }
 
+/// A runtime request to a layered disk.
+#[derive(MeshPayload)]
+pub enum LayeredDiskRequest {
+    /// Merge the present sectors of the top layer into the layer below it,
+    /// then remove the top layer from the disk. The top layer is stale
+    /// afterwards and must be discarded.
+    MergeTopLayer(FailableRpc<(), ()>),
+}
+
 impl ResourceId<DiskHandleKind> for LayeredDiskHandle {
     const ID: &'static str = "layered";
 }
@@ -0,0 +1,965 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A disk wrapper that caches blocks of an underlying disk in a bounded cache,
+//! [`CacheDisk`].
+//!
+//! Unlike the read caches supported by `disk_layered`, the cache can be much
+//! smaller than the disk: blocks are evicted in least recently used order
+//! when the cache is full. In write-back mode, writes are absorbed by the
+//! cache and written to the underlying disk when the block is evicted, when
+//! the guest flushes, when the media is ejected, when the disk is closed with
+//! [`CacheDisk::close`], and optionally on a periodic timer. This makes it
+//! practical to run a guest from a slow remote disk, such as a `disk_blob`
+//! HTTP image, with a small amount of local storage.
+//!
+//! Dirty blocks are not written back when the disk is dropped.
+//!
+//! The cache is not persistent. A file backing store is only used as scratch
+//! space, and its contents are meaningless after the disk is dropped.
+
+#![forbid(unsafe_code)]
+
+pub mod resolver;
+
+use blocking::unblock;
+use disk_backend::Disk;
+use disk_backend::DiskError;
+use disk_backend::DiskIo;
+use disk_backend::UnmapBehavior;
+use disk_backend_resources::CacheStorage;
+use disk_file::read_exact_at;
+use disk_file::write_all_at;
+use futures::lock::Mutex;
+use futures::lock::OwnedMutexGuard;
+use guestmem::GuestMemory;
+use guestmem::MemoryRead;
+use guestmem::MemoryWrite;
+use inspect::Inspect;
+use pal_async::task::Spawn;
+use pal_async::timer::PolledTimer;
+use scsi_buffers::OwnedRequestBuffers;
+use scsi_buffers::RequestBuffers;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::Weak;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use thiserror::Error;
+use vmcore::vm_task::VmTaskDriver;
+use vmcore::vm_task::VmTaskDriverSource;
+
+/// An error creating a [`CacheDisk`].
+#[derive(Debug, Error)]
+pub enum Error {
+    /// The block size is invalid.
+    #[error("block size {0:#x} must be a power of two and a multiple of the sector size")]
+    InvalidBlockSize(u32),
+    /// The cache cannot hold a single block.
+    #[error("cache size {cache_size:#x} is smaller than the block size {block_size:#x}")]
+    CacheTooSmall {
+        /// The cache size.
+        cache_size: u64,
+        /// The block size.
+        block_size: u32,
+    },
+    /// The cache file could not be sized.
+    #[error("failed to size the cache file")]
+    File(#[source] io::Error),
+}
+
+/// The cache parameters for a [`CacheDisk`].
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// The maximum size of the cache, in bytes.
+    pub cache_size: u64,
+    /// The size of each cached block, in bytes.
+    pub block_size: u32,
+    /// Whether writes are absorbed by the cache or written straight through
+    /// to the underlying disk.
+    pub write_back: bool,
+    /// How often to write dirty blocks back to the underlying disk, if at
+    /// all.
+    pub flush_interval: Option<Duration>,
+}
+
+/// A disk with a bounded block cache in front of it.
+///
+/// Dirty blocks still in the cache when the disk is dropped are lost. Users
+/// must call [`DiskIo::sync_cache`] or [`CacheDisk::close`] first.
+#[derive(Inspect)]
+pub struct CacheDisk {
+    #[inspect(flatten)]
+    shared: Arc<Shared>,
+}
+
+#[derive(Inspect)]
+#[inspect(extra = "Self::inspect_extra")]
+struct Shared {
+    inner: Disk,
+    #[inspect(skip)]
+    sector_shift: u32,
+    #[inspect(skip)]
+    block_sectors: u64,
+    block_size: usize,
+    capacity: usize,
+    write_back: bool,
+    #[inspect(flatten)]
+    stats: Stats,
+    #[inspect(skip)]
+    storage: Storage,
+    /// The cache metadata. This is only held while looking up or updating
+    /// the metadata, never across IO.
+    #[inspect(skip)]
+    state: parking_lot::Mutex<State>,
+}
+
+#[derive(Inspect, Default)]
+struct Stats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    write_backs: AtomicU64,
+    lines: AtomicU64,
+    dirty_sectors: AtomicU64,
+}
+
+struct State {
+    lines: HashMap<u64, Entry>,
+    /// The cached blocks, keyed by the tick of their last access.
+    lru: BTreeMap<u64, u64>,
+    next_tick: u64,
+    free_slots: Vec<usize>,
+}
+
+struct Entry {
+    line: Arc<Mutex<Line>>,
+    tick: u64,
+}
+
+/// A cached block.
+///
+/// The line's lock is held for the duration of any IO to its slot, which
+/// serializes IO to the same block without blocking IO to other blocks.
+struct Line {
+    slot: usize,
+    /// Whether the slot holds the whole block. A line is filled in full from
+    /// the underlying disk when it is first read, or when it is fully
+    /// overwritten.
+    filled: bool,
+    /// Whether the line has been removed from the cache. Users that find the
+    /// line evicted once they get the lock must look the block up again.
+    evicted: bool,
+    dirty: Box<[bool]>,
+}
+
+enum Storage {
+    /// The slots' buffers, which are allocated on first write.
+    Ram(Vec<parking_lot::Mutex<Vec<u8>>>),
+    /// A file with each slot at the offset `slot * block_size`.
+    File(Arc<File>),
+}
+
+impl Storage {
+    async fn read(
+        &self,
+        block_size: usize,
+        slot: usize,
+        offset: usize,
+        len: usize,
+    ) -> Result<Vec<u8>, DiskError> {
+        match self {
+            Storage::Ram(slots) => {
+                let buf = slots[slot].lock();
+                Ok(if buf.is_empty() {
+                    vec![0; len]
+                } else {
+                    buf[offset..offset + len].to_vec()
+                })
+            }
+            Storage::File(file) => {
+                let file = file.clone();
+                let file_offset = (slot * block_size + offset) as u64;
+                unblock(move || {
+                    let mut data = vec![0; len];
+                    read_exact_at(&file, &mut data, file_offset).map(|()| data)
+                })
+                .await
+                .map_err(DiskError::Io)
+            }
+        }
+    }
+
+    async fn write(
+        &self,
+        block_size: usize,
+        slot: usize,
+        offset: usize,
+        data: Vec<u8>,
+    ) -> Result<(), DiskError> {
+        match self {
+            Storage::Ram(slots) => {
+                let mut buf = slots[slot].lock();
+                if buf.is_empty() {
+                    buf.resize(block_size, 0);
+                }
+                buf[offset..offset + data.len()].copy_from_slice(&data);
+                Ok(())
+            }
+            Storage::File(file) => {
+                let file = file.clone();
+                let file_offset = (slot * block_size + offset) as u64;
+                unblock(move || write_all_at(&file, &data, file_offset))
+                    .await
+                    .map_err(DiskError::Io)
+            }
+        }
+    }
+}
+
+/// Splits a sector range into the blocks it covers, returning each block and
+/// the range of sectors within the block.
+fn blocks(
+    block_sectors: u64,
+    sector: u64,
+    count: u64,
+) -> impl Iterator<Item = (u64, Range<usize>)> {
+    let end = sector + count;
+    let mut sector = sector;
+    std::iter::from_fn(move || {
+        (sector < end).then(|| {
+            let block = sector / block_sectors;
+            let start = sector % block_sectors;
+            let n = (block_sectors - start).min(end - sector);
+            sector += n;
+            (block, start as usize..(start + n) as usize)
+        })
+    })
+}
+
+fn count_dirty(dirty: &[bool]) -> u64 {
+    dirty.iter().filter(|&&d| d).count() as u64
+}
+
+impl CacheDisk {
+    /// Creates a new cache in front of `inner`.
+    pub fn new(
+        inner: Disk,
+        storage: CacheStorage,
+        config: CacheConfig,
+        driver_source: &VmTaskDriverSource,
+    ) -> Result<Self, Error> {
+        let block_size = config.block_size;
+        if !block_size.is_power_of_two() || block_size < inner.sector_size() {
+            return Err(Error::InvalidBlockSize(block_size));
+        }
+        let capacity = (config.cache_size / block_size as u64) as usize;
+        if capacity == 0 {
+            return Err(Error::CacheTooSmall {
+                cache_size: config.cache_size,
+                block_size,
+            });
+        }
+        let storage = match storage {
+            CacheStorage::Ram => Storage::Ram((0..capacity).map(|_| Default::default()).collect()),
+            CacheStorage::File(file) => {
+                file.set_len(capacity as u64 * block_size as u64)
+                    .map_err(Error::File)?;
+                Storage::File(Arc::new(file))
+            }
+        };
+
+        let sector_shift = inner.sector_size().trailing_zeros();
+        let shared = Arc::new(Shared {
+            sector_shift,
+            block_sectors: (block_size >> sector_shift).into(),
+            block_size: block_size as usize,
+            capacity,
+            write_back: config.write_back,
+            stats: Default::default(),
+            storage,
+            state: parking_lot::Mutex::new(State {
+                lines: HashMap::new(),
+                lru: BTreeMap::new(),
+                next_tick: 0,
+                free_slots: (0..capacity).rev().collect(),
+            }),
+            inner,
+        });
+
+        if let Some(interval) = config.flush_interval.filter(|_| config.write_back) {
+            let driver = driver_source.simple();
+            driver
+                .spawn(
+                    "disk-cache-flush",
+                    flush_periodically(driver.clone(), Arc::downgrade(&shared), interval),
+                )
+                .detach();
+        }
+
+        Ok(Self { shared })
+    }
+
+    /// Writes all dirty blocks back to the underlying disk and flushes it,
+    /// then drops the cache.
+    pub async fn close(self) -> Result<(), DiskError> {
+        self.sync_cache().await
+    }
+}
+
+async fn flush_periodically(driver: VmTaskDriver, shared: Weak<Shared>, interval: Duration) {
+    let mut timer = PolledTimer::new(&driver);
+    loop {
+        timer.sleep(interval).await;
+        let Some(shared) = shared.upgrade() else {
+            break;
+        };
+        if let Err(err) = shared.flush().await {
+            tracing::warn!(
+                error = &err as &dyn std::error::Error,
+                "failed to write back dirty cache blocks"
+            );
+        }
+    }
+}
+
+impl Drop for CacheDisk {
+    fn drop(&mut self) {
+        let dirty_sectors = self.shared.stats.dirty_sectors.load(Ordering::Relaxed);
+        if dirty_sectors != 0 {
+            tracing::error!(
+                dirty_sectors,
+                "cache disk dropped without being flushed, data lost"
+            );
+        }
+    }
+}
+
+impl Shared {
+    fn inspect_extra(&self, resp: &mut inspect::Response<'_>) {
+        resp.field_with("hit_rate", || {
+            let hits = self.stats.hits.load(Ordering::Relaxed);
+            let misses = self.stats.misses.load(Ordering::Relaxed);
+            if hits + misses == 0 {
+                0.0
+            } else {
+                hits as f64 / (hits + misses) as f64
+            }
+        });
+    }
+
+    fn check_range(&self, sector: u64, count: u64) -> Result<(), DiskError> {
+        if sector
+            .checked_add(count)
+            .is_none_or(|end| end > self.inner.sector_count())
+        {
+            return Err(DiskError::IllegalBlock);
+        }
+        Ok(())
+    }
+
+    /// Returns the number of sectors of `block` that are within the disk.
+    fn valid_sectors(&self, block: u64) -> usize {
+        self.inner
+            .sector_count()
+            .saturating_sub(block * self.block_sectors)
+            .min(self.block_sectors) as usize
+    }
+
+    async fn read_inner(&self, sector: u64, len: usize) -> Result<Vec<u8>, DiskError> {
+        let mem = GuestMemory::allocate(len);
+        self.inner
+            .read_vectored(
+                &OwnedRequestBuffers::linear(0, len, true).buffer(&mem),
+                sector,
+            )
+            .await?;
+        let mut data = vec![0; len];
+        mem.read_at(0, &mut data)
+            .map_err(|err| DiskError::Io(io::Error::other(err)))?;
+        Ok(data)
+    }
+
+    async fn write_inner(&self, sector: u64, data: &[u8]) -> Result<(), DiskError> {
+        let mem = GuestMemory::allocate(data.len());
+        mem.write_at(0, data)
+            .map_err(|err| DiskError::Io(io::Error::other(err)))?;
+        self.inner
+            .write_vectored(
+                &OwnedRequestBuffers::linear(0, data.len(), false).buffer(&mem),
+                sector,
+                false,
+            )
+            .await
+    }
+
+    /// Returns the locked line caching `block`, allocating a slot for it if
+    /// necessary. A newly allocated line is not filled.
+    async fn line(&self, block: u64) -> Result<OwnedMutexGuard<Line>, DiskError> {
+        loop {
+            let (line, victim) = {
+                let mut state = self.state.lock();
+                let state = &mut *state;
+                state.next_tick += 1;
+                let tick = state.next_tick;
+                if let Some(entry) = state.lines.get_mut(&block) {
+                    state.lru.remove(&entry.tick);
+                    state.lru.insert(tick, block);
+                    entry.tick = tick;
+                    self.stats.hits.fetch_add(1, Ordering::Relaxed);
+                    (entry.line.clone(), None)
+                } else if let Some(slot) = state.free_slots.pop() {
+                    let line = Arc::new(Mutex::new(Line {
+                        slot,
+                        filled: false,
+                        evicted: false,
+                        dirty: vec![false; self.block_sectors as usize].into(),
+                    }));
+                    state.lines.insert(
+                        block,
+                        Entry {
+                            line: line.clone(),
+                            tick,
+                        },
+                    );
+                    state.lru.insert(tick, block);
+                    self.stats.misses.fetch_add(1, Ordering::Relaxed);
+                    self.stats.lines.fetch_add(1, Ordering::Relaxed);
+                    (line, None)
+                } else {
+                    let (_, &victim) = state
+                        .lru
+                        .first_key_value()
+                        .expect("cache is full so it must have blocks");
+                    (state.lines[&victim].line.clone(), Some(victim))
+                }
+            };
+
+            let mut line = line.lock_owned().await;
+            if line.evicted {
+                continue;
+            }
+            let Some(victim) = victim else {
+                return Ok(line);
+            };
+
+            // Write back and drop the least recently used block, then look
+            // the block up again, since another user may have cached it in
+            // the meantime.
+            self.write_back(&mut line, victim).await?;
+            self.remove(&mut line, victim);
+            self.stats.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Populates `line` from the underlying disk, if it is not already
+    /// filled.
+    async fn fill(&self, line: &mut Line, block: u64) -> Result<(), DiskError> {
+        if line.filled {
+            return Ok(());
+        }
+        let len = self.valid_sectors(block) << self.sector_shift;
+        if len > 0 {
+            let data = self.read_inner(block * self.block_sectors, len).await?;
+            self.storage
+                .write(self.block_size, line.slot, 0, data)
+                .await?;
+        }
+        line.filled = true;
+        Ok(())
+    }
+
+    /// Writes the dirty sectors of `line`, caching `block`, to the underlying
+    /// disk.
+    async fn write_back(&self, line: &mut Line, block: u64) -> Result<(), DiskError> {
+        let dirty_count = count_dirty(&line.dirty);
+        if dirty_count == 0 {
+            return Ok(());
+        }
+        let data = self
+            .storage
+            .read(self.block_size, line.slot, 0, self.block_size)
+            .await?;
+
+        // Skip any sectors past the end of the disk, in case it has shrunk.
+        let dirty = &line.dirty[..self.valid_sectors(block)];
+        let mut start = 0;
+        while let Some(i) = dirty[start..].iter().position(|&d| d) {
+            let run_start = start + i;
+            let run_end = dirty[run_start..]
+                .iter()
+                .position(|&d| !d)
+                .map_or(dirty.len(), |n| run_start + n);
+            self.write_inner(
+                block * self.block_sectors + run_start as u64,
+                &data[run_start << self.sector_shift..run_end << self.sector_shift],
+            )
+            .await?;
+            start = run_end;
+        }
+
+        line.dirty.fill(false);
+        self.stats
+            .dirty_sectors
+            .fetch_sub(dirty_count, Ordering::Relaxed);
+        self.stats.write_backs.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Drops `line`, caching `block`, from the cache, discarding any dirty
+    /// data.
+    fn remove(&self, line: &mut Line, block: u64) {
+        let mut state = self.state.lock();
+        let entry = state.lines.remove(&block).unwrap();
+        state.lru.remove(&entry.tick);
+        state.free_slots.push(line.slot);
+        line.evicted = true;
+        self.stats
+            .dirty_sectors
+            .fetch_sub(count_dirty(&line.dirty), Ordering::Relaxed);
+        self.stats.lines.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Returns the cached blocks in `blocks`, in block order.
+    fn cached_lines(&self, blocks: Range<u64>) -> Vec<(u64, Arc<Mutex<Line>>)> {
+        let state = self.state.lock();
+        let mut lines = state
+            .lines
+            .iter()
+            .filter(|&(block, _)| blocks.contains(block))
+            .map(|(&block, entry)| (block, entry.line.clone()))
+            .collect::<Vec<_>>();
+        lines.sort_unstable_by_key(|&(block, _)| block);
+        lines
+    }
+
+    /// Writes all dirty blocks back to the underlying disk.
+    async fn flush(&self) -> Result<(), DiskError> {
+        for (block, line) in self.cached_lines(0..u64::MAX) {
+            let mut line = line.lock().await;
+            if !line.evicted {
+                self.write_back(&mut line, block).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn read(&self, buffers: &RequestBuffers<'_>, sector: u64) -> Result<(), DiskError> {
+        let count = (buffers.len() >> self.sector_shift) as u64;
+        self.check_range(sector, count)?;
+        let mut offset = 0;
+        for (block, range) in blocks(self.block_sectors, sector, count) {
+            let mut line = self.line(block).await?;
+            self.fill(&mut line, block).await?;
+            let len = range.len() << self.sector_shift;
+            let data = self
+                .storage
+                .read(
+                    self.block_size,
+                    line.slot,
+                    range.start << self.sector_shift,
+                    len,
+                )
+                .await?;
+            drop(line);
+            buffers.subrange(offset, len).writer().write(&data)?;
+            offset += len;
+        }
+        Ok(())
+    }
+
+    async fn write(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        fua: bool,
+    ) -> Result<(), DiskError> {
+        if self.inner.is_read_only() {
+            return Err(DiskError::ReadOnly);
+        }
+        let count = (buffers.len() >> self.sector_shift) as u64;
+        self.check_range(sector, count)?;
+        let write_through = !self.write_back || fua;
+        let mut offset = 0;
+        for (block, range) in blocks(self.block_sectors, sector, count) {
+            let len = range.len() << self.sector_shift;
+            let block_buffers = buffers.subrange(offset, len);
+            let data = block_buffers.reader().read_all()?;
+            let full = range.start == 0 && range.end == self.valid_sectors(block);
+            // Hold the line while writing through, so that the block cannot
+            // be cached from the underlying disk until the write completes.
+            let mut line = self.line(block).await?;
+            if write_through {
+                self.inner
+                    .write_vectored(
+                        &block_buffers,
+                        block * self.block_sectors + range.start as u64,
+                        fua,
+                    )
+                    .await?;
+                if !line.filled && !full {
+                    // Don't bother caching a partial block. It will be read
+                    // from the underlying disk when it is next accessed.
+                    self.remove(&mut line, block);
+                    offset += len;
+                    continue;
+                }
+            } else if !full {
+                self.fill(&mut line, block).await?;
+            }
+
+            if let Err(err) = self
+                .storage
+                .write(
+                    self.block_size,
+                    line.slot,
+                    range.start << self.sector_shift,
+                    data,
+                )
+                .await
+            {
+                // The slot may now be partially written. Refill it before it
+                // is next used, unless that would lose dirty data.
+                if count_dirty(&line.dirty) == 0 {
+                    line.filled = false;
+                }
+                return Err(err);
+            }
+            line.filled = true;
+
+            let dirty_count = count_dirty(&line.dirty[range.clone()]);
+            if write_through {
+                // The written sectors are now clean.
+                line.dirty[range].fill(false);
+                self.stats
+                    .dirty_sectors
+                    .fetch_sub(dirty_count, Ordering::Relaxed);
+            } else {
+                let dirtied = range.len() as u64 - dirty_count;
+                line.dirty[range].fill(true);
+                self.stats
+                    .dirty_sectors
+                    .fetch_add(dirtied, Ordering::Relaxed);
+            }
+            offset += len;
+        }
+        Ok(())
+    }
+
+    /// Writes back and drops the cached blocks overlapping `sector..sector +
+    /// count`. If `discard` is set, the dirty sectors in the range are
+    /// discarded rather than written back.
+    async fn drop_range(&self, sector: u64, count: u64, discard: bool) -> Result<(), DiskError> {
+        let first = sector / self.block_sectors;
+        let end = (sector + count).div_ceil(self.block_sectors);
+        for (block, line) in self.cached_lines(first..end) {
+            let mut line = line.lock().await;
+            if line.evicted {
+                continue;
+            }
+            if discard {
+                let block_sector = block * self.block_sectors;
+                let start = sector.saturating_sub(block_sector) as usize;
+                let end = ((sector + count - block_sector) as usize).min(line.dirty.len());
+                let cleaned = count_dirty(&line.dirty[start..end]);
+                line.dirty[start..end].fill(false);
+                self.stats
+                    .dirty_sectors
+                    .fetch_sub(cleaned, Ordering::Relaxed);
+            }
+            self.write_back(&mut line, block).await?;
+            self.remove(&mut line, block);
+        }
+        Ok(())
+    }
+
+    async fn unmap(
+        &self,
+        sector: u64,
+        count: u64,
+        block_level_only: bool,
+    ) -> Result<(), DiskError> {
+        if self.inner.is_read_only() {
+            return Err(DiskError::ReadOnly);
+        }
+        self.check_range(sector, count)?;
+        // The unmapped sectors no longer need to be written back. Write back
+        // the rest of the affected blocks and drop them so that subsequent
+        // reads see the underlying disk's unmapped contents.
+        self.drop_range(sector, count, true).await?;
+        self.inner.unmap(sector, count, block_level_only).await?;
+        // Drop any blocks that were cached from the underlying disk while the
+        // unmap was in progress.
+        self.drop_range(sector, count, false).await
+    }
+}
+
+impl DiskIo for CacheDisk {
+    fn disk_type(&self) -> &str {
+        "cache"
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.shared.inner.sector_count()
+    }
+
+    fn sector_size(&self) -> u32 {
+        self.shared.inner.sector_size()
+    }
+
+    fn disk_id(&self) -> Option<[u8; 16]> {
+        self.shared.inner.disk_id()
+    }
+
+    fn physical_sector_size(&self) -> u32 {
+        self.shared.inner.physical_sector_size()
+    }
+
+    fn is_fua_respected(&self) -> bool {
+        self.shared.inner.is_fua_respected()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.shared.inner.is_read_only()
+    }
+
+    fn pr(&self) -> Option<&dyn disk_backend::pr::PersistentReservation> {
+        self.shared.inner.pr()
+    }
+
+    async fn eject(&self) -> Result<(), DiskError> {
+        self.shared.flush().await?;
+        self.shared.inner.eject().await
+    }
+
+    async fn read_vectored(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+    ) -> Result<(), DiskError> {
+        self.shared.read(buffers, sector).await
+    }
+
+    async fn write_vectored(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        fua: bool,
+    ) -> Result<(), DiskError> {
+        self.shared.write(buffers, sector, fua).await
+    }
+
+    async fn sync_cache(&self) -> Result<(), DiskError> {
+        self.shared.flush().await?;
+        self.shared.inner.sync_cache().await
+    }
+
+    async fn wait_resize(&self, sector_count: u64) -> u64 {
+        self.shared.inner.wait_resize(sector_count).await
+    }
+
+    async fn unmap(
+        &self,
+        sector: u64,
+        count: u64,
+        block_level_only: bool,
+    ) -> Result<(), DiskError> {
+        self.shared.unmap(sector, count, block_level_only).await
+    }
+
+    fn unmap_behavior(&self) -> UnmapBehavior {
+        self.shared.inner.unmap_behavior()
+    }
+
+    fn optimal_unmap_sectors(&self) -> u32 {
+        self.shared.inner.optimal_unmap_sectors()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CacheConfig;
+    use super::CacheDisk;
+    use super::Shared;
+    use disk_backend::Disk;
+    use disk_backend::DiskIo;
+    use disk_backend_resources::CacheStorage;
+    use disklayer_ram::ram_disk;
+    use guestmem::GuestMemory;
+    use pal_async::DefaultDriver;
+    use pal_async::async_test;
+    use scsi_buffers::OwnedRequestBuffers;
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use vmcore::vm_task::SingleDriverBackend;
+    use vmcore::vm_task::VmTaskDriverSource;
+
+    const BLOCK_SIZE: u32 = 4096;
+
+    fn cache(
+        driver: DefaultDriver,
+        inner: Disk,
+        storage: CacheStorage,
+        write_back: bool,
+    ) -> (Disk, Arc<Shared>) {
+        let cache = CacheDisk::new(
+            inner,
+            storage,
+            CacheConfig {
+                cache_size: 4 * BLOCK_SIZE as u64,
+                block_size: BLOCK_SIZE,
+                write_back,
+                flush_interval: None,
+            },
+            &VmTaskDriverSource::new(SingleDriverBackend::new(driver)),
+        )
+        .unwrap();
+        let shared = cache.shared.clone();
+        (Disk::new(cache).unwrap(), shared)
+    }
+
+    async fn read(disk: &Disk, sector: u64, len: usize) -> Vec<u8> {
+        let mem = GuestMemory::allocate(len);
+        disk.read_vectored(
+            &OwnedRequestBuffers::linear(0, len, true).buffer(&mem),
+            sector,
+        )
+        .await
+        .unwrap();
+        let mut data = vec![0; len];
+        mem.read_at(0, &mut data).unwrap();
+        data
+    }
+
+    async fn write(disk: &Disk, sector: u64, data: &[u8]) {
+        let mem = GuestMemory::allocate(data.len());
+        mem.write_at(0, data).unwrap();
+        disk.write_vectored(
+            &OwnedRequestBuffers::linear(0, data.len(), false).buffer(&mem),
+            sector,
+            false,
+        )
+        .await
+        .unwrap();
+    }
+
+    fn pattern(seed: u8, len: usize) -> Vec<u8> {
+        (0..len).map(|i| seed.wrapping_add(i as u8)).collect()
+    }
+
+    #[async_test]
+    async fn write_back(driver: DefaultDriver) {
+        let inner = ram_disk(1 << 20, false).unwrap();
+        let (disk, shared) = cache(driver, inner.clone(), CacheStorage::Ram, true);
+
+        // A partial block write is absorbed by the cache.
+        let data = pattern(1, 1024);
+        write(&disk, 9, &data).await;
+        assert_eq!(read(&disk, 9, 1024).await, data);
+        assert_eq!(read(&inner, 9, 1024).await, vec![0; 1024]);
+        assert_eq!(shared.stats.dirty_sectors.load(Ordering::Relaxed), 2);
+
+        disk.sync_cache().await.unwrap();
+        assert_eq!(read(&inner, 9, 1024).await, data);
+        assert_eq!(shared.stats.dirty_sectors.load(Ordering::Relaxed), 0);
+    }
+
+    #[async_test]
+    async fn eviction(driver: DefaultDriver) {
+        let inner = ram_disk(1 << 20, false).unwrap();
+        let (disk, shared) = cache(driver, inner.clone(), CacheStorage::Ram, true);
+
+        // Write twice as many blocks as the cache holds, with the writes
+        // straddling block boundaries.
+        let data = pattern(7, 8 * BLOCK_SIZE as usize);
+        for (i, chunk) in data.chunks(3 * 512).enumerate() {
+            write(&disk, 4 + i as u64 * 3, chunk).await;
+        }
+        assert!(shared.stats.evictions.load(Ordering::Relaxed) >= 4);
+        assert_eq!(shared.stats.lines.load(Ordering::Relaxed), 4);
+
+        // The evicted blocks have been written back.
+        assert_eq!(
+            read(&inner, 4, 4 * BLOCK_SIZE as usize - 4 * 512).await,
+            data[..4 * BLOCK_SIZE as usize - 4 * 512]
+        );
+        assert_eq!(read(&disk, 4, data.len()).await, data);
+        disk.sync_cache().await.unwrap();
+        assert_eq!(read(&inner, 4, data.len()).await, data);
+    }
+
+    #[async_test]
+    async fn file_read_cache(driver: DefaultDriver) {
+        let inner = ram_disk(1 << 20, false).unwrap();
+        let data = pattern(3, 2 * BLOCK_SIZE as usize);
+        write(&inner, 0, &data).await;
+
+        let file = tempfile::tempfile().unwrap();
+        let (disk, shared) = cache(driver, inner.clone(), CacheStorage::File(file), false);
+        assert_eq!(read(&disk, 0, data.len()).await, data);
+        assert_eq!(read(&disk, 0, data.len()).await, data);
+        assert_eq!(shared.stats.misses.load(Ordering::Relaxed), 2);
+        assert_eq!(shared.stats.hits.load(Ordering::Relaxed), 2);
+
+        // Write-through updates both the cache and the underlying disk.
+        let new_data = pattern(9, 512);
+        write(&disk, 3, &new_data).await;
+        assert_eq!(read(&inner, 3, 512).await, new_data);
+        assert_eq!(read(&disk, 3, 512).await, new_data);
+        assert_eq!(shared.stats.dirty_sectors.load(Ordering::Relaxed), 0);
+    }
+
+    #[async_test]
+    async fn unmap(driver: DefaultDriver) {
+        let inner = ram_disk(1 << 20, false).unwrap();
+        let (disk, shared) = cache(driver, inner.clone(), CacheStorage::Ram, true);
+
+        let data = pattern(5, BLOCK_SIZE as usize);
+        write(&disk, 0, &data).await;
+        disk.unmap(0, 4, false).await.unwrap();
+
+        // The rest of the block is written back and the block is dropped.
+        assert_eq!(shared.stats.lines.load(Ordering::Relaxed), 0);
+        assert_eq!(shared.stats.dirty_sectors.load(Ordering::Relaxed), 0);
+        assert_eq!(read(&inner, 4, 2048).await, data[2048..]);
+    }
+
+    #[async_test]
+    async fn close(driver: DefaultDriver) {
+        let inner = ram_disk(1 << 20, false).unwrap();
+        let cache = CacheDisk::new(
+            inner.clone(),
+            CacheStorage::Ram,
+            CacheConfig {
+                cache_size: 4 * BLOCK_SIZE as u64,
+                block_size: BLOCK_SIZE,
+                write_back: true,
+                flush_interval: None,
+            },
+            &VmTaskDriverSource::new(SingleDriverBackend::new(driver)),
+        )
+        .unwrap();
+
+        let data = pattern(11, 2 * BLOCK_SIZE as usize);
+        let mem = GuestMemory::allocate(data.len());
+        mem.write_at(0, &data).unwrap();
+        cache
+            .write_vectored(
+                &OwnedRequestBuffers::linear(0, data.len(), false).buffer(&mem),
+                0,
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!(read(&inner, 0, data.len()).await, vec![0; data.len()]);
+
+        // Closing the cache writes back the dirty blocks.
+        cache.close().await.unwrap();
+        assert_eq!(read(&inner, 0, data.len()).await, data);
+    }
+}
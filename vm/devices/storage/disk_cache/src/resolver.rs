@@ -0,0 +1,46 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resource resolver for the cache disk.
+
+use crate::CacheConfig;
+use crate::CacheDisk;
+use async_trait::async_trait;
+use disk_backend::resolve::ResolveDiskParameters;
+use disk_backend::resolve::ResolvedDisk;
+use disk_backend_resources::CacheDiskHandle;
+use vm_resource::AsyncResolveResource;
+use vm_resource::ResourceResolver;
+use vm_resource::declare_static_async_resolver;
+use vm_resource::kind::DiskHandleKind;
+
+/// A resolver for [`CacheDisk`].
+pub struct CacheDiskResolver;
+declare_static_async_resolver!(CacheDiskResolver, (DiskHandleKind, CacheDiskHandle));
+
+#[async_trait]
+impl AsyncResolveResource<DiskHandleKind, CacheDiskHandle> for CacheDiskResolver {
+    type Output = ResolvedDisk;
+    type Error = anyhow::Error;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        rsrc: CacheDiskHandle,
+        input: ResolveDiskParameters<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let inner = resolver.resolve(rsrc.disk, input).await?;
+        let disk = CacheDisk::new(
+            inner.0,
+            rsrc.storage,
+            CacheConfig {
+                cache_size: rsrc.cache_size,
+                block_size: rsrc.block_size,
+                write_back: rsrc.write_back,
+                flush_interval: rsrc.flush_interval,
+            },
+            input.driver_source,
+        )?;
+        Ok(ResolvedDisk::new(disk)?)
+    }
+}
@@ -0,0 +1,497 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A disk wrapper that injects faults into I/O, for testing the error paths
+//! of storage devices and guests.
+//!
+//! Faults are described by a list of [`FaultRule`]s, which can be replaced at
+//! runtime via a [`FaultHandle`] or a [`FaultDiskRequest`] channel. Rules that
+//! fire with a given probability draw from a seeded generator, so that a run
+//! can be reproduced exactly.
+//!
+//! Fault injection can be toggled at runtime via `inspect`, by updating
+//! `enabled`. Disabling fault injection also releases any hung I/Os.
+
+#![forbid(unsafe_code)]
+
+pub mod resolver;
+
+use disk_backend::Disk;
+use disk_backend::DiskError;
+use disk_backend::DiskIo;
+use disk_backend::MediumErrorDetails;
+use disk_backend::UnmapBehavior;
+use disk_backend_resources::fault::FaultAction;
+use disk_backend_resources::fault::FaultDiskRequest;
+use disk_backend_resources::fault::FaultOp;
+use disk_backend_resources::fault::FaultRule;
+use disk_backend_resources::fault::MediumError;
+use event_listener::Event;
+use futures::StreamExt;
+use inspect::Inspect;
+use parking_lot::Mutex;
+use scsi_buffers::RequestBuffers;
+use seeded_rng::SeededRng;
+use std::io;
+use std::ops::Range;
+use std::str::ParseBoolError;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// A disk that fails I/O according to a set of rules.
+pub struct FaultDisk {
+    inner: Disk,
+    sector_shift: u32,
+    shared: Arc<Shared>,
+}
+
+/// An error returned when a fault rule is invalid.
+#[derive(Debug, Error)]
+pub enum InvalidRule {
+    /// The rule's probability is out of range.
+    #[error("rule {0}: probability {1} is not between 0 and 1")]
+    Probability(usize, f64),
+    /// The rule's sector range is empty.
+    #[error("rule {0}: empty sector range")]
+    EmptySectorRange(usize),
+}
+
+impl FaultDisk {
+    /// Creates a new disk that injects faults into I/O to `inner`.
+    ///
+    /// `seed` seeds the generator used by rules with a probability.
+    pub fn new(inner: Disk, rules: Vec<FaultRule>, seed: u64) -> Result<Self, InvalidRule> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                enabled: true,
+                rules: Vec::new(),
+                rng: SeededRng::new(seed),
+                generation: 0,
+                hung: 0,
+            }),
+            released: Event::new(),
+        });
+        FaultHandle(shared.clone()).set_rules(rules)?;
+        Ok(Self {
+            sector_shift: inner.sector_size().trailing_zeros(),
+            inner,
+            shared,
+        })
+    }
+
+    /// Returns a handle for changing the disk's fault rules.
+    pub fn handle(&self) -> FaultHandle {
+        FaultHandle(self.shared.clone())
+    }
+
+    /// Checks whether an I/O should fail. Hung I/Os are waited for here, and
+    /// then passed through.
+    async fn inject(&self, op: FaultOp, sectors: Option<Range<u64>>) -> Option<FaultAction> {
+        match self.shared.check(op, sectors)? {
+            FaultAction::Hang => {
+                self.shared.hang().await;
+                None
+            }
+            action => Some(action),
+        }
+    }
+
+    fn sectors(&self, sector: u64, buffers: &RequestBuffers<'_>) -> Range<u64> {
+        sector..sector + (buffers.len() as u64 >> self.sector_shift)
+    }
+}
+
+impl Inspect for FaultDisk {
+    fn inspect(&self, req: inspect::Request<'_>) {
+        let mut resp = req.respond();
+        resp.field("inner", &self.inner).field_mut_with(
+            "enabled",
+            |v: Option<&str>| -> Result<bool, ParseBoolError> {
+                let handle = FaultHandle(self.shared.clone());
+                if let Some(v) = v {
+                    handle.set_enabled(v.parse()?);
+                }
+                Ok(self.shared.state.lock().enabled)
+            },
+        );
+        let state = self.shared.state.lock();
+        resp.field("hung", state.hung)
+            .field("rules", inspect::iter_by_index(&state.rules));
+    }
+}
+
+/// A handle for changing the fault rules of a [`FaultDisk`] at runtime.
+#[derive(Clone)]
+pub struct FaultHandle(Arc<Shared>);
+
+impl FaultHandle {
+    /// Replaces the fault rules, resetting their counters.
+    ///
+    /// Hung I/Os are not affected.
+    pub fn set_rules(&self, rules: Vec<FaultRule>) -> Result<(), InvalidRule> {
+        for (i, rule) in rules.iter().enumerate() {
+            if let Some(p) = rule.probability.filter(|p| !(0.0..=1.0).contains(p)) {
+                return Err(InvalidRule::Probability(i, p));
+            }
+            if rule.sectors.as_ref().is_some_and(|r| r.is_empty()) {
+                return Err(InvalidRule::EmptySectorRange(i));
+            }
+        }
+        self.0.state.lock().rules = rules
+            .into_iter()
+            .map(|rule| RuleState {
+                rule,
+                seen: 0,
+                hits: 0,
+            })
+            .collect();
+        Ok(())
+    }
+
+    /// Enables or disables fault injection. Disabling it releases any hung
+    /// I/Os.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.0.state.lock().enabled = enabled;
+        if !enabled {
+            self.release_hangs();
+        }
+    }
+
+    /// Releases any hung I/Os, passing them through to the underlying disk.
+    /// Returns the number of I/Os that were released.
+    pub fn release_hangs(&self) -> usize {
+        let mut state = self.0.state.lock();
+        state.generation += 1;
+        let hung = std::mem::take(&mut state.hung);
+        drop(state);
+        self.0.released.notify(usize::MAX);
+        hung
+    }
+
+    /// Handles requests from `requests` until the channel is closed.
+    pub async fn handle_requests(self, mut requests: mesh::Receiver<FaultDiskRequest>) {
+        while let Some(req) = requests.next().await {
+            match req {
+                FaultDiskRequest::SetRules(rpc) => {
+                    rpc.handle_failable_sync(|rules| self.set_rules(rules))
+                }
+                FaultDiskRequest::ReleaseHangs(rpc) => rpc.handle_sync(|()| self.release_hangs()),
+            }
+        }
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Signaled when hung I/Os are released.
+    released: Event,
+}
+
+struct State {
+    enabled: bool,
+    rules: Vec<RuleState>,
+    rng: SeededRng,
+    /// Incremented each time hung I/Os are released.
+    generation: u64,
+    /// The number of I/Os hung in the current generation.
+    hung: usize,
+}
+
+impl Shared {
+    fn check(&self, op: FaultOp, sectors: Option<Range<u64>>) -> Option<FaultAction> {
+        let mut state = self.state.lock();
+        let State {
+            enabled,
+            rules,
+            rng,
+            ..
+        } = &mut *state;
+        if !*enabled {
+            return None;
+        }
+        rules
+            .iter_mut()
+            .find_map(|rule| rule.check(op, sectors.as_ref(), rng))
+    }
+
+    async fn hang(&self) {
+        let generation = {
+            let mut state = self.state.lock();
+            state.hung += 1;
+            state.generation
+        };
+        // Stop counting this I/O as hung if it is dropped before it is
+        // released.
+        let _guard = HangGuard {
+            shared: self,
+            generation,
+        };
+        loop {
+            let listener = self.released.listen();
+            if self.state.lock().generation != generation {
+                break;
+            }
+            listener.await;
+        }
+    }
+}
+
+struct HangGuard<'a> {
+    shared: &'a Shared,
+    generation: u64,
+}
+
+impl Drop for HangGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock();
+        if state.generation == self.generation {
+            state.hung -= 1;
+        }
+    }
+}
+
+#[derive(Inspect)]
+struct RuleState {
+    #[inspect(debug)]
+    rule: FaultRule,
+    /// The number of I/Os that matched the rule's criteria.
+    seen: u64,
+    /// The number of times the rule fired.
+    hits: u64,
+}
+
+impl RuleState {
+    fn check(
+        &mut self,
+        op: FaultOp,
+        sectors: Option<&Range<u64>>,
+        rng: &mut SeededRng,
+    ) -> Option<FaultAction> {
+        let rule = &self.rule;
+        if !rule.ops.is_empty() && !rule.ops.contains(&op) {
+            return None;
+        }
+        if let Some(range) = &rule.sectors {
+            let sectors = sectors?;
+            if sectors.start >= range.end || range.start >= sectors.end {
+                return None;
+            }
+        }
+        self.seen += 1;
+        if self.seen <= rule.skip || rule.count.is_some_and(|count| self.hits >= count) {
+            return None;
+        }
+        if rule.probability.is_some_and(|p| rng.next_f64() >= p) {
+            return None;
+        }
+        self.hits += 1;
+        Some(rule.action.clone())
+    }
+}
+
+fn fault_error(action: FaultAction) -> DiskError {
+    let err = || io::Error::other("injected fault");
+    match action {
+        FaultAction::MediumError(kind) => {
+            let details = match kind {
+                MediumError::UnrecoveredRead => MediumErrorDetails::UnrecoveredReadError,
+                MediumError::WriteFault => MediumErrorDetails::WriteFault,
+                MediumError::GuardCheck => MediumErrorDetails::GuardCheckFailed,
+                MediumError::ApplicationTagCheck => MediumErrorDetails::ApplicationTagCheckFailed,
+                MediumError::ReferenceTagCheck => MediumErrorDetails::ReferenceTagCheckFailed,
+            };
+            DiskError::MediumError(err(), details)
+        }
+        FaultAction::ReservationConflict => DiskError::ReservationConflict,
+        FaultAction::IoError | FaultAction::TornWrite { .. } | FaultAction::Hang => {
+            DiskError::Io(err())
+        }
+    }
+}
+
+impl DiskIo for FaultDisk {
+    fn disk_type(&self) -> &str {
+        "fault"
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.inner.sector_count()
+    }
+
+    fn sector_size(&self) -> u32 {
+        self.inner.sector_size()
+    }
+
+    fn disk_id(&self) -> Option<[u8; 16]> {
+        self.inner.disk_id()
+    }
+
+    fn physical_sector_size(&self) -> u32 {
+        self.inner.physical_sector_size()
+    }
+
+    fn is_fua_respected(&self) -> bool {
+        self.inner.is_fua_respected()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+
+    fn pr(&self) -> Option<&dyn disk_backend::pr::PersistentReservation> {
+        self.inner.pr()
+    }
+
+    async fn read_vectored(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+    ) -> Result<(), DiskError> {
+        if let Some(action) = self
+            .inject(FaultOp::Read, Some(self.sectors(sector, buffers)))
+            .await
+        {
+            return Err(fault_error(action));
+        }
+        self.inner.read_vectored(buffers, sector).await
+    }
+
+    async fn write_vectored(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        fua: bool,
+    ) -> Result<(), DiskError> {
+        let sectors = self.sectors(sector, buffers);
+        match self.inject(FaultOp::Write, Some(sectors.clone())).await {
+            None => {}
+            Some(FaultAction::TornWrite { sectors: torn }) => {
+                let torn = torn.min(sectors.end - sectors.start);
+                if torn > 0 {
+                    self.inner
+                        .write_vectored(
+                            &buffers.subrange(0, (torn as usize) << self.sector_shift),
+                            sector,
+                            fua,
+                        )
+                        .await?;
+                }
+                return Err(fault_error(FaultAction::TornWrite { sectors: torn }));
+            }
+            Some(action) => return Err(fault_error(action)),
+        }
+        self.inner.write_vectored(buffers, sector, fua).await
+    }
+
+    async fn sync_cache(&self) -> Result<(), DiskError> {
+        if let Some(action) = self.inject(FaultOp::Flush, None).await {
+            return Err(fault_error(action));
+        }
+        self.inner.sync_cache().await
+    }
+
+    fn wait_resize(&self, sector_count: u64) -> impl Future<Output = u64> + Send {
+        self.inner.wait_resize(sector_count)
+    }
+
+    async fn unmap(
+        &self,
+        sector: u64,
+        count: u64,
+        block_level_only: bool,
+    ) -> Result<(), DiskError> {
+        if let Some(action) = self
+            .inject(FaultOp::Unmap, Some(sector..sector + count))
+            .await
+        {
+            return Err(fault_error(action));
+        }
+        self.inner.unmap(sector, count, block_level_only).await
+    }
+
+    fn unmap_behavior(&self) -> UnmapBehavior {
+        self.inner.unmap_behavior()
+    }
+
+    fn optimal_unmap_sectors(&self) -> u32 {
+        self.inner.optimal_unmap_sectors()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RuleState;
+    use disk_backend_resources::fault::FaultAction;
+    use disk_backend_resources::fault::FaultOp;
+    use disk_backend_resources::fault::FaultRule;
+    use seeded_rng::SeededRng;
+
+    fn rule_state(rule: FaultRule) -> RuleState {
+        RuleState {
+            rule,
+            seen: 0,
+            hits: 0,
+        }
+    }
+
+    #[test]
+    fn test_rule_match() {
+        let mut rng = SeededRng::new(0);
+        let mut rule = rule_state(
+            FaultRule::new(FaultAction::IoError)
+                .with_ops([FaultOp::Write])
+                .with_sectors(10..20),
+        );
+
+        assert!(
+            rule.check(FaultOp::Read, Some(&(10..11)), &mut rng)
+                .is_none()
+        );
+        assert!(
+            rule.check(FaultOp::Write, Some(&(0..10)), &mut rng)
+                .is_none()
+        );
+        assert!(
+            rule.check(FaultOp::Write, Some(&(20..30)), &mut rng)
+                .is_none()
+        );
+        assert!(rule.check(FaultOp::Write, None, &mut rng).is_none());
+        assert_eq!(
+            rule.check(FaultOp::Write, Some(&(5..15)), &mut rng),
+            Some(FaultAction::IoError)
+        );
+        assert_eq!(
+            rule.check(FaultOp::Write, Some(&(19..25)), &mut rng),
+            Some(FaultAction::IoError)
+        );
+        assert_eq!(rule.seen, 2);
+        assert_eq!(rule.hits, 2);
+    }
+
+    #[test]
+    fn test_rule_nth() {
+        let mut rng = SeededRng::new(0);
+        let mut rule = rule_state(FaultRule::new(FaultAction::ReservationConflict).with_nth(3));
+        let fired = (0..5)
+            .map(|_| rule.check(FaultOp::Flush, None, &mut rng).is_some())
+            .collect::<Vec<_>>();
+        assert_eq!(fired, [false, false, true, false, false]);
+    }
+
+    #[test]
+    fn test_rule_probability() {
+        let run = |seed| {
+            let mut rng = SeededRng::new(seed);
+            let mut rule = rule_state(FaultRule::new(FaultAction::IoError).with_probability(0.25));
+            (0..1000)
+                .map(|_| rule.check(FaultOp::Read, Some(&(0..1)), &mut rng).is_some())
+                .collect::<Vec<_>>()
+        };
+
+        // The same seed produces the same faults.
+        let faults = run(1234);
+        assert_eq!(faults, run(1234));
+        let count = faults.iter().filter(|&&f| f).count();
+        assert!((150..350).contains(&count), "{count}");
+    }
+}
@@ -0,0 +1,46 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resource resolver for the fault injection disk.
+
+use crate::FaultDisk;
+use async_trait::async_trait;
+use disk_backend::resolve::ResolveDiskParameters;
+use disk_backend::resolve::ResolvedDisk;
+use disk_backend_resources::FaultDiskHandle;
+use pal_async::task::Spawn;
+use vm_resource::AsyncResolveResource;
+use vm_resource::ResourceResolver;
+use vm_resource::declare_static_async_resolver;
+use vm_resource::kind::DiskHandleKind;
+
+/// A resolver for [`FaultDisk`].
+pub struct FaultDiskResolver;
+declare_static_async_resolver!(FaultDiskResolver, (DiskHandleKind, FaultDiskHandle));
+
+#[async_trait]
+impl AsyncResolveResource<DiskHandleKind, FaultDiskHandle> for FaultDiskResolver {
+    type Output = ResolvedDisk;
+    type Error = anyhow::Error;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        rsrc: FaultDiskHandle,
+        input: ResolveDiskParameters<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let inner = resolver.resolve(rsrc.disk, input).await?;
+        let disk = FaultDisk::new(inner.0, rsrc.rules, rsrc.seed)?;
+        if let Some(requests) = rsrc.requests {
+            input
+                .driver_source
+                .simple()
+                .spawn(
+                    "fault-disk-requests",
+                    disk.handle().handle_requests(requests),
+                )
+                .detach();
+        }
+        Ok(ResolvedDisk::new(disk)?)
+    }
+}
@@ -6,7 +6,9 @@
 
 mod readwriteat;
 
-use self::readwriteat::ReadWriteAt;
+pub use self::readwriteat::ReadWriteAt;
+pub use self::readwriteat::read_exact_at;
+pub use self::readwriteat::write_all_at;
 use blocking::unblock;
 use disk_backend::DiskError;
 use disk_backend::DiskIo;
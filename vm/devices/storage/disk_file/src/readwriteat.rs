@@ -4,6 +4,7 @@
 //! Helpers for doing IO at a given offset.
 
 use std::fs;
+use std::io;
 use std::io::Result;
 
 /// A unified extension trait for [`std::fs::File`] for reading/writing at a
@@ -37,3 +38,38 @@ fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
         std::os::unix::fs::FileExt::read_at(self, buf, offset)
     }
 }
+
+/// Reads exactly `buf.len()` bytes at `offset`, failing with
+/// [`io::ErrorKind::UnexpectedEof`] if the file ends first.
+pub fn read_exact_at<T: ReadWriteAt + ?Sized>(
+    file: &T,
+    mut buf: &mut [u8],
+    mut offset: u64,
+) -> Result<()> {
+    while !buf.is_empty() {
+        let n = file.read_at(buf, offset)?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf = &mut buf[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+/// Writes all of `buf` at `offset`.
+pub fn write_all_at<T: ReadWriteAt + ?Sized>(
+    file: &T,
+    mut buf: &[u8],
+    mut offset: u64,
+) -> Result<()> {
+    while !buf.is_empty() {
+        let n = file.write_at(buf, offset)?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        buf = &buf[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
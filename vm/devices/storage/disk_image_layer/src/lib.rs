@@ -0,0 +1,187 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Helpers shared by the disk image format parsers (VHD1, VHDX, qcow2) that
+//! open an image and its parents as the layers of a layered disk.
+
+#![forbid(unsafe_code)]
+
+#[cfg(feature = "test_helpers")]
+pub mod test_helpers;
+
+use disk_backend::DiskError;
+use disk_backend_resources::LayeredDiskHandle;
+use scsi_buffers::RequestBuffers;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use vm_resource::Resource;
+use vm_resource::kind::DiskLayerHandleKind;
+
+/// The maximum number of images in a differencing or backing file chain, to
+/// avoid following a cycle of parent references forever.
+pub const MAX_CHAIN_DEPTH: usize = 64;
+
+/// The order of the bits within each byte of a sector bitmap.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BitOrder {
+    /// The first sector is the most significant bit, as in VHD1.
+    MsbFirst,
+    /// The first sector is the least significant bit, as in VHDX.
+    LsbFirst,
+}
+
+impl BitOrder {
+    fn mask(self, i: u64) -> u8 {
+        match self {
+            BitOrder::MsbFirst => 0x80 >> (i % 8),
+            BitOrder::LsbFirst => 1 << (i % 8),
+        }
+    }
+}
+
+/// Returns whether bit `i` of `bitmap` is set.
+pub fn bit_is_set(bitmap: &[u8], order: BitOrder, i: u64) -> bool {
+    bitmap[(i / 8) as usize] & order.mask(i) != 0
+}
+
+/// Sets the bits in `range` of `bitmap`.
+pub fn set_bits(bitmap: &mut [u8], order: BitOrder, range: Range<u64>) {
+    for i in range {
+        bitmap[(i / 8) as usize] |= order.mask(i);
+    }
+}
+
+/// Returns the runs of sectors in `range` whose bit in `bitmap` is set.
+pub fn set_runs(bitmap: &[u8], order: BitOrder, range: Range<u64>) -> Vec<Range<u64>> {
+    let mut runs: Vec<Range<u64>> = Vec::new();
+    for i in range {
+        if bit_is_set(bitmap, order, i) {
+            match runs.last_mut() {
+                Some(run) if run.end == i => run.end += 1,
+                _ => runs.push(i..i + 1),
+            }
+        }
+    }
+    runs
+}
+
+/// Converts a path stored in a parent locator to a path relative to `dir`.
+pub fn locator_path(dir: &Path, name: &str) -> PathBuf {
+    // Parent locators store Windows paths.
+    #[cfg(not(windows))]
+    let name = name.replace('\\', "/");
+    dir.join(name)
+}
+
+/// Splits a request of `count` sectors at `sector` into per-block ranges.
+///
+/// Returns the block number, the sector range within the block, and the
+/// byte offset of the range within the request, for each block touched.
+pub fn block_ranges(
+    sector: u64,
+    count: u64,
+    sectors_per_block: u64,
+    sector_shift: u32,
+) -> impl Iterator<Item = (u64, Range<u64>, usize)> {
+    let end = sector + count;
+    let start = sector;
+    let mut sector = sector;
+    std::iter::from_fn(move || {
+        if sector >= end {
+            return None;
+        }
+        let block = sector / sectors_per_block;
+        let block_start = sector % sectors_per_block;
+        let n = (sectors_per_block - block_start).min(end - sector);
+        let offset = ((sector - start) << sector_shift) as usize;
+        sector += n;
+        Some((block, block_start..block_start + n, offset))
+    })
+}
+
+/// Checks that a request for `buffers` at `sector` is within a disk of
+/// `sector_count` sectors, returning the number of sectors in the request.
+pub fn check_range(
+    buffers: &RequestBuffers<'_>,
+    sector: u64,
+    sector_count: u64,
+    sector_shift: u32,
+) -> Result<u64, DiskError> {
+    let count = (buffers.len() >> sector_shift) as u64;
+    if sector
+        .checked_add(count)
+        .is_none_or(|end| end > sector_count)
+    {
+        return Err(DiskError::IllegalBlock);
+    }
+    Ok(count)
+}
+
+/// Returns the file of a layer that is being dropped.
+///
+/// Panics if there are IOs still outstanding against the layer.
+pub fn into_file(file: Arc<File>) -> File {
+    Arc::try_unwrap(file).expect("no outstanding IOs")
+}
+
+/// Opens the image at `path` and the chain of parents below it.
+///
+/// `open` is called on each image in turn, from the top image down, with the
+/// image's path, its file, and whether it must be opened read-only. It
+/// validates the image and returns the file along with the path and file of
+/// the image's parent, if any. Only the top image is opened for write, and
+/// only if `read_only` is false.
+///
+/// The files are returned ordered from the top image to the base, suitable
+/// for building a layered disk. `chain_too_deep` is returned if the chain is
+/// longer than [`MAX_CHAIN_DEPTH`].
+pub fn open_chain<E: From<io::Error>>(
+    path: &Path,
+    read_only: bool,
+    chain_too_deep: impl FnOnce() -> E,
+    mut open: impl FnMut(&Path, File, bool) -> Result<(File, Option<(PathBuf, File)>), E>,
+) -> Result<Vec<File>, E> {
+    let mut files = Vec::new();
+    let mut path = path.to_owned();
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .open(&path)?;
+    loop {
+        let (this, parent) = open(&path, file, read_only || !files.is_empty())?;
+        files.push(this);
+        let Some(parent) = parent else {
+            break;
+        };
+        if files.len() == MAX_CHAIN_DEPTH {
+            return Err(chain_too_deep());
+        }
+        (path, file) = parent;
+    }
+    Ok(files)
+}
+
+/// Returns a layered disk with a layer for each file of a chain opened by
+/// [`open_chain`].
+///
+/// `layer` returns the layer resource for a file and whether the layer is
+/// read-only. Only the top layer is writable, and only if `read_only` is
+/// false.
+pub fn layered_disk_handle(
+    files: Vec<File>,
+    read_only: bool,
+    mut layer: impl FnMut(File, bool) -> Resource<DiskLayerHandleKind>,
+) -> LayeredDiskHandle {
+    LayeredDiskHandle {
+        layers: files
+            .into_iter()
+            .enumerate()
+            .map(|(i, file)| layer(file, read_only || i > 0).into())
+            .collect(),
+        requests: None,
+    }
+}
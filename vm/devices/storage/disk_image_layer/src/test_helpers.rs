@@ -0,0 +1,72 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Test fixtures for the image format crates.
+
+use disk_backend::Disk;
+use disk_layered::DiskLayer;
+use disk_layered::LayerConfiguration;
+use disk_layered::LayerIo;
+use disk_layered::LayeredDisk;
+use guestmem::GuestMemory;
+use scsi_buffers::OwnedRequestBuffers;
+use std::fs::File;
+use std::path::Path;
+use zerocopy::IntoBytes;
+
+/// Creates a new, empty file at `path`, open for read and write.
+pub fn create(path: &Path) -> File {
+    File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .unwrap()
+}
+
+/// Opens a chain of image files as a layered disk, opening each file as a
+/// layer with `open`. Only the first file is opened for write, and only if
+/// `read_only` is false.
+pub async fn open_layered<T: LayerIo>(
+    files: Vec<File>,
+    read_only: bool,
+    open: impl Fn(File, bool) -> T,
+) -> Disk {
+    let layers = files
+        .into_iter()
+        .enumerate()
+        .map(|(i, file)| LayerConfiguration {
+            layer: DiskLayer::new(open(file, read_only || i != 0)),
+            write_through: false,
+            read_cache: false,
+        })
+        .collect();
+    Disk::new(LayeredDisk::new(read_only, layers).await.unwrap()).unwrap()
+}
+
+/// Writes `data` to the 512-byte sectors starting at `sector`, staging it
+/// through `mem`.
+pub async fn write_sectors(disk: &Disk, mem: &GuestMemory, sector: u64, data: &[u32]) {
+    mem.write_at(0, data.as_bytes()).unwrap();
+    disk.write_vectored(
+        &OwnedRequestBuffers::linear(0, data.len() * 4, false).buffer(mem),
+        sector,
+        false,
+    )
+    .await
+    .unwrap();
+}
+
+/// Reads `count` 512-byte sectors starting at `sector`, staging them through
+/// `mem`.
+pub async fn read_sectors(disk: &Disk, mem: &GuestMemory, sector: u64, count: usize) -> Vec<u32> {
+    let mut buf = vec![0_u32; count * 128];
+    disk.read_vectored(
+        &OwnedRequestBuffers::linear(0, count * 512, true).buffer(mem),
+        sector,
+    )
+    .await
+    .unwrap();
+    mem.read_at(0, buf.as_mut_bytes()).unwrap();
+    buf
+}
@@ -40,6 +40,19 @@ pub fn unset_iter(&mut self) -> impl Iterator<Item = SectorBitmapRange<'_>> {
                 }
             })
     }
+
+    pub fn set_iter(&self) -> impl '_ + Iterator<Item = Range<u64>> {
+        let mut n = self.sector;
+        self.bits.chunk_by(|&a, &b| a == b).filter_map(move |bits| {
+            let start = n;
+            n += bits.len() as u64;
+            if bits.first().is_some_and(|&x| x) {
+                Some(start..n)
+            } else {
+                None
+            }
+        })
+    }
 }
 
 pub(crate) struct SectorBitmapRange<'a> {
@@ -172,5 +185,9 @@ fn test_bitmap() {
             assert_eq!(range2.start_sector(), base + 8);
             assert_eq!(range2.end_sector(), base + 10);
         }
+        assert_eq!(
+            bitmap.set_iter().collect::<Vec<_>>(),
+            [base..base + 6, base + 7..base + 8]
+        );
     }
 }
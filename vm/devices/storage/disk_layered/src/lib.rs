@@ -16,18 +16,25 @@
 //! persistent and non-persistent caches, primarily designed for lazily
 //! populating local backing stores from remote sources.
 //!
+//! The top layer can be merged into the layer below it while the disk is in
+//! use, via [`MergeHandle`]. This folds the contents of a diff layer back into
+//! its base.
+//!
 //! Missing from this implementation is write-back caching and cache eviction,
 //! which would be needed for caches that are smaller than the disk. These
-//! require potentially complicated cache management policies and are probably
-//! best implemented in a separate disk implementation.
+//! require potentially complicated cache management policies and are
+//! implemented separately, by the `disk_cache` crate.
 
 #![forbid(unsafe_code)]
 
 mod bitmap;
+mod merge;
 pub mod resolve;
 pub mod resolver;
 
 pub use bitmap::SectorMarker;
+pub use merge::MergeError;
+pub use merge::MergeHandle;
 
 use bitmap::Bitmap;
 use disk_backend::Disk;
@@ -37,18 +44,23 @@
 use guestmem::GuestMemory;
 use guestmem::MemoryWrite;
 use inspect::Inspect;
+use merge::WriteGate;
+use parking_lot::RwLock;
 use scsi_buffers::OwnedRequestBuffers;
 use scsi_buffers::RequestBuffers;
 use std::convert::Infallible;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use thiserror::Error;
 
 /// A disk composed of multiple layers.
 #[derive(Inspect)]
 pub struct LayeredDisk {
-    #[inspect(iter_by_index)]
-    layers: Vec<Layer>,
+    #[inspect(flatten)]
+    stack: Arc<LayerStack>,
     read_only: bool,
     is_fua_respected: bool,
     sector_shift: u32,
@@ -58,12 +70,51 @@ pub struct LayeredDisk {
     optimal_unmap_sectors: u32,
 }
 
+/// The current set of layers, which can change at runtime when the top layer
+/// is merged into the one below it.
+struct LayerStack {
+    layers: RwLock<Arc<[Arc<Layer>]>>,
+    /// Gate for writes, unmaps, and flushes, closed while the layer stack is
+    /// being modified.
+    gate: WriteGate,
+    /// If true, writes to the top layer are also written to the next layer.
+    merging: AtomicBool,
+    /// Held for the duration of a merge.
+    merge_lock: futures::lock::Mutex<()>,
+}
+
+impl LayerStack {
+    fn layers(&self) -> Arc<[Arc<Layer>]> {
+        self.layers.read().clone()
+    }
+
+    /// Returns whether a write to layer `index` must also be written to the
+    /// next layer.
+    ///
+    /// Must be called with the write gate entered.
+    fn writes_through(&self, index: usize, layer: &Layer) -> bool {
+        layer.write_through || (index == 0 && self.merging.load(Ordering::Relaxed))
+    }
+}
+
+impl Inspect for LayerStack {
+    fn inspect(&self, req: inspect::Request<'_>) {
+        let layers = self.layers();
+        req.respond()
+            .field("layers", inspect::iter_by_index(layers.iter()))
+            .field("merging", self.merging.load(Ordering::Relaxed));
+    }
+}
+
 #[derive(Inspect)]
 struct Layer {
     backing: Box<dyn DynLayerIo>,
     visible_sector_count: u64,
     read_cache: bool,
     write_through: bool,
+    read_only: bool,
+    is_fua_respected: bool,
+    unmap_behavior: UnmapBehavior,
 }
 
 /// A single layer which can be attached to a [`LayeredDisk`].
@@ -301,14 +352,17 @@ pub async fn new(
                     read_cache,
                 } = config;
                 visible_sector_count = sector_count.min(visible_sector_count);
-                Layer {
+                Arc::new(Layer {
                     backing: layer.backing,
                     visible_sector_count,
                     read_cache,
                     write_through,
-                }
+                    read_only: layer.meta.read_only,
+                    is_fua_respected: layer.meta.is_fua_respected,
+                    unmap_behavior: layer.meta.unmap_behavior,
+                })
             })
-            .collect::<Vec<_>>();
+            .collect();
 
         Ok(Self {
             is_fua_respected,
@@ -318,9 +372,26 @@ pub async fn new(
             physical_sector_size,
             unmap_behavior,
             optimal_unmap_sectors,
-            layers,
+            stack: Arc::new(LayerStack {
+                layers: RwLock::new(layers),
+                gate: WriteGate::default(),
+                merging: AtomicBool::new(false),
+                merge_lock: Default::default(),
+            }),
         })
     }
+
+    /// Returns a handle that can be used to merge the top layer into the
+    /// layer below it while the disk is in use.
+    pub fn merge_handle(&self) -> MergeHandle {
+        MergeHandle::new(
+            self.stack.clone(),
+            self.read_only,
+            self.sector_shift,
+            self.is_fua_respected,
+            self.unmap_behavior,
+        )
+    }
 }
 
 trait DynLayerIo: Send + Sync + Inspect {
@@ -622,7 +693,7 @@ fn disk_type(&self) -> &str {
     }
 
     fn sector_count(&self) -> u64 {
-        self.layers[0].backing.sector_count()
+        self.stack.layers.read()[0].backing.sector_count()
     }
 
     fn sector_size(&self) -> u32 {
@@ -655,8 +726,9 @@ async fn read_vectored(
         let mut bitmap = Bitmap::new(sector, sector_count);
         let mut bits_set = 0;
         let mut populate_cache = Vec::new();
+        let layers = self.stack.layers();
         // FUTURE: queue the reads to the layers in parallel.
-        'done: for (i, layer) in self.layers.iter().enumerate() {
+        'done: for (i, layer) in layers.iter().enumerate() {
             if bits_set == sector_count {
                 break;
             }
@@ -763,9 +835,10 @@ async fn write_vectored(
         sector: u64,
         fua: bool,
     ) -> Result<(), DiskError> {
-        for layer in &self.layers {
+        let _guard = self.stack.gate.enter().await;
+        for (i, layer) in self.stack.layers().iter().enumerate() {
             layer.backing.write(buffers, sector, fua, false).await?;
-            if !layer.write_through {
+            if !self.stack.writes_through(i, layer) {
                 break;
             }
         }
@@ -773,9 +846,10 @@ async fn write_vectored(
     }
 
     async fn sync_cache(&self) -> Result<(), DiskError> {
-        for layer in &self.layers {
+        let _guard = self.stack.gate.enter().await;
+        for (i, layer) in self.stack.layers().iter().enumerate() {
             layer.backing.sync_cache().await?;
-            if !layer.write_through {
+            if !self.stack.writes_through(i, layer) {
                 break;
             }
         }
@@ -783,7 +857,9 @@ async fn sync_cache(&self) -> Result<(), DiskError> {
     }
 
     fn wait_resize(&self, sector_count: u64) -> impl Future<Output = u64> + Send {
-        self.layers[0].backing.wait_resize(sector_count)
+        // FUTURE: restart the wait if the top layer is merged away.
+        let layers = self.stack.layers();
+        async move { layers[0].backing.wait_resize(sector_count).await }
     }
 
     async fn unmap(
@@ -796,10 +872,12 @@ async fn unmap(
             return Ok(());
         }
 
-        for (layer, next_layer) in self
-            .layers
+        let _guard = self.stack.gate.enter().await;
+        let layers = self.stack.layers();
+        for (i, (layer, next_layer)) in layers
             .iter()
-            .zip(self.layers.iter().map(Some).skip(1).chain([None]))
+            .zip(layers.iter().map(Some).skip(1).chain([None]))
+            .enumerate()
         {
             let next_is_zero = if let Some(next_layer) = next_layer {
                 // Sectors beyond the layer's visible sector count are logically
@@ -816,7 +894,14 @@ async fn unmap(
                 .backing
                 .unmap(sector_offset, sector_count, block_level_only, next_is_zero)
                 .await?;
-            if !layer.write_through {
+            // While merging, only propagate the unmap to the next layer if
+            // both layers are guaranteed to zero the sectors. Otherwise, the
+            // next layer could end up with different contents than the top
+            // layer for sectors that have already been merged.
+            let through = layer.write_through
+                || (self.unmap_behavior == UnmapBehavior::Zeroes
+                    && self.stack.writes_through(i, layer));
+            if !through {
                 break;
             }
         }
@@ -911,6 +996,7 @@ mod tests {
     use crate::LayerConfiguration;
     use crate::LayerIo;
     use crate::LayeredDisk;
+    use crate::MergeError;
     use crate::SectorMarker;
     use crate::WriteNoOverwrite;
     use disk_backend::DiskIo;
@@ -1143,4 +1229,127 @@ async fn test_read_cache() {
             );
         }
     }
+    #[async_test]
+    async fn test_merge() {
+        const SIZE: u64 = 8192;
+        let top = Arc::new(TestLayer::new(SIZE));
+        let bottom = Arc::new(TestLayer::new(SIZE));
+        let disk = LayeredDisk::new(
+            false,
+            vec![
+                LayerConfiguration {
+                    layer: DiskLayer::new(top.clone()),
+                    read_cache: false,
+                    write_through: false,
+                },
+                LayerConfiguration {
+                    layer: DiskLayer::new(bottom.clone()),
+                    read_cache: false,
+                    write_through: false,
+                },
+            ],
+        )
+        .await
+        .unwrap();
+
+        let mut mem = GuestMemory::allocate(0x10000);
+        let buffers = OwnedRequestBuffers::linear(0, 0x10000, true);
+
+        mem.inner_buf_mut().unwrap().fill(0xcc);
+        for sector in [0, 100, 3000, SIZE - 1] {
+            disk.write_vectored(&buffers.buffer(&mem).subrange(0, 512), sector, false)
+                .await
+                .unwrap();
+        }
+        assert!(bottom.sectors.lock().is_empty());
+
+        let merge = disk.merge_handle();
+        merge.merge_top_layer().await.unwrap();
+        assert_eq!(
+            bottom.sectors.lock().keys().copied().collect::<Vec<_>>(),
+            [0, 100, 3000, SIZE - 1]
+        );
+
+        // Writes now go to the remaining layer only.
+        disk.write_vectored(&buffers.buffer(&mem).subrange(0, 512), 5, false)
+            .await
+            .unwrap();
+        assert!(!top.sectors.lock().contains_key(&5));
+        assert!(bottom.sectors.lock().contains_key(&5));
+
+        mem.inner_buf_mut().unwrap().fill(0);
+        disk.read_vectored(&buffers.buffer(&mem).subrange(0, 512), 3000)
+            .await
+            .unwrap();
+        assert!(
+            mem.inner_buf_mut().unwrap()[..512]
+                .iter()
+                .all(|&b| b == 0xcc)
+        );
+
+        assert!(matches!(
+            merge.merge_top_layer().await,
+            Err(MergeError::SingleLayer)
+        ));
+    }
+
+    #[async_test]
+    async fn test_merge_reopen() {
+        const SIZE: u64 = 64;
+        let top = Arc::new(TestLayer::new(SIZE));
+        let bottom = Arc::new(TestLayer::new(SIZE));
+        let mut mem = GuestMemory::allocate(0x10000);
+        let buffers = OwnedRequestBuffers::linear(0, 0x10000, true);
+        {
+            let disk = LayeredDisk::new(
+                false,
+                vec![
+                    LayerConfiguration {
+                        layer: DiskLayer::new(top.clone()),
+                        read_cache: false,
+                        write_through: false,
+                    },
+                    LayerConfiguration {
+                        layer: DiskLayer::new(bottom.clone()),
+                        read_cache: false,
+                        write_through: false,
+                    },
+                ],
+            )
+            .await
+            .unwrap();
+            mem.inner_buf_mut().unwrap().fill(0xcc);
+            disk.write_vectored(&buffers.buffer(&mem).subrange(0, 1024), 0, false)
+                .await
+                .unwrap();
+            disk.merge_handle().merge_top_layer().await.unwrap();
+
+            // Overwrite one of the merged sectors after the merge.
+            mem.inner_buf_mut().unwrap().fill(0xdd);
+            disk.write_vectored(&buffers.buffer(&mem).subrange(0, 512), 1, false)
+                .await
+                .unwrap();
+        }
+
+        // The top layer is discarded, and the reopened disk has both the
+        // merged sectors and the later write.
+        drop(top);
+        let disk = LayeredDisk::new(
+            false,
+            vec![LayerConfiguration {
+                layer: DiskLayer::new(bottom),
+                read_cache: false,
+                write_through: false,
+            }],
+        )
+        .await
+        .unwrap();
+        mem.inner_buf_mut().unwrap().fill(0);
+        disk.read_vectored(&buffers.buffer(&mem).subrange(0, 1024), 0)
+            .await
+            .unwrap();
+        let buf = mem.inner_buf_mut().unwrap();
+        assert!(buf[..512].iter().all(|&b| b == 0xcc));
+        assert!(buf[512..1024].iter().all(|&b| b == 0xdd));
+    }
 }
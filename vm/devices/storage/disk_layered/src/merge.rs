@@ -0,0 +1,281 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Support for merging the top layer of a layered disk into the layer below
+//! it, while the disk is in use.
+
+use super::Layer;
+use super::LayerStack;
+use crate::bitmap::Bitmap;
+use disk_backend::DiskError;
+use disk_backend::UnmapBehavior;
+use event_listener::Event;
+use guestmem::GuestMemory;
+use scsi_buffers::OwnedRequestBuffers;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use thiserror::Error;
+
+/// The number of bytes to merge at a time. Writes to the disk are paused while
+/// each chunk is being merged.
+const MERGE_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// An error returned when merging the top layer of a layered disk.
+#[derive(Debug, Error)]
+pub enum MergeError {
+    /// The disk has only one layer.
+    #[error("there is no layer to merge into")]
+    SingleLayer,
+    /// The disk is read only.
+    #[error("the disk is read only")]
+    ReadOnly,
+    /// The top layer is a read cache or write-through layer.
+    #[error("the top layer is a cache layer")]
+    CacheLayer,
+    /// The layer below the top layer is read only.
+    #[error("the layer below the top layer is read only")]
+    LowerLayerReadOnly,
+    /// The layers have different sizes.
+    #[error("the top layer has {top} sectors but the layer below has {lower}")]
+    SectorCountMismatch {
+        /// The sector count of the top layer.
+        top: u64,
+        /// The sector count of the layer below.
+        lower: u64,
+    },
+    /// The disk zeroes unmapped sectors, but the layer below would not.
+    #[error("the layer below the top layer does not zero unmapped sectors")]
+    UnmapBehavior,
+    /// The disk respects FUA, but the layer below would not.
+    #[error("the layer below the top layer does not respect FUA")]
+    FuaNotRespected,
+    /// Another merge is already in progress.
+    #[error("a merge is already in progress")]
+    InProgress,
+    /// Failed to read from the top layer.
+    #[error("failed to read from the top layer")]
+    Read(#[source] DiskError),
+    /// Failed to write to the layer below.
+    #[error("failed to write to the layer below the top layer")]
+    Write(#[source] DiskError),
+    /// Failed to flush the layer below.
+    #[error("failed to flush the layer below the top layer")]
+    Flush(#[source] DiskError),
+}
+
+/// A handle for merging the top layer of a [`LayeredDisk`](super::LayeredDisk)
+/// into the layer below it.
+#[derive(Clone)]
+pub struct MergeHandle {
+    stack: Arc<LayerStack>,
+    read_only: bool,
+    sector_shift: u32,
+    is_fua_respected: bool,
+    unmap_behavior: UnmapBehavior,
+}
+
+impl MergeHandle {
+    pub(crate) fn new(
+        stack: Arc<LayerStack>,
+        read_only: bool,
+        sector_shift: u32,
+        is_fua_respected: bool,
+        unmap_behavior: UnmapBehavior,
+    ) -> Self {
+        Self {
+            stack,
+            read_only,
+            sector_shift,
+            is_fua_respected,
+            unmap_behavior,
+        }
+    }
+
+    /// Merges the present sectors of the top layer into the layer below it,
+    /// then removes the top layer from the disk.
+    ///
+    /// The disk remains usable during the merge. Until the merge completes,
+    /// writes go to both layers, and they are briefly paused while each chunk
+    /// of the top layer is copied. If the merge fails, the top layer is left
+    /// in place, and the layer below may contain some of its sectors.
+    ///
+    /// A successful merge consumes the top layer. Writes then go only to the
+    /// layer below, so the top layer must not be stacked on it again, or those
+    /// writes would be rolled back.
+    pub async fn merge_top_layer(&self) -> Result<(), MergeError> {
+        let Some(_lock) = self.stack.merge_lock.try_lock() else {
+            return Err(MergeError::InProgress);
+        };
+
+        // The layer stack only changes while the merge lock is held, so this
+        // snapshot stays current for the duration of the merge.
+        let layers = self.stack.layers();
+        let (top, lower) = self.validate(&layers)?;
+
+        // Start mirroring writes to the layer below.
+        {
+            let _closed = self.stack.gate.close().await;
+            self.stack.merging.store(true, Ordering::Relaxed);
+        }
+
+        let result = self.copy(top, lower).await;
+
+        let _closed = self.stack.gate.close().await;
+        let result = match result {
+            Ok(()) => lower.backing.sync_cache().await.map_err(MergeError::Flush),
+            Err(err) => Err(err),
+        };
+        if result.is_ok() {
+            *self.stack.layers.write() = Arc::from(&layers[1..]);
+        }
+        self.stack.merging.store(false, Ordering::Relaxed);
+        result
+    }
+
+    fn validate<'a>(&self, layers: &'a [Arc<Layer>]) -> Result<(&'a Layer, &'a Layer), MergeError> {
+        if self.read_only {
+            return Err(MergeError::ReadOnly);
+        }
+        let [top, lower, ..] = layers else {
+            return Err(MergeError::SingleLayer);
+        };
+        if top.read_cache || top.write_through {
+            return Err(MergeError::CacheLayer);
+        }
+        if lower.read_only {
+            return Err(MergeError::LowerLayerReadOnly);
+        }
+        let (top_count, lower_count) = (top.backing.sector_count(), lower.backing.sector_count());
+        if top_count != lower_count {
+            return Err(MergeError::SectorCountMismatch {
+                top: top_count,
+                lower: lower_count,
+            });
+        }
+        // Removing the top layer must not weaken the guarantees that have
+        // already been reported for the disk.
+        if self.unmap_behavior == UnmapBehavior::Zeroes
+            && lower.unmap_behavior != UnmapBehavior::Zeroes
+        {
+            return Err(MergeError::UnmapBehavior);
+        }
+        if self.is_fua_respected && !lower.is_fua_respected {
+            return Err(MergeError::FuaNotRespected);
+        }
+        Ok((top, lower))
+    }
+
+    async fn copy(&self, top: &Layer, lower: &Layer) -> Result<(), MergeError> {
+        let chunk_sectors = (MERGE_CHUNK_SIZE >> self.sector_shift).max(1) as u64;
+        let len = (chunk_sectors as usize) << self.sector_shift;
+        let mem = GuestMemory::allocate(len);
+        let owned_buf = OwnedRequestBuffers::linear(0, len, true);
+        let buffers = owned_buf.buffer(&mem);
+
+        let sector_count = top.backing.sector_count();
+        let mut sector = 0;
+        while sector < sector_count {
+            let count = chunk_sectors.min(sector_count - sector);
+            let mut bitmap = Bitmap::new(sector, count as usize);
+
+            // Pause writes so that they cannot race with the copy.
+            let _closed = self.stack.gate.close().await;
+            {
+                let mut range = bitmap.unset_iter().next().unwrap();
+                top.backing
+                    .read(
+                        &buffers.subrange(0, (count as usize) << self.sector_shift),
+                        sector,
+                        range.view(count),
+                    )
+                    .await
+                    .map_err(MergeError::Read)?;
+            }
+            for range in bitmap.set_iter() {
+                let offset = ((range.start - sector) as usize) << self.sector_shift;
+                let len = ((range.end - range.start) as usize) << self.sector_shift;
+                lower
+                    .backing
+                    .write(&buffers.subrange(offset, len), range.start, false, false)
+                    .await
+                    .map_err(MergeError::Write)?;
+            }
+            sector += count;
+        }
+        Ok(())
+    }
+}
+
+/// Tracks in-flight writes so that they can be paused while the layer stack is
+/// being modified.
+#[derive(Default)]
+pub(crate) struct WriteGate {
+    /// The number of in-flight writes, plus `CLOSED` if the gate is closed.
+    state: AtomicUsize,
+    /// Signaled when the gate is opened.
+    opened: Event,
+    /// Signaled when the last in-flight write completes while the gate is
+    /// closed.
+    drained: Event,
+}
+
+const CLOSED: usize = 1 << (usize::BITS - 1);
+
+impl WriteGate {
+    /// Waits for the gate to be open, then enters it. The gate cannot finish
+    /// closing until the returned guard is dropped.
+    pub async fn enter(&self) -> WriteGuard<'_> {
+        loop {
+            if self.state.fetch_add(1, Ordering::AcqRel) & CLOSED == 0 {
+                break WriteGuard(self);
+            }
+            let listener = self.opened.listen();
+            self.exit();
+            if self.state.load(Ordering::Acquire) & CLOSED != 0 {
+                listener.await;
+            }
+        }
+    }
+
+    fn exit(&self) {
+        if self.state.fetch_sub(1, Ordering::AcqRel) == CLOSED | 1 {
+            self.drained.notify(usize::MAX);
+        }
+    }
+
+    /// Closes the gate and waits for in-flight writes to complete. The gate is
+    /// reopened when the returned guard is dropped.
+    ///
+    /// Only one caller may close the gate at a time.
+    pub async fn close(&self) -> ClosedGate<'_> {
+        let state = self.state.fetch_or(CLOSED, Ordering::AcqRel);
+        assert!(state & CLOSED == 0, "gate already closed");
+        let closed = ClosedGate(self);
+        while self.state.load(Ordering::Acquire) != CLOSED {
+            let listener = self.drained.listen();
+            if self.state.load(Ordering::Acquire) == CLOSED {
+                break;
+            }
+            listener.await;
+        }
+        closed
+    }
+}
+
+pub(crate) struct WriteGuard<'a>(&'a WriteGate);
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        self.0.exit();
+    }
+}
+
+pub(crate) struct ClosedGate<'a>(&'a WriteGate);
+
+impl Drop for ClosedGate<'_> {
+    fn drop(&mut self) {
+        self.0.state.fetch_and(!CLOSED, Ordering::AcqRel);
+        self.0.opened.notify(usize::MAX);
+    }
+}
@@ -6,6 +6,7 @@
 use super::InvalidLayeredDisk;
 use super::LayerConfiguration;
 use super::LayeredDisk;
+use super::MergeHandle;
 use super::resolve::ResolveDiskLayerParameters;
 use super::resolve::ResolvedDiskLayer;
 use crate::DiskLayer;
@@ -14,8 +15,11 @@
 use disk_backend::resolve::ResolveDiskParameters;
 use disk_backend::resolve::ResolvedDisk;
 use disk_backend_resources::LayeredDiskHandle;
+use disk_backend_resources::LayeredDiskRequest;
 use disk_backend_resources::layer::DiskLayerHandle;
+use futures::StreamExt;
 use futures::future::TryJoinAll;
+use pal_async::task::Spawn;
 use thiserror::Error;
 use vm_resource::AsyncResolveResource;
 use vm_resource::ResolveError;
@@ -58,6 +62,11 @@ async fn resolve(
         resource: LayeredDiskHandle,
         input: ResolveDiskParameters<'_>,
     ) -> Result<Self::Output, Self::Error> {
+        // If the disk can be asked to merge its top layer, then the layer
+        // below it is the merge target and must be writable too, although the
+        // disk only writes to it while merging. The layers below that are
+        // still read only.
+        let mut merge_target = resource.requests.is_some();
         let mut read_only = input.read_only;
         let layers = resource
             .layers
@@ -66,7 +75,11 @@ async fn resolve(
             .map(|(i, desc)| {
                 let this_read_only = read_only && !desc.read_cache;
                 if !desc.write_through {
-                    read_only = true;
+                    if merge_target {
+                        merge_target = false;
+                    } else {
+                        read_only = true;
+                    }
                 }
                 async move {
                     let layer = resolver
@@ -94,10 +107,32 @@ async fn resolve(
             .await
             .map_err(ResolveLayeredDiskError::CreateDisk)?;
 
+        if let Some(requests) = resource.requests {
+            input
+                .driver_source
+                .simple()
+                .spawn(
+                    "layered-disk-requests",
+                    handle_requests(disk.merge_handle(), requests),
+                )
+                .detach();
+        }
+
         ResolvedDisk::new(disk).map_err(ResolveLayeredDiskError::InvalidDisk)
     }
 }
 
+async fn handle_requests(merge: MergeHandle, mut requests: mesh::Receiver<LayeredDiskRequest>) {
+    while let Some(req) = requests.next().await {
+        match req {
+            LayeredDiskRequest::MergeTopLayer(rpc) => {
+                rpc.handle_failable(async |()| merge.merge_top_layer().await)
+                    .await
+            }
+        }
+    }
+}
+
 #[async_trait]
 impl AsyncResolveResource<DiskLayerHandleKind, DiskLayerHandle> for LayeredDiskResolver {
     type Output = ResolvedDiskLayer;
@@ -0,0 +1,110 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! qcow2 file format definitions, from QEMU's `docs/interop/qcow2.txt`.
+//!
+//! All on-disk fields are big endian.
+
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+type U32BE = zerocopy::byteorder::U32<zerocopy::byteorder::BigEndian>;
+type U64BE = zerocopy::byteorder::U64<zerocopy::byteorder::BigEndian>;
+
+pub const MAGIC: u32 = u32::from_be_bytes(*b"QFI\xfb");
+
+/// The header, including the version 3 fields. Version 2 headers end at
+/// `incompatible_features`.
+#[repr(C)]
+#[derive(Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct Header {
+    pub magic: U32BE,
+    pub version: U32BE,
+    pub backing_file_offset: U64BE,
+    pub backing_file_size: U32BE,
+    pub cluster_bits: U32BE,
+    pub size: U64BE,
+    pub crypt_method: U32BE,
+    pub l1_size: U32BE,
+    pub l1_table_offset: U64BE,
+    pub refcount_table_offset: U64BE,
+    pub refcount_table_clusters: U32BE,
+    pub nb_snapshots: U32BE,
+    pub snapshots_offset: U64BE,
+    pub incompatible_features: U64BE,
+    pub compatible_features: U64BE,
+    pub autoclear_features: U64BE,
+    pub refcount_order: U32BE,
+    pub header_length: U32BE,
+}
+
+pub const V2_HEADER_LENGTH: usize = 72;
+pub const V3_HEADER_LENGTH: usize = size_of::<Header>();
+
+/// The byte offset of the v3 compression type field, present if the header
+/// length is large enough.
+pub const COMPRESSION_TYPE_OFFSET: usize = 104;
+pub const COMPRESSION_TYPE_ZLIB: u8 = 0;
+
+/// The byte offsets of header fields that are updated in place.
+pub const REFCOUNT_TABLE_OFFSET_OFFSET: u64 = 48;
+pub const AUTOCLEAR_FEATURES_OFFSET: u64 = 88;
+
+pub const MIN_CLUSTER_BITS: u32 = 9;
+pub const MAX_CLUSTER_BITS: u32 = 21;
+pub const DEFAULT_CLUSTER_BITS: u32 = 16;
+pub const MAX_REFCOUNT_ORDER: u32 = 6;
+pub const DEFAULT_REFCOUNT_ORDER: u32 = 4;
+
+/// The longest backing file name QEMU will write.
+pub const MAX_BACKING_FILE_SIZE: u32 = 1023;
+
+pub const INCOMPAT_DIRTY: u64 = 1 << 0;
+pub const INCOMPAT_CORRUPT: u64 = 1 << 1;
+pub const INCOMPAT_EXTERNAL_DATA_FILE: u64 = 1 << 2;
+pub const INCOMPAT_COMPRESSION_TYPE: u64 = 1 << 3;
+pub const INCOMPAT_EXTENDED_L2: u64 = 1 << 4;
+
+/// The incompatible features this implementation understands, possibly only
+/// to reject opening the image for write.
+pub const INCOMPAT_SUPPORTED: u64 = INCOMPAT_DIRTY | INCOMPAT_CORRUPT | INCOMPAT_COMPRESSION_TYPE;
+
+#[repr(C)]
+#[derive(Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct HeaderExtension {
+    pub extension_type: U32BE,
+    pub length: U32BE,
+}
+
+pub const EXTENSION_END: u32 = 0;
+pub const EXTENSION_BACKING_FORMAT: u32 = 0xe2792aca;
+
+/// The mask of the host offset in L1 and standard L2 entries.
+pub const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/// The mask of the offset in refcount table entries.
+pub const REFCOUNT_TABLE_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+
+/// Set in L1 and L2 entries if the referenced cluster's refcount is exactly
+/// one, meaning it can be written in place.
+pub const OFLAG_COPIED: u64 = 1 << 63;
+/// Set in L2 entries for compressed clusters.
+pub const OFLAG_COMPRESSED: u64 = 1 << 62;
+/// Set in v3 L2 entries for clusters that read as zero.
+pub const OFLAG_ZERO: u64 = 1;
+
+/// Returns the shift of the sector count field in a compressed cluster
+/// descriptor.
+pub fn compressed_size_shift(cluster_bits: u32) -> u32 {
+    62 - (cluster_bits - 8)
+}
+
+/// Returns the host offset and maximum length in bytes of the compressed
+/// data for a compressed cluster L2 entry.
+pub fn compressed_range(entry: u64, cluster_bits: u32) -> (u64, u64) {
+    let shift = compressed_size_shift(cluster_bits);
+    let offset = entry & ((1 << shift) - 1);
+    let sectors = ((entry & !OFLAG_COPIED & !OFLAG_COMPRESSED) >> shift) + 1;
+    (offset, sectors * 512 - (offset & 511))
+}
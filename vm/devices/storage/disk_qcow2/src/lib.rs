@@ -0,0 +1,1649 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A user-mode qcow2 disk implementation, for use with images produced by
+//! QEMU tooling.
+//!
+//! A qcow2 image is opened as a disk layer via [`Qcow2Layer`]. An image's
+//! backing file chain is mapped onto the layers of a
+//! [`LayeredDisk`](disk_layered::LayeredDisk), with each qcow2 image in the
+//! chain becoming a layer, and a raw backing file, if any, becoming the bottom
+//! layer.
+//!
+//! Reads of compressed clusters require the `zlib` feature. Encrypted images,
+//! external data files, and extended L2 entries are not supported.
+
+#![forbid(unsafe_code)]
+
+mod format;
+pub mod resolver;
+
+use blocking::unblock;
+use disk_backend::Disk;
+use disk_backend::DiskError;
+use disk_backend::UnmapBehavior;
+use disk_backend_resources::FileDiskHandle;
+use disk_backend_resources::LayeredDiskHandle;
+use disk_backend_resources::layer::DiskLayerHandle;
+use disk_backend_resources::layer::Qcow2DiskLayerHandle;
+use disk_file::ReadWriteAt;
+use disk_file::read_exact_at;
+use disk_file::write_all_at;
+use disk_layered::LayerIo;
+use disk_layered::SectorMarker;
+use futures::lock::Mutex;
+use futures::lock::OwnedMutexGuard;
+use guestmem::GuestMemory;
+use guestmem::MemoryRead;
+use guestmem::MemoryWrite;
+use inspect::Inspect;
+use scsi_buffers::OwnedRequestBuffers;
+use scsi_buffers::RequestBuffers;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use thiserror::Error;
+use vm_resource::Resource;
+use vm_resource::kind::DiskHandleKind;
+use zerocopy::FromBytes;
+use zerocopy::FromZeros;
+use zerocopy::IntoBytes;
+
+const SECTOR_SHIFT: u32 = 9;
+
+/// The maximum size of the L1 table in bytes, matching QEMU's limit.
+const MAX_L1_SIZE: u64 = 32 * 1024 * 1024;
+
+/// An error encountered while opening or creating a qcow2 image.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum OpenError {
+    /// An IO error occurred.
+    #[error("io error")]
+    Io(#[from] io::Error),
+    /// The file is not a qcow2 image.
+    #[error("not a qcow2 image")]
+    InvalidMagic,
+    /// The image version is not supported.
+    #[error("unsupported qcow2 version: {0}")]
+    UnsupportedVersion(u32),
+    /// The header is invalid.
+    #[error("invalid qcow2 header")]
+    InvalidHeader,
+    /// The cluster size is invalid.
+    #[error("invalid qcow2 cluster bits: {0}")]
+    InvalidClusterBits(u32),
+    /// The refcount width is invalid.
+    #[error("invalid qcow2 refcount order: {0}")]
+    InvalidRefcountOrder(u32),
+    /// The disk size is invalid.
+    #[error("invalid qcow2 disk size: {0:#x}")]
+    InvalidDiskSize(u64),
+    /// The image is encrypted.
+    #[error("encrypted qcow2 images are not supported")]
+    Encrypted,
+    /// The image uses incompatible features that are not supported.
+    #[error("unsupported qcow2 incompatible features: {0:#x}")]
+    UnsupportedFeatures(u64),
+    /// The image uses a compression type other than zlib.
+    #[error("unsupported qcow2 compression type: {0}")]
+    UnsupportedCompression(u8),
+    /// The image was not closed cleanly, so its refcounts may be stale.
+    #[error(
+        "qcow2 image is dirty and cannot be opened for write; repair it with `qemu-img check -r all`"
+    )]
+    Dirty,
+    /// The image has been marked corrupt.
+    #[error("qcow2 image is marked corrupt and cannot be opened for write")]
+    Corrupt,
+    /// The L1 table is invalid.
+    #[error("invalid qcow2 L1 table")]
+    InvalidL1Table,
+    /// The refcount table is invalid.
+    #[error("invalid qcow2 refcount table")]
+    InvalidRefcountTable,
+    /// A header extension is invalid.
+    #[error("invalid qcow2 header extension")]
+    InvalidHeaderExtension,
+    /// The backing file name is invalid.
+    #[error("invalid qcow2 backing file name")]
+    InvalidBackingFile,
+    /// The backing file format is not supported.
+    #[error("unsupported qcow2 backing file format: {0}")]
+    UnsupportedBackingFormat(String),
+    /// The backing file could not be opened.
+    #[error("failed to open backing file {0}")]
+    BackingFile(PathBuf, #[source] io::Error),
+    /// The backing file chain is too deep.
+    #[error("too many images in qcow2 backing chain")]
+    ChainTooDeep,
+}
+
+fn corrupt(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt qcow2 image: {what}"),
+    )
+}
+
+#[cfg(feature = "zlib")]
+fn decompress(input: &[u8], output: &mut [u8]) -> io::Result<()> {
+    // QEMU writes raw deflate streams, without a zlib header.
+    let mut decompress = flate2::Decompress::new(false);
+    decompress
+        .decompress(input, output, flate2::FlushDecompress::Finish)
+        .map_err(|_| corrupt("invalid compressed cluster"))?;
+    if decompress.total_out() != output.len() as u64 {
+        return Err(corrupt("short compressed cluster"));
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "zlib"))]
+fn decompress(_input: &[u8], _output: &mut [u8]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "compressed qcow2 clusters require the zlib feature",
+    ))
+}
+
+/// The backing file of an image, from its header.
+#[derive(Debug, Clone, Inspect)]
+struct BackingFile {
+    name: String,
+    format: Option<String>,
+}
+
+/// The fixed layout of an image.
+#[derive(Debug, Copy, Clone, Inspect)]
+struct Geometry {
+    version: u32,
+    cluster_bits: u32,
+    refcount_order: u32,
+    disk_size: u64,
+    #[inspect(hex)]
+    l1_table_offset: u64,
+}
+
+impl Geometry {
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    fn l2_bits(&self) -> u32 {
+        self.cluster_bits - 3
+    }
+
+    fn refcount_block_bits(&self) -> u32 {
+        self.cluster_bits + 3 - self.refcount_order
+    }
+
+    fn l1_entries(&self) -> u64 {
+        self.disk_size
+            .div_ceil(self.cluster_size() << self.l2_bits())
+    }
+}
+
+/// How a guest cluster is mapped in the image.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Mapping {
+    /// The cluster is not allocated and is read from the backing file.
+    Unallocated,
+    /// The cluster reads as zero. `offset` is the host offset of a
+    /// preallocated cluster, or zero.
+    Zero { offset: u64 },
+    /// The cluster is stored uncompressed at `offset`. If `copied` is false,
+    /// the cluster is shared (e.g. with a snapshot) and must be copied before
+    /// being written.
+    Data { offset: u64, copied: bool },
+    /// The cluster is compressed.
+    Compressed { offset: u64, len: u64 },
+}
+
+/// The mutable metadata state of an image.
+struct State {
+    l1: Vec<u64>,
+    /// Cached L2 tables, by host offset.
+    l2_tables: HashMap<u64, Box<[u64]>>,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    /// Cached refcount blocks, by refcount table index.
+    refcount_blocks: HashMap<u64, Box<[u8]>>,
+    /// The end of the allocated portion of the file.
+    next_free: u64,
+    /// Clusters freed since the image was opened, available for reuse.
+    free_clusters: Vec<u64>,
+    /// Clusters freed while data IOs were in flight. These are not reused
+    /// until the IOs complete, since the IOs may still access them.
+    pending_free: Vec<u64>,
+    /// The number of data IOs in flight outside the metadata lock.
+    in_flight: Arc<AtomicUsize>,
+}
+
+/// A data IO in flight using host offsets looked up from the metadata.
+///
+/// Must be created with the metadata lock held.
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(state: &State) -> Self {
+        state.in_flight.fetch_add(1, Ordering::SeqCst);
+        Self(state.in_flight.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl State {
+    fn l2_table(&mut self, file: &File, geo: &Geometry, offset: u64) -> io::Result<&mut [u64]> {
+        if !self.l2_tables.contains_key(&offset) {
+            let mut table = vec![0u64; 1 << geo.l2_bits()];
+            read_exact_at(file, table.as_mut_bytes(), offset)?;
+            for entry in &mut table {
+                *entry = u64::from_be(*entry);
+            }
+            self.l2_tables.insert(offset, table.into());
+        }
+        Ok(self.l2_tables.get_mut(&offset).unwrap())
+    }
+
+    fn lookup(&mut self, file: &File, geo: &Geometry, cluster: u64) -> io::Result<Mapping> {
+        let l1_entry = self.l1[(cluster >> geo.l2_bits()) as usize];
+        let l2_offset = l1_entry & format::OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(Mapping::Unallocated);
+        }
+        if l2_offset & (geo.cluster_size() - 1) != 0 {
+            return Err(corrupt("unaligned L2 table"));
+        }
+        let l2_index = (cluster & ((1 << geo.l2_bits()) - 1)) as usize;
+        let entry = self.l2_table(file, geo, l2_offset)?[l2_index];
+        if entry & format::OFLAG_COMPRESSED != 0 {
+            let (offset, len) = format::compressed_range(entry, geo.cluster_bits);
+            return Ok(Mapping::Compressed { offset, len });
+        }
+        let offset = entry & format::OFFSET_MASK;
+        if offset & (geo.cluster_size() - 1) != 0 {
+            return Err(corrupt("unaligned data cluster"));
+        }
+        Ok(if geo.version >= 3 && entry & format::OFLAG_ZERO != 0 {
+            Mapping::Zero { offset }
+        } else if offset != 0 {
+            Mapping::Data {
+                offset,
+                copied: entry & format::OFLAG_COPIED != 0,
+            }
+        } else {
+            Mapping::Unallocated
+        })
+    }
+
+    /// Sets the L2 entry for `cluster`, allocating or copying the L2 table as
+    /// necessary.
+    fn set_l2_entry(
+        &mut self,
+        file: &File,
+        geo: &Geometry,
+        cluster: u64,
+        entry: u64,
+    ) -> io::Result<()> {
+        let l1_index = (cluster >> geo.l2_bits()) as usize;
+        let l1_entry = self.l1[l1_index];
+        let old_offset = l1_entry & format::OFFSET_MASK;
+        let l2_offset = if old_offset == 0 || l1_entry & format::OFLAG_COPIED == 0 {
+            // Allocate a new L2 table, copying the old one if it is shared.
+            let table = if old_offset != 0 {
+                self.l2_table(file, geo, old_offset)?.to_vec()
+            } else {
+                vec![0; 1 << geo.l2_bits()]
+            };
+            let new_offset = self.allocate(file, geo)?;
+            let be = table.iter().map(|&e| e.to_be()).collect::<Vec<_>>();
+            write_all_at(file, be.as_bytes(), new_offset)?;
+            self.l2_tables.insert(new_offset, table.into());
+            self.set_l1_entry(file, geo, l1_index, new_offset | format::OFLAG_COPIED)?;
+            if old_offset != 0 {
+                self.l2_tables.remove(&old_offset);
+                self.decref(file, geo, old_offset)?;
+            }
+            new_offset
+        } else {
+            old_offset
+        };
+
+        // Only update the cached entry once it is written, so that a failed
+        // write leaves the mapping unchanged.
+        let l2_index = cluster & ((1 << geo.l2_bits()) - 1);
+        let table = self.l2_table(file, geo, l2_offset)?;
+        write_all_at(file, &entry.to_be_bytes(), l2_offset + l2_index * 8)?;
+        table[l2_index as usize] = entry;
+        Ok(())
+    }
+
+    fn set_l1_entry(
+        &mut self,
+        file: &File,
+        geo: &Geometry,
+        index: usize,
+        entry: u64,
+    ) -> io::Result<()> {
+        self.l1[index] = entry;
+        write_all_at(
+            file,
+            &entry.to_be_bytes(),
+            geo.l1_table_offset + index as u64 * 8,
+        )
+    }
+
+    fn refcount_block(
+        &mut self,
+        file: &File,
+        geo: &Geometry,
+        table_index: u64,
+    ) -> io::Result<Option<&mut [u8]>> {
+        let Some(&entry) = self.refcount_table.get(table_index as usize) else {
+            return Ok(None);
+        };
+        let offset = entry & format::REFCOUNT_TABLE_OFFSET_MASK;
+        if offset == 0 {
+            return Ok(None);
+        }
+        if !self.refcount_blocks.contains_key(&table_index) {
+            let mut block = vec![0; geo.cluster_size() as usize];
+            read_exact_at(file, &mut block, offset)?;
+            self.refcount_blocks.insert(table_index, block.into());
+        }
+        Ok(Some(self.refcount_blocks.get_mut(&table_index).unwrap()))
+    }
+
+    /// Returns the refcount of the cluster at `offset`.
+    fn refcount(&mut self, file: &File, geo: &Geometry, offset: u64) -> io::Result<u64> {
+        let cluster = offset >> geo.cluster_bits;
+        let table_index = cluster >> geo.refcount_block_bits();
+        let index = cluster & ((1 << geo.refcount_block_bits()) - 1);
+        let Some(block) = self.refcount_block(file, geo, table_index)? else {
+            return Ok(0);
+        };
+        Ok(get_refcount(block, geo.refcount_order, index))
+    }
+
+    /// Sets the refcount of the cluster at `offset`, allocating refcount
+    /// blocks and growing the refcount table as necessary.
+    fn set_refcount(
+        &mut self,
+        file: &File,
+        geo: &Geometry,
+        offset: u64,
+        value: u64,
+    ) -> io::Result<()> {
+        let cluster = offset >> geo.cluster_bits;
+        let table_index = cluster >> geo.refcount_block_bits();
+        let index = cluster & ((1 << geo.refcount_block_bits()) - 1);
+        if table_index as usize >= self.refcount_table.len() {
+            self.grow_refcount_table(file, geo, table_index + 1)?;
+        }
+        if self.refcount_table[table_index as usize] == 0 {
+            // Allocate a new refcount block. Its own refcount is set
+            // afterwards, which may recursively allocate another block.
+            let block_offset = self.next_free;
+            self.next_free += geo.cluster_size();
+            write_all_at(file, &vec![0; geo.cluster_size() as usize], block_offset)?;
+            self.refcount_table[table_index as usize] = block_offset;
+            write_all_at(
+                file,
+                &block_offset.to_be_bytes(),
+                self.refcount_table_offset + table_index * 8,
+            )?;
+            self.refcount_blocks
+                .insert(table_index, vec![0; geo.cluster_size() as usize].into());
+            self.set_refcount(file, geo, block_offset, 1)?;
+        }
+
+        let block_offset = self.refcount_table[table_index as usize];
+        let block = self.refcount_block(file, geo, table_index)?.unwrap();
+        let byte_range = put_refcount(block, geo.refcount_order, index, value)?;
+        write_all_at(
+            file,
+            &block[byte_range.clone()],
+            block_offset + byte_range.start as u64,
+        )
+    }
+
+    /// Moves the refcount table to a larger allocation with at least
+    /// `min_entries` entries.
+    fn grow_refcount_table(
+        &mut self,
+        file: &File,
+        geo: &Geometry,
+        min_entries: u64,
+    ) -> io::Result<()> {
+        let entries = min_entries.max(self.refcount_table.len() as u64 * 2);
+        let clusters = (entries * 8).div_ceil(geo.cluster_size());
+        let old_offset = self.refcount_table_offset;
+        let old_clusters = (self.refcount_table.len() as u64 * 8).div_ceil(geo.cluster_size());
+
+        let new_offset = self.next_free;
+        self.next_free += clusters * geo.cluster_size();
+        self.refcount_table
+            .resize((clusters * geo.cluster_size() / 8) as usize, 0);
+        let be = self
+            .refcount_table
+            .iter()
+            .map(|&e| e.to_be())
+            .collect::<Vec<_>>();
+        write_all_at(file, be.as_bytes(), new_offset)?;
+        file.sync_data()?;
+
+        let mut fields = [0; 12];
+        fields[..8].copy_from_slice(&new_offset.to_be_bytes());
+        fields[8..].copy_from_slice(&(clusters as u32).to_be_bytes());
+        write_all_at(file, &fields, format::REFCOUNT_TABLE_OFFSET_OFFSET)?;
+        file.sync_data()?;
+        self.refcount_table_offset = new_offset;
+
+        for i in 0..clusters {
+            self.set_refcount(file, geo, new_offset + (i << geo.cluster_bits), 1)?;
+        }
+        for i in 0..old_clusters {
+            self.decref(file, geo, old_offset + (i << geo.cluster_bits))?;
+        }
+        Ok(())
+    }
+
+    /// Allocates a cluster, returning its host offset. The cluster's contents
+    /// are undefined.
+    fn allocate(&mut self, file: &File, geo: &Geometry) -> io::Result<u64> {
+        if self.in_flight.load(Ordering::SeqCst) == 0 {
+            self.free_clusters.append(&mut self.pending_free);
+        }
+        let offset = match self.free_clusters.pop() {
+            Some(offset) => offset,
+            None => {
+                let offset = self.next_free;
+                self.next_free += geo.cluster_size();
+                offset
+            }
+        };
+        self.set_refcount(file, geo, offset, 1)?;
+        Ok(offset)
+    }
+
+    /// Decrements the refcount of the cluster at `offset`, freeing it if it
+    /// reaches zero.
+    fn decref(&mut self, file: &File, geo: &Geometry, offset: u64) -> io::Result<()> {
+        let refcount = self.refcount(file, geo, offset)?;
+        if refcount == 0 {
+            return Err(corrupt("cluster refcount underflow"));
+        }
+        self.set_refcount(file, geo, offset, refcount - 1)?;
+        if refcount == 1 {
+            self.pending_free.push(offset);
+        }
+        Ok(())
+    }
+
+    /// Releases the host clusters referenced by `mapping`.
+    fn release(&mut self, file: &File, geo: &Geometry, mapping: Mapping) -> io::Result<()> {
+        match mapping {
+            Mapping::Unallocated | Mapping::Zero { offset: 0 } => {}
+            Mapping::Zero { offset } | Mapping::Data { offset, .. } => {
+                self.decref(file, geo, offset)?;
+            }
+            Mapping::Compressed { offset, len } => {
+                // The compressed data may span (and share) host clusters.
+                let mask = !(geo.cluster_size() - 1);
+                let mut cluster = offset & mask;
+                while cluster < offset + len {
+                    self.decref(file, geo, cluster)?;
+                    cluster += geo.cluster_size();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes `data` to the part of a cluster in `range`, if the cluster is
+/// allocated and not shared. Returns false if the cluster must be allocated
+/// instead.
+fn write_in_place(
+    file: &File,
+    mapping: Mapping,
+    range: &Range<u64>,
+    data: &[u8],
+) -> io::Result<bool> {
+    let Mapping::Data {
+        offset,
+        copied: true,
+    } = mapping
+    else {
+        return Ok(false);
+    };
+    let len = (range.end - range.start) as usize;
+    write_all_at(file, &data[..len], offset + range.start)?;
+    Ok(true)
+}
+
+/// Reads a refcount of width `1 << order` bits from a refcount block.
+fn get_refcount(block: &[u8], order: u32, index: u64) -> u64 {
+    if order < 3 {
+        let bits = 1 << order;
+        let per_byte = 8 >> order;
+        let byte = block[(index / per_byte) as usize];
+        ((byte >> ((index % per_byte) * bits)) & ((1 << bits) - 1) as u8) as u64
+    } else {
+        let width = 1 << (order - 3);
+        let start = index as usize * width;
+        block[start..start + width]
+            .iter()
+            .fold(0, |v, &b| (v << 8) | b as u64)
+    }
+}
+
+/// Writes a refcount to a refcount block, returning the modified byte range.
+fn put_refcount(block: &mut [u8], order: u32, index: u64, value: u64) -> io::Result<Range<usize>> {
+    if order < 6 && value >> (1 << order) != 0 {
+        return Err(io::Error::other("qcow2 refcount overflow"));
+    }
+    if order < 3 {
+        let bits = 1 << order;
+        let per_byte = 8 >> order;
+        let i = (index / per_byte) as usize;
+        let shift = (index % per_byte) * bits;
+        let mask = (((1u16 << bits) - 1) as u8) << shift;
+        block[i] = (block[i] & !mask) | ((value as u8) << shift);
+        Ok(i..i + 1)
+    } else {
+        let width = 1 << (order - 3);
+        let start = index as usize * width;
+        block[start..start + width].copy_from_slice(&value.to_be_bytes()[8 - width..]);
+        Ok(start..start + width)
+    }
+}
+
+/// A qcow2 image opened as a disk layer.
+#[derive(Inspect)]
+pub struct Qcow2Layer {
+    #[inspect(skip)]
+    file: Arc<File>,
+    #[inspect(flatten)]
+    geo: Geometry,
+    read_only: bool,
+    backing_file: Option<BackingFile>,
+    /// The disk below this layer, used to fill in the rest of a newly
+    /// allocated cluster on a partial write.
+    #[inspect(skip)]
+    backing: Option<Disk>,
+    #[inspect(skip)]
+    state: Arc<Mutex<State>>,
+}
+
+impl Qcow2Layer {
+    /// Opens a qcow2 image as a disk layer.
+    pub fn open(file: File, read_only: bool) -> Result<Self, OpenError> {
+        let mut header = format::Header::new_zeroed();
+        read_exact_at(
+            &file,
+            &mut header.as_mut_bytes()[..format::V2_HEADER_LENGTH],
+            0,
+        )?;
+        if header.magic.get() != format::MAGIC {
+            return Err(OpenError::InvalidMagic);
+        }
+        let version = header.version.get();
+        let mut compression_type = format::COMPRESSION_TYPE_ZLIB;
+        match version {
+            2 => {
+                header.refcount_order.set(format::DEFAULT_REFCOUNT_ORDER);
+                header.header_length.set(format::V2_HEADER_LENGTH as u32);
+            }
+            3 => {
+                read_exact_at(&file, header.as_mut_bytes(), 0)?;
+                let header_length = header.header_length.get() as usize;
+                if header_length < format::V3_HEADER_LENGTH || header_length % 8 != 0 {
+                    return Err(OpenError::InvalidHeader);
+                }
+                if header_length > format::COMPRESSION_TYPE_OFFSET {
+                    let mut b = [0];
+                    read_exact_at(&file, &mut b, format::COMPRESSION_TYPE_OFFSET as u64)?;
+                    compression_type = b[0];
+                }
+            }
+            _ => return Err(OpenError::UnsupportedVersion(version)),
+        }
+
+        let cluster_bits = header.cluster_bits.get();
+        if !(format::MIN_CLUSTER_BITS..=format::MAX_CLUSTER_BITS).contains(&cluster_bits) {
+            return Err(OpenError::InvalidClusterBits(cluster_bits));
+        }
+        let refcount_order = header.refcount_order.get();
+        if refcount_order > format::MAX_REFCOUNT_ORDER {
+            return Err(OpenError::InvalidRefcountOrder(refcount_order));
+        }
+        if header.crypt_method.get() != 0 {
+            return Err(OpenError::Encrypted);
+        }
+        let incompatible = header.incompatible_features.get();
+        if incompatible & !format::INCOMPAT_SUPPORTED != 0 {
+            return Err(OpenError::UnsupportedFeatures(
+                incompatible & !format::INCOMPAT_SUPPORTED,
+            ));
+        }
+        if incompatible & format::INCOMPAT_COMPRESSION_TYPE != 0
+            && compression_type != format::COMPRESSION_TYPE_ZLIB
+        {
+            return Err(OpenError::UnsupportedCompression(compression_type));
+        }
+        if !read_only {
+            if incompatible & format::INCOMPAT_CORRUPT != 0 {
+                return Err(OpenError::Corrupt);
+            }
+            // The image was written with lazy refcounts and not closed
+            // cleanly.
+            if incompatible & format::INCOMPAT_DIRTY != 0 {
+                return Err(OpenError::Dirty);
+            }
+        }
+
+        let geo = Geometry {
+            version,
+            cluster_bits,
+            refcount_order,
+            disk_size: header.size.get(),
+            l1_table_offset: header.l1_table_offset.get(),
+        };
+        let cluster_mask = geo.cluster_size() - 1;
+        if geo.disk_size % 512 != 0 {
+            return Err(OpenError::InvalidDiskSize(geo.disk_size));
+        }
+
+        let l1_size = header.l1_size.get() as u64;
+        let file_len = file.metadata()?.len();
+        if l1_size < geo.l1_entries()
+            || geo.l1_entries() * 8 > MAX_L1_SIZE
+            || geo.l1_table_offset & cluster_mask != 0
+            || geo
+                .l1_table_offset
+                .checked_add(geo.l1_entries() * 8)
+                .is_none_or(|end| end > file_len)
+        {
+            return Err(OpenError::InvalidL1Table);
+        }
+        let mut l1 = vec![0u64; geo.l1_entries() as usize];
+        read_exact_at(&file, l1.as_mut_bytes(), geo.l1_table_offset)?;
+        for entry in &mut l1 {
+            *entry = u64::from_be(*entry);
+        }
+
+        let refcount_table_offset = header.refcount_table_offset.get();
+        let refcount_table_clusters = header.refcount_table_clusters.get() as u64;
+        if refcount_table_offset & cluster_mask != 0
+            || refcount_table_clusters == 0
+            || refcount_table_clusters << cluster_bits > 64 * 1024 * 1024
+        {
+            return Err(OpenError::InvalidRefcountTable);
+        }
+        let mut refcount_table = vec![0u64; (refcount_table_clusters << cluster_bits) as usize / 8];
+        read_exact_at(&file, refcount_table.as_mut_bytes(), refcount_table_offset)?;
+        for entry in &mut refcount_table {
+            *entry = u64::from_be(*entry) & format::REFCOUNT_TABLE_OFFSET_MASK;
+        }
+
+        let backing_file = Self::read_backing_file(&file, &header)?;
+
+        if !read_only && header.autoclear_features.get() != 0 {
+            // None of the autoclear features are supported, so they must be
+            // cleared to tell other tools that their data may be stale.
+            write_all_at(&file, &[0; 8], format::AUTOCLEAR_FEATURES_OFFSET)?;
+            file.sync_data()?;
+        }
+
+        let next_free = file_len.next_multiple_of(geo.cluster_size());
+        Ok(Self {
+            file: Arc::new(file),
+            geo,
+            read_only,
+            backing_file,
+            backing: None,
+            state: Arc::new(Mutex::new(State {
+                l1,
+                l2_tables: HashMap::new(),
+                refcount_table_offset,
+                refcount_table,
+                refcount_blocks: HashMap::new(),
+                next_free,
+                free_clusters: Vec::new(),
+                pending_free: Vec::new(),
+                in_flight: Arc::new(AtomicUsize::new(0)),
+            })),
+        })
+    }
+
+    fn read_backing_file(
+        file: &File,
+        header: &format::Header,
+    ) -> Result<Option<BackingFile>, OpenError> {
+        let offset = header.backing_file_offset.get();
+        if offset == 0 {
+            return Ok(None);
+        }
+        let size = header.backing_file_size.get();
+        if size == 0 || size > format::MAX_BACKING_FILE_SIZE {
+            return Err(OpenError::InvalidBackingFile);
+        }
+        let mut name = vec![0; size as usize];
+        read_exact_at(file, &mut name, offset)?;
+        let name = String::from_utf8(name).map_err(|_| OpenError::InvalidBackingFile)?;
+
+        // Find the backing format in the header extensions.
+        let mut format = None;
+        let mut offset = header.header_length.get() as u64;
+        loop {
+            let mut ext = format::HeaderExtension::new_zeroed();
+            read_exact_at(file, ext.as_mut_bytes(), offset)?;
+            offset += size_of::<format::HeaderExtension>() as u64;
+            let len = ext.length.get() as u64;
+            match ext.extension_type.get() {
+                format::EXTENSION_END => break,
+                format::EXTENSION_BACKING_FORMAT => {
+                    if len > format::MAX_BACKING_FILE_SIZE as u64 {
+                        return Err(OpenError::InvalidHeaderExtension);
+                    }
+                    let mut data = vec![0; len as usize];
+                    read_exact_at(file, &mut data, offset)?;
+                    format = Some(
+                        String::from_utf8(data).map_err(|_| OpenError::InvalidHeaderExtension)?,
+                    );
+                }
+                _ => {}
+            }
+            offset += len.next_multiple_of(8);
+            if offset >= 1 << header.cluster_bits.get() {
+                return Err(OpenError::InvalidHeaderExtension);
+            }
+        }
+
+        Ok(Some(BackingFile { name, format }))
+    }
+
+    /// Opens the qcow2 image at `path` along with its backing file chain, as
+    /// a layered disk.
+    ///
+    /// Backing files that are qcow2 images become additional layers. A raw
+    /// backing file ends the chain and becomes the bottom layer. Only the top
+    /// image is opened for write, and only if `read_only` is false.
+    pub fn open_chain(path: &Path, read_only: bool) -> Result<LayeredDiskHandle, OpenError> {
+        // The format of the next file in the chain, if known.
+        let mut next_format = Some("qcow2".to_owned());
+        let mut raw_backing = false;
+        let mut files = disk_image_layer::open_chain(
+            path,
+            read_only,
+            || OpenError::ChainTooDeep,
+            |path, file, read_only| {
+                let is_qcow2 = match next_format.take().as_deref() {
+                    Some("qcow2") => true,
+                    Some("raw") => false,
+                    Some(format) => return Err(OpenError::UnsupportedBackingFormat(format.into())),
+                    None => {
+                        // Probe the format, as QEMU does.
+                        let mut magic = [0; 4];
+                        match read_exact_at(&file, &mut magic, 0) {
+                            Ok(()) => u32::from_be_bytes(magic) == format::MAGIC,
+                            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => false,
+                            Err(err) => return Err(err.into()),
+                        }
+                    }
+                };
+                if !is_qcow2 {
+                    raw_backing = true;
+                    return Ok((file, None));
+                }
+
+                let layer = Self::open(file, read_only)?;
+                let backing = layer.backing_file.clone();
+                let file = layer.into_inner();
+                let Some(backing) = backing else {
+                    return Ok((file, None));
+                };
+                // Relative backing file names are relative to the image.
+                let path = path.parent().unwrap_or(Path::new("")).join(&backing.name);
+                let backing_file =
+                    File::open(&path).map_err(|err| OpenError::BackingFile(path.clone(), err))?;
+                next_format = backing.format;
+                Ok((file, Some((path, backing_file))))
+            },
+        )?;
+        let raw_backing = if raw_backing { files.pop() } else { None };
+
+        // Give the top layer its own view of the backing chain so that
+        // partial writes to unallocated clusters can copy the rest of the
+        // cluster.
+        let backing = if !read_only && (files.len() > 1 || raw_backing.is_some()) {
+            let files = files[1..]
+                .iter()
+                .map(File::try_clone)
+                .collect::<Result<Vec<_>, _>>()?;
+            let raw_backing = raw_backing.as_ref().map(File::try_clone).transpose()?;
+            Some(Resource::new(chain_handle(files, raw_backing, true, None)))
+        } else {
+            None
+        };
+        Ok(chain_handle(files, raw_backing, read_only, backing))
+    }
+
+    /// Drops the parsing state, returning the file handle.
+    pub fn into_inner(self) -> File {
+        disk_image_layer::into_file(self.file)
+    }
+
+    /// Returns whether the image has a backing file.
+    pub fn has_backing_file(&self) -> bool {
+        self.backing_file.is_some()
+    }
+
+    /// Sets the disk that the rest of a newly allocated cluster is copied
+    /// from on a partial write to an unallocated cluster.
+    ///
+    /// This should be a read-only view of the layers below this one. Without
+    /// it, the rest of the cluster is zeroed.
+    pub fn set_backing(&mut self, backing: Disk) {
+        self.backing = Some(backing);
+    }
+
+    /// Turns an empty file into a qcow2 image of `disk_size` bytes.
+    pub fn create(file: &File, disk_size: u64) -> Result<(), OpenError> {
+        create(file, disk_size, None)
+    }
+
+    /// Turns an empty file into a qcow2 image backed by the image or raw disk
+    /// at `backing_path`.
+    ///
+    /// The backing file is referenced by its absolute path.
+    pub fn create_overlay(file: &File, backing_path: &Path) -> Result<(), OpenError> {
+        let backing_path = std::path::absolute(backing_path)?;
+        let backing = File::open(&backing_path)
+            .map_err(|err| OpenError::BackingFile(backing_path.clone(), err))?;
+        let (format, disk_size) = match Self::open(backing.try_clone()?, true) {
+            Ok(layer) => ("qcow2", layer.geo.disk_size),
+            Err(OpenError::InvalidMagic) => ("raw", backing.metadata()?.len()),
+            Err(err) => return Err(err),
+        };
+        let name = backing_path.to_str().ok_or(OpenError::InvalidBackingFile)?;
+        create(file, disk_size, Some((name, format)))
+    }
+
+    /// Runs `f` on the blocking thread pool with the metadata lock held.
+    ///
+    /// Data IO should not be done here, to avoid serializing it.
+    async fn run<T: 'static + Send>(
+        &self,
+        mut state: OwnedMutexGuard<State>,
+        f: impl 'static + Send + FnOnce(&mut State, &File, &Geometry) -> io::Result<T>,
+    ) -> Result<(OwnedMutexGuard<State>, T), DiskError> {
+        let file = self.file.clone();
+        let geo = self.geo;
+        let (state, r) = unblock(move || {
+            let r = f(&mut *state, &file, &geo);
+            (state, r)
+        })
+        .await;
+        Ok((state, r.map_err(DiskError::Io)?))
+    }
+
+    /// Looks up the mappings of `clusters`, holding the metadata lock only
+    /// for the lookup. The returned guard keeps clusters freed in the
+    /// meantime from being reused until the IO using the mappings is done.
+    async fn lookup(&self, clusters: Vec<u64>) -> Result<(Vec<Mapping>, InFlight), DiskError> {
+        let state = self.state.clone().lock_owned().await;
+        let (state, mappings) = self
+            .run(state, move |state, file, geo| {
+                clusters
+                    .into_iter()
+                    .map(|cluster| state.lookup(file, geo, cluster))
+                    .collect()
+            })
+            .await?;
+        Ok((mappings, InFlight::new(&state)))
+    }
+
+    /// Frees a cluster allocated for a write that failed before the cluster
+    /// was referenced. If this fails too, the cluster is only leaked.
+    async fn free_unreferenced(&self, host_offset: u64) {
+        let state = self.state.clone().lock_owned().await;
+        let _ = self
+            .run(state, move |state, file, geo| {
+                state.decref(file, geo, host_offset)
+            })
+            .await;
+    }
+
+    /// Runs `f` on the blocking thread pool without the metadata lock.
+    async fn unlocked<T: 'static + Send>(
+        &self,
+        f: impl 'static + Send + FnOnce(&File, &Geometry) -> io::Result<T>,
+    ) -> Result<T, DiskError> {
+        let file = self.file.clone();
+        let geo = self.geo;
+        unblock(move || f(&file, &geo)).await.map_err(DiskError::Io)
+    }
+
+    /// Returns the guest clusters touched by a request, along with the byte
+    /// range within each cluster and the byte offset of that range within the
+    /// request.
+    fn clusters(&self, offset: u64, len: u64) -> Vec<(u64, Range<u64>, usize)> {
+        let cluster_size = self.geo.cluster_size();
+        let end = offset + len;
+        let mut pos = offset;
+        let mut clusters = Vec::new();
+        while pos < end {
+            let cluster = pos >> self.geo.cluster_bits;
+            let start = pos & (cluster_size - 1);
+            let n = (cluster_size - start).min(end - pos);
+            clusters.push((cluster, start..start + n, (pos - offset) as usize));
+            pos += n;
+        }
+        clusters
+    }
+
+    /// Returns the number of bytes of `cluster` that are within the disk.
+    fn cluster_len(&self, cluster: u64) -> u64 {
+        self.geo
+            .cluster_size()
+            .min(self.geo.disk_size - (cluster << self.geo.cluster_bits))
+    }
+
+    fn check_range(&self, buffers: &RequestBuffers<'_>, sector: u64) -> Result<(), DiskError> {
+        disk_image_layer::check_range(buffers, sector, self.sector_count(), SECTOR_SHIFT)?;
+        Ok(())
+    }
+
+    /// Reads `len` bytes at `offset` from the backing disk, zero filling past
+    /// its end.
+    async fn read_backing(&self, offset: u64, len: usize) -> Result<Vec<u8>, DiskError> {
+        let mut data = vec![0; len];
+        let Some(backing) = &self.backing else {
+            return Ok(data);
+        };
+        let sector = offset >> SECTOR_SHIFT;
+        let count = (len as u64 >> SECTOR_SHIFT).min(backing.sector_count().saturating_sub(sector));
+        if count > 0 {
+            let len = (count << SECTOR_SHIFT) as usize;
+            let mem = GuestMemory::allocate(len);
+            backing
+                .read_vectored(
+                    &OwnedRequestBuffers::linear(0, len, true).buffer(&mem),
+                    sector,
+                )
+                .await?;
+            mem.read_at(0, &mut data[..len])
+                .map_err(|err| DiskError::Io(io::Error::other(err)))?;
+        }
+        Ok(data)
+    }
+}
+
+/// Returns a layered disk with a layer for each qcow2 image in `files` and
+/// for the raw backing file, if any. Only the top layer gets `backing`.
+fn chain_handle(
+    files: Vec<File>,
+    raw_backing: Option<File>,
+    read_only: bool,
+    mut backing: Option<Resource<DiskHandleKind>>,
+) -> LayeredDiskHandle {
+    let mut handle = disk_image_layer::layered_disk_handle(files, read_only, |file, read_only| {
+        Resource::new(Qcow2DiskLayerHandle {
+            file,
+            read_only,
+            backing: backing.take(),
+        })
+    });
+    if let Some(file) = raw_backing {
+        handle
+            .layers
+            .push(Resource::new(DiskLayerHandle(Resource::new(FileDiskHandle(file)))).into());
+    }
+    handle
+}
+
+/// Writes the metadata of a new image.
+///
+/// The image has a single cluster of header, a one cluster refcount table,
+/// a single refcount block, and the L1 table. `backing` is the backing file
+/// name and format.
+fn create(file: &File, disk_size: u64, backing: Option<(&str, &str)>) -> Result<(), OpenError> {
+    if disk_size == 0 || disk_size % 512 != 0 {
+        return Err(OpenError::InvalidDiskSize(disk_size));
+    }
+    let geo = Geometry {
+        version: 3,
+        cluster_bits: format::DEFAULT_CLUSTER_BITS,
+        refcount_order: format::DEFAULT_REFCOUNT_ORDER,
+        disk_size,
+        l1_table_offset: 3 << format::DEFAULT_CLUSTER_BITS,
+    };
+    let cluster_size = geo.cluster_size();
+    let l1_entries = geo.l1_entries();
+    if l1_entries * 8 > MAX_L1_SIZE {
+        return Err(OpenError::InvalidDiskSize(disk_size));
+    }
+    let l1_clusters = (l1_entries * 8).div_ceil(cluster_size);
+    let clusters = 3 + l1_clusters;
+    if clusters > 1 << geo.refcount_block_bits() {
+        return Err(OpenError::InvalidDiskSize(disk_size));
+    }
+
+    let mut header_cluster = vec![0; cluster_size as usize];
+    let mut header = format::Header::new_zeroed();
+    header.magic.set(format::MAGIC);
+    header.version.set(geo.version);
+    header.cluster_bits.set(geo.cluster_bits);
+    header.size.set(disk_size);
+    header.l1_size.set(l1_entries as u32);
+    header.l1_table_offset.set(geo.l1_table_offset);
+    header.refcount_table_offset.set(cluster_size);
+    header.refcount_table_clusters.set(1);
+    header.refcount_order.set(geo.refcount_order);
+    header.header_length.set(format::V3_HEADER_LENGTH as u32);
+
+    let mut offset = format::V3_HEADER_LENGTH;
+    if let Some((name, format)) = backing {
+        if name.is_empty() || name.len() > format::MAX_BACKING_FILE_SIZE as usize {
+            return Err(OpenError::InvalidBackingFile);
+        }
+        let mut ext = format::HeaderExtension::new_zeroed();
+        ext.extension_type.set(format::EXTENSION_BACKING_FORMAT);
+        ext.length.set(format.len() as u32);
+        header_cluster[offset..offset + 8].copy_from_slice(ext.as_bytes());
+        offset += 8;
+        header_cluster[offset..offset + format.len()].copy_from_slice(format.as_bytes());
+        offset += format.len().next_multiple_of(8);
+        // Leave an end extension of zeroes.
+        offset += 8;
+        header_cluster[offset..offset + name.len()].copy_from_slice(name.as_bytes());
+        header.backing_file_offset.set(offset as u64);
+        header.backing_file_size.set(name.len() as u32);
+    }
+    header_cluster[..format::V3_HEADER_LENGTH].copy_from_slice(header.as_bytes());
+
+    let mut refcount_table = vec![0; cluster_size as usize];
+    refcount_table[..8].copy_from_slice(&(2 * cluster_size).to_be_bytes());
+    let mut refcount_block = vec![0; cluster_size as usize];
+    for i in 0..clusters {
+        put_refcount(&mut refcount_block, geo.refcount_order, i, 1)?;
+    }
+
+    file.set_len(0)?;
+    file.set_len(clusters * cluster_size)?;
+    write_all_at(file, &refcount_table, cluster_size)?;
+    write_all_at(file, &refcount_block, 2 * cluster_size)?;
+    write_all_at(file, &header_cluster, 0)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+impl LayerIo for Qcow2Layer {
+    fn layer_type(&self) -> &str {
+        "qcow2"
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.geo.disk_size >> SECTOR_SHIFT
+    }
+
+    fn sector_size(&self) -> u32 {
+        1 << SECTOR_SHIFT
+    }
+
+    fn disk_id(&self) -> Option<[u8; 16]> {
+        None
+    }
+
+    fn physical_sector_size(&self) -> u32 {
+        4096
+    }
+
+    fn is_fua_respected(&self) -> bool {
+        false
+    }
+
+    fn is_logically_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    async fn sync_cache(&self) -> Result<(), DiskError> {
+        let file = self.file.clone();
+        unblock(move || file.sync_all())
+            .await
+            .map_err(DiskError::Io)
+    }
+
+    async fn read(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        mut marker: SectorMarker<'_>,
+    ) -> Result<(), DiskError> {
+        self.check_range(buffers, sector)?;
+        let clusters = self.clusters(sector << SECTOR_SHIFT, buffers.len() as u64);
+        let (mappings, io) = self
+            .lookup(clusters.iter().map(|&(cluster, _, _)| cluster).collect())
+            .await?;
+        let has_backing_file = self.has_backing_file();
+        let (data, present) = self
+            .unlocked(move |file, geo| {
+                let len = clusters.last().map_or(0, |(_, range, offset)| {
+                    offset + (range.end - range.start) as usize
+                });
+                let mut data = vec![0; len];
+                let mut present = Vec::new();
+                let mut compressed = vec![0; geo.cluster_size() as usize];
+                for ((_, range, offset), mapping) in clusters.into_iter().zip(mappings) {
+                    let buf = &mut data[offset..offset + (range.end - range.start) as usize];
+                    match mapping {
+                        Mapping::Unallocated if has_backing_file => continue,
+                        Mapping::Unallocated | Mapping::Zero { .. } => {}
+                        Mapping::Data { offset, .. } => {
+                            read_exact_at(file, buf, offset + range.start)?;
+                        }
+                        Mapping::Compressed { offset, len } => {
+                            let mut input = vec![0; len as usize];
+                            // The compressed data may end at the end of the
+                            // file, short of the sector count.
+                            let n = file.read_at(&mut input, offset)?;
+                            decompress(&input[..n], &mut compressed)?;
+                            buf.copy_from_slice(
+                                &compressed[range.start as usize..range.end as usize],
+                            );
+                        }
+                    }
+                    present.push(offset..offset + buf.len());
+                }
+                Ok((data, present))
+            })
+            .await?;
+        drop(io);
+
+        for range in present {
+            buffers
+                .subrange(range.start, range.len())
+                .writer()
+                .write(&data[range.clone()])?;
+            marker.set_range(
+                sector + (range.start >> SECTOR_SHIFT) as u64
+                    ..sector + (range.end >> SECTOR_SHIFT) as u64,
+            );
+        }
+        Ok(())
+    }
+
+    async fn write(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        _fua: bool,
+    ) -> Result<(), DiskError> {
+        if self.read_only {
+            return Err(DiskError::ReadOnly);
+        }
+        self.check_range(buffers, sector)?;
+        let data = Arc::new(buffers.reader().read_all()?);
+        let clusters = self.clusters(sector << SECTOR_SHIFT, buffers.len() as u64);
+
+        // Write clusters that are already allocated in place, and find the
+        // ones that need to be allocated.
+        let (mappings, io) = self
+            .lookup(clusters.iter().map(|&(cluster, _, _)| cluster).collect())
+            .await?;
+        let allocate = self
+            .unlocked({
+                let data = data.clone();
+                move |file, _geo| {
+                    let mut allocate = Vec::new();
+                    for ((cluster, range, offset), mapping) in clusters.into_iter().zip(mappings) {
+                        if !write_in_place(file, mapping, &range, &data[offset..])? {
+                            allocate.push((cluster, range, offset, mapping));
+                        }
+                    }
+                    Ok(allocate)
+                }
+            })
+            .await?;
+        drop(io);
+
+        for (cluster, range, offset, mut mapping) in allocate {
+            let cluster_len = self.cluster_len(cluster);
+            let partial = range.start != 0 || range.end < cluster_len;
+            loop {
+                let backing_data = match mapping {
+                    Mapping::Unallocated if partial => Some(
+                        self.read_backing(cluster << self.geo.cluster_bits, cluster_len as usize)
+                            .await?,
+                    ),
+                    _ => None,
+                };
+
+                // Build the new cluster contents and write them to a newly
+                // allocated cluster before referencing it from the L2 table.
+                // The old contents are read without protection against the
+                // old cluster being freed and reused, but in that case the
+                // mapping will have changed, and the write is retried.
+                let state = self.state.clone().lock_owned().await;
+                let (state, host_offset) = self
+                    .run(state, |state, file, geo| state.allocate(file, geo))
+                    .await?;
+                drop(state);
+                let write_range = range.clone();
+                let data = data.clone();
+                let write = self.unlocked(move |file, geo| {
+                    let mut cluster_data = match (backing_data, mapping) {
+                        (Some(backing_data), _) => backing_data,
+                        (None, Mapping::Data { offset, .. }) if partial => {
+                            let mut buf = vec![0; cluster_len as usize];
+                            read_exact_at(file, &mut buf, offset)?;
+                            buf
+                        }
+                        (None, Mapping::Compressed { offset, len }) if partial => {
+                            let mut input = vec![0; len as usize];
+                            let n = file.read_at(&mut input, offset)?;
+                            let mut buf = vec![0; geo.cluster_size() as usize];
+                            decompress(&input[..n], &mut buf)?;
+                            buf.truncate(cluster_len as usize);
+                            buf
+                        }
+                        _ => vec![0; cluster_len as usize],
+                    };
+                    let len = (write_range.end - write_range.start) as usize;
+                    cluster_data[write_range.start as usize..write_range.end as usize]
+                        .copy_from_slice(&data[offset..offset + len]);
+                    write_all_at(file, &cluster_data, host_offset)
+                });
+                if let Err(err) = write.await {
+                    self.free_unreferenced(host_offset).await;
+                    return Err(err);
+                }
+
+                let state = self.state.clone().lock_owned().await;
+                let (state, current) = self
+                    .run(state, move |state, file, geo| {
+                        let current = state.lookup(file, geo, cluster)?;
+                        if current != mapping {
+                            // A concurrent write or unmap changed the
+                            // cluster, so the new contents may be stale.
+                            state.decref(file, geo, host_offset)?;
+                            return Ok(Some(current));
+                        }
+                        if let Err(err) = state.set_l2_entry(
+                            file,
+                            geo,
+                            cluster,
+                            host_offset | format::OFLAG_COPIED,
+                        ) {
+                            // The new cluster is not referenced. If it cannot
+                            // be freed either, it is only leaked.
+                            let _ = state.decref(file, geo, host_offset);
+                            return Err(err);
+                        }
+                        state.release(file, geo, mapping)?;
+                        Ok(None)
+                    })
+                    .await?;
+                let Some(current) = current else {
+                    break;
+                };
+                mapping = current;
+                if matches!(mapping, Mapping::Data { copied: true, .. }) {
+                    // A concurrent write allocated the cluster, so write in
+                    // place.
+                    let io = InFlight::new(&state);
+                    drop(state);
+                    let data = data.clone();
+                    self.unlocked(move |file, _geo| {
+                        write_in_place(file, mapping, &range, &data[offset..])
+                    })
+                    .await?;
+                    drop(io);
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn unmap(
+        &self,
+        sector: u64,
+        count: u64,
+        _block_level_only: bool,
+        next_is_zero: bool,
+    ) -> Result<(), DiskError> {
+        if self.read_only {
+            return Err(DiskError::ReadOnly);
+        }
+        if sector
+            .checked_add(count)
+            .is_none_or(|end| end > self.sector_count())
+        {
+            return Err(DiskError::IllegalBlock);
+        }
+
+        // Unallocated clusters read from the backing file, so they can only be
+        // used if the backing file is known to be zero. Otherwise, use zero
+        // clusters, which are only available in v3 images.
+        let entry = if !self.has_backing_file() || next_is_zero {
+            0
+        } else if self.geo.version >= 3 {
+            format::OFLAG_ZERO
+        } else {
+            return Ok(());
+        };
+
+        // Only whole clusters can be unmapped.
+        let clusters = self
+            .clusters(sector << SECTOR_SHIFT, count << SECTOR_SHIFT)
+            .into_iter()
+            .filter(|(cluster, range, _)| {
+                range.start == 0 && range.end == self.cluster_len(*cluster)
+            })
+            .map(|(cluster, _, _)| cluster)
+            .collect::<Vec<_>>();
+        if clusters.is_empty() {
+            return Ok(());
+        }
+
+        let state = self.state.clone().lock_owned().await;
+        self.run(state, move |state, file, geo| {
+            for cluster in clusters {
+                let mapping = state.lookup(file, geo, cluster)?;
+                match (mapping, entry) {
+                    (Mapping::Unallocated, 0)
+                    | (Mapping::Zero { offset: 0 }, format::OFLAG_ZERO) => {
+                        continue;
+                    }
+                    _ => {}
+                }
+                state.set_l2_entry(file, geo, cluster, entry)?;
+                state.release(file, geo, mapping)?;
+            }
+            Ok(())
+        })
+        .await?;
+        Ok(())
+    }
+
+    fn unmap_behavior(&self) -> UnmapBehavior {
+        UnmapBehavior::Unspecified
+    }
+
+    fn optimal_unmap_sectors(&self) -> u32 {
+        (self.geo.cluster_size() >> SECTOR_SHIFT) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Qcow2Layer;
+    use super::format;
+    use super::get_refcount;
+    use super::put_refcount;
+    use crate::resolver::Qcow2LayerResolver;
+    use disk_backend::Disk;
+    use disk_backend::resolve::ResolveDiskParameters;
+    use disk_backend::resolve::ResolvedDisk;
+    use disk_backend_resources::FileDiskHandle;
+    use disk_backend_resources::LayeredDiskHandle;
+    use disk_backend_resources::layer::DiskLayerHandle;
+    use disk_backend_resources::layer::Qcow2DiskLayerHandle;
+    use disk_file::FileDiskResolver;
+    use disk_image_layer::test_helpers::create;
+    use disk_image_layer::test_helpers::read_sectors;
+    use disk_image_layer::test_helpers::write_sectors;
+    use disk_layered::resolver::LayeredDiskResolver;
+    use guestmem::GuestMemory;
+    use pal_async::DefaultDriver;
+    use pal_async::async_test;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Path;
+    use vm_resource::Resource;
+    use vm_resource::ResourceResolver;
+    use vm_resource::kind::DiskHandleKind;
+    use vm_resource::kind::DiskLayerHandleKind;
+    use vmcore::vm_task::SingleDriverBackend;
+    use vmcore::vm_task::VmTaskDriverSource;
+    use zerocopy::IntoBytes;
+
+    const CLUSTER_SIZE: u64 = 1 << format::DEFAULT_CLUSTER_BITS;
+
+    async fn open(driver: &DefaultDriver, path: &Path, read_only: bool) -> Disk {
+        let mut resolver = ResourceResolver::new();
+        resolver.add_async_resolver::<DiskHandleKind, _, LayeredDiskHandle, _>(LayeredDiskResolver);
+        resolver
+            .add_async_resolver::<DiskLayerHandleKind, _, DiskLayerHandle, _>(LayeredDiskResolver);
+        resolver.add_async_resolver::<DiskLayerHandleKind, _, Qcow2DiskLayerHandle, _>(
+            Qcow2LayerResolver,
+        );
+        resolver.add_resolver::<DiskHandleKind, _, FileDiskHandle, _>(FileDiskResolver);
+        let handle = Qcow2Layer::open_chain(path, read_only).unwrap();
+        let disk: ResolvedDisk = resolver
+            .resolve(
+                Resource::new(handle),
+                ResolveDiskParameters {
+                    read_only,
+                    driver_source: &VmTaskDriverSource::new(SingleDriverBackend::new(
+                        driver.clone(),
+                    )),
+                },
+            )
+            .await
+            .unwrap();
+        disk.0
+    }
+
+    #[test]
+    fn refcount_widths() {
+        let mut block = vec![0; 64];
+        for order in 0..=6 {
+            block.fill(0);
+            let max = if order == 6 {
+                !0
+            } else {
+                (1 << (1 << order)) - 1
+            };
+            put_refcount(&mut block, order, 3, max).unwrap();
+            put_refcount(&mut block, order, 4, 1).unwrap();
+            assert_eq!(get_refcount(&block, order, 2), 0);
+            assert_eq!(get_refcount(&block, order, 3), max);
+            assert_eq!(get_refcount(&block, order, 4), 1);
+            if order < 6 {
+                put_refcount(&mut block, order, 3, max + 1).unwrap_err();
+            }
+        }
+    }
+
+    #[async_test]
+    async fn read_write(driver: DefaultDriver) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        Qcow2Layer::create(&create(&path), 0x10000000).unwrap();
+        let mem = GuestMemory::allocate(0x10000);
+
+        // Write across a cluster boundary, so that two clusters are allocated.
+        let sector = CLUSTER_SIZE / 512 - 2;
+        let data = (0..4 * 128_u32).collect::<Vec<_>>();
+        {
+            let disk = open(&driver, &path, false).await;
+            assert_eq!(disk.sector_count(), 0x10000000 / 512);
+            assert!(
+                read_sectors(&disk, &mem, sector, 4)
+                    .await
+                    .iter()
+                    .all(|&x| x == 0)
+            );
+            write_sectors(&disk, &mem, sector, &data).await;
+            // Overwrite part of an allocated cluster in place.
+            write_sectors(&disk, &mem, sector + 3, &data[..128]).await;
+        }
+
+        let mut expected = data.clone();
+        expected[3 * 128..].copy_from_slice(&data[..128]);
+        let disk = open(&driver, &path, true).await;
+        assert_eq!(read_sectors(&disk, &mem, sector, 4).await, expected);
+        assert!(
+            read_sectors(&disk, &mem, 0, 4)
+                .await
+                .iter()
+                .all(|&x| x == 0)
+        );
+    }
+
+    #[async_test]
+    async fn concurrent_partial_writes(driver: DefaultDriver) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        Qcow2Layer::create(&create(&path), 0x1000000).unwrap();
+        let disk = open(&driver, &path, false).await;
+        let mem0 = GuestMemory::allocate(0x10000);
+        let mem1 = GuestMemory::allocate(0x10000);
+
+        // Both writes race to allocate the same cluster, and neither may be
+        // lost.
+        let data0 = vec![0x11111111_u32; 128];
+        let data1 = vec![0x22222222_u32; 128];
+        futures::join!(
+            write_sectors(&disk, &mem0, 0, &data0),
+            write_sectors(&disk, &mem1, 1, &data1),
+        );
+        let mut expected = data0;
+        expected.extend(&data1);
+        assert_eq!(read_sectors(&disk, &mem0, 0, 2).await, expected);
+    }
+
+    #[test]
+    fn l1_table_past_end_of_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        let file = create(&path);
+        Qcow2Layer::create(&file, 0x1000000).unwrap();
+
+        // Grow the disk without growing the L1 table.
+        let disk_size: u64 = 1 << 50;
+        let l1_size = (disk_size / (CLUSTER_SIZE * CLUSTER_SIZE / 8)) as u32;
+        disk_file::write_all_at(&file, &disk_size.to_be_bytes(), 24).unwrap();
+        disk_file::write_all_at(&file, &l1_size.to_be_bytes(), 36).unwrap();
+        assert!(matches!(
+            Qcow2Layer::open(file, true),
+            Err(super::OpenError::InvalidL1Table)
+        ));
+    }
+
+    #[async_test]
+    async fn overlay(driver: DefaultDriver) {
+        let dir = tempfile::tempdir().unwrap();
+        let mem = GuestMemory::allocate(0x10000);
+
+        // A raw base, a qcow2 middle layer, and a qcow2 top layer.
+        let base_path = dir.path().join("base.img");
+        let base_data = (0..0x100000_u32).collect::<Vec<_>>();
+        create(&base_path).write_all(base_data.as_bytes()).unwrap();
+
+        let middle_path = dir.path().join("middle.qcow2");
+        Qcow2Layer::create_overlay(&create(&middle_path), &base_path).unwrap();
+        let middle_data = (0..128_u32).map(|x| x | 0x80000000).collect::<Vec<_>>();
+        {
+            let disk = open(&driver, &middle_path, false).await;
+            write_sectors(&disk, &mem, 1, &middle_data).await;
+        }
+
+        let top_path = dir.path().join("top.qcow2");
+        Qcow2Layer::create_overlay(&create(&top_path), &middle_path).unwrap();
+        let handle = Qcow2Layer::open_chain(&top_path, true).unwrap();
+        assert_eq!(handle.layers.len(), 3);
+        drop(handle);
+
+        let top_data = (0..128_u32).map(|x| x | 0x40000000).collect::<Vec<_>>();
+        {
+            let disk = open(&driver, &top_path, false).await;
+            write_sectors(&disk, &mem, 3, &top_data).await;
+        }
+
+        // The rest of the top layer's partially written cluster is copied from
+        // the layers below.
+        let disk = open(&driver, &top_path, true).await;
+        let mut expected = base_data[..8 * 128].to_vec();
+        expected[128..256].copy_from_slice(&middle_data);
+        expected[3 * 128..4 * 128].copy_from_slice(&top_data);
+        assert_eq!(read_sectors(&disk, &mem, 0, 8).await, expected);
+        drop(disk);
+
+        // The top layer's allocated cluster is complete by itself.
+        let disk = disk_image_layer::test_helpers::open_layered(
+            vec![File::open(&top_path).unwrap()],
+            true,
+            |file, read_only| Qcow2Layer::open(file, read_only).unwrap(),
+        )
+        .await;
+        assert_eq!(read_sectors(&disk, &mem, 0, 8).await, expected);
+    }
+
+    #[async_test]
+    async fn unmap(driver: DefaultDriver) {
+        let dir = tempfile::tempdir().unwrap();
+        let mem = GuestMemory::allocate(0x10000);
+        let base_path = dir.path().join("base.qcow2");
+        Qcow2Layer::create(&create(&base_path), 0x1000000).unwrap();
+        let sectors_per_cluster = CLUSTER_SIZE / 512;
+        let data = vec![0x12345678_u32; 128];
+        {
+            let disk = open(&driver, &base_path, false).await;
+            for i in 0..4 {
+                write_sectors(&disk, &mem, i * sectors_per_cluster, &data).await;
+            }
+        }
+
+        let top_path = dir.path().join("top.qcow2");
+        Qcow2Layer::create_overlay(&create(&top_path), &base_path).unwrap();
+        let disk = open(&driver, &top_path, false).await;
+        write_sectors(&disk, &mem, 0, &data).await;
+        let len = File::open(&top_path).unwrap().metadata().unwrap().len();
+
+        // Unmapping whole clusters hides the backing file's data, while partial
+        // clusters are left alone.
+        disk.unmap(0, 3 * sectors_per_cluster - 1, false)
+            .await
+            .unwrap();
+        assert!(
+            read_sectors(&disk, &mem, 0, 1)
+                .await
+                .iter()
+                .all(|&x| x == 0)
+        );
+        assert!(
+            read_sectors(&disk, &mem, sectors_per_cluster, 1)
+                .await
+                .iter()
+                .all(|&x| x == 0)
+        );
+        assert_eq!(
+            read_sectors(&disk, &mem, 2 * sectors_per_cluster, 1).await,
+            data
+        );
+
+        // The freed cluster is reused.
+        write_sectors(&disk, &mem, 3 * sectors_per_cluster, &data).await;
+        assert_eq!(
+            File::open(&top_path).unwrap().metadata().unwrap().len(),
+            len
+        );
+    }
+}
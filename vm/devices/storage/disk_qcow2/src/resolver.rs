@@ -0,0 +1,54 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resolver implementation for qcow2 disk layers.
+
+use crate::Qcow2Layer;
+use async_trait::async_trait;
+use disk_backend::resolve::ResolveDiskParameters;
+use disk_backend_resources::layer::Qcow2DiskLayerHandle;
+use disk_layered::resolve::ResolveDiskLayerParameters;
+use disk_layered::resolve::ResolvedDiskLayer;
+use vm_resource::AsyncResolveResource;
+use vm_resource::ResourceResolver;
+use vm_resource::declare_static_async_resolver;
+use vm_resource::kind::DiskLayerHandleKind;
+
+/// A resolver for qcow2 disk layers.
+pub struct Qcow2LayerResolver;
+
+declare_static_async_resolver!(
+    Qcow2LayerResolver,
+    (DiskLayerHandleKind, Qcow2DiskLayerHandle)
+);
+
+#[async_trait]
+impl AsyncResolveResource<DiskLayerHandleKind, Qcow2DiskLayerHandle> for Qcow2LayerResolver {
+    type Output = ResolvedDiskLayer;
+    type Error = anyhow::Error;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        rsrc: Qcow2DiskLayerHandle,
+        input: ResolveDiskLayerParameters<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let read_only = rsrc.read_only || input.read_only;
+        let mut layer = Qcow2Layer::open(rsrc.file, read_only)?;
+        // The backing disk is only used to fill new clusters, so there is no
+        // need to open it for a read-only layer.
+        if let Some(backing) = rsrc.backing.filter(|_| !read_only) {
+            let backing = resolver
+                .resolve(
+                    backing,
+                    ResolveDiskParameters {
+                        read_only: true,
+                        driver_source: input.driver_source,
+                    },
+                )
+                .await?;
+            layer.set_backing(backing.0);
+        }
+        Ok(ResolvedDiskLayer::new(layer))
+    }
+}
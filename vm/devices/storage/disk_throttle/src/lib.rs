@@ -0,0 +1,296 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A disk wrapper that limits the rate of I/O to a disk, using token buckets
+//! for IOPS and bandwidth.
+//!
+//! This is useful for reproducing the behavior of a disk shared with noisy
+//! neighbors. The limits can be adjusted at runtime via `inspect`, by updating
+//! `read/iops`, `read/bytes_per_sec`, `write/iops`, and `write/bytes_per_sec`.
+//! A limit of zero means unlimited.
+
+#![forbid(unsafe_code)]
+
+pub mod resolver;
+
+use disk_backend::Disk;
+use disk_backend::DiskError;
+use disk_backend::DiskIo;
+use disk_backend::UnmapBehavior;
+use disk_backend_resources::ThrottleLimits;
+use inspect::Inspect;
+use pal_async::timer::PolledTimer;
+use parking_lot::Mutex;
+use scsi_buffers::RequestBuffers;
+use std::num::ParseIntError;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+use vmcore::vm_task::VmTaskDriver;
+use vmcore::vm_task::VmTaskDriverSource;
+
+/// A disk with IOPS and bandwidth limits on reads and writes.
+pub struct ThrottleDisk {
+    inner: Disk,
+    driver: VmTaskDriver,
+    read: Throttle,
+    write: Throttle,
+}
+
+impl ThrottleDisk {
+    /// Creates a new disk that limits the rate of I/O to `inner`.
+    pub fn new(inner: Disk, limits: ThrottleLimits, driver_source: &VmTaskDriverSource) -> Self {
+        let now = Instant::now();
+        Self {
+            inner,
+            driver: driver_source.simple(),
+            read: Throttle::new(limits.read_iops, limits.read_bytes_per_sec, now),
+            write: Throttle::new(limits.write_iops, limits.write_bytes_per_sec, now),
+        }
+    }
+
+    async fn throttle(&self, throttle: &Throttle, len: usize) {
+        if let Some(delay) = throttle.take(len as u64, Instant::now()) {
+            PolledTimer::new(&self.driver).sleep(delay).await;
+        }
+    }
+}
+
+impl Inspect for ThrottleDisk {
+    fn inspect(&self, req: inspect::Request<'_>) {
+        req.respond()
+            .field("inner", &self.inner)
+            .field("read", &self.read)
+            .field("write", &self.write);
+    }
+}
+
+/// The limits for one direction of I/O.
+struct Throttle {
+    iops: Bucket,
+    bytes: Bucket,
+    throttled: AtomicU64,
+}
+
+impl Throttle {
+    fn new(iops: Option<u64>, bytes_per_sec: Option<u64>, now: Instant) -> Self {
+        Self {
+            iops: Bucket::new(iops, now),
+            bytes: Bucket::new(bytes_per_sec, now),
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    /// Accounts for an I/O of `len` bytes, returning how long to wait before
+    /// issuing it, if at all.
+    fn take(&self, len: u64, now: Instant) -> Option<Duration> {
+        let delay = self.iops.take(1, now).max(self.bytes.take(len, now));
+        if delay.is_zero() {
+            return None;
+        }
+        self.throttled.fetch_add(1, Ordering::Relaxed);
+        Some(delay)
+    }
+}
+
+impl Inspect for Throttle {
+    fn inspect(&self, req: inspect::Request<'_>) {
+        req.respond()
+            .field_mut_with("iops", |v| self.iops.update(v))
+            .field_mut_with("bytes_per_sec", |v| self.bytes.update(v))
+            .counter("throttled", self.throttled.load(Ordering::Relaxed));
+    }
+}
+
+/// A token bucket.
+///
+/// Tokens accrue at `rate` per second, up to one second's worth. Callers may
+/// take more tokens than are available; the resulting debt delays subsequent
+/// callers as well, so that the long-term rate is respected even for requests
+/// larger than the bucket.
+struct Bucket(Mutex<BucketState>);
+
+struct BucketState {
+    /// Tokens per second, or zero for unlimited.
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: Option<u64>, now: Instant) -> Self {
+        let rate = rate.unwrap_or(0);
+        Self(Mutex::new(BucketState {
+            rate,
+            tokens: rate as f64,
+            last: now,
+        }))
+    }
+
+    /// Takes `n` tokens, returning how long the caller must wait for them to
+    /// accrue.
+    fn take(&self, n: u64, now: Instant) -> Duration {
+        let mut state = self.0.lock();
+        if state.rate == 0 {
+            return Duration::ZERO;
+        }
+        let rate = state.rate as f64;
+        let elapsed = now.saturating_duration_since(state.last).as_secs_f64();
+        state.last = state.last.max(now);
+        state.tokens = (state.tokens + elapsed * rate).min(rate) - n as f64;
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / rate)
+        }
+    }
+
+    fn update(&self, new: Option<&str>) -> Result<u64, ParseIntError> {
+        let mut state = self.0.lock();
+        if let Some(new) = new {
+            state.rate = new.parse()?;
+            // Drop any excess tokens, but keep any debt so that the new limit
+            // applies to I/O that has already been issued.
+            state.tokens = state.tokens.min(state.rate as f64);
+        }
+        Ok(state.rate)
+    }
+}
+
+impl DiskIo for ThrottleDisk {
+    fn disk_type(&self) -> &str {
+        "throttle"
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.inner.sector_count()
+    }
+
+    fn sector_size(&self) -> u32 {
+        self.inner.sector_size()
+    }
+
+    fn disk_id(&self) -> Option<[u8; 16]> {
+        self.inner.disk_id()
+    }
+
+    fn physical_sector_size(&self) -> u32 {
+        self.inner.physical_sector_size()
+    }
+
+    fn is_fua_respected(&self) -> bool {
+        self.inner.is_fua_respected()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+
+    fn pr(&self) -> Option<&dyn disk_backend::pr::PersistentReservation> {
+        self.inner.pr()
+    }
+
+    async fn read_vectored(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+    ) -> Result<(), DiskError> {
+        self.throttle(&self.read, buffers.len()).await;
+        self.inner.read_vectored(buffers, sector).await
+    }
+
+    async fn write_vectored(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        fua: bool,
+    ) -> Result<(), DiskError> {
+        self.throttle(&self.write, buffers.len()).await;
+        self.inner.write_vectored(buffers, sector, fua).await
+    }
+
+    fn sync_cache(&self) -> impl Future<Output = Result<(), DiskError>> + Send {
+        self.inner.sync_cache()
+    }
+
+    fn wait_resize(&self, sector_count: u64) -> impl Future<Output = u64> + Send {
+        self.inner.wait_resize(sector_count)
+    }
+
+    fn unmap(
+        &self,
+        sector: u64,
+        count: u64,
+        block_level_only: bool,
+    ) -> impl Future<Output = Result<(), DiskError>> + Send {
+        self.inner.unmap(sector, count, block_level_only)
+    }
+
+    fn unmap_behavior(&self) -> UnmapBehavior {
+        self.inner.unmap_behavior()
+    }
+
+    fn optimal_unmap_sectors(&self) -> u32 {
+        self.inner.optimal_unmap_sectors()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bucket;
+    use super::Throttle;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    #[test]
+    fn test_bucket() {
+        let now = Instant::now();
+        let bucket = Bucket::new(Some(10), now);
+
+        // The first second's worth of tokens is available immediately.
+        for _ in 0..10 {
+            assert_eq!(bucket.take(1, now), Duration::ZERO);
+        }
+        // After that, each token takes 100ms to accrue.
+        assert_eq!(bucket.take(1, now), Duration::from_millis(100));
+        assert_eq!(bucket.take(1, now), Duration::from_millis(200));
+
+        // Once the debt has been paid off, tokens accrue again.
+        let later = now + Duration::from_millis(300);
+        assert_eq!(bucket.take(1, later), Duration::ZERO);
+
+        // Tokens never accrue beyond one second's worth.
+        let much_later = later + Duration::from_secs(10);
+        assert_eq!(bucket.take(10, much_later), Duration::ZERO);
+        assert_eq!(bucket.take(1, much_later), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_bucket_update() {
+        let now = Instant::now();
+        let bucket = Bucket::new(None, now);
+        assert_eq!(bucket.take(1000, now), Duration::ZERO);
+
+        assert_eq!(bucket.update(Some("4")), Ok(4));
+        assert_eq!(bucket.take(1, now), Duration::from_millis(250));
+        assert!(bucket.update(Some("x")).is_err());
+        assert_eq!(bucket.update(None), Ok(4));
+
+        assert_eq!(bucket.update(Some("0")), Ok(0));
+        assert_eq!(bucket.take(1000, now), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_throttle() {
+        let now = Instant::now();
+        let throttle = Throttle::new(Some(1000), Some(1024 * 1024), now);
+
+        // Bandwidth is the limiting factor for large I/Os.
+        assert_eq!(throttle.take(1024 * 1024, now), None);
+        assert_eq!(
+            throttle.take(512 * 1024, now),
+            Some(Duration::from_millis(500))
+        );
+    }
+}
@@ -0,0 +1,38 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resource resolver for the throttled disk.
+
+use crate::ThrottleDisk;
+use async_trait::async_trait;
+use disk_backend::resolve::ResolveDiskParameters;
+use disk_backend::resolve::ResolvedDisk;
+use disk_backend_resources::ThrottleDiskHandle;
+use vm_resource::AsyncResolveResource;
+use vm_resource::ResourceResolver;
+use vm_resource::declare_static_async_resolver;
+use vm_resource::kind::DiskHandleKind;
+
+/// A resolver for [`ThrottleDisk`].
+pub struct ThrottleDiskResolver;
+declare_static_async_resolver!(ThrottleDiskResolver, (DiskHandleKind, ThrottleDiskHandle));
+
+#[async_trait]
+impl AsyncResolveResource<DiskHandleKind, ThrottleDiskHandle> for ThrottleDiskResolver {
+    type Output = ResolvedDisk;
+    type Error = anyhow::Error;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        rsrc: ThrottleDiskHandle,
+        input: ResolveDiskParameters<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let inner = resolver.resolve(rsrc.disk, input).await?;
+        Ok(ResolvedDisk::new(ThrottleDisk::new(
+            inner.0,
+            rsrc.limits,
+            input.driver_source,
+        ))?)
+    }
+}
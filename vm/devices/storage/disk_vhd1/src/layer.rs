@@ -0,0 +1,725 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A VHD1 disk layer, supporting fixed, dynamic, and differencing VHDs.
+//!
+//! Dynamic and differencing VHDs store the disk contents in fixed-size blocks,
+//! located via the block allocation table (BAT). Blocks are allocated at the
+//! end of the file the first time they are written. Each block is preceded by
+//! a sector bitmap; for differencing disks, sectors whose bit is clear are
+//! not present in this layer and are read from the parent instead.
+
+use crate::OpenError;
+use crate::validate_footer;
+use blocking::unblock;
+use disk_backend::DiskError;
+use disk_backend::UnmapBehavior;
+use disk_backend_resources::layer::Vhd1DiskLayerHandle;
+use disk_file::read_exact_at;
+use disk_file::write_all_at;
+use disk_image_layer::BitOrder;
+use disk_image_layer::bit_is_set;
+use disk_image_layer::set_bits;
+use disk_image_layer::set_runs;
+use disk_layered::LayerIo;
+use disk_layered::SectorMarker;
+use disk_layered::resolve::ResolveDiskLayerParameters;
+use disk_layered::resolve::ResolvedDiskLayer;
+use futures::lock::Mutex;
+use guestmem::MemoryRead;
+use guestmem::MemoryWrite;
+use guid::Guid;
+use inspect::Inspect;
+use scsi_buffers::RequestBuffers;
+use std::collections::HashMap;
+use std::fs::File;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use vhd1_defs::BAT_ENTRY_UNUSED;
+use vhd1_defs::ParentLocator;
+use vhd1_defs::VhdDynamicHeader;
+use vhd1_defs::VhdFooter;
+use vm_resource::ResolveResource;
+use vm_resource::declare_static_resolver;
+use vm_resource::kind::DiskLayerHandleKind;
+use zerocopy::FromZeros;
+use zerocopy::IntoBytes;
+
+const SECTOR_SIZE: u32 = 512;
+const SECTOR_SHIFT: u32 = SECTOR_SIZE.trailing_zeros();
+
+/// The maximum length of a parent locator's path, in bytes.
+const MAX_LOCATOR_LEN: u32 = 0x10000;
+
+pub struct Vhd1LayerResolver;
+declare_static_resolver!(
+    Vhd1LayerResolver,
+    (DiskLayerHandleKind, Vhd1DiskLayerHandle)
+);
+
+impl ResolveResource<DiskLayerHandleKind, Vhd1DiskLayerHandle> for Vhd1LayerResolver {
+    type Output = ResolvedDiskLayer;
+    type Error = OpenError;
+
+    fn resolve(
+        &self,
+        rsrc: Vhd1DiskLayerHandle,
+        input: ResolveDiskLayerParameters<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        Ok(ResolvedDiskLayer::new(Vhd1Layer::open(
+            rsrc.file,
+            rsrc.read_only || input.read_only,
+        )?))
+    }
+}
+
+/// A VHD1 file opened as a disk layer.
+#[derive(Inspect)]
+pub struct Vhd1Layer {
+    #[inspect(skip)]
+    file: Arc<File>,
+    #[inspect(debug)]
+    disk_type: DiskType,
+    disk_size: u64,
+    unique_id: Guid,
+    read_only: bool,
+    blocks: Option<Blocks>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum DiskType {
+    Fixed,
+    Dynamic,
+    Differencing,
+}
+
+/// The block state for dynamic and differencing disks.
+#[derive(Inspect)]
+struct Blocks {
+    #[inspect(skip)]
+    footer: VhdFooter,
+    #[inspect(skip)]
+    header: VhdDynamicHeader,
+    #[inspect(hex)]
+    table_offset: u64,
+    #[inspect(hex)]
+    block_size: u32,
+    #[inspect(hex)]
+    bitmap_size: u32,
+    #[inspect(skip)]
+    state: Mutex<BlockState>,
+}
+
+struct BlockState {
+    bat: Vec<u32>,
+    /// The offset of the footer at the end of the file, which is where the
+    /// next block will be allocated.
+    footer_offset: u64,
+    /// Sector bitmaps of differencing disk blocks, loaded on demand.
+    bitmaps: HashMap<u32, Box<[u8]>>,
+}
+
+impl Blocks {
+    fn sectors_per_block(&self) -> u64 {
+        (self.block_size >> SECTOR_SHIFT).into()
+    }
+
+    /// Returns the file offset of the sector bitmap for a BAT entry.
+    fn bitmap_offset(entry: u32) -> u64 {
+        (entry as u64) << SECTOR_SHIFT
+    }
+
+    /// Returns the file offset of the block data for a BAT entry.
+    fn data_offset(&self, entry: u32) -> u64 {
+        Self::bitmap_offset(entry) + self.bitmap_size as u64
+    }
+}
+
+/// How to find the parent of a differencing disk.
+struct Parent {
+    unique_id: Guid,
+    /// The parent's file name, for diagnostics.
+    name: String,
+    /// The candidate paths to the parent, in priority order.
+    paths: Vec<String>,
+}
+
+/// Decodes a UTF-16 string, stopping at the first null character.
+fn decode_utf16(data: &[u8], from_bytes: fn([u8; 2]) -> u16) -> Option<String> {
+    let chars = data
+        .chunks_exact(2)
+        .map(|c| from_bytes([c[0], c[1]]))
+        .take_while(|&c| c != 0);
+    char::decode_utf16(chars).collect::<Result<_, _>>().ok()
+}
+
+impl Vhd1Layer {
+    /// Opens a VHD1 file as a disk layer.
+    pub fn open(file: File, read_only: bool) -> Result<Self, OpenError> {
+        let len = file.metadata()?.len();
+        if len < VhdFooter::LEN {
+            return Err(OpenError::InvalidFileSize(len));
+        }
+        let footer_offset = len - VhdFooter::LEN;
+        let mut footer = VhdFooter::new_zeroed();
+        read_exact_at(&file, footer.as_mut_bytes(), footer_offset)?;
+        validate_footer(&footer)?;
+
+        let disk_size: u64 = footer.current_size.into();
+        if disk_size % SECTOR_SIZE as u64 != 0 {
+            return Err(OpenError::InvalidDiskSize(disk_size));
+        }
+
+        let disk_type = match footer.disk_type.get() {
+            VhdFooter::DISK_TYPE_FIXED => DiskType::Fixed,
+            VhdFooter::DISK_TYPE_DYNAMIC => DiskType::Dynamic,
+            VhdFooter::DISK_TYPE_DIFFERENCING => DiskType::Differencing,
+            disk_type => return Err(OpenError::UnsupportedDiskType(disk_type)),
+        };
+
+        let blocks = if disk_type == DiskType::Fixed {
+            if disk_size > footer_offset {
+                return Err(OpenError::InvalidDiskSize(disk_size));
+            }
+            None
+        } else {
+            Some(Self::open_blocks(&file, footer, disk_size, footer_offset)?)
+        };
+
+        Ok(Self {
+            file: Arc::new(file),
+            disk_type,
+            disk_size,
+            unique_id: footer.unique_id,
+            read_only,
+            blocks,
+        })
+    }
+
+    fn open_blocks(
+        file: &File,
+        footer: VhdFooter,
+        disk_size: u64,
+        footer_offset: u64,
+    ) -> Result<Blocks, OpenError> {
+        let header_offset: u64 = footer.data_offset.into();
+        if header_offset
+            .checked_add(VhdDynamicHeader::LEN)
+            .is_none_or(|end| end > footer_offset)
+        {
+            return Err(OpenError::InvalidFileSize(footer_offset + VhdFooter::LEN));
+        }
+        let mut header = VhdDynamicHeader::new_zeroed();
+        read_exact_at(file, header.as_mut_bytes(), header_offset)?;
+        if header.cookie != VhdDynamicHeader::COOKIE_MAGIC {
+            return Err(OpenError::InvalidHeaderCookie);
+        }
+        if header.checksum.get() != header.compute_checksum() {
+            return Err(OpenError::InvalidHeaderChecksum);
+        }
+        if header.header_version.get() != VhdDynamicHeader::HEADER_VERSION_MAGIC {
+            return Err(OpenError::UnsupportedHeaderVersion(
+                header.header_version.into(),
+            ));
+        }
+
+        let block_size: u32 = header.block_size.into();
+        if !block_size.is_power_of_two() || block_size < SECTOR_SIZE {
+            return Err(OpenError::InvalidBlockSize(block_size));
+        }
+        let entries: u32 = header.max_table_entries.into();
+        if disk_size.div_ceil(block_size as u64) > entries as u64 {
+            return Err(OpenError::InvalidDiskSize(disk_size));
+        }
+
+        // The table offset comes from the file, so the table's end may not
+        // fit in a `u64`.
+        let table_offset: u64 = header.table_offset.into();
+        if table_offset
+            .checked_add(entries as u64 * 4)
+            .is_none_or(|end| end > footer_offset)
+        {
+            return Err(OpenError::InvalidBlockTable);
+        }
+        let mut bat = vec![0u32; entries as usize];
+        read_exact_at(file, bat.as_mut_bytes(), table_offset)?;
+        for entry in &mut bat {
+            *entry = u32::from_be(*entry);
+        }
+
+        let bitmap_size = (block_size >> SECTOR_SHIFT)
+            .div_ceil(8)
+            .next_multiple_of(SECTOR_SIZE);
+
+        // Make sure all the allocated blocks are within the file.
+        for &entry in &bat {
+            if entry != BAT_ENTRY_UNUSED
+                && Blocks::bitmap_offset(entry) + bitmap_size as u64 + block_size as u64
+                    > footer_offset
+            {
+                return Err(OpenError::InvalidBlockTable);
+            }
+        }
+
+        Ok(Blocks {
+            footer,
+            header,
+            table_offset,
+            block_size,
+            bitmap_size,
+            state: Mutex::new(BlockState {
+                bat,
+                footer_offset: footer_offset.next_multiple_of(SECTOR_SIZE as u64),
+                bitmaps: HashMap::new(),
+            }),
+        })
+    }
+
+    /// Opens the VHD at `path` along with, if it is a differencing disk, its
+    /// chain of parents, found via their parent locators.
+    ///
+    /// The files are returned ordered from the child to the base disk, suitable
+    /// for building a layered disk. Only the child is opened for write, and
+    /// only if `read_only` is false.
+    pub fn open_chain(path: &Path, read_only: bool) -> Result<Vec<File>, OpenError> {
+        let mut parent_id = None;
+        disk_image_layer::open_chain(
+            path,
+            read_only,
+            || OpenError::ChainTooDeep,
+            |path, file, read_only| {
+                let layer = Self::open(file, read_only)?;
+                if parent_id.is_some_and(|id| id != layer.unique_id) {
+                    return Err(OpenError::ParentMismatch(path.to_owned()));
+                }
+                let parent = layer.parent()?;
+                let file = layer.into_inner();
+                let Some(parent) = parent else {
+                    return Ok((file, None));
+                };
+                let dir = path.parent().unwrap_or(Path::new(""));
+                let path = parent
+                    .paths
+                    .iter()
+                    .map(|name| disk_image_layer::locator_path(dir, name))
+                    .find(|path| path.exists())
+                    .ok_or(OpenError::ParentNotFound(parent.name))?;
+                parent_id = Some(parent.unique_id);
+                let parent_file = File::open(&path)?;
+                Ok((file, Some((path, parent_file))))
+            },
+        )
+    }
+
+    /// For differencing disks, returns how to find the parent disk.
+    fn parent(&self) -> Result<Option<Parent>, OpenError> {
+        let Some(blocks) = &self.blocks else {
+            return Ok(None);
+        };
+        if self.disk_type != DiskType::Differencing {
+            return Ok(None);
+        }
+
+        let mut relative = Vec::new();
+        let mut absolute = Vec::new();
+        for locator in &blocks.header.parent_locators {
+            let list = match locator.platform_code.get() {
+                ParentLocator::PLATFORM_CODE_W2RU => &mut relative,
+                ParentLocator::PLATFORM_CODE_W2KU => &mut absolute,
+                _ => continue,
+            };
+            let len = locator.platform_data_length.get();
+            if len > MAX_LOCATOR_LEN {
+                return Err(OpenError::InvalidParentLocator);
+            }
+            let mut data = vec![0; len as usize];
+            read_exact_at(&self.file, &mut data, locator.platform_data_offset.into())?;
+            let name =
+                decode_utf16(&data, u16::from_le_bytes).ok_or(OpenError::InvalidParentLocator)?;
+            list.push(name);
+        }
+
+        // Fall back to looking for the parent's file name next to the child,
+        // in case the disks were moved together.
+        let name = decode_utf16(&blocks.header.parent_unicode_name, u16::from_be_bytes)
+            .ok_or(OpenError::InvalidParentLocator)?;
+        let file_name = name.rsplit(['\\', '/']).next().unwrap_or_default();
+
+        let mut paths = relative;
+        paths.append(&mut absolute);
+        if !file_name.is_empty() {
+            paths.push(file_name.to_owned());
+        }
+        Ok(Some(Parent {
+            unique_id: blocks.header.parent_unique_id,
+            name,
+            paths,
+        }))
+    }
+
+    /// Drops the parsing state, returning the file handle.
+    pub fn into_inner(self) -> File {
+        disk_image_layer::into_file(self.file)
+    }
+
+    /// Turns an empty file into a dynamic VHD of `disk_size` bytes.
+    pub fn make_dynamic(file: &File, disk_size: u64) -> Result<(), OpenError> {
+        Self::make_sparse(
+            file,
+            VhdFooter::DISK_TYPE_DYNAMIC,
+            disk_size,
+            VhdDynamicHeader::new(0, 0, VhdDynamicHeader::DEFAULT_BLOCK_SIZE),
+            &[],
+        )
+    }
+
+    /// Turns an empty file into a differencing VHD whose parent is the VHD at
+    /// `parent_path`.
+    ///
+    /// The parent is located by its absolute path, or by its file name if the
+    /// disks are later moved to another directory together.
+    pub fn make_differencing(file: &File, parent_path: &Path) -> Result<(), OpenError> {
+        let parent_path = std::path::absolute(parent_path)?;
+        let parent = Self::open(File::open(&parent_path)?, true)?;
+
+        let mut header = VhdDynamicHeader::new(0, 0, VhdDynamicHeader::DEFAULT_BLOCK_SIZE);
+        header.parent_unique_id = parent.unique_id;
+        let file_name = parent_path
+            .file_name()
+            .map(|name| name.to_string_lossy().encode_utf16().collect::<Vec<_>>())
+            .unwrap_or_default();
+        for (dest, c) in header
+            .parent_unicode_name
+            .chunks_exact_mut(2)
+            .zip(file_name)
+        {
+            dest.copy_from_slice(&c.to_be_bytes());
+        }
+
+        Self::make_sparse(
+            file,
+            VhdFooter::DISK_TYPE_DIFFERENCING,
+            parent.disk_size,
+            header,
+            &[(
+                ParentLocator::PLATFORM_CODE_W2KU,
+                parent_path.to_string_lossy().into_owned(),
+            )],
+        )
+    }
+
+    /// Writes the metadata of a new dynamic or differencing VHD.
+    ///
+    /// The file is laid out as a copy of the footer, the dynamic header, the
+    /// BAT, the parent locator data, and the footer.
+    fn make_sparse(
+        file: &File,
+        disk_type: u32,
+        disk_size: u64,
+        mut header: VhdDynamicHeader,
+        locators: &[(u32, String)],
+    ) -> Result<(), OpenError> {
+        if disk_size % SECTOR_SIZE as u64 != 0 {
+            return Err(OpenError::InvalidDiskSize(disk_size));
+        }
+        let header_offset = VhdFooter::LEN;
+        let table_offset = header_offset + VhdDynamicHeader::LEN;
+        let entries = u32::try_from(disk_size.div_ceil(header.block_size.get() as u64))
+            .map_err(|_| OpenError::InvalidDiskSize(disk_size))?;
+        let table_len = (entries as u64 * 4).next_multiple_of(SECTOR_SIZE as u64);
+
+        let mut offset = table_offset + table_len;
+        let mut locator_data = Vec::new();
+        for (locator, (platform_code, path)) in header.parent_locators.iter_mut().zip(locators) {
+            let data = path
+                .encode_utf16()
+                .flat_map(|c| c.to_le_bytes())
+                .collect::<Vec<_>>();
+            let space = (data.len() as u64).next_multiple_of(SECTOR_SIZE as u64);
+            *locator = ParentLocator {
+                platform_code: (*platform_code).into(),
+                platform_data_space: (space as u32).into(),
+                platform_data_length: (data.len() as u32).into(),
+                reserved: 0.into(),
+                platform_data_offset: offset.into(),
+            };
+            locator_data.push((offset, data));
+            offset += space;
+        }
+
+        header.table_offset = table_offset.into();
+        header.max_table_entries = entries.into();
+        header.checksum = header.compute_checksum().into();
+
+        let footer =
+            VhdFooter::new_dynamic(disk_type, disk_size, header_offset, Guid::new_random());
+
+        file.set_len(0)?;
+        write_all_at(file, footer.as_bytes(), 0)?;
+        write_all_at(file, header.as_bytes(), header_offset)?;
+        write_all_at(file, &vec![0xff; table_len as usize], table_offset)?;
+        for (offset, data) in locator_data {
+            write_all_at(file, &data, offset)?;
+        }
+        file.set_len(offset)?;
+        write_all_at(file, footer.as_bytes(), offset)?;
+        Ok(())
+    }
+
+    async fn read_at(&self, offset: u64, len: usize) -> Result<Vec<u8>, DiskError> {
+        let file = self.file.clone();
+        unblock(move || {
+            let mut buf = vec![0; len];
+            read_exact_at(&file, &mut buf, offset)?;
+            Ok(buf)
+        })
+        .await
+        .map_err(DiskError::Io)
+    }
+
+    async fn write_at(&self, offset: u64, buf: Vec<u8>) -> Result<(), DiskError> {
+        let file = self.file.clone();
+        unblock(move || write_all_at(&file, &buf, offset))
+            .await
+            .map_err(DiskError::Io)
+    }
+
+    /// Returns the sector bitmap for an allocated block, loading it from the
+    /// file if necessary.
+    async fn bitmap<'a>(
+        &self,
+        blocks: &Blocks,
+        state: &'a mut BlockState,
+        block: u32,
+    ) -> Result<&'a mut [u8], DiskError> {
+        let entry = state.bat[block as usize];
+        if !state.bitmaps.contains_key(&block) {
+            let bitmap = self
+                .read_at(Blocks::bitmap_offset(entry), blocks.bitmap_size as usize)
+                .await?;
+            state.bitmaps.insert(block, bitmap.into());
+        }
+        Ok(state.bitmaps.get_mut(&block).unwrap())
+    }
+
+    /// Allocates a new block at the end of the file, returning its BAT entry.
+    async fn allocate_block(
+        &self,
+        blocks: &Blocks,
+        state: &mut BlockState,
+        block: u32,
+    ) -> Result<u32, DiskError> {
+        let offset = state.footer_offset;
+        let entry = u32::try_from(offset >> SECTOR_SHIFT).map_err(|_| DiskError::IllegalBlock)?;
+        let footer_offset = blocks.data_offset(entry) + blocks.block_size as u64;
+
+        // Extend the file first, so that the new block reads as zero, then
+        // move the footer past it.
+        let file = self.file.clone();
+        unblock(move || file.set_len(footer_offset + VhdFooter::LEN))
+            .await
+            .map_err(DiskError::Io)?;
+
+        // Sectors in a dynamic disk's block are always present. Sectors in a
+        // differencing disk's block are present only once written.
+        let fill = if self.disk_type == DiskType::Differencing {
+            0
+        } else {
+            0xff
+        };
+        let bitmap = vec![fill; blocks.bitmap_size as usize];
+        self.write_at(offset, bitmap.clone()).await?;
+        self.write_at(footer_offset, blocks.footer.as_bytes().to_vec())
+            .await?;
+
+        // Commit the block by updating the BAT.
+        self.write_at(
+            blocks.table_offset + block as u64 * 4,
+            entry.to_be_bytes().to_vec(),
+        )
+        .await?;
+
+        state.bat[block as usize] = entry;
+        state.footer_offset = footer_offset;
+        if self.disk_type == DiskType::Differencing {
+            state.bitmaps.insert(block, bitmap.into());
+        }
+        Ok(entry)
+    }
+
+    /// Splits a request into per-block ranges, as in
+    /// [`disk_image_layer::block_ranges`].
+    fn block_ranges(
+        blocks: &Blocks,
+        sector: u64,
+        count: u64,
+    ) -> impl Iterator<Item = (u32, Range<u64>, usize)> + use<> {
+        // The BAT has a `u32` number of entries, and the range has been
+        // checked against the disk size, which fits in the BAT.
+        disk_image_layer::block_ranges(sector, count, blocks.sectors_per_block(), SECTOR_SHIFT)
+            .map(|(block, range, offset)| (block as u32, range, offset))
+    }
+
+    fn check_range(&self, buffers: &RequestBuffers<'_>, sector: u64) -> Result<u64, DiskError> {
+        disk_image_layer::check_range(buffers, sector, self.sector_count(), SECTOR_SHIFT)
+    }
+}
+
+impl LayerIo for Vhd1Layer {
+    fn layer_type(&self) -> &str {
+        "vhd1"
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.disk_size >> SECTOR_SHIFT
+    }
+
+    fn sector_size(&self) -> u32 {
+        SECTOR_SIZE
+    }
+
+    fn disk_id(&self) -> Option<[u8; 16]> {
+        Some(self.unique_id.into())
+    }
+
+    fn physical_sector_size(&self) -> u32 {
+        SECTOR_SIZE
+    }
+
+    fn is_fua_respected(&self) -> bool {
+        false
+    }
+
+    fn is_logically_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    async fn sync_cache(&self) -> Result<(), DiskError> {
+        let file = self.file.clone();
+        unblock(move || file.sync_all())
+            .await
+            .map_err(DiskError::Io)
+    }
+
+    async fn read(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        mut marker: SectorMarker<'_>,
+    ) -> Result<(), DiskError> {
+        let count = self.check_range(buffers, sector)?;
+        let Some(blocks) = &self.blocks else {
+            let data = self.read_at(sector << SECTOR_SHIFT, buffers.len()).await?;
+            buffers.writer().write(&data)?;
+            marker.set_range(sector..sector + count);
+            return Ok(());
+        };
+
+        for (block, range, offset) in Self::block_ranges(blocks, sector, count) {
+            let block_base = sector + (offset >> SECTOR_SHIFT) as u64 - range.start;
+            let mut state = blocks.state.lock().await;
+            let entry = state.bat[block as usize];
+            if entry == BAT_ENTRY_UNUSED {
+                // Unallocated blocks of a dynamic disk read as zero. For a
+                // differencing disk, they are read from the parent.
+                if self.disk_type == DiskType::Dynamic {
+                    let len = ((range.end - range.start) << SECTOR_SHIFT) as usize;
+                    buffers.subrange(offset, len).writer().zero(len)?;
+                    marker.set_range(block_base + range.start..block_base + range.end);
+                }
+                continue;
+            }
+            let runs = if self.disk_type == DiskType::Differencing {
+                let bitmap = self.bitmap(blocks, &mut state, block).await?;
+                set_runs(bitmap, BitOrder::MsbFirst, range.clone())
+            } else {
+                vec![range.clone()]
+            };
+            drop(state);
+
+            for run in runs {
+                let len = ((run.end - run.start) << SECTOR_SHIFT) as usize;
+                let data = self
+                    .read_at(blocks.data_offset(entry) + (run.start << SECTOR_SHIFT), len)
+                    .await?;
+                buffers
+                    .subrange(
+                        offset + ((run.start - range.start) << SECTOR_SHIFT) as usize,
+                        len,
+                    )
+                    .writer()
+                    .write(&data)?;
+                marker.set_range(block_base + run.start..block_base + run.end);
+            }
+        }
+        Ok(())
+    }
+
+    async fn write(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        _fua: bool,
+    ) -> Result<(), DiskError> {
+        if self.read_only {
+            return Err(DiskError::ReadOnly);
+        }
+        let count = self.check_range(buffers, sector)?;
+        let Some(blocks) = &self.blocks else {
+            let mut data = vec![0; buffers.len()];
+            buffers.reader().read(&mut data)?;
+            return self.write_at(sector << SECTOR_SHIFT, data).await;
+        };
+
+        for (block, range, offset) in Self::block_ranges(blocks, sector, count) {
+            let len = ((range.end - range.start) << SECTOR_SHIFT) as usize;
+            let mut data = vec![0; len];
+            buffers.subrange(offset, len).reader().read(&mut data)?;
+
+            let entry = {
+                let mut state = blocks.state.lock().await;
+                match state.bat[block as usize] {
+                    BAT_ENTRY_UNUSED => self.allocate_block(blocks, &mut state, block).await?,
+                    entry => entry,
+                }
+            };
+
+            self.write_at(
+                blocks.data_offset(entry) + (range.start << SECTOR_SHIFT),
+                data,
+            )
+            .await?;
+
+            // Mark the sectors present only once the data has been written.
+            if self.disk_type == DiskType::Differencing {
+                let mut state = blocks.state.lock().await;
+                let bitmap = self.bitmap(blocks, &mut state, block).await?;
+                if range
+                    .clone()
+                    .all(|i| bit_is_set(bitmap, BitOrder::MsbFirst, i))
+                {
+                    continue;
+                }
+                set_bits(bitmap, BitOrder::MsbFirst, range);
+                let bitmap = bitmap.to_vec();
+                self.write_at(Blocks::bitmap_offset(entry), bitmap).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn unmap(
+        &self,
+        _sector: u64,
+        _count: u64,
+        _block_level_only: bool,
+        _next_is_zero: bool,
+    ) -> Result<(), DiskError> {
+        Ok(())
+    }
+
+    fn unmap_behavior(&self) -> UnmapBehavior {
+        UnmapBehavior::Ignored
+    }
+}
@@ -1,11 +1,20 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
-//! A VHD1 disk implementation. Currently only supports fixed VHD1.
+//! A VHD1 disk implementation.
+//!
+//! Fixed VHDs are supported directly as a disk via [`Vhd1Disk`]. Dynamic and
+//! differencing VHDs are supported as disk layers via [`Vhd1Layer`], with a
+//! differencing disk's parents forming the lower layers of a layered disk.
 
 #![expect(missing_docs)]
 #![forbid(unsafe_code)]
 
+mod layer;
+
+pub use layer::Vhd1Layer;
+pub use layer::Vhd1LayerResolver;
+
 use disk_backend::DiskError;
 use disk_backend::DiskIo;
 use disk_backend::resolve::ResolveDiskParameters;
@@ -20,6 +29,7 @@
 use std::io::Read;
 use std::io::Seek;
 use std::io::Write;
+use std::path::PathBuf;
 use thiserror::Error;
 use vhd1_defs::VhdFooter;
 use vm_resource::ResolveResource;
@@ -72,21 +82,26 @@ struct Metadata {
     unique_id: Guid,
 }
 
+/// Validates the fields of the footer common to all disk types.
+fn validate_footer(footer: &VhdFooter) -> Result<(), OpenError> {
+    if footer.cookie != VhdFooter::COOKIE_MAGIC {
+        return Err(OpenError::InvalidFooterCookie);
+    }
+    if footer.checksum != footer.compute_checksum().to_be_bytes() {
+        return Err(OpenError::InvalidFooterChecksum);
+    }
+    if footer.file_format_version != VhdFooter::FILE_FORMAT_VERSION_MAGIC.to_be_bytes() {
+        return Err(OpenError::UnsupportedVersion(
+            footer.file_format_version.into(),
+        ));
+    }
+    Ok(())
+}
+
 impl Metadata {
     /// Parses the essential metadata out of the footer.
     fn from_footer(footer: VhdFooter, file_size: u64) -> Result<Metadata, OpenError> {
-        if footer.cookie != VhdFooter::COOKIE_MAGIC {
-            return Err(OpenError::InvalidFooterCookie);
-        }
-        if footer.checksum != footer.compute_checksum().to_be_bytes() {
-            return Err(OpenError::InvalidFooterChecksum);
-        }
-        if footer.file_format_version != VhdFooter::FILE_FORMAT_VERSION_MAGIC.to_be_bytes() {
-            return Err(OpenError::UnsupportedVersion(
-                footer.file_format_version.into(),
-            ));
-        }
-        // FUTURE: support parsing non-fixed VHDs.
+        validate_footer(&footer)?;
         if footer.disk_type != VhdFooter::DISK_TYPE_FIXED.to_be_bytes() {
             return Err(OpenError::NotFixed);
         }
@@ -123,6 +138,26 @@ pub enum OpenError {
     UnsupportedVersion(u32),
     #[error("not a fixed VHD")]
     NotFixed,
+    #[error("unsupported VHD disk type: {0}")]
+    UnsupportedDiskType(u32),
+    #[error("invalid VHD dynamic header cookie")]
+    InvalidHeaderCookie,
+    #[error("invalid VHD dynamic header checksum")]
+    InvalidHeaderChecksum,
+    #[error("unsupported VHD dynamic header version: {0:#x}")]
+    UnsupportedHeaderVersion(u32),
+    #[error("invalid VHD block size: {0:#x}")]
+    InvalidBlockSize(u32),
+    #[error("invalid VHD block allocation table")]
+    InvalidBlockTable,
+    #[error("invalid VHD parent locator")]
+    InvalidParentLocator,
+    #[error("could not find parent VHD {0}")]
+    ParentNotFound(String),
+    #[error("parent VHD {0} does not match the differencing disk's parent ID")]
+    ParentMismatch(PathBuf),
+    #[error("too many VHDs in differencing chain")]
+    ChainTooDeep,
 }
 
 impl Vhd1Disk {
@@ -239,14 +274,30 @@ fn unmap_behavior(&self) -> disk_backend::UnmapBehavior {
 
 #[cfg(test)]
 mod tests {
+    use super::OpenError;
     use super::Vhd1Disk;
+    use super::Vhd1Layer;
     use disk_backend::Disk;
+    use disk_image_layer::test_helpers::create;
+    use disk_image_layer::test_helpers::read_sectors;
+    use disk_image_layer::test_helpers::write_sectors;
     use guestmem::GuestMemory;
     use pal_async::async_test;
     use scsi_buffers::OwnedRequestBuffers;
+    use std::fs::File;
     use std::io::Write;
+    use vhd1_defs::VhdDynamicHeader;
+    use vhd1_defs::VhdFooter;
+    use zerocopy::FromZeros;
     use zerocopy::IntoBytes;
 
+    async fn open_layered(files: Vec<File>) -> Disk {
+        disk_image_layer::test_helpers::open_layered(files, false, |file, read_only| {
+            Vhd1Layer::open(file, read_only).unwrap()
+        })
+        .await
+    }
+
     #[async_test]
     async fn open_fixed() {
         let mut file = tempfile::tempfile().unwrap();
@@ -267,4 +318,77 @@ async fn open_fixed() {
         mem.read_at(0, buf.as_mut_bytes()).unwrap();
         assert!(buf.iter().copied().eq(1000_u32 * 128..1001 * 128));
     }
+
+    #[async_test]
+    async fn dynamic() {
+        let file = tempfile::tempfile().unwrap();
+        Vhd1Layer::make_dynamic(&file, 0x1000000).unwrap();
+        let mem = GuestMemory::allocate(0x10000);
+
+        // Write across a block boundary, so that two blocks are allocated.
+        let sector = 0x200000 / 512 - 2;
+        let data = (0..4 * 128_u32).collect::<Vec<_>>();
+        {
+            let vhd = open_layered(vec![file.try_clone().unwrap()]).await;
+            assert!(
+                read_sectors(&vhd, &mem, sector, 4)
+                    .await
+                    .iter()
+                    .all(|&x| x == 0)
+            );
+            write_sectors(&vhd, &mem, sector, &data).await;
+        }
+
+        let vhd = open_layered(vec![file]).await;
+        assert_eq!(read_sectors(&vhd, &mem, sector, 4).await, data);
+        assert!(read_sectors(&vhd, &mem, 0, 4).await.iter().all(|&x| x == 0));
+    }
+
+    #[async_test]
+    async fn differencing() {
+        let dir = tempfile::tempdir().unwrap();
+        let mem = GuestMemory::allocate(0x10000);
+
+        let parent_path = dir.path().join("parent.vhd");
+        let parent = create(&parent_path);
+        Vhd1Layer::make_dynamic(&parent, 0x1000000).unwrap();
+        let parent_data = (0..8 * 128_u32).collect::<Vec<_>>();
+        {
+            let vhd = open_layered(vec![parent]).await;
+            write_sectors(&vhd, &mem, 0, &parent_data).await;
+        }
+
+        let child_path = dir.path().join("child.vhd");
+        let child = create(&child_path);
+        Vhd1Layer::make_differencing(&child, &parent_path).unwrap();
+        drop(child);
+
+        let files = Vhd1Layer::open_chain(&child_path, false).unwrap();
+        assert_eq!(files.len(), 2);
+        let vhd = open_layered(files).await;
+        let child_data = (0..2 * 128_u32).map(|x| !x).collect::<Vec<_>>();
+        write_sectors(&vhd, &mem, 3, &child_data).await;
+
+        // Sectors not written to the child are read from the parent.
+        let mut expected = parent_data.clone();
+        expected[3 * 128..5 * 128].copy_from_slice(&child_data);
+        assert_eq!(read_sectors(&vhd, &mem, 0, 8).await, expected);
+    }
+
+    #[test]
+    fn block_table_past_end_of_file() {
+        let file = tempfile::tempfile().unwrap();
+        Vhd1Layer::make_dynamic(&file, 0x1000000).unwrap();
+
+        // Move the BAT so far out that its end overflows.
+        let mut header = VhdDynamicHeader::new_zeroed();
+        disk_file::read_exact_at(&file, header.as_mut_bytes(), VhdFooter::LEN).unwrap();
+        header.table_offset = (u64::MAX - 4).into();
+        header.checksum = header.compute_checksum().into();
+        disk_file::write_all_at(&file, header.as_bytes(), VhdFooter::LEN).unwrap();
+        assert!(matches!(
+            Vhd1Layer::open(file, true),
+            Err(OpenError::InvalidBlockTable)
+        ));
+    }
 }
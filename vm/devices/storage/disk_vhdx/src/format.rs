@@ -0,0 +1,277 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! VHDX file format definitions, from the MS-VHDX specification.
+
+use guid::Guid;
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+pub const KB: u64 = 1024;
+pub const MB: u64 = 1024 * KB;
+pub const TB: u64 = 1024 * 1024 * MB;
+
+pub const FILE_IDENTIFIER_OFFSET: u64 = 0;
+pub const HEADER_OFFSETS: [u64; 2] = [64 * KB, 128 * KB];
+pub const HEADER_SIZE: usize = 4096;
+pub const REGION_TABLE_OFFSETS: [u64; 2] = [192 * KB, 256 * KB];
+pub const REGION_TABLE_SIZE: usize = 64 * KB as usize;
+pub const METADATA_TABLE_SIZE: usize = 64 * KB as usize;
+
+/// The alignment of regions and payload blocks within the file.
+pub const REGION_ALIGNMENT: u64 = MB;
+
+/// The size of a sector bitmap block.
+pub const SECTOR_BITMAP_BLOCK_SIZE: u64 = MB;
+
+/// The number of sectors described by a sector bitmap block, which
+/// determines the BAT's chunk ratio.
+pub const SECTORS_PER_BITMAP_BLOCK: u64 = SECTOR_BITMAP_BLOCK_SIZE * 8;
+
+pub const MIN_BLOCK_SIZE: u32 = MB as u32;
+pub const MAX_BLOCK_SIZE: u32 = 256 * MB as u32;
+pub const DEFAULT_BLOCK_SIZE: u32 = 32 * MB as u32;
+pub const DEFAULT_LOG_LENGTH: u32 = MB as u32;
+
+/// The largest virtual disk size the format allows.
+pub const MAX_DISK_SIZE: u64 = 64 * TB;
+
+#[repr(C)]
+#[derive(Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct FileIdentifier {
+    pub signature: u64,
+    /// The creator application, in UTF-16LE.
+    pub creator: [u16; 256],
+}
+
+pub const FILE_IDENTIFIER_SIGNATURE: u64 = u64::from_le_bytes(*b"vhdxfile");
+
+#[repr(C)]
+#[derive(Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct Header {
+    pub signature: u32,
+    pub checksum: u32,
+    pub sequence_number: u64,
+    pub file_write_guid: Guid,
+    pub data_write_guid: Guid,
+    pub log_guid: Guid,
+    pub log_version: u16,
+    pub version: u16,
+    pub log_length: u32,
+    pub log_offset: u64,
+    pub reserved: [u8; 4016],
+}
+
+pub const HEADER_SIGNATURE: u32 = u32::from_le_bytes(*b"head");
+pub const HEADER_VERSION: u16 = 1;
+pub const LOG_VERSION: u16 = 0;
+
+#[repr(C)]
+#[derive(Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct RegionTableHeader {
+    pub signature: u32,
+    pub checksum: u32,
+    pub entry_count: u32,
+    pub reserved: u32,
+}
+
+pub const REGION_TABLE_SIGNATURE: u32 = u32::from_le_bytes(*b"regi");
+pub const REGION_TABLE_MAX_ENTRIES: u32 = 2047;
+
+#[repr(C)]
+#[derive(Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct RegionTableEntry {
+    pub guid: Guid,
+    pub file_offset: u64,
+    pub length: u32,
+    /// Bit 0 is set if the region must be understood to open the file.
+    pub required: u32,
+}
+
+pub const REGION_BAT: Guid = guid::guid!("2dc27766-f623-4200-9d64-115e9bfd4a08");
+pub const REGION_METADATA: Guid = guid::guid!("8b7ca206-4790-4b9a-b8fe-575f050f886e");
+
+#[repr(C)]
+#[derive(Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct MetadataTableHeader {
+    pub signature: u64,
+    pub reserved: u16,
+    pub entry_count: u16,
+    pub reserved2: [u32; 5],
+}
+
+pub const METADATA_TABLE_SIGNATURE: u64 = u64::from_le_bytes(*b"metadata");
+pub const METADATA_TABLE_MAX_ENTRIES: u16 = 2047;
+
+#[repr(C)]
+#[derive(Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct MetadataTableEntry {
+    pub item_id: Guid,
+    /// The offset of the item from the start of the metadata region.
+    pub offset: u32,
+    pub length: u32,
+    pub flags: u32,
+    pub reserved: u32,
+}
+
+pub const METADATA_FLAG_IS_VIRTUAL_DISK: u32 = 0x2;
+pub const METADATA_FLAG_IS_REQUIRED: u32 = 0x4;
+
+pub const METADATA_FILE_PARAMETERS: Guid = guid::guid!("caa16737-fa36-4d43-b3b6-33f0aa44e76b");
+pub const METADATA_VIRTUAL_DISK_SIZE: Guid = guid::guid!("2fa54224-cd1b-4876-b211-5dbed83bf4b8");
+pub const METADATA_VIRTUAL_DISK_ID: Guid = guid::guid!("beca12ab-b2e6-4523-93ef-c309e000c746");
+pub const METADATA_LOGICAL_SECTOR_SIZE: Guid = guid::guid!("8141bf1d-a96f-4709-ba47-f233a8faab5f");
+pub const METADATA_PHYSICAL_SECTOR_SIZE: Guid = guid::guid!("cda348c7-445d-4471-9cc9-e9885251c556");
+pub const METADATA_PARENT_LOCATOR: Guid = guid::guid!("a8d35f2d-b30b-454d-abf7-d3d84834ab0c");
+
+#[repr(C)]
+#[derive(Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct FileParameters {
+    pub block_size: u32,
+    pub flags: u32,
+}
+
+pub const FILE_PARAMETERS_HAS_PARENT: u32 = 0x2;
+
+#[repr(C)]
+#[derive(Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct ParentLocatorHeader {
+    pub locator_type: Guid,
+    pub reserved: u16,
+    pub key_value_count: u16,
+}
+
+pub const PARENT_LOCATOR_TYPE_VHDX: Guid = guid::guid!("b04aefb7-d19e-4a81-b789-25b8e9445913");
+
+/// A key-value entry in the parent locator. The offsets are relative to the
+/// start of the parent locator item, and the strings are UTF-16LE.
+#[repr(C)]
+#[derive(Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct ParentLocatorEntry {
+    pub key_offset: u32,
+    pub value_offset: u32,
+    pub key_length: u16,
+    pub value_length: u16,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct LogEntryHeader {
+    pub signature: u32,
+    pub checksum: u32,
+    pub entry_length: u32,
+    pub tail: u32,
+    pub sequence_number: u64,
+    pub descriptor_count: u32,
+    pub reserved: u32,
+    pub log_guid: Guid,
+    pub flushed_file_offset: u64,
+    pub last_file_offset: u64,
+}
+
+pub const LOG_ENTRY_SIGNATURE: u32 = u32::from_le_bytes(*b"loge");
+pub const LOG_SECTOR_SIZE: u64 = 4096;
+
+/// A log descriptor, either a zero descriptor or a data descriptor.
+#[repr(C)]
+#[derive(Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct LogDescriptor {
+    pub signature: u32,
+    /// For data descriptors, the last 4 bytes of the sector. Reserved for
+    /// zero descriptors.
+    pub trailing_bytes: [u8; 4],
+    /// For data descriptors, the first 8 bytes of the sector. For zero
+    /// descriptors, the length of the range to zero.
+    pub leading_bytes: [u8; 8],
+    pub file_offset: u64,
+    pub sequence_number: u64,
+}
+
+pub const LOG_ZERO_DESCRIPTOR_SIGNATURE: u32 = u32::from_le_bytes(*b"zero");
+pub const LOG_DATA_DESCRIPTOR_SIGNATURE: u32 = u32::from_le_bytes(*b"desc");
+
+#[repr(C)]
+#[derive(Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct LogDataSector {
+    pub signature: u32,
+    pub sequence_high: u32,
+    pub data: [u8; 4084],
+    pub sequence_low: u32,
+}
+
+pub const LOG_DATA_SECTOR_SIGNATURE: u32 = u32::from_le_bytes(*b"data");
+
+/// Payload block states, in the low 3 bits of a payload BAT entry.
+///
+/// The undefined (1), zero (2), and unmapped (3) states all read as zero.
+pub mod payload_state {
+    pub const NOT_PRESENT: u64 = 0;
+    pub const FULLY_PRESENT: u64 = 6;
+    pub const PARTIALLY_PRESENT: u64 = 7;
+}
+
+/// Sector bitmap block states, in the low 3 bits of a sector bitmap BAT entry.
+pub mod bitmap_state {
+    pub const PRESENT: u64 = 6;
+}
+
+const BAT_STATE_MASK: u64 = 0x7;
+const BAT_OFFSET_SHIFT: u32 = 20;
+
+/// Returns the state of a BAT entry.
+pub fn bat_state(entry: u64) -> u64 {
+    entry & BAT_STATE_MASK
+}
+
+/// Returns the file offset of the block referenced by a BAT entry.
+pub fn bat_offset(entry: u64) -> u64 {
+    (entry >> BAT_OFFSET_SHIFT) << BAT_OFFSET_SHIFT
+}
+
+/// Returns a BAT entry with the given state and file offset.
+pub fn bat_entry(state: u64, offset: u64) -> u64 {
+    debug_assert_eq!(offset % MB, 0);
+    offset | state
+}
+
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f63b78
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32c_update(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, &b| {
+        CRC32C_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Computes the CRC-32C checksum used by VHDX structures.
+pub fn crc32c(data: &[u8]) -> u32 {
+    !crc32c_update(!0, data)
+}
+
+/// Computes the checksum of a structure whose checksum field is at byte offset
+/// 4, as with headers, region tables, and log entries. The checksum field is
+/// treated as zero.
+pub fn compute_checksum(data: &[u8]) -> u32 {
+    let crc = crc32c_update(!0, &data[..4]);
+    let crc = crc32c_update(crc, &[0; 4]);
+    !crc32c_update(crc, &data[8..])
+}
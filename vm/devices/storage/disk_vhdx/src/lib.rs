@@ -0,0 +1,1310 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A user-mode VHDX disk implementation.
+//!
+//! VHDX files are opened as disk layers via [`VhdxLayer`], so that a
+//! differencing VHDX and its parents can be stacked into a
+//! [`LayeredDisk`](disk_layered::LayeredDisk). Fixed, dynamic, and
+//! differencing VHDXs are supported. Any pending metadata log entries are
+//! replayed when the file is opened.
+
+#![forbid(unsafe_code)]
+
+mod format;
+mod log;
+pub mod resolver;
+
+use blocking::unblock;
+use disk_backend::DiskError;
+use disk_backend::UnmapBehavior;
+use disk_file::read_exact_at;
+use disk_file::write_all_at;
+use disk_image_layer::BitOrder;
+use disk_image_layer::bit_is_set;
+use disk_image_layer::set_bits;
+use disk_image_layer::set_runs;
+use disk_layered::LayerIo;
+use disk_layered::SectorMarker;
+use futures::lock::Mutex;
+use guestmem::MemoryRead;
+use guestmem::MemoryWrite;
+use guid::Guid;
+use inspect::Inspect;
+use log::LogWrite;
+use log::LogWriter;
+use scsi_buffers::RequestBuffers;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use thiserror::Error;
+use zerocopy::FromBytes;
+use zerocopy::FromZeros;
+use zerocopy::IntoBytes;
+
+/// The largest metadata region that will be read into memory.
+const MAX_METADATA_REGION_SIZE: u32 = 64 * format::MB as u32;
+
+/// An error encountered while opening or creating a VHDX.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum OpenError {
+    /// An IO error occurred.
+    #[error("io error")]
+    Io(#[from] io::Error),
+    /// The file identifier is missing.
+    #[error("not a VHDX file")]
+    InvalidSignature,
+    /// Neither header is valid.
+    #[error("no valid VHDX header")]
+    NoValidHeader,
+    /// The header's version is not supported.
+    #[error("unsupported VHDX version: {0}")]
+    UnsupportedVersion(u16),
+    /// Neither region table is valid.
+    #[error("no valid VHDX region table")]
+    InvalidRegionTable,
+    /// A required region is not understood.
+    #[error("unsupported required VHDX region {0}")]
+    UnsupportedRegion(Guid),
+    /// The metadata region is invalid or missing a required item.
+    #[error("invalid VHDX metadata")]
+    InvalidMetadata,
+    /// A required metadata item is not understood.
+    #[error("unsupported required VHDX metadata item {0}")]
+    UnsupportedMetadata(Guid),
+    /// The block size is invalid.
+    #[error("invalid VHDX block size: {0:#x}")]
+    InvalidBlockSize(u32),
+    /// The sector size is invalid.
+    #[error("invalid VHDX sector size: {0}")]
+    InvalidSectorSize(u32),
+    /// The disk size is invalid.
+    #[error("invalid VHDX disk size: {0:#x}")]
+    InvalidDiskSize(u64),
+    /// The BAT is invalid.
+    #[error("invalid VHDX block allocation table")]
+    InvalidBat,
+    /// The log is invalid.
+    #[error("invalid VHDX log")]
+    InvalidLog,
+    /// The log must be replayed, but the file was opened read-only.
+    #[error("VHDX log must be replayed, but the file is read-only")]
+    LogReplayRequired,
+    /// The parent locator is invalid.
+    #[error("invalid VHDX parent locator")]
+    InvalidParentLocator,
+    /// The parent disk could not be found.
+    #[error("could not find parent VHDX")]
+    ParentNotFound,
+    /// The parent disk has been modified since the differencing disk was
+    /// created.
+    #[error("parent VHDX {0} does not match the differencing disk's parent linkage")]
+    ParentMismatch(PathBuf),
+    /// The differencing chain is too deep.
+    #[error("too many VHDXs in differencing chain")]
+    ChainTooDeep,
+}
+
+fn decode_utf16le(data: &[u8]) -> Option<String> {
+    let chars = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]));
+    char::decode_utf16(chars).collect::<Result<_, _>>().ok()
+}
+
+fn encode_utf16le(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(|c| c.to_le_bytes()).collect()
+}
+
+/// Returns the most recent valid header and its slot.
+fn read_header(file: &File) -> Result<(usize, format::Header), OpenError> {
+    let mut best: Option<(usize, format::Header)> = None;
+    for (slot, &offset) in format::HEADER_OFFSETS.iter().enumerate() {
+        let mut buf = vec![0; format::HEADER_SIZE];
+        read_exact_at(file, &mut buf, offset)?;
+        let header = format::Header::read_from_bytes(&buf).unwrap();
+        if header.signature != format::HEADER_SIGNATURE
+            || header.checksum != format::compute_checksum(&buf)
+        {
+            continue;
+        }
+        if best
+            .as_ref()
+            .is_none_or(|(_, best)| header.sequence_number > best.sequence_number)
+        {
+            best = Some((slot, header));
+        }
+    }
+    best.ok_or(OpenError::NoValidHeader)
+}
+
+/// Writes an updated header to the non-current slot, returning the new
+/// current slot.
+fn write_header(file: &File, slot: usize, header: &mut format::Header) -> io::Result<usize> {
+    header.sequence_number += 1;
+    header.checksum = 0;
+    header.checksum = format::crc32c(header.as_bytes());
+    let slot = 1 - slot;
+    write_all_at(file, header.as_bytes(), format::HEADER_OFFSETS[slot])?;
+    file.sync_data()?;
+    Ok(slot)
+}
+
+struct Regions {
+    bat: (u64, u32),
+    metadata: (u64, u32),
+}
+
+fn read_regions(file: &File) -> Result<Regions, OpenError> {
+    for offset in format::REGION_TABLE_OFFSETS {
+        let mut buf = vec![0; format::REGION_TABLE_SIZE];
+        read_exact_at(file, &mut buf, offset)?;
+        let (header, rest) = format::RegionTableHeader::read_from_prefix(&buf).unwrap();
+        if header.signature != format::REGION_TABLE_SIGNATURE
+            || header.checksum != format::compute_checksum(&buf)
+            || header.entry_count > format::REGION_TABLE_MAX_ENTRIES
+        {
+            continue;
+        }
+
+        let mut bat = None;
+        let mut metadata = None;
+        for entry in rest
+            .chunks_exact(size_of::<format::RegionTableEntry>())
+            .take(header.entry_count as usize)
+        {
+            let entry = format::RegionTableEntry::read_from_bytes(entry).unwrap();
+            let region = Some((entry.file_offset, entry.length));
+            if entry.file_offset % format::REGION_ALIGNMENT != 0 {
+                return Err(OpenError::InvalidRegionTable);
+            }
+            match entry.guid {
+                format::REGION_BAT => bat = region,
+                format::REGION_METADATA => metadata = region,
+                guid if entry.required & 1 != 0 => return Err(OpenError::UnsupportedRegion(guid)),
+                _ => {}
+            }
+        }
+        return Ok(Regions {
+            bat: bat.ok_or(OpenError::InvalidRegionTable)?,
+            metadata: metadata.ok_or(OpenError::InvalidRegionTable)?,
+        });
+    }
+    Err(OpenError::InvalidRegionTable)
+}
+
+/// The parsed metadata region.
+struct Metadata {
+    file_parameters: format::FileParameters,
+    disk_size: u64,
+    disk_id: Guid,
+    logical_sector_size: u32,
+    physical_sector_size: u32,
+    parent_locator: Option<HashMap<String, String>>,
+}
+
+fn read_metadata(file: &File, offset: u64, len: u32) -> Result<Metadata, OpenError> {
+    if (len as usize) < format::METADATA_TABLE_SIZE || len > MAX_METADATA_REGION_SIZE {
+        return Err(OpenError::InvalidMetadata);
+    }
+    let mut region = vec![0; len as usize];
+    read_exact_at(file, &mut region, offset)?;
+    let (header, rest) = format::MetadataTableHeader::read_from_prefix(&region).unwrap();
+    if header.signature != format::METADATA_TABLE_SIGNATURE
+        || header.entry_count > format::METADATA_TABLE_MAX_ENTRIES
+    {
+        return Err(OpenError::InvalidMetadata);
+    }
+
+    let mut file_parameters = None;
+    let mut disk_size = None;
+    let mut disk_id = None;
+    let mut logical_sector_size = None;
+    let mut physical_sector_size = None;
+    let mut parent_locator = None;
+    for entry in rest
+        .chunks_exact(size_of::<format::MetadataTableEntry>())
+        .take(header.entry_count as usize)
+    {
+        let entry = format::MetadataTableEntry::read_from_bytes(entry).unwrap();
+        let item = region
+            .get(entry.offset as usize..)
+            .and_then(|item| item.get(..entry.length as usize))
+            .ok_or(OpenError::InvalidMetadata)?;
+        let invalid = |_| OpenError::InvalidMetadata;
+        match entry.item_id {
+            format::METADATA_FILE_PARAMETERS => {
+                file_parameters = Some(
+                    format::FileParameters::read_from_prefix(item)
+                        .map_err(invalid)?
+                        .0,
+                );
+            }
+            format::METADATA_VIRTUAL_DISK_SIZE => {
+                disk_size = Some(u64::read_from_prefix(item).map_err(invalid)?.0);
+            }
+            format::METADATA_VIRTUAL_DISK_ID => {
+                disk_id = Some(Guid::read_from_prefix(item).map_err(invalid)?.0);
+            }
+            format::METADATA_LOGICAL_SECTOR_SIZE => {
+                logical_sector_size = Some(u32::read_from_prefix(item).map_err(invalid)?.0);
+            }
+            format::METADATA_PHYSICAL_SECTOR_SIZE => {
+                physical_sector_size = Some(u32::read_from_prefix(item).map_err(invalid)?.0);
+            }
+            format::METADATA_PARENT_LOCATOR => {
+                parent_locator =
+                    Some(parse_parent_locator(item).ok_or(OpenError::InvalidParentLocator)?);
+            }
+            guid if entry.flags & format::METADATA_FLAG_IS_REQUIRED != 0 => {
+                return Err(OpenError::UnsupportedMetadata(guid));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Metadata {
+        file_parameters: file_parameters.ok_or(OpenError::InvalidMetadata)?,
+        disk_size: disk_size.ok_or(OpenError::InvalidMetadata)?,
+        disk_id: disk_id.ok_or(OpenError::InvalidMetadata)?,
+        logical_sector_size: logical_sector_size.ok_or(OpenError::InvalidMetadata)?,
+        physical_sector_size: physical_sector_size.ok_or(OpenError::InvalidMetadata)?,
+        parent_locator,
+    })
+}
+
+fn parse_parent_locator(item: &[u8]) -> Option<HashMap<String, String>> {
+    let (header, rest) = format::ParentLocatorHeader::read_from_prefix(item).ok()?;
+    if header.locator_type != format::PARENT_LOCATOR_TYPE_VHDX {
+        return None;
+    }
+    let string =
+        |offset: u32, len: u16| decode_utf16le(item.get(offset as usize..)?.get(..len as usize)?);
+    let entries = rest
+        .chunks_exact(size_of::<format::ParentLocatorEntry>())
+        .take(header.key_value_count as usize)
+        .map(|entry| format::ParentLocatorEntry::read_from_bytes(entry).unwrap())
+        .collect::<Vec<_>>();
+    if entries.len() != header.key_value_count as usize {
+        return None;
+    }
+    entries
+        .into_iter()
+        .map(|entry| {
+            Some((
+                string(entry.key_offset, entry.key_length)?,
+                string(entry.value_offset, entry.value_length)?,
+            ))
+        })
+        .collect()
+}
+
+/// A VHDX file opened as a disk layer.
+#[derive(Inspect)]
+pub struct VhdxLayer {
+    #[inspect(skip)]
+    file: Arc<File>,
+    read_only: bool,
+    disk_size: u64,
+    #[inspect(hex)]
+    block_size: u32,
+    logical_sector_size: u32,
+    physical_sector_size: u32,
+    #[inspect(skip)]
+    sector_shift: u32,
+    disk_id: Guid,
+    data_write_guid: Guid,
+    has_parent: bool,
+    chunk_ratio: u64,
+    #[inspect(hex)]
+    bat_offset: u64,
+    #[inspect(skip)]
+    parent_locator: Option<HashMap<String, String>>,
+    #[inspect(skip)]
+    state: Mutex<State>,
+}
+
+struct State {
+    bat: Vec<u64>,
+    /// The portions of the sector bitmaps for partially present blocks,
+    /// loaded on demand.
+    bitmaps: HashMap<u64, Box<[u8]>>,
+    /// The current length of the file. New blocks are allocated here.
+    file_len: u64,
+    header: format::Header,
+    header_slot: usize,
+    /// The log writer, present once the header has been updated for the first
+    /// write.
+    log: Option<LogWriter>,
+}
+
+impl VhdxLayer {
+    /// Opens a VHDX file as a disk layer.
+    ///
+    /// If the file's log needs to be replayed, it is replayed now, unless
+    /// `read_only` is set, in which case opening fails.
+    pub fn open(file: File, read_only: bool) -> Result<Self, OpenError> {
+        let mut ident = format::FileIdentifier::new_zeroed();
+        read_exact_at(&file, ident.as_mut_bytes(), format::FILE_IDENTIFIER_OFFSET)?;
+        if ident.signature != format::FILE_IDENTIFIER_SIGNATURE {
+            return Err(OpenError::InvalidSignature);
+        }
+
+        let (mut header_slot, mut header) = read_header(&file)?;
+        if header.version != format::HEADER_VERSION {
+            return Err(OpenError::UnsupportedVersion(header.version));
+        }
+        if header.log_version != format::LOG_VERSION {
+            return Err(OpenError::InvalidLog);
+        }
+        if !header.log_guid.is_zero() {
+            log::replay(&file, &header, read_only)?;
+            if !read_only {
+                header.log_guid = Guid::ZERO;
+                header_slot = write_header(&file, header_slot, &mut header)?;
+            }
+        }
+
+        let regions = read_regions(&file)?;
+        let metadata = read_metadata(&file, regions.metadata.0, regions.metadata.1)?;
+
+        let block_size = metadata.file_parameters.block_size;
+        if !block_size.is_power_of_two()
+            || !(format::MIN_BLOCK_SIZE..=format::MAX_BLOCK_SIZE).contains(&block_size)
+        {
+            return Err(OpenError::InvalidBlockSize(block_size));
+        }
+        let logical_sector_size = metadata.logical_sector_size;
+        if logical_sector_size != 512 && logical_sector_size != 4096 {
+            return Err(OpenError::InvalidSectorSize(logical_sector_size));
+        }
+        let physical_sector_size = metadata.physical_sector_size;
+        if physical_sector_size != 512 && physical_sector_size != 4096 {
+            return Err(OpenError::InvalidSectorSize(physical_sector_size));
+        }
+        let disk_size = metadata.disk_size;
+        if disk_size == 0
+            || disk_size > format::MAX_DISK_SIZE
+            || disk_size % logical_sector_size as u64 != 0
+        {
+            return Err(OpenError::InvalidDiskSize(disk_size));
+        }
+        let has_parent = metadata.file_parameters.flags & format::FILE_PARAMETERS_HAS_PARENT != 0;
+        if has_parent != metadata.parent_locator.is_some() {
+            return Err(OpenError::InvalidParentLocator);
+        }
+
+        let chunk_ratio =
+            format::SECTORS_PER_BITMAP_BLOCK * logical_sector_size as u64 / block_size as u64;
+        let data_blocks = disk_size.div_ceil(block_size as u64);
+        let bat_entries = if has_parent {
+            data_blocks.div_ceil(chunk_ratio) * (chunk_ratio + 1)
+        } else {
+            data_blocks + (data_blocks - 1) / chunk_ratio
+        };
+        // Check the BAT against the file before allocating it, so that a
+        // corrupt header cannot force a huge allocation.
+        let (bat_offset, bat_len) = regions.bat;
+        let file_len = file.metadata()?.len();
+        if bat_entries * 8 > bat_len as u64
+            || bat_offset
+                .checked_add(bat_entries * 8)
+                .is_none_or(|end| end > file_len)
+        {
+            return Err(OpenError::InvalidBat);
+        }
+        let mut bat = vec![0u64; bat_entries as usize];
+        read_exact_at(&file, bat.as_mut_bytes(), bat_offset)?;
+        for entry in &mut bat {
+            *entry = u64::from_le(*entry);
+        }
+
+        for (i, &entry) in bat.iter().enumerate() {
+            let is_bitmap = i as u64 % (chunk_ratio + 1) == chunk_ratio;
+            let len = match (is_bitmap, format::bat_state(entry)) {
+                (false, format::payload_state::FULLY_PRESENT) => block_size as u64,
+                (false, format::payload_state::PARTIALLY_PRESENT) if has_parent => {
+                    block_size as u64
+                }
+                (false, format::payload_state::PARTIALLY_PRESENT) => {
+                    return Err(OpenError::InvalidBat);
+                }
+                (true, format::bitmap_state::PRESENT) => format::SECTOR_BITMAP_BLOCK_SIZE,
+                _ => continue,
+            };
+            if format::bat_offset(entry)
+                .checked_add(len)
+                .is_none_or(|end| end > file_len)
+            {
+                return Err(OpenError::InvalidBat);
+            }
+        }
+
+        Ok(Self {
+            file: Arc::new(file),
+            read_only,
+            disk_size,
+            block_size,
+            logical_sector_size,
+            physical_sector_size,
+            sector_shift: logical_sector_size.trailing_zeros(),
+            disk_id: metadata.disk_id,
+            data_write_guid: header.data_write_guid,
+            has_parent,
+            chunk_ratio,
+            bat_offset,
+            parent_locator: metadata.parent_locator,
+            state: Mutex::new(State {
+                bat,
+                bitmaps: HashMap::new(),
+                file_len,
+                header,
+                header_slot,
+                log: None,
+            }),
+        })
+    }
+
+    /// Opens the VHDX at `path` along with, if it is a differencing disk, its
+    /// chain of parents, found via their parent locators.
+    ///
+    /// The files are returned ordered from the child to the base disk, suitable
+    /// for building a layered disk. Only the child is opened for write, and
+    /// only if `read_only` is false.
+    pub fn open_chain(path: &Path, read_only: bool) -> Result<Vec<File>, OpenError> {
+        let mut linkage = Vec::new();
+        disk_image_layer::open_chain(
+            path,
+            read_only,
+            || OpenError::ChainTooDeep,
+            |path, file, read_only| {
+                let layer = Self::open(file, read_only)?;
+                if !linkage.is_empty() && !linkage.contains(&layer.data_write_guid) {
+                    return Err(OpenError::ParentMismatch(path.to_owned()));
+                }
+                let parent = layer.parent_locator.clone();
+                let file = layer.into_inner();
+                let Some(parent) = parent else {
+                    return Ok((file, None));
+                };
+
+                linkage = ["parent_linkage", "parent_linkage2"]
+                    .into_iter()
+                    .filter_map(|key| parent.get(key)?.parse::<Guid>().ok())
+                    .collect::<Vec<_>>();
+                if linkage.is_empty() {
+                    return Err(OpenError::InvalidParentLocator);
+                }
+
+                // Fall back to looking for the parent's file name next to the
+                // child, in case the disks were moved together.
+                let dir = path.parent().unwrap_or(Path::new(""));
+                let paths = ["relative_path", "volume_path", "absolute_win32_path"]
+                    .into_iter()
+                    .filter_map(|key| parent.get(key))
+                    .collect::<Vec<_>>();
+                let file_names = paths
+                    .iter()
+                    .filter_map(|path| path.rsplit(['\\', '/']).next())
+                    .map(str::to_owned)
+                    .collect::<Vec<_>>();
+                let path = paths
+                    .iter()
+                    .map(|name| disk_image_layer::locator_path(dir, name))
+                    .chain(file_names.iter().map(|name| dir.join(name)))
+                    .find(|path| path.is_file())
+                    .ok_or(OpenError::ParentNotFound)?;
+                let parent_file = File::open(&path)?;
+                Ok((file, Some((path, parent_file))))
+            },
+        )
+    }
+
+    /// Drops the parsing state, returning the file handle.
+    pub fn into_inner(self) -> File {
+        disk_image_layer::into_file(self.file)
+    }
+
+    /// Turns an empty file into a dynamic VHDX of `disk_size` bytes.
+    pub fn make_dynamic(file: &File, disk_size: u64) -> Result<(), OpenError> {
+        make_new(file, disk_size, 512, None)
+    }
+
+    /// Turns an empty file into a differencing VHDX whose parent is the VHDX
+    /// at `parent_path`.
+    ///
+    /// The parent is located by its absolute path, or by its file name if the
+    /// disks are later moved to another directory together.
+    pub fn make_differencing(file: &File, parent_path: &Path) -> Result<(), OpenError> {
+        let parent_path = std::path::absolute(parent_path)?;
+        let parent = Self::open(File::open(&parent_path)?, true)?;
+        make_new(
+            file,
+            parent.disk_size,
+            parent.logical_sector_size,
+            Some((
+                parent.data_write_guid,
+                parent_path.to_string_lossy().into_owned(),
+            )),
+        )
+    }
+
+    fn sectors_per_block(&self) -> u64 {
+        (self.block_size >> self.sector_shift) as u64
+    }
+
+    fn payload_index(&self, block: u64) -> usize {
+        (block + block / self.chunk_ratio) as usize
+    }
+
+    fn bitmap_index(&self, block: u64) -> usize {
+        let chunk = block / self.chunk_ratio;
+        (chunk * (self.chunk_ratio + 1) + self.chunk_ratio) as usize
+    }
+
+    /// Returns the file offset and length of the portion of the sector
+    /// bitmap describing `block`.
+    fn block_bitmap_range(&self, state: &State, block: u64) -> Result<(u64, usize), DiskError> {
+        let entry = state.bat[self.bitmap_index(block)];
+        if format::bat_state(entry) != format::bitmap_state::PRESENT {
+            return Err(DiskError::Io(io::Error::other(
+                "missing sector bitmap block",
+            )));
+        }
+        let len = self.sectors_per_block() / 8;
+        let offset = format::bat_offset(entry) + (block % self.chunk_ratio) * len;
+        Ok((offset, len as usize))
+    }
+
+    /// Returns the sector bitmap for a partially present block, loading it
+    /// from the file if necessary.
+    async fn block_bitmap<'a>(
+        &self,
+        state: &'a mut State,
+        block: u64,
+    ) -> Result<&'a mut [u8], DiskError> {
+        if !state.bitmaps.contains_key(&block) {
+            let (offset, len) = self.block_bitmap_range(state, block)?;
+            let bitmap = self
+                .with_file(move |file| {
+                    let mut buf = vec![0; len];
+                    read_exact_at(file, &mut buf, offset)?;
+                    Ok(buf)
+                })
+                .await?;
+            state.bitmaps.insert(block, bitmap.into());
+        }
+        Ok(state.bitmaps.get_mut(&block).unwrap())
+    }
+
+    async fn with_file<T: 'static + Send>(
+        &self,
+        f: impl 'static + Send + FnOnce(&File) -> io::Result<T>,
+    ) -> Result<T, DiskError> {
+        let file = self.file.clone();
+        unblock(move || f(&file)).await.map_err(DiskError::Io)
+    }
+
+    /// Updates the header before the first write, as required by the spec.
+    async fn prepare_for_write(&self, state: &mut State) -> Result<(), DiskError> {
+        if state.log.is_some() {
+            return Ok(());
+        }
+        let mut header = state.header;
+        header.file_write_guid = Guid::new_random();
+        header.data_write_guid = Guid::new_random();
+        header.log_guid = Guid::new_random();
+        let slot = state.header_slot;
+        let (slot, header) = self
+            .with_file(move |file| {
+                let slot = write_header(file, slot, &mut header)?;
+                Ok((slot, header))
+            })
+            .await?;
+        state.header = header;
+        state.header_slot = slot;
+        state.log = Some(LogWriter::new(
+            header.log_offset,
+            header.log_length,
+            header.log_guid,
+        ));
+        Ok(())
+    }
+
+    /// Returns the log write for the BAT sector containing entry `index`.
+    fn bat_write(&self, state: &State, index: usize) -> LogWrite {
+        const ENTRIES_PER_SECTOR: usize = format::LOG_SECTOR_SIZE as usize / 8;
+        let start = index / ENTRIES_PER_SECTOR * ENTRIES_PER_SECTOR;
+        let end = (start + ENTRIES_PER_SECTOR).min(state.bat.len());
+        let mut data = Box::new([0; format::LOG_SECTOR_SIZE as usize]);
+        for (dest, entry) in data.chunks_exact_mut(8).zip(&state.bat[start..end]) {
+            dest.copy_from_slice(&entry.to_le_bytes());
+        }
+        LogWrite::Data {
+            file_offset: self.bat_offset + start as u64 * 8,
+            data,
+        }
+    }
+
+    /// Returns the log writes for the sector bitmap of `block`, reading the
+    /// surrounding bitmap contents from the file.
+    async fn bitmap_writes(&self, state: &State, block: u64) -> Result<Vec<LogWrite>, DiskError> {
+        let (offset, len) = self.block_bitmap_range(state, block)?;
+        let bitmap = state.bitmaps[&block].clone();
+        self.with_file(move |file| {
+            let sector_size = format::LOG_SECTOR_SIZE;
+            let start = offset / sector_size * sector_size;
+            let end = (offset + len as u64).next_multiple_of(sector_size);
+            let mut data = vec![0; (end - start) as usize];
+            read_exact_at(file, &mut data, start)?;
+            let bitmap_start = (offset - start) as usize;
+            data[bitmap_start..bitmap_start + len].copy_from_slice(&bitmap);
+            Ok(data
+                .chunks_exact(sector_size as usize)
+                .enumerate()
+                .map(|(i, sector)| LogWrite::Data {
+                    file_offset: start + i as u64 * sector_size,
+                    data: Box::new(sector.try_into().unwrap()),
+                })
+                .collect())
+        })
+        .await
+    }
+
+    /// Logs and applies metadata updates.
+    async fn commit(&self, state: &mut State, writes: Vec<LogWrite>) -> Result<(), DiskError> {
+        let mut log = state.log.clone().expect("header updated for write");
+        let file_len = state.file_len;
+        let log = self
+            .with_file(move |file| {
+                log.log_and_apply(file, &writes, file_len)?;
+                Ok(log)
+            })
+            .await?;
+        state.log = Some(log);
+        Ok(())
+    }
+
+    /// Allocates `block` and writes `data` to the sectors `range` within it.
+    async fn allocate_block(
+        &self,
+        state: &mut State,
+        block: u64,
+        range: Range<u64>,
+        data: Vec<u8>,
+    ) -> Result<(), DiskError> {
+        let index = self.payload_index(block);
+        let block_sectors = self
+            .sectors_per_block()
+            .min((self.disk_size >> self.sector_shift) - block * self.sectors_per_block());
+
+        // A partial write to a block that is not present in a differencing
+        // disk must leave the rest of the block to be read from the parent.
+        let partial = self.has_parent
+            && format::bat_state(state.bat[index]) == format::payload_state::NOT_PRESENT
+            && range != (0..block_sectors);
+
+        let offset = state.file_len.next_multiple_of(format::REGION_ALIGNMENT);
+        let mut file_len = offset + self.block_size as u64;
+        let bitmap_index = self.bitmap_index(block);
+        let new_bitmap_block =
+            partial && format::bat_state(state.bat[bitmap_index]) != format::bitmap_state::PRESENT;
+        if new_bitmap_block {
+            file_len += format::SECTOR_BITMAP_BLOCK_SIZE;
+        }
+
+        // Extending the file zeroes the new blocks. Write the data before
+        // committing the metadata that references it.
+        let data_offset = offset + (range.start << self.sector_shift);
+        self.with_file(move |file| {
+            file.set_len(file_len)?;
+            write_all_at(file, &data, data_offset)
+        })
+        .await?;
+        state.file_len = file_len;
+
+        let mut writes = Vec::new();
+        if partial {
+            if new_bitmap_block {
+                state.bat[bitmap_index] = format::bat_entry(
+                    format::bitmap_state::PRESENT,
+                    offset + self.block_size as u64,
+                );
+                writes.push(self.bat_write(state, bitmap_index));
+            }
+            let mut bitmap = vec![0; (self.sectors_per_block() / 8) as usize];
+            set_bits(&mut bitmap, BitOrder::LsbFirst, range);
+            state.bitmaps.insert(block, bitmap.into());
+            writes.extend(self.bitmap_writes(state, block).await?);
+            state.bat[index] = format::bat_entry(format::payload_state::PARTIALLY_PRESENT, offset);
+        } else {
+            state.bat[index] = format::bat_entry(format::payload_state::FULLY_PRESENT, offset);
+        }
+        // The sector bitmap's BAT entry may be in the same BAT sector as the
+        // payload's, in which case this write supersedes the earlier one.
+        let bat_write = self.bat_write(state, index);
+        writes.retain(|w| w.file_offset() != bat_write.file_offset());
+        writes.push(bat_write);
+        self.commit(state, writes).await
+    }
+
+    /// Marks the sectors `range` of a partially present block as present.
+    async fn set_present(
+        &self,
+        state: &mut State,
+        block: u64,
+        range: Range<u64>,
+    ) -> Result<(), DiskError> {
+        let bitmap = self.block_bitmap(state, block).await?;
+        if range
+            .clone()
+            .all(|i| bit_is_set(bitmap, BitOrder::LsbFirst, i))
+        {
+            return Ok(());
+        }
+        set_bits(bitmap, BitOrder::LsbFirst, range);
+        let writes = self.bitmap_writes(state, block).await?;
+        self.commit(state, writes).await
+    }
+
+    /// Splits a request into per-block ranges, as in
+    /// [`disk_image_layer::block_ranges`].
+    fn block_ranges(
+        &self,
+        sector: u64,
+        count: u64,
+    ) -> impl Iterator<Item = (u64, Range<u64>, usize)> + use<> {
+        disk_image_layer::block_ranges(sector, count, self.sectors_per_block(), self.sector_shift)
+    }
+
+    fn check_range(&self, buffers: &RequestBuffers<'_>, sector: u64) -> Result<u64, DiskError> {
+        disk_image_layer::check_range(buffers, sector, self.sector_count(), self.sector_shift)
+    }
+}
+
+/// Writes the metadata of a new dynamic or differencing VHDX.
+///
+/// `parent` is the parent's data write GUID and path.
+fn make_new(
+    file: &File,
+    disk_size: u64,
+    logical_sector_size: u32,
+    parent: Option<(Guid, String)>,
+) -> Result<(), OpenError> {
+    if disk_size == 0
+        || disk_size > format::MAX_DISK_SIZE
+        || disk_size % logical_sector_size as u64 != 0
+    {
+        return Err(OpenError::InvalidDiskSize(disk_size));
+    }
+    let block_size = format::DEFAULT_BLOCK_SIZE;
+    let log_offset = format::MB;
+    let metadata_offset = 2 * format::MB;
+    let metadata_len = format::MB as u32;
+    let bat_offset = 3 * format::MB;
+
+    let chunk_ratio =
+        format::SECTORS_PER_BITMAP_BLOCK * logical_sector_size as u64 / block_size as u64;
+    let data_blocks = disk_size.div_ceil(block_size as u64);
+    let bat_entries = if parent.is_some() {
+        data_blocks.div_ceil(chunk_ratio) * (chunk_ratio + 1)
+    } else {
+        data_blocks + (data_blocks - 1) / chunk_ratio
+    };
+    let bat_len = (bat_entries * 8).next_multiple_of(format::MB);
+
+    let mut ident = format::FileIdentifier::new_zeroed();
+    ident.signature = format::FILE_IDENTIFIER_SIGNATURE;
+    for (dest, c) in ident.creator.iter_mut().zip("OpenVMM".encode_utf16()) {
+        *dest = c;
+    }
+
+    let mut header = format::Header {
+        signature: format::HEADER_SIGNATURE,
+        checksum: 0,
+        sequence_number: 0,
+        file_write_guid: Guid::new_random(),
+        data_write_guid: Guid::new_random(),
+        log_guid: Guid::ZERO,
+        log_version: format::LOG_VERSION,
+        version: format::HEADER_VERSION,
+        log_length: format::DEFAULT_LOG_LENGTH,
+        log_offset,
+        reserved: [0; 4016],
+    };
+
+    let mut region_table = vec![0; format::REGION_TABLE_SIZE];
+    format::RegionTableHeader {
+        signature: format::REGION_TABLE_SIGNATURE,
+        checksum: 0,
+        entry_count: 2,
+        reserved: 0,
+    }
+    .write_to_prefix(&mut region_table)
+    .unwrap();
+    for (i, (guid, file_offset, length)) in [
+        (format::REGION_BAT, bat_offset, bat_len as u32),
+        (format::REGION_METADATA, metadata_offset, metadata_len),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let offset =
+            size_of::<format::RegionTableHeader>() + i * size_of::<format::RegionTableEntry>();
+        format::RegionTableEntry {
+            guid,
+            file_offset,
+            length,
+            required: 1,
+        }
+        .write_to_prefix(&mut region_table[offset..])
+        .unwrap();
+    }
+    let checksum = format::crc32c(&region_table);
+    region_table[4..8].copy_from_slice(&checksum.to_le_bytes());
+
+    let required = format::METADATA_FLAG_IS_REQUIRED;
+    let virtual_disk = format::METADATA_FLAG_IS_VIRTUAL_DISK | required;
+    let mut items = vec![
+        (
+            format::METADATA_FILE_PARAMETERS,
+            required,
+            format::FileParameters {
+                block_size,
+                flags: if parent.is_some() {
+                    format::FILE_PARAMETERS_HAS_PARENT
+                } else {
+                    0
+                },
+            }
+            .as_bytes()
+            .to_vec(),
+        ),
+        (
+            format::METADATA_VIRTUAL_DISK_SIZE,
+            virtual_disk,
+            disk_size.as_bytes().to_vec(),
+        ),
+        (
+            format::METADATA_VIRTUAL_DISK_ID,
+            virtual_disk,
+            Guid::new_random().as_bytes().to_vec(),
+        ),
+        (
+            format::METADATA_LOGICAL_SECTOR_SIZE,
+            virtual_disk,
+            logical_sector_size.as_bytes().to_vec(),
+        ),
+        (
+            format::METADATA_PHYSICAL_SECTOR_SIZE,
+            virtual_disk,
+            4096u32.as_bytes().to_vec(),
+        ),
+    ];
+    if let Some((data_write_guid, path)) = &parent {
+        items.push((
+            format::METADATA_PARENT_LOCATOR,
+            required,
+            build_parent_locator(&[
+                ("parent_linkage", &format!("{{{data_write_guid}}}")),
+                ("absolute_win32_path", path),
+            ]),
+        ));
+    }
+
+    let mut metadata = vec![0; metadata_len as usize];
+    format::MetadataTableHeader {
+        signature: format::METADATA_TABLE_SIGNATURE,
+        reserved: 0,
+        entry_count: items.len() as u16,
+        reserved2: [0; 5],
+    }
+    .write_to_prefix(&mut metadata)
+    .unwrap();
+    let mut item_offset = format::METADATA_TABLE_SIZE;
+    for (i, (item_id, flags, data)) in items.iter().enumerate() {
+        let offset =
+            size_of::<format::MetadataTableHeader>() + i * size_of::<format::MetadataTableEntry>();
+        format::MetadataTableEntry {
+            item_id: *item_id,
+            offset: item_offset as u32,
+            length: data.len() as u32,
+            flags: *flags,
+            reserved: 0,
+        }
+        .write_to_prefix(&mut metadata[offset..])
+        .unwrap();
+        metadata[item_offset..item_offset + data.len()].copy_from_slice(data);
+        item_offset += data.len().next_multiple_of(8);
+    }
+
+    file.set_len(0)?;
+    file.set_len(bat_offset + bat_len)?;
+    write_all_at(file, ident.as_bytes(), format::FILE_IDENTIFIER_OFFSET)?;
+    let mut slot = 1;
+    for _ in format::HEADER_OFFSETS {
+        slot = write_header(file, slot, &mut header)?;
+    }
+    for offset in format::REGION_TABLE_OFFSETS {
+        write_all_at(file, &region_table, offset)?;
+    }
+    write_all_at(file, &metadata, metadata_offset)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn build_parent_locator(entries: &[(&str, &str)]) -> Vec<u8> {
+    let header_len = size_of::<format::ParentLocatorHeader>()
+        + entries.len() * size_of::<format::ParentLocatorEntry>();
+    let mut data = format::ParentLocatorHeader {
+        locator_type: format::PARENT_LOCATOR_TYPE_VHDX,
+        reserved: 0,
+        key_value_count: entries.len() as u16,
+    }
+    .as_bytes()
+    .to_vec();
+    let mut strings = Vec::new();
+    for (key, value) in entries {
+        let key = encode_utf16le(key);
+        let value = encode_utf16le(value);
+        let key_offset = header_len + strings.len();
+        strings.extend_from_slice(&key);
+        let value_offset = header_len + strings.len();
+        strings.extend_from_slice(&value);
+        data.extend_from_slice(
+            format::ParentLocatorEntry {
+                key_offset: key_offset as u32,
+                value_offset: value_offset as u32,
+                key_length: key.len() as u16,
+                value_length: value.len() as u16,
+            }
+            .as_bytes(),
+        );
+    }
+    data.extend_from_slice(&strings);
+    data
+}
+
+impl LayerIo for VhdxLayer {
+    fn layer_type(&self) -> &str {
+        "vhdx"
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.disk_size >> self.sector_shift
+    }
+
+    fn sector_size(&self) -> u32 {
+        self.logical_sector_size
+    }
+
+    fn disk_id(&self) -> Option<[u8; 16]> {
+        Some(self.disk_id.into())
+    }
+
+    fn physical_sector_size(&self) -> u32 {
+        self.physical_sector_size
+    }
+
+    fn is_fua_respected(&self) -> bool {
+        false
+    }
+
+    fn is_logically_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    async fn sync_cache(&self) -> Result<(), DiskError> {
+        self.with_file(|file| file.sync_all()).await
+    }
+
+    async fn read(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        mut marker: SectorMarker<'_>,
+    ) -> Result<(), DiskError> {
+        let count = self.check_range(buffers, sector)?;
+        for (block, range, offset) in self.block_ranges(sector, count) {
+            let block_base = sector + (offset >> self.sector_shift) as u64 - range.start;
+            let mut state = self.state.lock().await;
+            let entry = state.bat[self.payload_index(block)];
+            let runs = match format::bat_state(entry) {
+                format::payload_state::FULLY_PRESENT => vec![range.clone()],
+                format::payload_state::PARTIALLY_PRESENT => {
+                    let bitmap = self.block_bitmap(&mut state, block).await?;
+                    set_runs(bitmap, BitOrder::LsbFirst, range.clone())
+                }
+                // Sectors not present in a differencing disk are read from the
+                // parent.
+                format::payload_state::NOT_PRESENT if self.has_parent => continue,
+                _ => {
+                    let len = ((range.end - range.start) << self.sector_shift) as usize;
+                    buffers.subrange(offset, len).writer().zero(len)?;
+                    marker.set_range(block_base + range.start..block_base + range.end);
+                    continue;
+                }
+            };
+            drop(state);
+
+            for run in runs {
+                let len = ((run.end - run.start) << self.sector_shift) as usize;
+                let file_offset = format::bat_offset(entry) + (run.start << self.sector_shift);
+                let data = self
+                    .with_file(move |file| {
+                        let mut buf = vec![0; len];
+                        read_exact_at(file, &mut buf, file_offset)?;
+                        Ok(buf)
+                    })
+                    .await?;
+                buffers
+                    .subrange(
+                        offset + ((run.start - range.start) << self.sector_shift) as usize,
+                        len,
+                    )
+                    .writer()
+                    .write(&data)?;
+                marker.set_range(block_base + run.start..block_base + run.end);
+            }
+        }
+        Ok(())
+    }
+
+    async fn write(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        _fua: bool,
+    ) -> Result<(), DiskError> {
+        if self.read_only {
+            return Err(DiskError::ReadOnly);
+        }
+        let count = self.check_range(buffers, sector)?;
+        for (block, range, offset) in self.block_ranges(sector, count) {
+            let len = ((range.end - range.start) << self.sector_shift) as usize;
+            let mut data = vec![0; len];
+            buffers.subrange(offset, len).reader().read(&mut data)?;
+
+            let mut state = self.state.lock().await;
+            self.prepare_for_write(&mut state).await?;
+            let entry = state.bat[self.payload_index(block)];
+            match format::bat_state(entry) {
+                format::payload_state::FULLY_PRESENT => {
+                    drop(state);
+                    let file_offset =
+                        format::bat_offset(entry) + (range.start << self.sector_shift);
+                    self.with_file(move |file| write_all_at(file, &data, file_offset))
+                        .await?;
+                }
+                format::payload_state::PARTIALLY_PRESENT => {
+                    drop(state);
+                    let file_offset =
+                        format::bat_offset(entry) + (range.start << self.sector_shift);
+                    self.with_file(move |file| write_all_at(file, &data, file_offset))
+                        .await?;
+                    // Mark the sectors present only once the data has been
+                    // written.
+                    let mut state = self.state.lock().await;
+                    self.set_present(&mut state, block, range).await?;
+                }
+                _ => self.allocate_block(&mut state, block, range, data).await?,
+            }
+        }
+        Ok(())
+    }
+
+    async fn unmap(
+        &self,
+        _sector: u64,
+        _count: u64,
+        _block_level_only: bool,
+        _next_is_zero: bool,
+    ) -> Result<(), DiskError> {
+        Ok(())
+    }
+
+    fn unmap_behavior(&self) -> UnmapBehavior {
+        UnmapBehavior::Ignored
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OpenError;
+    use super::VhdxLayer;
+    use super::read_header;
+    use crate::format;
+    use crate::log::LogWrite;
+    use crate::log::LogWriter;
+    use disk_backend::Disk;
+    use disk_image_layer::test_helpers::create;
+    use disk_image_layer::test_helpers::read_sectors;
+    use disk_image_layer::test_helpers::write_sectors;
+    use guestmem::GuestMemory;
+    use pal_async::async_test;
+    use std::fs::File;
+
+    async fn open_layered(files: Vec<File>) -> Disk {
+        disk_image_layer::test_helpers::open_layered(files, false, |file, read_only| {
+            VhdxLayer::open(file, read_only).unwrap()
+        })
+        .await
+    }
+
+    #[async_test]
+    async fn dynamic() {
+        let file = tempfile::tempfile().unwrap();
+        VhdxLayer::make_dynamic(&file, 0x10000000).unwrap();
+        let mem = GuestMemory::allocate(0x10000);
+
+        // Write across a block boundary, so that two blocks are allocated.
+        let sector = format::DEFAULT_BLOCK_SIZE as u64 / 512 - 2;
+        let data = (0..4 * 128_u32).collect::<Vec<_>>();
+        {
+            let vhdx = open_layered(vec![file.try_clone().unwrap()]).await;
+            assert!(
+                read_sectors(&vhdx, &mem, sector, 4)
+                    .await
+                    .iter()
+                    .all(|&x| x == 0)
+            );
+            write_sectors(&vhdx, &mem, sector, &data).await;
+        }
+
+        let vhdx = open_layered(vec![file]).await;
+        assert_eq!(vhdx.sector_count(), 0x10000000 / 512);
+        assert_eq!(read_sectors(&vhdx, &mem, sector, 4).await, data);
+        assert!(
+            read_sectors(&vhdx, &mem, 0, 4)
+                .await
+                .iter()
+                .all(|&x| x == 0)
+        );
+    }
+
+    #[async_test]
+    async fn differencing() {
+        let dir = tempfile::tempdir().unwrap();
+        let mem = GuestMemory::allocate(0x10000);
+
+        let parent_path = dir.path().join("parent.vhdx");
+        let parent = create(&parent_path);
+        VhdxLayer::make_dynamic(&parent, 0x10000000).unwrap();
+        let parent_data = (0..8 * 128_u32).collect::<Vec<_>>();
+        {
+            let vhdx = open_layered(vec![parent]).await;
+            write_sectors(&vhdx, &mem, 0, &parent_data).await;
+        }
+
+        let child_path = dir.path().join("child.vhdx");
+        let child = create(&child_path);
+        VhdxLayer::make_differencing(&child, &parent_path).unwrap();
+        drop(child);
+
+        let files = VhdxLayer::open_chain(&child_path, false).unwrap();
+        assert_eq!(files.len(), 2);
+        let vhdx = open_layered(files).await;
+        let child_data = (0..2 * 128_u32).map(|x| !x).collect::<Vec<_>>();
+        write_sectors(&vhdx, &mem, 3, &child_data).await;
+        drop(vhdx);
+
+        // Sectors not written to the child are read from the parent, both
+        // before and after reopening the chain.
+        let vhdx = open_layered(VhdxLayer::open_chain(&child_path, true).unwrap()).await;
+        let mut expected = parent_data.clone();
+        expected[3 * 128..5 * 128].copy_from_slice(&child_data);
+        assert_eq!(read_sectors(&vhdx, &mem, 0, 8).await, expected);
+        drop(vhdx);
+
+        // Modifying the parent breaks the chain.
+        {
+            let vhdx = open_layered(vec![
+                File::options()
+                    .read(true)
+                    .write(true)
+                    .open(&parent_path)
+                    .unwrap(),
+            ])
+            .await;
+            write_sectors(&vhdx, &mem, 0, &parent_data).await;
+        }
+        assert!(matches!(
+            VhdxLayer::open_chain(&child_path, true),
+            Err(OpenError::ParentMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn truncated_bat() {
+        let file = tempfile::tempfile().unwrap();
+        VhdxLayer::make_dynamic(&file, 0x10000000).unwrap();
+
+        // The BAT must be checked against the file before it is read.
+        file.set_len(3 * format::MB + 8).unwrap();
+        assert!(matches!(
+            VhdxLayer::open(file, true),
+            Err(OpenError::InvalidBat)
+        ));
+    }
+
+    #[async_test]
+    async fn log_replay() {
+        let file = tempfile::tempfile().unwrap();
+        VhdxLayer::make_dynamic(&file, 0x10000000).unwrap();
+        let mem = GuestMemory::allocate(0x10000);
+        let data = (0..128_u32).collect::<Vec<_>>();
+        {
+            let vhdx = open_layered(vec![file.try_clone().unwrap()]).await;
+            write_sectors(&vhdx, &mem, 0, &data).await;
+        }
+
+        // Simulate a crash before the BAT update was applied.
+        disk_file::write_all_at(&file, &[0; 4096], 3 * format::MB).unwrap();
+
+        assert!(matches!(
+            VhdxLayer::open(file.try_clone().unwrap(), true),
+            Err(OpenError::LogReplayRequired)
+        ));
+        let vhdx = open_layered(vec![file.try_clone().unwrap()]).await;
+        assert_eq!(read_sectors(&vhdx, &mem, 0, 1).await, data);
+        drop(vhdx);
+
+        // The log has been cleared, so the file can be opened read-only.
+        VhdxLayer::open(file, true).unwrap();
+    }
+
+    #[async_test]
+    async fn log_replay_out_of_bounds() {
+        let file = tempfile::tempfile().unwrap();
+        VhdxLayer::make_dynamic(&file, 0x10000000).unwrap();
+        let mem = GuestMemory::allocate(0x10000);
+        {
+            let vhdx = open_layered(vec![file.try_clone().unwrap()]).await;
+            write_sectors(&vhdx, &mem, 0, &[0; 128]).await;
+        }
+
+        let (_, header) = read_header(&file).unwrap();
+        let file_len = file.metadata().unwrap().len();
+        for (file_offset, len) in [
+            // Past the end of the file.
+            (file_len, 4096),
+            // Overflowing.
+            (4096, u64::MAX & !4095),
+            // Over the log.
+            (header.log_offset, 4096),
+        ] {
+            LogWriter::new(header.log_offset, header.log_length, header.log_guid)
+                .log(&file, &[LogWrite::Zero { file_offset, len }], file_len)
+                .unwrap();
+            assert!(matches!(
+                VhdxLayer::open(file.try_clone().unwrap(), false),
+                Err(OpenError::InvalidLog)
+            ));
+        }
+    }
+}
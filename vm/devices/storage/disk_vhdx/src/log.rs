@@ -0,0 +1,425 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! The VHDX metadata log.
+//!
+//! Updates to VHDX metadata (the BAT and sector bitmaps) are first written to
+//! a circular log, so that they can be replayed if the process crashes while
+//! applying them. This implementation only ever keeps a single entry in the
+//! log: each update is written to the start of the log, flushed, and applied
+//! before the next update is logged.
+
+use crate::OpenError;
+use crate::format;
+use disk_file::read_exact_at;
+use disk_file::write_all_at;
+use guid::Guid;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use zerocopy::FromBytes;
+use zerocopy::FromZeros;
+use zerocopy::IntoBytes;
+
+const SECTOR: usize = format::LOG_SECTOR_SIZE as usize;
+
+/// The largest log that will be read into memory for replay.
+const MAX_LOG_LENGTH: u32 = 256 * format::MB as u32;
+
+/// The size of the buffer used to zero or check zero ranges.
+const ZERO_CHUNK_SIZE: u64 = format::MB;
+
+/// A single sector write recovered from, or to be written to, the log.
+pub(crate) enum LogWrite {
+    Data {
+        file_offset: u64,
+        data: Box<[u8; SECTOR]>,
+    },
+    Zero {
+        file_offset: u64,
+        len: u64,
+    },
+}
+
+impl LogWrite {
+    /// Returns the file offset written.
+    pub fn file_offset(&self) -> u64 {
+        match *self {
+            LogWrite::Data { file_offset, .. } | LogWrite::Zero { file_offset, .. } => file_offset,
+        }
+    }
+
+    /// Returns the number of bytes written.
+    fn len(&self) -> u64 {
+        match *self {
+            LogWrite::Data { .. } => SECTOR as u64,
+            LogWrite::Zero { len, .. } => len,
+        }
+    }
+
+    fn apply(&self, file: &File) -> io::Result<()> {
+        match self {
+            LogWrite::Data { file_offset, data } => write_all_at(file, &data[..], *file_offset),
+            LogWrite::Zero { file_offset, len } => {
+                let zero = vec![0; (*len).min(ZERO_CHUNK_SIZE) as usize];
+                let mut offset = 0;
+                while offset < *len {
+                    let n = (*len - offset).min(zero.len() as u64);
+                    write_all_at(file, &zero[..n as usize], file_offset + offset)?;
+                    offset += n;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns whether the file already contains the result of this write.
+    fn is_applied(&self, file: &File) -> io::Result<bool> {
+        match self {
+            LogWrite::Data { file_offset, data } => {
+                let mut current = vec![0; SECTOR];
+                match read_exact_at(file, &mut current, *file_offset) {
+                    Ok(()) => Ok(current == data[..]),
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+                    Err(err) => Err(err),
+                }
+            }
+            LogWrite::Zero { file_offset, len } => {
+                let mut current = vec![0; (*len).min(ZERO_CHUNK_SIZE) as usize];
+                let mut offset = 0;
+                while offset < *len {
+                    let n = (*len - offset).min(current.len() as u64) as usize;
+                    match read_exact_at(file, &mut current[..n], file_offset + offset) {
+                        Ok(()) => {
+                            if current[..n].iter().any(|&b| b != 0) {
+                                return Ok(false);
+                            }
+                        }
+                        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                            return Ok(false);
+                        }
+                        Err(err) => return Err(err),
+                    }
+                    offset += n as u64;
+                }
+                Ok(true)
+            }
+        }
+    }
+}
+
+/// A validated log entry.
+struct Entry {
+    offset: u32,
+    header: format::LogEntryHeader,
+    writes: Vec<LogWrite>,
+}
+
+/// Reads a log entry at `offset` within the log. An entry does not wrap
+/// around the end of the log.
+fn read_entry(log: &[u8], offset: u32, log_guid: Guid) -> Option<Entry> {
+    let remaining = &log[offset as usize..];
+    let (header, _) = format::LogEntryHeader::read_from_prefix(remaining).ok()?;
+    let entry_length = header.entry_length as usize;
+    if header.signature != format::LOG_ENTRY_SIGNATURE
+        || header.log_guid != log_guid
+        || entry_length == 0
+        || entry_length % SECTOR != 0
+        || entry_length > remaining.len()
+        || header.tail as usize % SECTOR != 0
+        || header.tail as usize >= log.len()
+    {
+        return None;
+    }
+    let data = &remaining[..entry_length];
+    if format::compute_checksum(data) != header.checksum {
+        return None;
+    }
+
+    let descriptor_count = header.descriptor_count as usize;
+    let descriptor_sectors = (size_of::<format::LogEntryHeader>()
+        + descriptor_count * size_of::<format::LogDescriptor>())
+    .div_ceil(SECTOR);
+    if descriptor_sectors * SECTOR > entry_length {
+        return None;
+    }
+    let descriptors = data[size_of::<format::LogEntryHeader>()..]
+        .chunks_exact(size_of::<format::LogDescriptor>())
+        .take(descriptor_count)
+        .map(|d| format::LogDescriptor::read_from_bytes(d).unwrap());
+
+    let mut data_sectors = data
+        .get(descriptor_sectors * SECTOR..)?
+        .chunks_exact(SECTOR)
+        .map(|sector| format::LogDataSector::read_from_bytes(sector).unwrap());
+
+    let mut writes = Vec::new();
+    for descriptor in descriptors {
+        if descriptor.sequence_number != header.sequence_number {
+            return None;
+        }
+        match descriptor.signature {
+            format::LOG_DATA_DESCRIPTOR_SIGNATURE => {
+                let sector = data_sectors.next()?;
+                if sector.signature != format::LOG_DATA_SECTOR_SIGNATURE
+                    || sector.sequence_high != (header.sequence_number >> 32) as u32
+                    || sector.sequence_low != header.sequence_number as u32
+                    || descriptor.file_offset % format::LOG_SECTOR_SIZE != 0
+                {
+                    return None;
+                }
+                let mut data = Box::new([0; SECTOR]);
+                data[..8].copy_from_slice(&descriptor.leading_bytes);
+                data[8..SECTOR - 4].copy_from_slice(&sector.data);
+                data[SECTOR - 4..].copy_from_slice(&descriptor.trailing_bytes);
+                writes.push(LogWrite::Data {
+                    file_offset: descriptor.file_offset,
+                    data,
+                });
+            }
+            format::LOG_ZERO_DESCRIPTOR_SIGNATURE => {
+                let len = u64::from_le_bytes(descriptor.leading_bytes);
+                if descriptor.file_offset % format::LOG_SECTOR_SIZE != 0
+                    || len % format::LOG_SECTOR_SIZE != 0
+                {
+                    return None;
+                }
+                writes.push(LogWrite::Zero {
+                    file_offset: descriptor.file_offset,
+                    len,
+                });
+            }
+            _ => return None,
+        }
+    }
+
+    Some(Entry {
+        offset,
+        header,
+        writes,
+    })
+}
+
+/// Finds the active sequence of log entries: the sequence with the highest
+/// sequence number that is complete back to its head entry's tail.
+fn active_sequence(log: &[u8], log_guid: Guid) -> Option<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset < log.len() {
+        if let Some(entry) = read_entry(log, offset as u32, log_guid) {
+            // Another valid entry cannot start within this one.
+            offset += entry.header.entry_length as usize;
+            entries.push(entry);
+        } else {
+            offset += SECTOR;
+        }
+    }
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.header.sequence_number));
+    let by_offset = entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| (entry.offset, i))
+        .collect::<HashMap<_, _>>();
+
+    let sequence = entries.iter().enumerate().find_map(|(head, head_entry)| {
+        let mut sequence = Vec::new();
+        let mut offset = head_entry.header.tail;
+        loop {
+            let &i = by_offset.get(&offset)?;
+            let entry = &entries[i];
+            if sequence.last().is_some_and(|&prev: &usize| {
+                entry.header.sequence_number != entries[prev].header.sequence_number + 1
+            }) {
+                return None;
+            }
+            offset = ((offset as usize + entry.header.entry_length as usize) % log.len()) as u32;
+            sequence.push(i);
+            if i == head {
+                return Some(sequence);
+            }
+            if sequence.len() > entries.len() {
+                return None;
+            }
+        }
+    })?;
+
+    let mut entries = entries.into_iter().map(Some).collect::<Vec<_>>();
+    Some(
+        sequence
+            .into_iter()
+            .map(|i| entries[i].take().unwrap())
+            .collect(),
+    )
+}
+
+/// Replays the log described by `header`.
+///
+/// If `read_only`, the file is not modified, and an error is returned if the
+/// file does not already contain the result of replaying the log.
+pub(crate) fn replay(
+    file: &File,
+    header: &format::Header,
+    read_only: bool,
+) -> Result<(), OpenError> {
+    if header.log_length > MAX_LOG_LENGTH
+        || header.log_length as u64 % format::REGION_ALIGNMENT != 0
+        || header.log_offset % format::REGION_ALIGNMENT != 0
+        || header.log_length == 0
+    {
+        return Err(OpenError::InvalidLog);
+    }
+    let mut log = vec![0; header.log_length as usize];
+    read_exact_at(file, &mut log, header.log_offset)?;
+
+    // If there is no valid sequence, then the log is empty.
+    let Some(sequence) = active_sequence(&log, header.log_guid) else {
+        return Ok(());
+    };
+
+    let head = &sequence.last().unwrap().header;
+    let file_len = file.metadata()?.len();
+    if file_len < head.flushed_file_offset {
+        return Err(OpenError::InvalidLog);
+    }
+
+    // Each write must be within the file as described by its entry, and must
+    // not overwrite the log itself.
+    let log_end = header.log_offset + header.log_length as u64;
+    for entry in &sequence {
+        for write in &entry.writes {
+            let start = write.file_offset();
+            let end = start
+                .checked_add(write.len())
+                .ok_or(OpenError::InvalidLog)?;
+            if end > entry.header.last_file_offset || (start < log_end && end > header.log_offset) {
+                return Err(OpenError::InvalidLog);
+            }
+        }
+    }
+
+    if read_only {
+        for entry in &sequence {
+            for write in &entry.writes {
+                if !write.is_applied(file)? {
+                    return Err(OpenError::LogReplayRequired);
+                }
+            }
+        }
+    } else {
+        for entry in &sequence {
+            for write in &entry.writes {
+                write.apply(file)?;
+            }
+        }
+        if file_len < head.last_file_offset {
+            file.set_len(head.last_file_offset)?;
+        }
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Writes metadata updates through the log.
+#[derive(Clone)]
+pub(crate) struct LogWriter {
+    log_offset: u64,
+    log_length: u32,
+    log_guid: Guid,
+    sequence_number: u64,
+}
+
+impl LogWriter {
+    pub fn new(log_offset: u64, log_length: u32, log_guid: Guid) -> Self {
+        Self {
+            log_offset,
+            log_length,
+            log_guid,
+            sequence_number: 1,
+        }
+    }
+
+    /// Writes `writes` to the log as a single entry, and flushes it.
+    ///
+    /// `file_len` is the current length of the file.
+    pub fn log(&mut self, file: &File, writes: &[LogWrite], file_len: u64) -> io::Result<()> {
+        let descriptor_sectors = (size_of::<format::LogEntryHeader>()
+            + writes.len() * size_of::<format::LogDescriptor>())
+        .div_ceil(SECTOR);
+        let data_sectors = writes
+            .iter()
+            .filter(|w| matches!(w, LogWrite::Data { .. }))
+            .count();
+        let entry_length = (descriptor_sectors + data_sectors) * SECTOR;
+        if entry_length > self.log_length as usize {
+            return Err(io::Error::other("log entry too large"));
+        }
+
+        let sequence_number = self.sequence_number;
+        let mut entry = vec![0; entry_length];
+        let mut data_offset = descriptor_sectors * SECTOR;
+        for (i, write) in writes.iter().enumerate() {
+            let mut descriptor = format::LogDescriptor::new_zeroed();
+            descriptor.sequence_number = sequence_number;
+            match write {
+                LogWrite::Data { file_offset, data } => {
+                    descriptor.signature = format::LOG_DATA_DESCRIPTOR_SIGNATURE;
+                    descriptor.file_offset = *file_offset;
+                    descriptor.leading_bytes.copy_from_slice(&data[..8]);
+                    descriptor
+                        .trailing_bytes
+                        .copy_from_slice(&data[SECTOR - 4..]);
+                    let mut sector = format::LogDataSector::new_zeroed();
+                    sector.signature = format::LOG_DATA_SECTOR_SIGNATURE;
+                    sector.sequence_high = (sequence_number >> 32) as u32;
+                    sector.sequence_low = sequence_number as u32;
+                    sector.data.copy_from_slice(&data[8..SECTOR - 4]);
+                    entry[data_offset..data_offset + SECTOR].copy_from_slice(sector.as_bytes());
+                    data_offset += SECTOR;
+                }
+                LogWrite::Zero { file_offset, len } => {
+                    descriptor.signature = format::LOG_ZERO_DESCRIPTOR_SIGNATURE;
+                    descriptor.file_offset = *file_offset;
+                    descriptor.leading_bytes = len.to_le_bytes();
+                }
+            }
+            let offset =
+                size_of::<format::LogEntryHeader>() + i * size_of::<format::LogDescriptor>();
+            descriptor.write_to_prefix(&mut entry[offset..]).unwrap();
+        }
+
+        let mut header = format::LogEntryHeader {
+            signature: format::LOG_ENTRY_SIGNATURE,
+            checksum: 0,
+            entry_length: entry_length as u32,
+            tail: 0,
+            sequence_number,
+            descriptor_count: writes.len() as u32,
+            reserved: 0,
+            log_guid: self.log_guid,
+            flushed_file_offset: file_len,
+            last_file_offset: file_len,
+        };
+        header.write_to_prefix(&mut entry[..]).unwrap();
+        header.checksum = format::crc32c(&entry);
+        header.write_to_prefix(&mut entry[..]).unwrap();
+
+        write_all_at(file, &entry, self.log_offset)?;
+        file.sync_data()?;
+        self.sequence_number += 1;
+        Ok(())
+    }
+
+    /// Logs `writes`, then applies them to the file.
+    pub fn log_and_apply(
+        &mut self,
+        file: &File,
+        writes: &[LogWrite],
+        file_len: u64,
+    ) -> io::Result<()> {
+        self.log(file, writes, file_len)?;
+        for write in writes {
+            write.apply(file)?;
+        }
+        file.sync_data()
+    }
+}
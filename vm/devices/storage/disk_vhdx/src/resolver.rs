@@ -0,0 +1,37 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Resolver implementation for VHDX disk layers.
+
+use crate::OpenError;
+use crate::VhdxLayer;
+use disk_backend_resources::layer::VhdxDiskLayerHandle;
+use disk_layered::resolve::ResolveDiskLayerParameters;
+use disk_layered::resolve::ResolvedDiskLayer;
+use vm_resource::ResolveResource;
+use vm_resource::declare_static_resolver;
+use vm_resource::kind::DiskLayerHandleKind;
+
+/// A resolver for VHDX disk layers.
+pub struct VhdxLayerResolver;
+
+declare_static_resolver!(
+    VhdxLayerResolver,
+    (DiskLayerHandleKind, VhdxDiskLayerHandle)
+);
+
+impl ResolveResource<DiskLayerHandleKind, VhdxDiskLayerHandle> for VhdxLayerResolver {
+    type Output = ResolvedDiskLayer;
+    type Error = OpenError;
+
+    fn resolve(
+        &self,
+        rsrc: VhdxDiskLayerHandle,
+        input: ResolveDiskLayerParameters<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        Ok(ResolvedDiskLayer::new(VhdxLayer::open(
+            rsrc.file,
+            rsrc.read_only || input.read_only,
+        )?))
+    }
+}
@@ -0,0 +1,116 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A tool for operating on OpenVMM disk images while no VM is using them.
+
+#![forbid(unsafe_code)]
+
+use anyhow::Context;
+use clap::Parser;
+use disk_backend::resolve::ResolveDiskParameters;
+use disk_layered::DiskLayer;
+use disk_layered::LayerConfiguration;
+use disk_layered::LayeredDisk;
+use disklayer_sqlite::SqliteDiskLayer;
+use hvlite_helpers::disk::open_disk_type;
+use pal_async::DefaultDriver;
+use pal_async::DefaultPool;
+use std::path::Path;
+use std::path::PathBuf;
+use vm_resource::ResourceResolver;
+use vmcore::vm_task::SingleDriverBackend;
+use vmcore::vm_task::VmTaskDriverSource;
+
+vm_resource::register_static_resolvers! {
+    disk_file::FileDiskResolver,
+    disk_layered::resolver::LayeredDiskResolver,
+    disk_qcow2::resolver::Qcow2LayerResolver,
+    disk_vhd1::Vhd1Resolver,
+    disk_vhd1::Vhd1LayerResolver,
+    disk_vhdx::resolver::VhdxLayerResolver,
+    #[cfg(windows)]
+    disk_vhdmp::VhdmpDiskResolver,
+}
+
+#[derive(Parser)]
+#[clap(name = "disktool", about = "Operate on OpenVMM disk images")]
+enum Options {
+    /// Merge a sqlite diff disk into the disk below it, then delete the diff
+    /// disk.
+    ///
+    /// This is the offline equivalent of the interactive console's
+    /// `merge-disk` command.
+    Merge {
+        /// The sqlite diff disk (.dbhd) to merge.
+        #[clap(long)]
+        diff: PathBuf,
+        /// The disk to merge into. This can be a raw, vhd, vhdx, or qcow2
+        /// image, or a sqlite disk (.dbhd).
+        base: PathBuf,
+    },
+}
+
+fn main() -> anyhow::Result<()> {
+    let opt = Options::parse();
+    DefaultPool::run_with(async |driver| match opt {
+        Options::Merge { diff, base } => merge(driver, &diff, &base).await,
+    })
+}
+
+async fn merge(driver: DefaultDriver, diff: &Path, base: &Path) -> anyhow::Result<()> {
+    let base_layer = if base.extension().is_some_and(|ext| ext == "dbhd") {
+        DiskLayer::new(
+            SqliteDiskLayer::new(base, false, None)
+                .with_context(|| format!("failed to open {}", base.display()))?,
+        )
+    } else {
+        let resource = open_disk_type(base, false)
+            .with_context(|| format!("failed to open {}", base.display()))?;
+        let disk = ResourceResolver::new()
+            .resolve(
+                resource,
+                ResolveDiskParameters {
+                    read_only: false,
+                    driver_source: &VmTaskDriverSource::new(SingleDriverBackend::new(driver)),
+                },
+            )
+            .await
+            .with_context(|| format!("failed to open {}", base.display()))?;
+        DiskLayer::from_disk(disk.0)
+    };
+
+    let diff_layer = DiskLayer::new(
+        SqliteDiskLayer::new(diff, false, None)
+            .with_context(|| format!("failed to open {}", diff.display()))?,
+    );
+
+    let disk = LayeredDisk::new(
+        false,
+        vec![
+            LayerConfiguration {
+                layer: diff_layer,
+                write_through: false,
+                read_cache: false,
+            },
+            LayerConfiguration {
+                layer: base_layer,
+                write_through: false,
+                read_cache: false,
+            },
+        ],
+    )
+    .await
+    .context("failed to stack the diff disk on the base disk")?;
+
+    disk.merge_handle()
+        .merge_top_layer()
+        .await
+        .context("failed to merge the diff disk")?;
+    drop(disk);
+
+    // The diff disk is stale now that the base disk has its contents, and
+    // stacking it on the base disk again would roll back later writes.
+    fs_err::remove_file(diff)?;
+    println!("merged {} into {}", diff.display(), base.display());
+    Ok(())
+}
@@ -11,6 +11,7 @@
 mod prp;
 mod queue;
 pub mod resolver;
+mod sgl;
 mod workers;
 
 #[cfg(test)]
@@ -7,17 +7,25 @@
 
 use crate::error::CommandResult;
 use crate::error::NvmeError;
-use crate::prp::PrpRange;
+use crate::prp::DataRange;
 use crate::spec;
 use crate::spec::nvm;
 use disk_backend::Disk;
+use disk_backend::DiskError;
+use disk_backend::UnmapBehavior;
 use guestmem::GuestMemory;
 use inspect::Inspect;
+use scsi_buffers::OwnedRequestBuffers;
 use scsi_buffers::RequestBuffers;
 use zerocopy::FromBytes;
 use zerocopy::FromZeros;
 use zerocopy::IntoBytes;
 
+/// The maximum size of the buffer used by commands that access the disk
+/// without transferring data to or from the guest, such as Write Zeroes and
+/// Verify.
+const SCRATCH_BUFFER_SIZE: usize = 64 * 1024;
+
 /// An NVMe namespace built on top of a [`Disk`].
 #[derive(Inspect)]
 pub struct Namespace {
@@ -56,6 +64,14 @@ pub fn identify(&self, buf: &mut [u8]) {
             nvm::ReservationCapabilities::new()
         };
 
+        let dlfeat = if self.disk.unmap_behavior() == UnmapBehavior::Zeroes {
+            nvm::Dlfeat::new()
+                .with_read_behavior(nvm::DeallocatedReadBehavior::ZEROES.0)
+                .with_write_zeroes_deallocate(true)
+        } else {
+            nvm::Dlfeat::new()
+        };
+
         *id = nvm::IdentifyNamespace {
             nsze: size,
             ncap: size,
@@ -63,6 +79,7 @@ pub fn identify(&self, buf: &mut [u8]) {
             nlbaf: 0,
             flbas: nvm::Flbas::new().with_low_index(0),
             rescap,
+            dlfeat: dlfeat.into(),
             ..FromZeros::new_zeroed()
         };
         id.lbaf[0] = nvm::Lbaf::new().with_lbads(self.block_shift as u8);
@@ -133,7 +150,7 @@ pub async fn nvm_command(
                 if byte_count > max_data_transfer_size {
                     return Err(spec::Status::INVALID_FIELD_IN_COMMAND.into());
                 }
-                let range = PrpRange::parse(&self.mem, byte_count, command.dptr)?;
+                let range = DataRange::parse(&self.mem, byte_count, command)?;
 
                 let disk_sector_count = self.disk.sector_count();
                 if disk_sector_count < lba || disk_sector_count - lba < count as u64 {
@@ -142,11 +159,18 @@ pub async fn nvm_command(
 
                 tracing::trace!(nsid = self.nsid, lba, count, byte_count, "read");
 
-                let buffers = RequestBuffers::new(&self.mem, range.range(), true);
-                self.disk
-                    .read_vectored(&buffers, lba)
-                    .await
-                    .map_err(map_disk_error)?;
+                if let Some(range) = range.paged() {
+                    let buffers = RequestBuffers::new(&self.mem, range, true);
+                    self.disk
+                        .read_vectored(&buffers, lba)
+                        .await
+                        .map_err(map_disk_error)?;
+                } else {
+                    // The SGL cannot be handed to the disk directly, so bounce
+                    // the data.
+                    let data = self.read_scratch(lba, count as u64).await?;
+                    range.write(&self.mem, &data)?;
+                }
             }
             nvm::NvmOpcode::WRITE => {
                 let cdw10 = nvm::Cdw10ReadWrite::from(command.cdw10);
@@ -158,7 +182,7 @@ pub async fn nvm_command(
                 if byte_count > max_data_transfer_size {
                     return Err(spec::Status::INVALID_FIELD_IN_COMMAND.into());
                 }
-                let range = PrpRange::parse(&self.mem, byte_count, command.dptr)?;
+                let range = DataRange::parse(&self.mem, byte_count, command)?;
 
                 let disk_sector_count = self.disk.sector_count();
                 if disk_sector_count < lba || disk_sector_count - lba < count as u64 {
@@ -167,11 +191,19 @@ pub async fn nvm_command(
 
                 tracing::trace!(nsid = self.nsid, lba, count, byte_count, "write");
 
-                let buffers = RequestBuffers::new(&self.mem, range.range(), false);
-                self.disk
-                    .write_vectored(&buffers, lba, cdw12.fua())
-                    .await
-                    .map_err(map_disk_error)?;
+                if let Some(range) = range.paged() {
+                    let buffers = RequestBuffers::new(&self.mem, range, false);
+                    self.disk
+                        .write_vectored(&buffers, lba, cdw12.fua())
+                        .await
+                        .map_err(map_disk_error)?;
+                } else {
+                    // The SGL cannot be handed to the disk directly, so bounce
+                    // the data.
+                    let mut data = vec![0; byte_count];
+                    range.read(&self.mem, &mut data)?;
+                    self.write_scratch(lba, &data, cdw12.fua()).await?;
+                }
             }
             nvm::NvmOpcode::FLUSH => {
                 tracing::debug!(nsid = self.nsid, "flush");
@@ -186,12 +218,23 @@ pub async fn nvm_command(
                 let mut dsm_ranges =
                     <[nvm::DsmRange]>::new_box_zeroed_with_elems(cdw10.nr_z() as usize + 1)
                         .unwrap();
-                let prp =
-                    PrpRange::parse(&self.mem, size_of_val(dsm_ranges.as_ref()), command.dptr)?;
-                prp.read(&self.mem, dsm_ranges.as_mut_bytes())?;
+                let range = DataRange::parse(&self.mem, size_of_val(dsm_ranges.as_ref()), command)?;
+                range.read(&self.mem, dsm_ranges.as_mut_bytes())?;
                 tracing::debug!(nsid = self.nsid, ?cdw11, ?dsm_ranges, "dsm");
-                if cdw11.ad() {
+                if cdw11.ad() && self.disk.unmap_behavior() != UnmapBehavior::Ignored {
+                    // Validate all the ranges before deallocating any of them.
+                    let disk_sector_count = self.disk.sector_count();
                     for range in dsm_ranges.as_ref() {
+                        if disk_sector_count < range.starting_lba
+                            || disk_sector_count - range.starting_lba < range.lba_count.into()
+                        {
+                            return Err(spec::Status::LBA_OUT_OF_RANGE.into());
+                        }
+                    }
+                    for range in dsm_ranges.as_ref() {
+                        if range.lba_count == 0 {
+                            continue;
+                        }
                         self.disk
                             .unmap(range.starting_lba, range.lba_count.into(), false)
                             .await
@@ -199,6 +242,51 @@ pub async fn nvm_command(
                     }
                 }
             }
+            nvm::NvmOpcode::WRITE_ZEROES => {
+                let cdw12 = nvm::Cdw12WriteZeroes::from(command.cdw12);
+                let (lba, count) = self.lba_range(command, cdw12.nlb_z())?;
+                tracing::trace!(nsid = self.nsid, lba, count, ?cdw12, "write zeroes");
+                if self.disk.is_read_only() {
+                    return Err(spec::Status::ATTEMPTED_WRITE_TO_READ_ONLY_RANGE.into());
+                }
+                self.write_zeroes(lba, count, cdw12.fua())
+                    .await
+                    .map_err(map_disk_error)?;
+            }
+            nvm::NvmOpcode::COMPARE => {
+                let cdw12 = nvm::Cdw12ReadWrite::from(command.cdw12);
+                let (lba, count) = self.lba_range(command, cdw12.nlb_z())?;
+                let byte_count = (count as usize) << self.block_shift;
+                if byte_count > max_data_transfer_size {
+                    return Err(spec::Status::INVALID_FIELD_IN_COMMAND.into());
+                }
+                let range = DataRange::parse(&self.mem, byte_count, command)?;
+
+                tracing::trace!(nsid = self.nsid, lba, count, byte_count, "compare");
+
+                let mut expected = vec![0; byte_count];
+                range.read(&self.mem, &mut expected)?;
+                let actual = self.read_scratch(lba, count).await?;
+                if actual != expected {
+                    return Err(spec::Status::MEDIA_COMPARE_FAILURE.into());
+                }
+            }
+            nvm::NvmOpcode::VERIFY => {
+                let cdw12 = nvm::Cdw12ReadWrite::from(command.cdw12);
+                let (lba, count) = self.lba_range(command, cdw12.nlb_z())?;
+                tracing::trace!(nsid = self.nsid, lba, count, "verify");
+
+                // Reading the data is the only way to check that it is
+                // readable. Read it in chunks, since the data is discarded.
+                let chunk = (SCRATCH_BUFFER_SIZE >> self.block_shift).max(1) as u64;
+                let end = lba + count;
+                let mut lba = lba;
+                while lba < end {
+                    let count = chunk.min(end - lba);
+                    self.read_scratch(lba, count).await?;
+                    lba += count;
+                }
+            }
             nvm::NvmOpcode::RESERVATION_REGISTER if self.pr => {
                 self.reservation_register(self.disk.pr().unwrap(), command)
                     .await?
@@ -222,6 +310,76 @@ pub async fn nvm_command(
         }
         Ok(Default::default())
     }
+
+    /// Parses the starting LBA of an NVM command, along with the zero-based
+    /// block count `nlb_z`, and checks that the range is within the namespace.
+    fn lba_range(&self, command: &spec::Command, nlb_z: u16) -> Result<(u64, u64), NvmeError> {
+        let cdw10 = nvm::Cdw10ReadWrite::from(command.cdw10);
+        let cdw11 = nvm::Cdw11ReadWrite::from(command.cdw11);
+        let lba = cdw10.sbla_low() as u64 | ((cdw11.sbla_high() as u64) << 32);
+        let count = nlb_z as u64 + 1;
+        let disk_sector_count = self.disk.sector_count();
+        if disk_sector_count < lba || disk_sector_count - lba < count {
+            return Err(spec::Status::LBA_OUT_OF_RANGE.into());
+        }
+        Ok((lba, count))
+    }
+
+    /// Reads `count` blocks into a newly allocated buffer.
+    async fn read_scratch(&self, lba: u64, count: u64) -> Result<Vec<u8>, NvmeError> {
+        let len = (count as usize) << self.block_shift;
+        let mem = GuestMemory::allocate(len);
+        self.disk
+            .read_vectored(&OwnedRequestBuffers::linear(0, len, true).buffer(&mem), lba)
+            .await
+            .map_err(map_disk_error)?;
+        let mut buf = vec![0; len];
+        mem.read_at(0, &mut buf)
+            .map_err(|err| NvmeError::new(spec::Status::DATA_TRANSFER_ERROR, err))?;
+        Ok(buf)
+    }
+
+    /// Writes `data` from a newly allocated buffer.
+    async fn write_scratch(&self, lba: u64, data: &[u8], fua: bool) -> Result<(), NvmeError> {
+        let mem = GuestMemory::allocate(data.len());
+        mem.write_at(0, data)
+            .map_err(|err| NvmeError::new(spec::Status::DATA_TRANSFER_ERROR, err))?;
+        self.disk
+            .write_vectored(
+                &OwnedRequestBuffers::linear(0, data.len(), false).buffer(&mem),
+                lba,
+                fua,
+            )
+            .await
+            .map_err(map_disk_error)
+    }
+
+    /// Zeroes `count` blocks, by unmapping them if the disk guarantees that
+    /// unmapped blocks read as zero, or by writing zeroes otherwise.
+    async fn write_zeroes(&self, lba: u64, count: u64, fua: bool) -> Result<(), DiskError> {
+        if self.disk.unmap_behavior() == UnmapBehavior::Zeroes {
+            self.disk.unmap(lba, count, false).await?;
+            if fua {
+                self.disk.sync_cache().await?;
+            }
+            return Ok(());
+        }
+
+        let chunk = (SCRATCH_BUFFER_SIZE >> self.block_shift).max(1) as u64;
+        let zeroes = GuestMemory::allocate((chunk.min(count) as usize) << self.block_shift);
+        let end = lba + count;
+        let mut lba = lba;
+        while lba < end {
+            let count = chunk.min(end - lba);
+            let buffers =
+                OwnedRequestBuffers::linear(0, (count as usize) << self.block_shift, false);
+            self.disk
+                .write_vectored(&buffers.buffer(&zeroes), lba, fua)
+                .await?;
+            lba += count;
+        }
+        Ok(())
+    }
 }
 
 fn map_disk_error(err: disk_backend::DiskError) -> NvmeError {
@@ -6,7 +6,7 @@
 use super::Namespace;
 use super::map_disk_error;
 use crate::error::NvmeError;
-use crate::prp::PrpRange;
+use crate::prp::DataRange;
 use crate::spec;
 use crate::spec::nvm;
 use disk_backend::pr::PersistentReservation;
@@ -22,7 +22,7 @@ pub(super) async fn reservation_register(
     ) -> Result<(), NvmeError> {
         let cdw10 = nvm::Cdw10ReservationRegister::from(command.cdw10);
         let mut data = nvm::ReservationRegister::new_zeroed();
-        let range = PrpRange::parse(&self.mem, size_of_val(&data), command.dptr)?;
+        let range = DataRange::parse(&self.mem, size_of_val(&data), command)?;
         range.read(&self.mem, data.as_mut_bytes())?;
 
         let current_key = (!cdw10.iekey()).then_some(data.crkey);
@@ -70,7 +70,7 @@ pub(super) async fn reservation_report(
         let cdw11 = nvm::Cdw11ReservationReport::from(command.cdw11);
         let numd = cdw10.numd_z().saturating_add(1) as usize;
         let len = numd * 4;
-        let range = PrpRange::parse(&self.mem, len, command.dptr)?;
+        let range = DataRange::parse(&self.mem, len, command)?;
 
         let report = pr.report().await.map_err(map_disk_error)?;
 
@@ -134,7 +134,7 @@ pub(super) async fn reservation_acquire(
     ) -> Result<(), NvmeError> {
         let cdw10 = nvm::Cdw10ReservationAcquire::from(command.cdw10);
         let mut data = nvm::ReservationAcquire::new_zeroed();
-        let range = PrpRange::parse(&self.mem, size_of_val(&data), command.dptr)?;
+        let range = DataRange::parse(&self.mem, size_of_val(&data), command)?;
         range.read(&self.mem, data.as_mut_bytes())?;
 
         // According to the spec, this is never to be set.
@@ -175,7 +175,7 @@ pub(super) async fn reservation_release(
     ) -> Result<(), NvmeError> {
         let cdw10 = nvm::Cdw10ReservationRelease::from(command.cdw10);
         let mut data = nvm::ReservationRelease::new_zeroed();
-        let range = PrpRange::parse(&self.mem, size_of_val(&data), command.dptr)?;
+        let range = DataRange::parse(&self.mem, size_of_val(&data), command)?;
         range.read(&self.mem, data.as_mut_bytes())?;
 
         // According to the spec, this is never to be set.
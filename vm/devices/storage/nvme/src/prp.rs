@@ -1,7 +1,8 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
-//! Types for parsing NVMe PRP (Physical Region Page) entries and lists.
+//! Types for parsing NVMe data pointers, which describe guest memory either
+//! with PRP (Physical Region Page) entries and lists or with an SGL.
 
 use crate::PAGE_MASK;
 use crate::PAGE_SHIFT;
@@ -9,6 +10,7 @@
 #[cfg(test)]
 use crate::PAGE_SIZE64;
 use crate::error::NvmeError;
+use crate::sgl::SglRange;
 use crate::spec;
 use guestmem::GuestMemory;
 use guestmem::ranges::PagedRange;
@@ -58,6 +60,16 @@ impl PrpRange {
         Ok(Self { offset, len, pfns })
     }
 
+    /// Returns a range of `len` bytes starting `offset` bytes into the first
+    /// of `pfns`.
+    pub fn from_pfns(pfns: Vec<u64>, offset: usize, len: usize) -> Self {
+        Self {
+            offset,
+            len,
+            pfns: PrpPfns::Long(pfns),
+        }
+    }
+
     #[cfg(test)]
     pub fn new(mut gpas: Vec<u64>, offset: usize, len: u64) -> Result<Self, &'static str> {
         for gpa in &mut gpas {
@@ -122,3 +134,58 @@ pub fn zero(&self, mem: &GuestMemory, len: usize) -> Result<(), NvmeError> {
         Ok(())
     }
 }
+
+/// Guest memory described by a command's data pointer.
+pub enum DataRange {
+    /// Memory that can be described by pages, from PRPs or an SGL.
+    Paged(PrpRange),
+    /// Memory from an SGL whose data blocks cannot be described by pages.
+    Scattered(SglRange),
+}
+
+impl DataRange {
+    /// Parses the data pointer of `command`, for memory of `len` bytes.
+    pub fn parse(
+        mem: &GuestMemory,
+        len: usize,
+        command: &spec::Command,
+    ) -> Result<Self, NvmeError> {
+        match spec::Psdt(command.cdw0.psdt()) {
+            spec::Psdt::PRP => Ok(Self::Paged(PrpRange::parse(mem, len, command.dptr)?)),
+            // There is no metadata, so the metadata pointer is ignored.
+            spec::Psdt::SGL_CONTIGUOUS_METADATA | spec::Psdt::SGL_METADATA_SGL => {
+                let sgl = SglRange::parse(mem, len, command.dptr)?;
+                Ok(match sgl.to_prp_range() {
+                    Some(range) => Self::Paged(range),
+                    None => Self::Scattered(sgl),
+                })
+            }
+            _ => Err(spec::Status::INVALID_FIELD_IN_COMMAND.into()),
+        }
+    }
+
+    /// Returns the range as a [`PagedRange`], if it can be described by
+    /// pages.
+    pub fn paged(&self) -> Option<PagedRange<'_>> {
+        match self {
+            DataRange::Paged(range) => Some(range.range()),
+            DataRange::Scattered(_) => None,
+        }
+    }
+
+    /// Reads from the range.
+    pub fn read(&self, mem: &GuestMemory, buf: &mut [u8]) -> Result<(), NvmeError> {
+        match self {
+            DataRange::Paged(range) => range.read(mem, buf),
+            DataRange::Scattered(range) => range.read(mem, buf),
+        }
+    }
+
+    /// Writes to the range.
+    pub fn write(&self, mem: &GuestMemory, buf: &[u8]) -> Result<(), NvmeError> {
+        match self {
+            DataRange::Paged(range) => range.write(mem, buf),
+            DataRange::Scattered(range) => range.write(mem, buf),
+        }
+    }
+}
@@ -37,6 +37,12 @@ pub enum Error {
         #[source]
         source: ResolveError,
     },
+    #[error("failed to resolve namespace pool disk {index}")]
+    PoolDiskResolve {
+        index: usize,
+        #[source]
+        source: ResolveError,
+    },
     #[error(transparent)]
     NsidConflict(NsidConflict),
 }
@@ -85,6 +91,19 @@ async fn resolve(
                 .await
                 .map_err(Error::NsidConflict)?;
         }
+        for (index, disk) in resource.namespace_pool.into_iter().enumerate() {
+            let disk = resolver
+                .resolve(
+                    disk,
+                    ResolveDiskParameters {
+                        read_only: false,
+                        driver_source: input.driver_source,
+                    },
+                )
+                .await
+                .map_err(|source| Error::PoolDiskResolve { index, source })?;
+            controller.client().add_pool_disk(disk.0).await;
+        }
         Ok(controller.into())
     }
 }
@@ -0,0 +1,168 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Types for parsing NVMe SGLs (Scatter Gather Lists).
+
+use crate::PAGE_SHIFT;
+use crate::PAGE_SIZE;
+use crate::PAGE_SIZE64;
+use crate::error::NvmeError;
+use crate::prp::PrpRange;
+use crate::spec;
+use guestmem::GuestMemory;
+use zerocopy::FromBytes;
+use zerocopy::FromZeros;
+use zerocopy::IntoBytes;
+
+/// The maximum number of descriptors to process for a single command, to
+/// bound the work done for a malicious or looping SGL.
+const MAX_DESCRIPTORS: usize = PAGE_SIZE * 4 / size_of::<spec::SglDescriptor>();
+
+/// A contiguous range of guest memory described by a data block descriptor.
+#[derive(Debug, Copy, Clone)]
+struct DataBlock {
+    gpa: u64,
+    len: usize,
+}
+
+/// Guest memory described by an SGL.
+pub struct SglRange {
+    blocks: Vec<DataBlock>,
+    len: usize,
+}
+
+impl SglRange {
+    /// Parses an SGL for memory of `len` bytes, from the SGL descriptor in
+    /// `dptr`.
+    ///
+    /// Data block, segment, and last segment descriptors are supported. The
+    /// total length of the data blocks must be exactly `len`.
+    pub fn parse(mem: &GuestMemory, len: usize, dptr: [u64; 2]) -> Result<Self, NvmeError> {
+        let mut segment = vec![spec::SglDescriptor::read_from_bytes(dptr.as_bytes()).unwrap()];
+        let mut last_segment = false;
+        let mut blocks = Vec::new();
+        let mut total = 0;
+        let mut processed = 0;
+        loop {
+            let mut next = None;
+            for (i, desc) in segment.iter().enumerate() {
+                processed += 1;
+                if processed > MAX_DESCRIPTORS {
+                    return Err(spec::Status::INVALID_NUMBER_OF_SGL_DESCRIPTORS.into());
+                }
+                if spec::SglDescriptorSubtype(desc.identifier.subtype())
+                    != spec::SglDescriptorSubtype::ADDRESS
+                {
+                    return Err(spec::Status::SGL_DESCRIPTOR_TYPE_INVALID.into());
+                }
+                match spec::SglDescriptorType(desc.identifier.descriptor_type()) {
+                    spec::SglDescriptorType::DATA_BLOCK => {
+                        let block_len = desc.length as usize;
+                        total += block_len;
+                        if total > len {
+                            return Err(spec::Status::DATA_SGL_LENGTH_INVALID.into());
+                        }
+                        if block_len != 0 {
+                            blocks.push(DataBlock {
+                                gpa: desc.address,
+                                len: block_len,
+                            });
+                        }
+                    }
+                    ty @ (spec::SglDescriptorType::SEGMENT
+                    | spec::SglDescriptorType::LAST_SEGMENT) => {
+                        // A segment descriptor must be the last descriptor in
+                        // its segment, and the last segment must not chain to
+                        // another.
+                        if last_segment
+                            || i != segment.len() - 1
+                            || desc.length == 0
+                            || desc.length as usize % size_of::<spec::SglDescriptor>() != 0
+                        {
+                            return Err(spec::Status::INVALID_SGL_SEGMENT_DESCRIPTOR.into());
+                        }
+                        last_segment = ty == spec::SglDescriptorType::LAST_SEGMENT;
+                        next = Some(*desc);
+                    }
+                    ty => {
+                        tracelimit::warn_ratelimited!(?ty, "unsupported sgl descriptor type");
+                        return Err(spec::Status::SGL_DESCRIPTOR_TYPE_INVALID.into());
+                    }
+                }
+            }
+
+            let Some(desc) = next else {
+                break;
+            };
+            let count = desc.length as usize / size_of::<spec::SglDescriptor>();
+            if processed + count > MAX_DESCRIPTORS {
+                return Err(spec::Status::INVALID_NUMBER_OF_SGL_DESCRIPTORS.into());
+            }
+            segment = vec![spec::SglDescriptor::new_zeroed(); count];
+            mem.read_at(desc.address, segment.as_mut_bytes())
+                .map_err(|err| NvmeError::new(spec::Status::DATA_TRANSFER_ERROR, err))?;
+        }
+
+        if total != len {
+            return Err(spec::Status::DATA_SGL_LENGTH_INVALID.into());
+        }
+        Ok(Self { blocks, len })
+    }
+
+    /// Returns the equivalent PRP range, if the data blocks can be described
+    /// by pages. This is the case when each block but the first starts on a
+    /// page boundary, and each block but the last ends on one.
+    pub fn to_prp_range(&self) -> Option<PrpRange> {
+        let first = self.blocks.first()?;
+        let last_index = self.blocks.len() - 1;
+        let mut pfns = Vec::new();
+        for (i, block) in self.blocks.iter().enumerate() {
+            let end = block.gpa.checked_add(block.len as u64)?;
+            if (i != 0 && block.gpa % PAGE_SIZE64 != 0)
+                || (i != last_index && end % PAGE_SIZE64 != 0)
+            {
+                return None;
+            }
+            pfns.extend((block.gpa >> PAGE_SHIFT)..end.div_ceil(PAGE_SIZE64));
+        }
+        Some(PrpRange::from_pfns(
+            pfns,
+            (first.gpa % PAGE_SIZE64) as usize,
+            self.len,
+        ))
+    }
+
+    /// Returns the data blocks covering the first `len` bytes of the range.
+    fn blocks(&self, len: usize) -> impl Iterator<Item = DataBlock> + '_ {
+        self.blocks.iter().scan(len, |remaining, block| {
+            let len = block.len.min(*remaining);
+            *remaining -= len;
+            (len != 0).then_some(DataBlock {
+                gpa: block.gpa,
+                len,
+            })
+        })
+    }
+
+    /// Reads from the range.
+    pub fn read(&self, mem: &GuestMemory, mut buf: &mut [u8]) -> Result<(), NvmeError> {
+        for block in self.blocks(buf.len()) {
+            let (this, rest) = std::mem::take(&mut buf).split_at_mut(block.len);
+            mem.read_at(block.gpa, this)
+                .map_err(|err| NvmeError::new(spec::Status::DATA_TRANSFER_ERROR, err))?;
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    /// Writes to the range.
+    pub fn write(&self, mem: &GuestMemory, mut buf: &[u8]) -> Result<(), NvmeError> {
+        for block in self.blocks(buf.len()) {
+            let (this, rest) = buf.split_at(block.len);
+            mem.write_at(block.gpa, this)
+                .map_err(|err| NvmeError::new(spec::Status::DATA_TRANSFER_ERROR, err))?;
+            buf = rest;
+        }
+        Ok(())
+    }
+}
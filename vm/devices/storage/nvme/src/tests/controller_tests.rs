@@ -326,3 +326,152 @@ async fn test_send_identify(driver: DefaultDriver) {
     let cqe = read_completion_from_queue(&gm, &dm1, 0);
     assert_eq!(cqe.status.status(), spec::Status::SUCCESS.0);
 }
+
+fn write_admin_command(
+    nvmec: &mut NvmeController,
+    gm: &GuestMemory,
+    asq: &PrpRange,
+    slot: usize,
+    command: &spec::Command,
+) {
+    write_command_to_queue(gm, asq, slot, command);
+    nvmec
+        .write_bar0(0x1000, ((slot + 1) as u32).as_bytes())
+        .unwrap();
+}
+
+async fn wait_for_completion(
+    driver: &DefaultDriver,
+    gm: &GuestMemory,
+    acq: &PrpRange,
+    slot: usize,
+) -> spec::Completion {
+    let mut backoff = Backoff::new(driver);
+    for _ in 0..1000 {
+        let cqe = read_completion_from_queue(gm, acq, slot);
+        if cqe.status.phase() {
+            return cqe;
+        }
+        backoff.back_off().await;
+    }
+    panic!("no completion in slot {slot}");
+}
+
+#[async_test]
+async fn test_namespace_management(driver: DefaultDriver) {
+    const DATA_GPA: u64 = 0x2000;
+    let acq = PrpRange::new(vec![0], 0, PAGE_SIZE64).unwrap();
+    let asq = PrpRange::new(vec![0x1000], 0, PAGE_SIZE64).unwrap();
+    let gm = test_memory();
+
+    let mut nvmec =
+        instantiate_and_build_admin_queue(&acq, 64, &asq, 64, false, None, driver.clone(), &gm)
+            .await;
+    nvmec
+        .client()
+        .add_pool_disk(disklayer_ram::ram_disk(512 * 1024, false).unwrap())
+        .await;
+
+    // Queue an asynchronous event request to observe the attach.
+    let mut aer = spec::Command::new_zeroed();
+    aer.cdw0
+        .set_opcode(spec::AdminOpcode::ASYNCHRONOUS_EVENT_REQUEST.0);
+    aer.cdw0.set_cid(100);
+    write_admin_command(&mut nvmec, &gm, &asq, 0, &aer);
+
+    // Create a namespace from the pool.
+    let mut id = spec::nvm::IdentifyNamespace::new_zeroed();
+    id.nsze = 1024;
+    id.ncap = 1024;
+    gm.write_at(DATA_GPA, id.as_bytes()).unwrap();
+    let mut create = spec::Command::new_zeroed();
+    create
+        .cdw0
+        .set_opcode(spec::AdminOpcode::NAMESPACE_MANAGEMENT.0);
+    create.cdw10 = spec::Cdw10NamespaceManagement::new()
+        .with_sel(spec::NamespaceManagementSelect::CREATE.0)
+        .into();
+    create.dptr[0] = DATA_GPA;
+    write_admin_command(&mut nvmec, &gm, &asq, 1, &create);
+    let cqe = wait_for_completion(&driver, &gm, &acq, 0).await;
+    assert_eq!(cqe.status.status(), spec::Status::SUCCESS.0);
+    let nsid = cqe.dw0;
+    assert_eq!(nsid, 1);
+
+    // Attach it, which should complete the asynchronous event request.
+    let mut controllers = spec::ControllerList::new_zeroed();
+    controllers.count = 1;
+    gm.write_at(DATA_GPA, controllers.as_bytes()).unwrap();
+    let mut attach = spec::Command::new_zeroed();
+    attach
+        .cdw0
+        .set_opcode(spec::AdminOpcode::NAMESPACE_ATTACHMENT.0);
+    attach.nsid = nsid;
+    attach.cdw10 = spec::Cdw10NamespaceAttachment::new()
+        .with_sel(spec::NamespaceAttachmentSelect::ATTACH.0)
+        .into();
+    attach.dptr[0] = DATA_GPA;
+    write_admin_command(&mut nvmec, &gm, &asq, 2, &attach);
+    let cqe = wait_for_completion(&driver, &gm, &acq, 1).await;
+    assert_eq!(cqe.status.status(), spec::Status::SUCCESS.0);
+    let cqe = wait_for_completion(&driver, &gm, &acq, 2).await;
+    assert_eq!(cqe.cid, 100);
+    let dw0 = spec::AsynchronousEventRequestDw0::from(cqe.dw0);
+    assert_eq!(
+        dw0.log_page_identifier(),
+        spec::LogPageIdentifier::CHANGED_NAMESPACE_LIST.0
+    );
+
+    // Attaching again fails.
+    write_admin_command(&mut nvmec, &gm, &asq, 3, &attach);
+    let cqe = wait_for_completion(&driver, &gm, &acq, 3).await;
+    assert_eq!(
+        cqe.status.status(),
+        spec::Status::NAMESPACE_ALREADY_ATTACHED.0
+    );
+
+    // The namespace is now active.
+    let mut identify = spec::Command::new_zeroed();
+    identify.cdw0.set_opcode(spec::AdminOpcode::IDENTIFY.0);
+    identify.cdw10 = spec::Cdw10Identify::new()
+        .with_cns(spec::Cns::ACTIVE_NAMESPACES.0)
+        .into();
+    identify.dptr[0] = DATA_GPA;
+    write_admin_command(&mut nvmec, &gm, &asq, 4, &identify);
+    let cqe = wait_for_completion(&driver, &gm, &acq, 4).await;
+    assert_eq!(cqe.status.status(), spec::Status::SUCCESS.0);
+    assert_eq!(gm.read_plain::<[u32; 2]>(DATA_GPA).unwrap(), [nsid, 0]);
+
+    // Deleting the namespace detaches it and returns the disk to the pool, so
+    // it can be created again.
+    let mut delete = create;
+    delete.nsid = nsid;
+    delete.cdw10 = spec::Cdw10NamespaceManagement::new()
+        .with_sel(spec::NamespaceManagementSelect::DELETE.0)
+        .into();
+    write_admin_command(&mut nvmec, &gm, &asq, 5, &delete);
+    let cqe = wait_for_completion(&driver, &gm, &acq, 5).await;
+    assert_eq!(cqe.status.status(), spec::Status::SUCCESS.0);
+
+    gm.write_at(DATA_GPA, controllers.as_bytes()).unwrap();
+    write_admin_command(&mut nvmec, &gm, &asq, 6, &attach);
+    let cqe = wait_for_completion(&driver, &gm, &acq, 6).await;
+    assert_eq!(
+        cqe.status.status(),
+        spec::Status::INVALID_NAMESPACE_OR_FORMAT.0
+    );
+
+    gm.write_at(DATA_GPA, id.as_bytes()).unwrap();
+    write_admin_command(&mut nvmec, &gm, &asq, 7, &create);
+    let cqe = wait_for_completion(&driver, &gm, &acq, 7).await;
+    assert_eq!(cqe.status.status(), spec::Status::SUCCESS.0);
+    assert_eq!(cqe.dw0, nsid);
+
+    // There is no disk left in the pool.
+    write_admin_command(&mut nvmec, &gm, &asq, 8, &create);
+    let cqe = wait_for_completion(&driver, &gm, &acq, 8).await;
+    assert_eq!(
+        cqe.status.status(),
+        spec::Status::NAMESPACE_IDENTIFIER_UNAVAILABLE.0
+    );
+}
@@ -0,0 +1,187 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use crate::namespace::Namespace;
+use crate::spec;
+use crate::spec::nvm;
+use crate::tests::test_helpers::DATA_GPA;
+use crate::tests::test_helpers::SECTOR_SIZE;
+use crate::tests::test_helpers::io_command;
+use crate::tests::test_helpers::namespace;
+use crate::tests::test_helpers::ram_disk;
+use crate::tests::test_helpers::run;
+use crate::tests::test_helpers::test_memory;
+use disk_backend::Disk;
+use disk_backend::DiskError;
+use disk_backend::DiskIo;
+use disk_backend::UnmapBehavior;
+use inspect::Inspect;
+use pal_async::async_test;
+use scsi_buffers::RequestBuffers;
+
+/// A disk that ignores unmaps, reporting the given unmap behavior.
+#[derive(Inspect)]
+struct NoUnmapDisk {
+    inner: Disk,
+    unmap_behavior: UnmapBehavior,
+}
+
+impl DiskIo for NoUnmapDisk {
+    fn disk_type(&self) -> &str {
+        "no_unmap"
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.inner.sector_count()
+    }
+
+    fn sector_size(&self) -> u32 {
+        self.inner.sector_size()
+    }
+
+    fn disk_id(&self) -> Option<[u8; 16]> {
+        self.inner.disk_id()
+    }
+
+    fn physical_sector_size(&self) -> u32 {
+        self.inner.physical_sector_size()
+    }
+
+    fn is_fua_respected(&self) -> bool {
+        self.inner.is_fua_respected()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.inner.is_read_only()
+    }
+
+    async fn read_vectored(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+    ) -> Result<(), DiskError> {
+        self.inner.read_vectored(buffers, sector).await
+    }
+
+    async fn write_vectored(
+        &self,
+        buffers: &RequestBuffers<'_>,
+        sector: u64,
+        fua: bool,
+    ) -> Result<(), DiskError> {
+        self.inner.write_vectored(buffers, sector, fua).await
+    }
+
+    async fn sync_cache(&self) -> Result<(), DiskError> {
+        self.inner.sync_cache().await
+    }
+
+    async fn unmap(
+        &self,
+        _sector: u64,
+        _count: u64,
+        _block_level_only: bool,
+    ) -> Result<(), DiskError> {
+        Ok(())
+    }
+
+    fn unmap_behavior(&self) -> UnmapBehavior {
+        self.unmap_behavior
+    }
+}
+
+#[async_test]
+async fn test_write_zeroes() {
+    let gm = test_memory();
+    let ns = namespace(&gm);
+
+    gm.fill_at(DATA_GPA, 0xcc, SECTOR_SIZE * 4).unwrap();
+    run(&ns, &io_command(nvm::NvmOpcode::WRITE, 0, 4))
+        .await
+        .unwrap();
+    run(&ns, &io_command(nvm::NvmOpcode::WRITE_ZEROES, 1, 2))
+        .await
+        .unwrap();
+
+    gm.fill_at(DATA_GPA, 0xff, SECTOR_SIZE * 4).unwrap();
+    run(&ns, &io_command(nvm::NvmOpcode::READ, 0, 4))
+        .await
+        .unwrap();
+    let mut data = vec![0; SECTOR_SIZE * 4];
+    gm.read_at(DATA_GPA, &mut data).unwrap();
+    assert!(data[..SECTOR_SIZE].iter().all(|&b| b == 0xcc));
+    assert!(data[SECTOR_SIZE..SECTOR_SIZE * 3].iter().all(|&b| b == 0));
+    assert!(data[SECTOR_SIZE * 3..].iter().all(|&b| b == 0xcc));
+
+    assert_eq!(
+        run(&ns, &io_command(nvm::NvmOpcode::WRITE_ZEROES, 63, 2)).await,
+        Err(spec::Status::LBA_OUT_OF_RANGE)
+    );
+}
+
+#[async_test]
+async fn test_write_zeroes_without_unmap() {
+    for unmap_behavior in [UnmapBehavior::Unspecified, UnmapBehavior::Ignored] {
+        let gm = test_memory();
+        let disk = Disk::new(NoUnmapDisk {
+            inner: ram_disk(),
+            unmap_behavior,
+        })
+        .unwrap();
+        let ns = Namespace::new(gm.clone(), 1, disk);
+
+        // Unmapping would leave the data in place, so the zeroes must be
+        // written.
+        gm.fill_at(DATA_GPA, 0xcc, SECTOR_SIZE * 4).unwrap();
+        run(&ns, &io_command(nvm::NvmOpcode::WRITE, 0, 4))
+            .await
+            .unwrap();
+        run(&ns, &io_command(nvm::NvmOpcode::WRITE_ZEROES, 1, 2))
+            .await
+            .unwrap();
+
+        gm.fill_at(DATA_GPA, 0xff, SECTOR_SIZE * 4).unwrap();
+        run(&ns, &io_command(nvm::NvmOpcode::READ, 0, 4))
+            .await
+            .unwrap();
+        let mut data = vec![0; SECTOR_SIZE * 4];
+        gm.read_at(DATA_GPA, &mut data).unwrap();
+        assert!(data[..SECTOR_SIZE].iter().all(|&b| b == 0xcc));
+        assert!(data[SECTOR_SIZE..SECTOR_SIZE * 3].iter().all(|&b| b == 0));
+        assert!(data[SECTOR_SIZE * 3..].iter().all(|&b| b == 0xcc));
+    }
+}
+
+#[async_test]
+async fn test_compare() {
+    let gm = test_memory();
+    let ns = namespace(&gm);
+
+    gm.fill_at(DATA_GPA, 0x5a, SECTOR_SIZE * 2).unwrap();
+    run(&ns, &io_command(nvm::NvmOpcode::WRITE, 8, 2))
+        .await
+        .unwrap();
+    run(&ns, &io_command(nvm::NvmOpcode::COMPARE, 8, 2))
+        .await
+        .unwrap();
+
+    gm.fill_at(DATA_GPA + SECTOR_SIZE as u64 + 7, 0, 1).unwrap();
+    assert_eq!(
+        run(&ns, &io_command(nvm::NvmOpcode::COMPARE, 8, 2)).await,
+        Err(spec::Status::MEDIA_COMPARE_FAILURE)
+    );
+}
+
+#[async_test]
+async fn test_verify() {
+    let gm = test_memory();
+    let ns = namespace(&gm);
+
+    run(&ns, &io_command(nvm::NvmOpcode::VERIFY, 0, 64))
+        .await
+        .unwrap();
+    assert_eq!(
+        run(&ns, &io_command(nvm::NvmOpcode::VERIFY, 64, 1)).await,
+        Err(spec::Status::LBA_OUT_OF_RANGE)
+    );
+}
@@ -0,0 +1,212 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+use crate::spec;
+use crate::spec::nvm;
+use crate::tests::test_helpers::SECTOR_SIZE;
+use crate::tests::test_helpers::io_command;
+use crate::tests::test_helpers::namespace;
+use crate::tests::test_helpers::run;
+use crate::tests::test_helpers::test_memory;
+use guestmem::GuestMemory;
+use pal_async::async_test;
+use zerocopy::IntoBytes;
+
+const SEGMENT_GPA: u64 = 0x1000;
+const DATA_GPA: u64 = 0x4000;
+
+fn descriptor(ty: spec::SglDescriptorType, address: u64, length: u32) -> spec::SglDescriptor {
+    spec::SglDescriptor {
+        address,
+        length,
+        rsvd: [0; 3],
+        identifier: spec::SglIdentifier::new().with_descriptor_type(ty.0),
+    }
+}
+
+fn data_block(address: u64, length: u32) -> spec::SglDescriptor {
+    descriptor(spec::SglDescriptorType::DATA_BLOCK, address, length)
+}
+
+/// Writes `descriptors` to the segment area and returns a last segment
+/// descriptor pointing at them.
+fn last_segment(gm: &GuestMemory, descriptors: &[spec::SglDescriptor]) -> spec::SglDescriptor {
+    gm.write_at(SEGMENT_GPA, descriptors.as_bytes()).unwrap();
+    descriptor(
+        spec::SglDescriptorType::LAST_SEGMENT,
+        SEGMENT_GPA,
+        size_of_val(descriptors) as u32,
+    )
+}
+
+/// Returns an NVM command whose data is described by `sgl` instead of PRPs.
+fn sgl_command(
+    opcode: nvm::NvmOpcode,
+    lba: u64,
+    count: u16,
+    sgl: spec::SglDescriptor,
+) -> spec::Command {
+    let mut command = io_command(opcode, lba, count);
+    command.cdw0.set_psdt(spec::Psdt::SGL_CONTIGUOUS_METADATA.0);
+    command.dptr.as_mut_bytes().copy_from_slice(sgl.as_bytes());
+    command
+}
+
+#[async_test]
+async fn test_sgl_data_block() {
+    let gm = test_memory();
+    let ns = namespace(&gm);
+
+    // A single data block at an unaligned address.
+    let gpa = DATA_GPA + 0x123;
+    let len = SECTOR_SIZE * 10;
+    let data = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+    gm.write_at(gpa, &data).unwrap();
+    run(
+        &ns,
+        &sgl_command(nvm::NvmOpcode::WRITE, 4, 10, data_block(gpa, len as u32)),
+    )
+    .await
+    .unwrap();
+
+    gm.fill_at(gpa, 0, len).unwrap();
+    run(
+        &ns,
+        &sgl_command(nvm::NvmOpcode::READ, 4, 10, data_block(gpa, len as u32)),
+    )
+    .await
+    .unwrap();
+    let mut read = vec![0; len];
+    gm.read_at(gpa, &mut read).unwrap();
+    assert_eq!(read, data);
+}
+
+#[async_test]
+async fn test_sgl_segments() {
+    let gm = test_memory();
+    let ns = namespace(&gm);
+
+    // Blocks that cannot be described by pages, chained through a segment
+    // and a last segment.
+    let blocks = [
+        (DATA_GPA + 0x10, 0x300u32),
+        (DATA_GPA + 0x2000 + 0x20, 0x100),
+        (DATA_GPA + 0x4000 + 0x30, 0x200),
+    ];
+    let last = last_segment(&gm, &[data_block(blocks[2].0, blocks[2].1)]);
+    let segment_gpa = SEGMENT_GPA + 0x800;
+    let segment = [
+        data_block(blocks[0].0, blocks[0].1),
+        data_block(blocks[1].0, blocks[1].1),
+        last,
+    ];
+    gm.write_at(segment_gpa, segment.as_bytes()).unwrap();
+    let sgl = descriptor(
+        spec::SglDescriptorType::SEGMENT,
+        segment_gpa,
+        size_of_val(&segment) as u32,
+    );
+
+    for (i, &(gpa, len)) in blocks.iter().enumerate() {
+        gm.fill_at(gpa, i as u8 + 1, len as usize).unwrap();
+    }
+    run(&ns, &sgl_command(nvm::NvmOpcode::WRITE, 0, 3, sgl))
+        .await
+        .unwrap();
+
+    // Read the data back linearly.
+    run(
+        &ns,
+        &sgl_command(
+            nvm::NvmOpcode::READ,
+            0,
+            3,
+            data_block(DATA_GPA + 0x8000, SECTOR_SIZE as u32 * 3),
+        ),
+    )
+    .await
+    .unwrap();
+    let mut read = vec![0; SECTOR_SIZE * 3];
+    gm.read_at(DATA_GPA + 0x8000, &mut read).unwrap();
+    let mut expected = Vec::new();
+    for (i, &(_, len)) in blocks.iter().enumerate() {
+        expected.extend(std::iter::repeat_n(i as u8 + 1, len as usize));
+    }
+    assert_eq!(read, expected);
+
+    // And through the SGL again.
+    for &(gpa, len) in &blocks {
+        gm.fill_at(gpa, 0, len as usize).unwrap();
+    }
+    run(&ns, &sgl_command(nvm::NvmOpcode::READ, 0, 3, sgl))
+        .await
+        .unwrap();
+    for (i, &(gpa, len)) in blocks.iter().enumerate() {
+        let mut read = vec![0; len as usize];
+        gm.read_at(gpa, &mut read).unwrap();
+        assert!(read.iter().all(|&b| b == i as u8 + 1));
+    }
+}
+
+#[async_test]
+async fn test_sgl_invalid() {
+    let gm = test_memory();
+    let ns = namespace(&gm);
+
+    // Too short.
+    assert_eq!(
+        run(
+            &ns,
+            &sgl_command(
+                nvm::NvmOpcode::READ,
+                0,
+                2,
+                data_block(DATA_GPA, SECTOR_SIZE as u32)
+            )
+        )
+        .await,
+        Err(spec::Status::DATA_SGL_LENGTH_INVALID)
+    );
+
+    // Too long.
+    assert_eq!(
+        run(
+            &ns,
+            &sgl_command(
+                nvm::NvmOpcode::READ,
+                0,
+                1,
+                data_block(DATA_GPA, SECTOR_SIZE as u32 * 2)
+            )
+        )
+        .await,
+        Err(spec::Status::DATA_SGL_LENGTH_INVALID)
+    );
+
+    // Bit buckets are not supported.
+    let sgl = descriptor(spec::SglDescriptorType::BIT_BUCKET, 0, SECTOR_SIZE as u32);
+    assert_eq!(
+        run(&ns, &sgl_command(nvm::NvmOpcode::READ, 0, 1, sgl)).await,
+        Err(spec::Status::SGL_DESCRIPTOR_TYPE_INVALID)
+    );
+
+    // A segment descriptor must be last in its segment.
+    let segment = last_segment(&gm, &[data_block(DATA_GPA, SECTOR_SIZE as u32)]);
+    let sgl = last_segment(&gm, &[segment, data_block(DATA_GPA, 0)]);
+    assert_eq!(
+        run(&ns, &sgl_command(nvm::NvmOpcode::READ, 0, 1, sgl)).await,
+        Err(spec::Status::INVALID_SGL_SEGMENT_DESCRIPTOR)
+    );
+
+    // A segment that refers to itself is eventually rejected.
+    let sgl = descriptor(
+        spec::SglDescriptorType::SEGMENT,
+        SEGMENT_GPA,
+        size_of::<spec::SglDescriptor>() as u32,
+    );
+    gm.write_at(SEGMENT_GPA, sgl.as_bytes()).unwrap();
+    assert_eq!(
+        run(&ns, &sgl_command(nvm::NvmOpcode::READ, 0, 1, sgl)).await,
+        Err(spec::Status::INVALID_NUMBER_OF_SGL_DESCRIPTORS)
+    );
+}
@@ -5,16 +5,27 @@
 
 use crate::PAGE_SIZE;
 use crate::PAGE_SIZE64;
+use crate::error::CommandResult;
+use crate::namespace::Namespace;
 use crate::prp::PrpRange;
 use crate::spec;
+use crate::spec::nvm;
 use chipset_device::mmio::ControlMmioIntercept;
 use chipset_device::mmio::RegisterMmioIntercept;
+use disk_backend::Disk;
 use guestmem::GuestMemory;
 use parking_lot::Mutex;
 use pci_core::msi::MsiControl;
 use pci_core::msi::MsiInterruptTarget;
 use std::collections::VecDeque;
 use std::sync::Arc;
+use zerocopy::FromZeros;
+
+/// The sector size of the disk returned by [`ram_disk`].
+pub const SECTOR_SIZE: usize = 512;
+
+/// The guest address of the data buffer for [`io_command`].
+pub const DATA_GPA: u64 = 0x1000;
 
 /// A test-only interrupt controller that simply stashes incoming interrupt
 /// requests in a FIFO queue. Implements [`MsiInterruptTarget`].
@@ -148,3 +159,36 @@ pub fn read_completion_from_queue(
 
     gm.read_plain::<spec::Completion>(gpa).unwrap()
 }
+
+/// Returns a 64-sector RAM disk.
+pub fn ram_disk() -> Disk {
+    disklayer_ram::ram_disk(SECTOR_SIZE as u64 * 64, false).unwrap()
+}
+
+/// Returns namespace 1, backed by a [`ram_disk`].
+pub fn namespace(gm: &GuestMemory) -> Namespace {
+    Namespace::new(gm.clone(), 1, ram_disk())
+}
+
+/// Returns an NVM command for `count` blocks at `lba` of namespace 1, with
+/// its data at [`DATA_GPA`].
+pub fn io_command(opcode: nvm::NvmOpcode, lba: u64, count: u16) -> spec::Command {
+    let mut command = spec::Command::new_zeroed();
+    command.cdw0.set_opcode(opcode.0);
+    command.nsid = 1;
+    command.dptr[0] = DATA_GPA;
+    command.cdw10 = nvm::Cdw10ReadWrite::new().with_sbla_low(lba as u32).into();
+    command.cdw11 = nvm::Cdw11ReadWrite::new()
+        .with_sbla_high((lba >> 32) as u32)
+        .into();
+    command.cdw12 = nvm::Cdw12ReadWrite::new().with_nlb_z(count - 1).into();
+    command
+}
+
+/// Runs an NVM command, returning its status if it fails.
+pub async fn run(ns: &Namespace, command: &spec::Command) -> Result<(), spec::Status> {
+    ns.nvm_command(PAGE_SIZE * 4, command)
+        .await
+        .map(drop)
+        .map_err(|err| CommandResult::from(err).status)
+}
@@ -35,6 +35,7 @@
 use parking_lot::Mutex;
 use parking_lot::RwLock;
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::btree_map;
 use std::future::pending;
 use std::future::poll_fn;
@@ -58,6 +59,8 @@
 const IOCQES: u8 = 4;
 const MAX_ASYNC_EVENT_REQUESTS: u8 = 4; // minimum recommended by spec
 const ERROR_LOG_PAGE_ENTRIES: u8 = 1;
+/// The ID of this controller, which is the only one in its subsystem.
+const CONTROLLER_ID: u16 = 0;
 
 #[derive(Inspect)]
 pub struct AdminConfig {
@@ -82,6 +85,21 @@ pub struct AdminHandler {
     config: AdminConfig,
     #[inspect(iter_by_key)]
     namespaces: BTreeMap<u32, Arc<Namespace>>,
+    /// Disks that the guest can create namespaces on with the Namespace
+    /// Management command.
+    #[inspect(iter_by_index)]
+    namespace_pool: Vec<Disk>,
+    /// Namespaces created from the pool, whether or not they are attached.
+    #[inspect(iter_by_key)]
+    allocated_namespaces: BTreeMap<u32, PoolNamespace>,
+}
+
+#[derive(Inspect)]
+struct PoolNamespace {
+    namespace: Arc<Namespace>,
+    /// The pool disk, to return to the pool when the namespace is deleted.
+    #[inspect(skip)]
+    disk: Disk,
 }
 
 #[derive(Inspect)]
@@ -276,12 +294,6 @@ async fn remove_namespace(&mut self, nsid: u32) {
 
         // Notify the guest driver of the change.
         self.add_changed_namespace(nsid);
-
-        self.poll_namespace_change
-            .remove(&nsid)
-            .unwrap()
-            .cancel()
-            .await;
     }
 }
 
@@ -303,6 +315,16 @@ fn spawn_namespace_notifier(
     })
 }
 
+/// Writes a controller list with the IDs of this subsystem's controllers that
+/// are at least `min_cntid`.
+fn write_controller_list(buf: &mut [u8], min_cntid: u16) {
+    let list = spec::ControllerList::mut_from_bytes(buf).unwrap();
+    for id in [CONTROLLER_ID].into_iter().filter(|&id| id >= min_cntid) {
+        list.ids[list.count as usize] = id;
+        list.count += 1;
+    }
+}
+
 #[derive(Debug, Error)]
 #[error("invalid queue identifier {qid}")]
 struct InvalidQueueIdentifier {
@@ -344,15 +366,25 @@ pub fn new(driver: VmTaskDriver, config: AdminConfig) -> Self {
             driver,
             config,
             namespaces: Default::default(),
+            namespace_pool: Vec::new(),
+            allocated_namespaces: Default::default(),
         }
     }
 
+    /// Adds a disk to the pool that the guest can create namespaces on.
+    pub fn add_pool_disk(&mut self, disk: Disk) {
+        self.namespace_pool.push(disk);
+    }
+
     pub async fn add_namespace(
         &mut self,
         state: Option<&mut AdminState>,
         nsid: u32,
         disk: Disk,
     ) -> Result<(), NsidConflict> {
+        if self.allocated_namespaces.contains_key(&nsid) {
+            return Err(NsidConflict(nsid));
+        }
         let namespace = &*match self.namespaces.entry(nsid) {
             btree_map::Entry::Vacant(entry) => entry.insert(Arc::new(Namespace::new(
                 self.config.mem.clone(),
@@ -474,6 +506,20 @@ async fn process_event(
                     spec::AdminOpcode::GET_LOG_PAGE => self
                         .handle_get_log_page(state, &command)
                         .map(|()| Some(Default::default())),
+                    spec::AdminOpcode::NAMESPACE_MANAGEMENT
+                        if self.supports_namespace_management() =>
+                    {
+                        self.handle_namespace_management(state, &command)
+                            .await
+                            .map(Some)
+                    }
+                    spec::AdminOpcode::NAMESPACE_ATTACHMENT
+                        if self.supports_namespace_management() =>
+                    {
+                        self.handle_namespace_attachment(state, &command)
+                            .await
+                            .map(|()| Some(Default::default()))
+                    }
                     spec::AdminOpcode::DOORBELL_BUFFER_CONFIG
                         if self.supports_shadow_doorbells(state) =>
                     {
@@ -578,6 +624,45 @@ fn handle_identify(
                     tracelimit::warn_ratelimited!(nsid = command.nsid, "unknown namespace id");
                 }
             }
+            spec::Cns::ALLOCATED_NAMESPACE_LIST => {
+                if command.nsid >= 0xfffffffe {
+                    return Err(spec::Status::INVALID_NAMESPACE_OR_FORMAT.into());
+                }
+                let allocated = self
+                    .namespaces
+                    .keys()
+                    .chain(self.allocated_namespaces.keys())
+                    .copied()
+                    .collect::<BTreeSet<_>>();
+                let nsids = <[u32]>::mut_from_bytes(buf).unwrap();
+                for (ns, nsid) in allocated
+                    .into_iter()
+                    .filter(|&ns| ns > command.nsid)
+                    .zip(nsids)
+                {
+                    *nsid = ns;
+                }
+            }
+            spec::Cns::ALLOCATED_NAMESPACE => {
+                let ns = self.namespaces.get(&command.nsid).or_else(|| {
+                    self.allocated_namespaces
+                        .get(&command.nsid)
+                        .map(|ns| &ns.namespace)
+                });
+                if let Some(ns) = ns {
+                    ns.identify(buf);
+                } else {
+                    tracelimit::warn_ratelimited!(nsid = command.nsid, "unknown namespace id");
+                }
+            }
+            spec::Cns::CONTROLLER_LIST_OF_NSID => {
+                if self.namespaces.contains_key(&command.nsid) {
+                    write_controller_list(buf, cdw10.cntid());
+                }
+            }
+            spec::Cns::CONTROLLER_LIST_OF_NVM_SUBSYSTEM => {
+                write_controller_list(buf, cdw10.cntid());
+            }
             cns => {
                 tracelimit::warn_ratelimited!(?cns, "unsupported cns");
                 return Err(spec::Status::INVALID_FIELD_IN_COMMAND.into());
@@ -588,6 +673,13 @@ fn handle_identify(
     }
 
     fn identify_controller(&self, state: &AdminState) -> spec::IdentifyController {
+        let disk_bytes = |disk: &Disk| disk.sector_count() * disk.sector_size() as u64;
+        let unallocated_capacity: u64 = self.namespace_pool.iter().map(disk_bytes).sum();
+        let allocated_capacity: u64 = self
+            .allocated_namespaces
+            .values()
+            .map(|ns| disk_bytes(&ns.disk))
+            .sum();
         spec::IdentifyController {
             vid: VENDOR_ID,
             ssvid: VENDOR_ID,
@@ -602,7 +694,8 @@ fn identify_controller(&self, state: &AdminState) -> spec::IdentifyController {
                 .with_min(IOCQES)
                 .with_max(IOCQES),
             frmw: spec::FirmwareUpdates::new().with_ffsro(true).with_nofs(1),
-            nn: self.namespaces.keys().copied().max().unwrap_or(0),
+            cntlid: CONTROLLER_ID,
+            nn: self.max_nsid(),
             ieee: [0x74, 0xe2, 0x8c], // Microsoft
             fr: (*b"v1.00000").into(),
             mn: (*b"MSFT NVMe Accelerator v1.0              ").into(),
@@ -611,7 +704,10 @@ fn identify_controller(&self, state: &AdminState) -> spec::IdentifyController {
             elpe: ERROR_LOG_PAGE_ENTRIES - 1,
             oaes: spec::Oaes::new().with_namespace_attribute(true),
             oncs: spec::Oncs::new()
+                .with_compare(true)
                 .with_dataset_management(true)
+                .with_write_zeroes(true)
+                .with_verify(true)
                 // Namespaces still have to opt in individually via `rescap`.
                 .with_reservations(true),
             vwc: spec::VolatileWriteCache::new()
@@ -619,7 +715,11 @@ fn identify_controller(&self, state: &AdminState) -> spec::IdentifyController {
                 .with_broadcast_flush_behavior(spec::BroadcastFlushBehavior::NOT_SUPPORTED.0),
             cntrltype: spec::ControllerType::IO_CONTROLLER,
             oacs: spec::OptionalAdminCommandSupport::new()
+                .with_ns_management(self.supports_namespace_management())
                 .with_doorbell_buffer_config(self.supports_shadow_doorbells(state)),
+            sgls: spec::SglSupport::new().with_support(spec::SglSupportLevel::UNALIGNED.0),
+            tnvmcap: zerocopy::U128::new((allocated_capacity + unallocated_capacity).into()),
+            unvmcap: zerocopy::U128::new(unallocated_capacity.into()),
             ..FromZeros::new_zeroed()
         }
     }
@@ -980,6 +1080,189 @@ fn handle_get_log_page(
         Ok(())
     }
 
+    fn supports_namespace_management(&self) -> bool {
+        !self.namespace_pool.is_empty() || !self.allocated_namespaces.is_empty()
+    }
+
+    /// Returns the maximum namespace ID, which covers both the namespaces
+    /// supplied by the host and those the guest can create from the pool.
+    fn max_nsid(&self) -> u32 {
+        let host_namespaces = self
+            .namespaces
+            .keys()
+            .filter(|nsid| !self.allocated_namespaces.contains_key(nsid))
+            .count();
+        let pool_namespaces = self.namespace_pool.len() + self.allocated_namespaces.len();
+        self.namespaces
+            .keys()
+            .chain(self.allocated_namespaces.keys())
+            .copied()
+            .max()
+            .unwrap_or(0)
+            .max((host_namespaces + pool_namespaces) as u32)
+    }
+
+    async fn handle_namespace_management(
+        &mut self,
+        state: &mut AdminState,
+        command: &spec::Command,
+    ) -> Result<CommandResult, NvmeError> {
+        let cdw10 = spec::Cdw10NamespaceManagement::from(command.cdw10);
+        let mut dw = [0; 2];
+        match spec::NamespaceManagementSelect(cdw10.sel()) {
+            spec::NamespaceManagementSelect::CREATE => {
+                if cdw10.csi() != 0 {
+                    return Err(spec::Status::IO_COMMAND_SET_NOT_SUPPORTED.into());
+                }
+                let mut id = spec::nvm::IdentifyNamespace::new_zeroed();
+                PrpRange::parse(&self.config.mem, size_of_val(&id), command.dptr)?
+                    .read(&self.config.mem, id.as_mut_bytes())?;
+                dw[0] = self.create_namespace(&id)?;
+            }
+            spec::NamespaceManagementSelect::DELETE => {
+                if command.nsid == !0 {
+                    let nsids = self
+                        .allocated_namespaces
+                        .keys()
+                        .copied()
+                        .collect::<Vec<_>>();
+                    for nsid in nsids {
+                        self.delete_namespace(state, nsid).await?;
+                    }
+                } else {
+                    self.delete_namespace(state, command.nsid).await?;
+                }
+            }
+            sel => {
+                tracelimit::warn_ratelimited!(?sel, "unsupported namespace management select");
+                return Err(spec::Status::INVALID_FIELD_IN_COMMAND.into());
+            }
+        }
+        Ok(CommandResult::new(spec::Status::SUCCESS, dw))
+    }
+
+    /// Creates a namespace on the smallest pool disk that can hold it, the
+    /// way an array would carve it out of its free capacity, and returns the
+    /// new namespace ID.
+    ///
+    /// The namespace always spans the whole disk, and the disk's sector size
+    /// is its only LBA format, so `nsze` is interpreted in units of each
+    /// candidate disk's sectors.
+    fn create_namespace(&mut self, id: &spec::nvm::IdentifyNamespace) -> Result<u32, NvmeError> {
+        if id.flbas.low_index() != 0 || id.flbas.high_index() != 0 {
+            return Err(spec::Status::INVALID_FORMAT.into());
+        }
+        if id.nsze == 0 {
+            return Err(spec::Status::INVALID_FIELD_IN_COMMAND.into());
+        }
+        if id.ncap != id.nsze {
+            return Err(spec::Status::THIN_PROVISIONING_NOT_SUPPORTED.into());
+        }
+        if self.namespace_pool.is_empty() {
+            return Err(spec::Status::NAMESPACE_IDENTIFIER_UNAVAILABLE.into());
+        }
+        let (index, _) = self
+            .namespace_pool
+            .iter()
+            .enumerate()
+            .filter(|(_, disk)| disk.sector_count() >= id.nsze)
+            .min_by_key(|(_, disk)| disk.sector_count())
+            .ok_or(spec::Status::NAMESPACE_INSUFFICIENT_CAPACITY)?;
+
+        // There is always a free ID no larger than `max_nsid()`, since each
+        // pool disk accounts for one.
+        let nsid = (1..)
+            .find(|nsid| {
+                !self.namespaces.contains_key(nsid) && !self.allocated_namespaces.contains_key(nsid)
+            })
+            .unwrap();
+
+        let disk = self.namespace_pool.remove(index);
+        let namespace = Arc::new(Namespace::new(self.config.mem.clone(), nsid, disk.clone()));
+        self.allocated_namespaces
+            .insert(nsid, PoolNamespace { namespace, disk });
+        tracing::info!(nsid, "created namespace");
+        Ok(nsid)
+    }
+
+    async fn delete_namespace(
+        &mut self,
+        state: &mut AdminState,
+        nsid: u32,
+    ) -> Result<(), NvmeError> {
+        let Some(pool_namespace) = self.allocated_namespaces.remove(&nsid) else {
+            return Err(self.unmanaged_namespace_error(nsid));
+        };
+        // Deleting a namespace implicitly detaches it.
+        if self.namespaces.remove(&nsid).is_some() {
+            state.remove_namespace(nsid).await;
+        }
+        self.namespace_pool.push(pool_namespace.disk);
+        tracing::info!(nsid, "deleted namespace");
+        Ok(())
+    }
+
+    async fn handle_namespace_attachment(
+        &mut self,
+        state: &mut AdminState,
+        command: &spec::Command,
+    ) -> Result<(), NvmeError> {
+        let cdw10 = spec::Cdw10NamespaceAttachment::from(command.cdw10);
+        let mut controllers = spec::ControllerList::new_zeroed();
+        PrpRange::parse(&self.config.mem, size_of_val(&controllers), command.dptr)?
+            .read(&self.config.mem, controllers.as_mut_bytes())?;
+        let ids = controllers
+            .ids
+            .get(..usize::from(controllers.count))
+            .ok_or(spec::Status::CONTROLLER_LIST_INVALID)?;
+        if ids.is_empty() || ids.iter().any(|&id| id != CONTROLLER_ID) {
+            return Err(spec::Status::CONTROLLER_LIST_INVALID.into());
+        }
+
+        let nsid = command.nsid;
+        let Some(pool_namespace) = self.allocated_namespaces.get(&nsid) else {
+            return Err(self.unmanaged_namespace_error(nsid));
+        };
+
+        match spec::NamespaceAttachmentSelect(cdw10.sel()) {
+            spec::NamespaceAttachmentSelect::ATTACH => {
+                let namespace = &*match self.namespaces.entry(nsid) {
+                    btree_map::Entry::Vacant(entry) => {
+                        entry.insert(pool_namespace.namespace.clone())
+                    }
+                    btree_map::Entry::Occupied(_) => {
+                        return Err(spec::Status::NAMESPACE_ALREADY_ATTACHED.into());
+                    }
+                };
+                state.add_namespace(&self.driver, nsid, namespace).await;
+                tracing::info!(nsid, "attached namespace");
+            }
+            spec::NamespaceAttachmentSelect::DETACH => {
+                if self.namespaces.remove(&nsid).is_none() {
+                    return Err(spec::Status::NAMESPACE_NOT_ATTACHED.into());
+                }
+                state.remove_namespace(nsid).await;
+                tracing::info!(nsid, "detached namespace");
+            }
+            sel => {
+                tracelimit::warn_ratelimited!(?sel, "unsupported namespace attachment select");
+                return Err(spec::Status::INVALID_FIELD_IN_COMMAND.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the error for a namespace management or attachment command
+    /// that targets a namespace not created from the pool.
+    fn unmanaged_namespace_error(&self, nsid: u32) -> NvmeError {
+        if self.namespaces.contains_key(&nsid) {
+            // Namespaces supplied by the host are not the guest's to manage.
+            spec::Status::NAMESPACE_IS_PRIVATE.into()
+        } else {
+            spec::Status::INVALID_NAMESPACE_OR_FORMAT.into()
+        }
+    }
+
     fn supports_shadow_doorbells(&self, state: &AdminState) -> bool {
         let num_queues = state.io_sqs.len().max(state.io_cqs.len()) + 1;
         let len = num_queues * (2 << DOORBELL_STRIDE_BITS);
@@ -199,6 +199,17 @@ pub async fn remove_namespace(&self, nsid: u32) -> bool {
             .await
             .unwrap()
     }
+
+    /// Adds a disk to the pool that the guest can create namespaces on with
+    /// the Namespace Management command. Namespaces created this way are
+    /// attached and detached by the guest with the Namespace Attachment
+    /// command.
+    pub async fn add_pool_disk(&self, disk: Disk) {
+        self.send
+            .call(CoordinatorRequest::AddPoolDisk, disk)
+            .await
+            .unwrap()
+    }
 }
 
 #[derive(Inspect)]
@@ -214,6 +225,7 @@ enum CoordinatorRequest {
     EnableAdmin(Rpc<EnableAdminParams, ()>),
     AddNamespace(Rpc<(u32, Disk), Result<(), NsidConflict>>),
     RemoveNamespace(Rpc<u32, bool>),
+    AddPoolDisk(Rpc<Disk, ()>),
     Inspect(inspect::Deferred),
     ControllerReset(Rpc<(), ()>),
 }
@@ -295,6 +307,16 @@ enum Event {
                         })
                         .await
                     }
+                    CoordinatorRequest::AddPoolDisk(rpc) => {
+                        rpc.handle(async |disk| {
+                            let running = self.admin.stop().await;
+                            self.admin.task_mut().add_pool_disk(disk);
+                            if running {
+                                self.admin.start();
+                            }
+                        })
+                        .await
+                    }
                     CoordinatorRequest::ControllerReset(rpc) => {
                         assert!(self.reset.is_none());
                         self.reset = Some(rpc);
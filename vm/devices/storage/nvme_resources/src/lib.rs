@@ -26,6 +26,9 @@ pub struct NvmeControllerHandle {
     pub max_io_queues: u16,
     /// The initial set of namespaces.
     pub namespaces: Vec<NamespaceDefinition>,
+    /// Disks that the guest can create namespaces on with the Namespace
+    /// Management command, and then attach and detach at runtime.
+    pub namespace_pool: Vec<Resource<DiskHandleKind>>,
 }
 
 impl ResourceId<PciDeviceHandleKind> for NvmeControllerHandle {
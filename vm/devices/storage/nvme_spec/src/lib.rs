@@ -173,6 +173,57 @@ pub struct Cdw0 {
     pub cid: u16,
 }
 
+open_enum! {
+    /// PRP or SGL for data transfer.
+    pub enum Psdt: u8 {
+        PRP = 0,
+        /// SGL for data, with a contiguous metadata buffer.
+        SGL_CONTIGUOUS_METADATA = 1,
+        /// SGL for data, with an SGL for metadata.
+        SGL_METADATA_SGL = 2,
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct SglDescriptor {
+    pub address: u64,
+    pub length: u32,
+    pub rsvd: [u8; 3],
+    pub identifier: SglIdentifier,
+}
+static_assertions::assert_eq_size!(SglDescriptor, [u8; 16]);
+
+#[bitfield(u8)]
+#[derive(IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct SglIdentifier {
+    /// An [`SglDescriptorSubtype`] value.
+    #[bits(4)]
+    pub subtype: u8,
+    /// An [`SglDescriptorType`] value.
+    #[bits(4)]
+    pub descriptor_type: u8,
+}
+
+open_enum! {
+    pub enum SglDescriptorType: u8 {
+        DATA_BLOCK = 0,
+        BIT_BUCKET = 1,
+        SEGMENT = 2,
+        LAST_SEGMENT = 3,
+        KEYED_DATA_BLOCK = 4,
+        TRANSPORT_DATA_BLOCK = 5,
+        VENDOR_SPECIFIC = 0xf,
+    }
+}
+
+open_enum! {
+    pub enum SglDescriptorSubtype: u8 {
+        ADDRESS = 0,
+        OFFSET = 1,
+    }
+}
+
 #[repr(C)]
 pub struct Opcode(pub u8);
 
@@ -522,7 +573,7 @@ pub struct IdentifyController {
     pub nwpc: u8,
     pub acwu: u16,
     pub copy_descriptor_fmt: u16,
-    pub sgls: u32,
+    pub sgls: SglSupport,
     pub mnan: u32,
     #[inspect(display)]
     pub maxdna: U128LE,
@@ -607,6 +658,38 @@ pub struct Oncs {
     _rsvd: u16,
 }
 
+/// SGL support
+#[derive(Inspect)]
+#[bitfield(u32)]
+#[derive(IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct SglSupport {
+    /// An [`SglSupportLevel`] value.
+    #[bits(2)]
+    pub support: u8,
+    pub keyed_data_block: bool,
+    #[bits(13)]
+    _rsvd: u16,
+    pub bit_bucket: bool,
+    pub byte_aligned_metadata: bool,
+    /// Supports SGLs longer than the data to transfer.
+    pub oversized: bool,
+    pub metadata_sgl: bool,
+    pub address_as_offset: bool,
+    pub transport_data_block: bool,
+    #[bits(10)]
+    _rsvd2: u16,
+}
+
+open_enum! {
+    pub enum SglSupportLevel: u8 {
+        UNSUPPORTED = 0,
+        /// No alignment or granularity requirements for data blocks.
+        UNALIGNED = 1,
+        /// Data blocks must be dword aligned and a multiple of dwords long.
+        DWORD_ALIGNED = 2,
+    }
+}
+
 open_enum! {
     #[derive(IntoBytes, Immutable, KnownLayout, FromBytes, Inspect)]
     #[inspect(debug)]
@@ -744,6 +827,52 @@ pub struct Cdw10DeleteIoQueue {
     pub rsvd: u16,
 }
 
+#[bitfield(u32)]
+pub struct Cdw10NamespaceManagement {
+    /// Select, a [`NamespaceManagementSelect`] value.
+    #[bits(4)]
+    pub sel: u8,
+    #[bits(20)]
+    pub rsvd: u32,
+    /// Command set identifier.
+    pub csi: u8,
+}
+
+open_enum! {
+    pub enum NamespaceManagementSelect: u8 {
+        CREATE = 0,
+        DELETE = 1,
+    }
+}
+
+#[bitfield(u32)]
+pub struct Cdw10NamespaceAttachment {
+    /// Select, a [`NamespaceAttachmentSelect`] value.
+    #[bits(4)]
+    pub sel: u8,
+    #[bits(28)]
+    pub rsvd: u32,
+}
+
+open_enum! {
+    pub enum NamespaceAttachmentSelect: u8 {
+        ATTACH = 0,
+        DETACH = 1,
+    }
+}
+
+/// A controller list, as used by Namespace Attachment and returned by some
+/// Identify CNS values.
+#[repr(C)]
+#[derive(Debug, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct ControllerList {
+    /// The number of valid entries in `ids`.
+    pub count: u16,
+    pub ids: [u16; 2047],
+}
+
+const _: () = assert!(size_of::<ControllerList>() == 4096);
+
 #[bitfield(u32)]
 pub struct Cdw10GetLogPage {
     /// Log page identifier
@@ -104,6 +104,31 @@ pub struct Nsfeat {
     _rsvd: u8,
 }
 
+/// Deallocate logical block features
+#[derive(Inspect)]
+#[bitfield(u8)]
+#[derive(IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct Dlfeat {
+    /// The values read from a deallocated logical block.
+    #[bits(3)]
+    pub read_behavior: u8,
+    /// The controller supports the deallocate bit in the Write Zeroes
+    /// command.
+    pub write_zeroes_deallocate: bool,
+    /// The guard field for deallocated logical blocks is the CRC of the data.
+    pub guard_crc: bool,
+    #[bits(3)]
+    _rsvd: u8,
+}
+
+open_enum! {
+    pub enum DeallocatedReadBehavior: u8 {
+        NOT_REPORTED = 0,
+        ZEROES = 1,
+        ONES = 2,
+    }
+}
+
 /// LBA format
 #[derive(Inspect)]
 #[bitfield(u32)]
@@ -154,8 +179,11 @@ pub enum NvmOpcode: u8 {
         FLUSH = 0x00,
         WRITE = 0x01,
         READ = 0x02,
+        COMPARE = 0x05,
+        WRITE_ZEROES = 0x08,
         /// Dataset management.
         DSM = 0x09,
+        VERIFY = 0x0c,
 
         RESERVATION_REGISTER = 0xd,
         RESERVATION_REPORT = 0xe,
@@ -197,6 +225,28 @@ pub struct Cdw12ReadWrite {
     pub lr: bool,
 }
 
+#[bitfield(u32)]
+pub struct Cdw12WriteZeroes {
+    /// Number of logical blocks. Zero-based.
+    pub nlb_z: u16,
+    #[bits(4)]
+    _rsvd: u8,
+    /// Directive type.
+    #[bits(4)]
+    pub dtype: u8,
+    /// Storage tag check.
+    pub stc: bool,
+    /// Deallocate.
+    pub deac: bool,
+    /// Protection information
+    #[bits(4)]
+    pub prinfo: u8,
+    /// Force unit access
+    pub fua: bool,
+    /// Limited retry
+    pub lr: bool,
+}
+
 #[bitfield(u32)]
 pub struct Cdw10Dsm {
     /// Number of ranges. Zero-based.
@@ -0,0 +1,356 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Tests that faults injected by the disk backend surface correctly through
+//! the storage devices.
+
+use crate::storvsc::generate_read_packet;
+use crate::storvsc::generate_write_packet;
+use chipset_device::pio::ExternallyManagedPortIoIntercepts;
+use chipset_device::pio::PortIoIntercept;
+use chipset_device::poll_device::PollDevice;
+use disk_backend::Disk;
+use disk_backend_resources::fault::FaultAction;
+use disk_backend_resources::fault::FaultOp;
+use disk_backend_resources::fault::FaultRule;
+use disk_backend_resources::fault::MediumError;
+use disk_fault::FaultDisk;
+use disk_fault::FaultHandle;
+use guestmem::GuestMemory;
+use ide::DriveMedia;
+use ide::IdeDevice;
+use ide::IdeIoPort;
+use pal_async::DefaultDriver;
+use pal_async::async_test;
+use pal_async::timer::PolledTimer;
+use scsi_defs::ScsiStatus;
+use std::future::poll_fn;
+use std::sync::Arc;
+use std::task::Poll;
+use storvsc_driver::test_helpers::TestStorvscWorker;
+use storvsp::ScsiController;
+use storvsp::ScsiControllerDisk;
+use storvsp::test_helpers::TestWorker;
+use storvsp_resources::ScsiPath;
+use test_with_tracing::test;
+use vmbus_channel::connected_async_channels;
+use vmcore::line_interrupt::LineInterrupt;
+
+const SECTOR_SIZE: usize = 512;
+
+fn fault_disk(rules: Vec<FaultRule>) -> (Disk, FaultHandle) {
+    let disk = FaultDisk::new(disklayer_ram::ram_disk(0x100000, false).unwrap(), rules, 0).unwrap();
+    let handle = disk.handle();
+    (Disk::new(disk).unwrap(), handle)
+}
+
+#[async_test]
+async fn storvsp_faults(driver: DefaultDriver) {
+    let (host, guest) = connected_async_channels(16 * 1024);
+    let guest_mem = GuestMemory::allocate(0x4000);
+
+    let (disk, handle) = fault_disk(vec![
+        FaultRule::new(FaultAction::MediumError(MediumError::UnrecoveredRead))
+            .with_ops([FaultOp::Read])
+            .with_sectors(8..16),
+        FaultRule::new(FaultAction::ReservationConflict).with_ops([FaultOp::Write]),
+    ]);
+    let controller = ScsiController::new();
+    controller
+        .attach(
+            ScsiPath {
+                path: 0,
+                target: 0,
+                lun: 0,
+            },
+            ScsiControllerDisk::new(Arc::new(scsidisk::SimpleScsiDisk::new(
+                disk,
+                Default::default(),
+            ))),
+        )
+        .unwrap();
+
+    let storvsp = TestWorker::start(controller, driver.clone(), guest_mem, host, None);
+    let mut storvsc = TestStorvscWorker::new();
+    storvsc.start(driver.clone(), guest);
+    storvsc
+        .wait_for_negotiation(&mut PolledTimer::new(&driver), 1000)
+        .await;
+
+    // Reads outside the faulty range succeed.
+    let response = storvsc
+        .send_request(&generate_read_packet(0, 0, 0, 0, 4096), 0, 4096)
+        .await
+        .unwrap();
+    assert_eq!(response.scsi_status, ScsiStatus::GOOD);
+
+    let response = storvsc
+        .send_request(&generate_read_packet(0, 0, 0, 12, 1024), 0, 1024)
+        .await
+        .unwrap();
+    assert_eq!(response.scsi_status, ScsiStatus::CHECK_CONDITION);
+
+    let response = storvsc
+        .send_request(&generate_write_packet(0, 0, 0, 0, 512), 0, 512)
+        .await
+        .unwrap();
+    assert_eq!(response.scsi_status, ScsiStatus::RESERVATION_CONFLICT);
+
+    // Once the rules are cleared, I/O succeeds again.
+    handle.set_rules(Vec::new()).unwrap();
+    let response = storvsc
+        .send_request(&generate_read_packet(0, 0, 0, 12, 1024), 0, 1024)
+        .await
+        .unwrap();
+    assert_eq!(response.scsi_status, ScsiStatus::GOOD);
+
+    storvsc.teardown().await;
+    storvsp.teardown_or_panic().await;
+}
+
+#[cfg(any(windows, target_os = "linux"))]
+mod nvme_faults {
+    use super::fault_disk;
+    use chipset_device::mmio::ExternallyManagedMmioIntercepts;
+    use disk_backend::Disk;
+    use disk_backend::DiskError;
+    use disk_backend::MediumErrorDetails;
+    use disk_backend_resources::fault::FaultAction;
+    use disk_backend_resources::fault::FaultDiskRequest;
+    use disk_backend_resources::fault::FaultOp;
+    use disk_backend_resources::fault::FaultRule;
+    use disk_backend_resources::fault::MediumError;
+    use disk_fault::FaultHandle;
+    use disk_nvme::NvmeDisk;
+    use guestmem::GuestMemory;
+    use guid::Guid;
+    use mesh::rpc::RpcSend;
+    use nvme::NvmeController;
+    use nvme::NvmeControllerCaps;
+    use nvme_driver::NvmeDriver;
+    use page_pool_alloc::PagePoolAllocator;
+    use pal_async::DefaultDriver;
+    use pal_async::async_test;
+    use pal_async::task::Spawn;
+    use pal_async::timer::PolledTimer;
+    use pci_core::msi::MsiInterruptSet;
+    use scsi_buffers::OwnedRequestBuffers;
+    use std::pin::pin;
+    use std::time::Duration;
+    use test_with_tracing::test;
+    use user_driver_emulated_mock::DeviceTestMemory;
+    use user_driver_emulated_mock::EmulatedDevice;
+    use vmcore::vm_task::SingleDriverBackend;
+    use vmcore::vm_task::VmTaskDriverSource;
+
+    struct NvmeFaultTest {
+        disk: Disk,
+        handle: FaultHandle,
+        payload_mem: GuestMemory,
+        _nvme_driver: NvmeDriver<EmulatedDevice<NvmeController, PagePoolAllocator>>,
+    }
+
+    impl NvmeFaultTest {
+        async fn new(driver: &DefaultDriver, rules: Vec<FaultRule>) -> Self {
+            let driver_source = VmTaskDriverSource::new(SingleDriverBackend::new(driver.clone()));
+            let mem = DeviceTestMemory::new(1024, false, "storage_tests_fault_injection");
+            let mut msi_set = MsiInterruptSet::new();
+            let nvme = NvmeController::new(
+                &driver_source,
+                mem.guest_memory(),
+                &mut msi_set,
+                &mut ExternallyManagedMmioIntercepts,
+                NvmeControllerCaps {
+                    msix_count: 2,
+                    max_io_queues: 64,
+                    subsystem_id: Guid::new_random(),
+                },
+            );
+
+            let (disk, handle) = fault_disk(rules);
+            nvme.client().add_namespace(1, disk).await.unwrap();
+
+            let device = EmulatedDevice::new(nvme, msi_set, mem.dma_client());
+            let mut nvme_driver = NvmeDriver::new(&driver_source, 64, device, false)
+                .await
+                .unwrap();
+            let namespace = nvme_driver.namespace(1).await.unwrap();
+            Self {
+                disk: Disk::new(NvmeDisk::new(namespace)).unwrap(),
+                handle,
+                payload_mem: mem.payload_mem(),
+                _nvme_driver: nvme_driver,
+            }
+        }
+    }
+
+    #[async_test]
+    async fn nvme_medium_error(driver: DefaultDriver) {
+        let test = NvmeFaultTest::new(
+            &driver,
+            vec![
+                FaultRule::new(FaultAction::MediumError(MediumError::UnrecoveredRead))
+                    .with_ops([FaultOp::Read])
+                    .with_nth(2),
+            ],
+        )
+        .await;
+
+        let buf = OwnedRequestBuffers::linear(0, 4096, true);
+        let buffers = buf.buffer(&test.payload_mem);
+        test.disk.read_vectored(&buffers, 0).await.unwrap();
+        let err = test.disk.read_vectored(&buffers, 0).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                DiskError::MediumError(_, MediumErrorDetails::UnrecoveredReadError)
+            ),
+            "{err:?}"
+        );
+        test.disk.read_vectored(&buffers, 0).await.unwrap();
+    }
+
+    #[async_test]
+    async fn nvme_torn_write(driver: DefaultDriver) {
+        let test = NvmeFaultTest::new(
+            &driver,
+            vec![FaultRule::new(FaultAction::TornWrite { sectors: 2 }).with_ops([FaultOp::Write])],
+        )
+        .await;
+
+        // Control the rules via mesh RPC, as a VM would.
+        let (send, recv) = mesh::channel();
+        driver
+            .spawn(
+                "fault-disk-requests",
+                test.handle.clone().handle_requests(recv),
+            )
+            .detach();
+
+        let buf = OwnedRequestBuffers::linear(0, 4096, true);
+        let buffers = buf.buffer(&test.payload_mem);
+        test.payload_mem.fill_at(0, 0xaa, 4096).unwrap();
+        let err = test
+            .disk
+            .write_vectored(&buffers, 0, false)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, DiskError::Io(_)), "{err:?}");
+
+        send.call_failable(FaultDiskRequest::SetRules, Vec::new())
+            .await
+            .unwrap();
+
+        // Only the first two sectors were written.
+        test.payload_mem.fill_at(0, 0, 4096).unwrap();
+        test.disk.read_vectored(&buffers, 0).await.unwrap();
+        let mut data = vec![0; 4096];
+        test.payload_mem.read_at(0, &mut data).unwrap();
+        assert!(data[..1024].iter().all(|&b| b == 0xaa));
+        assert!(data[1024..].iter().all(|&b| b == 0));
+    }
+
+    #[async_test]
+    async fn nvme_hang(driver: DefaultDriver) {
+        let test = NvmeFaultTest::new(
+            &driver,
+            vec![FaultRule::new(FaultAction::Hang).with_ops([FaultOp::Flush])],
+        )
+        .await;
+
+        let mut flush = pin!(test.disk.sync_cache());
+        let mut timer = PolledTimer::new(&driver);
+        let r = futures::future::select(
+            flush.as_mut(),
+            pin!(timer.sleep(Duration::from_millis(100))),
+        )
+        .await;
+        assert!(
+            matches!(r, futures::future::Either::Right(_)),
+            "flush should be hung"
+        );
+
+        // Wait for the flush to reach the disk, then release it.
+        while test.handle.release_hangs() == 0 {
+            timer.sleep(Duration::from_millis(10)).await;
+        }
+        flush.await.unwrap();
+    }
+}
+
+#[async_test]
+async fn ide_faults() {
+    let (disk, handle) = fault_disk(vec![
+        FaultRule::new(FaultAction::IoError)
+            .with_ops([FaultOp::Read])
+            .with_sectors(4..5),
+    ]);
+    let mut ide = IdeDevice::new(
+        GuestMemory::allocate(0x1000),
+        &mut ExternallyManagedPortIoIntercepts,
+        [Some(DriveMedia::hard_disk(disk)), None],
+        [None, None],
+        LineInterrupt::detached(),
+        LineInterrupt::detached(),
+    )
+    .unwrap();
+
+    assert!(!ide_read_sector(&mut ide, 0).await);
+    assert!(ide_read_sector(&mut ide, 4).await);
+
+    handle.set_enabled(false);
+    assert!(!ide_read_sector(&mut ide, 4).await);
+}
+
+const IDE_STATUS_ERR: u8 = 0x01;
+const IDE_STATUS_DRQ: u8 = 0x08;
+const IDE_STATUS_BSY: u8 = 0x80;
+const IDE_DEVICE_HEAD_LBA: u8 = 0x40;
+const IDE_READ_SECTORS: u8 = 0x20;
+
+/// Polls the IDE device until the primary channel is no longer busy, returning
+/// its status.
+async fn ide_wait_not_busy(ide: &mut IdeDevice) -> u8 {
+    poll_fn(|cx| {
+        ide.poll_device(cx);
+        let mut status = [0];
+        ide.io_read(IdeIoPort::PRI_STATUS_CMD.0, &mut status)
+            .unwrap();
+        if status[0] & IDE_STATUS_BSY == 0 {
+            Poll::Ready(status[0])
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+/// Issues a PIO READ SECTORS command for one sector to the primary drive on
+/// the primary channel, returning whether the device reported an error.
+async fn ide_read_sector(ide: &mut IdeDevice, sector: u32) -> bool {
+    ide_wait_not_busy(ide).await;
+    for (port, value) in [
+        (
+            IdeIoPort::PRI_DEVICE_HEAD,
+            IDE_DEVICE_HEAD_LBA | (sector >> 24) as u8,
+        ),
+        (IdeIoPort::PRI_SECTOR_COUNT, 1),
+        (IdeIoPort::PRI_SECTOR_NUM, sector as u8),
+        (IdeIoPort::PRI_CYLINDER_LSB, (sector >> 8) as u8),
+        (IdeIoPort::PRI_CYLINDER_MSB, (sector >> 16) as u8),
+        (IdeIoPort::PRI_STATUS_CMD, IDE_READ_SECTORS),
+    ] {
+        ide.io_write(port.0, &[value]).unwrap();
+    }
+
+    let status = ide_wait_not_busy(ide).await;
+    if status & IDE_STATUS_ERR != 0 {
+        return true;
+    }
+    assert!(status & IDE_STATUS_DRQ != 0, "{status:#x}");
+    let mut data = [0; 2];
+    for _ in 0..SECTOR_SIZE / 2 {
+        ide.io_read(IdeIoPort::PRI_DATA.0, &mut data).unwrap();
+    }
+    false
+}
@@ -21,7 +21,7 @@
 use zerocopy::IntoBytes;
 
 // This function assumes the sector size is 512.
-fn generate_write_packet(
+pub(crate) fn generate_write_packet(
     target_id: u8,
     path_id: u8,
     lun: u8,
@@ -50,7 +50,7 @@ fn generate_write_packet(
 }
 
 // This function assumes the sector size is 512.
-fn generate_read_packet(
+pub(crate) fn generate_read_packet(
     target_id: u8,
     path_id: u8,
     lun: u8,
@@ -2,5 +2,6 @@
 // Licensed under the MIT License.
 
 //! Tests for storage devices that don't qualify as unit tests, including integration tests.
+mod fault_injection;
 mod scsidvd_nvme;
 mod storvsc;
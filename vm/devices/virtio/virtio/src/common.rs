@@ -13,6 +13,7 @@
 use guestmem::GuestMemory;
 use guestmem::GuestMemoryError;
 use guestmem::MappedMemoryRegion;
+use inspect::InspectMut;
 use pal_async::DefaultPool;
 use pal_async::driver::Driver;
 use pal_async::task::Spawn;
@@ -48,9 +49,10 @@ pub struct VirtioQueueUsedHandler {
 
 impl VirtioQueueUsedHandler {
     fn new(core: QueueCore, notify_guest: Interrupt) -> Self {
+        let last_used_index = core.initial_index();
         Self {
             core,
-            last_used_index: 0,
+            last_used_index,
             outstanding_desc_count: Arc::new(Mutex::new((0, event_listener::Event::new()))),
             notify_guest,
         }
@@ -70,10 +72,16 @@ pub fn await_outstanding_descriptors(&self) -> event_listener::EventListener {
         listener
     }
 
-    pub fn complete_descriptor(&mut self, descriptor_index: u16, bytes_written: u32) {
+    pub fn complete_descriptor(
+        &mut self,
+        descriptor_index: u16,
+        descriptor_count: u16,
+        bytes_written: u32,
+    ) {
         match self.core.complete_descriptor(
             &mut self.last_used_index,
             descriptor_index,
+            descriptor_count,
             bytes_written,
         ) {
             Ok(true) => {
@@ -101,6 +109,7 @@ pub struct VirtioQueueCallbackWork {
     pub payload: Vec<VirtioQueuePayload>,
     used_queue_handler: Arc<Mutex<VirtioQueueUsedHandler>>,
     descriptor_index: u16,
+    descriptor_count: u16,
     completed: bool,
 }
 
@@ -109,6 +118,7 @@ pub fn new(
         payload: Vec<VirtioQueuePayload>,
         used_queue_handler: &Arc<Mutex<VirtioQueueUsedHandler>>,
         descriptor_index: u16,
+        descriptor_count: u16,
     ) -> Self {
         let used_queue_handler = used_queue_handler.clone();
         used_queue_handler.lock().add_outstanding_descriptor();
@@ -116,15 +126,18 @@ pub fn new(
             payload,
             used_queue_handler,
             descriptor_index,
+            descriptor_count,
             completed: false,
         }
     }
 
     pub fn complete(&mut self, bytes_written: u32) {
         assert!(!self.completed);
-        self.used_queue_handler
-            .lock()
-            .complete_descriptor(self.descriptor_index, bytes_written);
+        self.used_queue_handler.lock().complete_descriptor(
+            self.descriptor_index,
+            self.descriptor_count,
+            bytes_written,
+        );
         self.completed = true;
     }
 
@@ -245,9 +258,10 @@ pub fn new(
             core.clone(),
             notify,
         )));
+        let last_avail_index = core.initial_index();
         Ok(Self {
             core,
-            last_avail_index: 0,
+            last_avail_index,
             used_handler,
             queue_event,
         })
@@ -268,16 +282,19 @@ fn poll_next_buffer(
             };
             ready!(self.queue_event.wait().poll_unpin(cx)).expect("waits on Event cannot fail");
         };
-        let payload = self
-            .core
-            .reader(descriptor_index)
-            .collect::<Result<Vec<_>, _>>()?;
+        let mut reader = self.core.reader(descriptor_index);
+        let payload = reader.by_ref().collect::<Result<Vec<_>, _>>()?;
+        let buffer_id = reader.buffer_id();
+        let descriptor_count = reader.descriptor_count();
 
-        self.last_avail_index = self.last_avail_index.wrapping_add(1);
+        self.last_avail_index = self
+            .core
+            .advance_index(self.last_avail_index, descriptor_count);
         Poll::Ready(Ok(Some(VirtioQueueCallbackWork::new(
             payload,
             &self.used_handler,
-            descriptor_index,
+            buffer_id,
+            descriptor_count,
         ))))
     }
 }
@@ -484,7 +501,7 @@ pub trait LegacyVirtioDevice: Send {
     fn state_change(&mut self, state: &VirtioState);
 }
 
-pub trait VirtioDevice: Send {
+pub trait VirtioDevice: InspectMut + Send {
     fn traits(&self) -> DeviceTraits;
     fn read_registers_u32(&self, offset: u16) -> u32;
     fn write_registers_u32(&mut self, offset: u16, val: u32);
@@ -503,6 +520,8 @@ pub struct Resources {
     pub queues: Vec<QueueResources>,
     pub shared_memory_region: Option<Arc<dyn MappedMemoryRegion>>,
     pub shared_memory_size: u64,
+    /// Signals the driver that the device configuration space has changed.
+    pub config_change: Interrupt,
 }
 
 /// Wraps an object implementing [`LegacyVirtioDevice`] and implements [`VirtioDevice`].
@@ -526,6 +545,12 @@ pub fn new(driver_source: &VmTaskDriverSource, device: T, mem: &GuestMemory) ->
     }
 }
 
+impl<T: LegacyVirtioDevice> InspectMut for LegacyWrapper<T> {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        req.respond().field("workers", self.workers.len());
+    }
+}
+
 impl<T: LegacyVirtioDevice> VirtioDevice for LegacyWrapper<T> {
     fn traits(&self) -> DeviceTraits {
         self.device.traits()
@@ -10,6 +10,8 @@
 pub mod resolve;
 pub mod resolver;
 pub mod spec;
+#[cfg(any(test, feature = "test_helpers"))]
+pub mod test_helpers;
 mod tests;
 pub mod transport;
 
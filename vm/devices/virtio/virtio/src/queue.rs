@@ -6,11 +6,18 @@
 
 use crate::spec::queue as spec;
 use crate::spec::u16_le;
+use crate::spec::u32_le;
 use guestmem::GuestMemory;
 use guestmem::GuestMemoryError;
 use std::sync::atomic;
 use thiserror::Error;
 
+/// The guest memory backing a split or packed virtqueue.
+///
+/// For packed queues (`VIRTIO_F_RING_PACKED`), `queue_desc` is the descriptor
+/// ring, `queue_avail` is the driver event suppression structure, and
+/// `queue_used` is the device event suppression structure. Ring indexes for
+/// packed queues carry the wrap counter in bit 15.
 #[derive(Debug, Clone)]
 pub(crate) struct QueueCore {
     queue_size: u16,
@@ -18,6 +25,7 @@ pub(crate) struct QueueCore {
     queue_avail: GuestMemory,
     queue_used: GuestMemory,
     use_ring_event_index: bool,
+    use_packed_ring: bool,
     mem: GuestMemory,
 }
 
@@ -29,6 +37,10 @@ pub enum QueueError {
     DoubleIndirect,
     #[error("a descriptor chain is too long or has a cycle")]
     TooLong,
+    #[error("buffer id {0} is out of range")]
+    InvalidBufferId(u16),
+    #[error("an indirect descriptor table has an invalid length")]
+    InvalidIndirectLength,
 }
 
 #[derive(Debug, Copy, Clone, Default)]
@@ -43,6 +55,10 @@ pub struct QueueParams {
 impl QueueCore {
     pub fn new(features: u64, mem: GuestMemory, params: QueueParams) -> Result<Self, QueueError> {
         let use_ring_event_index = (features & crate::spec::VIRTIO_F_RING_EVENT_IDX as u64) != 0;
+        let use_packed_ring = (features & ((crate::spec::VIRTIO_F_RING_PACKED as u64) << 32)) != 0;
+        if use_packed_ring {
+            return Self::new_packed(use_ring_event_index, mem, params);
+        }
 
         let queue_avail = mem
             .subrange(
@@ -78,10 +94,68 @@ pub fn new(features: u64, mem: GuestMemory, params: QueueParams) -> Result<Self,
             queue_avail,
             queue_used,
             use_ring_event_index,
+            use_packed_ring: false,
+            mem,
+        })
+    }
+
+    fn new_packed(
+        use_ring_event_index: bool,
+        mem: GuestMemory,
+        params: QueueParams,
+    ) -> Result<Self, QueueError> {
+        let queue_desc = mem
+            .subrange(
+                params.desc_addr,
+                size_of::<spec::PackedDescriptor>() as u64 * params.size as u64,
+                true,
+            )
+            .map_err(QueueError::Memory)?;
+
+        let queue_avail = mem
+            .subrange(params.avail_addr, spec::EVENT_SUPPRESS_SIZE, true)
+            .map_err(QueueError::Memory)?;
+
+        let queue_used = mem
+            .subrange(params.used_addr, spec::EVENT_SUPPRESS_SIZE, true)
+            .map_err(QueueError::Memory)?;
+
+        Ok(Self {
+            queue_size: params.size,
+            queue_desc,
+            queue_avail,
+            queue_used,
+            use_ring_event_index,
+            use_packed_ring: true,
             mem,
         })
     }
 
+    /// Returns the starting value for the available and used ring indexes.
+    pub fn initial_index(&self) -> u16 {
+        // The packed ring wrap counters start at 1.
+        if self.use_packed_ring {
+            spec::PACKED_WRAP_COUNTER
+        } else {
+            0
+        }
+    }
+
+    /// Returns the ring index following `index` after consuming
+    /// `descriptor_count` descriptors.
+    pub fn advance_index(&self, index: u16, descriptor_count: u16) -> u16 {
+        if !self.use_packed_ring {
+            return index.wrapping_add(1);
+        }
+        let mut slot = (index & !spec::PACKED_WRAP_COUNTER) as u32 + descriptor_count as u32;
+        let mut wrap = index & spec::PACKED_WRAP_COUNTER;
+        if slot >= self.queue_size as u32 {
+            slot -= self.queue_size as u32;
+            wrap ^= spec::PACKED_WRAP_COUNTER;
+        }
+        slot as u16 | wrap
+    }
+
     fn set_used_flags(&self, flags: spec::UsedFlags) -> Result<(), QueueError> {
         self.queue_used
             .write_plain::<u16_le>(0, &u16::from(flags).into())
@@ -123,8 +197,64 @@ fn is_available(&self, queue_last_avail_index: u16) -> Result<bool, QueueError>
         Ok(true)
     }
 
+    fn is_packed_descriptor_available(&self, avail_index: u16) -> Result<bool, QueueError> {
+        let slot = avail_index & !spec::PACKED_WRAP_COUNTER;
+        let wrap = avail_index & spec::PACKED_WRAP_COUNTER != 0;
+        let flags: spec::PackedDescriptorFlags = self
+            .queue_desc
+            .read_plain::<u16_le>(
+                slot as u64 * size_of::<spec::PackedDescriptor>() as u64
+                    + spec::PACKED_DESC_OFFSET_FLAGS,
+            )
+            .map_err(QueueError::Memory)?
+            .get()
+            .into();
+        Ok(flags.avail() == wrap && flags.used() != wrap)
+    }
+
+    fn set_device_event(&self, off_wrap: u16, flags: u16) -> Result<(), QueueError> {
+        self.queue_used
+            .write_plain::<u16_le>(spec::EVENT_SUPPRESS_OFFSET_OFF_WRAP, &off_wrap.into())
+            .map_err(QueueError::Memory)?;
+        self.queue_used
+            .write_plain::<u16_le>(spec::EVENT_SUPPRESS_OFFSET_FLAGS, &flags.into())
+            .map_err(QueueError::Memory)
+    }
+
+    fn is_packed_available(&self, avail_index: u16) -> Result<bool, QueueError> {
+        if !self.is_packed_descriptor_available(avail_index)? {
+            if self.use_ring_event_index {
+                self.set_device_event(avail_index, spec::EVENT_FLAGS_DESC)?;
+            } else {
+                self.set_device_event(0, spec::EVENT_FLAGS_ENABLE)?;
+            }
+            // Ensure the device event suppression update is visible before
+            // checking the descriptor again.
+            atomic::fence(atomic::Ordering::SeqCst);
+            if !self.is_packed_descriptor_available(avail_index)? {
+                return Ok(false);
+            }
+        }
+        self.set_device_event(0, spec::EVENT_FLAGS_DISABLE)?;
+        // Ensure the descriptor flags read is ordered before subsequent
+        // descriptor reads.
+        atomic::fence(atomic::Ordering::Acquire);
+        Ok(true)
+    }
+
+    /// Returns the descriptor to start reading from for the next available
+    /// buffer, if there is one.
+    ///
+    /// For packed queues this is the ring slot of the first descriptor; the
+    /// buffer id is reported by the [`DescriptorReader`].
     pub fn descriptor_index(&self, avail_index: u16) -> Result<Option<u16>, QueueError> {
-        if self.is_available(avail_index)? {
+        if self.use_packed_ring {
+            if self.is_packed_available(avail_index)? {
+                Ok(Some(avail_index & !spec::PACKED_WRAP_COUNTER))
+            } else {
+                Ok(None)
+            }
+        } else if self.is_available(avail_index)? {
             Ok(Some(self.get_available_descriptor_index(avail_index)?))
         } else {
             Ok(None)
@@ -132,11 +262,15 @@ pub fn descriptor_index(&self, avail_index: u16) -> Result<Option<u16>, QueueErr
     }
 
     pub fn reader(&mut self, descriptor_index: u16) -> DescriptorReader<'_> {
+        let use_packed_ring = self.use_packed_ring;
         DescriptorReader {
             queue: self,
             indirect_queue: None,
+            indirect_len: 0,
             descriptor_index: Some(descriptor_index),
             num_read: 0,
+            buffer_id: descriptor_index,
+            descriptor_count: if use_packed_ring { 0 } else { 1 },
         }
     }
 
@@ -172,8 +306,17 @@ pub fn complete_descriptor(
         &mut self,
         queue_last_used_index: &mut u16,
         descriptor_index: u16,
+        descriptor_count: u16,
         bytes_written: u32,
     ) -> Result<bool, QueueError> {
+        if self.use_packed_ring {
+            return self.complete_packed_descriptor(
+                queue_last_used_index,
+                descriptor_index,
+                descriptor_count,
+                bytes_written,
+            );
+        }
         self.set_used_descriptor(*queue_last_used_index, descriptor_index, bytes_written)?;
         let last_used_index = *queue_last_used_index;
         *queue_last_used_index = queue_last_used_index.wrapping_add(1);
@@ -194,6 +337,68 @@ pub fn complete_descriptor(
         Ok(send_signal)
     }
 
+    fn complete_packed_descriptor(
+        &mut self,
+        queue_last_used_index: &mut u16,
+        buffer_id: u16,
+        descriptor_count: u16,
+        bytes_written: u32,
+    ) -> Result<bool, QueueError> {
+        let slot = *queue_last_used_index & !spec::PACKED_WRAP_COUNTER;
+        let wrap = *queue_last_used_index & spec::PACKED_WRAP_COUNTER != 0;
+        let addr = slot as u64 * size_of::<spec::PackedDescriptor>() as u64;
+        self.queue_desc
+            .write_plain::<u32_le>(addr + spec::PACKED_DESC_OFFSET_LEN, &bytes_written.into())
+            .map_err(QueueError::Memory)?;
+        self.queue_desc
+            .write_plain::<u16_le>(addr + spec::PACKED_DESC_OFFSET_ID, &buffer_id.into())
+            .map_err(QueueError::Memory)?;
+
+        // Ensure the id and length writes are ordered before the flags write
+        // that hands the descriptor back to the driver.
+        atomic::fence(atomic::Ordering::Release);
+        let flags = spec::PackedDescriptorFlags::new()
+            .with_avail(wrap)
+            .with_used(wrap);
+        self.queue_desc
+            .write_plain::<u16_le>(
+                addr + spec::PACKED_DESC_OFFSET_FLAGS,
+                &u16::from(flags).into(),
+            )
+            .map_err(QueueError::Memory)?;
+        *queue_last_used_index = self.advance_index(*queue_last_used_index, descriptor_count);
+
+        // Ensure the flags write is visible before reading the driver event
+        // suppression structure.
+        atomic::fence(atomic::Ordering::SeqCst);
+        let flags = self
+            .queue_avail
+            .read_plain::<u16_le>(spec::EVENT_SUPPRESS_OFFSET_FLAGS)
+            .map_err(QueueError::Memory)?
+            .get();
+        let send_signal = match flags {
+            spec::EVENT_FLAGS_DISABLE => false,
+            spec::EVENT_FLAGS_DESC if self.use_ring_event_index => {
+                let off_wrap = self
+                    .queue_avail
+                    .read_plain::<u16_le>(spec::EVENT_SUPPRESS_OFFSET_OFF_WRAP)
+                    .map_err(QueueError::Memory)?
+                    .get();
+                // Signal if the driver's event index falls within the
+                // descriptors just used, accounting for the wrap counter.
+                let new_index = *queue_last_used_index & !spec::PACKED_WRAP_COUNTER;
+                let mut event_index = off_wrap & !spec::PACKED_WRAP_COUNTER;
+                if (off_wrap ^ *queue_last_used_index) & spec::PACKED_WRAP_COUNTER != 0 {
+                    event_index = event_index.wrapping_sub(self.queue_size);
+                }
+                new_index.wrapping_sub(event_index).wrapping_sub(1) < descriptor_count
+            }
+            _ => true,
+        };
+
+        Ok(send_signal)
+    }
+
     fn get_available_flags(&self) -> Result<spec::AvailableFlags, QueueError> {
         Ok(self
             .queue_avail
@@ -241,8 +446,11 @@ fn set_used_index(&self, index: u16) -> Result<(), QueueError> {
 pub struct DescriptorReader<'a> {
     queue: &'a mut QueueCore,
     indirect_queue: Option<GuestMemory>,
+    indirect_len: u16,
     descriptor_index: Option<u16>,
     num_read: u8,
+    buffer_id: u16,
+    descriptor_count: u16,
 }
 
 pub struct VirtioQueuePayload {
@@ -252,7 +460,106 @@ pub struct VirtioQueuePayload {
 }
 
 impl DescriptorReader<'_> {
+    /// The id to report when completing the buffer. Only valid once all
+    /// descriptors have been read.
+    pub fn buffer_id(&self) -> u16 {
+        self.buffer_id
+    }
+
+    /// The number of ring descriptors consumed by the buffer. Only valid once
+    /// all descriptors have been read.
+    pub fn descriptor_count(&self) -> u16 {
+        self.descriptor_count
+    }
+
+    fn set_packed_buffer_id(&mut self, id: u16) -> Result<(), QueueError> {
+        // Devices use the buffer id to index per-descriptor state, so keep it
+        // within the queue size as with split queues.
+        if id >= self.queue.queue_size {
+            return Err(QueueError::InvalidBufferId(id));
+        }
+        self.buffer_id = id;
+        Ok(())
+    }
+
+    fn next_packed_descriptor(&mut self) -> Result<Option<VirtioQueuePayload>, QueueError> {
+        let Some(descriptor_index) = self.descriptor_index else {
+            return Ok(None);
+        };
+        let descriptor_size = size_of::<spec::PackedDescriptor>() as u64;
+        let descriptor: spec::PackedDescriptor = if let Some(indirect_queue) = &self.indirect_queue
+        {
+            let descriptor: spec::PackedDescriptor = indirect_queue
+                .read_plain(descriptor_index as u64 * descriptor_size)
+                .map_err(QueueError::Memory)?;
+            if descriptor.flags().indirect() {
+                return Err(QueueError::DoubleIndirect);
+            }
+            // Indirect tables are read sequentially; the next flag is unused.
+            let next = descriptor_index + 1;
+            self.descriptor_index = (next < self.indirect_len).then_some(next);
+            descriptor
+        } else {
+            let descriptor: spec::PackedDescriptor = self
+                .queue
+                .queue_desc
+                .read_plain(descriptor_index as u64 * descriptor_size)
+                .map_err(QueueError::Memory)?;
+            self.descriptor_count += 1;
+            if descriptor.flags().indirect() {
+                self.set_packed_buffer_id(descriptor.id.get())?;
+                let indirect_len = descriptor.length.get() as u64 / descriptor_size;
+                if indirect_len == 0 || indirect_len > u16::MAX.into() {
+                    return Err(QueueError::InvalidIndirectLength);
+                }
+                self.indirect_queue = Some(
+                    self.queue
+                        .mem
+                        .subrange(
+                            descriptor.address.get(),
+                            indirect_len * descriptor_size,
+                            true,
+                        )
+                        .map_err(QueueError::Memory)?,
+                );
+                self.indirect_len = indirect_len as u16;
+                self.descriptor_index = Some(0);
+                return self.next_packed_descriptor();
+            }
+            if descriptor.flags().next() {
+                if self.descriptor_count == self.queue.queue_size {
+                    return Err(QueueError::TooLong);
+                }
+                let next = descriptor_index + 1;
+                self.descriptor_index = Some(if next == self.queue.queue_size {
+                    0
+                } else {
+                    next
+                });
+            } else {
+                self.set_packed_buffer_id(descriptor.id.get())?;
+                self.descriptor_index = None;
+            }
+            descriptor
+        };
+
+        self.num_read += 1;
+        // Limit the descriptor chain length to avoid running out of memory.
+        if self.descriptor_index.is_some() && self.num_read == 128 {
+            return Err(QueueError::TooLong);
+        }
+
+        Ok(Some(VirtioQueuePayload {
+            writeable: descriptor.flags().write(),
+            address: descriptor.address.get(),
+            length: descriptor.length.get(),
+        }))
+    }
+
     fn next_descriptor(&mut self) -> Result<Option<VirtioQueuePayload>, QueueError> {
+        if self.queue.use_packed_ring {
+            return self.next_packed_descriptor();
+        }
         let Some(descriptor_index) = self.descriptor_index else {
             return Ok(None);
         };
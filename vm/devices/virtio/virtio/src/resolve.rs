@@ -5,6 +5,7 @@
 
 use crate::VirtioDevice;
 use guestmem::GuestMemory;
+use memory_range::MemoryRange;
 use vm_resource::CanResolveTo;
 use vm_resource::kind::VirtioDeviceHandle;
 use vmcore::vm_task::VmTaskDriverSource;
@@ -28,4 +29,6 @@ pub struct VirtioResolveInput<'a> {
     pub driver_source: &'a VmTaskDriverSource,
     /// The guest memory for virtio device DMA.
     pub guest_memory: &'a GuestMemory,
+    /// The guest RAM ranges.
+    pub guest_ram: &'a [MemoryRange],
 }
@@ -50,6 +50,7 @@ async fn resolve(
                 VirtioResolveInput {
                     driver_source: input.driver_source,
                     guest_memory: input.guest_memory,
+                    guest_ram: input.guest_ram,
                 },
             )
             .await
@@ -17,6 +17,7 @@ mod packed_nums {
 pub const VIRTIO_F_RING_EVENT_IDX: u32 = 0x20000000;
 // Device features - second bank
 pub const VIRTIO_F_VERSION_1: u32 = 1;
+pub const VIRTIO_F_RING_PACKED: u32 = 4;
 
 // Device status
 pub const VIRTIO_ACKNOWLEDGE: u32 = 1;
@@ -126,4 +127,63 @@ pub struct UsedFlags {
         #[bits(15)]
         _reserved: u16,
     }
+
+    /*
+    struct pvirtq_desc {
+        le64 addr;
+        le32 len;
+        le16 id;
+        le16 flags;
+    };
+    */
+    #[repr(C)]
+    #[derive(Debug, Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+    pub struct PackedDescriptor {
+        pub address: u64_le,
+        pub length: u32_le,
+        pub id: u16_le,
+        pub flags_raw: u16_le,
+    }
+
+    impl PackedDescriptor {
+        pub fn flags(&self) -> PackedDescriptorFlags {
+            self.flags_raw.get().into()
+        }
+    }
+
+    pub const PACKED_DESC_OFFSET_LEN: u64 = 8;
+    pub const PACKED_DESC_OFFSET_ID: u64 = 12;
+    pub const PACKED_DESC_OFFSET_FLAGS: u64 = 14;
+
+    #[bitfield(u16)]
+    #[derive(IntoBytes, Immutable, KnownLayout, FromBytes)]
+    pub struct PackedDescriptorFlags {
+        pub next: bool,
+        pub write: bool,
+        pub indirect: bool,
+        #[bits(4)]
+        _reserved: u8,
+        pub avail: bool,
+        #[bits(7)]
+        _reserved2: u8,
+        pub used: bool,
+    }
+
+    /*
+    struct pvirtq_event_suppress {
+        le16 desc_event_off_wrap;
+        le16 desc_event_flags;
+    };
+    */
+    pub const EVENT_SUPPRESS_OFFSET_OFF_WRAP: u64 = 0;
+    pub const EVENT_SUPPRESS_OFFSET_FLAGS: u64 = 2;
+    pub const EVENT_SUPPRESS_SIZE: u64 = 4;
+
+    pub const EVENT_FLAGS_ENABLE: u16 = 0;
+    pub const EVENT_FLAGS_DISABLE: u16 = 1;
+    pub const EVENT_FLAGS_DESC: u16 = 2;
+
+    /// Bit in a packed ring index (and in `desc_event_off_wrap`) holding the
+    /// ring wrap counter.
+    pub const PACKED_WRAP_COUNTER: u16 = 0x8000;
 }
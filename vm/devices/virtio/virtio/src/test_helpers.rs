@@ -0,0 +1,196 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Helpers for testing virtio devices, acting as the guest driver of a split
+//! virtqueue.
+
+use crate::QueueResources;
+use crate::queue::QueueParams;
+use crate::spec::queue::AVAIL_ELEMENT_SIZE;
+use crate::spec::queue::AVAIL_OFFSET_IDX;
+use crate::spec::queue::AVAIL_OFFSET_RING;
+use crate::spec::queue::Descriptor;
+use crate::spec::queue::DescriptorFlags;
+use crate::spec::queue::USED_ELEMENT_SIZE;
+use crate::spec::queue::USED_OFFSET_IDX;
+use crate::spec::queue::USED_OFFSET_RING;
+use crate::spec::queue::UsedElement;
+use crate::spec::u16_le;
+use guestmem::GuestMemory;
+use pal_async::driver::Driver;
+use pal_async::wait::PolledWait;
+use pal_event::Event;
+use vmcore::interrupt::Interrupt;
+
+/// A buffer in a descriptor chain.
+#[derive(Debug, Copy, Clone)]
+pub struct TestBuffer {
+    /// The guest physical address of the buffer.
+    pub address: u64,
+    /// The length of the buffer.
+    pub len: u32,
+    /// Whether the device writes to the buffer.
+    pub writeable: bool,
+}
+
+impl TestBuffer {
+    /// A buffer read by the device.
+    pub fn readable(address: u64, len: u32) -> Self {
+        Self {
+            address,
+            len,
+            writeable: false,
+        }
+    }
+
+    /// A buffer written by the device.
+    pub fn writeable(address: u64, len: u32) -> Self {
+        Self {
+            address,
+            len,
+            writeable: true,
+        }
+    }
+}
+
+/// The driver side of a split virtqueue in guest memory.
+pub struct TestQueue {
+    mem: GuestMemory,
+    params: QueueParams,
+    kick: Event,
+    interrupt: PolledWait<Event>,
+    next_desc: u16,
+    avail_idx: u16,
+    used_idx: u16,
+}
+
+impl TestQueue {
+    /// The number of bytes of guest memory used by a queue of `size`
+    /// entries.
+    pub fn memory_size(size: u16) -> u64 {
+        let (_, _, used) = Self::layout(0, size);
+        used + USED_OFFSET_RING + USED_ELEMENT_SIZE * size as u64 + 2
+    }
+
+    /// Returns the addresses of the descriptor table, available ring and
+    /// used ring.
+    fn layout(base: u64, size: u16) -> (u64, u64, u64) {
+        let avail = base + size_of::<Descriptor>() as u64 * size as u64;
+        let used =
+            (avail + AVAIL_OFFSET_RING + AVAIL_ELEMENT_SIZE * size as u64 + 2).next_multiple_of(4);
+        (base, avail, used)
+    }
+
+    /// Creates a queue of `size` entries at `base` in `mem`, zeroing its
+    /// rings.
+    pub fn new(driver: &(impl ?Sized + Driver), mem: &GuestMemory, base: u64, size: u16) -> Self {
+        let (desc_addr, avail_addr, used_addr) = Self::layout(base, size);
+        mem.fill_at(base, 0, Self::memory_size(size) as usize)
+            .unwrap();
+        let interrupt = Event::new();
+        Self {
+            mem: mem.clone(),
+            params: QueueParams {
+                size,
+                enable: true,
+                desc_addr,
+                avail_addr,
+                used_addr,
+            },
+            kick: Event::new(),
+            interrupt: PolledWait::new(driver, interrupt).unwrap(),
+            next_desc: 0,
+            avail_idx: 0,
+            used_idx: 0,
+        }
+    }
+
+    /// Returns the resources to pass to the device for this queue.
+    pub fn resources(&self) -> QueueResources {
+        QueueResources {
+            params: self.params,
+            notify: Interrupt::from_event(self.interrupt.get().clone()),
+            event: self.kick.clone(),
+        }
+    }
+
+    /// Makes a descriptor chain of `buffers` available to the device and
+    /// notifies it, returning the chain's head descriptor index.
+    pub fn add(&mut self, buffers: &[TestBuffer]) -> u16 {
+        assert!(!buffers.is_empty() && buffers.len() <= self.params.size as usize);
+        let size = self.params.size;
+        let head = self.next_desc;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let index = (head + i as u16) % size;
+            let last = i == buffers.len() - 1;
+            let descriptor = Descriptor {
+                address: buffer.address.into(),
+                length: buffer.len.into(),
+                flags_raw: u16::from(
+                    DescriptorFlags::new()
+                        .with_next(!last)
+                        .with_write(buffer.writeable),
+                )
+                .into(),
+                next: ((index + 1) % size).into(),
+            };
+            self.mem
+                .write_plain(
+                    self.params.desc_addr + size_of::<Descriptor>() as u64 * index as u64,
+                    &descriptor,
+                )
+                .unwrap();
+        }
+        self.next_desc = (head + buffers.len() as u16) % size;
+
+        let slot = self.avail_idx % size;
+        self.mem
+            .write_plain::<u16_le>(
+                self.params.avail_addr + AVAIL_OFFSET_RING + AVAIL_ELEMENT_SIZE * slot as u64,
+                &head.into(),
+            )
+            .unwrap();
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        self.mem
+            .write_plain::<u16_le>(
+                self.params.avail_addr + AVAIL_OFFSET_IDX,
+                &self.avail_idx.into(),
+            )
+            .unwrap();
+        self.kick.signal();
+        head
+    }
+
+    /// Returns the head descriptor index and written length of the next used
+    /// chain, if there is one.
+    pub fn try_used(&mut self) -> Option<(u16, u32)> {
+        let used_idx = self
+            .mem
+            .read_plain::<u16_le>(self.params.used_addr + USED_OFFSET_IDX)
+            .unwrap()
+            .get();
+        if used_idx == self.used_idx {
+            return None;
+        }
+        let slot = self.used_idx % self.params.size;
+        let element = self
+            .mem
+            .read_plain::<UsedElement>(
+                self.params.used_addr + USED_OFFSET_RING + USED_ELEMENT_SIZE * slot as u64,
+            )
+            .unwrap();
+        self.used_idx = self.used_idx.wrapping_add(1);
+        Some((element.id.get() as u16, element.len.get()))
+    }
+
+    /// Waits for the device to return the next used chain, returning its head
+    /// descriptor index and written length.
+    pub async fn used(&mut self) -> (u16, u32) {
+        loop {
+            if let Some(used) = self.try_used() {
+                break used;
+            }
+            self.interrupt.wait().await.unwrap();
+        }
+    }
+}
@@ -242,9 +242,12 @@ struct VirtioTestGuest {
     num_queues: u16,
     queue_size: u16,
     use_ring_event_index: bool,
+    use_packed_ring: bool,
     last_avail_index: Vec<u16>,
     last_used_index: Vec<u16>,
     avail_descriptors: Vec<Vec<bool>>,
+    // Number of ring descriptors used by each outstanding packed buffer id.
+    packed_descriptor_count: Vec<Vec<u16>>,
     exit_event: event_listener::Event,
 }
 
@@ -255,20 +258,27 @@ fn new(
         num_queues: u16,
         queue_size: u16,
         use_ring_event_index: bool,
+        use_packed_ring: bool,
     ) -> Self {
-        let last_avail_index: Vec<u16> = vec![0; num_queues as usize];
-        let last_used_index: Vec<u16> = vec![0; num_queues as usize];
+        // Packed ring indexes carry the wrap counter in bit 15, starting at 1.
+        let initial_index = if use_packed_ring { 0x8000 } else { 0 };
+        let last_avail_index: Vec<u16> = vec![initial_index; num_queues as usize];
+        let last_used_index: Vec<u16> = vec![initial_index; num_queues as usize];
         let avail_descriptors: Vec<Vec<bool>> =
             vec![vec![true; queue_size as usize]; num_queues as usize];
+        let packed_descriptor_count: Vec<Vec<u16>> =
+            vec![vec![0; queue_size as usize]; num_queues as usize];
         let test_guest = Self {
             test_mem: test_mem.clone(),
             driver: driver.clone(),
             num_queues,
             queue_size,
             use_ring_event_index,
+            use_packed_ring,
             last_avail_index,
             last_used_index,
             avail_descriptors,
+            packed_descriptor_count,
             exit_event: event_listener::Event::new(),
         };
         for i in 0..num_queues {
@@ -310,11 +320,14 @@ fn create_direct_queues<F>(&self, f: F) -> Vec<TaskControl<VirtioQueueWorker, Vi
     }
 
     fn queue_features(&self) -> u64 {
+        let mut features = 0;
         if self.use_ring_event_index {
-            VIRTIO_F_RING_EVENT_IDX as u64
-        } else {
-            0
+            features |= VIRTIO_F_RING_EVENT_IDX as u64;
+        }
+        if self.use_packed_ring {
+            features |= (VIRTIO_F_RING_PACKED as u64) << 32;
         }
+        features
     }
 
     fn queue_params(&self, i: u16) -> QueueParams {
@@ -487,6 +500,11 @@ fn get_queue_descriptor(&self, queue_index: u16, descriptor_index: u16) -> u64 {
     }
 
     fn add_queue_memory(&self, queue_index: u16) {
+        if self.use_packed_ring {
+            self.add_packed_queue_memory(queue_index);
+            return;
+        }
+
         // descriptors
         for i in 0..self.queue_size {
             let base = self.get_queue_descriptor(queue_index, i);
@@ -555,6 +573,31 @@ fn add_queue_memory(&self, queue_index: u16) {
         }
     }
 
+    fn add_packed_queue_memory(&self, queue_index: u16) {
+        // descriptor ring, written by both the driver and the device
+        for i in 0..self.queue_size {
+            let base = self.get_queue_descriptor(queue_index, i);
+            self.test_mem.modify_memory_map(base, &[0; 16], true);
+        }
+
+        // driver event suppression (off_wrap, flags)
+        let (off_wrap, flags) = if self.use_ring_event_index {
+            (0x8000u16, EVENT_FLAGS_DESC)
+        } else {
+            (0, EVENT_FLAGS_ENABLE)
+        };
+        let base = self.get_queue_available_base_address(queue_index);
+        self.test_mem
+            .modify_memory_map(base, &off_wrap.to_le_bytes(), false);
+        self.test_mem
+            .modify_memory_map(base + 2, &flags.to_le_bytes(), false);
+
+        // device event suppression (off_wrap, flags)
+        let base = self.get_queue_used_base_address(queue_index);
+        self.test_mem
+            .modify_memory_map(base, &0u32.to_le_bytes(), true);
+    }
+
     fn reserve_descriptor(&mut self, queue_index: u16) -> u16 {
         let avail_descriptors = &mut self.avail_descriptors[queue_index as usize];
         for (i, desc) in avail_descriptors.iter_mut().enumerate() {
@@ -569,6 +612,12 @@ fn reserve_descriptor(&mut self, queue_index: u16) -> u16 {
 
     fn free_descriptor(&mut self, queue_index: u16, desc_index: u16) {
         assert!(desc_index < self.queue_size);
+        if self.use_packed_ring {
+            let avail_descriptors = &mut self.avail_descriptors[queue_index as usize];
+            assert_eq!(avail_descriptors[desc_index as usize], false);
+            avail_descriptors[desc_index as usize] = true;
+            return;
+        }
         let desc_addr = self.get_queue_descriptor(queue_index, desc_index);
         let flags: DescriptorFlags = self.test_mem.memory_map_get_u16(desc_addr + 12).into();
         if flags.next() {
@@ -597,7 +646,81 @@ fn queue_available_desc(&mut self, queue_index: u16, desc_index: u16) {
         );
     }
 
+    fn advance_packed_index(&self, index: u16) -> u16 {
+        let slot = (index & 0x7fff) + 1;
+        if slot == self.queue_size {
+            (index & 0x8000) ^ 0x8000
+        } else {
+            slot | (index & 0x8000)
+        }
+    }
+
+    /// Writes the buffer `id` to the next available packed ring slots. The
+    /// descriptors are given as (address, length, flags) and the first
+    /// descriptor is made available last.
+    fn queue_available_packed(
+        &mut self,
+        queue_index: u16,
+        id: u16,
+        descriptors: &[(u64, u32, u16)],
+    ) {
+        let start = self.last_avail_index[queue_index as usize];
+        let mut index = start;
+        let mut first_flags = 0;
+        for (i, &(address, length, flags)) in descriptors.iter().enumerate() {
+            let wrap = index & 0x8000 != 0;
+            let flags = u16::from(
+                PackedDescriptorFlags::from(flags)
+                    .with_avail(wrap)
+                    .with_used(!wrap),
+            );
+            let base = self.get_queue_descriptor(queue_index, index & 0x7fff);
+            self.test_mem
+                .modify_memory_map(base, &address.to_le_bytes(), true);
+            self.test_mem
+                .modify_memory_map(base + 8, &length.to_le_bytes(), true);
+            self.test_mem
+                .modify_memory_map(base + 12, &id.to_le_bytes(), true);
+            if i == 0 {
+                first_flags = flags;
+            } else {
+                self.test_mem
+                    .modify_memory_map(base + 14, &flags.to_le_bytes(), true);
+            }
+            index = self.advance_packed_index(index);
+        }
+        self.packed_descriptor_count[queue_index as usize][id as usize] = descriptors.len() as u16;
+        self.last_avail_index[queue_index as usize] = index;
+        self.test_mem.modify_memory_map(
+            self.get_queue_descriptor(queue_index, start & 0x7fff) + 14,
+            &first_flags.to_le_bytes(),
+            true,
+        );
+    }
+
+    fn add_packed_indirect_table(&self, queue_index: u16, desc_count: u16) -> u64 {
+        let buffer_addr = self.get_queue_descriptor_backing_memory_address(queue_index);
+        for i in 0..desc_count {
+            let base = buffer_addr + 0x10 * i as u64;
+            let indirect_buffer_addr = 0xffffffff00000000u64 + 0x1000 * i as u64;
+            self.test_mem
+                .modify_memory_map(base, &indirect_buffer_addr.to_le_bytes(), false);
+            self.test_mem
+                .modify_memory_map(base + 8, &0x1000u32.to_le_bytes(), false);
+            self.test_mem
+                .modify_memory_map(base + 12, &0u32.to_le_bytes(), false);
+        }
+        buffer_addr
+    }
+
     fn add_to_avail_queue(&mut self, queue_index: u16) {
+        if self.use_packed_ring {
+            let id = self.reserve_descriptor(queue_index);
+            let address =
+                self.get_queue_descriptor_backing_memory_address(queue_index) + 0x1000 * id as u64;
+            self.queue_available_packed(queue_index, id, &[(address, 0x1000, 0)]);
+            return;
+        }
         let next_descriptor = self.reserve_descriptor(queue_index);
         // flags
         self.test_mem.modify_memory_map(
@@ -609,6 +732,10 @@ fn add_to_avail_queue(&mut self, queue_index: u16) {
     }
 
     fn add_indirect_to_avail_queue(&mut self, queue_index: u16) {
+        if self.use_packed_ring {
+            self.add_indirect_linked_to_avail_queue(queue_index, 1);
+            return;
+        }
         let next_descriptor = self.reserve_descriptor(queue_index);
         // flags
         self.test_mem.modify_memory_map(
@@ -634,6 +761,22 @@ fn add_indirect_to_avail_queue(&mut self, queue_index: u16) {
     }
 
     fn add_linked_to_avail_queue(&mut self, queue_index: u16, desc_count: u16) {
+        if self.use_packed_ring {
+            let id = self.reserve_descriptor(queue_index);
+            let base_address = self.get_queue_descriptor_backing_memory_address(queue_index);
+            let descriptors = (0..desc_count)
+                .map(|i| {
+                    let flags = if i < desc_count - 1 {
+                        u16::from(PackedDescriptorFlags::new().with_next(true))
+                    } else {
+                        0
+                    };
+                    (base_address + 0x1000 * (id + i) as u64, 0x1000, flags)
+                })
+                .collect::<Vec<_>>();
+            self.queue_available_packed(queue_index, id, &descriptors);
+            return;
+        }
         let mut descriptors = Vec::with_capacity(desc_count as usize);
         for _ in 0..desc_count {
             descriptors.push(self.reserve_descriptor(queue_index));
@@ -660,6 +803,17 @@ fn add_linked_to_avail_queue(&mut self, queue_index: u16, desc_count: u16) {
     }
 
     fn add_indirect_linked_to_avail_queue(&mut self, queue_index: u16, desc_count: u16) {
+        if self.use_packed_ring {
+            let id = self.reserve_descriptor(queue_index);
+            let table = self.add_packed_indirect_table(queue_index, desc_count);
+            let flags = u16::from(PackedDescriptorFlags::new().with_indirect(true));
+            self.queue_available_packed(
+                queue_index,
+                id,
+                &[(table, 0x10 * desc_count as u32, flags)],
+            );
+            return;
+        }
         let next_descriptor = self.reserve_descriptor(queue_index);
         // flags
         self.test_mem.modify_memory_map(
@@ -695,6 +849,9 @@ fn add_indirect_linked_to_avail_queue(&mut self, queue_index: u16, desc_count: u
     }
 
     fn get_next_completed(&mut self, queue_index: u16) -> Option<(u16, u32)> {
+        if self.use_packed_ring {
+            return self.get_next_completed_packed(queue_index);
+        }
         let avail_base_addr = self.get_queue_available_base_address(queue_index);
         let used_base_addr = self.get_queue_used_base_address(queue_index);
         let cur_used_index = self.test_mem.memory_map_get_u16(used_base_addr + 2);
@@ -723,6 +880,35 @@ fn get_next_completed(&mut self, queue_index: u16) -> Option<(u16, u32)> {
         self.free_descriptor(queue_index, desc_index);
         Some((desc_index, bytes_written))
     }
+
+    fn get_next_completed_packed(&mut self, queue_index: u16) -> Option<(u16, u32)> {
+        let last_used_index = self.last_used_index[queue_index as usize];
+        let wrap = last_used_index & 0x8000 != 0;
+        let base = self.get_queue_descriptor(queue_index, last_used_index & 0x7fff);
+        let flags: PackedDescriptorFlags = self.test_mem.memory_map_get_u16(base + 14).into();
+        if flags.avail() != wrap || flags.used() != wrap {
+            return None;
+        }
+
+        let desc_index = self.test_mem.memory_map_get_u16(base + 12);
+        let bytes_written = self.test_mem.memory_map_get_u32(base + 8);
+        let mut index = last_used_index;
+        for _ in 0..self.packed_descriptor_count[queue_index as usize][desc_index as usize] {
+            index = self.advance_packed_index(index);
+        }
+        self.last_used_index[queue_index as usize] = index;
+
+        if self.use_ring_event_index {
+            self.test_mem.modify_memory_map(
+                self.get_queue_available_base_address(queue_index),
+                &index.to_le_bytes(),
+                false,
+            );
+        }
+
+        self.free_descriptor(queue_index, desc_index);
+        Some((desc_index, bytes_written))
+    }
 }
 
 struct VirtioTestWork {
@@ -896,7 +1082,7 @@ async fn verify_chipset_config(driver: DefaultDriver) {
     // device feature (bank 1)
     dev.write_u32(20, 1);
     assert_eq!(dev.read_u32(20), 1);
-    assert_eq!(dev.read_u32(16), VIRTIO_F_VERSION_1);
+    assert_eq!(dev.read_u32(16), VIRTIO_F_VERSION_1 | VIRTIO_F_RING_PACKED);
     // device feature (bank 2)
     dev.write_u32(20, 2);
     assert_eq!(dev.read_u32(16), 0);
@@ -916,7 +1102,7 @@ async fn verify_chipset_config(driver: DefaultDriver) {
     // driver feature (bank 1)
     assert_eq!(dev.read_u32(32), 0);
     dev.write_u32(32, 0xffffffff);
-    assert_eq!(dev.read_u32(32), VIRTIO_F_VERSION_1);
+    assert_eq!(dev.read_u32(32), VIRTIO_F_VERSION_1 | VIRTIO_F_RING_PACKED);
     // driver feature (bank 2)
     dev.write_u32(36, 2);
     assert_eq!(dev.read_u32(32), 0);
@@ -1203,7 +1389,7 @@ async fn verify_pci_registers(driver: DefaultDriver) {
     assert_eq!(pci_test_device.read_u32(bar_address1), 1);
     assert_eq!(
         pci_test_device.read_u32(bar_address1 + 4),
-        VIRTIO_F_VERSION_1
+        VIRTIO_F_VERSION_1 | VIRTIO_F_RING_PACKED
     );
     // device feature (bank 2)
     pci_test_device.write_u32(bar_address1, 2);
@@ -1227,7 +1413,7 @@ async fn verify_pci_registers(driver: DefaultDriver) {
     pci_test_device.write_u32(bar_address1 + 12, 0xffffffff);
     assert_eq!(
         pci_test_device.read_u32(bar_address1 + 12),
-        VIRTIO_F_VERSION_1
+        VIRTIO_F_VERSION_1 | VIRTIO_F_RING_PACKED
     );
     // driver feature (bank 2)
     pci_test_device.write_u32(bar_address1 + 8, 2);
@@ -1321,10 +1507,9 @@ async fn verify_pci_registers(driver: DefaultDriver) {
     assert_eq!(pci_test_device.read_u32(bar_address1 + 52), 0);
 }
 
-#[async_test]
-async fn verify_queue_simple(driver: DefaultDriver) {
+async fn queue_simple(driver: DefaultDriver, use_packed_ring: bool) {
     let test_mem = VirtioTestMemoryAccess::new();
-    let mut guest = VirtioTestGuest::new(&driver, &test_mem, 1, 2, true);
+    let mut guest = VirtioTestGuest::new(&driver, &test_mem, 1, 2, true, use_packed_ring);
     let base_addr = guest.get_queue_descriptor_backing_memory_address(0);
     let (tx, mut rx) = mesh::mpsc_channel();
     let event = Event::new();
@@ -1357,9 +1542,18 @@ async fn verify_queue_simple(driver: DefaultDriver) {
 }
 
 #[async_test]
-async fn verify_queue_indirect(driver: DefaultDriver) {
+async fn verify_queue_simple(driver: DefaultDriver) {
+    queue_simple(driver, false).await;
+}
+
+#[async_test]
+async fn verify_packed_queue_simple(driver: DefaultDriver) {
+    queue_simple(driver, true).await;
+}
+
+async fn queue_indirect(driver: DefaultDriver, use_packed_ring: bool) {
     let test_mem = VirtioTestMemoryAccess::new();
-    let mut guest = VirtioTestGuest::new(&driver, &test_mem, 1, 2, true);
+    let mut guest = VirtioTestGuest::new(&driver, &test_mem, 1, 2, true, use_packed_ring);
     let (tx, mut rx) = mesh::mpsc_channel();
     let event = Event::new();
     let mut queues = guest.create_direct_queues(|i| {
@@ -1391,9 +1585,18 @@ async fn verify_queue_indirect(driver: DefaultDriver) {
 }
 
 #[async_test]
-async fn verify_queue_linked(driver: DefaultDriver) {
+async fn verify_queue_indirect(driver: DefaultDriver) {
+    queue_indirect(driver, false).await;
+}
+
+#[async_test]
+async fn verify_packed_queue_indirect(driver: DefaultDriver) {
+    queue_indirect(driver, true).await;
+}
+
+async fn queue_linked(driver: DefaultDriver, use_packed_ring: bool) {
     let test_mem = VirtioTestMemoryAccess::new();
-    let mut guest = VirtioTestGuest::new(&driver, &test_mem, 1, 5, true);
+    let mut guest = VirtioTestGuest::new(&driver, &test_mem, 1, 5, true, use_packed_ring);
     let (tx, mut rx) = mesh::mpsc_channel();
     let base_address = guest.get_queue_descriptor_backing_memory_address(0);
     let event = Event::new();
@@ -1428,9 +1631,18 @@ async fn verify_queue_linked(driver: DefaultDriver) {
 }
 
 #[async_test]
-async fn verify_queue_indirect_linked(driver: DefaultDriver) {
+async fn verify_queue_linked(driver: DefaultDriver) {
+    queue_linked(driver, false).await;
+}
+
+#[async_test]
+async fn verify_packed_queue_linked(driver: DefaultDriver) {
+    queue_linked(driver, true).await;
+}
+
+async fn queue_indirect_linked(driver: DefaultDriver, use_packed_ring: bool) {
     let test_mem = VirtioTestMemoryAccess::new();
-    let mut guest = VirtioTestGuest::new(&driver, &test_mem, 1, 5, true);
+    let mut guest = VirtioTestGuest::new(&driver, &test_mem, 1, 5, true, use_packed_ring);
     let (tx, mut rx) = mesh::mpsc_channel();
     let event = Event::new();
     let mut queues = guest.create_direct_queues(|i| {
@@ -1467,9 +1679,18 @@ async fn verify_queue_indirect_linked(driver: DefaultDriver) {
 }
 
 #[async_test]
-async fn verify_queue_avail_rollover(driver: DefaultDriver) {
+async fn verify_queue_indirect_linked(driver: DefaultDriver) {
+    queue_indirect_linked(driver, false).await;
+}
+
+#[async_test]
+async fn verify_packed_queue_indirect_linked(driver: DefaultDriver) {
+    queue_indirect_linked(driver, true).await;
+}
+
+async fn queue_avail_rollover(driver: DefaultDriver, use_packed_ring: bool) {
     let test_mem = VirtioTestMemoryAccess::new();
-    let mut guest = VirtioTestGuest::new(&driver, &test_mem, 1, 2, true);
+    let mut guest = VirtioTestGuest::new(&driver, &test_mem, 1, 2, true, use_packed_ring);
     let base_addr = guest.get_queue_descriptor_backing_memory_address(0);
     let (tx, mut rx) = mesh::mpsc_channel();
     let event = Event::new();
@@ -1505,9 +1726,18 @@ async fn verify_queue_avail_rollover(driver: DefaultDriver) {
 }
 
 #[async_test]
-async fn verify_multi_queue(driver: DefaultDriver) {
+async fn verify_queue_avail_rollover(driver: DefaultDriver) {
+    queue_avail_rollover(driver, false).await;
+}
+
+#[async_test]
+async fn verify_packed_queue_avail_rollover(driver: DefaultDriver) {
+    queue_avail_rollover(driver, true).await;
+}
+
+async fn multi_queue(driver: DefaultDriver, use_packed_ring: bool) {
     let test_mem = VirtioTestMemoryAccess::new();
-    let mut guest = VirtioTestGuest::new(&driver, &test_mem, 5, 2, true);
+    let mut guest = VirtioTestGuest::new(&driver, &test_mem, 5, 2, true, use_packed_ring);
     let (tx, mut rx) = mesh::mpsc_channel();
     let events = (0..guest.num_queues)
         .map(|_| Event::new())
@@ -1554,6 +1784,16 @@ async fn verify_multi_queue(driver: DefaultDriver) {
     }
 }
 
+#[async_test]
+async fn verify_multi_queue(driver: DefaultDriver) {
+    multi_queue(driver, false).await;
+}
+
+#[async_test]
+async fn verify_packed_multi_queue(driver: DefaultDriver) {
+    multi_queue(driver, true).await;
+}
+
 fn take_mmio_interrupt_status(dev: &mut VirtioMmioDevice, mask: u32) -> u32 {
     let mut v = [0; 4];
     dev.mmio_read(96, &mut v).unwrap();
@@ -1573,13 +1813,12 @@ async fn expect_mmio_interrupt(
     assert!(multiple_expected || !target.is_high(0));
 }
 
-#[async_test]
-async fn verify_device_queue_simple(driver: DefaultDriver) {
+async fn device_queue_simple(driver: DefaultDriver, use_packed_ring: bool) {
     let test_mem = VirtioTestMemoryAccess::new();
     let doorbell_registration: Arc<dyn DoorbellRegistration> = test_mem.clone();
-    let mut guest = VirtioTestGuest::new(&driver, &test_mem, 1, 2, true);
+    let mut guest = VirtioTestGuest::new(&driver, &test_mem, 1, 2, true, use_packed_ring);
     let mem = guest.mem();
-    let features = ((VIRTIO_F_VERSION_1 as u64) << 32) | VIRTIO_F_RING_EVENT_IDX as u64 | 2;
+    let features = ((VIRTIO_F_VERSION_1 as u64) << 32) | guest.queue_features() | 2;
     let target = TestLineInterruptTarget::new_arc();
     let interrupt = LineInterrupt::new_with_target("test", target.clone(), 0);
     let base_addr = guest.get_queue_descriptor_backing_memory_address(0);
@@ -1638,13 +1877,22 @@ async fn verify_device_queue_simple(driver: DefaultDriver) {
 }
 
 #[async_test]
-async fn verify_device_multi_queue(driver: DefaultDriver) {
+async fn verify_device_queue_simple(driver: DefaultDriver) {
+    device_queue_simple(driver, false).await;
+}
+
+#[async_test]
+async fn verify_packed_device_queue_simple(driver: DefaultDriver) {
+    device_queue_simple(driver, true).await;
+}
+
+async fn device_multi_queue(driver: DefaultDriver, use_packed_ring: bool) {
     let num_queues = 5;
     let test_mem = VirtioTestMemoryAccess::new();
     let doorbell_registration: Arc<dyn DoorbellRegistration> = test_mem.clone();
-    let mut guest = VirtioTestGuest::new(&driver, &test_mem, num_queues, 2, true);
+    let mut guest = VirtioTestGuest::new(&driver, &test_mem, num_queues, 2, true, use_packed_ring);
     let mem = guest.mem();
-    let features = ((VIRTIO_F_VERSION_1 as u64) << 32) | VIRTIO_F_RING_EVENT_IDX as u64 | 2;
+    let features = ((VIRTIO_F_VERSION_1 as u64) << 32) | guest.queue_features() | 2;
     let target = TestLineInterruptTarget::new_arc();
     let interrupt = LineInterrupt::new_with_target("test", target.clone(), 0);
     let base_addr: Vec<_> = (0..num_queues)
@@ -1716,11 +1964,20 @@ async fn verify_device_multi_queue(driver: DefaultDriver) {
 }
 
 #[async_test]
-async fn verify_device_multi_queue_pci(driver: DefaultDriver) {
+async fn verify_device_multi_queue(driver: DefaultDriver) {
+    device_multi_queue(driver, false).await;
+}
+
+#[async_test]
+async fn verify_packed_device_multi_queue(driver: DefaultDriver) {
+    device_multi_queue(driver, true).await;
+}
+
+async fn device_multi_queue_pci(driver: DefaultDriver, use_packed_ring: bool) {
     let num_queues = 5;
     let test_mem = VirtioTestMemoryAccess::new();
-    let mut guest = VirtioTestGuest::new(&driver, &test_mem, num_queues, 2, true);
-    let features = ((VIRTIO_F_VERSION_1 as u64) << 32) | VIRTIO_F_RING_EVENT_IDX as u64 | 2;
+    let mut guest = VirtioTestGuest::new(&driver, &test_mem, num_queues, 2, true, use_packed_ring);
+    let features = ((VIRTIO_F_VERSION_1 as u64) << 32) | guest.queue_features() | 2;
     let base_addr: Vec<_> = (0..num_queues)
         .map(|i| guest.get_queue_descriptor_backing_memory_address(i))
         .collect();
@@ -1774,3 +2031,13 @@ async fn verify_device_multi_queue_pci(driver: DefaultDriver) {
         .unwrap();
     drop(dev);
 }
+
+#[async_test]
+async fn verify_device_multi_queue_pci(driver: DefaultDriver) {
+    device_multi_queue_pci(driver, false).await;
+}
+
+#[async_test]
+async fn verify_packed_device_multi_queue_pci(driver: DefaultDriver) {
+    device_multi_queue_pci(driver, true).await;
+}
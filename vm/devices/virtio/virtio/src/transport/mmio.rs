@@ -72,8 +72,9 @@ fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 }
 
 impl InspectMut for VirtioMmioDevice {
-    fn inspect_mut(&mut self, _req: inspect::Request<'_>) {
-        // TODO
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        // TODO: inspect the transport state
+        req.respond().field_mut("device", &mut *self.device);
     }
 }
 
@@ -109,7 +110,7 @@ pub fn new(
                 traits.device_features as u32
                     | VIRTIO_F_RING_EVENT_IDX
                     | VIRTIO_F_RING_INDIRECT_DESC,
-                (traits.device_features >> 32) as u32 | VIRTIO_F_VERSION_1,
+                (traits.device_features >> 32) as u32 | VIRTIO_F_VERSION_1 | VIRTIO_F_RING_PACKED,
             ],
             device_feature_select: 0,
             driver_feature: [0; 2],
@@ -404,11 +405,21 @@ pub(crate) fn write_u32(&mut self, address: u64, val: u32) {
                         })
                         .collect();
 
+                    let config_change = {
+                        let interrupt_state = self.interrupt_state.clone();
+                        Interrupt::from_fn(move || {
+                            interrupt_state
+                                .lock()
+                                .update(true, VIRTIO_MMIO_INTERRUPT_STATUS_CONFIG_CHANGE);
+                        })
+                    };
+
                     self.device.enable(Resources {
                         features,
                         queues,
                         shared_memory_region: None,
                         shared_memory_size: 0,
+                        config_change,
                     });
 
                     self.device_status |= VIRTIO_DRIVER_OK;
@@ -62,7 +62,7 @@ enum InterruptKind {
 /// Run a virtio device over PCI
 #[derive(InspectMut)]
 pub struct VirtioPciDevice {
-    #[inspect(skip)]
+    #[inspect(mut)]
     device: Box<dyn VirtioDevice>,
     #[inspect(skip)]
     device_feature: [u32; 2],
@@ -216,7 +216,7 @@ pub fn new(
                 (traits.device_features & 0xffffffff) as u32
                     | VIRTIO_F_RING_EVENT_IDX
                     | VIRTIO_F_RING_INDIRECT_DESC,
-                (traits.device_features >> 32) as u32 | VIRTIO_F_VERSION_1,
+                (traits.device_features >> 32) as u32 | VIRTIO_F_VERSION_1 | VIRTIO_F_RING_PACKED,
             ],
             device_feature_select: 0,
             driver_feature: [0; 2],
@@ -461,11 +461,27 @@ fn write_u32(&mut self, address: u64, offset: u16, val: u32) {
                         })
                         .collect();
 
+                    let config_change = match &self.interrupt_kind {
+                        // The driver may not assign a config vector at all.
+                        InterruptKind::Msix(msix) => msix
+                            .interrupt(self.msix_config_vector)
+                            .unwrap_or_else(Interrupt::null),
+                        InterruptKind::IntX(line) => {
+                            let interrupt_status = self.interrupt_status.clone();
+                            let line = line.clone();
+                            Interrupt::from_fn(move || {
+                                *interrupt_status.lock() |= 2;
+                                line.set_level(true);
+                            })
+                        }
+                    };
+
                     self.device.enable(Resources {
                         features,
                         queues,
                         shared_memory_region: self.shared_memory_region.clone(),
                         shared_memory_size: self.shared_memory_size,
+                        config_change,
                     });
 
                     self.device_status |= VIRTIO_DRIVER_OK;
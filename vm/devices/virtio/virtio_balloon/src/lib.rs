@@ -0,0 +1,718 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A virtio balloon device, which lets the host reclaim memory from the guest.
+//!
+//! Pages that the guest places in the balloon, and free pages that it reports
+//! via free page reporting, are decommitted from the guest memory backing so
+//! that the host can reuse the memory behind them. Since the addresses come
+//! from the guest, only whole pages within guest RAM are decommitted.
+
+#![forbid(unsafe_code)]
+
+pub mod resolver;
+mod spec;
+
+use futures::StreamExt;
+use guestmem::GuestMemory;
+use inspect::Inspect;
+use inspect::InspectMut;
+use memory_range::MemoryRange;
+use pal_async::task::Spawn;
+use pal_async::task::Task;
+use pal_async::timer::Instant;
+use pal_async::timer::PolledTimer;
+use pal_async::wait::PolledWait;
+use parking_lot::Mutex;
+use spec::*;
+use std::future::poll_fn;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use task_control::AsyncRun;
+use task_control::Cancelled;
+use task_control::StopTask;
+use task_control::TaskControl;
+use virtio::DeviceTraits;
+use virtio::DeviceTraitsSharedMemory;
+use virtio::QueueResources;
+use virtio::Resources;
+use virtio::VirtioDevice;
+use virtio::VirtioQueue;
+use virtio::VirtioQueueCallbackWork;
+use virtio_resources::balloon::BalloonRpc;
+use virtio_resources::balloon::BalloonStats;
+use virtio_resources::balloon::BalloonStatus;
+use vmcore::interrupt::Interrupt;
+use vmcore::vm_task::VmTaskDriver;
+use vmcore::vm_task::VmTaskDriverSource;
+use zerocopy::FromBytes;
+use zerocopy::IntoBytes;
+
+/// The maximum number of queues: inflate, deflate, stats, and free page
+/// reporting.
+const MAX_QUEUES: u16 = 4;
+
+/// How often to ask the guest for updated memory statistics.
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The largest inflate or deflate request that will be processed. Linux sends
+/// at most 256 page frame numbers at a time.
+const MAX_PFN_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The largest stats buffer that will be processed.
+const MAX_STATS_BUFFER_SIZE: usize = 64 * size_of::<BalloonStat>();
+
+const PAGE_SIZE: u64 = guestmem::PAGE_SIZE as u64;
+
+/// A virtio balloon device.
+pub struct Device {
+    driver: VmTaskDriver,
+    mem: GuestMemory,
+    ram: Arc<[MemoryRange]>,
+    deflate_on_oom: bool,
+    state: Arc<Mutex<BalloonState>>,
+    worker: Option<TaskControl<BalloonWorker, BalloonQueues>>,
+    _rpc_task: Task<()>,
+}
+
+impl Device {
+    /// Creates a new virtio balloon device, controlled by requests sent to
+    /// `recv`.
+    ///
+    /// `ram` is the guest RAM ranges. Pages outside these ranges are never
+    /// decommitted.
+    pub fn new(
+        driver_source: &VmTaskDriverSource,
+        memory: GuestMemory,
+        ram: Vec<MemoryRange>,
+        deflate_on_oom: bool,
+        recv: mesh::Receiver<BalloonRpc>,
+    ) -> Self {
+        let driver = driver_source.simple();
+        let state = Arc::new(Mutex::new(BalloonState::default()));
+        let rpc_task = driver.spawn("virtio-balloon-rpc", handle_rpcs(state.clone(), recv));
+        Self {
+            driver,
+            mem: memory,
+            ram: ram.into(),
+            deflate_on_oom,
+            state,
+            worker: None,
+            _rpc_task: rpc_task,
+        }
+    }
+}
+
+async fn handle_rpcs(state: Arc<Mutex<BalloonState>>, mut recv: mesh::Receiver<BalloonRpc>) {
+    while let Some(rpc) = recv.next().await {
+        match rpc {
+            BalloonRpc::SetTarget(rpc) => rpc.handle_sync(|target| state.lock().set_target(target)),
+            BalloonRpc::Query(rpc) => rpc.handle_sync(|()| state.lock().status()),
+        }
+    }
+}
+
+/// State shared between the device, its queue worker, and its control task.
+#[derive(Default)]
+struct BalloonState {
+    /// The number of pages the guest has been asked to give up.
+    target_pages: u32,
+    /// The number of pages the guest reports that it has given up.
+    actual_pages: u32,
+    stats: BalloonStats,
+    counters: Counters,
+    /// Notifies the driver of a config space change. Only set while the
+    /// device is enabled.
+    config_change: Option<Interrupt>,
+}
+
+#[derive(Default, Inspect)]
+struct Counters {
+    inflated_pages: u64,
+    deflated_pages: u64,
+    reported_bytes: u64,
+    decommit_failures: u64,
+    /// Ranges given up by the guest that were not entirely within guest RAM.
+    rejected_ranges: u64,
+}
+
+impl BalloonState {
+    fn set_target(&mut self, target: u64) {
+        let target_pages = (target >> VIRTIO_BALLOON_PFN_SHIFT)
+            .try_into()
+            .unwrap_or(u32::MAX);
+        if target_pages != self.target_pages {
+            self.target_pages = target_pages;
+            if let Some(config_change) = &self.config_change {
+                config_change.deliver();
+            }
+        }
+    }
+
+    fn status(&self) -> BalloonStatus {
+        BalloonStatus {
+            target: (self.target_pages as u64) << VIRTIO_BALLOON_PFN_SHIFT,
+            actual: (self.actual_pages as u64) << VIRTIO_BALLOON_PFN_SHIFT,
+            stats: self.stats.clone(),
+        }
+    }
+
+    fn update_stats(&mut self, tag: u16, val: u64) {
+        let stat = match tag {
+            VIRTIO_BALLOON_S_SWAP_IN => &mut self.stats.swap_in,
+            VIRTIO_BALLOON_S_SWAP_OUT => &mut self.stats.swap_out,
+            VIRTIO_BALLOON_S_MAJFLT => &mut self.stats.major_faults,
+            VIRTIO_BALLOON_S_MINFLT => &mut self.stats.minor_faults,
+            VIRTIO_BALLOON_S_MEMFREE => &mut self.stats.free_memory,
+            VIRTIO_BALLOON_S_MEMTOT => &mut self.stats.total_memory,
+            VIRTIO_BALLOON_S_AVAIL => &mut self.stats.available_memory,
+            VIRTIO_BALLOON_S_CACHES => &mut self.stats.disk_caches,
+            _ => return,
+        };
+        *stat = Some(val);
+    }
+}
+
+fn inspect_stats(stats: &BalloonStats, req: inspect::Request<'_>) {
+    let BalloonStats {
+        swap_in,
+        swap_out,
+        major_faults,
+        minor_faults,
+        free_memory,
+        total_memory,
+        available_memory,
+        disk_caches,
+    } = stats;
+    req.respond()
+        .field("swap_in", swap_in)
+        .field("swap_out", swap_out)
+        .field("major_faults", major_faults)
+        .field("minor_faults", minor_faults)
+        .field("free_memory", free_memory)
+        .field("total_memory", total_memory)
+        .field("available_memory", available_memory)
+        .field("disk_caches", disk_caches);
+}
+
+impl InspectMut for Device {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        req.respond()
+            .field("deflate_on_oom", self.deflate_on_oom)
+            .field("enabled", self.worker.is_some())
+            .field_mut_with("target", |new| {
+                if let Some(new) = new {
+                    state.set_target(new.parse()?);
+                }
+                Ok::<_, std::num::ParseIntError>(
+                    (state.target_pages as u64) << VIRTIO_BALLOON_PFN_SHIFT,
+                )
+            })
+            .field(
+                "actual",
+                (state.actual_pages as u64) << VIRTIO_BALLOON_PFN_SHIFT,
+            )
+            .field(
+                "stats",
+                inspect::adhoc(|req| inspect_stats(&state.stats, req)),
+            )
+            .merge(&state.counters);
+    }
+}
+
+impl VirtioDevice for Device {
+    fn traits(&self) -> DeviceTraits {
+        let mut features = VIRTIO_BALLOON_F_STATS_VQ | VIRTIO_BALLOON_F_PAGE_REPORTING;
+        if self.deflate_on_oom {
+            features |= VIRTIO_BALLOON_F_DEFLATE_ON_OOM;
+        }
+        DeviceTraits {
+            device_id: VIRTIO_DEVICE_TYPE_BALLOON,
+            device_features: features,
+            max_queues: MAX_QUEUES,
+            device_register_length: size_of::<BalloonConfig>() as u32,
+            shared_memory: DeviceTraitsSharedMemory { id: 0, size: 0 },
+        }
+    }
+
+    fn read_registers_u32(&self, offset: u16) -> u32 {
+        let config = {
+            let state = self.state.lock();
+            BalloonConfig {
+                num_pages: state.target_pages.into(),
+                actual: state.actual_pages.into(),
+                free_page_hint_cmd_id: 0.into(),
+                poison_val: 0.into(),
+            }
+        };
+        let offset = offset as usize;
+        config
+            .as_bytes()
+            .get(offset..offset + 4)
+            .map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn write_registers_u32(&mut self, offset: u16, val: u32) {
+        // The driver may only write the actual balloon size.
+        if offset == BALLOON_CONFIG_OFFSET_ACTUAL {
+            self.state.lock().actual_pages = val;
+        }
+    }
+
+    fn enable(&mut self, resources: Resources) {
+        assert!(self.worker.is_none());
+        let features = resources.features;
+
+        // Queues for features that were not negotiated are omitted, with the
+        // remaining queues numbered consecutively.
+        let mut queues = resources.queues.into_iter();
+        let mut next_queue = |enabled: bool| {
+            enabled
+                .then(|| queues.next())
+                .flatten()
+                .and_then(|queue| self.create_queue(features, queue))
+        };
+        let inflate = next_queue(true);
+        let deflate = next_queue(true);
+        let stats = next_queue(features & VIRTIO_BALLOON_F_STATS_VQ != 0);
+        let reporting = next_queue(features & VIRTIO_BALLOON_F_PAGE_REPORTING != 0);
+
+        let (Some(inflate), Some(deflate)) = (inflate, deflate) else {
+            tracing::error!("virtio balloon enabled without inflate and deflate queues");
+            return;
+        };
+
+        self.state.lock().config_change = Some(resources.config_change);
+
+        let mut worker = TaskControl::new(BalloonWorker {
+            mem: self.mem.clone(),
+            ram: self.ram.clone(),
+            state: self.state.clone(),
+        });
+        worker.insert(
+            &self.driver,
+            "virtio-balloon",
+            BalloonQueues {
+                inflate,
+                deflate,
+                stats,
+                reporting,
+                stats_buffer: None,
+                stats_timer: PolledTimer::new(&self.driver),
+                stats_deadline: Instant::now(),
+            },
+        );
+        worker.start();
+        self.worker = Some(worker);
+    }
+
+    fn disable(&mut self) {
+        {
+            // The guest forgets about the balloon across a reset.
+            let mut state = self.state.lock();
+            state.config_change = None;
+            state.actual_pages = 0;
+        }
+        let Some(mut worker) = self.worker.take() else {
+            return;
+        };
+        self.driver
+            .spawn("shutdown-virtio-balloon", async move {
+                worker.stop().await;
+            })
+            .detach();
+    }
+}
+
+impl Device {
+    fn create_queue(&self, features: u64, resources: QueueResources) -> Option<VirtioQueue> {
+        let QueueResources {
+            params,
+            notify,
+            event,
+        } = resources;
+        if !params.enable {
+            return None;
+        }
+        let queue_event = PolledWait::new(&self.driver, event)
+            .inspect_err(|err| {
+                tracing::error!(
+                    error = err as &dyn std::error::Error,
+                    "failed creating queue event"
+                )
+            })
+            .ok()?;
+        VirtioQueue::new(features, params, self.mem.clone(), notify, queue_event)
+            .inspect_err(|err| {
+                tracing::error!(
+                    error = err as &dyn std::error::Error,
+                    "failed creating virtio balloon queue"
+                )
+            })
+            .ok()
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        self.disable();
+    }
+}
+
+struct BalloonWorker {
+    mem: GuestMemory,
+    ram: Arc<[MemoryRange]>,
+    state: Arc<Mutex<BalloonState>>,
+}
+
+struct BalloonQueues {
+    inflate: VirtioQueue,
+    deflate: VirtioQueue,
+    stats: Option<VirtioQueue>,
+    reporting: Option<VirtioQueue>,
+    /// The most recent stats buffer from the guest. It is returned to the
+    /// guest to request updated statistics.
+    stats_buffer: Option<VirtioQueueCallbackWork>,
+    stats_timer: PolledTimer,
+    stats_deadline: Instant,
+}
+
+enum QueueEvent {
+    Inflate(VirtioQueueCallbackWork),
+    Deflate(VirtioQueueCallbackWork),
+    Stats(VirtioQueueCallbackWork),
+    Report(VirtioQueueCallbackWork),
+    RequestStats,
+}
+
+impl BalloonQueues {
+    fn poll_event(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<QueueEvent>> {
+        fn poll_queue(
+            queue: &mut VirtioQueue,
+            cx: &mut Context<'_>,
+            f: fn(VirtioQueueCallbackWork) -> QueueEvent,
+        ) -> Poll<std::io::Result<QueueEvent>> {
+            queue
+                .poll_next_unpin(cx)
+                .map(|work| work.expect("queue will never complete").map(f))
+        }
+
+        if let Poll::Ready(r) = poll_queue(&mut self.inflate, cx, QueueEvent::Inflate) {
+            return Poll::Ready(r);
+        }
+        if let Poll::Ready(r) = poll_queue(&mut self.deflate, cx, QueueEvent::Deflate) {
+            return Poll::Ready(r);
+        }
+        if let Some(queue) = &mut self.stats {
+            if let Poll::Ready(r) = poll_queue(queue, cx, QueueEvent::Stats) {
+                return Poll::Ready(r);
+            }
+        }
+        if let Some(queue) = &mut self.reporting {
+            if let Poll::Ready(r) = poll_queue(queue, cx, QueueEvent::Report) {
+                return Poll::Ready(r);
+            }
+        }
+        if self.stats_buffer.is_some()
+            && self
+                .stats_timer
+                .poll_until(cx, self.stats_deadline)
+                .is_ready()
+        {
+            return Poll::Ready(Ok(QueueEvent::RequestStats));
+        }
+        Poll::Pending
+    }
+}
+
+impl AsyncRun<BalloonQueues> for BalloonWorker {
+    async fn run(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        queues: &mut BalloonQueues,
+    ) -> Result<(), Cancelled> {
+        stop.until_stopped(self.process(queues)).await
+    }
+}
+
+impl BalloonWorker {
+    async fn process(&mut self, queues: &mut BalloonQueues) {
+        loop {
+            let event = match poll_fn(|cx| queues.poll_event(cx)).await {
+                Ok(event) => event,
+                Err(err) => {
+                    tracing::error!(error = &err as &dyn std::error::Error, "queue error");
+                    std::future::pending().await
+                }
+            };
+            match event {
+                QueueEvent::Inflate(work) => {
+                    let count = self.for_each_pfn_range(&work, |this, pfn, count| {
+                        this.decommit(
+                            pfn << VIRTIO_BALLOON_PFN_SHIFT,
+                            count << VIRTIO_BALLOON_PFN_SHIFT,
+                        )
+                    });
+                    self.state.lock().counters.inflated_pages += count;
+                }
+                QueueEvent::Deflate(work) => {
+                    // The pages will be faulted back in when the guest next
+                    // accesses them, so there is nothing to do.
+                    let count = self.for_each_pfn_range(&work, |_, _, _| {});
+                    self.state.lock().counters.deflated_pages += count;
+                }
+                QueueEvent::Stats(work) => {
+                    self.read_stats(&work);
+                    queues.stats_buffer = Some(work);
+                    queues.stats_deadline = Instant::now().saturating_add(STATS_INTERVAL);
+                }
+                QueueEvent::Report(work) => {
+                    let mut reported = 0;
+                    for payload in &work.payload {
+                        self.decommit(payload.address, payload.length.into());
+                        reported += u64::from(payload.length);
+                    }
+                    self.state.lock().counters.reported_bytes += reported;
+                }
+                QueueEvent::RequestStats => {
+                    // Returning the buffer tells the driver to send a new one
+                    // with updated statistics.
+                    queues.stats_buffer.take().unwrap().complete(0);
+                }
+            }
+        }
+    }
+
+    /// Reads the page frame numbers from an inflate or deflate request,
+    /// calling `f` for each run of consecutive pages. Returns the number of
+    /// pages.
+    fn for_each_pfn_range(
+        &self,
+        work: &VirtioQueueCallbackWork,
+        mut f: impl FnMut(&Self, u64, u64),
+    ) -> u64 {
+        let len = (work.get_payload_length(false) as usize).min(MAX_PFN_BUFFER_SIZE);
+        let mut buf = vec![0; len];
+        if let Err(err) = work.read(&self.mem, &mut buf) {
+            tracelimit::warn_ratelimited!(
+                error = &err as &dyn std::error::Error,
+                "failed to read balloon page frame numbers"
+            );
+            return 0;
+        }
+
+        let mut total = 0;
+        let mut run: Option<(u64, u64)> = None;
+        for pfn in buf
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as u64)
+        {
+            total += 1;
+            match &mut run {
+                Some((start, count)) if *start + *count == pfn => *count += 1,
+                _ => {
+                    if let Some((start, count)) = run.replace((pfn, 1)) {
+                        f(self, start, count);
+                    }
+                }
+            }
+        }
+        if let Some((start, count)) = run {
+            f(self, start, count);
+        }
+        total
+    }
+
+    fn read_stats(&self, work: &VirtioQueueCallbackWork) {
+        let len = (work.get_payload_length(false) as usize).min(MAX_STATS_BUFFER_SIZE);
+        let mut buf = vec![0; len];
+        if let Err(err) = work.read(&self.mem, &mut buf) {
+            tracelimit::warn_ratelimited!(
+                error = &err as &dyn std::error::Error,
+                "failed to read balloon stats"
+            );
+            return;
+        }
+        let mut state = self.state.lock();
+        for stat in buf.chunks_exact(size_of::<BalloonStat>()) {
+            let stat = BalloonStat::read_from_bytes(stat).unwrap();
+            state.update_stats(stat.tag.get(), stat.val.get());
+        }
+    }
+
+    /// Decommits the whole pages of `gpa..gpa + len` that are within guest
+    /// RAM, rejecting any part of the range outside RAM.
+    fn decommit(&self, gpa: u64, len: u64) {
+        let (ranges, in_ram) = ram_pages(&self.ram, gpa, len);
+        if !in_ram {
+            self.state.lock().counters.rejected_ranges += 1;
+            tracelimit::warn_ratelimited!(gpa, len, "balloon range is not within guest RAM");
+        }
+        for range in ranges {
+            if let Err(err) = self.mem.decommit(range.start(), range.len()) {
+                self.state.lock().counters.decommit_failures += 1;
+                tracelimit::warn_ratelimited!(
+                    error = &err as &dyn std::error::Error,
+                    %range,
+                    "failed to decommit balloon memory"
+                );
+            }
+        }
+    }
+}
+
+/// Returns the whole pages of `gpa..gpa + len` that are within `ram`, and
+/// whether the range is entirely within `ram`.
+fn ram_pages(ram: &[MemoryRange], gpa: u64, len: u64) -> (Vec<MemoryRange>, bool) {
+    let Some(end) = gpa
+        .checked_add(len)
+        .filter(|&end| end <= MemoryRange::MAX_ADDRESS)
+    else {
+        return (Vec::new(), false);
+    };
+    let start = gpa.next_multiple_of(PAGE_SIZE);
+    let end = end & !(PAGE_SIZE - 1);
+    let range = MemoryRange::new(start..end.max(start));
+    let ranges = ram
+        .iter()
+        .map(|ram| ram.intersection(&range))
+        .filter(|range| !range.is_empty())
+        .collect::<Vec<_>>();
+    let in_ram = ranges.iter().map(|r| r.len()).sum::<u64>() == range.len();
+    (ranges, in_ram)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pal_async::DefaultDriver;
+    use pal_async::async_test;
+    use virtio::test_helpers::TestBuffer;
+    use virtio::test_helpers::TestQueue;
+    use vmcore::vm_task::SingleDriverBackend;
+
+    /// The guest RAM. The rest of guest memory stands in for device memory.
+    const RAM: MemoryRange = MemoryRange::new(0..0x80000);
+    const BUFFER: u64 = 0x10000;
+
+    struct TestDevice {
+        device: Device,
+        inflate: TestQueue,
+        deflate: TestQueue,
+        reporting: TestQueue,
+        mem: GuestMemory,
+    }
+
+    impl TestDevice {
+        fn new(driver: &DefaultDriver) -> Self {
+            let mem = GuestMemory::allocate(0x100000);
+            let (_send, recv) = mesh::channel();
+            let mut device = Device::new(
+                &VmTaskDriverSource::new(SingleDriverBackend::new(driver.clone())),
+                mem.clone(),
+                vec![RAM],
+                false,
+                recv,
+            );
+            let inflate = TestQueue::new(driver, &mem, 0, 16);
+            let deflate = TestQueue::new(driver, &mem, 0x1000, 16);
+            let reporting = TestQueue::new(driver, &mem, 0x2000, 16);
+            device.enable(Resources {
+                features: VIRTIO_BALLOON_F_PAGE_REPORTING,
+                queues: vec![
+                    inflate.resources(),
+                    deflate.resources(),
+                    reporting.resources(),
+                ],
+                shared_memory_region: None,
+                shared_memory_size: 0,
+                config_change: Interrupt::null(),
+            });
+            Self {
+                device,
+                inflate,
+                deflate,
+                reporting,
+                mem,
+            }
+        }
+
+        fn counters(&self) -> (u64, u64, u64, u64) {
+            let state = self.device.state.lock();
+            let c = &state.counters;
+            (
+                c.inflated_pages,
+                c.deflated_pages,
+                c.reported_bytes,
+                c.rejected_ranges,
+            )
+        }
+    }
+
+    async fn send_pfns(queue: &mut TestQueue, mem: &GuestMemory, pfns: &[u32]) {
+        mem.write_at(BUFFER, pfns.as_bytes()).unwrap();
+        let head = queue.add(&[TestBuffer::readable(BUFFER, size_of_val(pfns) as u32)]);
+        assert_eq!(queue.used().await, (head, 0));
+    }
+
+    #[async_test]
+    async fn test_inflate_deflate(driver: DefaultDriver) {
+        let mut dev = TestDevice::new(&driver);
+        // Two pages in RAM and one outside it.
+        send_pfns(&mut dev.inflate, &dev.mem, &[0x40, 0x41, 0x90]).await;
+        assert_eq!(dev.counters(), (3, 0, 0, 1));
+
+        send_pfns(&mut dev.deflate, &dev.mem, &[0x40, 0x41]).await;
+        assert_eq!(dev.counters(), (3, 2, 0, 1));
+    }
+
+    #[async_test]
+    async fn test_free_page_reporting(driver: DefaultDriver) {
+        let mut dev = TestDevice::new(&driver);
+        let head = dev.reporting.add(&[TestBuffer::writeable(0x40000, 0x4000)]);
+        assert_eq!(dev.reporting.used().await, (head, 0));
+        assert_eq!(dev.counters(), (0, 0, 0x4000, 0));
+
+        // A range that extends past the end of RAM is rejected.
+        let head = dev
+            .reporting
+            .add(&[TestBuffer::writeable(RAM.end() - 0x1000, 0x2000)]);
+        assert_eq!(dev.reporting.used().await, (head, 0));
+        assert_eq!(dev.counters(), (0, 0, 0x6000, 1));
+    }
+
+    #[test]
+    fn test_ram_pages() {
+        let ram = [
+            MemoryRange::new(0..0x10000),
+            MemoryRange::new(0x20000..0x30000),
+        ];
+        assert_eq!(
+            ram_pages(&ram, 0x1000, 0x2000),
+            (vec![MemoryRange::new(0x1000..0x3000)], true)
+        );
+        // Partial pages are not decommitted.
+        assert_eq!(
+            ram_pages(&ram, 0x1800, 0x2000),
+            (vec![MemoryRange::new(0x2000..0x3000)], true)
+        );
+        assert_eq!(ram_pages(&ram, 0x1800, 0x100), (vec![], true));
+        // Only the parts within RAM are decommitted.
+        assert_eq!(
+            ram_pages(&ram, 0xf000, 0x12000),
+            (
+                vec![
+                    MemoryRange::new(0xf000..0x10000),
+                    MemoryRange::new(0x20000..0x21000)
+                ],
+                false
+            )
+        );
+        assert_eq!(ram_pages(&ram, 0x40000, 0x1000), (vec![], false));
+        assert_eq!(ram_pages(&ram, u64::MAX - 0xfff, 0x1000), (vec![], false));
+    }
+}
@@ -0,0 +1,53 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Constants and structures defined by the virtio balloon device spec.
+
+use virtio::spec::u16_le;
+use virtio::spec::u32_le;
+use virtio::spec::u64_le;
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+pub const VIRTIO_DEVICE_TYPE_BALLOON: u16 = 5;
+
+// Device features.
+pub const VIRTIO_BALLOON_F_STATS_VQ: u64 = 1 << 1;
+pub const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u64 = 1 << 2;
+pub const VIRTIO_BALLOON_F_PAGE_REPORTING: u64 = 1 << 5;
+
+/// The page size used by the inflate and deflate queues, independent of the
+/// guest's page size.
+pub const VIRTIO_BALLOON_PFN_SHIFT: u32 = 12;
+
+// Memory statistic tags.
+pub const VIRTIO_BALLOON_S_SWAP_IN: u16 = 0;
+pub const VIRTIO_BALLOON_S_SWAP_OUT: u16 = 1;
+pub const VIRTIO_BALLOON_S_MAJFLT: u16 = 2;
+pub const VIRTIO_BALLOON_S_MINFLT: u16 = 3;
+pub const VIRTIO_BALLOON_S_MEMFREE: u16 = 4;
+pub const VIRTIO_BALLOON_S_MEMTOT: u16 = 5;
+pub const VIRTIO_BALLOON_S_AVAIL: u16 = 6;
+pub const VIRTIO_BALLOON_S_CACHES: u16 = 7;
+
+#[repr(C)]
+#[derive(Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct BalloonConfig {
+    /// The number of pages the device wants the driver to give up.
+    pub num_pages: u32_le,
+    /// The number of pages the driver has given up.
+    pub actual: u32_le,
+    pub free_page_hint_cmd_id: u32_le,
+    pub poison_val: u32_le,
+}
+
+pub const BALLOON_CONFIG_OFFSET_ACTUAL: u16 = 4;
+
+#[repr(C)]
+#[derive(Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct BalloonStat {
+    pub tag: u16_le,
+    pub val: u64_le,
+}
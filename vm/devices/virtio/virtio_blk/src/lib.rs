@@ -0,0 +1,920 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A virtio block device backed by a [`Disk`].
+
+#![forbid(unsafe_code)]
+
+pub mod resolver;
+mod spec;
+
+use disk_backend::Disk;
+use disk_backend::DiskError;
+use disk_backend::UnmapBehavior;
+use futures::FutureExt;
+use futures::StreamExt;
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use guestmem::GuestMemory;
+use guestmem::GuestMemoryError;
+use guestmem::PAGE_SIZE;
+use inspect::InspectMut;
+use pal_async::task::Spawn;
+use pal_async::wait::PolledWait;
+use scsi_buffers::OwnedRequestBuffers;
+use spec::*;
+use std::future::poll_fn;
+use std::sync::Arc;
+use task_control::AsyncRun;
+use task_control::Cancelled;
+use task_control::StopTask;
+use task_control::TaskControl;
+use thiserror::Error;
+use virtio::DeviceTraits;
+use virtio::DeviceTraitsSharedMemory;
+use virtio::QueueResources;
+use virtio::Resources;
+use virtio::VirtioDevice;
+use virtio::VirtioQueue;
+use virtio::VirtioQueueCallbackWork;
+use virtio::queue::VirtioQueuePayload;
+use vmcore::vm_task::VmTaskDriver;
+use vmcore::vm_task::VmTaskDriverSource;
+use zerocopy::FromBytes;
+use zerocopy::FromZeros;
+use zerocopy::IntoBytes;
+
+/// The maximum number of data segments in a single request. The virtio queue
+/// limits descriptor chains to 128 entries, two of which are used by the
+/// request header and status.
+const MAX_SEGMENTS: u32 = 126;
+
+/// The maximum number of ranges in a single discard or write zeroes request.
+const MAX_DISCARD_WRITE_ZEROES_SEGMENTS: u32 = 16;
+
+/// The largest request that will be bounced through an intermediate buffer
+/// when the guest's data buffers cannot be described as a paged range.
+const MAX_BOUNCE_SIZE: usize = 8 * 1024 * 1024;
+
+/// The size of the zero buffer used to emulate write zeroes requests that
+/// cannot be satisfied by an unmap.
+const ZERO_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A virtio block device.
+pub struct Device {
+    driver: VmTaskDriver,
+    driver_source: VmTaskDriverSource,
+    disk: Arc<DiskState>,
+    max_queues: u16,
+    workers: Vec<TaskControl<BlkQueueWorker, BlkQueueState>>,
+}
+
+impl Device {
+    /// Creates a new virtio block device over `disk`.
+    ///
+    /// `max_queues` is the maximum number of request queues offered to the
+    /// guest.
+    pub fn new(
+        driver_source: &VmTaskDriverSource,
+        memory: GuestMemory,
+        disk: Disk,
+        read_only: bool,
+        max_queues: u16,
+    ) -> Self {
+        let read_only = read_only || disk.is_read_only();
+        let mut id = [0; VIRTIO_BLK_ID_BYTES];
+        if let Some(disk_id) = disk.disk_id() {
+            // Use the hex encoding of the disk ID as the serial number,
+            // truncated to fit.
+            for (dest, b) in id.chunks_exact_mut(2).zip(disk_id) {
+                dest.copy_from_slice(format!("{b:02x}").as_bytes());
+            }
+        }
+        Self {
+            driver: driver_source.simple(),
+            driver_source: driver_source.clone(),
+            disk: Arc::new(DiskState {
+                zero_buffer: GuestMemory::allocate(ZERO_BUFFER_SIZE),
+                disk,
+                mem: memory,
+                read_only,
+                id,
+            }),
+            max_queues: max_queues.max(1),
+            workers: Vec::new(),
+        }
+    }
+
+    fn config(&self) -> BlkConfig {
+        let disk = &self.disk.disk;
+        let sector_size = disk.sector_size();
+        let virtio_sectors_per_block = sector_size >> VIRTIO_BLK_SECTOR_SHIFT;
+        let physical_block_exp = (disk.physical_sector_size() / sector_size)
+            .max(1)
+            .trailing_zeros() as u8;
+        BlkConfig {
+            capacity: (disk.sector_count() << (disk.sector_shift() - VIRTIO_BLK_SECTOR_SHIFT))
+                .into(),
+            size_max: 0.into(),
+            seg_max: MAX_SEGMENTS.into(),
+            geometry: FromZeros::new_zeroed(),
+            blk_size: sector_size.into(),
+            topology: Topology {
+                physical_block_exp,
+                alignment_offset: 0,
+                min_io_size: 1.into(),
+                opt_io_size: 0.into(),
+            },
+            writeback: 1,
+            unused0: 0,
+            num_queues: self.max_queues.into(),
+            max_discard_sectors: u32::MAX.into(),
+            max_discard_seg: MAX_DISCARD_WRITE_ZEROES_SEGMENTS.into(),
+            discard_sector_alignment: (disk.optimal_unmap_sectors() * virtio_sectors_per_block)
+                .into(),
+            max_write_zeroes_sectors: u32::MAX.into(),
+            max_write_zeroes_seg: MAX_DISCARD_WRITE_ZEROES_SEGMENTS.into(),
+            write_zeroes_may_unmap: (disk.unmap_behavior() == UnmapBehavior::Zeroes) as u8,
+            unused1: [0; 3],
+        }
+    }
+}
+
+impl InspectMut for Device {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        req.respond()
+            .field("disk", &self.disk.disk)
+            .field("read_only", self.disk.read_only)
+            .field("max_queues", self.max_queues)
+            .field("active_queues", self.workers.len());
+    }
+}
+
+impl VirtioDevice for Device {
+    fn traits(&self) -> DeviceTraits {
+        let mut features = VIRTIO_BLK_F_SEG_MAX
+            | VIRTIO_BLK_F_BLK_SIZE
+            | VIRTIO_BLK_F_FLUSH
+            | VIRTIO_BLK_F_TOPOLOGY
+            | VIRTIO_BLK_F_MQ;
+        if self.disk.read_only {
+            features |= VIRTIO_BLK_F_RO;
+        } else {
+            features |= VIRTIO_BLK_F_DISCARD | VIRTIO_BLK_F_WRITE_ZEROES;
+        }
+        DeviceTraits {
+            device_id: VIRTIO_DEVICE_TYPE_BLOCK,
+            device_features: features,
+            max_queues: self.max_queues,
+            device_register_length: size_of::<BlkConfig>() as u32,
+            shared_memory: DeviceTraitsSharedMemory { id: 0, size: 0 },
+        }
+    }
+
+    fn read_registers_u32(&self, offset: u16) -> u32 {
+        let config = self.config();
+        let offset = offset as usize;
+        config
+            .as_bytes()
+            .get(offset..offset + 4)
+            .map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn write_registers_u32(&mut self, _offset: u16, _val: u32) {}
+
+    fn enable(&mut self, resources: Resources) {
+        assert!(self.workers.is_empty());
+        for (
+            i,
+            QueueResources {
+                params,
+                notify,
+                event,
+            },
+        ) in resources.queues.into_iter().enumerate()
+        {
+            if !params.enable {
+                continue;
+            }
+
+            let driver = self
+                .driver_source
+                .builder()
+                .build(format!("virtio-blk-{i}"));
+
+            let queue_event = match PolledWait::new(&driver, event) {
+                Ok(queue_event) => queue_event,
+                Err(err) => {
+                    tracing::error!(
+                        error = &err as &dyn std::error::Error,
+                        "failed creating queue event"
+                    );
+                    continue;
+                }
+            };
+
+            let queue = match VirtioQueue::new(
+                resources.features,
+                params,
+                self.disk.mem.clone(),
+                notify,
+                queue_event,
+            ) {
+                Ok(queue) => queue,
+                Err(err) => {
+                    tracing::error!(
+                        error = &err as &dyn std::error::Error,
+                        "failed creating virtio blk queue"
+                    );
+                    continue;
+                }
+            };
+
+            let mut worker = TaskControl::new(BlkQueueWorker {
+                disk: self.disk.clone(),
+            });
+            worker.insert(
+                &driver,
+                format!("virtio-blk-queue-{i}"),
+                BlkQueueState {
+                    queue,
+                    pending: FuturesUnordered::new(),
+                },
+            );
+            worker.start();
+            self.workers.push(worker);
+        }
+    }
+
+    fn disable(&mut self) {
+        if self.workers.is_empty() {
+            return;
+        }
+        let mut workers = std::mem::take(&mut self.workers);
+        self.driver
+            .spawn("shutdown-virtio-blk-queues".to_owned(), async move {
+                futures::future::join_all(workers.iter_mut().map(async |worker| {
+                    worker.stop().await;
+                    // Wait for in-flight requests to complete before dropping
+                    // the queue.
+                    if let Some(state) = worker.state_mut() {
+                        while state.pending.next().await.is_some() {}
+                    }
+                }))
+                .await;
+            })
+            .detach();
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        self.disable();
+    }
+}
+
+/// State shared between all the queues of a device.
+struct DiskState {
+    disk: Disk,
+    mem: GuestMemory,
+    read_only: bool,
+    id: [u8; VIRTIO_BLK_ID_BYTES],
+    /// A buffer of zeroes, used as the source for write zeroes requests.
+    zero_buffer: GuestMemory,
+}
+
+struct BlkQueueWorker {
+    disk: Arc<DiskState>,
+}
+
+struct BlkQueueState {
+    queue: VirtioQueue,
+    pending: FuturesUnordered<BoxFuture<'static, ()>>,
+}
+
+impl AsyncRun<BlkQueueState> for BlkQueueWorker {
+    async fn run(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        state: &mut BlkQueueState,
+    ) -> Result<(), Cancelled> {
+        stop.until_stopped(self.process(state)).await
+    }
+}
+
+impl BlkQueueWorker {
+    async fn process(&mut self, state: &mut BlkQueueState) {
+        loop {
+            // Drive the in-flight requests while waiting for the next one.
+            let work = poll_fn(|cx| {
+                while let std::task::Poll::Ready(Some(())) = state.pending.poll_next_unpin(cx) {}
+                state.queue.poll_next_unpin(cx)
+            })
+            .await;
+
+            match work.expect("queue will never complete") {
+                Ok(work) => {
+                    let disk = self.disk.clone();
+                    state
+                        .pending
+                        .push(async move { disk.handle_request(work).await }.boxed());
+                }
+                Err(err) => {
+                    tracing::error!(error = &err as &dyn std::error::Error, "queue error");
+                    // Keep completing outstanding requests, but stop
+                    // processing new ones.
+                    while state.pending.next().await.is_some() {}
+                    std::future::pending::<()>().await;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+enum RequestError {
+    #[error("request is missing the header or status byte")]
+    Malformed,
+    #[error("unsupported request type {0}")]
+    Unsupported(u32),
+    #[error("write to read-only device")]
+    ReadOnly,
+    #[error("request not aligned to the logical block size")]
+    Unaligned,
+    #[error("request out of range")]
+    OutOfRange,
+    #[error("request is too large")]
+    TooLarge,
+    #[error("guest memory access error")]
+    Memory(#[source] GuestMemoryError),
+    #[error("disk error")]
+    Disk(#[source] DiskError),
+}
+
+/// A contiguous range of guest memory.
+#[derive(Copy, Clone)]
+struct Segment {
+    address: u64,
+    len: u64,
+}
+
+/// Returns the segments of `payload` in the given direction, skipping the
+/// first `skip_front` and last `skip_back` bytes.
+fn segments(
+    payload: &[VirtioQueuePayload],
+    writeable: bool,
+    mut skip_front: u64,
+    skip_back: u64,
+) -> Vec<Segment> {
+    let total: u64 = payload
+        .iter()
+        .filter(|p| p.writeable == writeable)
+        .map(|p| p.length as u64)
+        .sum();
+    let mut remaining = total.saturating_sub(skip_front + skip_back);
+    let mut segments = Vec::new();
+    for p in payload.iter().filter(|p| p.writeable == writeable) {
+        let mut address = p.address;
+        let mut len = p.length as u64;
+        let skip = skip_front.min(len);
+        skip_front -= skip;
+        address += skip;
+        len -= skip;
+        let len = len.min(remaining);
+        if len != 0 {
+            segments.push(Segment { address, len });
+            remaining -= len;
+        }
+    }
+    segments
+}
+
+/// Describes `segments` as a single paged range, if possible.
+fn paged_buffers(segments: &[Segment]) -> Option<OwnedRequestBuffers> {
+    let page_size = PAGE_SIZE as u64;
+    let first = segments.first()?;
+    let mut gpns = Vec::new();
+    let mut len = 0;
+    for (i, segment) in segments.iter().enumerate() {
+        let end = segment.address + segment.len;
+        if (i > 0 && segment.address % page_size != 0)
+            || (i < segments.len() - 1 && end % page_size != 0)
+        {
+            return None;
+        }
+        gpns.extend(segment.address / page_size..end.div_ceil(page_size));
+        len += segment.len;
+    }
+    Some(OwnedRequestBuffers::new_unaligned(
+        &gpns,
+        (first.address % page_size) as usize,
+        len as usize,
+    ))
+}
+
+impl DiskState {
+    async fn handle_request(&self, mut work: VirtioQueueCallbackWork) {
+        let (status, bytes_written) = match self.handle_request_inner(&work).await {
+            Ok(n) => (VIRTIO_BLK_S_OK, n),
+            Err(err) => {
+                tracelimit::warn_ratelimited!(
+                    error = &err as &dyn std::error::Error,
+                    "virtio-blk request failed"
+                );
+                let status = match err {
+                    RequestError::Unsupported(_) => VIRTIO_BLK_S_UNSUPP,
+                    _ => VIRTIO_BLK_S_IOERR,
+                };
+                (status, 0)
+            }
+        };
+
+        // The status is the last writeable byte of the request.
+        let Some(status_payload) = work.payload.iter().rfind(|p| p.writeable && p.length > 0)
+        else {
+            tracelimit::warn_ratelimited!("virtio-blk request missing status byte");
+            work.complete(0);
+            return;
+        };
+        let status_address = status_payload.address + status_payload.length as u64 - 1;
+        if let Err(err) = self.mem.write_plain(status_address, &status) {
+            tracelimit::warn_ratelimited!(
+                error = &err as &dyn std::error::Error,
+                "failed to write virtio-blk status"
+            );
+        }
+        work.complete(bytes_written + 1);
+    }
+
+    /// Handles a request, returning the number of data bytes written to guest
+    /// memory.
+    async fn handle_request_inner(
+        &self,
+        work: &VirtioQueueCallbackWork,
+    ) -> Result<u32, RequestError> {
+        let mut header = RequestHeader::new_zeroed();
+        let n = work
+            .read(&self.mem, header.as_mut_bytes())
+            .map_err(RequestError::Memory)?;
+        if n < size_of::<RequestHeader>() || work.get_payload_length(true) == 0 {
+            return Err(RequestError::Malformed);
+        }
+
+        let header_len = size_of::<RequestHeader>() as u64;
+        match header.request_type.get() {
+            VIRTIO_BLK_T_IN => {
+                let segments = segments(&work.payload, true, 0, 1);
+                let len = self.read(header.sector.get(), &segments).await?;
+                Ok(len as u32)
+            }
+            VIRTIO_BLK_T_OUT => {
+                if self.read_only {
+                    return Err(RequestError::ReadOnly);
+                }
+                let segments = segments(&work.payload, false, header_len, 0);
+                self.write(header.sector.get(), &segments).await?;
+                Ok(0)
+            }
+            VIRTIO_BLK_T_FLUSH => {
+                self.disk.sync_cache().await.map_err(RequestError::Disk)?;
+                Ok(0)
+            }
+            VIRTIO_BLK_T_GET_ID => {
+                let segments = segments(&work.payload, true, 0, 1);
+                let mut remaining = &self.id[..];
+                let mut written = 0;
+                for segment in segments {
+                    if remaining.is_empty() {
+                        break;
+                    }
+                    let (this, rest) =
+                        remaining.split_at((segment.len as usize).min(remaining.len()));
+                    self.mem
+                        .write_at(segment.address, this)
+                        .map_err(RequestError::Memory)?;
+                    written += this.len();
+                    remaining = rest;
+                }
+                Ok(written as u32)
+            }
+            ty @ (VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES) => {
+                if self.read_only {
+                    return Err(RequestError::ReadOnly);
+                }
+                let segments = segments(&work.payload, false, header_len, 0);
+                let ranges = self.read_ranges(&segments)?;
+                for range in ranges {
+                    let (sector, count) = self.disk_range(
+                        range.sector.get(),
+                        (range.num_sectors.get() as u64) << VIRTIO_BLK_SECTOR_SHIFT,
+                    )?;
+                    if ty == VIRTIO_BLK_T_DISCARD {
+                        self.disk
+                            .unmap(sector, count, false)
+                            .await
+                            .map_err(RequestError::Disk)?;
+                    } else {
+                        self.write_zeroes(
+                            sector,
+                            count,
+                            range.flags.get() & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0,
+                        )
+                        .await?;
+                    }
+                }
+                Ok(0)
+            }
+            ty => Err(RequestError::Unsupported(ty)),
+        }
+    }
+
+    /// Converts a virtio sector and byte length to a disk sector and sector
+    /// count, validating alignment and range.
+    fn disk_range(&self, virtio_sector: u64, len: u64) -> Result<(u64, u64), RequestError> {
+        let sector_size = self.disk.sector_size() as u64;
+        let offset = virtio_sector
+            .checked_mul(1 << VIRTIO_BLK_SECTOR_SHIFT)
+            .ok_or(RequestError::OutOfRange)?;
+        if offset % sector_size != 0 || len % sector_size != 0 {
+            return Err(RequestError::Unaligned);
+        }
+        let sector = offset >> self.disk.sector_shift();
+        let count = len >> self.disk.sector_shift();
+        if sector
+            .checked_add(count)
+            .is_none_or(|end| end > self.disk.sector_count())
+        {
+            return Err(RequestError::OutOfRange);
+        }
+        Ok((sector, count))
+    }
+
+    fn read_ranges(&self, segments: &[Segment]) -> Result<Vec<DiscardWriteZeroes>, RequestError> {
+        let len: u64 = segments.iter().map(|s| s.len).sum();
+        let count = len / size_of::<DiscardWriteZeroes>() as u64;
+        if count == 0 || count > MAX_DISCARD_WRITE_ZEROES_SEGMENTS as u64 {
+            return Err(RequestError::Malformed);
+        }
+        let mut buf = self.gather(segments)?;
+        buf.truncate(count as usize * size_of::<DiscardWriteZeroes>());
+        Ok(<[DiscardWriteZeroes]>::ref_from_bytes(&buf)
+            .unwrap()
+            .to_vec())
+    }
+
+    /// Reads the contents of `segments` into a buffer.
+    fn gather(&self, segments: &[Segment]) -> Result<Vec<u8>, RequestError> {
+        let len: u64 = segments.iter().map(|s| s.len).sum();
+        let mut buf = vec![0; len as usize];
+        let mut offset = 0;
+        for segment in segments {
+            let next = offset + segment.len as usize;
+            self.mem
+                .read_at(segment.address, &mut buf[offset..next])
+                .map_err(RequestError::Memory)?;
+            offset = next;
+        }
+        Ok(buf)
+    }
+
+    /// Writes `buf` to `segments`.
+    fn scatter(&self, segments: &[Segment], buf: &[u8]) -> Result<(), RequestError> {
+        let mut offset = 0;
+        for segment in segments {
+            let next = offset + segment.len as usize;
+            self.mem
+                .write_at(segment.address, &buf[offset..next])
+                .map_err(RequestError::Memory)?;
+            offset = next;
+        }
+        Ok(())
+    }
+
+    async fn read(&self, virtio_sector: u64, segments: &[Segment]) -> Result<u64, RequestError> {
+        let len: u64 = segments.iter().map(|s| s.len).sum();
+        let (sector, _) = self.disk_range(virtio_sector, len)?;
+        if len == 0 {
+            return Ok(0);
+        }
+        if let Some(buffers) = paged_buffers(segments) {
+            self.disk
+                .read_vectored(&buffers.buffer(&self.mem), sector)
+                .await
+                .map_err(RequestError::Disk)?;
+        } else {
+            if len > MAX_BOUNCE_SIZE as u64 {
+                return Err(RequestError::TooLarge);
+            }
+            let bounce = GuestMemory::allocate(len as usize);
+            let buffers = OwnedRequestBuffers::linear(0, len as usize, true);
+            self.disk
+                .read_vectored(&buffers.buffer(&bounce), sector)
+                .await
+                .map_err(RequestError::Disk)?;
+            let mut buf = vec![0; len as usize];
+            bounce.read_at(0, &mut buf).map_err(RequestError::Memory)?;
+            self.scatter(segments, &buf)?;
+        }
+        Ok(len)
+    }
+
+    async fn write(&self, virtio_sector: u64, segments: &[Segment]) -> Result<(), RequestError> {
+        let len: u64 = segments.iter().map(|s| s.len).sum();
+        let (sector, _) = self.disk_range(virtio_sector, len)?;
+        if len == 0 {
+            return Ok(());
+        }
+        if let Some(buffers) = paged_buffers(segments) {
+            self.disk
+                .write_vectored(&buffers.buffer(&self.mem), sector, false)
+                .await
+                .map_err(RequestError::Disk)?;
+        } else {
+            if len > MAX_BOUNCE_SIZE as u64 {
+                return Err(RequestError::TooLarge);
+            }
+            let buf = self.gather(segments)?;
+            let bounce = GuestMemory::allocate(len as usize);
+            bounce.write_at(0, &buf).map_err(RequestError::Memory)?;
+            let buffers = OwnedRequestBuffers::linear(0, len as usize, false);
+            self.disk
+                .write_vectored(&buffers.buffer(&bounce), sector, false)
+                .await
+                .map_err(RequestError::Disk)?;
+        }
+        Ok(())
+    }
+
+    async fn write_zeroes(
+        &self,
+        sector: u64,
+        count: u64,
+        may_unmap: bool,
+    ) -> Result<(), RequestError> {
+        if may_unmap && self.disk.unmap_behavior() == UnmapBehavior::Zeroes {
+            return self
+                .disk
+                .unmap(sector, count, false)
+                .await
+                .map_err(RequestError::Disk);
+        }
+
+        let chunk_sectors = (ZERO_BUFFER_SIZE >> self.disk.sector_shift()) as u64;
+        let mut sector = sector;
+        let end = sector + count;
+        while sector < end {
+            let n = (end - sector).min(chunk_sectors);
+            let buffers =
+                OwnedRequestBuffers::linear(0, (n << self.disk.sector_shift()) as usize, false);
+            self.disk
+                .write_vectored(&buffers.buffer(&self.zero_buffer), sector, false)
+                .await
+                .map_err(RequestError::Disk)?;
+            sector += n;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pal_async::DefaultDriver;
+    use pal_async::async_test;
+    use virtio::test_helpers::TestBuffer;
+    use virtio::test_helpers::TestQueue;
+    use vmcore::interrupt::Interrupt;
+    use vmcore::vm_task::SingleDriverBackend;
+
+    const HEADER: u64 = 0x10000;
+    const STATUS: u64 = 0x11000;
+    const DATA: u64 = 0x20000;
+    const DISK_SIZE: u64 = 0x100000;
+
+    struct TestDevice {
+        device: Device,
+        queue: TestQueue,
+        mem: GuestMemory,
+    }
+
+    impl TestDevice {
+        fn new(driver: &DefaultDriver, read_only: bool) -> Self {
+            let mem = GuestMemory::allocate(0x100000);
+            let disk = disklayer_ram::ram_disk(DISK_SIZE, false).unwrap();
+            let mut device = Device::new(
+                &VmTaskDriverSource::new(SingleDriverBackend::new(driver.clone())),
+                mem.clone(),
+                disk,
+                read_only,
+                1,
+            );
+            let queue = TestQueue::new(driver, &mem, 0, 16);
+            device.enable(Resources {
+                features: device.traits().device_features,
+                queues: vec![queue.resources()],
+                shared_memory_region: None,
+                shared_memory_size: 0,
+                config_change: Interrupt::null(),
+            });
+            Self { device, queue, mem }
+        }
+
+        /// Issues a request with the given data buffers, returning the status
+        /// and the used length.
+        async fn request(&mut self, ty: u32, sector: u64, data: &[TestBuffer]) -> (u8, u32) {
+            self.mem
+                .write_plain(
+                    HEADER,
+                    &RequestHeader {
+                        request_type: ty.into(),
+                        reserved: 0.into(),
+                        sector: sector.into(),
+                    },
+                )
+                .unwrap();
+            self.mem.write_plain(STATUS, &0xffu8).unwrap();
+            let mut buffers = vec![TestBuffer::readable(
+                HEADER,
+                size_of::<RequestHeader>() as u32,
+            )];
+            buffers.extend_from_slice(data);
+            buffers.push(TestBuffer::writeable(STATUS, 1));
+            let head = self.queue.add(&buffers);
+            let (id, len) = self.queue.used().await;
+            assert_eq!(id, head);
+            (self.mem.read_plain(STATUS).unwrap(), len)
+        }
+
+        async fn discard_or_write_zeroes(
+            &mut self,
+            ty: u32,
+            sector: u64,
+            count: u32,
+            flags: u32,
+        ) -> u8 {
+            let range = DATA + 0x10000;
+            self.mem
+                .write_plain(
+                    range,
+                    &DiscardWriteZeroes {
+                        sector: sector.into(),
+                        num_sectors: count.into(),
+                        flags: flags.into(),
+                    },
+                )
+                .unwrap();
+            let (status, len) = self
+                .request(
+                    ty,
+                    0,
+                    &[TestBuffer::readable(
+                        range,
+                        size_of::<DiscardWriteZeroes>() as u32,
+                    )],
+                )
+                .await;
+            assert_eq!(len, 1);
+            status
+        }
+
+        async fn read_back(&mut self, sector: u64, len: u32) -> Vec<u8> {
+            let (status, used) = self
+                .request(VIRTIO_BLK_T_IN, sector, &[TestBuffer::writeable(DATA, len)])
+                .await;
+            assert_eq!(status, VIRTIO_BLK_S_OK);
+            assert_eq!(used, len + 1);
+            let mut buf = vec![0; len as usize];
+            self.mem.read_at(DATA, &mut buf).unwrap();
+            buf
+        }
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8 + 1).collect()
+    }
+
+    #[async_test]
+    async fn test_read_write(driver: DefaultDriver) {
+        let mut dev = TestDevice::new(&driver, false);
+        let data = pattern(0x2000);
+        dev.mem.write_at(DATA, &data).unwrap();
+        let (status, len) = dev
+            .request(
+                VIRTIO_BLK_T_OUT,
+                16,
+                &[TestBuffer::readable(DATA, data.len() as u32)],
+            )
+            .await;
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        assert_eq!(len, 1);
+
+        dev.mem.fill_at(DATA, 0, data.len()).unwrap();
+        assert_eq!(dev.read_back(16, data.len() as u32).await, data);
+
+        // Read into segments that cannot be described as a paged range, to
+        // exercise the bounce path.
+        let (status, len) = dev
+            .request(
+                VIRTIO_BLK_T_IN,
+                16,
+                &[
+                    TestBuffer::writeable(DATA + 0x8000, 0x200),
+                    TestBuffer::writeable(DATA + 0x9100, 0x1e00),
+                ],
+            )
+            .await;
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        assert_eq!(len, 0x2001);
+        let mut buf = vec![0; 0x2000];
+        dev.mem.read_at(DATA + 0x8000, &mut buf[..0x200]).unwrap();
+        dev.mem.read_at(DATA + 0x9100, &mut buf[0x200..]).unwrap();
+        assert_eq!(buf, data);
+
+        // Requests past the end of the disk fail.
+        let (status, _) = dev
+            .request(
+                VIRTIO_BLK_T_IN,
+                DISK_SIZE >> VIRTIO_BLK_SECTOR_SHIFT,
+                &[TestBuffer::writeable(DATA, 0x200)],
+            )
+            .await;
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+    }
+
+    #[async_test]
+    async fn test_read_only(driver: DefaultDriver) {
+        let mut dev = TestDevice::new(&driver, true);
+        assert_ne!(dev.device.traits().device_features & VIRTIO_BLK_F_RO, 0);
+        let (status, _) = dev
+            .request(VIRTIO_BLK_T_OUT, 0, &[TestBuffer::readable(DATA, 0x200)])
+            .await;
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+    }
+
+    #[async_test]
+    async fn test_flush(driver: DefaultDriver) {
+        let mut dev = TestDevice::new(&driver, false);
+        let (status, len) = dev.request(VIRTIO_BLK_T_FLUSH, 0, &[]).await;
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        assert_eq!(len, 1);
+
+        let (status, len) = dev.request(0x1234, 0, &[]).await;
+        assert_eq!(status, VIRTIO_BLK_S_UNSUPP);
+        assert_eq!(len, 1);
+    }
+
+    #[async_test]
+    async fn test_discard_write_zeroes(driver: DefaultDriver) {
+        let mut dev = TestDevice::new(&driver, false);
+        let data = pattern(0x4000);
+        dev.mem.write_at(DATA, &data).unwrap();
+        let (status, _) = dev
+            .request(
+                VIRTIO_BLK_T_OUT,
+                0,
+                &[TestBuffer::readable(DATA, data.len() as u32)],
+            )
+            .await;
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+
+        // Discard the first 4KiB.
+        assert_eq!(
+            dev.discard_or_write_zeroes(VIRTIO_BLK_T_DISCARD, 0, 8, 0)
+                .await,
+            VIRTIO_BLK_S_OK
+        );
+        // Zero the next 4KiB without allowing unmap.
+        assert_eq!(
+            dev.discard_or_write_zeroes(VIRTIO_BLK_T_WRITE_ZEROES, 8, 8, 0)
+                .await,
+            VIRTIO_BLK_S_OK
+        );
+        // Zero the next 4KiB, allowing unmap.
+        assert_eq!(
+            dev.discard_or_write_zeroes(
+                VIRTIO_BLK_T_WRITE_ZEROES,
+                16,
+                8,
+                VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP
+            )
+            .await,
+            VIRTIO_BLK_S_OK
+        );
+
+        let read = dev.read_back(0, data.len() as u32).await;
+        assert!(read[..0x3000].iter().all(|&b| b == 0));
+        assert_eq!(read[0x3000..], data[0x3000..]);
+
+        // Ranges past the end of the disk fail.
+        assert_eq!(
+            dev.discard_or_write_zeroes(
+                VIRTIO_BLK_T_WRITE_ZEROES,
+                DISK_SIZE >> VIRTIO_BLK_SECTOR_SHIFT,
+                8,
+                0
+            )
+            .await,
+            VIRTIO_BLK_S_IOERR
+        );
+    }
+}
@@ -0,0 +1,103 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Constants and structures defined by the virtio block device spec.
+
+use virtio::spec::u16_le;
+use virtio::spec::u32_le;
+use virtio::spec::u64_le;
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+pub const VIRTIO_DEVICE_TYPE_BLOCK: u16 = 2;
+
+// Device features.
+pub const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+pub const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+pub const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+pub const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+pub const VIRTIO_BLK_F_TOPOLOGY: u64 = 1 << 10;
+pub const VIRTIO_BLK_F_MQ: u64 = 1 << 12;
+pub const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+pub const VIRTIO_BLK_F_WRITE_ZEROES: u64 = 1 << 14;
+
+// Request types.
+pub const VIRTIO_BLK_T_IN: u32 = 0;
+pub const VIRTIO_BLK_T_OUT: u32 = 1;
+pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
+pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
+pub const VIRTIO_BLK_T_DISCARD: u32 = 11;
+pub const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+
+// Request status values.
+pub const VIRTIO_BLK_S_OK: u8 = 0;
+pub const VIRTIO_BLK_S_IOERR: u8 = 1;
+pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// The length of the device ID string returned by `VIRTIO_BLK_T_GET_ID`.
+pub const VIRTIO_BLK_ID_BYTES: usize = 20;
+
+/// The sector size used in virtio block requests, independent of the logical
+/// block size of the device.
+pub const VIRTIO_BLK_SECTOR_SHIFT: u32 = 9;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct Geometry {
+    pub cylinders: u16_le,
+    pub heads: u8,
+    pub sectors: u8,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct Topology {
+    pub physical_block_exp: u8,
+    pub alignment_offset: u8,
+    pub min_io_size: u16_le,
+    pub opt_io_size: u32_le,
+}
+
+/// The device configuration space (`struct virtio_blk_config`).
+#[repr(C)]
+#[derive(Debug, Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct BlkConfig {
+    pub capacity: u64_le,
+    pub size_max: u32_le,
+    pub seg_max: u32_le,
+    pub geometry: Geometry,
+    pub blk_size: u32_le,
+    pub topology: Topology,
+    pub writeback: u8,
+    pub unused0: u8,
+    pub num_queues: u16_le,
+    pub max_discard_sectors: u32_le,
+    pub max_discard_seg: u32_le,
+    pub discard_sector_alignment: u32_le,
+    pub max_write_zeroes_sectors: u32_le,
+    pub max_write_zeroes_seg: u32_le,
+    pub write_zeroes_may_unmap: u8,
+    pub unused1: [u8; 3],
+}
+
+/// The request header (`struct virtio_blk_req`, minus the data and status).
+#[repr(C)]
+#[derive(Debug, Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct RequestHeader {
+    pub request_type: u32_le,
+    pub reserved: u32_le,
+    pub sector: u64_le,
+}
+
+/// A discard or write zeroes range (`struct virtio_blk_discard_write_zeroes`).
+#[repr(C)]
+#[derive(Debug, Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct DiscardWriteZeroes {
+    pub sector: u64_le,
+    pub num_sectors: u32_le,
+    pub flags: u32_le,
+}
+
+pub const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
@@ -21,6 +21,9 @@ struct RxPacket {
 }
 
 /// Holds virtio buffers available for a network backend to send data to the client.
+///
+/// Each buffer starts with the virtio-net header, which is written by
+/// `write_header`. The packet data follows it.
 #[derive(Clone)]
 pub struct VirtioWorkPool {
     mem: GuestMemory,
@@ -75,7 +78,7 @@ fn guest_memory(&self) -> &GuestMemory {
     }
 
     fn write_data(&mut self, id: RxId, data: &[u8]) {
-        let mut locked_packet = self.rx_packets[id.0 as usize].lock();
+        let locked_packet = self.rx_packets[id.0 as usize].lock();
         let work = locked_packet.work.as_ref().expect("invalid buffer index");
         if let Err(err) = work.write_at_offset(header_size() as u64, &self.mem, data) {
             tracing::warn!(
@@ -84,19 +87,24 @@ fn write_data(&mut self, id: RxId, data: &[u8]) {
                 "rx memory write failure"
             );
         }
-        locked_packet.len = (header_size() + data.len()) as u32;
     }
 
     fn guest_addresses(&mut self, id: RxId) -> &[RxBufferSegment] {
         let locked_packet = self.rx_packets[id.0 as usize].lock();
         let work = locked_packet.work.as_ref().expect("invalid buffer index");
+        // Skip the space reserved for the header.
+        let mut skip = header_size() as u32;
         self.buffer_segments = work
             .payload
             .iter()
             .filter(|x| x.writeable)
-            .map(|p| RxBufferSegment {
-                gpa: p.address,
-                len: p.length,
+            .filter_map(|p| {
+                let n = skip.min(p.length);
+                skip -= n;
+                (n < p.length).then(|| RxBufferSegment {
+                    gpa: p.address + n as u64,
+                    len: p.length - n,
+                })
             })
             .collect();
 
@@ -106,7 +114,7 @@ fn guest_addresses(&mut self, id: RxId) -> &[RxBufferSegment] {
     fn capacity(&self, id: RxId) -> u32 {
         let locked_packet = self.rx_packets[id.0 as usize].lock();
         let work = locked_packet.work.as_ref().expect("invalid buffer index");
-        work.get_payload_length(true) as u32
+        (work.get_payload_length(true) as u32).saturating_sub(header_size() as u32)
     }
 
     fn write_header(&mut self, id: RxId, metadata: &RxMetadata) {
@@ -123,9 +131,11 @@ fn write_header(&mut self, id: RxId, metadata: &RxMetadata) {
             num_buffers: 1,
             ..FromZeros::new_zeroed()
         };
-        let locked_packet = self.rx_packets[id.0 as usize].lock();
+        let mut locked_packet = self.rx_packets[id.0 as usize].lock();
+        // The data may have been written with `write_data` or directly to
+        // the guest addresses, so take the length from the metadata.
+        locked_packet.len = (header_size() + metadata.len) as u32;
         let work = locked_packet.work.as_ref().expect("invalid buffer index");
-        assert_eq!(metadata.len + header_size(), locked_packet.len as usize);
         if let Err(err) = work.write(&self.mem, &virtio_net_header.as_bytes()[..header_size()]) {
             tracing::warn!(
                 error = &err as &dyn std::error::Error,
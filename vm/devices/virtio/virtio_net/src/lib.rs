@@ -8,8 +8,8 @@
 pub mod resolver;
 
 // use anyhow::Context;
-use crate::buffers::VirtioWorkPool;
 use bitfield_struct::bitfield;
+pub use buffers::VirtioWorkPool;
 use futures::FutureExt;
 use futures::StreamExt;
 use futures_concurrency::future::Race;
@@ -9,6 +9,7 @@
 use anyhow::Context;
 use async_trait::async_trait;
 use guestmem::GuestMemory;
+use inspect::InspectMut;
 use pal_async::task::Spawn;
 use std::fs;
 use std::sync::Arc;
@@ -65,6 +66,14 @@ struct PmemConfig {
     size: u64,
 }
 
+impl InspectMut for Device {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        req.respond()
+            .hex("len", self.len)
+            .field("writable", self.writable);
+    }
+}
+
 impl VirtioDevice for Device {
     fn traits(&self) -> DeviceTraits {
         DeviceTraits {
@@ -82,6 +82,25 @@ impl ResourceId<VirtioDeviceHandle> for VirtioPmemHandle {
     }
 }
 
+pub mod blk {
+    use mesh::MeshPayload;
+    use vm_resource::Resource;
+    use vm_resource::ResourceId;
+    use vm_resource::kind::DiskHandleKind;
+    use vm_resource::kind::VirtioDeviceHandle;
+
+    #[derive(MeshPayload)]
+    pub struct VirtioBlkHandle {
+        pub disk: Resource<DiskHandleKind>,
+        pub read_only: bool,
+        pub max_queues: Option<u16>,
+    }
+
+    impl ResourceId<VirtioDeviceHandle> for VirtioBlkHandle {
+        const ID: &'static str = "virtio-blk";
+    }
+}
+
 pub mod net {
     use mesh::MeshPayload;
     use net_backend_resources::mac_address::MacAddress;
@@ -101,3 +120,105 @@ impl ResourceId<VirtioDeviceHandle> for VirtioNetHandle {
         const ID: &'static str = "virtio-net";
     }
 }
+
+pub mod rng {
+    use mesh::MeshPayload;
+    use vm_resource::ResourceId;
+    use vm_resource::kind::VirtioDeviceHandle;
+
+    /// A handle to a virtio entropy device.
+    #[derive(MeshPayload)]
+    pub struct VirtioRngHandle {
+        /// The maximum rate at which random data is provided to the guest.
+        /// Must be nonzero. If `None`, the rate is not limited.
+        pub bytes_per_second: Option<u64>,
+    }
+
+    impl ResourceId<VirtioDeviceHandle> for VirtioRngHandle {
+        const ID: &'static str = "virtio-rng";
+    }
+}
+
+pub mod vsock {
+    use mesh::MeshPayload;
+    use vm_resource::ResourceId;
+    use vm_resource::kind::VirtioDeviceHandle;
+
+    #[derive(MeshPayload)]
+    pub struct VirtioVsockHandle {
+        /// The guest's context ID.
+        pub guest_cid: u64,
+        /// The base path of the hybrid vsock Unix sockets. Guest connections
+        /// to host port `N` are relayed to `<base_path>_N`.
+        pub base_path: String,
+        /// The listener for host-initiated connections, using the hybrid vsock
+        /// `CONNECT <port>` handshake.
+        pub listener: Option<unix_socket::UnixListener>,
+    }
+
+    impl ResourceId<VirtioDeviceHandle> for VirtioVsockHandle {
+        const ID: &'static str = "virtio-vsock";
+    }
+}
+
+pub mod balloon {
+    use mesh::MeshPayload;
+    use mesh::rpc::Rpc;
+    use vm_resource::ResourceId;
+    use vm_resource::kind::VirtioDeviceHandle;
+
+    #[derive(MeshPayload)]
+    pub struct VirtioBalloonHandle {
+        /// Whether the guest may deflate the balloon when it runs out of
+        /// memory.
+        pub deflate_on_oom: bool,
+        /// The channel by which to receive balloon control requests.
+        pub recv: mesh::Receiver<BalloonRpc>,
+    }
+
+    impl ResourceId<VirtioDeviceHandle> for VirtioBalloonHandle {
+        const ID: &'static str = "virtio-balloon";
+    }
+
+    /// An RPC request to the balloon device.
+    #[derive(MeshPayload)]
+    pub enum BalloonRpc {
+        /// Sets the number of bytes the guest should give up.
+        SetTarget(Rpc<u64, ()>),
+        /// Gets the current balloon state.
+        Query(Rpc<(), BalloonStatus>),
+    }
+
+    /// The state of the balloon, as reported by the device.
+    #[derive(Debug, MeshPayload)]
+    pub struct BalloonStatus {
+        /// The number of bytes the guest has been asked to give up.
+        pub target: u64,
+        /// The number of bytes the guest has given up.
+        pub actual: u64,
+        /// The most recent memory statistics reported by the guest.
+        pub stats: BalloonStats,
+    }
+
+    /// Memory statistics reported by the guest. Each value is `None` until the
+    /// guest has reported it.
+    #[derive(Debug, Default, Clone, MeshPayload)]
+    pub struct BalloonStats {
+        /// The amount of memory swapped in, in bytes.
+        pub swap_in: Option<u64>,
+        /// The amount of memory swapped out, in bytes.
+        pub swap_out: Option<u64>,
+        /// The number of major page faults.
+        pub major_faults: Option<u64>,
+        /// The number of minor page faults.
+        pub minor_faults: Option<u64>,
+        /// The amount of unused memory, in bytes.
+        pub free_memory: Option<u64>,
+        /// The total amount of usable memory, in bytes.
+        pub total_memory: Option<u64>,
+        /// An estimate of the memory available for new allocations, in bytes.
+        pub available_memory: Option<u64>,
+        /// The amount of memory used by the disk cache, in bytes.
+        pub disk_caches: Option<u64>,
+    }
+}
@@ -0,0 +1,346 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A virtio entropy device, which provides the guest with random data from the
+//! host OS random number generator.
+
+#![forbid(unsafe_code)]
+
+pub mod resolver;
+
+use futures::StreamExt;
+use guestmem::GuestMemory;
+use inspect::Inspect;
+use inspect::InspectMut;
+use pal_async::task::Spawn;
+use pal_async::timer::Instant;
+use pal_async::timer::PolledTimer;
+use pal_async::wait::PolledWait;
+use std::num::NonZeroU64;
+use std::time::Duration;
+use task_control::AsyncRun;
+use task_control::Cancelled;
+use task_control::InspectTask;
+use task_control::StopTask;
+use task_control::TaskControl;
+use virtio::DeviceTraits;
+use virtio::DeviceTraitsSharedMemory;
+use virtio::QueueResources;
+use virtio::Resources;
+use virtio::VirtioDevice;
+use virtio::VirtioQueue;
+use virtio::VirtioQueueCallbackWork;
+use vmcore::vm_task::VmTaskDriver;
+use vmcore::vm_task::VmTaskDriverSource;
+
+const VIRTIO_DEVICE_TYPE_ENTROPY: u16 = 4;
+
+/// The most random data provided for a single request.
+const MAX_REQUEST_SIZE: u32 = 64 * 1024;
+
+/// The shortest time to wait for the rate limiter to refill, to avoid waking
+/// up for every few bytes at low rates.
+const MIN_RATE_LIMIT_WAIT: Duration = Duration::from_millis(10);
+
+/// A virtio entropy device.
+pub struct Device {
+    driver: VmTaskDriver,
+    mem: GuestMemory,
+    bytes_per_second: Option<NonZeroU64>,
+    worker: Option<TaskControl<RngWorker, RngQueue>>,
+}
+
+impl Device {
+    /// Creates a new virtio entropy device, optionally limiting the guest to
+    /// `bytes_per_second` bytes of random data per second.
+    pub fn new(
+        driver_source: &VmTaskDriverSource,
+        memory: GuestMemory,
+        bytes_per_second: Option<NonZeroU64>,
+    ) -> Self {
+        Self {
+            driver: driver_source.simple(),
+            mem: memory,
+            bytes_per_second,
+            worker: None,
+        }
+    }
+
+    fn create_queue(&self, features: u64, resources: QueueResources) -> Option<VirtioQueue> {
+        let QueueResources {
+            params,
+            notify,
+            event,
+        } = resources;
+        if !params.enable {
+            return None;
+        }
+        let queue_event = PolledWait::new(&self.driver, event)
+            .inspect_err(|err| {
+                tracing::error!(
+                    error = err as &dyn std::error::Error,
+                    "failed creating queue event"
+                )
+            })
+            .ok()?;
+        VirtioQueue::new(features, params, self.mem.clone(), notify, queue_event)
+            .inspect_err(|err| {
+                tracing::error!(
+                    error = err as &dyn std::error::Error,
+                    "failed creating virtio rng queue"
+                )
+            })
+            .ok()
+    }
+}
+
+impl InspectMut for Device {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        req.respond()
+            .field("bytes_per_second", self.bytes_per_second)
+            .field("worker", &self.worker);
+    }
+}
+
+impl VirtioDevice for Device {
+    fn traits(&self) -> DeviceTraits {
+        DeviceTraits {
+            device_id: VIRTIO_DEVICE_TYPE_ENTROPY,
+            device_features: 0,
+            max_queues: 1,
+            device_register_length: 0,
+            shared_memory: DeviceTraitsSharedMemory { id: 0, size: 0 },
+        }
+    }
+
+    fn read_registers_u32(&self, _offset: u16) -> u32 {
+        0
+    }
+
+    fn write_registers_u32(&mut self, _offset: u16, _val: u32) {}
+
+    fn enable(&mut self, resources: Resources) {
+        assert!(self.worker.is_none());
+        let features = resources.features;
+        let Some(queue) = resources
+            .queues
+            .into_iter()
+            .next()
+            .and_then(|queue| self.create_queue(features, queue))
+        else {
+            return;
+        };
+
+        let mut worker = TaskControl::new(RngWorker {
+            mem: self.mem.clone(),
+            rate_limiter: self
+                .bytes_per_second
+                .map(|rate| RateLimiter::new(&self.driver, rate)),
+            bytes_provided: 0,
+        });
+        worker.insert(&self.driver, "virtio-rng", RngQueue { queue });
+        worker.start();
+        self.worker = Some(worker);
+    }
+
+    fn disable(&mut self) {
+        let Some(mut worker) = self.worker.take() else {
+            return;
+        };
+        self.driver
+            .spawn("shutdown-virtio-rng", async move {
+                worker.stop().await;
+            })
+            .detach();
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        self.disable();
+    }
+}
+
+#[derive(Inspect)]
+struct RngWorker {
+    #[inspect(skip)]
+    mem: GuestMemory,
+    rate_limiter: Option<RateLimiter>,
+    bytes_provided: u64,
+}
+
+struct RngQueue {
+    queue: VirtioQueue,
+}
+
+impl InspectTask<RngQueue> for RngWorker {
+    fn inspect(&self, req: inspect::Request<'_>, _state: Option<&RngQueue>) {
+        req.respond().merge(self);
+    }
+}
+
+impl AsyncRun<RngQueue> for RngWorker {
+    async fn run(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        state: &mut RngQueue,
+    ) -> Result<(), Cancelled> {
+        stop.until_stopped(self.process(state)).await
+    }
+}
+
+impl RngWorker {
+    async fn process(&mut self, state: &mut RngQueue) {
+        loop {
+            let work = state.queue.next().await.expect("queue will never complete");
+            match work {
+                Ok(work) => self.fill(work).await,
+                Err(err) => {
+                    tracing::error!(error = &err as &dyn std::error::Error, "queue error");
+                    break;
+                }
+            }
+        }
+        std::future::pending::<()>().await;
+    }
+
+    async fn fill(&mut self, mut work: VirtioQueueCallbackWork) {
+        let mut len = work.get_payload_length(true).min(MAX_REQUEST_SIZE.into());
+        if len == 0 {
+            return;
+        }
+        if let Some(rate_limiter) = &mut self.rate_limiter {
+            len = rate_limiter.acquire(len).await;
+        }
+
+        let mut buf = vec![0; len as usize];
+        if let Err(err) = getrandom::fill(&mut buf) {
+            tracelimit::error_ratelimited!(error = %err, "failed to get random data");
+            return;
+        }
+        if let Err(err) = work.write(&self.mem, &buf) {
+            tracelimit::warn_ratelimited!(
+                error = &err as &dyn std::error::Error,
+                "failed to write random data"
+            );
+            return;
+        }
+        self.bytes_provided += len;
+        work.complete(len as u32);
+    }
+}
+
+/// A token bucket limiting how quickly random data is provided to the guest.
+/// The bucket holds up to one second's worth of data.
+#[derive(Inspect)]
+struct RateLimiter {
+    bytes_per_second: u64,
+    available: u64,
+    #[inspect(skip)]
+    last_refill: Instant,
+    #[inspect(skip)]
+    timer: PolledTimer,
+    /// The number of times a request had to wait for the bucket to refill.
+    throttled: u64,
+}
+
+impl RateLimiter {
+    fn new(driver: &VmTaskDriver, bytes_per_second: NonZeroU64) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second.get(),
+            available: bytes_per_second.get(),
+            last_refill: Instant::now(),
+            timer: PolledTimer::new(driver),
+            throttled: 0,
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.saturating_sub(self.last_refill);
+        let refill = (elapsed.as_nanos() * self.bytes_per_second as u128 / 1_000_000_000)
+            .try_into()
+            .unwrap_or(u64::MAX);
+        // Leave the refill time alone until at least a byte has accumulated so
+        // that low rates still make progress.
+        if refill > 0 {
+            self.available = self
+                .available
+                .saturating_add(refill)
+                .min(self.bytes_per_second);
+            self.last_refill = now;
+        }
+    }
+
+    /// Waits until some data is available, and then takes up to `len` bytes.
+    async fn acquire(&mut self, len: u64) -> u64 {
+        self.refill();
+        if self.available == 0 {
+            self.throttled += 1;
+            let wait = Duration::from_nanos(1_000_000_000u64.div_ceil(self.bytes_per_second))
+                .max(MIN_RATE_LIMIT_WAIT);
+            while self.available == 0 {
+                self.timer.sleep(wait).await;
+                self.refill();
+            }
+        }
+        let n = self.available.min(len);
+        self.available -= n;
+        n
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pal_async::DefaultDriver;
+    use pal_async::async_test;
+    use virtio::test_helpers::TestBuffer;
+    use virtio::test_helpers::TestQueue;
+    use vmcore::interrupt::Interrupt;
+    use vmcore::vm_task::SingleDriverBackend;
+
+    fn enabled_device(
+        driver: &DefaultDriver,
+        mem: &GuestMemory,
+        bytes_per_second: Option<NonZeroU64>,
+    ) -> (Device, TestQueue) {
+        let mut device = Device::new(
+            &VmTaskDriverSource::new(SingleDriverBackend::new(driver.clone())),
+            mem.clone(),
+            bytes_per_second,
+        );
+        let queue = TestQueue::new(driver, mem, 0, 16);
+        device.enable(Resources {
+            features: 0,
+            queues: vec![queue.resources()],
+            shared_memory_region: None,
+            shared_memory_size: 0,
+            config_change: Interrupt::null(),
+        });
+        (device, queue)
+    }
+
+    #[async_test]
+    async fn test_fill(driver: DefaultDriver) {
+        let mem = GuestMemory::allocate(0x10000);
+        let (_device, mut queue) = enabled_device(&driver, &mem, None);
+        let head = queue.add(&[
+            TestBuffer::writeable(0x1000, 100),
+            TestBuffer::writeable(0x2000, 200),
+        ]);
+        assert_eq!(queue.used().await, (head, 300));
+
+        let mut buf = [0; 200];
+        mem.read_at(0x2000, &mut buf).unwrap();
+        assert!(buf.iter().any(|&b| b != 0));
+    }
+
+    #[async_test]
+    async fn test_rate_limit(driver: DefaultDriver) {
+        let mem = GuestMemory::allocate(0x10000);
+        let (_device, mut queue) = enabled_device(&driver, &mem, NonZeroU64::new(64));
+        let head = queue.add(&[TestBuffer::writeable(0x1000, 1000)]);
+        assert_eq!(queue.used().await, (head, 64));
+    }
+}
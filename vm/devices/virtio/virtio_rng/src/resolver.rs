@@ -0,0 +1,45 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Defines the resource resolver for virtio-rng devices.
+
+use crate::Device;
+use std::num::NonZeroU64;
+use virtio::resolve::ResolvedVirtioDevice;
+use virtio::resolve::VirtioResolveInput;
+use virtio_resources::rng::VirtioRngHandle;
+use vm_resource::ResolveResource;
+use vm_resource::declare_static_resolver;
+use vm_resource::kind::VirtioDeviceHandle;
+
+/// Resolver for virtio-rng devices.
+pub struct VirtioRngResolver;
+
+declare_static_resolver! {
+    VirtioRngResolver,
+    (VirtioDeviceHandle, VirtioRngHandle),
+}
+
+impl ResolveResource<VirtioDeviceHandle, VirtioRngHandle> for VirtioRngResolver {
+    type Output = ResolvedVirtioDevice;
+    type Error = anyhow::Error;
+
+    fn resolve(
+        &self,
+        resource: VirtioRngHandle,
+        input: VirtioResolveInput<'_>,
+    ) -> Result<Self::Output, Self::Error> {
+        let bytes_per_second = resource
+            .bytes_per_second
+            .map(|n| {
+                NonZeroU64::new(n).ok_or_else(|| anyhow::anyhow!("rate limit must be nonzero"))
+            })
+            .transpose()?;
+        let device = Device::new(
+            input.driver_source,
+            input.guest_memory.clone(),
+            bytes_per_second,
+        );
+        Ok(device.into())
+    }
+}
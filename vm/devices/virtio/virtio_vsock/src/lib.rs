@@ -0,0 +1,290 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A virtio vsock device that relays guest stream sockets to Unix sockets on
+//! the host.
+//!
+//! This uses the same [hybrid vsock connection model][1] as the vmbus
+//! hvsocket relay, so host software written against hvsocket (such as
+//! pipette) works unmodified with guests that only have virtio drivers:
+//!
+//! * A guest connection to host port `N` is relayed to the Unix socket at
+//!   `<base_path>_N`.
+//! * A host connection to the listener at `<base_path>` writes
+//!   `CONNECT <port>\n`, is relayed to guest port `<port>`, and receives
+//!   `OK <host port>\n` once the guest accepts the connection.
+//!
+//! [1]: <https://github.com/firecracker-microvm/firecracker/blob/7b2e87dc65fc45162303e5708b83c379cf1b0426/docs/vsock.md>
+
+#![forbid(unsafe_code)]
+
+mod relay;
+pub mod resolver;
+mod spec;
+
+use anyhow::Context as _;
+use futures::AsyncReadExt;
+use guestmem::GuestMemory;
+use inspect::InspectMut;
+use pal_async::socket::PolledSocket;
+use pal_async::task::Spawn;
+use pal_async::task::Task;
+use pal_async::wait::PolledWait;
+use parking_lot::Mutex;
+use relay::HostConnection;
+use relay::VsockRelay;
+use relay::VsockRelayState;
+use spec::*;
+use std::path::PathBuf;
+use std::sync::Arc;
+use task_control::TaskControl;
+use unix_socket::UnixListener;
+use unix_socket::UnixStream;
+use virtio::DeviceTraits;
+use virtio::DeviceTraitsSharedMemory;
+use virtio::QueueResources;
+use virtio::Resources;
+use virtio::VirtioDevice;
+use virtio::VirtioQueue;
+use vmcore::vm_task::VmTaskDriver;
+use vmcore::vm_task::VmTaskDriverSource;
+use zerocopy::IntoBytes;
+
+/// A virtio vsock device.
+pub struct Device {
+    driver: VmTaskDriver,
+    mem: GuestMemory,
+    guest_cid: u64,
+    base_path: PathBuf,
+    /// Where the listener sends host-initiated connections. Only set while
+    /// the device is enabled.
+    host_connections: Arc<Mutex<Option<mesh::Sender<HostConnection>>>>,
+    worker: Option<TaskControl<VsockRelay, VsockRelayState>>,
+    _listener_task: Option<Task<()>>,
+}
+
+impl Device {
+    /// Creates a new virtio vsock device for a guest with context ID
+    /// `guest_cid`.
+    pub fn new(
+        driver_source: &VmTaskDriverSource,
+        memory: GuestMemory,
+        guest_cid: u64,
+        base_path: PathBuf,
+        listener: Option<UnixListener>,
+    ) -> anyhow::Result<Self> {
+        let driver = driver_source.simple();
+        let host_connections = Arc::new(Mutex::new(None));
+        let listener_task = listener
+            .map(|listener| {
+                let listener = PolledSocket::new(&driver, listener)
+                    .context("failed to create polled listener")?;
+                anyhow::Ok(driver.spawn(
+                    "virtio-vsock-listener",
+                    run_listener(driver.clone(), listener, host_connections.clone()),
+                ))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            driver,
+            mem: memory,
+            guest_cid,
+            base_path,
+            host_connections,
+            worker: None,
+            _listener_task: listener_task,
+        })
+    }
+
+    fn create_queue(&self, features: u64, resources: QueueResources) -> Option<VirtioQueue> {
+        let QueueResources {
+            params,
+            notify,
+            event,
+        } = resources;
+        if !params.enable {
+            return None;
+        }
+        let queue_event = PolledWait::new(&self.driver, event)
+            .inspect_err(|err| {
+                tracing::error!(
+                    error = err as &dyn std::error::Error,
+                    "failed creating queue event"
+                )
+            })
+            .ok()?;
+        VirtioQueue::new(features, params, self.mem.clone(), notify, queue_event)
+            .inspect_err(|err| {
+                tracing::error!(
+                    error = err as &dyn std::error::Error,
+                    "failed creating virtio vsock queue"
+                )
+            })
+            .ok()
+    }
+}
+
+impl InspectMut for Device {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        let mut resp = req.respond();
+        resp.field("guest_cid", self.guest_cid)
+            .field("base_path", self.base_path.display().to_string())
+            .field("relay", &self.worker);
+    }
+}
+
+impl VirtioDevice for Device {
+    fn traits(&self) -> DeviceTraits {
+        DeviceTraits {
+            device_id: VIRTIO_DEVICE_TYPE_VSOCK,
+            device_features: 0,
+            max_queues: 3,
+            device_register_length: size_of::<VsockConfig>() as u32,
+            shared_memory: DeviceTraitsSharedMemory { id: 0, size: 0 },
+        }
+    }
+
+    fn read_registers_u32(&self, offset: u16) -> u32 {
+        let config = VsockConfig {
+            guest_cid: self.guest_cid.into(),
+        };
+        let offset = offset as usize;
+        config
+            .as_bytes()
+            .get(offset..offset + 4)
+            .map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn write_registers_u32(&mut self, _offset: u16, _val: u32) {}
+
+    fn enable(&mut self, resources: Resources) {
+        assert!(self.worker.is_none());
+        let features = resources.features;
+        let mut queues = resources.queues.into_iter();
+        let rx = queues.next().and_then(|q| self.create_queue(features, q));
+        let tx = queues.next().and_then(|q| self.create_queue(features, q));
+        // The event queue is only used to report transport resets, which this
+        // device never does.
+        let (Some(rx), Some(tx)) = (rx, tx) else {
+            tracing::error!("virtio vsock enabled without rx and tx queues");
+            return;
+        };
+
+        let (send, recv) = mesh::channel();
+        *self.host_connections.lock() = Some(send);
+
+        let mut worker = TaskControl::new(VsockRelay::new(
+            self.driver.clone(),
+            self.mem.clone(),
+            self.guest_cid,
+            self.base_path.clone(),
+        ));
+        worker.insert(
+            &self.driver,
+            "virtio-vsock",
+            VsockRelayState::new(rx, tx, recv),
+        );
+        worker.start();
+        self.worker = Some(worker);
+    }
+
+    fn disable(&mut self) {
+        *self.host_connections.lock() = None;
+        let Some(mut worker) = self.worker.take() else {
+            return;
+        };
+        self.driver
+            .spawn("shutdown-virtio-vsock", async move {
+                worker.stop().await;
+            })
+            .detach();
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        self.disable();
+    }
+}
+
+/// Accepts host connections to the hybrid vsock listener and forwards them to
+/// the relay once the connect request has been read.
+async fn run_listener(
+    driver: VmTaskDriver,
+    mut listener: PolledSocket<UnixListener>,
+    host_connections: Arc<Mutex<Option<mesh::Sender<HostConnection>>>>,
+) {
+    loop {
+        let connection = match listener.accept().await {
+            Ok((connection, _address)) => connection,
+            Err(err) => {
+                tracing::error!(
+                    error = &err as &dyn std::error::Error,
+                    "failed to accept hybrid vsock connection, shutting down listener"
+                );
+                break;
+            }
+        };
+        let host_connections = host_connections.clone();
+        let driver_clone = driver.clone();
+        driver
+            .spawn("virtio-vsock-connect", async move {
+                let connection = match read_connect_request(&driver_clone, connection).await {
+                    Ok(connection) => connection,
+                    Err(err) => {
+                        tracelimit::warn_ratelimited!(
+                            error = err.as_ref() as &dyn std::error::Error,
+                            "invalid hybrid vsock connect request"
+                        );
+                        return;
+                    }
+                };
+                if let Some(send) = &*host_connections.lock() {
+                    send.send(connection);
+                } else {
+                    tracelimit::warn_ratelimited!(
+                        port = connection.port,
+                        "virtio vsock not ready, dropping host connection"
+                    );
+                }
+            })
+            .detach();
+    }
+}
+
+/// Reads a hybrid vsock `CONNECT <port>\n` request.
+async fn read_connect_request(
+    driver: &VmTaskDriver,
+    connection: UnixStream,
+) -> anyhow::Result<HostConnection> {
+    let mut socket = PolledSocket::new(driver, connection)?;
+    let mut buf = [0; "CONNECT 4294967295\n".len()];
+    let mut i = 0;
+    // Read a byte at a time to avoid consuming any data that follows the
+    // request.
+    while i == 0 || buf[i - 1] != b'\n' {
+        if i == buf.len() {
+            anyhow::bail!("connect request did not fit");
+        }
+        let n = socket
+            .read(&mut buf[i..i + 1])
+            .await
+            .context("failed to read connect request")?;
+        if n == 0 {
+            anyhow::bail!("no connect request");
+        }
+        i += n;
+    }
+
+    let port = buf[..i - 1]
+        .strip_prefix(b"CONNECT ")
+        .and_then(|port| std::str::from_utf8(port).ok())
+        .context("invalid connect request")?;
+    let port = port
+        .parse()
+        .with_context(|| format!("invalid port {port}"))?;
+
+    tracing::debug!(port, "got hybrid vsock connect request");
+    Ok(HostConnection { socket, port })
+}
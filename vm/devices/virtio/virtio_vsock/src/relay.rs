@@ -0,0 +1,936 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! The relay between guest vsock stream connections and host Unix sockets.
+
+use crate::spec::*;
+use futures::AsyncRead;
+use futures::AsyncWrite;
+use futures::StreamExt;
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use guestmem::GuestMemory;
+use inspect::Inspect;
+use pal_async::socket::PolledSocket;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+use task_control::AsyncRun;
+use task_control::Cancelled;
+use task_control::InspectTask;
+use task_control::StopTask;
+use unix_socket::UnixStream;
+use virtio::VirtioQueue;
+use virtio::VirtioQueueCallbackWork;
+use vmcore::vm_task::VmTaskDriver;
+use zerocopy::FromBytes;
+use zerocopy::IntoBytes;
+
+/// The receive buffer space advertised to the guest for each connection. The
+/// guest will not send more than this much data that has not yet been written
+/// to the host socket.
+const HOST_BUF_ALLOC: u32 = 256 * 1024;
+
+/// The largest payload read from a host socket or accepted from the guest in
+/// a single packet.
+const MAX_PACKET_PAYLOAD: usize = 64 * 1024;
+
+/// The maximum number of packets waiting for guest receive buffers before
+/// the relay stops reading from host sockets and processing guest packets.
+const MAX_RX_PENDING: usize = 256;
+
+/// The maximum number of concurrent connections.
+const MAX_CONNECTIONS: usize = 1024;
+
+/// The first host port used for host-initiated connections.
+const FIRST_HOST_PORT: u32 = 1024;
+
+/// A host connection that has completed the hybrid vsock handshake and is
+/// waiting to be connected to the guest.
+pub(crate) struct HostConnection {
+    pub socket: PolledSocket<UnixStream>,
+    pub port: u32,
+}
+
+/// The relay task, which persists across queue resets.
+pub(crate) struct VsockRelay {
+    driver: VmTaskDriver,
+    mem: GuestMemory,
+    guest_cid: u64,
+    base_path: PathBuf,
+}
+
+impl VsockRelay {
+    pub fn new(driver: VmTaskDriver, mem: GuestMemory, guest_cid: u64, base_path: PathBuf) -> Self {
+        Self {
+            driver,
+            mem,
+            guest_cid,
+            base_path,
+        }
+    }
+}
+
+/// The relay state, which is reset along with the queues.
+pub(crate) struct VsockRelayState {
+    rx: VirtioQueue,
+    tx: VirtioQueue,
+    host_connections: mesh::Receiver<HostConnection>,
+    /// Guest connections waiting for the host socket to connect.
+    connects:
+        FuturesUnordered<BoxFuture<'static, (ConnectionKey, io::Result<PolledSocket<UnixStream>>)>>,
+    /// The guest's credit for each connection in `connects`. Removed if the
+    /// guest resets the connection before it completes.
+    connecting: HashMap<ConnectionKey, Credit>,
+    connections: HashMap<ConnectionKey, Connection>,
+    /// Packets waiting for a guest receive buffer.
+    rx_pending: VecDeque<Packet>,
+    next_host_port: u32,
+    read_buffer: Box<[u8]>,
+}
+
+impl VsockRelayState {
+    pub fn new(
+        rx: VirtioQueue,
+        tx: VirtioQueue,
+        host_connections: mesh::Receiver<HostConnection>,
+    ) -> Self {
+        Self {
+            rx,
+            tx,
+            host_connections,
+            connects: FuturesUnordered::new(),
+            connecting: HashMap::new(),
+            connections: HashMap::new(),
+            rx_pending: VecDeque::new(),
+            next_host_port: FIRST_HOST_PORT,
+            read_buffer: vec![0; MAX_PACKET_PAYLOAD].into(),
+        }
+    }
+
+    fn connection_count(&self) -> usize {
+        self.connections.len() + self.connecting.len()
+    }
+}
+
+impl AsyncRun<VsockRelayState> for VsockRelay {
+    async fn run(
+        &mut self,
+        stop: &mut StopTask<'_>,
+        state: &mut VsockRelayState,
+    ) -> Result<(), Cancelled> {
+        stop.until_stopped(async {
+            let err = poll_fn(|cx| state.poll_relay(cx, self)).await;
+            tracing::error!(error = &err as &dyn std::error::Error, "queue error");
+            std::future::pending::<()>().await;
+        })
+        .await
+    }
+}
+
+impl InspectTask<VsockRelayState> for VsockRelay {
+    fn inspect(&self, req: inspect::Request<'_>, state: Option<&VsockRelayState>) {
+        let mut resp = req.respond();
+        if let Some(state) = state {
+            resp.field("connecting", state.connecting.len())
+                .field("rx_pending", state.rx_pending.len())
+                .child("connections", |req| {
+                    let mut resp = req.respond();
+                    for (key, connection) in &state.connections {
+                        resp.field(&format!("{}:{}", key.host_port, key.guest_port), connection);
+                    }
+                });
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct ConnectionKey {
+    host_port: u32,
+    guest_port: u32,
+}
+
+/// The guest's receive buffer state for a connection.
+#[derive(Debug, Copy, Clone, Default, Inspect)]
+struct Credit {
+    buf_alloc: u32,
+    fwd_cnt: u32,
+}
+
+impl Credit {
+    fn from_header(header: &VsockHeader) -> Self {
+        Self {
+            buf_alloc: header.buf_alloc.get(),
+            fwd_cnt: header.fwd_cnt.get(),
+        }
+    }
+}
+
+struct Packet {
+    header: VsockHeader,
+    data: Vec<u8>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Inspect)]
+enum ConnectionState {
+    /// Waiting for the guest to accept a host-initiated connection.
+    Connecting,
+    Connected,
+}
+
+/// The result of polling a connection's socket.
+enum IoStatus {
+    Idle,
+    Progress,
+    /// The connection has been reset and should be removed.
+    Closed,
+}
+
+#[derive(Inspect)]
+struct Connection {
+    #[inspect(skip)]
+    socket: PolledSocket<UnixStream>,
+    #[inspect(skip)]
+    template: VsockHeader,
+    state: ConnectionState,
+    /// Data to write to the socket: the hybrid vsock reply, if any, followed
+    /// by data from the guest.
+    #[inspect(with = "VecDeque::len")]
+    write_buf: VecDeque<u8>,
+    /// The length of the hybrid vsock reply at the front of `write_buf`.
+    reply_len: usize,
+    /// The number of bytes from the guest written to the socket.
+    fwd_cnt: u32,
+    /// The value of `fwd_cnt` most recently sent to the guest.
+    last_fwd_cnt_sent: u32,
+    /// The number of bytes sent to the guest.
+    tx_cnt: u32,
+    peer_credit: Credit,
+    /// The shutdown flags received from the guest.
+    peer_shutdown: u32,
+    /// The socket has reached end of file.
+    read_closed: bool,
+    /// The socket has been shut down for writing.
+    write_closed: bool,
+}
+
+impl Connection {
+    fn new(
+        relay: &VsockRelay,
+        key: ConnectionKey,
+        socket: PolledSocket<UnixStream>,
+        state: ConnectionState,
+        peer_credit: Credit,
+    ) -> Self {
+        Self {
+            socket,
+            template: VsockHeader {
+                src_cid: VSOCK_HOST_CID.into(),
+                dst_cid: relay.guest_cid.into(),
+                src_port: key.host_port.into(),
+                dst_port: key.guest_port.into(),
+                len: 0.into(),
+                socket_type: VIRTIO_VSOCK_TYPE_STREAM.into(),
+                op: 0.into(),
+                flags: 0.into(),
+                buf_alloc: HOST_BUF_ALLOC.into(),
+                fwd_cnt: 0.into(),
+            },
+            state,
+            write_buf: VecDeque::new(),
+            reply_len: 0,
+            fwd_cnt: 0,
+            last_fwd_cnt_sent: 0,
+            tx_cnt: 0,
+            peer_credit,
+            peer_shutdown: 0,
+            read_closed: false,
+            write_closed: false,
+        }
+    }
+
+    fn packet(&mut self, op: u16, flags: u32, data: Vec<u8>) -> Packet {
+        self.last_fwd_cnt_sent = self.fwd_cnt;
+        Packet {
+            header: VsockHeader {
+                len: (data.len() as u32).into(),
+                op: op.into(),
+                flags: flags.into(),
+                fwd_cnt: self.fwd_cnt.into(),
+                ..self.template
+            },
+            data,
+        }
+    }
+
+    /// Returns the number of bytes that can be sent to the guest without
+    /// overrunning its receive buffer.
+    fn guest_credit(&self) -> u32 {
+        let in_flight = self.tx_cnt.wrapping_sub(self.peer_credit.fwd_cnt);
+        self.peer_credit.buf_alloc.saturating_sub(in_flight)
+    }
+
+    fn poll_io(
+        &mut self,
+        cx: &mut Context<'_>,
+        rx_pending: &mut VecDeque<Packet>,
+        read_buffer: &mut [u8],
+    ) -> IoStatus {
+        if self.state == ConnectionState::Connecting {
+            return IoStatus::Idle;
+        }
+
+        let mut status = IoStatus::Idle;
+
+        // Write the pending data to the socket.
+        while !self.write_buf.is_empty() {
+            let (data, _) = self.write_buf.as_slices();
+            match Pin::new(&mut self.socket).poll_write(cx, data) {
+                Poll::Ready(Ok(n)) if n > 0 => {
+                    self.write_buf.drain(..n);
+                    let reply_n = n.min(self.reply_len);
+                    self.reply_len -= reply_n;
+                    self.fwd_cnt = self.fwd_cnt.wrapping_add((n - reply_n) as u32);
+                    status = IoStatus::Progress;
+                }
+                Poll::Ready(r) => {
+                    let err = r.err().unwrap_or_else(|| io::ErrorKind::WriteZero.into());
+                    tracing::debug!(
+                        error = &err as &dyn std::error::Error,
+                        "vsock socket write failed"
+                    );
+                    rx_pending.push_back(self.packet(VIRTIO_VSOCK_OP_RST, 0, Vec::new()));
+                    return IoStatus::Closed;
+                }
+                Poll::Pending => break,
+            }
+        }
+
+        // Let the guest know about the freed buffer space before it runs out.
+        if self.fwd_cnt.wrapping_sub(self.last_fwd_cnt_sent) >= HOST_BUF_ALLOC / 4 {
+            rx_pending.push_back(self.packet(VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, Vec::new()));
+            status = IoStatus::Progress;
+        }
+
+        if self.write_buf.is_empty() && self.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_SEND != 0 {
+            if !self.write_closed {
+                // Forward the guest's shutdown to the host.
+                if let Poll::Ready(Err(err)) = Pin::new(&mut self.socket).poll_close(cx) {
+                    tracing::debug!(
+                        error = &err as &dyn std::error::Error,
+                        "vsock socket shutdown failed"
+                    );
+                }
+                self.write_closed = true;
+            }
+            // The connection is done once the guest has closed both
+            // directions, or once both sides have finished sending.
+            if self.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_RCV != 0 || self.read_closed {
+                rx_pending.push_back(self.packet(VIRTIO_VSOCK_OP_RST, 0, Vec::new()));
+                return IoStatus::Closed;
+            }
+        }
+
+        // Read data from the socket, as long as the guest has room for it.
+        if !self.read_closed
+            && self.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_RCV == 0
+            && rx_pending.len() < MAX_RX_PENDING
+        {
+            let credit = self.guest_credit() as usize;
+            if credit > 0 {
+                let buf = &mut read_buffer[..credit.min(MAX_PACKET_PAYLOAD)];
+                match Pin::new(&mut self.socket).poll_read(cx, buf) {
+                    Poll::Ready(Ok(0)) => {
+                        self.read_closed = true;
+                        rx_pending.push_back(self.packet(
+                            VIRTIO_VSOCK_OP_SHUTDOWN,
+                            VIRTIO_VSOCK_SHUTDOWN_SEND,
+                            Vec::new(),
+                        ));
+                        status = IoStatus::Progress;
+                    }
+                    Poll::Ready(Ok(n)) => {
+                        self.tx_cnt = self.tx_cnt.wrapping_add(n as u32);
+                        rx_pending.push_back(self.packet(VIRTIO_VSOCK_OP_RW, 0, buf[..n].to_vec()));
+                        status = IoStatus::Progress;
+                    }
+                    Poll::Ready(Err(err)) => {
+                        tracing::debug!(
+                            error = &err as &dyn std::error::Error,
+                            "vsock socket read failed"
+                        );
+                        rx_pending.push_back(self.packet(VIRTIO_VSOCK_OP_RST, 0, Vec::new()));
+                        return IoStatus::Closed;
+                    }
+                    Poll::Pending => {}
+                }
+            }
+        }
+
+        status
+    }
+}
+
+impl VsockRelayState {
+    /// Runs the relay until a queue fails.
+    fn poll_relay(&mut self, cx: &mut Context<'_>, relay: &VsockRelay) -> Poll<io::Error> {
+        loop {
+            let mut progress = false;
+
+            // Process packets from the guest. Each packet may generate a
+            // reply, so stop while the guest is not taking packets from the
+            // receive queue.
+            while self.rx_pending.len() < MAX_RX_PENDING {
+                let Poll::Ready(work) = self.tx.poll_next_unpin(cx) else {
+                    break;
+                };
+                match work.expect("queue will never complete") {
+                    Ok(work) => self.handle_guest_packet(relay, &work),
+                    Err(err) => return Poll::Ready(err),
+                }
+                progress = true;
+            }
+
+            // Start connections from the host.
+            while let Poll::Ready(Some(connection)) = self.host_connections.poll_next_unpin(cx) {
+                self.handle_host_connect(relay, connection);
+                progress = true;
+            }
+
+            // Finish connections from the guest.
+            while let Poll::Ready(Some((key, result))) = self.connects.poll_next_unpin(cx) {
+                self.handle_connect_complete(relay, key, result);
+                progress = true;
+            }
+
+            // Relay data between the guest and the host sockets.
+            self.connections.retain(|_, connection| {
+                match connection.poll_io(cx, &mut self.rx_pending, &mut self.read_buffer) {
+                    IoStatus::Idle => true,
+                    IoStatus::Progress => {
+                        progress = true;
+                        true
+                    }
+                    IoStatus::Closed => {
+                        progress = true;
+                        false
+                    }
+                }
+            });
+
+            // Send pending packets to the guest.
+            while !self.rx_pending.is_empty() {
+                let Poll::Ready(work) = self.rx.poll_next_unpin(cx) else {
+                    break;
+                };
+                match work.expect("queue will never complete") {
+                    Ok(work) => self.deliver(relay, work),
+                    Err(err) => return Poll::Ready(err),
+                }
+                progress = true;
+            }
+
+            if !progress {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    fn deliver(&mut self, relay: &VsockRelay, mut work: VirtioQueueCallbackWork) {
+        let capacity = work.get_payload_length(true) as usize;
+        let Some(capacity) = capacity.checked_sub(size_of::<VsockHeader>()) else {
+            tracelimit::warn_ratelimited!(capacity, "vsock receive buffer too small");
+            return;
+        };
+
+        // Split the packet if it does not fit in the buffer.
+        let mut packet = self.rx_pending.pop_front().unwrap();
+        if packet.data.len() > capacity {
+            let rest = packet.data.split_off(capacity);
+            self.rx_pending.push_front(Packet {
+                header: VsockHeader {
+                    len: (rest.len() as u32).into(),
+                    ..packet.header
+                },
+                data: rest,
+            });
+            packet.header.len = (capacity as u32).into();
+        }
+
+        if let Err(err) = work
+            .write_at_offset(0, &relay.mem, packet.header.as_bytes())
+            .and_then(|()| {
+                work.write_at_offset(size_of::<VsockHeader>() as u64, &relay.mem, &packet.data)
+            })
+        {
+            tracelimit::warn_ratelimited!(
+                error = &err as &dyn std::error::Error,
+                "failed to write vsock packet"
+            );
+            return;
+        }
+        work.complete((size_of::<VsockHeader>() + packet.data.len()) as u32);
+    }
+
+    fn handle_guest_packet(&mut self, relay: &VsockRelay, work: &VirtioQueueCallbackWork) {
+        let len = (work.get_payload_length(false) as usize)
+            .min(size_of::<VsockHeader>() + MAX_PACKET_PAYLOAD);
+        let mut buf = vec![0; len];
+        if let Err(err) = work.read(&relay.mem, &mut buf) {
+            tracelimit::warn_ratelimited!(
+                error = &err as &dyn std::error::Error,
+                "failed to read vsock packet"
+            );
+            return;
+        }
+        let Ok((header, data)) = VsockHeader::read_from_prefix(&buf) else {
+            tracelimit::warn_ratelimited!(len, "vsock packet too small");
+            return;
+        };
+        let op = header.op.get();
+
+        if header.socket_type.get() != VIRTIO_VSOCK_TYPE_STREAM
+            || header.src_cid.get() != relay.guest_cid
+            || header.dst_cid.get() != VSOCK_HOST_CID
+        {
+            tracelimit::warn_ratelimited!(
+                socket_type = header.socket_type.get(),
+                src_cid = header.src_cid.get(),
+                dst_cid = header.dst_cid.get(),
+                "unsupported vsock packet"
+            );
+            self.reset(&header);
+            return;
+        }
+
+        let Some(data) = data.get(..header.len.get() as usize) else {
+            tracelimit::warn_ratelimited!(len = header.len.get(), "truncated vsock packet");
+            self.reset(&header);
+            return;
+        };
+
+        let key = ConnectionKey {
+            host_port: header.dst_port.get(),
+            guest_port: header.src_port.get(),
+        };
+
+        if op == VIRTIO_VSOCK_OP_REQUEST {
+            self.handle_guest_connect(relay, key, &header);
+            return;
+        }
+
+        if self.connecting.contains_key(&key) {
+            // The host socket is not connected yet, so the only packet the
+            // guest can meaningfully send is a reset.
+            if op == VIRTIO_VSOCK_OP_RST {
+                self.connecting.remove(&key);
+            }
+            return;
+        }
+
+        let Some(connection) = self.connections.get_mut(&key) else {
+            self.reset(&header);
+            return;
+        };
+
+        connection.peer_credit = Credit::from_header(&header);
+        match op {
+            VIRTIO_VSOCK_OP_RESPONSE if connection.state == ConnectionState::Connecting => {
+                // Complete the hybrid vsock handshake.
+                let reply = format!("OK {}\n", key.host_port);
+                connection.reply_len = reply.len();
+                connection.write_buf.extend(reply.as_bytes());
+                connection.state = ConnectionState::Connected;
+            }
+            VIRTIO_VSOCK_OP_RST => {
+                self.connections.remove(&key);
+            }
+            VIRTIO_VSOCK_OP_SHUTDOWN => {
+                connection.peer_shutdown |=
+                    header.flags.get() & (VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND);
+            }
+            VIRTIO_VSOCK_OP_RW
+                if connection.state == ConnectionState::Connected
+                    && connection.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_SEND == 0 =>
+            {
+                // The guest should never exceed the advertised buffer space.
+                if connection.write_buf.len() - connection.reply_len + data.len()
+                    > HOST_BUF_ALLOC as usize
+                {
+                    tracelimit::warn_ratelimited!(?key, "guest exceeded vsock credit");
+                    let packet = connection.packet(VIRTIO_VSOCK_OP_RST, 0, Vec::new());
+                    self.rx_pending.push_back(packet);
+                    self.connections.remove(&key);
+                    return;
+                }
+                connection.write_buf.extend(data);
+            }
+            VIRTIO_VSOCK_OP_CREDIT_UPDATE => {}
+            VIRTIO_VSOCK_OP_CREDIT_REQUEST => {
+                let packet = connection.packet(VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, Vec::new());
+                self.rx_pending.push_back(packet);
+            }
+            op => {
+                tracelimit::warn_ratelimited!(?key, op, "unexpected vsock packet");
+                let packet = connection.packet(VIRTIO_VSOCK_OP_RST, 0, Vec::new());
+                self.rx_pending.push_back(packet);
+                self.connections.remove(&key);
+            }
+        }
+    }
+
+    /// Sends a reset in reply to `header`, unless it is a reset itself.
+    fn reset(&mut self, header: &VsockHeader) {
+        if header.op.get() == VIRTIO_VSOCK_OP_RST {
+            return;
+        }
+        self.rx_pending.push_back(Packet {
+            header: VsockHeader {
+                src_cid: header.dst_cid,
+                dst_cid: header.src_cid,
+                src_port: header.dst_port,
+                dst_port: header.src_port,
+                len: 0.into(),
+                socket_type: header.socket_type,
+                op: VIRTIO_VSOCK_OP_RST.into(),
+                flags: 0.into(),
+                buf_alloc: 0.into(),
+                fwd_cnt: 0.into(),
+            },
+            data: Vec::new(),
+        });
+    }
+
+    fn handle_guest_connect(
+        &mut self,
+        relay: &VsockRelay,
+        key: ConnectionKey,
+        header: &VsockHeader,
+    ) {
+        if self.connections.contains_key(&key)
+            || self.connecting.contains_key(&key)
+            || self.connection_count() >= MAX_CONNECTIONS
+        {
+            self.reset(header);
+            return;
+        }
+
+        let mut path = relay.base_path.clone().into_os_string();
+        path.push(format!("_{}", key.host_port));
+        let driver = relay.driver.clone();
+        self.connecting.insert(key, Credit::from_header(header));
+        self.connects.push(Box::pin(async move {
+            (key, PolledSocket::connect_unix(&driver, path).await)
+        }));
+    }
+
+    fn handle_connect_complete(
+        &mut self,
+        relay: &VsockRelay,
+        key: ConnectionKey,
+        result: io::Result<PolledSocket<UnixStream>>,
+    ) {
+        let Some(credit) = self.connecting.remove(&key) else {
+            // The guest gave up on the connection.
+            return;
+        };
+        match result {
+            Ok(socket) => {
+                tracing::debug!(?key, "connected guest to host");
+                let mut connection =
+                    Connection::new(relay, key, socket, ConnectionState::Connected, credit);
+                self.rx_pending.push_back(connection.packet(
+                    VIRTIO_VSOCK_OP_RESPONSE,
+                    0,
+                    Vec::new(),
+                ));
+                self.connections.insert(key, connection);
+            }
+            Err(err) => {
+                tracelimit::warn_ratelimited!(
+                    error = &err as &dyn std::error::Error,
+                    port = key.host_port,
+                    "failed to connect to hybrid vsock listener"
+                );
+                self.rx_pending.push_back(Packet {
+                    header: VsockHeader {
+                        src_cid: VSOCK_HOST_CID.into(),
+                        dst_cid: relay.guest_cid.into(),
+                        src_port: key.host_port.into(),
+                        dst_port: key.guest_port.into(),
+                        len: 0.into(),
+                        socket_type: VIRTIO_VSOCK_TYPE_STREAM.into(),
+                        op: VIRTIO_VSOCK_OP_RST.into(),
+                        flags: 0.into(),
+                        buf_alloc: 0.into(),
+                        fwd_cnt: 0.into(),
+                    },
+                    data: Vec::new(),
+                });
+            }
+        }
+    }
+
+    fn handle_host_connect(&mut self, relay: &VsockRelay, connection: HostConnection) {
+        if self.connection_count() >= MAX_CONNECTIONS {
+            tracelimit::warn_ratelimited!(
+                port = connection.port,
+                "too many vsock connections, dropping host connection"
+            );
+            return;
+        }
+        let key = ConnectionKey {
+            host_port: self.allocate_host_port(),
+            guest_port: connection.port,
+        };
+        tracing::debug!(?key, "connecting host to guest");
+        let mut connection = Connection::new(
+            relay,
+            key,
+            connection.socket,
+            ConnectionState::Connecting,
+            Credit::default(),
+        );
+        self.rx_pending
+            .push_back(connection.packet(VIRTIO_VSOCK_OP_REQUEST, 0, Vec::new()));
+        self.connections.insert(key, connection);
+    }
+
+    fn allocate_host_port(&mut self) -> u32 {
+        loop {
+            let port = self.next_host_port;
+            self.next_host_port = port.checked_add(1).unwrap_or(FIRST_HOST_PORT);
+            if !self
+                .connections
+                .keys()
+                .chain(self.connecting.keys())
+                .any(|key| key.host_port == port)
+            {
+                break port;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Device;
+    use futures::AsyncReadExt;
+    use futures::AsyncWriteExt;
+    use pal_async::DefaultDriver;
+    use pal_async::async_test;
+    use pal_async::timer::PolledTimer;
+    use std::time::Duration;
+    use unix_socket::UnixListener;
+    use virtio::Resources;
+    use virtio::VirtioDevice;
+    use virtio::test_helpers::TestBuffer;
+    use virtio::test_helpers::TestQueue;
+    use vmcore::interrupt::Interrupt;
+    use vmcore::vm_task::SingleDriverBackend;
+    use vmcore::vm_task::VmTaskDriverSource;
+
+    const GUEST_CID: u64 = 3;
+    const TX_BUF: u64 = 0x20000;
+    const RX_BUF: u64 = 0x30000;
+    const RX_BUF_SIZE: u64 = 0x100;
+    const RX_QUEUE_SIZE: u16 = 256;
+
+    struct TestGuest {
+        _device: Device,
+        mem: GuestMemory,
+        rx: TestQueue,
+        tx: TestQueue,
+        rx_posted: VecDeque<u64>,
+        rx_count: u64,
+    }
+
+    impl TestGuest {
+        fn new(driver: &DefaultDriver, base_path: PathBuf) -> Self {
+            let mem = GuestMemory::allocate(0x100000);
+            let mut device = Device::new(
+                &VmTaskDriverSource::new(SingleDriverBackend::new(driver.clone())),
+                mem.clone(),
+                GUEST_CID,
+                base_path,
+                None,
+            )
+            .unwrap();
+            let rx = TestQueue::new(driver, &mem, 0, RX_QUEUE_SIZE);
+            let tx = TestQueue::new(driver, &mem, 0x10000, 512);
+            device.enable(Resources {
+                features: 0,
+                queues: vec![rx.resources(), tx.resources()],
+                shared_memory_region: None,
+                shared_memory_size: 0,
+                config_change: Interrupt::null(),
+            });
+            Self {
+                _device: device,
+                mem,
+                rx,
+                tx,
+                rx_posted: VecDeque::new(),
+                rx_count: 0,
+            }
+        }
+
+        /// Sends a packet from the guest without waiting for the device to
+        /// process it.
+        fn send(&mut self, header: VsockHeader, data: &[u8]) {
+            self.mem.write_at(TX_BUF, header.as_bytes()).unwrap();
+            self.mem
+                .write_at(TX_BUF + size_of::<VsockHeader>() as u64, data)
+                .unwrap();
+            self.tx.add(&[TestBuffer::readable(
+                TX_BUF,
+                (size_of::<VsockHeader>() + data.len()) as u32,
+            )]);
+        }
+
+        /// Sends a packet from the guest and waits for the device to process
+        /// it.
+        async fn send_sync(&mut self, header: VsockHeader, data: &[u8]) {
+            self.send(header, data);
+            self.tx.used().await;
+        }
+
+        fn post_rx(&mut self, count: usize) {
+            for _ in 0..count {
+                let address = RX_BUF + (self.rx_count % RX_QUEUE_SIZE as u64) * RX_BUF_SIZE;
+                self.rx_count += 1;
+                self.rx
+                    .add(&[TestBuffer::writeable(address, RX_BUF_SIZE as u32)]);
+                self.rx_posted.push_back(address);
+            }
+        }
+
+        async fn recv(&mut self) -> (VsockHeader, Vec<u8>) {
+            let (_, len) = self.rx.used().await;
+            let address = self.rx_posted.pop_front().unwrap();
+            let mut buf = vec![0; len as usize];
+            self.mem.read_at(address, &mut buf).unwrap();
+            let (header, data) = VsockHeader::read_from_prefix(&buf).unwrap();
+            assert_eq!(header.len.get() as usize, data.len());
+            (header, data.to_vec())
+        }
+    }
+
+    fn guest_header(guest_port: u32, host_port: u32, op: u16, len: usize) -> VsockHeader {
+        VsockHeader {
+            src_cid: GUEST_CID.into(),
+            dst_cid: VSOCK_HOST_CID.into(),
+            src_port: guest_port.into(),
+            dst_port: host_port.into(),
+            len: (len as u32).into(),
+            socket_type: VIRTIO_VSOCK_TYPE_STREAM.into(),
+            op: op.into(),
+            flags: 0.into(),
+            buf_alloc: 0x10000.into(),
+            fwd_cnt: 0.into(),
+        }
+    }
+
+    #[async_test]
+    async fn test_guest_connect(driver: DefaultDriver) {
+        let dir = tempfile::tempdir().unwrap();
+        let base_path = dir.path().join("vsock");
+        let listener = UnixListener::bind(dir.path().join("vsock_1234")).unwrap();
+        let mut listener = PolledSocket::new(&driver, listener).unwrap();
+        let mut guest = TestGuest::new(&driver, base_path);
+        guest.post_rx(16);
+
+        guest
+            .send_sync(guest_header(5000, 1234, VIRTIO_VSOCK_OP_REQUEST, 0), &[])
+            .await;
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut socket = PolledSocket::new(&driver, socket).unwrap();
+        let (header, _) = guest.recv().await;
+        assert_eq!(header.op.get(), VIRTIO_VSOCK_OP_RESPONSE);
+        assert_eq!(header.src_port.get(), 1234);
+        assert_eq!(header.dst_port.get(), 5000);
+        assert_eq!(header.dst_cid.get(), GUEST_CID);
+
+        // Guest to host.
+        guest
+            .send_sync(guest_header(5000, 1234, VIRTIO_VSOCK_OP_RW, 5), b"hello")
+            .await;
+        let mut buf = [0; 5];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // Host to guest.
+        socket.write_all(b"world").await.unwrap();
+        let (header, data) = guest.recv().await;
+        assert_eq!(header.op.get(), VIRTIO_VSOCK_OP_RW);
+        assert_eq!(data, b"world");
+
+        // The guest resets the connection, closing the host socket.
+        guest
+            .send_sync(guest_header(5000, 1234, VIRTIO_VSOCK_OP_RST, 0), &[])
+            .await;
+        assert_eq!(socket.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[async_test]
+    async fn test_unknown_connection_reset(driver: DefaultDriver) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut guest = TestGuest::new(&driver, dir.path().join("vsock"));
+        guest.post_rx(1);
+        guest
+            .send_sync(guest_header(5000, 1234, VIRTIO_VSOCK_OP_RW, 0), &[])
+            .await;
+        let (header, _) = guest.recv().await;
+        assert_eq!(header.op.get(), VIRTIO_VSOCK_OP_RST);
+        assert_eq!(header.src_port.get(), 1234);
+        assert_eq!(header.dst_port.get(), 5000);
+
+        // A connection request to a port with no listener is reset.
+        guest.post_rx(1);
+        guest
+            .send_sync(guest_header(5001, 1235, VIRTIO_VSOCK_OP_REQUEST, 0), &[])
+            .await;
+        let (header, _) = guest.recv().await;
+        assert_eq!(header.op.get(), VIRTIO_VSOCK_OP_RST);
+        assert_eq!(header.dst_port.get(), 5001);
+    }
+
+    #[async_test]
+    async fn test_replies_bounded(driver: DefaultDriver) {
+        const COUNT: usize = MAX_RX_PENDING + 44;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut guest = TestGuest::new(&driver, dir.path().join("vsock"));
+
+        // Each of these packets generates a reset, but the guest has not
+        // posted any receive buffers.
+        for _ in 0..COUNT {
+            guest.send(guest_header(5000, 1234, VIRTIO_VSOCK_OP_RW, 0), &[]);
+        }
+        for _ in 0..MAX_RX_PENDING {
+            guest.tx.used().await;
+        }
+        PolledTimer::new(&driver)
+            .sleep(Duration::from_millis(50))
+            .await;
+        assert!(guest.tx.try_used().is_none());
+
+        // Once the guest takes the replies, the rest of the packets are
+        // processed.
+        guest.post_rx(RX_QUEUE_SIZE.into());
+        for _ in 0..COUNT {
+            let (header, _) = guest.recv().await;
+            assert_eq!(header.op.get(), VIRTIO_VSOCK_OP_RST);
+            guest.post_rx(1);
+        }
+        for _ in MAX_RX_PENDING..COUNT {
+            guest.tx.used().await;
+        }
+    }
+}
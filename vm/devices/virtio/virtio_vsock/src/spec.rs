@@ -0,0 +1,53 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Constants and structures defined by the virtio vsock device spec.
+
+use virtio::spec::u16_le;
+use virtio::spec::u32_le;
+use virtio::spec::u64_le;
+use zerocopy::FromBytes;
+use zerocopy::Immutable;
+use zerocopy::IntoBytes;
+use zerocopy::KnownLayout;
+
+pub const VIRTIO_DEVICE_TYPE_VSOCK: u16 = 19;
+
+/// The well-known CID of the host.
+pub const VSOCK_HOST_CID: u64 = 2;
+
+pub const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+// Packet operations.
+pub const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+pub const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+pub const VIRTIO_VSOCK_OP_RST: u16 = 3;
+pub const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+pub const VIRTIO_VSOCK_OP_RW: u16 = 5;
+pub const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+pub const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+// Shutdown flags.
+pub const VIRTIO_VSOCK_SHUTDOWN_RCV: u32 = 1;
+pub const VIRTIO_VSOCK_SHUTDOWN_SEND: u32 = 2;
+
+#[repr(C)]
+#[derive(Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct VsockConfig {
+    pub guest_cid: u64_le,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct VsockHeader {
+    pub src_cid: u64_le,
+    pub dst_cid: u64_le,
+    pub src_port: u32_le,
+    pub dst_port: u32_le,
+    pub len: u32_le,
+    pub socket_type: u16_le,
+    pub op: u16_le,
+    pub flags: u32_le,
+    pub buf_alloc: u32_le,
+    pub fwd_cnt: u32_le,
+}
@@ -8,6 +8,7 @@
 use async_trait::async_trait;
 use guestmem::GuestMemory;
 use guestmem::MappedMemoryRegion;
+use inspect::InspectMut;
 use pal_async::task::Spawn;
 use std::io;
 use std::io::Write;
@@ -93,6 +94,14 @@ pub fn new<Fs>(
     }
 }
 
+impl InspectMut for VirtioFsDevice {
+    fn inspect_mut(&mut self, req: inspect::Request<'_>) {
+        req.respond()
+            .field("name", &*self.name)
+            .hex("shmem_size", self.shmem_size);
+    }
+}
+
 impl VirtioDevice for VirtioFsDevice {
     fn traits(&self) -> DeviceTraits {
         DeviceTraits {
@@ -36,6 +36,7 @@ mod ioctl {
     #[cfg(target_arch = "x86_64")]
     ioctl_readwrite!(kvm_get_supported_cpuid, KVMIO, 0x05, kvm_cpuid2);
     ioctl_write_int_bad!(kvm_create_vcpu, request_code_none!(KVMIO, 0x41));
+    ioctl_write_ptr!(kvm_get_dirty_log, KVMIO, 0x42, kvm_dirty_log);
     ioctl_write_ptr!(
         kvm_set_user_memory_region,
         KVMIO,
@@ -109,6 +110,8 @@ pub enum Error {
     SignalMsi(#[source] nix::Error),
     #[error("SetMemoryRegion")]
     SetMemoryRegion(#[source] nix::Error),
+    #[error("GetDirtyLog")]
+    GetDirtyLog(#[source] nix::Error),
     #[error("CreateVm")]
     CreateVm(#[source] nix::Error),
     #[error("EnableCap({0})")]
@@ -476,10 +479,18 @@ pub unsafe fn set_user_memory_region(
         size: usize,
         addr: u64,
         readonly: bool,
+        log_dirty_pages: bool,
     ) -> Result<()> {
+        let mut flags = 0;
+        if readonly {
+            flags |= KVM_MEM_READONLY;
+        }
+        if log_dirty_pages {
+            flags |= KVM_MEM_LOG_DIRTY_PAGES;
+        }
         let region = kvm_userspace_memory_region {
             slot,
-            flags: if readonly { KVM_MEM_READONLY } else { 0 },
+            flags,
             guest_phys_addr: addr,
             memory_size: size as u64,
             userspace_addr: data as usize as u64,
@@ -491,6 +502,30 @@ pub unsafe fn set_user_memory_region(
         Ok(())
     }
 
+    /// Retrieves and clears the dirty page log for memory slot `slot`, which
+    /// must have been registered with `log_dirty_pages` set.
+    ///
+    /// `bitmap` gets one bit per page in the slot.
+    ///
+    /// # Safety
+    ///
+    /// `bitmap` must be large enough to hold the bits for the whole slot.
+    pub unsafe fn get_dirty_log(&self, slot: u32, bitmap: &mut [u64]) -> Result<()> {
+        let log = kvm_dirty_log {
+            slot,
+            padding1: 0,
+            __bindgen_anon_1: kvm_dirty_log__bindgen_ty_1 {
+                dirty_bitmap: bitmap.as_mut_ptr().cast(),
+            },
+        };
+        // SAFETY: `bitmap` is a valid buffer for writes, and the caller
+        // guarantees that it is large enough for the slot.
+        unsafe {
+            ioctl::kvm_get_dirty_log(self.vm.as_raw_fd(), &log).map_err(Error::GetDirtyLog)?;
+        }
+        Ok(())
+    }
+
     pub fn set_gsi_routes(&self, routes: &[(u32, RoutingEntry)]) -> Result<()> {
         const MAX_ROUTES: usize = 2048;
         assert!(routes.len() <= MAX_ROUTES);
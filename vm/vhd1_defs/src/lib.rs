@@ -2,8 +2,6 @@
 // Licensed under the MIT License.
 
 //! VHD1 file format definitions.
-//!
-//! Currently incomplete (missing defs for non-fixed disks).
 
 #![expect(missing_docs)]
 #![forbid(unsafe_code)]
@@ -54,6 +52,8 @@ impl VhdFooter {
     pub const FIXED_DATA_OFFSET: u64 = !0;
     pub const CREATOR_VERSION_MAGIC: u32 = 0x000a0000;
     pub const DISK_TYPE_FIXED: u32 = 2;
+    pub const DISK_TYPE_DYNAMIC: u32 = 3;
+    pub const DISK_TYPE_DIFFERENCING: u32 = 4;
 
     pub fn new_fixed(size: u64, guid: Guid) -> Self {
         let mut footer = Self {
@@ -73,6 +73,72 @@ pub fn new_fixed(size: u64, guid: Guid) -> Self {
         footer
     }
 
+    /// Returns a footer for a dynamic or differencing disk whose dynamic
+    /// header is at `header_offset`.
+    pub fn new_dynamic(disk_type: u32, size: u64, header_offset: u64, guid: Guid) -> Self {
+        let mut footer = Self {
+            data_offset: header_offset.into(),
+            disk_type: disk_type.into(),
+            ..Self::new_fixed(size, guid)
+        };
+        footer.checksum = footer.compute_checksum().into();
+        footer
+    }
+
+    pub fn compute_checksum(&self) -> u32 {
+        !(self.as_bytes().iter().map(|b| *b as u32).sum::<u32>()
+            - self
+                .checksum
+                .as_bytes()
+                .iter()
+                .map(|b| *b as u32)
+                .sum::<u32>())
+    }
+}
+
+/// The header following the footer copy at the start of dynamic and
+/// differencing disks.
+#[repr(C)]
+#[derive(Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct VhdDynamicHeader {
+    pub cookie: u64_be,
+    pub data_offset: u64_be,
+    pub table_offset: u64_be,
+    pub header_version: u32_be,
+    pub max_table_entries: u32_be,
+    pub block_size: u32_be,
+    pub checksum: u32_be,
+    pub parent_unique_id: Guid,
+    pub parent_time_stamp: u32_be,
+    pub reserved: u32_be,
+    /// The parent's file name, in UTF-16BE.
+    pub parent_unicode_name: [u8; 512],
+    pub parent_locators: [ParentLocator; 8],
+    pub reserved2: [u8; 256],
+}
+
+impl VhdDynamicHeader {
+    pub const LEN: u64 = 1024;
+
+    pub const COOKIE_MAGIC: u64_be = u64_be::from_bytes(*b"cxsparse");
+    pub const DATA_OFFSET: u64 = !0;
+    pub const HEADER_VERSION_MAGIC: u32 = 0x00010000;
+    pub const DEFAULT_BLOCK_SIZE: u32 = 0x200000;
+
+    pub fn new(table_offset: u64, max_table_entries: u32, block_size: u32) -> Self {
+        let mut header = Self {
+            cookie: Self::COOKIE_MAGIC,
+            data_offset: Self::DATA_OFFSET.into(),
+            table_offset: table_offset.into(),
+            header_version: Self::HEADER_VERSION_MAGIC.into(),
+            max_table_entries: max_table_entries.into(),
+            block_size: block_size.into(),
+            ..FromZeros::new_zeroed()
+        };
+        header.checksum = header.compute_checksum().into();
+        header
+    }
+
     pub fn compute_checksum(&self) -> u32 {
         !(self.as_bytes().iter().map(|b| *b as u32).sum::<u32>()
             - self
@@ -83,3 +149,26 @@ pub fn compute_checksum(&self) -> u32 {
                 .sum::<u32>())
     }
 }
+
+/// A parent locator entry in a differencing disk's dynamic header.
+#[repr(C)]
+#[derive(Copy, Clone, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct ParentLocator {
+    pub platform_code: u32_be,
+    pub platform_data_space: u32_be,
+    pub platform_data_length: u32_be,
+    pub reserved: u32_be,
+    pub platform_data_offset: u64_be,
+}
+
+impl ParentLocator {
+    pub const PLATFORM_CODE_NONE: u32 = 0;
+    /// A relative Windows path, in UTF-16LE.
+    pub const PLATFORM_CODE_W2RU: u32 = u32::from_be_bytes(*b"W2ru");
+    /// An absolute Windows path, in UTF-16LE.
+    pub const PLATFORM_CODE_W2KU: u32 = u32::from_be_bytes(*b"W2ku");
+}
+
+/// The block allocation table entry for a block that is not present in the
+/// file.
+pub const BAT_ENTRY_UNUSED: u32 = !0;
@@ -0,0 +1,162 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Tracking of writes made through [`GuestMemory`](crate::GuestMemory).
+
+use crate::PAGE_SIZE64;
+use memory_range::MemoryRange;
+use std::sync::RwLock;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+/// A dirty bitmap covering a set of guest physical address ranges.
+#[derive(Debug, Default)]
+pub(crate) struct DirtyLog {
+    /// Checked before taking the lock, so that writes are cheap when tracking
+    /// is disabled.
+    enabled: AtomicBool,
+    ranges: RwLock<Vec<DirtyRange>>,
+    /// The number of pages that went from clean to dirty.
+    pages_dirtied: AtomicU64,
+    /// The number of dirty pages that have been retrieved.
+    pages_harvested: AtomicU64,
+}
+
+#[derive(Debug)]
+struct DirtyRange {
+    range: MemoryRange,
+    bits: Box<[AtomicU64]>,
+}
+
+impl DirtyRange {
+    /// Returns the page number range covered by this range that overlaps
+    /// `start_page..end_page`.
+    fn overlap(&self, start_page: u64, end_page: u64) -> std::ops::Range<u64> {
+        let start = start_page.max(self.range.start() / PAGE_SIZE64);
+        let end = end_page.min(self.range.end() / PAGE_SIZE64);
+        start..end.max(start)
+    }
+}
+
+impl DirtyLog {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn enable(&self, ranges: &[MemoryRange]) {
+        let mut dirty_ranges = self.ranges.write().unwrap();
+        *dirty_ranges = ranges
+            .iter()
+            .map(|&range| DirtyRange {
+                range,
+                bits: (0..range.page_count_4k().div_ceil(64))
+                    .map(|_| AtomicU64::new(0))
+                    .collect(),
+            })
+            .collect();
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn disable(&self) {
+        let mut dirty_ranges = self.ranges.write().unwrap();
+        self.enabled.store(false, Ordering::Relaxed);
+        dirty_ranges.clear();
+    }
+
+    /// Marks the pages in `gpa..gpa + len` dirty.
+    ///
+    /// This must be called after the write, so that a concurrent call to
+    /// [`Self::take`] cannot clear the bit before the data is written.
+    pub fn mark(&self, gpa: u64, len: u64) {
+        if len == 0 {
+            return;
+        }
+        let start_page = gpa / PAGE_SIZE64;
+        let end_page = gpa.saturating_add(len - 1) / PAGE_SIZE64 + 1;
+        self.mark_pages(start_page, end_page);
+    }
+
+    /// Marks the pages `start_page..end_page` dirty.
+    fn mark_pages(&self, start_page: u64, end_page: u64) {
+        let ranges = self.ranges.read().unwrap();
+        let mut newly_dirtied = 0;
+        for range in ranges.iter() {
+            let base = range.range.start() / PAGE_SIZE64;
+            for page in range.overlap(start_page, end_page) {
+                let index = page - base;
+                let bit = 1 << (index % 64);
+                let old = range.bits[(index / 64) as usize].fetch_or(bit, Ordering::Release);
+                if old & bit == 0 {
+                    newly_dirtied += 1;
+                }
+            }
+        }
+        if newly_dirtied != 0 {
+            self.pages_dirtied
+                .fetch_add(newly_dirtied, Ordering::Relaxed);
+        }
+    }
+
+    /// Sets the bits in `bitmap` for the dirty pages in `range` and clears
+    /// them from the log.
+    pub fn take(&self, range: MemoryRange, bitmap: &mut [u64]) {
+        assert!(
+            bitmap.len() as u64 * 64 >= range.page_count_4k(),
+            "bitmap too small"
+        );
+        let start_page = range.start() / PAGE_SIZE64;
+        let end_page = range.end() / PAGE_SIZE64;
+        let ranges = self.ranges.read().unwrap();
+        let mut harvested = 0;
+        for dirty_range in ranges.iter() {
+            let base = dirty_range.range.start() / PAGE_SIZE64;
+            let pages = dirty_range.overlap(start_page, end_page);
+            let mut page = pages.start;
+            // Process a word of the log at a time.
+            while page < pages.end {
+                let index = page - base;
+                let shift = index % 64;
+                let count = (64 - shift).min(pages.end - page);
+                let mask = if count == 64 {
+                    !0
+                } else {
+                    ((1 << count) - 1) << shift
+                };
+                let word = &dirty_range.bits[(index / 64) as usize];
+                let value = word.fetch_and(!mask, Ordering::Acquire) & mask;
+                if value != 0 {
+                    harvested += value.count_ones() as u64;
+                    or_bits(bitmap, page - start_page, value >> shift);
+                }
+                page += count;
+            }
+        }
+        self.pages_harvested.fetch_add(harvested, Ordering::Relaxed);
+    }
+}
+
+/// Sets the bits of `value` in `bitmap`, starting at bit `bit`.
+fn or_bits(bitmap: &mut [u64], bit: u64, value: u64) {
+    let index = (bit / 64) as usize;
+    let shift = bit % 64;
+    bitmap[index] |= value << shift;
+    if shift != 0 {
+        let high = value >> (64 - shift);
+        if high != 0 {
+            bitmap[index + 1] |= high;
+        }
+    }
+}
+
+impl inspect::Inspect for DirtyLog {
+    fn inspect(&self, req: inspect::Request<'_>) {
+        req.respond()
+            .field("enabled", self.is_enabled())
+            .counter("pages_dirtied", self.pages_dirtied.load(Ordering::Relaxed))
+            .counter(
+                "pages_harvested",
+                self.pages_harvested.load(Ordering::Relaxed),
+            );
+    }
+}
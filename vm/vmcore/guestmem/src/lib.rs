@@ -7,10 +7,13 @@
 #![expect(unsafe_code)]
 #![expect(missing_docs)]
 
+mod dirty;
 pub mod ranges;
 
+use self::dirty::DirtyLog;
 use self::ranges::PagedRange;
 use inspect::Inspect;
+use memory_range::MemoryRange;
 use pal_event::Event;
 use sparse_mmap::AsMappableRef;
 use std::any::Any;
@@ -70,6 +73,7 @@ enum GuestMemoryOperation {
     Lock,
     Subrange,
     Probe,
+    Decommit,
 }
 
 impl std::fmt::Display for GuestMemoryOperation {
@@ -82,6 +86,7 @@ fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             GuestMemoryOperation::Lock => "lock",
             GuestMemoryOperation::Subrange => "subrange",
             GuestMemoryOperation::Probe => "probe",
+            GuestMemoryOperation::Decommit => "decommit",
         })
     }
 }
@@ -398,7 +403,8 @@ fn access_bitmap(&self) -> Option<BitmapInfo> {
     }
 
     // Returns an accessor for a subrange, or `None` to use the default
-    // implementation.
+    // implementation. The accessor must be newly created, since it is updated
+    // to share the dirty log of this object.
     fn subrange(
         &self,
         offset: u64,
@@ -510,6 +516,16 @@ fn expose_va(&self, address: u64, len: u64) -> Result<(), GuestMemoryBackingErro
         Ok(())
     }
 
+    /// Hints that the guest no longer needs the contents of the given range,
+    /// allowing the backing to release the host memory behind it.
+    ///
+    /// The contents of the range are undefined afterwards. By default, this
+    /// does nothing.
+    fn decommit(&self, address: u64, len: u64) -> Result<(), GuestMemoryBackingError> {
+        let _ = (address, len);
+        Ok(())
+    }
+
     /// Returns the base IO virtual address for the mapping.
     ///
     /// This is the base address that should be used for DMA from a user-mode
@@ -583,6 +599,8 @@ fn compare_exchange_fallback(
 
     fn expose_va(&self, address: u64, len: u64) -> Result<(), GuestMemoryBackingError>;
 
+    fn decommit(&self, address: u64, len: u64) -> Result<(), GuestMemoryBackingError>;
+
     fn lock_gpns(&self, gpns: &[u64]) -> Result<bool, GuestMemoryBackingError>;
 
     fn unlock_gpns(&self, gpns: &[u64]);
@@ -645,6 +663,10 @@ fn expose_va(&self, address: u64, len: u64) -> Result<(), GuestMemoryBackingErro
         self.expose_va(address, len)
     }
 
+    fn decommit(&self, address: u64, len: u64) -> Result<(), GuestMemoryBackingError> {
+        self.decommit(address, len)
+    }
+
     fn lock_gpns(&self, gpns: &[u64]) -> Result<bool, GuestMemoryBackingError> {
         self.lock_gpns(gpns)
     }
@@ -751,6 +773,10 @@ fn expose_va(&self, address: u64, len: u64) -> Result<(), GuestMemoryBackingErro
         self.as_ref().expose_va(address, len)
     }
 
+    fn decommit(&self, address: u64, len: u64) -> Result<(), GuestMemoryBackingError> {
+        self.as_ref().decommit(address, len)
+    }
+
     fn base_iova(&self) -> Option<u64> {
         self.as_ref().base_iova()
     }
@@ -765,6 +791,11 @@ fn mapping(&self) -> Option<NonNull<u8>> {
     fn max_address(&self) -> u64 {
         self.len() as u64
     }
+
+    fn decommit(&self, address: u64, len: u64) -> Result<(), GuestMemoryBackingError> {
+        self.decommit(address as usize, len as usize)
+            .map_err(|err| GuestMemoryBackingError::other(address, err))
+    }
 }
 
 /// Default guest memory range type, enforcing access boundaries.
@@ -910,6 +941,11 @@ fn expose_va(&self, address: u64, len: u64) -> Result<(), GuestMemoryBackingErro
         self.base.imp.expose_va(address, len)
     }
 
+    fn decommit(&self, address: u64, len: u64) -> Result<(), GuestMemoryBackingError> {
+        let address = self.adjust_range(address, len)?;
+        self.base.imp.decommit(address, len)
+    }
+
     fn base_iova(&self) -> Option<u64> {
         let region = &self.base.regions[self.region];
         Some(region.base_iova? + (self.offset & self.base.region_def.region_mask))
@@ -1007,6 +1043,11 @@ fn expose_va(&self, address: u64, len: u64) -> Result<(), GuestMemoryBackingErro
         region.expose_va(offset_in_region, len)
     }
 
+    fn decommit(&self, address: u64, len: u64) -> Result<(), GuestMemoryBackingError> {
+        let (region, offset_in_region) = self.region(address, len)?;
+        region.decommit(offset_in_region, len)
+    }
+
     fn page_fault(
         &self,
         address: u64,
@@ -1058,6 +1099,10 @@ struct GuestMemoryInner<T: ?Sized = dyn DynGuestMemoryAccess> {
     regions: Vec<MemoryRegion>,
     debug_name: Arc<str>,
     allocated: bool,
+    /// Shared with subranges of this object.
+    dirty_log: Arc<DirtyLog>,
+    /// The offset of this object's addresses in `dirty_log`, for subranges.
+    dirty_log_offset: u64,
     imp: T,
 }
 
@@ -1253,6 +1298,8 @@ fn new_inner(debug_name: Arc<str>, imp: impl GuestMemoryAccess, allocated: bool)
                 },
                 regions,
                 allocated,
+                dirty_log: Default::default(),
+                dirty_log_offset: 0,
             }),
         }
     }
@@ -1331,6 +1378,8 @@ pub fn new_multi_region(
             regions,
             imp,
             allocated: false,
+            dirty_log: Default::default(),
+            dirty_log_offset: 0,
         };
 
         Ok(Self {
@@ -1430,15 +1479,21 @@ pub fn subrange(
         allow_preemptive_locking: bool,
     ) -> Result<GuestMemory, GuestMemoryError> {
         self.with_op(Some((offset, len)), GuestMemoryOperation::Subrange, || {
-            if let Some(guest_memory) =
+            let mut guest_memory = if let Some(guest_memory) =
                 self.inner
                     .imp
                     .subrange(offset, len, allow_preemptive_locking)?
             {
-                Ok(guest_memory)
+                guest_memory
             } else {
-                create_memory_subrange(self.inner.clone(), offset, len, allow_preemptive_locking)
-            }
+                create_memory_subrange(self.inner.clone(), offset, len, allow_preemptive_locking)?
+            };
+            // Track writes through the subrange in this object's dirty log.
+            let inner = Arc::get_mut(&mut guest_memory.inner)
+                .expect("subrange guest memory should be newly created");
+            inner.dirty_log = self.inner.dirty_log.clone();
+            inner.dirty_log_offset = self.inner.dirty_log_offset + offset;
+            Ok(guest_memory)
         })
     }
 
@@ -1569,9 +1624,15 @@ fn run_on_mapping<T, P>(
         // critical section. This will allow callers to flush concurrent
         // accesses after bitmap updates.
         #[cfg(feature = "bitmap")]
-        return rcu().run(op);
+        let r = rcu().run(op);
         #[cfg(not(feature = "bitmap"))]
-        op()
+        let r = op();
+        // Mark the pages dirty even on failure, since part of the write may
+        // have succeeded.
+        if access_type == AccessType::Write {
+            self.inner.mark_dirty(gpa, len as u64);
+        }
+        r
     }
 
     /// # Safety
@@ -1880,15 +1941,64 @@ pub fn lock_gpns(
                 let page = self.probe_page_for_lock(with_kernel_access, gpa)?;
                 pages.push(PagePtr(page));
             }
-            let store_gpns = self.inner.imp.lock_gpns(gpns)?;
+            let unlock_gpns = self.inner.imp.lock_gpns(gpns)?;
+            self.inner.mark_gpns_dirty(gpns);
             Ok(LockedPages {
                 pages: pages.into_boxed_slice(),
-                gpns: store_gpns.then(|| gpns.to_vec().into_boxed_slice()),
+                gpns: gpns.into(),
+                unlock_gpns,
                 mem: self.inner.clone(),
             })
         })
     }
 
+    /// Hints that the guest no longer needs the contents of `len` bytes at
+    /// `gpa`, allowing the backing to release the host memory behind them.
+    ///
+    /// The contents of the range are undefined afterwards, so this is only
+    /// appropriate for memory the guest has given up, such as pages handed to
+    /// a balloon device.
+    pub fn decommit(&self, gpa: u64, len: u64) -> Result<(), GuestMemoryError> {
+        self.with_op(Some((gpa, len)), GuestMemoryOperation::Decommit, || {
+            self.inner.imp.decommit(gpa, len)
+        })
+    }
+
+    /// Starts tracking writes made through this object and its clones to the
+    /// pages in `ranges`, replacing any previous tracking state. All pages
+    /// start out clean.
+    ///
+    /// This only tracks writes made by the host, such as device DMA. Writes
+    /// made by the guest's processors must be tracked by the hypervisor.
+    /// Objects returned by [`GuestMemory::subrange`] share the tracking state
+    /// of the object they were created from, so their writes are tracked at
+    /// the corresponding addresses of that object.
+    pub fn enable_dirty_tracking(&self, ranges: &[MemoryRange]) {
+        self.inner.dirty_log.enable(ranges);
+    }
+
+    /// Stops tracking writes.
+    pub fn disable_dirty_tracking(&self) {
+        self.inner.dirty_log.disable();
+    }
+
+    /// Sets the bits in `bitmap` for the pages in `range` that have been
+    /// written since they were last retrieved, and marks the pages clean.
+    /// Other bits in `bitmap` are left unchanged.
+    ///
+    /// Bit `n` of `bitmap` corresponds to the `n`th page of `range`, which
+    /// must be page aligned.
+    ///
+    /// Panics if `bitmap` does not have at least one bit per page in `range`.
+    pub fn take_dirty_bitmap(&self, range: MemoryRange, bitmap: &mut [u64]) {
+        self.inner.dirty_log.take(range, bitmap);
+    }
+
+    /// Returns an object for inspecting the dirty tracking state and counters.
+    pub fn inspect_dirty_tracking(&self) -> impl '_ + Inspect {
+        &self.inner.dirty_log
+    }
+
     pub fn probe_gpns(&self, gpns: &[u64]) -> Result<(), GuestMemoryError> {
         self.with_op(None, GuestMemoryOperation::Probe, || {
             for &gpn in gpns {
@@ -2061,10 +2171,12 @@ pub fn lock_range<T: LockedRange>(
                     self.dangerous_access_pre_locked_memory(range.start, range.len() as usize),
                 );
             }
-            let store_gpns = self.inner.imp.lock_gpns(paged_range.gpns())?;
+            let unlock_gpns = self.inner.imp.lock_gpns(paged_range.gpns())?;
+            self.inner.mark_gpns_dirty(paged_range.gpns());
             Ok(LockedRangeImpl {
                 mem: self.inner.clone(),
-                gpns: store_gpns.then(|| paged_range.gpns().to_vec().into_boxed_slice()),
+                gpns: paged_range.gpns().into(),
+                unlock_gpns,
                 inner: locked_range,
             })
         })
@@ -2125,21 +2237,51 @@ fn region(
         }
         Ok((&self.regions[index], offset, index))
     }
+
+    /// Releases pages locked by [`GuestMemory::lock_gpns`] or
+    /// [`GuestMemory::lock_range`].
+    fn release_locked_gpns(&self, gpns: &[u64], unlock: bool) {
+        if unlock {
+            self.imp.unlock_gpns(gpns);
+        }
+        self.mark_gpns_dirty(gpns);
+    }
+
+    /// Marks `gpa..gpa + len` dirty if dirty tracking is enabled.
+    fn mark_dirty(&self, gpa: u64, len: u64) {
+        if self.dirty_log.is_enabled() {
+            self.dirty_log.mark(self.dirty_log_offset + gpa, len);
+        }
+    }
+
+    /// Marks locked pages dirty.
+    ///
+    /// Writes through locked pages bypass the dirty log, so conservatively
+    /// treat locked pages as written both when they are locked and when they
+    /// are released. This ensures that a write made while the pages are
+    /// locked is reported no later than the release.
+    fn mark_gpns_dirty(&self, gpns: &[u64]) {
+        if self.dirty_log.is_enabled() {
+            for &gpn in gpns {
+                self.dirty_log
+                    .mark(self.dirty_log_offset + gpn * PAGE_SIZE64, PAGE_SIZE64);
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct LockedPages {
     pages: Box<[PagePtr]>,
-    gpns: Option<Box<[u64]>>,
+    gpns: Box<[u64]>,
+    unlock_gpns: bool,
     // maintain a reference to the backing memory
     mem: Arc<GuestMemoryInner>,
 }
 
 impl Drop for LockedPages {
     fn drop(&mut self) {
-        if let Some(gpns) = &self.gpns {
-            self.mem.imp.unlock_gpns(gpns);
-        }
+        self.mem.release_locked_gpns(&self.gpns, self.unlock_gpns);
     }
 }
 
@@ -2190,7 +2332,8 @@ pub trait LockedRange {
 
 pub struct LockedRangeImpl<T: LockedRange> {
     mem: Arc<GuestMemoryInner>,
-    gpns: Option<Box<[u64]>>,
+    gpns: Box<[u64]>,
+    unlock_gpns: bool,
     inner: T,
 }
 
@@ -2202,9 +2345,7 @@ pub fn get(&self) -> &T {
 
 impl<T: LockedRange> Drop for LockedRangeImpl<T> {
     fn drop(&mut self) {
-        if let Some(gpns) = &self.gpns {
-            self.mem.imp.unlock_gpns(gpns);
-        }
+        self.mem.release_locked_gpns(&self.gpns, self.unlock_gpns);
     }
 }
 
@@ -2462,6 +2603,7 @@ mod tests {
     use crate::PAGE_SIZE64;
     use crate::PageFaultAction;
     use crate::PageFaultError;
+    use memory_range::MemoryRange;
     use sparse_mmap::SparseMapping;
     use std::ptr::NonNull;
     use std::sync::Arc;
@@ -2692,4 +2834,85 @@ fn test_allocated() {
         assert_eq!(gm.inner_buf_mut().unwrap(), &pattern);
         gm.into_inner_buf().unwrap();
     }
+
+    #[test]
+    fn test_dirty_tracking() {
+        let gm = GuestMemory::allocate(PAGE_SIZE * 80);
+        let ram = MemoryRange::new(PAGE_SIZE64..PAGE_SIZE64 * 80);
+
+        // Writes are not tracked until tracking is enabled.
+        gm.write_at(PAGE_SIZE64, &[1]).unwrap();
+        gm.enable_dirty_tracking(&[ram]);
+        let mut bitmap = [0; 2];
+        gm.take_dirty_bitmap(ram, &mut bitmap);
+        assert_eq!(bitmap, [0, 0]);
+
+        // Writes outside the tracked ranges are ignored, and multi-page writes
+        // mark every page they touch.
+        gm.write_at(0, &[1]).unwrap();
+        gm.write_at(PAGE_SIZE64 * 2 - 1, &[1, 2]).unwrap();
+        gm.fill_at(PAGE_SIZE64 * 70, 0, PAGE_SIZE).unwrap();
+        gm.take_dirty_bitmap(ram, &mut bitmap);
+        assert_eq!(bitmap, [0b11, 1 << 5]);
+
+        // Retrieving the bitmap clears it, and retrieving a subrange only
+        // clears that subrange.
+        let mut bitmap = [0; 2];
+        gm.take_dirty_bitmap(ram, &mut bitmap);
+        assert_eq!(bitmap, [0, 0]);
+        gm.write_plain(PAGE_SIZE64 * 3, &0u32).unwrap();
+        gm.write_plain(PAGE_SIZE64 * 4, &0u32).unwrap();
+        let mut bitmap = [0; 1];
+        gm.take_dirty_bitmap(
+            MemoryRange::new(PAGE_SIZE64 * 4..PAGE_SIZE64 * 5),
+            &mut bitmap,
+        );
+        assert_eq!(bitmap, [1]);
+        let mut bitmap = [0; 2];
+        gm.take_dirty_bitmap(ram, &mut bitmap);
+        assert_eq!(bitmap, [0b100, 0]);
+
+        // Locked pages are dirty when they are locked and again once they are
+        // released.
+        let locked = gm.lock_gpns(false, &[10]).unwrap();
+        gm.take_dirty_bitmap(ram, &mut bitmap);
+        assert_eq!(bitmap, [0b100 | 1 << 9, 0]);
+        let mut bitmap = [0; 2];
+        drop(locked);
+        gm.take_dirty_bitmap(ram, &mut bitmap);
+        assert_eq!(bitmap, [1 << 9, 0]);
+
+        gm.disable_dirty_tracking();
+        gm.write_at(PAGE_SIZE64, &[1]).unwrap();
+        let mut bitmap = [0; 2];
+        gm.take_dirty_bitmap(ram, &mut bitmap);
+        assert_eq!(bitmap, [0, 0]);
+    }
+
+    #[test]
+    fn test_dirty_tracking_subrange() {
+        let gm = GuestMemory::allocate(PAGE_SIZE * 16);
+        let ram = MemoryRange::new(0..PAGE_SIZE64 * 16);
+        let sub = gm
+            .subrange(PAGE_SIZE64 * 4, PAGE_SIZE64 * 8, false)
+            .unwrap();
+        let nested = sub
+            .subrange(PAGE_SIZE64 * 2, PAGE_SIZE64 * 4, false)
+            .unwrap();
+        gm.enable_dirty_tracking(&[ram]);
+
+        // Writes through subranges are tracked at the parent's addresses.
+        sub.write_at(PAGE_SIZE64, &[1]).unwrap();
+        nested.fill_at(PAGE_SIZE64, 0, PAGE_SIZE + 1).unwrap();
+        let mut bitmap = [0; 1];
+        gm.take_dirty_bitmap(ram, &mut bitmap);
+        assert_eq!(bitmap, [1 << 5 | 1 << 7 | 1 << 8]);
+
+        // As are pages locked through a subrange.
+        let locked = sub.lock_gpns(false, &[3]).unwrap();
+        let mut bitmap = [0; 1];
+        gm.take_dirty_bitmap(ram, &mut bitmap);
+        assert_eq!(bitmap, [1 << 7]);
+        drop(locked);
+    }
 }
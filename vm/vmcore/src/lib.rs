@@ -19,6 +19,7 @@
 pub mod notify;
 pub mod reference_time;
 pub mod save_restore;
+pub mod shared_ram;
 pub mod slim_event;
 pub mod synic;
 pub mod vm_task;
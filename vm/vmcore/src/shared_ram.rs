@@ -0,0 +1,63 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Types for sharing guest RAM with other components, such as out-of-process
+//! device back-ends.
+
+#![forbid(unsafe_code)]
+
+use std::convert::Infallible;
+use std::sync::Arc;
+use vm_resource::CanResolveTo;
+use vm_resource::PlatformResource;
+use vm_resource::ResolveResource;
+use vm_resource::ResourceKind;
+
+/// A resource kind for accessing the memory object backing guest RAM.
+///
+/// Only the platform resource makes sense for this resource kind, since there
+/// is only one guest RAM backing for a partition.
+pub enum SharedGuestRamKind {}
+
+impl ResourceKind for SharedGuestRamKind {
+    const NAME: &'static str = "shared_guest_ram";
+}
+
+impl CanResolveTo<SharedGuestRam> for SharedGuestRamKind {
+    type Input<'a> = ();
+}
+
+/// The memory object backing guest RAM, and where each range of guest RAM is
+/// within it.
+#[derive(Debug, Clone)]
+pub struct SharedGuestRam {
+    /// The memory object backing guest RAM.
+    pub memory: Arc<sparse_mmap::Mappable>,
+    /// The ranges of guest RAM, in increasing address order.
+    pub ranges: Vec<SharedRamRange>,
+}
+
+/// A range of guest RAM that is contiguous in both the guest physical address
+/// space and the backing memory object.
+#[derive(Debug, Copy, Clone)]
+pub struct SharedRamRange {
+    /// The guest physical address of the range.
+    pub gpa: u64,
+    /// The length of the range in bytes.
+    pub len: u64,
+    /// The offset of the range within [`SharedGuestRam::memory`].
+    pub offset: u64,
+}
+
+impl ResolveResource<SharedGuestRamKind, PlatformResource> for SharedGuestRam {
+    type Output = SharedGuestRam;
+    type Error = Infallible;
+
+    fn resolve(
+        &self,
+        PlatformResource: PlatformResource,
+        (): (),
+    ) -> Result<Self::Output, Self::Error> {
+        Ok(self.clone())
+    }
+}
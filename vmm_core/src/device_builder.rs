@@ -8,6 +8,7 @@
 use closeable_mutex::CloseableMutex;
 use guestmem::DoorbellRegistration;
 use guestmem::GuestMemory;
+use memory_range::MemoryRange;
 use pci_core::msi::MsiInterruptSet;
 use pci_core::msi::MsiInterruptTarget;
 use std::sync::Arc;
@@ -27,6 +28,7 @@ pub async fn build_vpci_device(
     driver_source: &VmTaskDriverSource,
     resolver: &ResourceResolver,
     guest_memory: &GuestMemory,
+    guest_ram: &[MemoryRange],
     vmbus: &VmbusServerControl,
     instance_id: Guid,
     resource: Resource<PciDeviceHandleKind>,
@@ -52,6 +54,7 @@ pub async fn build_vpci_device(
         driver_source,
         resolver,
         guest_memory,
+        guest_ram,
         resource,
         doorbell_registration,
         mapper,
@@ -100,6 +103,7 @@ pub async fn build_pcie_device(
     driver_source: &VmTaskDriverSource,
     resolver: &ResourceResolver,
     guest_memory: &GuestMemory,
+    guest_ram: &[MemoryRange],
     resource: Resource<PciDeviceHandleKind>,
     doorbell_registration: Option<Arc<dyn DoorbellRegistration>>,
     mapper: Option<&dyn guestmem::MemoryMapper>,
@@ -115,6 +119,7 @@ pub async fn build_pcie_device(
         driver_source,
         resolver,
         guest_memory,
+        guest_ram,
         resource,
         doorbell_registration,
         mapper,
@@ -134,6 +139,7 @@ pub async fn resolve_and_add_pci_device(
     driver_source: &VmTaskDriverSource,
     resolver: &ResourceResolver,
     guest_memory: &GuestMemory,
+    guest_ram: &[MemoryRange],
     resource: Resource<PciDeviceHandleKind>,
     doorbell_registration: Option<Arc<dyn DoorbellRegistration>>,
     mapper: Option<&dyn guestmem::MemoryMapper>,
@@ -151,6 +157,7 @@ pub async fn resolve_and_add_pci_device(
                             register_mmio: &mut services.register_mmio(),
                             driver_source,
                             guest_memory,
+                            guest_ram,
                             doorbell_registration,
                             shared_mem_mapper: mapper,
                         },
@@ -267,6 +267,11 @@ fn supports_vtl_scrub(&self) -> Option<&dyn ScrubVtl<Error = <Self as Hv1>::Erro
         None
     }
 
+    /// Returns a trait object to track guest writes to memory, if supported.
+    fn supports_dirty_tracking(&self) -> Option<&dyn DirtyTracking<Error = <Self as Hv1>::Error>> {
+        None
+    }
+
     /// Returns an interface for registering MMIO doorbells for this partition.
     ///
     /// Not all partitions support this.
@@ -365,6 +370,30 @@ pub trait ScrubVtl {
     fn scrub(&self, vtl: Vtl) -> Result<(), Self::Error>;
 }
 
+/// Extension trait for tracking which guest pages have been written by the
+/// guest, for use by incremental snapshots and live migration.
+///
+/// This only tracks writes made by the guest's processors. Writes made by
+/// devices through [`GuestMemory`] are tracked separately; see
+/// [`GuestMemory::take_dirty_bitmap`].
+pub trait DirtyTracking {
+    type Error: std::error::Error;
+
+    /// Enables or disables dirty page tracking for guest RAM.
+    ///
+    /// When tracking is enabled, all pages start out clean.
+    fn set_dirty_tracking(&self, enable: bool) -> Result<(), Self::Error>;
+
+    /// Sets the bits in `bitmap` for the pages in `range` that have been
+    /// written since they were last retrieved, and marks the pages clean.
+    /// Other bits in `bitmap` are left unchanged.
+    ///
+    /// Bit `n` of `bitmap` (counting from the low bit of the first element)
+    /// corresponds to the `n`th 4KB page of `range`. `range` must be page
+    /// aligned, and `bitmap` must have at least one bit per page in `range`.
+    fn take_dirty_bitmap(&self, range: MemoryRange, bitmap: &mut [u64]) -> Result<(), Self::Error>;
+}
+
 /// Provides access to partition state for save, restore, and reset.
 ///
 /// This is not part of [`Partition`] because some scenarios do not require such
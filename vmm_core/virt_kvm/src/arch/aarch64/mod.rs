@@ -624,6 +624,7 @@ fn build(
         let partition = KvmPartitionInner {
             kvm: self.vm,
             memory: Default::default(),
+            dirty_pages_harvested: Default::default(),
             hv1_enabled: self.config.hv_config.is_some(),
             gm: config.guest_memory.clone(),
             vps: self
@@ -670,6 +671,12 @@ fn supports_reset(
         None
     }
 
+    fn supports_dirty_tracking(
+        &self,
+    ) -> Option<&dyn virt::DirtyTracking<Error = <Self as virt::Hv1>::Error>> {
+        Some(self)
+    }
+
     fn caps(&self) -> &PartitionCapabilities {
         &self.inner.caps
     }
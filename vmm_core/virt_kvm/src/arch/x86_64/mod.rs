@@ -392,6 +392,7 @@ fn build(
         let partition = KvmPartitionInner {
             kvm: self.vm,
             memory: Default::default(),
+            dirty_pages_harvested: Default::default(),
             hv1_enabled: self.config.hv_config.is_some(),
             gm: config.guest_memory.clone(),
             vps: self
@@ -484,6 +485,10 @@ fn supports_reset(&self) -> Option<&dyn ResetPartition<Error = Self::Error>> {
         Some(self)
     }
 
+    fn supports_dirty_tracking(&self) -> Option<&dyn virt::DirtyTracking<Error = Self::Error>> {
+        Some(self)
+    }
+
     fn doorbell_registration(
         self: &Arc<Self>,
         _minimum_vtl: Vtl,
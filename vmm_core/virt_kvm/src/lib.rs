@@ -24,7 +24,9 @@
 
 pub use arch::Kvm;
 use arch::KvmVpInner;
+use hvdef::HV_PAGE_SIZE;
 use hvdef::Vtl;
+use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
 use virt::VpIndex;
 use vmcore::vmtime::VmTimeAccess;
@@ -49,12 +51,81 @@ pub enum KvmError {
     Misaligned,
     #[error("host does not support required cpu capabilities")]
     Capabilities(virt::PartitionCapabilitiesError),
+    #[error("dirty page tracking is not enabled")]
+    DirtyTrackingNotEnabled,
 }
 
 #[derive(Debug, Inspect)]
 struct KvmMemoryRange {
     host_addr: *mut u8,
     range: MemoryRange,
+    readonly: bool,
+    /// Dirty bits retrieved from KVM but not yet taken by the caller, one bit
+    /// per 4KB page. Only set while dirty page tracking is enabled.
+    #[inspect(skip)]
+    dirty_bitmap: Option<Vec<u64>>,
+}
+
+impl KvmMemoryRange {
+    fn new_dirty_bitmap(&self) -> Vec<u64> {
+        vec![0; self.range.page_count_4k().div_ceil(64) as usize]
+    }
+}
+
+/// Retrieves and clears KVM's dirty log for `slot`, which maps `range`, and
+/// merges it into `dirty_bitmap`.
+fn sync_dirty_log(
+    kvm: &kvm::Partition,
+    slot: u32,
+    range: MemoryRange,
+    dirty_bitmap: &mut [u64],
+) -> Result<(), kvm::Error> {
+    // KVM logs host pages, which may be larger than the 4KB pages that are
+    // handed out.
+    let host_page_size = sparse_mmap::SparseMapping::page_size() as u64;
+    let pages_per_bit = host_page_size / HV_PAGE_SIZE;
+    let mut log = vec![0; range.len().div_ceil(host_page_size).div_ceil(64) as usize];
+    // SAFETY: the bitmap has a bit for every page in the slot.
+    unsafe { kvm.get_dirty_log(slot, &mut log)? };
+    let page_count = range.page_count_4k();
+    for (i, &word) in log.iter().enumerate() {
+        let mut word = word;
+        while word != 0 {
+            let first = (i as u64 * 64 + word.trailing_zeros() as u64) * pages_per_bit;
+            word &= word - 1;
+            for page in first..(first + pages_per_bit).min(page_count) {
+                dirty_bitmap[page as usize / 64] |= 1 << (page % 64);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Moves the bits in `dirty_bitmap`, which covers `dirty_range`, for the
+/// pages in `range` to `bitmap`. Returns the number of bits moved.
+fn harvest_dirty_bits(
+    dirty_range: MemoryRange,
+    dirty_bitmap: &mut [u64],
+    range: MemoryRange,
+    bitmap: &mut [u64],
+) -> u64 {
+    let start_page = range.start() / HV_PAGE_SIZE;
+    let end_page = range.end() / HV_PAGE_SIZE;
+    let base = dirty_range.start() / HV_PAGE_SIZE;
+    let start = start_page.max(base);
+    let end = end_page.min(dirty_range.end() / HV_PAGE_SIZE);
+    let mut harvested = 0;
+    for page in start..end {
+        let index = (page - base) as usize;
+        let bit = 1 << (index % 64);
+        if dirty_bitmap[index / 64] & bit != 0 {
+            dirty_bitmap[index / 64] &= !bit;
+            let out = (page - start_page) as usize;
+            bitmap[out / 64] |= 1 << (out % 64);
+            harvested += 1;
+        }
+    }
+    harvested
 }
 
 unsafe impl Sync for KvmMemoryRange {}
@@ -64,6 +135,26 @@ unsafe impl Send for KvmMemoryRange {}
 struct KvmMemoryRangeState {
     #[inspect(flatten, iter_by_index)]
     ranges: Vec<Option<KvmMemoryRange>>,
+    dirty_tracking: bool,
+    /// Dirty bits of ranges that have been unmapped or moved but not yet
+    /// taken by the caller.
+    #[inspect(skip)]
+    unmapped_dirty: Vec<(MemoryRange, Vec<u64>)>,
+}
+
+impl KvmMemoryRangeState {
+    /// Saves the dirty bits of the range in `slot` before the slot is
+    /// cleared or moved, so that they can still be taken.
+    fn retire_dirty_bitmap(&mut self, kvm: &kvm::Partition, slot: usize) -> Result<(), kvm::Error> {
+        let Some(range) = &mut self.ranges[slot] else {
+            return Ok(());
+        };
+        if let Some(mut dirty_bitmap) = range.dirty_bitmap.take() {
+            sync_dirty_log(kvm, slot as u32, range.range, &mut dirty_bitmap)?;
+            self.unmapped_dirty.push((range.range, dirty_bitmap));
+        }
+        Ok(())
+    }
 }
 
 #[derive(Inspect)]
@@ -77,6 +168,8 @@ struct KvmPartitionInner {
     #[inspect(skip)]
     kvm: kvm::Partition,
     memory: Mutex<KvmMemoryRangeState>,
+    #[inspect(with = "|x| inspect::AsCounter(x.load(Ordering::Relaxed))")]
+    dirty_pages_harvested: AtomicU64,
     hv1_enabled: bool,
     gm: GuestMemory,
     #[inspect(skip)]
@@ -164,16 +257,117 @@ unsafe fn map_region(
             state.ranges.push(None);
         }
         let slot_to_use = slot_to_use.unwrap();
+        state.retire_dirty_bitmap(&self.kvm, slot_to_use)?;
+        let log_dirty_pages = state.dirty_tracking && !readonly;
         unsafe {
-            self.kvm
-                .set_user_memory_region(slot_to_use as u32, data, size, addr, readonly)?
+            self.kvm.set_user_memory_region(
+                slot_to_use as u32,
+                data,
+                size,
+                addr,
+                readonly,
+                log_dirty_pages,
+            )?
         };
-        state.ranges[slot_to_use] = Some(KvmMemoryRange {
+        let mut range = KvmMemoryRange {
             host_addr: data,
             range: MemoryRange::new(addr..addr + size as u64),
-        });
+            readonly,
+            dirty_bitmap: None,
+        };
+        if log_dirty_pages {
+            // The contents of the range may have moved, so conservatively
+            // treat every page as dirty.
+            let mut bitmap = range.new_dirty_bitmap();
+            bitmap.fill(!0);
+            range.dirty_bitmap = Some(bitmap);
+        }
+        state.ranges[slot_to_use] = Some(range);
         Ok(())
     }
+
+    fn set_dirty_tracking(&self, enable: bool) -> Result<(), KvmError> {
+        let mut state = self.memory.lock();
+        let was_enabled = state.dirty_tracking;
+        for (slot, entry) in state.ranges.iter_mut().enumerate() {
+            let Some(range) = entry else { continue };
+            if range.readonly {
+                continue;
+            }
+            if was_enabled && enable {
+                // Discard the pages that are already dirty.
+                let mut bitmap = range.new_dirty_bitmap();
+                sync_dirty_log(&self.kvm, slot as u32, range.range, &mut bitmap)?;
+            } else {
+                // SAFETY: the slot is registered again with the same mapping,
+                // changing only its flags.
+                unsafe {
+                    self.kvm.set_user_memory_region(
+                        slot as u32,
+                        range.host_addr,
+                        range.range.len() as usize,
+                        range.range.start(),
+                        false,
+                        enable,
+                    )?;
+                }
+            }
+            range.dirty_bitmap = enable.then(|| range.new_dirty_bitmap());
+        }
+        state.unmapped_dirty.clear();
+        state.dirty_tracking = enable;
+        Ok(())
+    }
+
+    fn take_dirty_bitmap(&self, range: MemoryRange, bitmap: &mut [u64]) -> Result<(), KvmError> {
+        assert!(
+            bitmap.len() as u64 * 64 >= range.page_count_4k(),
+            "bitmap too small"
+        );
+        let mut state = self.memory.lock();
+        if !state.dirty_tracking {
+            return Err(KvmError::DirtyTrackingNotEnabled);
+        }
+        let state = &mut *state;
+        let mut harvested = 0;
+        for (slot, entry) in state.ranges.iter_mut().enumerate() {
+            let Some(kvm_range) = entry else { continue };
+            if !kvm_range.range.overlaps(&range) {
+                continue;
+            }
+            let Some(dirty_bitmap) = &mut kvm_range.dirty_bitmap else {
+                continue;
+            };
+
+            // KVM clears the log for the whole slot, so accumulate it and
+            // only hand out the bits that were asked for.
+            sync_dirty_log(&self.kvm, slot as u32, kvm_range.range, dirty_bitmap)?;
+            harvested += harvest_dirty_bits(kvm_range.range, dirty_bitmap, range, bitmap);
+        }
+        state
+            .unmapped_dirty
+            .retain_mut(|(dirty_range, dirty_bitmap)| {
+                if dirty_range.overlaps(&range) {
+                    harvested += harvest_dirty_bits(*dirty_range, dirty_bitmap, range, bitmap);
+                }
+                dirty_bitmap.iter().any(|&bits| bits != 0)
+            });
+        self.dirty_pages_harvested
+            .fetch_add(harvested, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl virt::DirtyTracking for KvmPartition {
+    type Error = KvmError;
+
+    fn set_dirty_tracking(&self, enable: bool) -> Result<(), Self::Error> {
+        self.inner.set_dirty_tracking(enable)
+    }
+
+    fn take_dirty_bitmap(&self, range: MemoryRange, bitmap: &mut [u64]) -> Result<(), Self::Error> {
+        self.inner.take_dirty_bitmap(range, bitmap)
+    }
 }
 
 impl virt::PartitionMemoryMapper for KvmPartition {
@@ -200,9 +394,14 @@ unsafe fn map_range(
     fn unmap_range(&self, addr: u64, size: u64) -> anyhow::Result<()> {
         let range = MemoryRange::new(addr..addr + size);
         let mut state = self.memory.lock();
-        for (slot, entry) in state.ranges.iter_mut().enumerate() {
-            let Some(kvm_range) = entry else { continue };
+        for slot in 0..state.ranges.len() {
+            let Some(kvm_range) = &state.ranges[slot] else {
+                continue;
+            };
             if range.contains(&kvm_range.range) {
+                // Keep the pages dirtied before the unmap until they are
+                // taken.
+                state.retire_dirty_bitmap(&self.kvm, slot)?;
                 // SAFETY: clearing a slot should always be safe since it removes
                 // and does not add memory references.
                 unsafe {
@@ -212,9 +411,10 @@ fn unmap_range(&self, addr: u64, size: u64) -> anyhow::Result<()> {
                         0,
                         0,
                         false,
+                        false,
                     )?;
                 }
-                *entry = None;
+                state.ranges[slot] = None;
             } else {
                 assert!(
                     !range.overlaps(&kvm_range.range),
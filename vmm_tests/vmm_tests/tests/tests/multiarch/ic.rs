@@ -2,6 +2,7 @@
 // Licensed under the MIT License.
 
 use anyhow::Context;
+use hyperv_ic_resources::heartbeat::HeartbeatStatus;
 use hyperv_ic_resources::kvp::KvpRpc;
 use jiff::SignedDuration;
 use mesh::rpc::RpcSend;
@@ -135,3 +136,57 @@ async fn timesync_ic(config: PetriVmBuilder<OpenVmmPetriBackend>) -> anyhow::Res
     vm.wait_for_clean_teardown().await?;
     Ok(())
 }
+
+/// Test the heartbeat IC.
+#[openvmm_test(
+    uefi_x64(vhd(windows_datacenter_core_2022_x64)),
+    uefi_x64(vhd(ubuntu_2504_server_x64)),
+    uefi_aarch64(vhd(windows_11_enterprise_aarch64)),
+    uefi_aarch64(vhd(ubuntu_2404_server_aarch64))
+)]
+async fn heartbeat_ic(config: PetriVmBuilder<OpenVmmPetriBackend>) -> anyhow::Result<()> {
+    let (mut vm, agent) = config.run().await?;
+
+    vm.backend().wait_for_heartbeat_ok().await?;
+    assert_eq!(vm.backend().heartbeat_status().await?, HeartbeatStatus::Ok);
+
+    agent.power_off().await?;
+    vm.wait_for_clean_teardown().await?;
+    Ok(())
+}
+
+/// Test the VSS IC.
+///
+/// Windows-only right now, because the Linux images do not include the VSS IC
+/// daemon.
+#[openvmm_test(uefi_x64(vhd(windows_datacenter_core_2022_x64)))]
+async fn vss_ic(config: PetriVmBuilder<OpenVmmPetriBackend>) -> anyhow::Result<()> {
+    let (mut vm, agent) = config.run().await?;
+
+    // The guest's VSS service may take a while to connect after boot.
+    let mut frozen = false;
+    for _ in 0..30 {
+        match vm.backend().freeze_guest().await {
+            Ok(()) => {
+                frozen = true;
+                break;
+            }
+            Err(err) => tracing::info!(error = ?err, "freeze failed, retrying"),
+        }
+        mesh::CancelContext::new()
+            .with_timeout(Duration::from_secs(1))
+            .cancelled()
+            .await;
+    }
+    if !frozen {
+        anyhow::bail!("guest never froze");
+    }
+
+    vm.backend().thaw_guest().await?;
+    // The guest is no longer frozen, so thawing again fails.
+    assert!(vm.backend().thaw_guest().await.is_err());
+
+    agent.power_off().await?;
+    vm.wait_for_clean_teardown().await?;
+    Ok(())
+}
@@ -174,6 +174,7 @@ async fn vpci_filter(config: PetriVmBuilder<OpenVmmPetriBackend>) -> anyhow::Res
                         resource: NvmeControllerHandle {
                             subsystem_id: nvme_guid,
                             msix_count: 1,
+                            namespace_pool: Vec::new(),
                             max_io_queues: 1,
                             namespaces: Vec::new(),
                         }
@@ -64,6 +64,7 @@ pub(crate) fn new_test_vtl2_nvme_device(
             subsystem_id: instance_id,
             max_io_queues: 64,
             msix_count: 64,
+            namespace_pool: Vec::new(),
             namespaces: vec![NamespaceDefinition {
                 nsid,
                 disk: layer.into_resource(),